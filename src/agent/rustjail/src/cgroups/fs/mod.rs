@@ -369,8 +369,8 @@ fn set_block_io_resources(
         let dr = BlkIoDeviceResource {
             major: d.blk.major as u64,
             minor: d.blk.minor as u64,
-            weight: blkio.weight,
-            leaf_weight: blkio.leaf_weight,
+            weight: d.weight,
+            leaf_weight: d.leaf_weight,
         };
         blk_device_resources.push(dr);
     }
@@ -0,0 +1,170 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+use slog::Logger;
+
+use crate::protocols::agent::Storage;
+
+const CRYPTSETUP_PATH: &str = "/sbin/cryptsetup";
+const DEV_MAPPER_DIR: &str = "/dev/mapper";
+// the keys are provisioned into the guest over the secure channel of the
+// attestation agent, they're never seen by the host
+const KEYS_DIR: &str = "/run/kata-containers/keys";
+
+// the driver option of the storage naming the key of the encrypted volume
+const ENCRYPTION_KEY_OPTION: &str = "encryption_key=";
+const MAPPING_PREFIX: &str = "kata-crypt-";
+
+// the exit code of cryptsetup for no key slot unlocked by the key
+const CRYPTSETUP_WRONG_KEY: i32 = 2;
+
+// encryption_key returns the name of the key of the encrypted volume.
+fn encryption_key(storage: &Storage) -> Option<&str> {
+    storage
+        .driver_options
+        .iter()
+        .find_map(|o| o.strip_prefix(ENCRYPTION_KEY_OPTION))
+}
+
+// mapping_name derives the name of the dm-crypt mapping from the mount point,
+// so that it's found again on the removal of the storage.
+fn mapping_name(mount_point: &str) -> String {
+    // FNV-1a, stable across the restarts of the agent
+    let hash = mount_point.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{}{:016x}", MAPPING_PREFIX, hash)
+}
+
+fn key_file(key: &str) -> Result<String> {
+    if key.is_empty() || key.contains('/') || key == "." || key == ".." {
+        return Err(anyhow!("invalid key name {:?}", key));
+    }
+    let path = Path::new(KEYS_DIR).join(key);
+    if !path.exists() {
+        return Err(anyhow!("key {} isn't provisioned in the guest", key));
+    }
+    Ok(path.display().to_string())
+}
+
+fn cryptsetup(args: &[&str]) -> Result<()> {
+    let output = Command::new(CRYPTSETUP_PATH)
+        .args(args)
+        .output()
+        .context("run cryptsetup")?;
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let std_err = String::from_utf8_lossy(&output.stderr);
+    match output.status.code() {
+        Some(CRYPTSETUP_WRONG_KEY) => Err(anyhow!("wrong key: {}", std_err.trim())),
+        Some(code) => Err(anyhow!(
+            "cryptsetup return code: {} stderr: {}",
+            code,
+            std_err.trim()
+        )),
+        None => Err(anyhow!("Process terminated by signal")),
+    }
+}
+
+/// decrypted_storage opens the LUKS device of the encrypted storage with its
+/// key, the storage returned mounts the decrypted device. The storages not
+/// encrypted are returned as they are.
+pub fn decrypted_storage(logger: &Logger, storage: &Storage) -> Result<Storage> {
+    let key = match encryption_key(storage) {
+        Some(key) => key,
+        None => return Ok(storage.clone()),
+    };
+
+    let name = mapping_name(&storage.mount_point);
+    let device = Path::new(DEV_MAPPER_DIR).join(&name);
+    if !device.exists() {
+        info!(
+            logger,
+            "open encrypted device {} as {}", storage.source, name
+        );
+        let key_file = key_file(key)?;
+        cryptsetup(&[
+            "open",
+            "--type",
+            "luks",
+            "--key-file",
+            &key_file,
+            &storage.source,
+            &name,
+        ])
+        .with_context(|| format!("open encrypted device {} with key {}", storage.source, key))?;
+    }
+
+    Ok(Storage {
+        source: device.display().to_string(),
+        ..storage.clone()
+    })
+}
+
+/// close_encrypted_device closes the dm-crypt mapping of the storage mounted
+/// at the mount point, if any.
+pub fn close_encrypted_device(logger: &Logger, mount_point: &str) -> Result<()> {
+    let name = mapping_name(mount_point);
+    if !Path::new(DEV_MAPPER_DIR).join(&name).exists() {
+        return Ok(());
+    }
+
+    info!(logger, "close encrypted device {}", name);
+    cryptsetup(&["close", &name]).with_context(|| format!("close encrypted device {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encryption_key() {
+        let mut storage = Storage::default();
+        assert_eq!(encryption_key(&storage), None);
+
+        storage.driver_options = vec![
+            "io.katacontainers.fs-opt.overlay-rw".to_string(),
+            "encryption_key=volume-key".to_string(),
+        ];
+        assert_eq!(encryption_key(&storage), Some("volume-key"));
+    }
+
+    #[test]
+    fn test_mapping_name() {
+        let name = mapping_name("/run/kata-containers/sandbox/storage/vol1");
+        assert!(name.starts_with(MAPPING_PREFIX));
+        assert_eq!(name.len(), MAPPING_PREFIX.len() + 16);
+        assert_eq!(
+            name,
+            mapping_name("/run/kata-containers/sandbox/storage/vol1")
+        );
+        assert_ne!(
+            name,
+            mapping_name("/run/kata-containers/sandbox/storage/vol2")
+        );
+    }
+
+    #[test]
+    fn test_key_file() {
+        for key in ["", ".", "..", "../etc/shadow"] {
+            assert!(key_file(key).is_err(), "{}", key);
+        }
+
+        let err = key_file("missing-key").unwrap_err();
+        assert!(format!("{}", err).contains("isn't provisioned"));
+    }
+
+    #[test]
+    fn test_close_encrypted_device_not_opened() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        assert!(close_encrypted_device(&logger, "/run/kata-containers/none").is_ok());
+    }
+}
@@ -22,7 +22,8 @@ use crate::sandbox::Sandbox;
 use crate::uevent::{wait_for_uevent, Uevent, UeventMatcher};
 use anyhow::{anyhow, Context, Result};
 use cfg_if::cfg_if;
-use oci::{LinuxDeviceCgroup, LinuxResources, Spec};
+use kata_types::device::DeviceNodeAttrs;
+use oci::{LinuxBlockIo, LinuxDeviceCgroup, LinuxResources, Spec};
 use protocols::agent::Device;
 use tracing::instrument;
 
@@ -52,6 +53,7 @@ pub const DRIVER_VFIO_PCI_GK_TYPE: &str = "vfio-pci-gk";
 pub const DRIVER_VFIO_PCI_TYPE: &str = "vfio-pci";
 pub const DRIVER_VFIO_AP_TYPE: &str = "vfio-ap";
 pub const DRIVER_OVERLAYFS_TYPE: &str = "overlayfs";
+pub const DRIVER_IMAGE_GUEST_PULL_TYPE: &str = "image_guest_pull";
 pub const FS_TYPE_HUGETLB: &str = "hugetlbfs";
 
 cfg_if! {
@@ -550,6 +552,8 @@ struct DevUpdate {
     // an optional new path to update the device to in the "inner" container
     // specification
     final_path: Option<String>,
+    // the ownership and the mode of the node asked for by the runtime
+    node: DeviceNodeAttrs,
 }
 
 impl DevUpdate {
@@ -566,6 +570,7 @@ impl From<DevNumUpdate> for DevUpdate {
         DevUpdate {
             num,
             final_path: None,
+            node: DeviceNodeAttrs::default(),
         }
     }
 }
@@ -622,6 +627,16 @@ fn update_spec_devices(spec: &mut Spec, mut updates: HashMap<&str, DevUpdate>) -
             if let Some(final_path) = update.final_path {
                 specdev.path = final_path;
             }
+            // the node is created with the defaults for the ones unset
+            if let Some(file_mode) = update.node.file_mode {
+                specdev.file_mode = Some(file_mode);
+            }
+            if let Some(uid) = update.node.uid {
+                specdev.uid = Some(uid);
+            }
+            if let Some(gid) = update.node.gid {
+                specdev.gid = Some(gid);
+            }
 
             if res_updates
                 .insert(
@@ -676,6 +691,54 @@ fn update_spec_devices(spec: &mut Spec, mut updates: HashMap<&str, DevUpdate>) -
     Ok(())
 }
 
+// block_devnums returns a map of (host numbers => guest numbers) of the
+// block devices updated in the OCI spec
+fn block_devnums(
+    spec: &Spec,
+    updates: &HashMap<&str, DevUpdate>,
+) -> HashMap<(i64, i64), (i64, i64)> {
+    spec.linux
+        .iter()
+        .flat_map(|linux| linux.devices.iter())
+        .filter(|d| d.r#type == "b")
+        .filter_map(|d| {
+            let update = updates.get(d.path.as_str())?;
+            Some((
+                (d.major, d.minor),
+                (update.num.guest_major, update.num.guest_minor),
+            ))
+        })
+        .collect()
+}
+
+// update_weight_devices alters the weights of the block devices to be
+// for their numbers in the VM instead of the host.  It is given a map of
+// (host numbers => guest numbers), the weights of the devices the VM
+// doesn't have are dropped.
+#[instrument]
+pub fn update_weight_devices(
+    block_io: &mut LinuxBlockIo,
+    devnums: &HashMap<(i64, i64), (i64, i64)>,
+) {
+    let mut weight_device = Vec::with_capacity(block_io.weight_device.len());
+    for mut d in block_io.weight_device.drain(..) {
+        match devnums.get(&(d.blk.major, d.blk.minor)) {
+            Some((guest_major, guest_minor)) => {
+                d.blk.major = *guest_major;
+                d.blk.minor = *guest_minor;
+                weight_device.push(d);
+            }
+            None => warn!(
+                sl!(),
+                "drop weight of block device not in the VM";
+                "host_major" => d.blk.major,
+                "host_minor" => d.blk.minor,
+            ),
+        }
+    }
+    block_io.weight_device = weight_device;
+}
+
 // update_env_pci alters PCI addresses in a set of environment
 // variables to be correct for the VM instead of the host.  It is
 // given a map of (host address => guest address)
@@ -879,6 +942,7 @@ async fn vfio_ap_device_handler(_: &Device, _: &Arc<Mutex<Sandbox>>) -> Result<S
 
 #[instrument]
 pub async fn add_devices(
+    cid: &str,
     devices: &[Device],
     spec: &mut Spec,
     sandbox: &Arc<Mutex<Sandbox>>,
@@ -887,7 +951,9 @@ pub async fn add_devices(
 
     for device in devices.iter() {
         let update = add_device(device, sandbox).await?;
-        if let Some(dev_update) = update.dev {
+        if let Some(mut dev_update) = update.dev {
+            dev_update.node = DeviceNodeAttrs::from_options(&device.options)
+                .with_context(|| format!("device {}", device.container_path))?;
             if dev_updates
                 .insert(&device.container_path, dev_update)
                 .is_some()
@@ -915,7 +981,23 @@ pub async fn add_devices(
     if let Some(process) = spec.process.as_mut() {
         update_env_pci(&mut process.env, &sandbox.lock().await.pcimap)?
     }
-    update_spec_devices(spec, dev_updates)
+    // the host numbers are taken before they're updated
+    let devnums = block_devnums(spec, &dev_updates);
+    update_spec_devices(spec, dev_updates)?;
+
+    // the weights may be given to the devices of the other containers, the
+    // ones of the later updates are translated the same way
+    let mut sb = sandbox.lock().await;
+    sb.devnums.insert(cid.to_string(), devnums);
+    if let Some(block_io) = spec
+        .linux
+        .as_mut()
+        .and_then(|l| l.resources.as_mut())
+        .and_then(|r| r.block_io.as_mut())
+    {
+        update_weight_devices(block_io, &sb.block_devnums());
+    }
+    Ok(())
 }
 
 #[instrument]
@@ -1320,6 +1402,135 @@ mod tests {
         assert_eq!(final_path, specdevices[0].path);
     }
 
+    #[test]
+    fn test_update_spec_devices_node_attrs() {
+        let container_path = "/dev/xvda";
+        let mut spec = Spec {
+            linux: Some(Linux {
+                devices: vec![oci::LinuxDevice {
+                    path: container_path.to_string(),
+                    r#type: "b".to_string(),
+                    major: 8,
+                    minor: 0,
+                    uid: Some(1000),
+                    ..oci::LinuxDevice::default()
+                }],
+                ..Linux::default()
+            }),
+            ..Spec::default()
+        };
+
+        let mut update: DevUpdate = DevNumUpdate::from_vm_path("/dev/null").unwrap().into();
+        update.node =
+            DeviceNodeAttrs::from_options(&["file_mode=0660".to_string(), "gid=107".to_string()])
+                .unwrap();
+        update_spec_devices(
+            &mut spec,
+            HashMap::from_iter(vec![(container_path, update)]),
+        )
+        .unwrap();
+
+        // the uid not passed is kept
+        let device = &spec.linux.as_ref().unwrap().devices[0];
+        assert_eq!(device.file_mode, Some(0o660));
+        assert_eq!(device.uid, Some(1000));
+        assert_eq!(device.gid, Some(107));
+    }
+
+    #[test]
+    fn test_update_spec_devices_weight_device() {
+        let null_rdev = fs::metadata("/dev/null").unwrap().rdev();
+        let guest_major = stat::major(null_rdev) as i64;
+        let guest_minor = stat::minor(null_rdev) as i64;
+
+        let container_path = "/dev/disk";
+        let host_major: i64 = 8;
+        let host_minor: i64 = 0;
+
+        let weight_device = |major: i64, minor: i64, weight: u16| oci::LinuxWeightDevice {
+            blk: oci::LinuxBlockIoDevice { major, minor },
+            weight: Some(weight),
+            ..oci::LinuxWeightDevice::default()
+        };
+        let mut spec = Spec {
+            linux: Some(Linux {
+                devices: vec![oci::LinuxDevice {
+                    path: container_path.to_string(),
+                    r#type: "b".to_string(),
+                    major: host_major,
+                    minor: host_minor,
+                    ..oci::LinuxDevice::default()
+                }],
+                resources: Some(LinuxResources {
+                    block_io: Some(LinuxBlockIo {
+                        weight: Some(500),
+                        weight_device: vec![
+                            weight_device(host_major, host_minor, 100),
+                            // not a device of the container
+                            weight_device(8, 16, 200),
+                        ],
+                        ..LinuxBlockIo::default()
+                    }),
+                    ..LinuxResources::default()
+                }),
+                ..Linux::default()
+            }),
+            ..Spec::default()
+        };
+
+        let updates = HashMap::from_iter(vec![(
+            container_path,
+            DevNumUpdate::from_vm_path("/dev/null").unwrap().into(),
+        )]);
+        let devnums = block_devnums(&spec, &updates);
+        assert_eq!(
+            devnums.get(&(host_major, host_minor)),
+            Some(&(guest_major, guest_minor))
+        );
+
+        let res = update_spec_devices(&mut spec, updates);
+        assert!(res.is_ok());
+
+        // the weights are translated once the devices of all the containers
+        // are known, 8:16 is a device of another container
+        let block_io = spec
+            .linux
+            .as_mut()
+            .unwrap()
+            .resources
+            .as_mut()
+            .unwrap()
+            .block_io
+            .as_mut()
+            .unwrap();
+        let mut all_devnums = devnums.clone();
+        all_devnums.insert((8, 16), (252, 16));
+        update_weight_devices(block_io, &all_devnums);
+        assert_eq!(block_io.weight, Some(500));
+        assert_eq!(
+            block_io.weight_device,
+            vec![
+                weight_device(guest_major, guest_minor, 100),
+                weight_device(252, 16, 200)
+            ]
+        );
+
+        // an update refers to the host numbers again
+        let mut block_io = LinuxBlockIo {
+            weight_device: vec![
+                weight_device(host_major, host_minor, 300),
+                // the other container is gone
+                weight_device(8, 16, 400),
+            ],
+            ..LinuxBlockIo::default()
+        };
+        update_weight_devices(&mut block_io, &devnums);
+        assert_eq!(
+            block_io.weight_device,
+            vec![weight_device(guest_major, guest_minor, 300)]
+        );
+    }
+
     #[test]
     fn test_update_env_pci() {
         let example_map = [
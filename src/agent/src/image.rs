@@ -0,0 +1,166 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use slog::Logger;
+use tokio::process::Command;
+
+use crate::protocols::agent::Storage;
+
+const SKOPEO_PATH: &str = "/usr/bin/skopeo";
+const UMOCI_PATH: &str = "/usr/bin/umoci";
+// the images pulled in the guest, each one in the directory of the rootfs
+// it's mounted at, out of the shared directories of the host
+const IMAGES_DIR: &str = "/run/kata-containers/images";
+const IMAGE_TAG: &str = "latest";
+
+// image_dir derives the directory of the image from the mount point of the
+// rootfs, so that it's found again on the removal of the storage.
+fn image_dir(mount_point: &str) -> PathBuf {
+    // FNV-1a, stable across the restarts of the agent
+    let hash = mount_point.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    Path::new(IMAGES_DIR).join(format!("{:016x}", hash))
+}
+
+// pull_commands returns the commands copying the image from its registry to
+// an OCI layout, then unpacking it to a bundle.
+fn pull_commands(image: &str, dir: &Path) -> Vec<(&'static str, Vec<String>)> {
+    let layout = format!("{}:{}", dir.join("oci").display(), IMAGE_TAG);
+    vec![
+        (
+            SKOPEO_PATH,
+            vec![
+                "copy".to_string(),
+                format!("docker://{}", image),
+                format!("oci:{}", layout),
+            ],
+        ),
+        (
+            UMOCI_PATH,
+            vec![
+                "unpack".to_string(),
+                "--image".to_string(),
+                layout,
+                dir.join("bundle").display().to_string(),
+            ],
+        ),
+    ]
+}
+
+async fn run(path: &str, args: &[String]) -> Result<()> {
+    let output = Command::new(path)
+        .args(args)
+        .output()
+        .await
+        .with_context(|| format!("run {}", path))?;
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let std_err = String::from_utf8_lossy(&output.stderr);
+    match output.status.code() {
+        Some(code) => Err(anyhow!(
+            "{} return code: {} stderr: {}",
+            path,
+            code,
+            std_err.trim()
+        )),
+        None => Err(anyhow!("Process terminated by signal")),
+    }
+}
+
+/// pull_image pulls the image named by the source of the storage in the
+/// guest, the storage returned bind mounts its rootfs at the mount point.
+/// The image pulled already for the mount point isn't pulled again.
+pub async fn pull_image(logger: &Logger, storage: &Storage) -> Result<Storage> {
+    if storage.source.is_empty() {
+        return Err(anyhow!("no image to pull at {}", storage.mount_point));
+    }
+    let dir = image_dir(&storage.mount_point);
+    let rootfs = dir.join("bundle").join("rootfs");
+    if !rootfs.exists() {
+        info!(logger, "pull image {} into {:?}", storage.source, dir);
+        // the image half pulled before is pulled again
+        if dir.exists() {
+            fs::remove_dir_all(&dir).with_context(|| format!("remove {:?}", dir))?;
+        }
+        fs::create_dir_all(&dir).with_context(|| format!("create {:?}", dir))?;
+        for (path, args) in pull_commands(&storage.source, &dir) {
+            if let Err(e) = run(path, &args).await {
+                if let Err(err) = fs::remove_dir_all(&dir) {
+                    warn!(logger, "failed to remove {:?}: {:?}", dir, err);
+                }
+                return Err(e).with_context(|| format!("pull image {}", storage.source));
+            }
+        }
+    }
+
+    Ok(Storage {
+        source: rootfs.display().to_string(),
+        fstype: "bind".to_string(),
+        options: vec!["bind".to_string()],
+        ..storage.clone()
+    })
+}
+
+/// remove_image removes the image pulled for the rootfs mounted at the mount
+/// point, if any.
+pub fn remove_image(logger: &Logger, mount_point: &str) -> Result<()> {
+    let dir = image_dir(mount_point);
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    info!(logger, "remove image {:?}", dir);
+    fs::remove_dir_all(&dir).with_context(|| format!("remove {:?}", dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_dir() {
+        let dir = image_dir("/run/kata-containers/shared/containers/c1/rootfs");
+        assert!(dir.starts_with(IMAGES_DIR));
+        assert_eq!(
+            dir,
+            image_dir("/run/kata-containers/shared/containers/c1/rootfs")
+        );
+        assert_ne!(
+            dir,
+            image_dir("/run/kata-containers/shared/containers/c2/rootfs")
+        );
+    }
+
+    #[test]
+    fn test_pull_commands() {
+        let commands = pull_commands("docker.io/library/busybox:1.36", Path::new("/images/a"));
+        assert_eq!(commands[0].0, SKOPEO_PATH);
+        assert_eq!(
+            commands[0].1,
+            vec![
+                "copy",
+                "docker://docker.io/library/busybox:1.36",
+                "oci:/images/a/oci:latest"
+            ]
+        );
+        assert_eq!(commands[1].0, UMOCI_PATH);
+        assert_eq!(
+            commands[1].1,
+            vec![
+                "unpack",
+                "--image",
+                "/images/a/oci:latest",
+                "/images/a/bundle"
+            ]
+        );
+    }
+}
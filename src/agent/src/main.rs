@@ -37,7 +37,9 @@ use tracing::{instrument, span};
 
 mod config;
 mod console;
+mod crypt;
 mod device;
+mod image;
 mod linux_abi;
 mod metrics;
 mod mount;
@@ -20,13 +20,15 @@ use nix::unistd::{Gid, Uid};
 
 use regex::Regex;
 
+use crate::crypt;
 use crate::device::{
     get_scsi_device_name, get_virtio_blk_pci_device_name, get_virtio_mmio_device_name,
     online_device, wait_for_pmem_device, DRIVER_9P_TYPE, DRIVER_BLK_CCW_TYPE, DRIVER_BLK_TYPE,
-    DRIVER_EPHEMERAL_TYPE, DRIVER_LOCAL_TYPE, DRIVER_MMIO_BLK_TYPE, DRIVER_NVDIMM_TYPE,
-    DRIVER_OVERLAYFS_TYPE, DRIVER_SCSI_TYPE, DRIVER_VIRTIOFS_TYPE, DRIVER_WATCHABLE_BIND_TYPE,
-    FS_TYPE_HUGETLB,
+    DRIVER_EPHEMERAL_TYPE, DRIVER_IMAGE_GUEST_PULL_TYPE, DRIVER_LOCAL_TYPE, DRIVER_MMIO_BLK_TYPE,
+    DRIVER_NVDIMM_TYPE, DRIVER_OVERLAYFS_TYPE, DRIVER_SCSI_TYPE, DRIVER_VIRTIOFS_TYPE,
+    DRIVER_WATCHABLE_BIND_TYPE, FS_TYPE_HUGETLB,
 };
+use crate::image;
 use crate::linux_abi::*;
 use crate::pci;
 use crate::protocols::agent::Storage;
@@ -143,6 +145,7 @@ pub const STORAGE_HANDLER_LIST: &[&str] = &[
     DRIVER_SCSI_TYPE,
     DRIVER_NVDIMM_TYPE,
     DRIVER_WATCHABLE_BIND_TYPE,
+    DRIVER_IMAGE_GUEST_PULL_TYPE,
 ];
 
 #[instrument]
@@ -209,13 +212,16 @@ async fn ephemeral_storage_handler(
     // normal ephemeral storage
     fs::create_dir_all(Path::new(&storage.mount_point))?;
 
-    // By now we only support one option field: "fsGroup" which
-    // isn't an valid mount option, thus we should remove it when
-    // do mount.
+    // "fsGroup" isn't a valid mount option, thus we should remove it when
+    // do mount, the others (e.g. size of the tmpfs) are passed through.
     if !storage.options.is_empty() {
-        // ephemeral_storage didn't support mount options except fsGroup.
         let mut new_storage = storage.clone();
-        new_storage.options = Default::default();
+        new_storage.options = storage
+            .options
+            .iter()
+            .filter(|o| !o.starts_with(FS_GID))
+            .cloned()
+            .collect();
         common_storage_handler(logger, &new_storage)?;
 
         let opts_vec: Vec<String> = storage.options.to_vec();
@@ -311,6 +317,16 @@ async fn overlayfs_storage_handler(
     storage: &Storage,
     _sandbox: Arc<Mutex<Sandbox>>,
 ) -> Result<String> {
+    // the upperdir and the workdir may be on a fresh storage mounted just
+    // before, overlayfs doesn't create them.
+    for opt in storage.options.iter() {
+        if let Some(dir) = opt
+            .strip_prefix("upperdir=")
+            .or_else(|| opt.strip_prefix("workdir="))
+        {
+            fs::create_dir_all(dir).context(format!("failed to create dir all {:?}", dir))?;
+        }
+    }
     common_storage_handler(logger, storage)
 }
 
@@ -484,6 +500,29 @@ async fn virtiommio_blk_storage_handler(
     common_storage_handler(logger, &storage)
 }
 
+// the driver option of the virtio-fs storage telling whether the share has
+// the extended attributes of the files
+const XATTR_DRIVER_OPTION: &str = "xattr=";
+
+// share_xattr returns whether the virtio-fs share has the extended
+// attributes of the files, none if the runtime doesn't tell.
+fn share_xattr(storage: &Storage) -> Result<Option<bool>> {
+    match storage
+        .driver_options
+        .iter()
+        .find_map(|o| o.strip_prefix(XATTR_DRIVER_OPTION))
+    {
+        None => Ok(None),
+        Some("on") => Ok(Some(true)),
+        Some("off") => Ok(Some(false)),
+        Some(v) => Err(anyhow!(
+            "invalid xattr option {} of storage {}",
+            v,
+            storage.mount_point
+        )),
+    }
+}
+
 // virtiofs_storage_handler handles the storage for virtio-fs.
 #[instrument]
 async fn virtiofs_storage_handler(
@@ -491,6 +530,15 @@ async fn virtiofs_storage_handler(
     storage: &Storage,
     _sandbox: Arc<Mutex<Sandbox>>,
 ) -> Result<String> {
+    // the SELinux labels of the files are lost without their extended
+    // attributes, the containers relying on them fail to access them
+    if share_xattr(storage)? == Some(false) && rustjail::selinux::is_enabled().unwrap_or(false) {
+        warn!(
+            logger,
+            "the extended attributes of {} are disabled, the SELinux labels of its files aren't shared",
+            storage.mount_point
+        );
+    }
     common_storage_handler(logger, storage)
 }
 
@@ -567,7 +615,14 @@ fn common_storage_handler(logger: &Logger, storage: &Storage) -> Result<String>
     // Mount the storage device.
     let mount_point = storage.mount_point.to_string();
 
-    mount_storage(logger, storage)?;
+    // the encrypted block devices are mounted decrypted
+    let storage = &crypt::decrypted_storage(logger, storage)?;
+    if let Err(e) = mount_storage(logger, storage) {
+        if let Err(err) = crypt::close_encrypted_device(logger, &mount_point) {
+            warn!(logger, "failed to close encrypted device: {:?}", err);
+        }
+        return Err(e);
+    }
     set_ownership(logger, storage)?;
     Ok(mount_point)
 }
@@ -587,6 +642,18 @@ async fn nvdimm_storage_handler(
     common_storage_handler(logger, &storage)
 }
 
+// image_guest_pull_storage_handler pulls the image named by the storage
+// source in the guest, and mounts its rootfs at the mount point.
+#[instrument]
+async fn image_guest_pull_storage_handler(
+    logger: &Logger,
+    storage: &Storage,
+    _sandbox: Arc<Mutex<Sandbox>>,
+) -> Result<String> {
+    let storage = image::pull_image(logger, storage).await?;
+    common_storage_handler(logger, &storage)
+}
+
 async fn bind_watcher_storage_handler(
     logger: &Logger,
     storage: &Storage,
@@ -798,6 +865,9 @@ pub async fn add_storages(
     cid: Option<String>,
 ) -> Result<Vec<String>> {
     let mut mount_list = Vec::new();
+    // the storages taken by this request, released if one fails so that the
+    // runtime could retry it, e.g. with a volume degraded
+    let mut taken: Vec<String> = Vec::new();
 
     for storage in storages {
         let handler_name = storage.driver.clone();
@@ -809,6 +879,7 @@ pub async fn add_storages(
             let mut sb = sandbox.lock().await;
             let new_storage = sb.set_sandbox_storage(&storage.mount_point);
             if !new_storage {
+                taken.push(storage.mount_point.clone());
                 continue;
             }
         }
@@ -836,11 +907,14 @@ pub async fn add_storages(
                 virtio_scsi_storage_handler(&logger, &storage, sandbox.clone()).await
             }
             DRIVER_NVDIMM_TYPE => nvdimm_storage_handler(&logger, &storage, sandbox.clone()).await,
+            DRIVER_IMAGE_GUEST_PULL_TYPE => {
+                image_guest_pull_storage_handler(&logger, &storage, sandbox.clone()).await
+            }
             DRIVER_WATCHABLE_BIND_TYPE => {
-                bind_watcher_storage_handler(&logger, &storage, sandbox.clone(), cid.clone())
-                    .await?;
                 // Don't register watch mounts, they're handled separately by the watcher.
-                Ok(String::new())
+                bind_watcher_storage_handler(&logger, &storage, sandbox.clone(), cid.clone())
+                    .await
+                    .map(|_| String::new())
             }
             _ => {
                 return Err(anyhow!(
@@ -860,10 +934,17 @@ pub async fn add_storages(
                 sb.unset_sandbox_storage(&storage.mount_point)
                     .map_err(|e| warn!(logger, "fail to unset sandbox storage {:?}", e))
                     .ok();
-                return Err(e);
+                for mount_point in taken.iter().rev() {
+                    sb.unset_and_remove_sandbox_storage(mount_point)
+                        .map_err(|e| warn!(logger, "fail to release sandbox storage {:?}", e))
+                        .ok();
+                }
+                // the runtime finds the storage failed by its mount point
+                return Err(e).context(format!("add storage {}", storage.mount_point));
             }
             Ok(m) => m,
         };
+        taken.push(storage.mount_point.clone());
 
         if !mount_point.is_empty() {
             mount_list.push(mount_point);
@@ -1256,6 +1337,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_share_xattr() {
+        let mut storage = Storage {
+            mount_point: "/run/kata-containers/shared/containers/".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(share_xattr(&storage).unwrap(), None);
+        storage.driver_options = vec!["xattr=on".to_string()];
+        assert_eq!(share_xattr(&storage).unwrap(), Some(true));
+        storage.driver_options = vec!["xattr=off".to_string()];
+        assert_eq!(share_xattr(&storage).unwrap(), Some(false));
+        storage.driver_options = vec!["xattr=yes".to_string()];
+        assert!(share_xattr(&storage).is_err());
+    }
+
     #[test]
     fn test_is_mounted() {
         assert!(is_mounted("/proc").unwrap());
@@ -130,6 +130,12 @@ impl Handle {
             request.name(link.name()).up().execute().await?;
         }
 
+        // the virtio-net device may come up with a single queue pair enabled
+        if iface.queues > 1 {
+            set_combined_channels(&iface.name, iface.queues)
+                .with_context(|| format!("set {} queues of {}", iface.queues, iface.name))?;
+        }
+
         Ok(())
     }
 
@@ -294,9 +300,16 @@ impl Handle {
 
             let mut route = Route {
                 scope: msg.header.scope as _,
+                flags: (msg.header.flags & packet::RouteFlags::RTNH_F_ONLINK).bits(),
                 ..Default::default()
             };
 
+            for nla in msg.nlas.iter() {
+                if let packet::nlas::route::Nla::Priority(metric) = nla {
+                    route.metric = *metric;
+                }
+            }
+
             if let Some((ip, mask)) = msg.destination_prefix() {
                 route.dest = format!("{}/{}", ip, mask);
             }
@@ -340,7 +353,10 @@ impl Handle {
         let list = a.iter().chain(&b);
 
         for route in list {
-            let link = self.find_link(LinkFilter::Name(&route.device)).await?;
+            let link = self
+                .find_link(LinkFilter::Name(&route.device))
+                .await
+                .with_context(|| format!("Failed to find link of route to {}", route.dest()))?;
 
             const MAIN_TABLE: u8 = packet::constants::RT_TABLE_MAIN;
             const UNICAST: u8 = packet::constants::RTN_UNICAST;
@@ -351,7 +367,7 @@ impl Handle {
             use packet::nlas::route::Nla;
 
             // Build a common indeterminate ip request
-            let request = self
+            let mut request = self
                 .handle
                 .route()
                 .add()
@@ -360,6 +376,18 @@ impl Handle {
                 .protocol(BOOT_PROT)
                 .scope(scope);
 
+            // the gateway of an onlink route isn't on the subnet of the link
+            if route.flags & packet::RouteFlags::RTNH_F_ONLINK.bits() != 0 {
+                request
+                    .message_mut()
+                    .header
+                    .flags
+                    .insert(packet::RouteFlags::RTNH_F_ONLINK);
+            }
+            if route.metric > 0 {
+                request.message_mut().nlas.push(Nla::Priority(route.metric));
+            }
+
             // `rtnetlink` offers a separate request builders for different IP versions (IP v4 and v6).
             // This if branch is a bit clumsy because it does almost the same.
             if route.family() == IPFamily::v6 {
@@ -600,7 +628,8 @@ impl Handle {
             },
         };
 
-        // Send request and ACK
+        // Send request and ACK, the neighbor is replaced if it exists so that
+        // the neighbors could be pushed again by the runtime
         let mut req = NetlinkMessage::from(RtnlMessage::NewNeighbour(message));
         req.header.flags = NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE;
 
@@ -615,6 +644,70 @@ impl Handle {
     }
 }
 
+const SIOCETHTOOL: libc::c_ulong = 0x8946;
+const ETHTOOL_GCHANNELS: u32 = 0x3c;
+const ETHTOOL_SCHANNELS: u32 = 0x3d;
+
+#[repr(C)]
+#[derive(Default)]
+struct EthtoolChannels {
+    cmd: u32,
+    max_rx: u32,
+    max_tx: u32,
+    max_other: u32,
+    max_combined: u32,
+    rx_count: u32,
+    tx_count: u32,
+    other_count: u32,
+    combined_count: u32,
+}
+
+#[repr(C)]
+struct EthtoolRequest {
+    ifr_name: [u8; libc::IFNAMSIZ],
+    ifr_data: *mut EthtoolChannels,
+}
+
+// set_combined_channels enables the queue pairs of the interface, as
+// `ethtool -L <name> combined <queues>` does, up to the ones the device has.
+fn set_combined_channels(name: &str, queues: u32) -> Result<()> {
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(anyhow!("interface name {} too long", name));
+    }
+    let mut channels = EthtoolChannels {
+        cmd: ETHTOOL_GCHANNELS,
+        ..Default::default()
+    };
+    let mut req = EthtoolRequest {
+        ifr_name: [0; libc::IFNAMSIZ],
+        ifr_data: &mut channels,
+    };
+    req.ifr_name[..name.len()].copy_from_slice(name.as_bytes());
+
+    let fd = nix::sys::socket::socket(
+        nix::sys::socket::AddressFamily::Inet,
+        nix::sys::socket::SockType::Datagram,
+        nix::sys::socket::SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    let ethtool = |req: &mut EthtoolRequest| {
+        Errno::result(unsafe { libc::ioctl(fd, SIOCETHTOOL as _, req as *mut EthtoolRequest) })
+    };
+
+    let result = ethtool(&mut req).context("get channels").and_then(|_| {
+        let count = queues.min(channels.max_combined);
+        if count <= 1 || count == channels.combined_count {
+            return Ok(());
+        }
+        channels.cmd = ETHTOOL_SCHANNELS;
+        channels.combined_count = count;
+        req.ifr_data = &mut channels;
+        ethtool(&mut req).map(|_| ()).context("set channels")
+    });
+    let _ = nix::unistd::close(fd);
+    result
+}
+
 fn format_address(data: &[u8]) -> Result<String> {
     match data.len() {
         4 => {
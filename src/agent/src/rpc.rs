@@ -8,6 +8,7 @@ use rustjail::{pipestream::PipeStream, process::StreamType};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf};
 use tokio::sync::Mutex;
 
+use std::collections::HashMap;
 use std::ffi::CString;
 use std::io;
 use std::path::Path;
@@ -51,7 +52,8 @@ use nix::unistd::{self, Pid};
 use rustjail::process::ProcessOperations;
 
 use crate::device::{
-    add_devices, get_virtio_blk_pci_device_name, update_device_cgroup, update_env_pci,
+    add_devices, get_virtio_blk_pci_device_name, get_virtio_mmio_device_name, update_device_cgroup,
+    update_env_pci, update_weight_devices,
 };
 use crate::linux_abi::*;
 use crate::metrics::get_metrics;
@@ -86,6 +88,14 @@ use std::path::PathBuf;
 
 const CONTAINER_BASE: &str = "/run/kata-containers";
 const MODPROBE_PATH: &str = "/sbin/modprobe";
+const SYSFS_MODULE_PATH: &str = "/sys/module";
+const RESIZE2FS_PATH: &str = "/sbin/resize2fs";
+const PROC_SYS: &str = "/proc/sys";
+// the prefix of the sysctls SetSysctls accepts, the network ones of the pod
+const NET_SYSCTL_PREFIX: &str = "net.";
+const XFS_GROWFS_PATH: &str = "/sbin/xfs_growfs";
+const PROC_SELF_MOUNTS: &str = "/proc/self/mounts";
+const SYSFS_BLOCK_PATH: &str = "/sys/class/block";
 
 /// the iptables seriers binaries could appear either in /sbin
 /// or /usr/sbin, we need to check both of them
@@ -180,7 +190,7 @@ impl AgentService {
         // updates the devices listed in the OCI spec, so that they actually
         // match real devices inside the VM. This step is necessary since we
         // cannot predict everything from the caller.
-        add_devices(&req.devices.to_vec(), &mut oci, &self.sandbox).await?;
+        add_devices(&cid, &req.devices.to_vec(), &mut oci, &self.sandbox).await?;
 
         // Both rootfs and volumes (invoked with --volume for instance) will
         // be processed the same way. The idea is to always mount any provided
@@ -189,7 +199,7 @@ impl AgentService {
         // After all those storages have been processed, no matter the order
         // here, the agent will rely on rustjail (using the oci.Mounts
         // list) to bind mount all of them inside the container.
-        let m = add_storages(
+        let mut m = add_storages(
             sl!(),
             req.storages.to_vec(),
             self.sandbox.clone(),
@@ -199,6 +209,15 @@ impl AgentService {
         {
             sandbox = self.sandbox.clone();
             s = sandbox.lock().await;
+            // the storages shared with other containers are only referred
+            // to by the mounts, take a reference on them so that they outlive
+            // the container which added them.
+            for mount in oci.mounts.iter() {
+                if !m.contains(&mount.source) && s.storages.contains_key(&mount.source) {
+                    s.set_sandbox_storage(&mount.source);
+                    m.push(mount.source.clone());
+                }
+            }
             s.container_mounts.insert(cid.clone(), m);
         }
 
@@ -739,6 +758,15 @@ impl agent_ttrpc::AgentService for AgentService {
         let s = Arc::clone(&self.sandbox);
         let mut sandbox = s.lock().await;
 
+        // the weights of the block devices are given for the host numbers
+        let oci_res = res.as_ref().map(|res| {
+            let mut oci_res = rustjail::resources_grpc_to_oci(res);
+            if let Some(block_io) = oci_res.block_io.as_mut() {
+                update_weight_devices(block_io, &sandbox.block_devnums());
+            }
+            oci_res
+        });
+
         let ctr = sandbox.get_container(&cid).ok_or_else(|| {
             ttrpc_error!(
                 ttrpc::Code::INVALID_ARGUMENT,
@@ -748,8 +776,7 @@ impl agent_ttrpc::AgentService for AgentService {
 
         let resp = Empty::new();
 
-        if let Some(res) = res.as_ref() {
-            let oci_res = rustjail::resources_grpc_to_oci(res);
+        if let Some(oci_res) = oci_res {
             match ctr.set(oci_res) {
                 Err(e) => {
                     return Err(ttrpc_error!(ttrpc::Code::INTERNAL, e));
@@ -1379,6 +1406,72 @@ impl agent_ttrpc::AgentService for AgentService {
         Ok(Empty::new())
     }
 
+    async fn set_sysctls(
+        &self,
+        ctx: &TtrpcContext,
+        req: protocols::agent::SetSysctlsRequest,
+    ) -> ttrpc::Result<Empty> {
+        trace_rpc_call!(ctx, "set_sysctls", req);
+        is_allowed!(req);
+
+        do_set_sysctls(Path::new(PROC_SYS), &req.sysctls)
+            .map_err(|e| ttrpc_error!(ttrpc::Code::INVALID_ARGUMENT, e))?;
+
+        Ok(Empty::new())
+    }
+
+    async fn setup_network(
+        &self,
+        ctx: &TtrpcContext,
+        req: protocols::agent::SetupNetworkRequest,
+    ) -> ttrpc::Result<Empty> {
+        trace_rpc_call!(ctx, "setup_network", req);
+        is_allowed!(req);
+
+        // the error tells the entry failed, the ones before it are applied
+        let mut sandbox = self.sandbox.lock().await;
+        for (i, interface) in req.interfaces.iter().enumerate() {
+            sandbox
+                .rtnl
+                .update_interface(interface)
+                .await
+                .map_err(|e| {
+                    ttrpc_error!(
+                        ttrpc::Code::INTERNAL,
+                        format!("update interface {} ({}): {:?}", i, interface.name, e),
+                    )
+                })?;
+        }
+
+        if let Some(neighbors) = req.neighbors.into_option() {
+            sandbox
+                .rtnl
+                .add_arp_neighbors(neighbors.ARPNeighbors)
+                .await
+                .map_err(|e| {
+                    ttrpc_error!(
+                        ttrpc::Code::INTERNAL,
+                        format!("Failed to add ARP neighbours: {:?}", e),
+                    )
+                })?;
+        }
+
+        if let Some(routes) = req.routes.into_option() {
+            sandbox
+                .rtnl
+                .update_routes(routes.Routes)
+                .await
+                .map_err(|e| {
+                    ttrpc_error!(
+                        ttrpc::Code::INTERNAL,
+                        format!("Failed to update routes: {:?}", e),
+                    )
+                })?;
+        }
+
+        Ok(Empty::new())
+    }
+
     async fn online_cpu_mem(
         &self,
         ctx: &TtrpcContext,
@@ -1396,6 +1489,23 @@ impl agent_ttrpc::AgentService for AgentService {
         Ok(Empty::new())
     }
 
+    async fn offline_cpu(
+        &self,
+        ctx: &TtrpcContext,
+        req: protocols::agent::OfflineCPURequest,
+    ) -> ttrpc::Result<Empty> {
+        is_allowed!(req);
+        let s = Arc::clone(&self.sandbox);
+        let sandbox = s.lock().await;
+        trace_rpc_call!(ctx, "offline_cpu", req);
+
+        sandbox
+            .offline_cpus(&req)
+            .map_err(|e| ttrpc_error!(ttrpc::Code::INTERNAL, e))?;
+
+        Ok(Empty::new())
+    }
+
     async fn reseed_random_dev(
         &self,
         ctx: &TtrpcContext,
@@ -1569,6 +1679,24 @@ impl agent_ttrpc::AgentService for AgentService {
         Ok(resp)
     }
 
+    async fn resize_volume(
+        &self,
+        ctx: &TtrpcContext,
+        req: protocols::agent::ResizeVolumeRequest,
+    ) -> ttrpc::Result<Empty> {
+        trace_rpc_call!(ctx, "resize_volume", req);
+        is_allowed!(req);
+
+        info!(
+            sl!(),
+            "resize volume {} to {} bytes", req.volume_guest_path, req.size
+        );
+        grow_volume(&req.volume_guest_path, req.size)
+            .map_err(|e| ttrpc_error!(ttrpc::Code::FAILED_PRECONDITION, e))?;
+
+        Ok(Empty::new())
+    }
+
     async fn add_swap(
         &self,
         ctx: &TtrpcContext,
@@ -1583,6 +1711,68 @@ impl agent_ttrpc::AgentService for AgentService {
 
         Ok(Empty::new())
     }
+
+    async fn remove_storage(
+        &self,
+        ctx: &TtrpcContext,
+        req: protocols::agent::RemoveStorageRequest,
+    ) -> ttrpc::Result<Empty> {
+        trace_rpc_call!(ctx, "remove_storage", req);
+        is_allowed!(req);
+
+        let mut sandbox = self.sandbox.lock().await;
+        let path = req.mount_point.as_str();
+        if !sandbox.storages.contains_key(path) {
+            return Err(ttrpc_error!(
+                ttrpc::Code::NOT_FOUND,
+                format!("Sandbox storage with path {} not found", path)
+            ));
+        }
+
+        // the storage stays mounted through the mounts of the container, so
+        // they go first. Neither is forced to umount, the storage still used
+        // by the container is left to the caller to retry.
+        if let Some(pid) = sandbox
+            .get_container(&req.container_id)
+            .map(|c| c.init_process_pid)
+        {
+            umount_container_paths(pid, &req.container_paths)
+                .map_err(|e| ttrpc_error!(storage_error_code(&e), e))?;
+        }
+
+        let last = sandbox
+            .unset_sandbox_storage(path)
+            .map_err(|e| ttrpc_error!(ttrpc::Code::NOT_FOUND, e))?;
+        if !last {
+            return Ok(Empty::new());
+        }
+
+        if let Err(e) = sandbox.remove_sandbox_storage(path) {
+            sandbox.set_sandbox_storage(path);
+            return Err(ttrpc_error!(storage_error_code(&e), e));
+        }
+
+        Ok(Empty::new())
+    }
+
+    async fn load_kernel_modules(
+        &self,
+        ctx: &TtrpcContext,
+        req: protocols::agent::LoadKernelModulesRequest,
+    ) -> ttrpc::Result<Empty> {
+        trace_rpc_call!(ctx, "load_kernel_modules", req);
+        is_allowed!(req);
+
+        for m in req.modules.iter() {
+            if is_kernel_module_present(SYSFS_MODULE_PATH, &m.name) {
+                info!(sl!(), "kernel module {} is already present", m.name);
+                continue;
+            }
+            load_kernel_module(m).map_err(|e| ttrpc_error!(ttrpc::Code::NOT_FOUND, e))?;
+        }
+
+        Ok(Empty::new())
+    }
 }
 
 #[derive(Clone)]
@@ -1686,6 +1876,134 @@ fn get_volume_inode_stats(path: &str) -> Result<VolumeUsage> {
     Ok(usage)
 }
 
+// storage_error_code tells the caller to retry later if the storage is busy.
+fn storage_error_code(e: &anyhow::Error) -> ttrpc::Code {
+    match e.root_cause().downcast_ref::<Errno>() {
+        Some(Errno::EBUSY) => ttrpc::Code::FAILED_PRECONDITION,
+        _ => ttrpc::Code::INTERNAL,
+    }
+}
+
+// umount_container_paths unmounts the paths of the container in the mount
+// namespace of its init process, from a thread of its own so that the
+// agent stays in its namespace. The umount isn't forced, it fails with
+// EBUSY while the container uses the path. The paths unmounted already,
+// e.g. by an earlier attempt, and the container exited are skipped.
+fn umount_container_paths(pid: pid_t, paths: &[String]) -> Result<()> {
+    if paths.is_empty() || pid <= 0 {
+        return Ok(());
+    }
+    let mnt_ns = match fs::File::open(format!("/proc/{}/ns/mnt", pid)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context("open the mount namespace of the container"),
+    };
+    let paths = paths.to_vec();
+
+    std::thread::spawn(move || -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        nix::sched::unshare(nix::sched::CloneFlags::CLONE_FS).context("unshare fs")?;
+        nix::sched::setns(mnt_ns.as_raw_fd(), nix::sched::CloneFlags::CLONE_NEWNS)
+            .context("enter the mount namespace of the container")?;
+        for path in paths.iter() {
+            match nix::mount::umount(path.as_str()) {
+                // not a mount point anymore
+                Ok(()) | Err(Errno::EINVAL) | Err(Errno::ENOENT) => {}
+                Err(e) => return Err(e).with_context(|| format!("umount {}", path)),
+            }
+        }
+        Ok(())
+    })
+    .join()
+    .map_err(|_| anyhow!("umount the paths of the container panicked"))?
+}
+
+// VolumeMount is the mount of a volume in the guest, as listed in the mounts
+// of the agent.
+#[derive(Debug, PartialEq)]
+struct VolumeMount {
+    device: String,
+    fs_type: String,
+    read_only: bool,
+}
+
+fn find_volume_mount(mounts: &str, mount_point: &str) -> Option<VolumeMount> {
+    // the last one is on top of the others at the same mount point
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 4 || fields[1] != mount_point {
+                return None;
+            }
+            Some(VolumeMount {
+                device: fields[0].to_string(),
+                fs_type: fields[2].to_string(),
+                read_only: fields[3].split(',').any(|o| o == "ro"),
+            })
+        })
+        .last()
+}
+
+// grow_fs_command returns the command growing the mounted filesystem to
+// the size of its device, and its argument.
+fn grow_fs_command(m: &VolumeMount, mount_point: &str) -> Result<(&'static str, String)> {
+    match m.fs_type.as_str() {
+        "ext3" | "ext4" => Ok((RESIZE2FS_PATH, m.device.clone())),
+        "xfs" => Ok((XFS_GROWFS_PATH, mount_point.to_string())),
+        fs_type => Err(anyhow!(
+            "filesystem {} of volume {} doesn't support online growth",
+            fs_type,
+            mount_point
+        )),
+    }
+}
+
+// grow_volume grows the filesystem of the volume mounted at the path, once
+// the hypervisor has grown its block device to the size.
+fn grow_volume(path: &str, size: u64) -> Result<()> {
+    let mounts = fs::read_to_string(PROC_SELF_MOUNTS).context("read mounts")?;
+    let m =
+        find_volume_mount(&mounts, path).ok_or_else(|| anyhow!("volume {} isn't mounted", path))?;
+    if m.read_only {
+        return Err(anyhow!("volume {} is read-only", path));
+    }
+
+    // the devices mapped, e.g. decrypted, aren't listed by their name
+    if let Some(name) = Path::new(&m.device).file_name() {
+        let sectors = Path::new(SYSFS_BLOCK_PATH).join(name).join("size");
+        if let Ok(sectors) = fs::read_to_string(sectors) {
+            let device_size = sectors.trim().parse::<u64>().unwrap_or_default() * 512;
+            if device_size < size {
+                return Err(anyhow!(
+                    "device {} of volume {} is {} bytes, it hasn't grown to {} bytes",
+                    m.device,
+                    path,
+                    device_size,
+                    size
+                ));
+            }
+        }
+    }
+
+    let (cmd, arg) = grow_fs_command(&m, path)?;
+    let output = Command::new(cmd)
+        .arg(&arg)
+        .output()
+        .with_context(|| format!("run {}", cmd))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} {} failed: {}",
+            cmd,
+            arg,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(())
+}
+
 pub fn have_seccomp() -> bool {
     if cfg!(feature = "seccomp") {
         return true;
@@ -1817,7 +2135,9 @@ fn remove_container_resources(sandbox: &mut Sandbox, cid: &str) -> Result<()> {
         }
     }
 
-    for m in cmounts.iter() {
+    // in the reverse order of mounting, a storage may be mounted on the
+    // ones before it
+    for m in cmounts.iter().rev() {
         if let Err(err) = sandbox.unset_and_remove_sandbox_storage(m) {
             error!(
                 sl!(),
@@ -1829,6 +2149,7 @@ fn remove_container_resources(sandbox: &mut Sandbox, cid: &str) -> Result<()> {
     }
 
     sandbox.container_mounts.remove(cid);
+    sandbox.devnums.remove(cid);
     sandbox.containers.remove(cid);
     Ok(())
 }
@@ -1920,6 +2241,33 @@ fn do_set_guest_date_time(sec: i64, usec: i64) -> Result<()> {
     Ok(())
 }
 
+// sysctl_path returns the path of the network sysctl under the root of the
+// sysctls, the name is checked not to get out of net/
+fn sysctl_path(proc_sys: &Path, key: &str) -> Result<PathBuf> {
+    if !key.starts_with(NET_SYSCTL_PREFIX)
+        || key.contains('/')
+        || key.split('.').any(|c| c.is_empty())
+    {
+        return Err(anyhow!("invalid network sysctl {}", key));
+    }
+    Ok(proc_sys.join(key.replace('.', "/")))
+}
+
+// do_set_sysctls writes the network sysctls, all of them are checked before
+// any is written
+fn do_set_sysctls(proc_sys: &Path, sysctls: &HashMap<String, String>) -> Result<()> {
+    let paths = sysctls
+        .iter()
+        .map(|(key, value)| Ok((key, sysctl_path(proc_sys, key)?, value)))
+        .collect::<Result<Vec<_>>>()?;
+    for (key, path, value) in paths {
+        fs::write(&path, value.as_bytes())
+            .with_context(|| format!("failed to set sysctl {} to {}", key, value))?;
+        info!(sl!(), "set sysctl {} to {}", key, value);
+    }
+    Ok(())
+}
+
 fn do_copy_file(req: &CopyFileRequest) -> Result<()> {
     let path = PathBuf::from(req.path.as_str());
 
@@ -1974,12 +2322,20 @@ fn do_copy_file(req: &CopyFileRequest) -> Result<()> {
 }
 
 async fn do_add_swap(sandbox: &Arc<Mutex<Sandbox>>, req: &AddSwapRequest) -> Result<()> {
-    let mut slots = Vec::new();
-    for slot in &req.PCIPath {
-        slots.push(pci::SlotFn::new(*slot, 0)?);
-    }
-    let pcipath = pci::Path::new(slots)?;
-    let dev_name = get_virtio_blk_pci_device_name(sandbox, &pcipath).await?;
+    let dev_name = if req.PCIPath.is_empty() {
+        // virtio-mmio block device, the guest path is known by the runtime
+        if !Path::new(&req.device_path).exists() {
+            get_virtio_mmio_device_name(sandbox, &req.device_path).await?;
+        }
+        req.device_path.clone()
+    } else {
+        let mut slots = Vec::new();
+        for slot in &req.PCIPath {
+            slots.push(pci::SlotFn::new(*slot, 0)?);
+        }
+        let pcipath = pci::Path::new(slots)?;
+        get_virtio_blk_pci_device_name(sandbox, &pcipath).await?
+    };
 
     let c_str = CString::new(dev_name)?;
     let ret = unsafe { libc::swapon(c_str.as_ptr() as *const c_char, 0) };
@@ -2048,6 +2404,15 @@ pub fn setup_bundle(cid: &str, spec: &mut Spec) -> Result<PathBuf> {
     Ok(olddir)
 }
 
+// the loaded modules and the built-in ones with parameters are in sysfs,
+// modprobe fails on the built-in ones without modules.builtin in the guest
+fn is_kernel_module_present(sysfs_module_path: &str, name: &str) -> bool {
+    !name.is_empty()
+        && Path::new(sysfs_module_path)
+            .join(name.replace('-', "_"))
+            .exists()
+}
+
 fn load_kernel_module(module: &protocols::agent::KernelModule) -> Result<()> {
     if module.name.is_empty() {
         return Err(anyhow!("Kernel module name is empty"));
@@ -2151,6 +2516,41 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_grow_fs_command() {
+        let mounts = "/dev/vda1 / ext4 rw,relatime 0 0\n\
+            /dev/vdb /run/kata-containers/shared/containers/c1-data ext4 rw,relatime 0 0\n\
+            /dev/vdc /run/kata-containers/shared/containers/c1-logs xfs ro,relatime 0 0\n\
+            /dev/vdd /run/kata-containers/shared/containers/c1-vfat vfat rw 0 0\n";
+
+        let m =
+            find_volume_mount(mounts, "/run/kata-containers/shared/containers/c1-data").unwrap();
+        assert!(!m.read_only);
+        assert_eq!(
+            grow_fs_command(&m, "/run/kata-containers/shared/containers/c1-data").unwrap(),
+            (RESIZE2FS_PATH, "/dev/vdb".to_string())
+        );
+
+        // xfs is grown by its mount point
+        let m =
+            find_volume_mount(mounts, "/run/kata-containers/shared/containers/c1-logs").unwrap();
+        assert!(m.read_only);
+        assert_eq!(
+            grow_fs_command(&m, "/run/kata-containers/shared/containers/c1-logs")
+                .unwrap()
+                .1,
+            "/run/kata-containers/shared/containers/c1-logs"
+        );
+
+        let m =
+            find_volume_mount(mounts, "/run/kata-containers/shared/containers/c1-vfat").unwrap();
+        let err =
+            grow_fs_command(&m, "/run/kata-containers/shared/containers/c1-vfat").unwrap_err();
+        assert!(err.to_string().contains("doesn't support online growth"));
+
+        assert!(find_volume_mount(mounts, "/run/kata-containers/shared/containers/c2").is_none());
+    }
+
     #[test]
     fn test_load_kernel_module() {
         let mut m = protocols::agent::KernelModule {
@@ -2175,6 +2575,18 @@ mod tests {
         assert!(result.is_ok(), "load module should success");
     }
 
+    #[test]
+    fn test_is_kernel_module_present() {
+        let dir = tempdir().expect("failed to create tmpdir");
+        let path = dir.path().to_str().unwrap();
+        fs::create_dir(dir.path().join("nvme_core")).unwrap();
+
+        assert!(is_kernel_module_present(path, "nvme_core"));
+        assert!(is_kernel_module_present(path, "nvme-core"));
+        assert!(!is_kernel_module_present(path, "nvme"));
+        assert!(!is_kernel_module_present(path, ""));
+    }
+
     #[tokio::test]
     async fn test_append_guest_hooks() {
         let logger = slog::Logger::root(slog::Discard, o!());
@@ -2822,6 +3234,87 @@ OtherField:other
         assert_eq!(stats.available, available - 2);
     }
 
+    #[tokio::test]
+    async fn test_remove_storage_busy() {
+        skip_if_not_root!();
+
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let sandbox = Sandbox::new(&logger).unwrap();
+        let agent_service = Box::new(AgentService {
+            sandbox: Arc::new(Mutex::new(sandbox)),
+            init_mode: true,
+        });
+        let ctx = mk_ttrpc_context();
+
+        // the storage and the mount of the container on it
+        let dir = tempdir().unwrap();
+        let storage = dir.path().join("storage");
+        let container = dir.path().join("container");
+        fs::create_dir_all(&storage).unwrap();
+        fs::create_dir_all(&container).unwrap();
+        mount::mount(
+            Some("tmpfs"),
+            &storage,
+            Some("tmpfs"),
+            MsFlags::empty(),
+            None::<&str>,
+        )
+        .unwrap();
+        mount::mount(
+            Some(&storage),
+            &container,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .unwrap();
+        let storage_path = storage.to_str().unwrap().to_string();
+        agent_service
+            .sandbox
+            .lock()
+            .await
+            .set_sandbox_storage(&storage_path);
+
+        // the mount of the container is busy while a file is open in it
+        let pid = std::process::id() as pid_t;
+        let paths = vec![container.to_str().unwrap().to_string()];
+        let file = fs::File::create(container.join("file")).unwrap();
+        let err = umount_container_paths(pid, &paths).unwrap_err();
+        assert_eq!(storage_error_code(&err), ttrpc::Code::FAILED_PRECONDITION);
+        drop(file);
+        umount_container_paths(pid, &paths).unwrap();
+        // the one unmounted already is skipped
+        umount_container_paths(pid, &paths).unwrap();
+
+        // so is the storage, which is kept for the caller to retry
+        let file = fs::File::create(storage.join("file")).unwrap();
+        let req = protocols::agent::RemoveStorageRequest {
+            mount_point: storage_path.clone(),
+            ..Default::default()
+        };
+        match agent_service.remove_storage(&ctx, req.clone()).await {
+            Err(ttrpc::Error::RpcStatus(s)) => {
+                assert_eq!(s.code.value(), ttrpc::Code::FAILED_PRECONDITION as i32)
+            }
+            r => panic!("expected the storage to be busy, got {:?}", r),
+        }
+        assert!(agent_service
+            .sandbox
+            .lock()
+            .await
+            .storages
+            .contains_key(&storage_path));
+
+        drop(file);
+        agent_service.remove_storage(&ctx, req).await.unwrap();
+        assert!(!agent_service
+            .sandbox
+            .lock()
+            .await
+            .storages
+            .contains_key(&storage_path));
+    }
+
     #[tokio::test]
     async fn test_ip_tables() {
         skip_if_not_root!();
@@ -3001,4 +3494,36 @@ COMMIT
             "We should see the resulting rule"
         );
     }
+
+    #[test]
+    fn test_do_set_sysctls() {
+        let dir = tempdir().unwrap();
+        let ipv4 = dir.path().join("net/ipv4");
+        fs::create_dir_all(&ipv4).unwrap();
+
+        let sysctls: HashMap<String, String> = vec![(
+            "net.ipv4.ip_unprivileged_port_start".to_string(),
+            "0".to_string(),
+        )]
+        .into_iter()
+        .collect();
+        do_set_sysctls(dir.path(), &sysctls).unwrap();
+        assert_eq!(
+            fs::read_to_string(ipv4.join("ip_unprivileged_port_start")).unwrap(),
+            "0"
+        );
+
+        // only the network ones, without getting out of net/
+        for key in [
+            "kernel.shm_rmid_forced",
+            "net..ipv4",
+            "net.ipv4/../../x",
+            "net.",
+        ] {
+            let sysctls: HashMap<String, String> = vec![(key.to_string(), "1".to_string())]
+                .into_iter()
+                .collect();
+            assert!(do_set_sysctls(dir.path(), &sysctls).is_err(), "{}", key);
+        }
+    }
 }
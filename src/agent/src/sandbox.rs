@@ -3,6 +3,8 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use crate::crypt;
+use crate::image;
 use crate::linux_abi::*;
 use crate::mount::{get_mount_fs_type, remove_mounts, TYPE_ROOTFS};
 use crate::namespace::Namespace;
@@ -14,7 +16,7 @@ use crate::watcher::BindWatcher;
 use anyhow::{anyhow, Context, Result};
 use libc::pid_t;
 use oci::{Hook, Hooks};
-use protocols::agent::OnlineCPUMemRequest;
+use protocols::agent::{OfflineCPURequest, OnlineCPUMemRequest};
 use regex::Regex;
 use rustjail::cgroups as rustjail_cgroups;
 use rustjail::container::BaseContainer;
@@ -60,6 +62,9 @@ pub struct Sandbox {
     pub event_tx: Option<Sender<String>>,
     pub bind_watcher: BindWatcher,
     pub pcimap: HashMap<pci::Address, pci::Address>,
+    // the guest numbers of the block devices of each container by their
+    // host ones
+    pub devnums: HashMap<String, HashMap<(i64, i64), (i64, i64)>>,
 }
 
 impl Sandbox {
@@ -93,6 +98,7 @@ impl Sandbox {
             event_tx: Some(tx),
             bind_watcher: BindWatcher::new(),
             pcimap: HashMap::new(),
+            devnums: HashMap::new(),
         })
     }
 
@@ -151,6 +157,10 @@ impl Sandbox {
     pub fn remove_sandbox_storage(&self, path: &str) -> Result<()> {
         let mounts = vec![path.to_string()];
         remove_mounts(&mounts)?;
+        // the dm-crypt mapping of an encrypted volume is closed once unmounted
+        crypt::close_encrypted_device(&self.logger, path)?;
+        // so is the image pulled for a rootfs
+        image::remove_image(&self.logger, path)?;
         // "remove_dir" will fail if the mount point is backed by a read-only filesystem.
         // This is the case with the device mapper snapshotter, where we mount the block device directly
         // at the underlying sandbox path which was provided from the base RO kataShared path from the host.
@@ -226,6 +236,16 @@ impl Sandbox {
         self.containers.get_mut(id)
     }
 
+    /// block_devnums returns the guest numbers of the block devices of all
+    /// the containers by their host ones, a container may give weights to
+    /// the devices of the others.
+    pub fn block_devnums(&self) -> HashMap<(i64, i64), (i64, i64)> {
+        self.devnums
+            .values()
+            .flat_map(|devnums| devnums.iter().map(|(host, guest)| (*host, *guest)))
+            .collect()
+    }
+
     pub fn find_process(&mut self, pid: pid_t) -> Option<&mut Process> {
         for (_, c) in self.containers.iter_mut() {
             if c.processes.get(&pid).is_some() {
@@ -275,6 +295,23 @@ impl Sandbox {
             return Ok(());
         }
 
+        self.update_cpusets()
+    }
+
+    #[instrument]
+    pub fn offline_cpus(&self, req: &OfflineCPURequest) -> Result<()> {
+        if req.nb_cpus == 0 {
+            return Ok(());
+        }
+
+        offline_cpus(&self.logger, req.nb_cpus as i32)?;
+
+        self.update_cpusets()
+    }
+
+    // update_cpusets resets the cpuset of every container to the cpus
+    // online in the guest after some of them were onlined or offlined.
+    fn update_cpusets(&self) -> Result<()> {
         let guest_cpuset = rustjail_cgroups::fs::get_guest_cpuset()?;
 
         for (_, ctr) in self.containers.iter() {
@@ -461,6 +498,57 @@ fn online_cpus(logger: &Logger, num: i32) -> Result<i32> {
     ))
 }
 
+// offline_resources offlines the num online cpus with the highest ids
+// under path, cpu0 usually can't be offlined and is kept online anyway.
+#[instrument]
+fn offline_resources(logger: &Logger, path: &str, num: i32) -> Result<i32> {
+    let re = Regex::new(r"^cpu([0-9]+)$")?;
+    let mut cpus = Vec::new();
+
+    for e in fs::read_dir(path)? {
+        let entry = e?;
+        let name = entry.file_name();
+        let id = match re
+            .captures(name.to_str().unwrap_or_default())
+            .and_then(|c| c[1].parse::<u32>().ok())
+        {
+            Some(id) if id > 0 => id,
+            _ => continue,
+        };
+
+        let file = format!("{}/{}", entry.path().display(), SYSFS_ONLINE_FILE);
+        match fs::read_to_string(file.as_str()) {
+            Ok(c) if c.trim() == "1" => cpus.push((id, file)),
+            _ => continue,
+        }
+    }
+    cpus.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let mut count = 0;
+    for (_, file) in cpus.iter().take(num as usize) {
+        info!(logger, "{}", file.as_str());
+        fs::write(file.as_str(), "0").context(format!("offline {}", file))?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+#[instrument]
+fn offline_cpus(logger: &Logger, num: i32) -> Result<i32> {
+    let count = offline_resources(logger, SYSFS_CPU_ONLINE_PATH, num)?;
+    if count != num {
+        return Err(anyhow!(
+            "failed to offline {} CPU(s), only {} online",
+            num,
+            count
+        ));
+    }
+
+    info!(logger, "offline {} CPU(s)", num);
+    Ok(num)
+}
+
 #[instrument]
 fn online_memory(logger: &Logger) -> Result<()> {
     online_resources(logger, SYSFS_MEMORY_ONLINE_PATH, r"memory[0-9]+", -1)?;
@@ -1103,4 +1191,37 @@ mod tests {
             }
         }
     }
+
+    #[tokio::test]
+    async fn test_offline_resources() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let tmpdir = Builder::new().tempdir().unwrap();
+        let tmpdir_path = tmpdir.path().to_str().unwrap();
+
+        // cpu0 to cpu10 online but cpu9, and a directory without online file
+        for j in 0..11 {
+            let subdir_path = format!("{}/cpu{}", tmpdir_path, j);
+            fs::create_dir(&subdir_path).unwrap();
+            let online = if j == 9 { b"0" } else { b"1" };
+            let mut subfile =
+                File::create(format!("{}/{}", subdir_path, SYSFS_ONLINE_FILE)).unwrap();
+            subfile.write_all(online).unwrap();
+        }
+        fs::create_dir(format!("{}/cpufreq", tmpdir_path)).unwrap();
+
+        let online = |j: u32| {
+            fs::read_to_string(format!("{}/cpu{}/{}", tmpdir_path, j, SYSFS_ONLINE_FILE)).unwrap()
+        };
+
+        // the highest online cpus go first
+        assert_eq!(offline_resources(&logger, tmpdir_path, 2).unwrap(), 2);
+        assert_eq!(online(10), "0");
+        assert_eq!(online(8), "0");
+        assert_eq!(online(7), "1");
+
+        // cpu0 is never offlined
+        assert_eq!(offline_resources(&logger, tmpdir_path, 10).unwrap(), 7);
+        assert_eq!(online(1), "0");
+        assert_eq!(online(0), "1");
+    }
 }
@@ -280,6 +280,40 @@ pub fn bind_mount_unchecked<S: AsRef<Path>, D: AsRef<Path>>(
     Ok(())
 }
 
+/// Parse the propagation requested by the mount options, e.g. `rshared` of an OCI mount.
+///
+/// The last propagation option wins, `None` is returned if there's none.
+pub fn parse_propagation(options: &[String]) -> Option<MsFlags> {
+    options
+        .iter()
+        .filter_map(|opt| match opt.as_str() {
+            "shared" => Some(MsFlags::MS_SHARED),
+            "rshared" => Some(MsFlags::MS_SHARED | MsFlags::MS_REC),
+            "slave" => Some(MsFlags::MS_SLAVE),
+            "rslave" => Some(MsFlags::MS_SLAVE | MsFlags::MS_REC),
+            "private" => Some(MsFlags::MS_PRIVATE),
+            "rprivate" => Some(MsFlags::MS_PRIVATE | MsFlags::MS_REC),
+            "unbindable" => Some(MsFlags::MS_UNBINDABLE),
+            "runbindable" => Some(MsFlags::MS_UNBINDABLE | MsFlags::MS_REC),
+            _ => None,
+        })
+        .last()
+}
+
+/// Change the propagation of the existing mount at `dst`.
+///
+/// # Safety
+/// Caller needs to ensure safety of the `dst` to avoid possible file path based attacks.
+pub fn set_propagation<P: AsRef<Path>>(dst: P, propagation: MsFlags) -> Result<()> {
+    let dst = dst.as_ref();
+    if dst.is_empty() {
+        return Err(Error::NullMountPointPath);
+    }
+    let flags = propagation & (*PROPAGATION_FLAGS | MsFlags::MS_REC);
+    mount(Some(""), dst, Some(""), flags, Some(""))
+        .map_err(|e| Error::Mount(PathBuf::new(), dst.to_path_buf(), e))
+}
+
 /// Trait to mount a `kata_types::mount::Mount`.
 pub trait Mounter {
     /// Mount to the specified `target`.
@@ -1040,6 +1074,54 @@ mod tests {
         assert!(parse_mount_options(&options).is_err());
     }
 
+    #[test]
+    fn test_parse_propagation() {
+        assert_eq!(parse_propagation(&[]), None);
+        assert_eq!(
+            parse_propagation(&["rbind".to_string(), "ro".to_string()]),
+            None
+        );
+        assert_eq!(
+            parse_propagation(&["rbind".to_string(), "rshared".to_string()]),
+            Some(MsFlags::MS_SHARED | MsFlags::MS_REC)
+        );
+        assert_eq!(
+            parse_propagation(&["rprivate".to_string(), "slave".to_string()]),
+            Some(MsFlags::MS_SLAVE)
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn test_set_propagation() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let tmpdir2 = tempfile::tempdir().unwrap();
+
+        assert!(matches!(
+            set_propagation(Path::new(""), MsFlags::MS_SHARED),
+            Err(Error::NullMountPointPath)
+        ));
+
+        // the bind mount is a slave, or private if the source isn't shared
+        bind_mount_unchecked(tmpdir2.path(), tmpdir.path(), false).unwrap();
+        let dst = tmpdir.path().to_str().unwrap().to_string();
+        let optional_fields = || {
+            let mountinfo = fs::read_to_string("/proc/self/mountinfo").unwrap();
+            mountinfo
+                .lines()
+                .map(|l| l.split(' ').collect::<Vec<&str>>())
+                .filter(|f| f[4] == dst)
+                .last()
+                .map(|f| f[6..f.iter().position(|o| *o == "-").unwrap()].join(" "))
+                .unwrap()
+        };
+        assert!(!optional_fields().contains("shared:"));
+
+        set_propagation(tmpdir.path(), MsFlags::MS_SHARED | MsFlags::MS_REC).unwrap();
+        assert!(optional_fields().contains("shared:"));
+        umount_timeout(tmpdir.path().to_str().unwrap(), 0).unwrap();
+    }
+
     #[test]
     #[ignore]
     fn test_mount_at() {
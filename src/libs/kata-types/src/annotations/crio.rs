@@ -11,3 +11,4 @@ pub const SANDBOX: &str = "sandbox";
 pub const CONTAINER: &str = "container";
 
 pub const SANDBOX_ID_LABEL_KEY: &str = "io.kubernetes.cri-o.SandboxID";
+pub const IMAGE_NAME_KEY: &str = "io.kubernetes.cri-o.ImageName";
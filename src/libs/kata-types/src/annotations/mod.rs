@@ -20,7 +20,9 @@ use crate::config::hypervisor::get_hypervisor_plugin;
 use crate::config::TomlConfig;
 use crate::sl;
 
-use self::cri_containerd::{SANDBOX_CPU_PERIOD_KEY, SANDBOX_CPU_QUOTA_KEY, SANDBOX_MEM_KEY};
+use self::cri_containerd::{
+    SANDBOX_CPU_PERIOD_KEY, SANDBOX_CPU_QUOTA_KEY, SANDBOX_CPU_SHARE_KEY, SANDBOX_MEM_KEY,
+};
 
 /// CRI-containerd specific annotations.
 pub mod cri_containerd;
@@ -60,6 +62,31 @@ pub const KATA_ANNO_CONTAINER_RES_SWAPPINESS: &str =
 pub const KATA_ANNO_CONTAINER_RES_SWAP_IN_BYTES: &str =
     "io.katacontainers.container.resource.swap_in_bytes";
 
+/// A container annotation to specify the volumes, comma separated destinations in the container,
+/// which fall back to a bind from the shared directory, or to a copy, if the guest fails to mount
+/// them, as the volumes on the filesystem types of share_fs_fallback_fstypes.
+pub const KATA_ANNO_CONTAINER_SHARE_FS_FALLBACK: &str =
+    "io.katacontainers.container.share_fs_fallback";
+/// A container annotation to specify the SR-IOV VFs passed through to the guest, separated by ';',
+/// each with the PF they're allocated from and their config set on it, e.g.
+/// "pf=ens1f0,mac=02:00:00:00:00:01,vlan=100". It has to be enabled by enable_annotations, and
+/// the PFs allowed by sriov_allowed_pfs.
+pub const KATA_ANNO_CONTAINER_SRIOV_VFS: &str = "io.katacontainers.container.sriov_vfs";
+
+// Pod resource related annotations
+/// A sandbox annotation to specify the cpu quota of the pod overhead of the runtimeclass.
+pub const KATA_ANNO_POD_OVERHEAD_CPU_QUOTA: &str = "io.katacontainers.pod.overhead.cpu_quota";
+/// A sandbox annotation to specify the cpu period of the pod overhead of the runtimeclass.
+pub const KATA_ANNO_POD_OVERHEAD_CPU_PERIOD: &str = "io.katacontainers.pod.overhead.cpu_period";
+/// A sandbox annotation to specify the memory in bytes of the pod overhead of the runtimeclass.
+pub const KATA_ANNO_POD_OVERHEAD_MEMORY: &str = "io.katacontainers.pod.overhead.memory";
+/// A sandbox annotation to specify the cpu quota of the largest init container.
+pub const KATA_ANNO_POD_INIT_CPU_QUOTA: &str = "io.katacontainers.pod.init_containers.cpu_quota";
+/// A sandbox annotation to specify the cpu period of the largest init container.
+pub const KATA_ANNO_POD_INIT_CPU_PERIOD: &str = "io.katacontainers.pod.init_containers.cpu_period";
+/// A sandbox annotation to specify the memory in bytes of the largest init container.
+pub const KATA_ANNO_POD_INIT_MEMORY: &str = "io.katacontainers.pod.init_containers.memory";
+
 // Agent related annotations
 /// Prefix for Agent configurations.
 pub const KATA_ANNO_CFG_AGENT_PREFIX: &str = "io.katacontainers.config.agent.";
@@ -307,6 +334,11 @@ pub const KATA_ANNO_CFG_DISABLE_NEW_NETNS: &str =
     "io.katacontainers.config.runtime.disable_new_netns";
 /// A sandbox annotation to specify how attached VFIO devices should be treated.
 pub const KATA_ANNO_CFG_VFIO_MODE: &str = "io.katacontainers.config.runtime.vfio_mode";
+/// A sandbox annotation that determines if the devices are only attached to the containers having
+/// the capabilities of device_capabilities. It can only enable the enforcement, never disable the
+/// one of the config.
+pub const KATA_ANNO_CFG_ENFORCE_DEVICE_CAPABILITIES: &str =
+    "io.katacontainers.config.runtime.enforce_device_capabilities";
 
 /// A sandbox annotation used to specify prefetch_files.list host path container image
 /// being used,
@@ -316,7 +348,8 @@ pub const KATA_ANNO_CFG_VFIO_MODE: &str = "io.katacontainers.config.runtime.vfio
 pub const KATA_ANNO_CFG_HYPERVISOR_PREFETCH_FILES_LIST: &str =
     "io.katacontainers.config.hypervisor.prefetch_files.list";
 
-/// A sandbox annotation for sandbox level volume sharing with host.
+/// A sandbox annotation for sandbox level volume sharing with host, the host paths separated by
+/// whitespaces are mounted along with the sandbox_bind_mounts of the configuration.
 pub const KATA_ANNO_CFG_SANDBOX_BIND_MOUNTS: &str =
     "io.katacontainers.config.runtime.sandbox_bind_mounts";
 
@@ -410,12 +443,60 @@ impl Annotation {
         value.unwrap_or(0)
     }
 
+    /// Get the annotation of cpu shares for sandbox
+    pub fn get_sandbox_cpu_shares(&self) -> u64 {
+        let value = self
+            .get_value::<u64>(SANDBOX_CPU_SHARE_KEY)
+            .unwrap_or(Some(0));
+        value.unwrap_or(0)
+    }
+
     /// Get the annotation of memory for sandbox
     pub fn get_sandbox_mem(&self) -> i64 {
         let value = self.get_value::<i64>(SANDBOX_MEM_KEY).unwrap_or(Some(0));
         value.unwrap_or(0)
     }
 
+    /// Get the annotation of cpu quota and period of the pod overhead.
+    pub fn get_pod_overhead_cpu(&self) -> (i64, u64) {
+        (
+            self.get_value::<i64>(KATA_ANNO_POD_OVERHEAD_CPU_QUOTA)
+                .unwrap_or(Some(0))
+                .unwrap_or(0),
+            self.get_value::<u64>(KATA_ANNO_POD_OVERHEAD_CPU_PERIOD)
+                .unwrap_or(Some(0))
+                .unwrap_or(0),
+        )
+    }
+
+    /// Get the annotation of memory of the pod overhead.
+    pub fn get_pod_overhead_mem(&self) -> i64 {
+        let value = self
+            .get_value::<i64>(KATA_ANNO_POD_OVERHEAD_MEMORY)
+            .unwrap_or(Some(0));
+        value.unwrap_or(0)
+    }
+
+    /// Get the annotation of cpu quota and period of the largest init container.
+    pub fn get_pod_init_cpu(&self) -> (i64, u64) {
+        (
+            self.get_value::<i64>(KATA_ANNO_POD_INIT_CPU_QUOTA)
+                .unwrap_or(Some(0))
+                .unwrap_or(0),
+            self.get_value::<u64>(KATA_ANNO_POD_INIT_CPU_PERIOD)
+                .unwrap_or(Some(0))
+                .unwrap_or(0),
+        )
+    }
+
+    /// Get the annotation of memory of the largest init container.
+    pub fn get_pod_init_mem(&self) -> i64 {
+        let value = self
+            .get_value::<i64>(KATA_ANNO_POD_INIT_MEMORY)
+            .unwrap_or(Some(0));
+        value.unwrap_or(0)
+    }
+
     /// Get the annotation to specify the Resources.Memory.Swappiness.
     pub fn get_container_resource_swappiness(&self) -> Result<Option<u32>> {
         match self.get_value::<u32>(KATA_ANNO_CONTAINER_RES_SWAPPINESS) {
@@ -445,6 +526,13 @@ impl Annotation {
 impl Annotation {
     /// update config info by annotation
     pub fn update_config_by_annotation(&self, config: &mut TomlConfig) -> Result<()> {
+        config.annotations = self
+            .annotations
+            .iter()
+            .filter(|(k, _)| k.starts_with(KATA_ANNO_CFG_PREFIX))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
         if let Some(hv) = self.annotations.get(KATA_ANNO_CFG_RUNTIME_HYPERVISOR) {
             if config.hypervisor.get(hv).is_some() {
                 config.runtime.hypervisor_name = hv.to_string();
@@ -954,16 +1042,23 @@ impl Annotation {
                     KATA_ANNO_CFG_VFIO_MODE => {
                         config.runtime.vfio_mode = value.to_string();
                     }
-                    KATA_ANNO_CFG_SANDBOX_BIND_MOUNTS => {
-                        let args: Vec<String> = value
-                            .to_string()
-                            .split_ascii_whitespace()
-                            .map(str::to_string)
-                            .collect();
-                        for arg in args {
-                            config.runtime.sandbox_bind_mounts.push(arg.to_string());
+                    // the pod could otherwise lift the restriction set by the admin
+                    KATA_ANNO_CFG_ENFORCE_DEVICE_CAPABILITIES => {
+                        match self.get_value::<bool>(key) {
+                            Ok(Some(true)) => {
+                                config.runtime.enforce_device_capabilities = true;
+                            }
+                            Ok(_) => {
+                                warn!(sl!(), "Annotation {} can't disable the enforcement", key);
+                            }
+                            Err(_e) => {
+                                return Err(bool_err);
+                            }
                         }
                     }
+                    // kept in the annotations of the config, the host paths are checked
+                    // against valid_sandbox_bind_mount_prefixes when they're mounted
+                    KATA_ANNO_CFG_SANDBOX_BIND_MOUNTS => {}
                     _ => {
                         warn!(sl!(), "Annotation {} not enabled", key);
                     }
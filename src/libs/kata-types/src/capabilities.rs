@@ -6,8 +6,13 @@
 
 use bitmask_enum::bitmask;
 
+/// Size of a memory slot in MiB when the memory is hotplugged by ACPI.
+pub const ACPI_MEMORY_SLOT_SIZE_MB: u32 = 128;
+/// Size of a block in MiB when the memory is hotplugged by virtio-mem.
+pub const VIRTIO_MEM_BLOCK_SIZE_MB: u32 = 2;
+
 /// CapabilityBits
-#[bitmask(u8)]
+#[bitmask(u16)]
 pub enum CapabilityBits {
     /// hypervisor supports use block device
     BlockDeviceSupport,
@@ -17,6 +22,17 @@ pub enum CapabilityBits {
     MultiQueueSupport,
     /// hypervisor supports filesystem share
     FsSharingSupport,
+    /// hypervisor supports memory balloon
+    BalloonSupport,
+    /// hypervisor supports placing the passthrough devices in the guest PCIe topology
+    PciTopologySupport,
+    /// hypervisor hotplugs the backend of a device, e.g. the drive or the netdev, before the
+    /// device itself
+    SeparateBackendHotplug,
+    /// hypervisor supports network device hotplug
+    NetworkDeviceHotplugSupport,
+    /// hypervisor supports block devices with several virtqueues
+    BlockDeviceMultiQueueSupport,
 }
 
 /// Capabilities describe a virtcontainers hypervisor capabilities through a bit mask.
@@ -24,6 +40,8 @@ pub enum CapabilityBits {
 pub struct Capabilities {
     /// Capability flags
     flags: CapabilityBits,
+    /// Granularity in MiB of the memory hotplugged, 0 means no alignment
+    memory_hotplug_granularity_mb: u32,
 }
 
 impl Default for Capabilities {
@@ -37,6 +55,7 @@ impl Capabilities {
     pub fn new() -> Self {
         Capabilities {
             flags: CapabilityBits { bits: 0 },
+            memory_hotplug_granularity_mb: 0,
         }
     }
 
@@ -64,6 +83,47 @@ impl Capabilities {
     pub fn is_fs_sharing_supported(&self) -> bool {
         self.flags.and(CapabilityBits::FsSharingSupport) != 0
     }
+
+    /// is_balloon_supported tells if an hypervisor supports memory balloon.
+    pub fn is_balloon_supported(&self) -> bool {
+        self.flags.and(CapabilityBits::BalloonSupport) != 0
+    }
+
+    /// is_pci_topology_supported tells if an hypervisor places the passthrough devices at the
+    /// NUMA node or the root port asked for.
+    pub fn is_pci_topology_supported(&self) -> bool {
+        self.flags.and(CapabilityBits::PciTopologySupport) != 0
+    }
+
+    /// is_separate_backend_hotplug tells if an hypervisor needs the backend of a device, e.g.
+    /// the drive or the netdev, hotplugged before the device itself, as QEMU does.
+    pub fn is_separate_backend_hotplug(&self) -> bool {
+        self.flags.and(CapabilityBits::SeparateBackendHotplug) != 0
+    }
+
+    /// is_network_device_hotplug_supported tells if an hypervisor supports network devices
+    /// hotplug, the ones it doesn't are cold plugged before the VM starts.
+    pub fn is_network_device_hotplug_supported(&self) -> bool {
+        self.flags.and(CapabilityBits::NetworkDeviceHotplugSupport) != 0
+    }
+
+    /// is_block_device_multi_queue_supported tells if an hypervisor supports block devices with
+    /// several virtqueues.
+    pub fn is_block_device_multi_queue_supported(&self) -> bool {
+        self.flags.and(CapabilityBits::BlockDeviceMultiQueueSupport) != 0
+    }
+
+    /// set_memory_hotplug_granularity_mb sets the granularity in MiB that the
+    /// hypervisor hotplugs memory in.
+    pub fn set_memory_hotplug_granularity_mb(&mut self, granularity_mb: u32) {
+        self.memory_hotplug_granularity_mb = granularity_mb;
+    }
+
+    /// memory_hotplug_granularity_mb tells the granularity in MiB that the
+    /// hypervisor hotplugs memory in, it's at least 1 MiB.
+    pub fn memory_hotplug_granularity_mb(&self) -> u32 {
+        self.memory_hotplug_granularity_mb.max(1)
+    }
 }
 
 #[cfg(test)]
@@ -102,6 +162,36 @@ mod tests {
                 | CapabilityBits::MultiQueueSupport
                 | CapabilityBits::FsSharingSupport,
         );
-        assert!(cap.is_fs_sharing_supported())
+        assert!(cap.is_fs_sharing_supported());
+        assert!(!cap.is_balloon_supported());
+
+        // test set memory balloon support
+        cap.set(CapabilityBits::FsSharingSupport | CapabilityBits::BalloonSupport);
+        assert!(cap.is_balloon_supported());
+        assert!(!cap.is_separate_backend_hotplug());
+
+        // test set separate backend hotplug
+        cap.set(CapabilityBits::FsSharingSupport | CapabilityBits::SeparateBackendHotplug);
+        assert!(cap.is_separate_backend_hotplug());
+        assert!(!cap.is_network_device_hotplug_supported());
+
+        // test set network device hotplug support
+        cap.set(CapabilityBits::NetworkDeviceHotplugSupport);
+        assert!(cap.is_network_device_hotplug_supported());
+        assert!(!cap.is_block_device_multi_queue_supported());
+
+        // test set block device multi queue support, apart from the network one
+        cap.set(CapabilityBits::BlockDeviceSupport | CapabilityBits::BlockDeviceMultiQueueSupport);
+        assert!(cap.is_block_device_multi_queue_supported());
+        assert!(!cap.is_multi_queue_supported());
+    }
+
+    #[test]
+    fn test_memory_hotplug_granularity() {
+        let mut cap = Capabilities::new();
+        assert_eq!(cap.memory_hotplug_granularity_mb(), 1);
+
+        cap.set_memory_hotplug_granularity_mb(super::VIRTIO_MEM_BLOCK_SIZE_MB);
+        assert_eq!(cap.memory_hotplug_granularity_mb(), 2);
     }
 }
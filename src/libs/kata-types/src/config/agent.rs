@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use std::collections::HashMap;
 use std::io::Result;
 
 use crate::config::{ConfigOps, TomlConfig};
@@ -10,13 +11,23 @@ use crate::config::{ConfigOps, TomlConfig};
 pub use vendor::AgentVendor;
 
 use super::default::{
-    DEFAULT_AGENT_DIAL_TIMEOUT_MS, DEFAULT_AGENT_LOG_PORT, DEFAULT_AGENT_VSOCK_PORT,
+    DEFAULT_AGENT_DIAL_TIMEOUT_MS, DEFAULT_AGENT_LOG_PORT, DEFAULT_AGENT_REQUEST_TIMEOUT_MS,
+    DEFAULT_AGENT_VSOCK_PORT,
 };
 use crate::eother;
 
 /// agent name of Kata agent.
 pub const AGENT_NAME_KATA: &str = "kata";
 
+/// Device class of the block devices in device_kernel_modules and device_capabilities.
+pub const DEVICE_CLASS_BLOCK: &str = "block";
+/// Device class of the vfio devices in device_kernel_modules and device_capabilities.
+pub const DEVICE_CLASS_VFIO: &str = "vfio";
+/// Device class of the other char devices in device_kernel_modules and device_capabilities.
+pub const DEVICE_CLASS_CHAR: &str = "char";
+pub(crate) const DEVICE_CLASSES: [&str; 3] =
+    [DEVICE_CLASS_BLOCK, DEVICE_CLASS_VFIO, DEVICE_CLASS_CHAR];
+
 /// Kata agent configuration information.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Agent {
@@ -65,6 +76,34 @@ pub struct Agent {
     #[serde(default = "default_health_check_timeout")]
     pub health_check_request_timeout_ms: u32,
 
+    /// Agent network setup request timeout value in millisecond, `request_timeout_ms` is used
+    /// if it's 0
+    #[serde(default)]
+    pub network_request_timeout_ms: u32,
+
+    /// Agent storage request timeout value in millisecond, `request_timeout_ms` is used if
+    /// it's 0
+    #[serde(default)]
+    pub storage_request_timeout_ms: u32,
+
+    /// Agent container lifecycle request timeout value in millisecond, `request_timeout_ms` is
+    /// used if it's 0
+    #[serde(default)]
+    pub container_request_timeout_ms: u32,
+
+    /// Agent request timeout values in millisecond of single requests, keyed by the name of
+    /// the request, e.g. `update_interface`, they take precedence over the timeouts of the
+    /// request categories.
+    #[serde(default)]
+    pub request_timeouts_ms: HashMap<String, u32>,
+
+    /// Deadline in millisecond to wait for the agent to answer the health check once the VM
+    /// is started, before the network is set up. It's separate from the timeouts of the
+    /// requests, the check is retried with backoff until the deadline. The agent isn't waited
+    /// for if it's 0.
+    #[serde(default = "default_ready_timeout")]
+    pub ready_timeout_ms: u32,
+
     /// Comma separated list of kernel modules and their parameters.
     ///
     /// These modules will be loaded in the guest kernel using modprobe(8).
@@ -79,6 +118,15 @@ pub struct Agent {
     #[serde(default)]
     pub kernel_modules: Vec<String>,
 
+    /// Kernel modules loaded in the guest before a device of the class is
+    /// handed to a container, keyed by the device class "block", "vfio" or
+    /// "char", in the same format as kernel_modules. The modules already
+    /// present in the guest, e.g. built-in, are skipped, the missing ones
+    /// fail the container. No module is loaded by default.
+    ///  - device_kernel_modules={ block=["nvme"], vfio=["nvidia"] }
+    #[serde(default)]
+    pub device_kernel_modules: HashMap<String, Vec<String>>,
+
     /// container pipe size
     #[serde(default)]
     pub container_pipe_size: u32,
@@ -94,9 +142,15 @@ impl std::default::Default for Agent {
             log_port: DEFAULT_AGENT_LOG_PORT,
             dial_timeout_ms: DEFAULT_AGENT_DIAL_TIMEOUT_MS,
             reconnect_timeout_ms: 3_000,
-            request_timeout_ms: 30_000,
+            request_timeout_ms: DEFAULT_AGENT_REQUEST_TIMEOUT_MS,
             health_check_request_timeout_ms: 90_000,
+            network_request_timeout_ms: 0,
+            storage_request_timeout_ms: 0,
+            container_request_timeout_ms: 0,
+            request_timeouts_ms: HashMap::new(),
+            ready_timeout_ms: 10_000,
             kernel_modules: Default::default(),
+            device_kernel_modules: HashMap::new(),
             container_pipe_size: 0,
         }
     }
@@ -121,8 +175,7 @@ fn default_reconnect_timeout() -> u32 {
 }
 
 fn default_request_timeout() -> u32 {
-    // ms
-    30_000
+    DEFAULT_AGENT_REQUEST_TIMEOUT_MS
 }
 
 fn default_health_check_timeout() -> u32 {
@@ -130,11 +183,25 @@ fn default_health_check_timeout() -> u32 {
     90_000
 }
 
+fn default_ready_timeout() -> u32 {
+    // ms
+    10_000
+}
+
 impl Agent {
     fn validate(&self) -> Result<()> {
         if self.dial_timeout_ms == 0 {
             return Err(eother!("dial_timeout_ms couldn't be 0."));
         }
+        for class in self.device_kernel_modules.keys() {
+            if !DEVICE_CLASSES.contains(&class.as_str()) {
+                return Err(eother!(
+                    "device_kernel_modules of unknown device class {}, expect one of {:?}",
+                    class,
+                    DEVICE_CLASSES
+                ));
+            }
+        }
 
         Ok(())
     }
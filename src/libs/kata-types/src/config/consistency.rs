@@ -0,0 +1,333 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::fmt;
+use std::io::Result;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use super::hypervisor::{SHARED_FS_AUTO, VIRTIO_9P, VIRTIO_FS, VIRTIO_FS_INLINE};
+use super::TomlConfig;
+use crate::capabilities::Capabilities;
+use crate::eother;
+use crate::mount::split_bind_mounts;
+
+/// Severity of a violation found by the consistency checks of the configuration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The sandbox may work, but not as configured.
+    Warning,
+    /// The sandbox fails to be created.
+    Error,
+}
+
+/// Violation is an inconsistency of the configuration found by the consistency checks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// Severity of the violation.
+    pub severity: Severity,
+    /// What's inconsistent, with the option involved.
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Violations(Vec<Violation>);
+
+impl Violations {
+    fn warn(&mut self, message: String) {
+        self.0.push(Violation {
+            severity: Severity::Warning,
+            message,
+        });
+    }
+
+    fn error(&mut self, message: String) {
+        self.0.push(Violation {
+            severity: Severity::Error,
+            message,
+        });
+    }
+}
+
+/// Validate the violations found by the consistency checks all at once.
+///
+/// An error listing all the violations is returned if any of them is an error, or a warning in
+/// `strict` mode. The warnings are returned otherwise, for the caller to report them.
+pub fn validate_violations(violations: Vec<Violation>, strict: bool) -> Result<Vec<Violation>> {
+    if !violations
+        .iter()
+        .any(|v| strict || v.severity == Severity::Error)
+    {
+        return Ok(violations);
+    }
+
+    let list: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+    Err(eother!(
+        "{} configuration violations: {}",
+        violations.len(),
+        list.join("; ")
+    ))
+}
+
+impl TomlConfig {
+    /// Cross-check the runtime and the hypervisor sections against each other and the host.
+    ///
+    /// All the violations are returned rather than the first one, nothing is changed on the host,
+    /// so that the configuration file could be checked without creating a sandbox.
+    pub fn check_consistency(&self) -> Vec<Violation> {
+        let mut violations = Violations::default();
+        let runtime = &self.runtime;
+
+        if !self.agent.contains_key(&runtime.agent_name) {
+            violations.error(format!("agent `{}` isn't configured", runtime.agent_name));
+        }
+        for bind in runtime.sandbox_bind_mounts.iter() {
+            let (real_path, _mode) = split_bind_mounts(bind);
+            if !Path::new(real_path).exists() {
+                violations.error(format!(
+                    "sandbox bind mount `{}` doesn't exist on the host",
+                    real_path
+                ));
+            }
+        }
+        if !runtime.rootfs_lower_layer.is_empty()
+            && !Path::new(&runtime.rootfs_lower_layer).is_dir()
+        {
+            violations.error(format!(
+                "rootfs_lower_layer `{}` isn't a directory on the host",
+                runtime.rootfs_lower_layer
+            ));
+        }
+
+        let hv = match self.hypervisor.get(&runtime.hypervisor_name) {
+            Some(hv) => hv,
+            None => {
+                violations.error(format!(
+                    "hypervisor `{}` isn't configured",
+                    runtime.hypervisor_name
+                ));
+                return violations.0;
+            }
+        };
+
+        for (option, path) in [
+            ("path", &hv.path),
+            ("ctlpath", &hv.ctlpath),
+            ("jailer_path", &hv.jailer_path),
+        ] {
+            check_executable(&mut violations, option, path);
+        }
+        let shared_fs = hv.shared_fs.shared_fs.as_deref();
+        if matches!(shared_fs, Some(VIRTIO_FS) | Some(SHARED_FS_AUTO)) {
+            check_executable(
+                &mut violations,
+                "virtio_fs_daemon",
+                &hv.shared_fs.virtio_fs_daemon,
+            );
+        }
+        if shared_fs.is_none() && hv.blockdev_info.disable_block_device_use {
+            violations.warn(format!(
+                "hypervisor `{}` shares neither a filesystem nor block devices, the images must be pulled in the guest",
+                runtime.hypervisor_name
+            ));
+        }
+
+        if !hv.blockdev_info.disable_block_device_use {
+            let driver = hv.blockdev_info.block_device_driver.as_str();
+            if let Some(arches) = block_driver_arches(driver) {
+                if !arches.contains(&std::env::consts::ARCH) {
+                    violations.error(format!(
+                        "block_device_driver `{}` isn't available on {}, only on {:?}",
+                        driver,
+                        std::env::consts::ARCH,
+                        arches
+                    ));
+                }
+            }
+        }
+
+        if hv.cpu_info.default_vcpus > hv.cpu_info.default_maxvcpus as i32 {
+            violations.error(format!(
+                "default_vcpus {} is greater than default_maxvcpus {}",
+                hv.cpu_info.default_vcpus, hv.cpu_info.default_maxvcpus
+            ));
+        }
+        let memory = &hv.memory_info;
+        if memory.default_maxmemory != 0 && memory.default_memory > memory.default_maxmemory {
+            violations.error(format!(
+                "default_memory {} MiB is greater than default_maxmemory {} MiB",
+                memory.default_memory, memory.default_maxmemory
+            ));
+        }
+        if memory.default_memory != 0 && runtime.default_shm_size_mb > memory.default_memory {
+            violations.warn(format!(
+                "default_shm_size_mb {} is greater than default_memory {} MiB, /dev/shm is limited to the guest memory",
+                runtime.default_shm_size_mb, memory.default_memory
+            ));
+        }
+
+        violations.0
+    }
+
+    /// Cross-check the hypervisor section against the capabilities of the hypervisor, which are
+    /// only known once the hypervisor is created.
+    pub fn check_capabilities(&self, caps: &Capabilities) -> Vec<Violation> {
+        let mut violations = Violations::default();
+        let name = &self.runtime.hypervisor_name;
+        let hv = match self.hypervisor.get(name) {
+            Some(hv) => hv,
+            None => return violations.0,
+        };
+
+        // auto shares nothing with the protected guests, whatever the hypervisor does
+        let shared_fs = hv.shared_fs.shared_fs.as_deref();
+        if matches!(
+            shared_fs,
+            Some(VIRTIO_FS) | Some(VIRTIO_FS_INLINE) | Some(VIRTIO_9P)
+        ) && !caps.is_fs_sharing_supported()
+        {
+            violations.error(format!(
+                "shared_fs `{}` isn't supported by hypervisor `{}`",
+                shared_fs.unwrap_or_default(),
+                name
+            ));
+        }
+        if !hv.blockdev_info.disable_block_device_use && !caps.is_block_device_supported() {
+            violations.warn(format!(
+                "hypervisor `{}` doesn't support block devices, set disable_block_device_use",
+                name
+            ));
+        }
+
+        violations.0
+    }
+}
+
+// the architectures the block device driver is available on, none if it's available on all
+fn block_driver_arches(driver: &str) -> Option<&'static [&'static str]> {
+    match driver {
+        "virtio-blk-ccw" => Some(&["s390x"]),
+        "nvdimm" => Some(&["x86_64", "aarch64"]),
+        _ => None,
+    }
+}
+
+fn check_executable(violations: &mut Violations, option: &str, path: &str) {
+    if path.is_empty() {
+        return;
+    }
+    match Path::new(path).metadata() {
+        Err(e) => violations.error(format!("{} `{}` is invalid: {}", option, path, e)),
+        Ok(m) if !m.is_file() || m.permissions().mode() & 0o111 == 0 => {
+            violations.error(format!("{} `{}` isn't an executable file", option, path))
+        }
+        Ok(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capabilities::CapabilityBits;
+    use crate::config::{Agent, Hypervisor};
+
+    fn config() -> TomlConfig {
+        let mut config = TomlConfig {
+            ..Default::default()
+        };
+        config.runtime.hypervisor_name = "qemu".to_string();
+        config.runtime.agent_name = "kata".to_string();
+        config.agent.insert("kata".to_string(), Agent::default());
+        let mut hv = Hypervisor {
+            path: "/bin/sh".to_string(),
+            ..Default::default()
+        };
+        hv.cpu_info.default_vcpus = 1;
+        hv.cpu_info.default_maxvcpus = 4;
+        hv.memory_info.default_memory = 2048;
+        config.hypervisor.insert("qemu".to_string(), hv);
+        config
+    }
+
+    #[test]
+    fn test_check_consistency() {
+        let config = config();
+        assert_eq!(config.check_consistency(), vec![]);
+
+        // all the violations are found at once
+        let mut config = self::config();
+        config.runtime.sandbox_bind_mounts = vec!["/no/such/path:ro".to_string()];
+        config.runtime.default_shm_size_mb = 4096;
+        let hv = config.hypervisor.get_mut("qemu").unwrap();
+        hv.path = "/etc/passwd".to_string();
+        hv.cpu_info.default_vcpus = 8;
+        let violations = config.check_consistency();
+        let errors: Vec<&Violation> = violations
+            .iter()
+            .filter(|v| v.severity == Severity::Error)
+            .collect();
+        assert_eq!(errors.len(), 3, "{:?}", violations);
+        assert!(errors[0].message.contains("/no/such/path"));
+        assert!(errors[1].message.contains("path `/etc/passwd`"));
+        assert!(errors[2].message.contains("default_vcpus"));
+        assert_eq!(violations.len(), 4);
+
+        let err = validate_violations(violations, false).unwrap_err();
+        assert!(err.to_string().starts_with("4 configuration violations"));
+
+        // only the warnings, they're errors in strict mode
+        let mut config = self::config();
+        config.runtime.default_shm_size_mb = 4096;
+        let violations = config.check_consistency();
+        assert_eq!(
+            validate_violations(violations.clone(), false).unwrap(),
+            violations
+        );
+        validate_violations(violations, true).unwrap_err();
+
+        // nothing more to check without the hypervisor
+        let mut config = self::config();
+        config.runtime.hypervisor_name = "dragonball".to_string();
+        let violations = config.check_consistency();
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].message.contains("dragonball"));
+    }
+
+    #[test]
+    fn test_check_capabilities() {
+        let mut config = config();
+        config
+            .hypervisor
+            .get_mut("qemu")
+            .unwrap()
+            .shared_fs
+            .shared_fs = Some(VIRTIO_FS.to_string());
+
+        let mut caps = Capabilities::new();
+        caps.set(CapabilityBits::BlockDeviceSupport | CapabilityBits::FsSharingSupport);
+        assert_eq!(config.check_capabilities(&caps), vec![]);
+
+        caps.set(CapabilityBits::FsSharingSupport);
+        let violations = config.check_capabilities(&caps);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].severity, Severity::Warning);
+
+        caps.set(CapabilityBits::BlockDeviceSupport);
+        let violations = config.check_capabilities(&caps);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].severity, Severity::Error);
+        assert!(violations[0].message.contains("shared_fs `virtio-fs`"));
+    }
+}
@@ -26,11 +26,17 @@ pub const DEFAULT_AGENT_LOG_PORT: u32 = 1025;
 pub const DEFAULT_AGENT_DBG_CONSOLE_PORT: u32 = 1026;
 pub const DEFAULT_AGENT_TYPE_NAME: &str = AGENT_NAME_KATA;
 pub const DEFAULT_AGENT_DIAL_TIMEOUT_MS: u32 = 10;
+pub const DEFAULT_AGENT_REQUEST_TIMEOUT_MS: u32 = 30_000;
 
 pub const DEFAULT_RUNTIME_NAME: &str = RUNTIME_NAME_VIRTCONTAINER;
 pub const DEFAULT_HYPERVISOR: &str = HYPERVISOR_NAME_DRAGONBALL;
 
 pub const DEFAULT_INTERNETWORKING_MODEL: &str = "tcfilter";
+pub const DEFAULT_COPY_FILE_MAX_SIZE: u64 = 64 * 1024;
+
+// the containers with their own virtio-fs export with per_container_sharefs
+pub const DEFAULT_MAX_SHAREFS_INSTANCES: u32 = 4;
+pub const MAX_SHAREFS_INSTANCES: u32 = 16;
 
 pub const DEFAULT_BLOCK_DEVICE_TYPE: &str = "virtio-blk";
 pub const DEFAULT_VHOST_USER_STORE_PATH: &str = "/var/run/vhost-user";
@@ -39,6 +45,7 @@ pub const DEFAULT_BLOCK_NVDIMM_MEM_OFFSET: u64 = 0;
 pub const DEFAULT_SHARED_FS_TYPE: &str = "virtio-fs";
 pub const DEFAULT_VIRTIO_FS_CACHE_MODE: &str = "never";
 pub const DEFAULT_VIRTIO_FS_DAX_SIZE_MB: u32 = 1024;
+pub const DEFAULT_VIRTIO_FS_READY_TIMEOUT_SECS: u32 = 10;
 pub const DEFAULT_SHARED_9PFS_SIZE_MB: u32 = 128 * 1024;
 pub const MIN_SHARED_9PFS_SIZE_MB: u32 = 4 * 1024;
 pub const MAX_SHARED_9PFS_SIZE_MB: u32 = 8 * 1024 * 1024;
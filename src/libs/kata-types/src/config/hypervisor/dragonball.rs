@@ -20,6 +20,10 @@ use crate::{eother, resolve_path, validate_path};
 /// Hypervisor name for dragonball, used to index `TomlConfig::hypervisor`.
 pub const HYPERVISOR_NAME_DRAGONBALL: &str = "dragonball";
 
+/// AIO modes of the block devices supported by dragonball, it uses io_uring and falls back to
+/// native aio on the hosts without io_uring.
+pub const DRAGONBALL_BLOCK_DEVICE_AIO_MODES: [&str; 1] = ["io_uring"];
+
 /// Configuration information for dragonball.
 #[derive(Default, Debug)]
 pub struct DragonballConfig {}
@@ -116,6 +120,16 @@ impl ConfigPlugin for DragonballConfig {
                     db.blockdev_info.block_device_driver
                 ));
             }
+            if !db.blockdev_info.block_device_aio.is_empty()
+                && !DRAGONBALL_BLOCK_DEVICE_AIO_MODES
+                    .contains(&db.blockdev_info.block_device_aio.as_str())
+            {
+                return Err(eother!(
+                    "{} is unsupported block device aio mode of dragonball, must be one of {:?}",
+                    db.blockdev_info.block_device_aio,
+                    DRAGONBALL_BLOCK_DEVICE_AIO_MODES
+                ));
+            }
 
             if db.boot_info.kernel.is_empty() {
                 return Err(eother!(
@@ -167,12 +181,6 @@ impl ConfigPlugin for DragonballConfig {
                 return Err(eother!("dragonball hypervisor does not support pflashes"));
             }
 
-            if db.memory_info.enable_guest_swap {
-                return Err(eother!(
-                    "dragonball hypervisor doesn't support enable_guest_swap"
-                ));
-            }
-
             if db.security_info.rootless {
                 return Err(eother!(
                     "dragonball hypervisor does not support rootless mode"
@@ -185,6 +193,14 @@ impl ConfigPlugin for DragonballConfig {
                 }
             }
 
+            // dragonball hotplugs memory by virtio-mem only, the sandbox
+            // sized with the containers couldn't grow otherwise
+            if !db.memory_info.enable_virtio_mem && !conf.runtime.static_sandbox_resource_mgmt {
+                return Err(eother!(
+                    "dragonball hypervisor hotplugs memory by virtio-mem only, enable_virtio_mem is required unless static_sandbox_resource_mgmt is set"
+                ));
+            }
+
             if db.memory_info.default_memory < MIN_DRAGONBALL_MEMORY_SIZE_MB {
                 return Err(eother!(
                     "dragonball hypervisor has minimal memory limitation {}",
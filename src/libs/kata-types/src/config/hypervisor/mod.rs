@@ -31,11 +31,13 @@ use lazy_static::lazy_static;
 use regex::RegexSet;
 
 use super::{default, ConfigOps, ConfigPlugin, TomlConfig};
-use crate::annotations::KATA_ANNO_CFG_HYPERVISOR_PREFIX;
+use crate::annotations::{KATA_ANNO_CFG_HYPERVISOR_PREFIX, KATA_ANNO_CONTAINER_PREFIX};
 use crate::{eother, resolve_path, sl, validate_path};
 
 mod dragonball;
-pub use self::dragonball::{DragonballConfig, HYPERVISOR_NAME_DRAGONBALL};
+pub use self::dragonball::{
+    DragonballConfig, DRAGONBALL_BLOCK_DEVICE_AIO_MODES, HYPERVISOR_NAME_DRAGONBALL,
+};
 
 mod qemu;
 pub use self::qemu::{QemuConfig, HYPERVISOR_NAME_QEMU};
@@ -48,10 +50,19 @@ const VIRTIO_BLK_MMIO: &str = "virtio-blk-mmio";
 const VIRTIO_BLK_CCW: &str = "virtio-blk-ccw";
 const VIRTIO_SCSI: &str = "virtio-scsi";
 const VIRTIO_PMEM: &str = "nvdimm";
-const VIRTIO_9P: &str = "virtio-9p";
-const VIRTIO_FS: &str = "virtio-fs";
-const VIRTIO_FS_INLINE: &str = "inline-virtio-fs";
+pub(crate) const VIRTIO_9P: &str = "virtio-9p";
+pub(crate) const VIRTIO_FS: &str = "virtio-fs";
+pub(crate) const VIRTIO_FS_INLINE: &str = "inline-virtio-fs";
+/// virtio-fs unless the guest is protected from the host, e.g. by TDX or SEV,
+/// nothing is shared from the host then.
+pub const SHARED_FS_AUTO: &str = "auto";
 const MAX_BRIDGE_SIZE: u32 = 5;
+/// Largest size of a virtio-blk queue, the sizes are powers of 2 up to it.
+pub const MAX_BLOCK_DEVICE_QUEUE_SIZE: u32 = 1024;
+// the agent only finds the disks behind the first SCSI host of the guest
+const MAX_SCSI_CONTROLLERS: u32 = 1;
+/// AIO modes of the drives of the block devices.
+pub const BLOCK_DEVICE_AIO_MODES: [&str; 3] = ["threads", "native", "io_uring"];
 
 const KERNEL_PARAM_DELIMITER: &str = " ";
 
@@ -72,6 +83,25 @@ pub fn get_hypervisor_plugin(name: &str) -> Option<Arc<dyn ConfigPlugin>> {
     hypervisors.get(name).cloned()
 }
 
+/// Get the amount of physical RAM on the host in MiB.
+pub fn get_host_memory_mb() -> Result<u32> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")?;
+    for line in meminfo.lines() {
+        // MemTotal:       16307296 kB
+        if let Some(total) = line.strip_prefix("MemTotal:") {
+            let kb = total
+                .trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| eother!("Invalid MemTotal in /proc/meminfo: {}", e))?;
+            return Ok((kb / 1024) as u32);
+        }
+    }
+
+    Err(eother!("Can not find MemTotal in /proc/meminfo"))
+}
+
 /// Configuration information for block device.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct BlockDeviceInfo {
@@ -135,6 +165,32 @@ pub struct BlockDeviceInfo {
     /// The default if not set is empty (all annotations rejected.)
     #[serde(default)]
     pub valid_vhost_user_store_paths: Vec<String>,
+
+    /// Number of virtio-blk queues of each block device.
+    ///
+    /// The default 0-sized value means one queue per default vCPU. A single queue is used if the
+    /// hypervisor doesn't support multi-queue devices.
+    #[serde(default)]
+    pub block_device_num_queues: u32,
+
+    /// Size of each virtio-blk queue, the hypervisor default is used if 0.
+    #[serde(default)]
+    pub block_device_queue_size: u32,
+
+    /// AIO mode of the drives of the block devices: threads, native or io_uring.
+    ///
+    /// It's only used by the hypervisors adding the drive of a block device separately, e.g.
+    /// QEMU, the hypervisor default is used if empty.
+    #[serde(default)]
+    pub block_device_aio: String,
+
+    /// Number of virtio-scsi controllers of the sandbox, with the virtio-scsi driver.
+    ///
+    /// The controllers are added to the VM at boot, the block devices are attached to the first
+    /// controller with a free LUN. 0 means a single controller, which is the most supported as
+    /// the agent only finds the disks behind the first SCSI host of the guest.
+    #[serde(default)]
+    pub block_device_scsi_controllers: u32,
 }
 
 impl BlockDeviceInfo {
@@ -184,6 +240,32 @@ impl BlockDeviceInfo {
                 self.block_device_driver
             ));
         }
+        if self.block_device_queue_size != 0
+            && (!self.block_device_queue_size.is_power_of_two()
+                || self.block_device_queue_size > MAX_BLOCK_DEVICE_QUEUE_SIZE)
+        {
+            return Err(eother!(
+                "block device queue size {} isn't a power of 2 up to {}",
+                self.block_device_queue_size,
+                MAX_BLOCK_DEVICE_QUEUE_SIZE
+            ));
+        }
+        if !self.block_device_aio.is_empty()
+            && !BLOCK_DEVICE_AIO_MODES.contains(&self.block_device_aio.as_str())
+        {
+            return Err(eother!(
+                "{} is unsupported block device aio mode, must be one of {:?}",
+                self.block_device_aio,
+                BLOCK_DEVICE_AIO_MODES
+            ));
+        }
+        if self.block_device_scsi_controllers > MAX_SCSI_CONTROLLERS {
+            return Err(eother!(
+                "{} scsi controllers, at most {} are supported",
+                self.block_device_scsi_controllers,
+                MAX_SCSI_CONTROLLERS
+            ));
+        }
         validate_path!(
             self.vhost_user_store_path,
             "Invalid vhost-user-store-path {}: {}"
@@ -523,6 +605,15 @@ pub struct MemoryInfo {
     #[serde(default)]
     pub default_memory: u32,
 
+    /// Default maximum memory in MiB per SB/VM:
+    /// - unspecified or == 0           --> will be set to the actual amount of physical RAM
+    /// - > 0 <= amount of physical RAM --> will be set to the specified number
+    /// - > amount of physical RAM      --> will be set to the actual amount of physical RAM
+    ///
+    /// The memory hotplugged to the SB/VM for containers can't go beyond this limit.
+    #[serde(default)]
+    pub default_maxmemory: u32,
+
     /// Default memory slots per SB/VM.
     ///
     /// This is will determine the times that memory will be hotadded to sandbox/VM.
@@ -566,6 +657,19 @@ pub struct MemoryInfo {
     #[serde(default)]
     pub enable_virtio_mem: bool,
 
+    /// The memory in MiB that the balloon always leaves to the guest on top of
+    /// the memory limits of the containers when reclaiming guest memory.
+    ///
+    /// If unspecified or 0, the minimal guest memory (128 MiB) is used.
+    #[serde(default)]
+    pub memory_reclaim_floor: u32,
+
+    /// Interval in seconds to reclaim the free guest memory by the balloon.
+    ///
+    /// If unspecified or 0, the guest memory is only reclaimed on request.
+    #[serde(default)]
+    pub memory_reclaim_interval: u32,
+
     /// Enable swap of vm memory. Default false.
     ///
     /// The behaviour is undefined if mem_prealloc is also set to true
@@ -574,16 +678,19 @@ pub struct MemoryInfo {
 
     /// Enable swap in the guest. Default false.
     ///
-    /// When enable_guest_swap is enabled, insert a raw file to the guest as the swap device if the
-    /// swappiness of a container (set by annotation "io.katacontainers.container.resource.swappiness")
-    /// is bigger than 0.
-    ///
-    /// The size of the swap device should be swap_in_bytes (set by annotation
-    /// "io.katacontainers.container.resource.swap_in_bytes") - memory_limit_in_bytes.
-    /// If swap_in_bytes is not set, the size should be memory_limit_in_bytes.
-    /// If swap_in_bytes and memory_limit_in_bytes is not set, the size should be default_memory.
+    /// When enable_guest_swap is enabled, a raw file on the host is inserted to the guest as the
+    /// swap device after the VM starts. The swappiness of the containers is set by
+    /// "memory.swappiness" of the OCI spec or the annotation
+    /// "io.katacontainers.container.resource.swappiness".
     #[serde(default)]
     pub enable_guest_swap: bool,
+
+    /// The size in MiB of the swap device of the guest.
+    ///
+    /// If unspecified or 0, the memory limit of the pod is used, or default_memory if the pod
+    /// has no memory limit.
+    #[serde(default)]
+    pub guest_swap_size: u32,
 }
 
 impl MemoryInfo {
@@ -593,6 +700,14 @@ impl MemoryInfo {
             self.file_mem_backend,
             "Memory backend file {} is invalid: {}"
         )?;
+
+        // adjust default_maxmemory
+        if let Ok(host_memory) = get_host_memory_mb() {
+            if self.default_maxmemory == 0 || self.default_maxmemory > host_memory {
+                self.default_maxmemory = host_memory;
+            }
+        }
+
         Ok(())
     }
 
@@ -608,6 +723,13 @@ impl MemoryInfo {
         if self.memory_slots == 0 {
             return Err(eother!("Configured memory slots for guest VM are zero"));
         }
+        if self.default_maxmemory != 0 && self.default_memory > self.default_maxmemory {
+            return Err(eother!(
+                "The default_memory({}) is greater than default_maxmemory({})",
+                self.default_memory,
+                self.default_maxmemory
+            ));
+        }
 
         Ok(())
     }
@@ -643,7 +765,10 @@ pub struct NetworkInfo {
     #[serde(default)]
     pub tx_rate_limiter_max_rate: u64,
 
-    /// network queues
+    /// Number of virtio-net queue pairs for each network interface.
+    ///
+    /// The default 0-sized value means one queue pair per default vCPU. The guest may negotiate
+    /// down to fewer queue pairs if it can't use all of them.
     #[serde(default)]
     pub network_queues: u32,
 }
@@ -707,7 +832,9 @@ pub struct SecurityInfo {
     /// List of valid annotation names for the hypervisor.
     ///
     /// Each member of the list is a regular expression, which is the base name of the annotation,
-    /// e.g. "path" for io.katacontainers.config.hypervisor.path"
+    /// e.g. "path" for io.katacontainers.config.hypervisor.path". The container annotations
+    /// changing the host, e.g. "sriov_vfs" for io.katacontainers.container.sriov_vfs, are enabled
+    /// the same way.
     #[serde(default)]
     pub enable_annotations: Vec<String>,
 }
@@ -739,6 +866,18 @@ impl SecurityInfo {
         false
     }
 
+    /// Check whether container annotation key is enabled or not.
+    pub fn is_container_annotation_enabled(&self, path: &str) -> bool {
+        let key = match path.strip_prefix(KATA_ANNO_CONTAINER_PREFIX) {
+            Some(key) => key,
+            None => return false,
+        };
+        if let Ok(set) = RegexSet::new(&self.enable_annotations) {
+            return set.is_match(key);
+        }
+        false
+    }
+
     /// Validate path
     pub fn validate_path(&self, path: &str) -> Result<()> {
         validate_path!(path, "path {} is invalid{}")?;
@@ -752,6 +891,7 @@ pub struct SharedFsInfo {
     /// Shared file system type:
     /// - virtio-fs (default)
     /// - virtio-9p`
+    /// - auto: virtio-fs unless the guest is protected
     pub shared_fs: Option<String>,
 
     /// Path to vhost-user-fs daemon.
@@ -793,6 +933,27 @@ pub struct SharedFsInfo {
     #[serde(default)]
     pub virtio_fs_is_dax: bool,
 
+    /// Enable the extended attributes of the files shared with virtio-fs.
+    ///
+    /// They're shared for the rootfs and the volumes alike, e.g. the SELinux labels and the file
+    /// capabilities of the rootfs are lost without them. The default of the backend is kept if not
+    /// set: virtio-fs leaves them to virtiofsd, disabled unless `--xattr` is passed in the extra
+    /// args, and inline-virtio-fs enables them. The agent is told by the storage of the share
+    /// whether the backend serves them.
+    #[serde(default)]
+    pub enable_xattr: Option<bool>,
+
+    /// Timeout in seconds waiting for virtiofsd to listen on its vhost-user socket before the VM
+    /// is started, the default is used if 0.
+    #[serde(default)]
+    pub virtio_fs_ready_timeout_secs: u32,
+
+    /// Subdirectories of the shared directory of the sandbox virtiofsd is confined to, relative
+    /// to it, e.g. `passthrough`. virtiofsd is sandboxed in a mount namespace where only them are
+    /// left, the guest can't reach the rest. All the shared directory is served if empty.
+    #[serde(default)]
+    pub virtio_fs_allowed_dirs: Vec<String>,
+
     /// This is the msize used for 9p shares. It is the number of bytes used for 9p packet payload.
     #[serde(default)]
     pub msize_9p: u32,
@@ -805,7 +966,7 @@ impl SharedFsInfo {
             self.shared_fs = Some(default::DEFAULT_SHARED_FS_TYPE.to_string());
         }
         match self.shared_fs.as_deref() {
-            Some(VIRTIO_FS) => self.adjust_virtio_fs(false)?,
+            Some(VIRTIO_FS) | Some(SHARED_FS_AUTO) => self.adjust_virtio_fs(false)?,
             Some(VIRTIO_FS_INLINE) => self.adjust_virtio_fs(true)?,
             Some(VIRTIO_9P) => {
                 if self.msize_9p == 0 {
@@ -822,7 +983,7 @@ impl SharedFsInfo {
     pub fn validate(&self) -> Result<()> {
         match self.shared_fs.as_deref() {
             None => Ok(()),
-            Some(VIRTIO_FS) => self.validate_virtio_fs(false),
+            Some(VIRTIO_FS) | Some(SHARED_FS_AUTO) => self.validate_virtio_fs(false),
             Some(VIRTIO_FS_INLINE) => self.validate_virtio_fs(true),
             Some(VIRTIO_9P) => {
                 if self.msize_9p < default::MIN_SHARED_9PFS_SIZE_MB
@@ -866,6 +1027,9 @@ impl SharedFsInfo {
         if !self.virtio_fs_is_dax && self.virtio_fs_cache_size != 0 {
             self.virtio_fs_is_dax = true;
         }
+        if self.virtio_fs_ready_timeout_secs == 0 {
+            self.virtio_fs_ready_timeout_secs = default::DEFAULT_VIRTIO_FS_READY_TIMEOUT_SECS;
+        }
         Ok(())
     }
 
@@ -892,6 +1056,51 @@ impl SharedFsInfo {
                 &self.virtio_fs_cache_size
             ));
         }
+        if self.enable_xattr == Some(false)
+            && self.virtio_fs_extra_args.iter().any(|a| a == "--xattr")
+        {
+            return Err(eother!(
+                "virtio-fs extra args enable xattr disabled by enable_xattr: {:?}",
+                &self.virtio_fs_extra_args
+            ));
+        }
+        if !self.virtio_fs_allowed_dirs.is_empty() {
+            self.validate_virtio_fs_allowed_dirs(inline)?;
+        }
+        Ok(())
+    }
+
+    fn validate_virtio_fs_allowed_dirs(&self, inline: bool) -> Result<()> {
+        if inline {
+            return Err(eother!(
+                "virtio-fs allowed dirs need virtiofsd, not inline-virtio-fs: {:?}",
+                &self.virtio_fs_allowed_dirs
+            ));
+        }
+        // virtiofsd is confined by its own sandbox
+        if self
+            .virtio_fs_extra_args
+            .iter()
+            .any(|a| a.starts_with("--sandbox"))
+        {
+            return Err(eother!(
+                "virtio-fs extra args override the sandbox of the allowed dirs: {:?}",
+                &self.virtio_fs_extra_args
+            ));
+        }
+        for dir in self.virtio_fs_allowed_dirs.iter() {
+            let path = Path::new(dir);
+            if dir.is_empty()
+                || !path
+                    .components()
+                    .all(|c| matches!(c, std::path::Component::Normal(_)))
+            {
+                return Err(eother!(
+                    "Invalid virtio-fs allowed dir {}, not a relative path without ..",
+                    dir
+                ));
+            }
+        }
         Ok(())
     }
 }
@@ -1113,6 +1322,25 @@ mod tests {
         assert!(get_hypervisor_plugin("dragonball2").is_none());
     }
 
+    #[test]
+    fn test_validate_virtio_fs_allowed_dirs() {
+        let mut info = SharedFsInfo {
+            virtio_fs_allowed_dirs: vec!["passthrough".to_string(), "rafs/images".to_string()],
+            ..Default::default()
+        };
+        info.validate_virtio_fs_allowed_dirs(false).unwrap();
+        info.validate_virtio_fs_allowed_dirs(true).unwrap_err();
+
+        for dir in vec!["", "/passthrough", "passthrough/../rw", "./passthrough"] {
+            info.virtio_fs_allowed_dirs = vec![dir.to_string()];
+            info.validate_virtio_fs_allowed_dirs(false).unwrap_err();
+        }
+
+        info.virtio_fs_allowed_dirs = vec!["passthrough".to_string()];
+        info.virtio_fs_extra_args = vec!["--sandbox=chroot".to_string()];
+        info.validate_virtio_fs_allowed_dirs(false).unwrap_err();
+    }
+
     #[test]
     fn test_add_kernel_params() {
         let mut boot_info = BootInfo {
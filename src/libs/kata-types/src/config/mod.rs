@@ -30,9 +30,17 @@ pub use self::hypervisor::{
 };
 
 mod runtime;
-pub use self::runtime::{Runtime, RuntimeVendor, RUNTIME_NAME_VIRTCONTAINER};
+pub use self::runtime::{
+    Runtime, RuntimeVendor, MISSING_DEVICE_POLICY_SKIP, RUNTIME_NAME_VIRTCONTAINER,
+};
+
+mod retry;
+pub use self::retry::{RetryConfig, RetryDelays, RetryPolicy};
+
+mod consistency;
+pub use self::consistency::{validate_violations, Severity, Violation};
 
-pub use self::agent::AGENT_NAME_KATA;
+pub use self::agent::{AGENT_NAME_KATA, DEVICE_CLASS_BLOCK, DEVICE_CLASS_CHAR, DEVICE_CLASS_VFIO};
 
 // TODO: let agent use the constants here for consistency
 /// Debug console enabled flag for agent
@@ -108,6 +116,9 @@ pub struct TomlConfig {
     /// Kata runtime configuration information.
     #[serde(default)]
     pub runtime: Runtime,
+    /// Configuration annotations of the sandbox merged into the configuration.
+    #[serde(skip)]
+    pub annotations: HashMap<String, String>,
 }
 
 impl TomlConfig {
@@ -0,0 +1,268 @@
+// Copyright (c) 2023 Alibaba Cloud
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Result;
+use std::time::Duration;
+
+use crate::eother;
+
+/// Maximum jitter in percent of the delay.
+pub const MAX_RETRY_JITTER: u32 = 100;
+
+/// Policy to retry an operation with exponential backoff.
+///
+/// The n-th retry waits `base_delay_ms * 2^(n-1)`, capped by `max_delay_ms`. The delay is then
+/// randomly shortened by up to `jitter` percent, so that the retries of concurrent sandboxes
+/// don't go in lockstep. A policy with `base_delay_ms == max_delay_ms` and no jitter retries at
+/// a fixed interval.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one.
+    #[serde(default)]
+    pub max_attempts: u32,
+
+    /// Delay in milliseconds before the first retry.
+    #[serde(default)]
+    pub base_delay_ms: u64,
+
+    /// Maximum delay in milliseconds between two attempts.
+    #[serde(default)]
+    pub max_delay_ms: u64,
+
+    /// Percentage of the delay to be randomly cut, from 0 to 100.
+    #[serde(default)]
+    pub jitter: u32,
+}
+
+impl RetryPolicy {
+    /// Create a policy to retry at a fixed interval.
+    pub const fn fixed(max_attempts: u32, delay_ms: u64) -> Self {
+        Self {
+            max_attempts,
+            base_delay_ms: delay_ms,
+            max_delay_ms: delay_ms,
+            jitter: 0,
+        }
+    }
+
+    /// Validate the policy.
+    pub fn validate(&self) -> Result<()> {
+        if self.max_attempts == 0 {
+            return Err(eother!("retry policy needs at least one attempt"));
+        }
+        if self.base_delay_ms > self.max_delay_ms {
+            return Err(eother!(
+                "retry base delay {} ms is bigger than max delay {} ms",
+                self.base_delay_ms,
+                self.max_delay_ms
+            ));
+        }
+        if self.jitter > MAX_RETRY_JITTER {
+            return Err(eother!(
+                "retry jitter {} is bigger than {}",
+                self.jitter,
+                MAX_RETRY_JITTER
+            ));
+        }
+        Ok(())
+    }
+
+    /// Get the delays between the attempts, one less than `max_attempts`.
+    ///
+    /// It works for both sync and async callers, which sleep for each delay returned before
+    /// retrying, and give up when there is no delay left.
+    pub fn delays(&self) -> RetryDelays {
+        RetryDelays {
+            policy: *self,
+            retry: 0,
+            random: RandomState::new(),
+        }
+    }
+
+    /// Run the operation until it succeeds or the attempts are used up, the last error is
+    /// returned. It sleeps the calling thread between the attempts.
+    pub fn retry<T, E, F>(&self, mut f: F) -> std::result::Result<T, E>
+    where
+        F: FnMut(u32) -> std::result::Result<T, E>,
+    {
+        let mut delays = self.delays();
+        let mut attempt = 0;
+        loop {
+            match f(attempt) {
+                Ok(v) => return Ok(v),
+                Err(e) => match delays.next() {
+                    Some(delay) => std::thread::sleep(delay),
+                    None => return Err(e),
+                },
+            }
+            attempt += 1;
+        }
+    }
+
+    fn delay(&self, retry: u32, random: u64) -> Duration {
+        let backoff = self
+            .base_delay_ms
+            .saturating_mul(1u64.checked_shl(retry).unwrap_or(u64::MAX))
+            .min(self.max_delay_ms);
+        let jitter = backoff.saturating_mul(self.jitter.min(MAX_RETRY_JITTER) as u64) / 100;
+        let cut = if jitter == 0 {
+            0
+        } else {
+            random % (jitter + 1)
+        };
+        Duration::from_millis(backoff - cut)
+    }
+}
+
+/// Iterator of the delays between the attempts of a [`RetryPolicy`].
+#[derive(Clone, Debug)]
+pub struct RetryDelays {
+    policy: RetryPolicy,
+    retry: u32,
+    random: RandomState,
+}
+
+impl Iterator for RetryDelays {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        if self.retry + 1 >= self.policy.max_attempts {
+            return None;
+        }
+        let mut hasher = self.random.build_hasher();
+        hasher.write_u32(self.retry);
+        let delay = self.policy.delay(self.retry, hasher.finish());
+        self.retry += 1;
+        Some(delay)
+    }
+}
+
+/// Retry policies of the runtime, the built-in policy of each operation is used if unset.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RetryConfig {
+    /// Policy to retry the requests to the hypervisor which isn't ready yet.
+    #[serde(default)]
+    pub hypervisor_request: Option<RetryPolicy>,
+
+    /// Policy to retry connecting to the agent while the guest boots.
+    #[serde(default)]
+    pub agent_connect: Option<RetryPolicy>,
+
+    /// Policy to retry the idempotent agent requests after reconnecting to the agent.
+    #[serde(default)]
+    pub agent_request: Option<RetryPolicy>,
+
+    /// Policy to check the health of the agent again until it's ready or the ready timeout of
+    /// the agent is over.
+    #[serde(default)]
+    pub agent_ready: Option<RetryPolicy>,
+
+    /// Policy to check the readiness of the resources of the sandbox again once the VM is
+    /// started, until they're ready or the timeout is over.
+    #[serde(default)]
+    pub resource_ready: Option<RetryPolicy>,
+
+    /// Policy to check the mount points of the shared filesystem in the guest again until
+    /// they're mounted or the timeout is over.
+    #[serde(default)]
+    pub share_fs_mount: Option<RetryPolicy>,
+}
+
+impl RetryConfig {
+    /// Validate the retry policies.
+    pub fn validate(&self) -> Result<()> {
+        for (name, policy) in [
+            ("hypervisor_request", &self.hypervisor_request),
+            ("agent_connect", &self.agent_connect),
+            ("agent_request", &self.agent_request),
+            ("agent_ready", &self.agent_ready),
+            ("resource_ready", &self.resource_ready),
+            ("share_fs_mount", &self.share_fs_mount),
+        ] {
+            if let Some(policy) = policy {
+                policy
+                    .validate()
+                    .map_err(|e| eother!("invalid retry policy {}: {}", name, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_policy_delays() {
+        let policy = RetryPolicy::fixed(3, 10);
+        let delays: Vec<Duration> = policy.delays().collect();
+        assert_eq!(delays, vec![Duration::from_millis(10); 2]);
+
+        let policy = RetryPolicy {
+            max_attempts: 6,
+            base_delay_ms: 10,
+            max_delay_ms: 100,
+            jitter: 0,
+        };
+        let delays: Vec<u128> = policy.delays().map(|d| d.as_millis()).collect();
+        assert_eq!(delays, vec![10, 20, 40, 80, 100]);
+
+        let policy = RetryPolicy {
+            jitter: 50,
+            ..policy
+        };
+        for (delay, backoff) in policy.delays().zip([10, 20, 40, 80, 100]) {
+            let delay = delay.as_millis();
+            assert!(delay <= backoff && delay >= backoff / 2);
+        }
+
+        assert_eq!(RetryPolicy::fixed(1, 10).delays().count(), 0);
+        assert_eq!(RetryPolicy::fixed(0, 10).delays().count(), 0);
+    }
+
+    #[test]
+    fn test_retry_policy_retry() {
+        let policy = RetryPolicy::fixed(3, 0);
+
+        let mut attempts = 0;
+        let result: std::result::Result<u32, u32> = policy.retry(|attempt| {
+            attempts += 1;
+            if attempt < 2 {
+                Err(attempt)
+            } else {
+                Ok(attempt)
+            }
+        });
+        assert_eq!(result, Ok(2));
+        assert_eq!(attempts, 3);
+
+        let result: std::result::Result<(), u32> = policy.retry(Err);
+        assert_eq!(result, Err(2));
+    }
+
+    #[test]
+    fn test_retry_policy_validate() {
+        assert!(RetryPolicy::fixed(1, 10).validate().is_ok());
+        assert!(RetryPolicy::fixed(0, 10).validate().is_err());
+        assert!(RetryPolicy {
+            max_attempts: 1,
+            base_delay_ms: 20,
+            max_delay_ms: 10,
+            jitter: 0,
+        }
+        .validate()
+        .is_err());
+        assert!(RetryPolicy {
+            jitter: 101,
+            ..RetryPolicy::fixed(1, 10)
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -3,17 +3,26 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use std::collections::HashMap;
 use std::io::Result;
 use std::path::Path;
 
+use super::agent::{DEVICE_CLASSES, DEVICE_CLASS_VFIO};
 use super::default;
-use crate::config::{ConfigOps, TomlConfig};
-use crate::mount::split_bind_mounts;
+use crate::config::{ConfigOps, RetryConfig, TomlConfig};
+use crate::mount::{
+    is_rootfs_mount_options_fs_type, is_safe_rootfs_mount_option, split_bind_mounts,
+};
 use crate::{eother, validate_path};
 
 /// Type of runtime VirtContainer.
 pub const RUNTIME_NAME_VIRTCONTAINER: &str = "virt_container";
 
+/// The container fails to be created if one of its devices isn't on the host.
+pub const MISSING_DEVICE_POLICY_FAIL: &str = "fail";
+/// The devices of the container not on the host are left out with a warning.
+pub const MISSING_DEVICE_POLICY_SKIP: &str = "skip";
+
 /// Kata runtime configuration information.
 #[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Runtime {
@@ -50,6 +59,18 @@ pub struct Runtime {
     #[serde(default)]
     pub internetworking_model: String,
 
+    /// Type of the network endpoints connecting the interfaces to the VM.
+    ///
+    /// Options:
+    /// - tap: a tap device connected to the network interface by the internetworking model.
+    /// - macvtap: the queues of a macvtap network interface with a real parent link, handed to
+    ///   the hypervisor as file descriptors.
+    /// - ipvlan: a tap device connected to an ipvlan network interface.
+    ///
+    /// If unspecified, the endpoint type is inferred from the network interface.
+    #[serde(default)]
+    pub network_endpoint_type: String,
+
     /// If enabled, the runtime won't create a network namespace for shim and hypervisor processes.
     ///
     /// This option may have some potential impacts to your host. It should only be used when you
@@ -75,6 +96,25 @@ pub struct Runtime {
     #[serde(default)]
     pub sandbox_bind_mounts: Vec<String>,
 
+    /// Host path prefixes the sandbox bind mounts of the pod annotation may be mounted from, the
+    /// annotation is refused if empty. The annotated paths are checked once their symlinks are
+    /// resolved, and mounted along with sandbox_bind_mounts.
+    #[serde(default)]
+    pub valid_sandbox_bind_mount_prefixes: Vec<String>,
+
+    /// Host path prefixes the sources of the volumes, of the rootfs and of the sandbox bind mounts
+    /// must stay under, checked before anything is mounted or attached against the symlink
+    /// escapes of a compromised CSI driver or of a crafted spec: neither the symlinks nor ".." may
+    /// leave the prefix on the way. Empty, the default, to leave the mount sources unchecked, e.g.
+    /// for the hostPath volumes mounting from anywhere on the host.
+    #[serde(default)]
+    pub mount_source_prefixes: Vec<String>,
+
+    /// Don't check the mount sources against mount_source_prefixes even if it's set, e.g. to turn
+    /// the check off for a while without losing the prefixes.
+    #[serde(default)]
+    pub disable_mount_source_jail: bool,
+
     /// If enabled, the runtime will add all the kata processes inside one dedicated cgroup.
     ///
     /// The container cgroups in the host are not created, just one single cgroup per sandbox.
@@ -103,16 +143,212 @@ pub struct Runtime {
     #[serde(default)]
     pub enable_pprof: bool,
 
+    /// Size in MiB of the /dev/shm of the containers mounted without a size option, 64MiB if 0.
+    /// The size is clamped to the memory of the guest.
+    #[serde(default)]
+    pub default_shm_size_mb: u32,
+
+    /// If enabled, the metrics of the resources served by the shim are labeled with the
+    /// sandbox id. Off by default, since each sandbox makes new series in the metrics store.
+    #[serde(default)]
+    pub metrics_sandbox_id_label: bool,
+
+    /// If enabled, the warnings of the consistency checks of the configuration fail the creation
+    /// of the sandbox like the errors do, rather than being logged.
+    #[serde(default)]
+    pub strict_config_validation: bool,
+
+    /// If enabled, the mounts asking for the propagation from the host, which their volumes can't
+    /// do, are only warned about rather than failing the creation of the container.
+    #[serde(default)]
+    pub allow_unsupported_mount_propagation: bool,
+
+    /// If enabled, the devices of the classes in device_capabilities are only attached to the
+    /// containers having the capability of their class in their effective set, the others fail
+    /// the creation of the container.
+    #[serde(default)]
+    pub enforce_device_capabilities: bool,
+
+    /// Capability a container needs for a device of the class to be attached when
+    /// enforce_device_capabilities is set, keyed by the device class "block", "vfio" or "char".
+    /// The classes not listed need no capability.
+    ///  - device_capabilities={ block="CAP_SYS_RAWIO", vfio="CAP_SYS_ADMIN" }
+    #[serde(default)]
+    pub device_capabilities: HashMap<String, String>,
+
+    /// Guest kernel parameters the devices of the class need to work, keyed by the device class,
+    /// only "vfio" today, e.g. the IOMMU of the guest. They're added to the kernel command line
+    /// of the VM if a device of the class is cold plugged, the physical network interfaces passed
+    /// through with vfio, they can't be once the VM is booted, so the devices of the class
+    /// hotplugged go without them.
+    /// The parameters asked by several devices are added once, and the values of "vfio-pci.ids"
+    /// and "modprobe.blacklist" are merged, the other parameters given different values fail
+    /// the creation of the sandbox.
+    ///  - device_kernel_params={ vfio=["intel_iommu=on", "iommu=pt"] }
+    #[serde(default)]
+    pub device_kernel_params: HashMap<String, Vec<String>>,
+
+    /// What's done with the block and the char devices of a container which aren't on the host,
+    /// e.g. from a stale spec or removed by a hotplug race: "fail" fails the creation of the
+    /// container before anything is attached, "skip" leaves them out with a warning. "fail" if
+    /// empty.
+    #[serde(default)]
+    pub missing_device_policy: String,
+
+    /// Maximum number of the devices taking a guest PCI slot, the block and the vfio devices and
+    /// the SR-IOV VFs, a container asks for. The containers beyond it fail to be created before
+    /// anything is attached. 0 for no limit.
+    #[serde(default)]
+    pub max_devices_per_container: u32,
+
+    /// Maximum number of the volumes from the host a container mounts, shared with the guest or
+    /// passed as block devices. The containers beyond it fail to be created before anything is
+    /// set up. 0 for no limit.
+    #[serde(default)]
+    pub max_volumes_per_container: u32,
+
+    /// Maximum number of the devices of max_devices_per_container the containers of the sandbox
+    /// ask for altogether, the ones of the containers deleted are given back. 0 for no limit.
+    #[serde(default)]
+    pub max_devices_per_sandbox: u32,
+
+    /// The network interfaces of the SR-IOV PFs whose VFs the containers may ask for with the
+    /// annotation `io.katacontainers.container.sriov_vfs`, which also has to be enabled by
+    /// enable_annotations of the hypervisor. Only the VFs bound to vfio-pci by the admin are
+    /// taken, the VFs of the host drivers are left alone. Empty to refuse the annotation.
+    #[serde(default)]
+    pub sriov_allowed_pfs: Vec<String>,
+
+    /// Maximum number of the mounts in the guest: the storages of the sandbox, the rootfs of the
+    /// containers with their storages, and the mounts and the storages of their volumes, a
+    /// storage shared by several volumes is counted once. The containers whose volumes would go
+    /// over it fail to be created. 0 for no limit.
+    #[serde(default)]
+    pub max_mounts_per_sandbox: u32,
+
     /// If enabled, static resource management will calculate the vcpu and memory for the sandbox/container
     /// And pod configured this will not be able to further update its CPU/Memory resource
     #[serde(default)]
     pub static_sandbox_resource_mgmt: bool,
 
+    /// If enabled, each guest vCPU is pinned to a host CPU of the cpuset of the sandbox cgroup,
+    /// as long as there are as many vCPUs as CPUs in it; otherwise the vCPUs run on any of them.
+    /// It's checked again whenever the containers are resized.
+    #[serde(default)]
+    pub enable_vcpus_pinning: bool,
+
+    /// If enabled, the sandbox is privileged: the host block devices of the CDI devices the
+    /// privileged containers (the ones allowed to access all devices) ask for by the `cdi.k8s.io/`
+    /// annotations are attached to them, and their device cgroup rules are relaxed to allow all
+    /// devices in the guest.
+    /// It gives the containers access to the host devices, so only enable it for trusted workloads.
+    #[serde(default)]
+    pub privileged_sandbox: bool,
+
+    /// If enabled, the host block devices listed in the spec of the privileged containers aren't
+    /// attached to the guest, except the device nodes of the CDI devices asked for by the
+    /// `cdi.k8s.io/` annotations of the container, e.g. by the device plugins. Otherwise the
+    /// privileged containers are refused beyond a limit of block devices.
+    #[serde(default)]
+    pub privileged_without_host_devices: bool,
+
     /// Determines whether container seccomp profiles are passed to the virtual machine and
     /// applied by the kata agent. If set to true, seccomp is not applied within the guest.
     #[serde(default)]
     pub disable_guest_seccomp: bool,
 
+    /// If enabled, the seccomp profile of the containers accessing devices is still applied in
+    /// the guest when `disable_guest_seccomp` is set, so that the devices don't bypass the
+    /// syscall filtering.
+    #[serde(default)]
+    pub guest_seccomp_for_devices: bool,
+
+    /// If enabled, the regular files mounted in the containers up to copy_file_max_size bytes,
+    /// e.g. resolv.conf or hostname, are copied into the guest instead of being shared with the
+    /// guest. They're static: the updates on the host aren't seen by the containers.
+    #[serde(default)]
+    pub enable_copy_small_files: bool,
+
+    /// Maximum size in bytes of the files copied into the guest when enable_copy_small_files is
+    /// set, the default is used if 0.
+    #[serde(default)]
+    pub copy_file_max_size: u64,
+
+    /// If enabled, the images of the containers are pulled inside the guest by the agent instead
+    /// of sharing the rootfs from the host, which needs an agent built with image pull support.
+    /// It's how the rootfs gets into the guest when the hypervisor supports neither filesystem
+    /// sharing nor block device hotplug, the volumes needing filesystem sharing are rejected then.
+    #[serde(default)]
+    pub experimental_force_guest_pull: bool,
+
+    /// Host directory stacked as the lowest read-only layer under the overlay rootfs of the
+    /// containers, e.g. to inject the debug tools without modifying the images. The files of the
+    /// images are never shadowed by the layer. Empty disables it.
+    #[serde(default)]
+    pub rootfs_lower_layer: String,
+
+    /// Storage in the guest of the writable layer of the containers, the overlay upperdir and
+    /// workdir stacked over their rootfs: "tmpfs" or "block" for a dedicated block device of each
+    /// container. Empty keeps the writable layer in the rootfs shared from the host.
+    #[serde(default)]
+    pub rootfs_upper_storage: String,
+
+    /// Default mount options of the rootfs of the containers on a block device, keyed by its
+    /// filesystem type "ext4", "xfs" or "erofs". They're merged with the options of the rootfs
+    /// mount, which win on conflicts. Only the options known safe for the type are accepted.
+    ///  - rootfs_mount_options={ ext4=["noatime", "discard"], xfs=["noatime"] }
+    #[serde(default)]
+    pub rootfs_mount_options: HashMap<String, Vec<String>>,
+
+    /// Filesystem types on the host of the shared volumes which fall back if the guest fails to
+    /// mount them, e.g. "vfat": to a bind from the shared directory of the sandbox instead of
+    /// their own storage in the guest, or to a copy into the guest for the small files. The
+    /// container is created with the volume degraded rather than failing. Empty disables it.
+    #[serde(default)]
+    pub share_fs_fallback_fstypes: Vec<String>,
+
+    /// Host directory under which the directories shared with the guests are created, one for
+    /// each sandbox by its id, e.g. on a fast local disk. It must exist and be writable. Empty
+    /// keeps them under /run/kata-containers/shared/sandboxes.
+    #[serde(default)]
+    pub shared_dir_root: String,
+
+    /// If enabled, each container gets its own virtio-fs export, served by its own virtiofsd
+    /// with its own device tag, instead of the one of the sandbox: the rootfs and the volumes of
+    /// a container are only shared through its export, out of reach of the other containers.
+    /// The sandbox-wide export keeps the sandbox bind mounts. Only with the "virtio-fs" shared_fs.
+    #[serde(default)]
+    pub per_container_sharefs: bool,
+
+    /// Maximum number of the containers having their own export with per_container_sharefs,
+    /// the sandbox container included. The exports are all set up before the VM starts, the
+    /// virtio-fs devices can't be hotplugged, and a container over it fails to be created. 4 if
+    /// 0, at most 16.
+    #[serde(default)]
+    pub max_sharefs_instances: u32,
+
+    /// Size in MiB of the storage of the writable layer of each container, the writes beyond fail
+    /// with ENOSPC in the container. Needed for "block", half of the guest memory for "tmpfs" if 0.
+    #[serde(default)]
+    pub rootfs_upper_size_mb: u32,
+
+    /// Path on the host of the mkfs.ext4 formatting the "block" storage of the writable layer,
+    /// the one found in PATH if empty.
+    #[serde(default)]
+    pub rootfs_upper_mkfs_path: String,
+
+    /// Timeout in seconds of each step setting up the resources of the sandbox before the VM
+    /// starts, e.g. the shared filesystem or the network. What's set up already is undone on a
+    /// timeout. The default timeout is used if 0.
+    #[serde(default)]
+    pub resource_setup_timeout: u64,
+
+    /// Timeout in milliseconds to wait for the storages of the shared filesystem to be mounted in
+    /// the guest before a container starts, the guest mounts them asynchronously. The container
+    /// fails to start if one is still missing after it. It isn't waited for if 0.
+    #[serde(default)]
+    pub share_fs_ready_timeout_ms: u64,
+
     /// Determines how VFIO devices should be be presented to the container.
     ///
     /// Options:
@@ -137,6 +373,12 @@ pub struct Runtime {
     /// This option is typically used to retain abnormal information for debugging.
     #[serde(default)]
     pub keep_abnormal: bool,
+
+    /// Retry policies of the operations which may fail transiently, e.g. while the guest boots.
+    ///
+    /// The built-in policy of an operation is used if its policy is unset.
+    #[serde(default)]
+    pub retry: RetryConfig,
 }
 
 impl ConfigOps for Runtime {
@@ -145,6 +387,12 @@ impl ConfigOps for Runtime {
         if conf.runtime.internetworking_model.is_empty() {
             conf.runtime.internetworking_model = default::DEFAULT_INTERNETWORKING_MODEL.to_owned();
         }
+        if conf.runtime.copy_file_max_size == 0 {
+            conf.runtime.copy_file_max_size = default::DEFAULT_COPY_FILE_MAX_SIZE;
+        }
+        if conf.runtime.max_sharefs_instances == 0 {
+            conf.runtime.max_sharefs_instances = default::DEFAULT_MAX_SHAREFS_INSTANCES;
+        }
 
         for bind in conf.runtime.sandbox_bind_mounts.iter_mut() {
             // Split the bind mount, canonicalize the path and then append rw mode to it.
@@ -175,6 +423,20 @@ impl ConfigOps for Runtime {
             ));
         }
 
+        let endpoint_type = &conf.runtime.network_endpoint_type;
+        if !endpoint_type.is_empty()
+            && endpoint_type != "tap"
+            && endpoint_type != "macvtap"
+            && endpoint_type != "ipvlan"
+        {
+            return Err(eother!(
+                "Invalid network_endpoint_type `{}` in configuration file",
+                endpoint_type
+            ));
+        }
+
+        conf.runtime.retry.validate()?;
+
         let vfio_mode = &conf.runtime.vfio_mode;
         if !vfio_mode.is_empty() && vfio_mode != "vfio" && vfio_mode != "guest-kernel" {
             return Err(eother!(
@@ -183,6 +445,130 @@ impl ConfigOps for Runtime {
             ));
         }
 
+        let missing_device_policy = &conf.runtime.missing_device_policy;
+        if !missing_device_policy.is_empty()
+            && missing_device_policy != MISSING_DEVICE_POLICY_FAIL
+            && missing_device_policy != MISSING_DEVICE_POLICY_SKIP
+        {
+            return Err(eother!(
+                "Invalid missing_device_policy `{}` in configuration file",
+                missing_device_policy
+            ));
+        }
+
+        let upper_storage = &conf.runtime.rootfs_upper_storage;
+        if !upper_storage.is_empty() && upper_storage != "tmpfs" && upper_storage != "block" {
+            return Err(eother!(
+                "Invalid rootfs_upper_storage `{}` in configuration file",
+                upper_storage
+            ));
+        }
+        if upper_storage == "block" && conf.runtime.rootfs_upper_size_mb == 0 {
+            return Err(eother!(
+                "rootfs_upper_size_mb is needed for block rootfs_upper_storage"
+            ));
+        }
+        let mkfs_path = &conf.runtime.rootfs_upper_mkfs_path;
+        if !mkfs_path.is_empty() && !Path::new(mkfs_path).is_file() {
+            return Err(eother!(
+                "rootfs_upper_mkfs_path `{}` isn't a file",
+                mkfs_path
+            ));
+        }
+
+        let lower_layer = &conf.runtime.rootfs_lower_layer;
+        if !lower_layer.is_empty() {
+            if !Path::new(lower_layer).is_absolute() {
+                return Err(eother!(
+                    "rootfs_lower_layer `{}` isn't an absolute path",
+                    lower_layer
+                ));
+            }
+            validate_path!(lower_layer, "rootfs_lower_layer `{}` is invalid: {}")?;
+        }
+
+        for (fs_type, options) in conf.runtime.rootfs_mount_options.iter() {
+            if !is_rootfs_mount_options_fs_type(fs_type) {
+                return Err(eother!(
+                    "rootfs_mount_options of unsupported filesystem type {}",
+                    fs_type
+                ));
+            }
+            if let Some(option) = options
+                .iter()
+                .find(|o| !is_safe_rootfs_mount_option(fs_type, o))
+            {
+                return Err(eother!(
+                    "Invalid mount option `{}` of {} in rootfs_mount_options",
+                    option,
+                    fs_type
+                ));
+            }
+        }
+
+        for (class, capability) in conf.runtime.device_capabilities.iter() {
+            if !DEVICE_CLASSES.contains(&class.as_str()) {
+                return Err(eother!(
+                    "device_capabilities of unknown device class {}, expect one of {:?}",
+                    class,
+                    DEVICE_CLASSES
+                ));
+            }
+            if !is_capability_name(capability) {
+                return Err(eother!(
+                    "Invalid capability `{}` of device class {} in device_capabilities",
+                    capability,
+                    class
+                ));
+            }
+        }
+
+        // the block and the char devices are never cold plugged
+        for (class, params) in conf.runtime.device_kernel_params.iter() {
+            if class != DEVICE_CLASS_VFIO {
+                return Err(eother!(
+                    "device_kernel_params of device class {}, only the {} devices are cold plugged",
+                    class,
+                    DEVICE_CLASS_VFIO
+                ));
+            }
+            if let Some(param) = params
+                .iter()
+                .find(|p| p.is_empty() || p.starts_with('=') || p.contains(char::is_whitespace))
+            {
+                return Err(eother!(
+                    "Invalid kernel parameter `{}` of device class {} in device_kernel_params",
+                    param,
+                    class
+                ));
+            }
+        }
+
+        let shared_dir_root = &conf.runtime.shared_dir_root;
+        if !shared_dir_root.is_empty() && !Path::new(shared_dir_root).is_absolute() {
+            return Err(eother!(
+                "shared_dir_root `{}` isn't an absolute path",
+                shared_dir_root
+            ));
+        }
+
+        if conf.runtime.max_sharefs_instances > default::MAX_SHAREFS_INSTANCES {
+            return Err(eother!(
+                "max_sharefs_instances {} is over the maximum {}",
+                conf.runtime.max_sharefs_instances,
+                default::MAX_SHAREFS_INSTANCES
+            ));
+        }
+
+        for prefix in conf.runtime.mount_source_prefixes.iter() {
+            if !Path::new(prefix).is_absolute() {
+                return Err(eother!(
+                    "mount source prefix `{}` isn't an absolute path",
+                    prefix
+                ));
+            }
+        }
+
         for bind in conf.runtime.sandbox_bind_mounts.iter() {
             // Just validate the real_path.
             let (real_path, _mode) = split_bind_mounts(bind);
@@ -203,6 +589,19 @@ impl Runtime {
     }
 }
 
+// the capabilities are named as in the OCI spec, e.g. CAP_SYS_RAWIO
+fn is_capability_name(capability: &str) -> bool {
+    match capability.strip_prefix("CAP_") {
+        Some(name) => {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+        }
+        None => false,
+    }
+}
+
 #[cfg(not(feature = "enable-vendor"))]
 mod vendor {
     use super::*;
@@ -251,6 +650,14 @@ internetworking_model = "macvtap,none"
         let content = r#"
 [runtime]
 enable_debug = true
+network_endpoint_type = "veth"
+"#;
+        let config: TomlConfig = TomlConfig::load(content).unwrap();
+        config.validate().unwrap_err();
+
+        let content = r#"
+[runtime]
+enable_debug = true
 vfio_mode = "none"
 "#;
         let config: TomlConfig = TomlConfig::load(content).unwrap();
@@ -268,6 +675,165 @@ vfio_mode = "vfio,guest-kernel"
 [runtime]
 enable_debug = true
 vfio_mode = "guest_kernel"
+"#;
+        let config: TomlConfig = TomlConfig::load(content).unwrap();
+        config.validate().unwrap_err();
+
+        let content = r#"
+[runtime]
+missing_device_policy = "ignore"
+"#;
+        let config: TomlConfig = TomlConfig::load(content).unwrap();
+        config.validate().unwrap_err();
+
+        let content = r#"
+[runtime]
+mount_source_prefixes = ["var/lib/kubelet"]
+"#;
+        let config: TomlConfig = TomlConfig::load(content).unwrap();
+        config.validate().unwrap_err();
+
+        let content = r#"
+[runtime]
+enable_debug = true
+[runtime.retry.agent_connect]
+max_attempts = 10
+base_delay_ms = 100
+max_delay_ms = 10
+"#;
+        let config: TomlConfig = TomlConfig::load(content).unwrap();
+        config.validate().unwrap_err();
+    }
+
+    #[test]
+    fn test_retry_config() {
+        let content = r#"
+[runtime]
+[runtime.retry.hypervisor_request]
+max_attempts = 20
+base_delay_ms = 10
+max_delay_ms = 200
+jitter = 20
+[runtime.retry.share_fs_mount]
+max_attempts = 100
+base_delay_ms = 20
+max_delay_ms = 500
+"#;
+        let config: TomlConfig = TomlConfig::load(content).unwrap();
+        config.validate().unwrap();
+        let policy = config.runtime.retry.hypervisor_request.unwrap();
+        assert_eq!(policy.max_attempts, 20);
+        assert_eq!(policy.max_delay_ms, 200);
+        assert_eq!(policy.jitter, 20);
+        assert!(config.runtime.retry.agent_connect.is_none());
+        let policy = config.runtime.retry.share_fs_mount.unwrap();
+        assert_eq!(policy.max_attempts, 100);
+        assert_eq!(policy.base_delay_ms, 20);
+        assert!(config.runtime.retry.resource_ready.is_none());
+    }
+
+    #[test]
+    fn test_device_capabilities() {
+        let content = r#"
+[runtime]
+enforce_device_capabilities = true
+device_capabilities = { block = "CAP_SYS_RAWIO", vfio = "CAP_SYS_ADMIN" }
+"#;
+        let config: TomlConfig = TomlConfig::load(content).unwrap();
+        config.validate().unwrap();
+        assert!(config.runtime.enforce_device_capabilities);
+        assert_eq!(
+            config.runtime.device_capabilities.get("block").unwrap(),
+            "CAP_SYS_RAWIO"
+        );
+
+        let content = r#"
+[runtime]
+device_capabilities = { gpu = "CAP_SYS_ADMIN" }
+"#;
+        let config: TomlConfig = TomlConfig::load(content).unwrap();
+        config.validate().unwrap_err();
+
+        let content = r#"
+[runtime]
+device_capabilities = { block = "sys_rawio" }
+"#;
+        let config: TomlConfig = TomlConfig::load(content).unwrap();
+        config.validate().unwrap_err();
+    }
+
+    #[test]
+    fn test_device_kernel_params() {
+        let content = r#"
+[runtime]
+device_kernel_params = { vfio = ["intel_iommu=on", "iommu=pt"] }
+"#;
+        let config: TomlConfig = TomlConfig::load(content).unwrap();
+        config.validate().unwrap();
+        assert_eq!(
+            config.runtime.device_kernel_params.get("vfio").unwrap(),
+            &vec!["intel_iommu=on".to_string(), "iommu=pt".to_string()]
+        );
+
+        for invalid in [
+            r#"device_kernel_params = { gpu = ["intel_iommu=on"] }"#,
+            r#"device_kernel_params = { block = ["scsi_mod.scan=sync"] }"#,
+            r#"device_kernel_params = { vfio = ["intel_iommu=on iommu=pt"] }"#,
+            r#"device_kernel_params = { vfio = [""] }"#,
+            r#"device_kernel_params = { vfio = ["=on"] }"#,
+        ] {
+            let content = format!("[runtime]\n{}\n", invalid);
+            let config: TomlConfig = TomlConfig::load(&content).unwrap();
+            config.validate().unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_per_container_sharefs() {
+        let content = r#"
+[runtime]
+per_container_sharefs = true
+"#;
+        let config: TomlConfig = TomlConfig::load(content).unwrap();
+        config.validate().unwrap();
+        assert!(config.runtime.per_container_sharefs);
+        assert_eq!(
+            config.runtime.max_sharefs_instances,
+            default::DEFAULT_MAX_SHAREFS_INSTANCES
+        );
+
+        let content = r#"
+[runtime]
+per_container_sharefs = true
+max_sharefs_instances = 17
+"#;
+        let config: TomlConfig = TomlConfig::load(content).unwrap();
+        config.validate().unwrap_err();
+    }
+
+    #[test]
+    fn test_rootfs_mount_options() {
+        let content = r#"
+[runtime]
+rootfs_mount_options = { ext4 = ["noatime", "discard"], erofs = ["user_xattr"] }
+"#;
+        let config: TomlConfig = TomlConfig::load(content).unwrap();
+        config.validate().unwrap();
+        assert_eq!(
+            config.runtime.rootfs_mount_options.get("ext4").unwrap(),
+            &vec!["noatime".to_string(), "discard".to_string()]
+        );
+
+        let content = r#"
+[runtime]
+rootfs_mount_options = { btrfs = ["noatime"] }
+"#;
+        let config: TomlConfig = TomlConfig::load(content).unwrap();
+        config.validate().unwrap_err();
+
+        let content = r#"
+[runtime]
+rootfs_mount_options = { xfs = ["noatime", "exec"] }
 "#;
         let config: TomlConfig = TomlConfig::load(content).unwrap();
         config.validate().unwrap_err();
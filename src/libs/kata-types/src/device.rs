@@ -0,0 +1,118 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use anyhow::{anyhow, Context, Result};
+
+/// Option of the agent device with the file mode of the node in the container, in octal.
+pub const DEVICE_OPTION_FILE_MODE: &str = "file_mode";
+/// Option of the agent device with the uid owning the node in the container.
+pub const DEVICE_OPTION_UID: &str = "uid";
+/// Option of the agent device with the gid owning the node in the container.
+pub const DEVICE_OPTION_GID: &str = "gid";
+
+/// The ownership and the mode of the node of a device in the container, passed from the runtime
+/// to the agent in the options of the device. The agent keeps its defaults for the ones unset.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceNodeAttrs {
+    /// File mode of the node, the permission bits.
+    pub file_mode: Option<u32>,
+    /// Uid owning the node.
+    pub uid: Option<u32>,
+    /// Gid owning the node.
+    pub gid: Option<u32>,
+}
+
+impl DeviceNodeAttrs {
+    /// Get the options of the agent device, the values unset or zero are left out.
+    pub fn to_options(&self) -> Vec<String> {
+        let mut options = vec![];
+        if let Some(mode) = self.file_mode.filter(|m| *m != 0) {
+            options.push(format!("{}={:04o}", DEVICE_OPTION_FILE_MODE, mode));
+        }
+        if let Some(uid) = self.uid.filter(|u| *u != 0) {
+            options.push(format!("{}={}", DEVICE_OPTION_UID, uid));
+        }
+        if let Some(gid) = self.gid.filter(|g| *g != 0) {
+            options.push(format!("{}={}", DEVICE_OPTION_GID, gid));
+        }
+        options
+    }
+
+    /// Parse the options of the agent device, the other options, e.g. the PCI addresses of the
+    /// vfio devices, are skipped.
+    pub fn from_options(options: &[String]) -> Result<Self> {
+        let mut attrs = Self::default();
+        for option in options {
+            let (key, value) = match option.split_once('=') {
+                Some(kv) => kv,
+                None => continue,
+            };
+            match key {
+                DEVICE_OPTION_FILE_MODE => {
+                    let mode = u32::from_str_radix(value, 8)
+                        .with_context(|| format!("invalid device option {}", option))?;
+                    if mode & !0o7777 != 0 {
+                        return Err(anyhow!("invalid device option {}", option));
+                    }
+                    attrs.file_mode = Some(mode);
+                }
+                DEVICE_OPTION_UID => {
+                    attrs.uid = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("invalid device option {}", option))?,
+                    );
+                }
+                DEVICE_OPTION_GID => {
+                    attrs.gid = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("invalid device option {}", option))?,
+                    );
+                }
+                _ => {}
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_node_attrs() {
+        let attrs = DeviceNodeAttrs {
+            file_mode: Some(0o660),
+            uid: Some(0),
+            gid: Some(107),
+        };
+        let options = attrs.to_options();
+        assert_eq!(options, vec!["file_mode=0660", "gid=107"]);
+        assert_eq!(
+            DeviceNodeAttrs::from_options(&options).unwrap(),
+            DeviceNodeAttrs {
+                file_mode: Some(0o660),
+                uid: None,
+                gid: Some(107),
+            }
+        );
+        assert!(DeviceNodeAttrs::default().to_options().is_empty());
+
+        // the pci addresses of the vfio devices are skipped
+        let options = vec!["0000:01:00.0=02/01".to_string()];
+        assert_eq!(
+            DeviceNodeAttrs::from_options(&options).unwrap(),
+            DeviceNodeAttrs::default()
+        );
+
+        let options = vec!["file_mode=rw".to_string()];
+        DeviceNodeAttrs::from_options(&options).unwrap_err();
+        let options = vec!["file_mode=170000".to_string()];
+        DeviceNodeAttrs::from_options(&options).unwrap_err();
+    }
+}
@@ -159,6 +159,107 @@ impl NydusExtraOptions {
     }
 }
 
+/// Mount options of the rootfs safe for all the filesystem types of the block rootfs.
+const ROOTFS_COMMON_MOUNT_OPTIONS: &[&str] = &[
+    "atime",
+    "noatime",
+    "relatime",
+    "norelatime",
+    "strictatime",
+    "nostrictatime",
+    "diratime",
+    "nodiratime",
+    "lazytime",
+    "nolazytime",
+    "nodev",
+    "nosuid",
+];
+
+/// Mount options of the rootfs safe for each filesystem type of the block rootfs, the guest
+/// mounts the rootfs with them. The ones ending with '=' take a number, e.g. commit=30.
+const ROOTFS_MOUNT_OPTIONS: &[(&str, &[&str])] = &[
+    (
+        "ext4",
+        &[
+            "discard",
+            "nodiscard",
+            "barrier",
+            "nobarrier",
+            "delalloc",
+            "nodelalloc",
+            "commit=",
+            "data=ordered",
+            "data=writeback",
+            "data=journal",
+        ],
+    ),
+    (
+        "xfs",
+        &[
+            "discard",
+            "nodiscard",
+            "inode64",
+            "largeio",
+            "nolargeio",
+            "nouuid",
+            "logbufs=",
+            "logbsize=",
+        ],
+    ),
+    (
+        "erofs",
+        &[
+            "user_xattr",
+            "nouser_xattr",
+            "acl",
+            "noacl",
+            "cache_strategy=disabled",
+            "cache_strategy=readahead",
+            "cache_strategy=readaround",
+        ],
+    ),
+];
+
+/// Check whether the rootfs of the filesystem type could have default mount options.
+pub fn is_rootfs_mount_options_fs_type(fs_type: &str) -> bool {
+    ROOTFS_MOUNT_OPTIONS.iter().any(|(t, _)| *t == fs_type)
+}
+
+/// Check whether the mount option is known safe for the rootfs of the filesystem type.
+pub fn is_safe_rootfs_mount_option(fs_type: &str, option: &str) -> bool {
+    if ROOTFS_COMMON_MOUNT_OPTIONS.contains(&option) {
+        return true;
+    }
+    let options = match ROOTFS_MOUNT_OPTIONS.iter().find(|(t, _)| *t == fs_type) {
+        Some((_, options)) => options,
+        None => return false,
+    };
+    options.iter().any(|safe| {
+        if !safe.ends_with('=') {
+            return *safe == option;
+        }
+        // the sizes may have a unit, e.g. logbsize=256k
+        option
+            .strip_prefix(safe)
+            .map(|v| v.trim_end_matches(|c| c == 'k' || c == 'm'))
+            .map_or(false, |v| v.parse::<u64>().is_ok())
+    })
+}
+
+/// Get the key of the mount option, the options with the same key conflict: the value of the
+/// key=value options, the no prefix of the flags, and the atime ones which are exclusive.
+pub fn mount_option_key(option: &str) -> &str {
+    if let Some((key, _)) = option.split_once('=') {
+        return key;
+    }
+    match option {
+        "atime" | "noatime" | "relatime" | "norelatime" | "strictatime" | "nostrictatime" => {
+            "atime"
+        }
+        _ => option.strip_prefix("no").unwrap_or(option),
+    }
+}
+
 /// sandbox bindmount format:  /path/to/dir, or /path/to/dir:ro[:rw]
 /// the real path is without suffix ":ro" or ":rw".
 pub fn split_bind_mounts(bindmount: &str) -> (&str, &str) {
@@ -242,4 +343,30 @@ mod tests {
         );
         assert_eq!(extra_option.fs_version, "v6");
     }
+
+    #[test]
+    fn test_rootfs_mount_options() {
+        assert!(is_rootfs_mount_options_fs_type("xfs"));
+        assert!(!is_rootfs_mount_options_fs_type("overlay"));
+
+        assert!(is_safe_rootfs_mount_option("ext4", "noatime"));
+        assert!(is_safe_rootfs_mount_option("ext4", "discard"));
+        assert!(is_safe_rootfs_mount_option("ext4", "commit=30"));
+        assert!(is_safe_rootfs_mount_option("xfs", "logbsize=256k"));
+        assert!(is_safe_rootfs_mount_option(
+            "erofs",
+            "cache_strategy=readahead"
+        ));
+        // the options of another type, or unknown
+        assert!(!is_safe_rootfs_mount_option("erofs", "discard"));
+        assert!(!is_safe_rootfs_mount_option("ext4", "commit=soon"));
+        assert!(!is_safe_rootfs_mount_option("ext4", "data=unordered"));
+        assert!(!is_safe_rootfs_mount_option("ext4", "noexec"));
+
+        assert_eq!(mount_option_key("noatime"), "atime");
+        assert_eq!(mount_option_key("relatime"), "atime");
+        assert_eq!(mount_option_key("nodiscard"), "discard");
+        assert_eq!(mount_option_key("commit=30"), "commit");
+        assert_eq!(mount_option_key("ro"), "ro");
+    }
 }
@@ -7,7 +7,8 @@ mod tests {
     use kata_types::annotations::{
         Annotation, KATA_ANNO_CFG_AGENT_CONTAINER_PIPE_SIZE, KATA_ANNO_CFG_AGENT_TRACE,
         KATA_ANNO_CFG_DISABLE_GUEST_SECCOMP, KATA_ANNO_CFG_ENABLE_PPROF,
-        KATA_ANNO_CFG_EXPERIMENTAL, KATA_ANNO_CFG_HYPERVISOR_BLOCK_DEV_CACHE_NOFLUSH,
+        KATA_ANNO_CFG_ENFORCE_DEVICE_CAPABILITIES, KATA_ANNO_CFG_EXPERIMENTAL,
+        KATA_ANNO_CFG_HYPERVISOR_BLOCK_DEV_CACHE_NOFLUSH,
         KATA_ANNO_CFG_HYPERVISOR_BLOCK_DEV_DRIVER, KATA_ANNO_CFG_HYPERVISOR_CTLPATH,
         KATA_ANNO_CFG_HYPERVISOR_DEFAULT_MEMORY, KATA_ANNO_CFG_HYPERVISOR_DEFAULT_VCPUS,
         KATA_ANNO_CFG_HYPERVISOR_ENABLE_GUEST_SWAP, KATA_ANNO_CFG_HYPERVISOR_ENABLE_IO_THREADS,
@@ -490,4 +491,39 @@ mod tests {
         let mut config = TomlConfig::load(content).unwrap();
         assert!(anno.update_config_by_annotation(&mut config).is_err());
     }
+
+    #[test]
+    fn test_enforce_device_capabilities_only_tightened() {
+        let content = include_str!("texture/configuration-anno-0.toml");
+
+        let qemu = QemuConfig::new();
+        qemu.register();
+
+        let config = TomlConfig::load(content).unwrap();
+        KataConfig::set_active_config(Some(config), "qemu", "agent0");
+        let anno = |value: &str| {
+            let mut anno_hash = HashMap::new();
+            anno_hash.insert(
+                KATA_ANNO_CFG_ENFORCE_DEVICE_CAPABILITIES.to_string(),
+                value.to_string(),
+            );
+            Annotation::new(anno_hash)
+        };
+
+        let mut config = TomlConfig::load(content).unwrap();
+        assert!(!config.runtime.enforce_device_capabilities);
+        anno("true")
+            .update_config_by_annotation(&mut config)
+            .unwrap();
+        assert!(config.runtime.enforce_device_capabilities);
+
+        // the enforcement of the config is kept
+        anno("false")
+            .update_config_by_annotation(&mut config)
+            .unwrap();
+        assert!(config.runtime.enforce_device_capabilities);
+        assert!(anno("maybe")
+            .update_config_by_annotation(&mut config)
+            .is_err());
+    }
 }
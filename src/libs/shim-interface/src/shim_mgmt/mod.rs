@@ -20,5 +20,15 @@ pub const IP_TABLE_URL: &str = "/iptables";
 pub const IP6_TABLE_URL: &str = "/ip6tables";
 /// URL for querying metrics inside shim
 pub const METRICS_URL: &str = "/metrics";
+/// The key for the memory to reclaim in MiB
+pub const MEMORY_RECLAIM_SIZE_KEY: &str = "size_mb";
+/// URL for reclaiming guest memory by the balloon
+pub const MEMORY_RECLAIM_URL: &str = "/memory/reclaim";
+/// URL for querying the timings of the resource setup phases
+pub const RESOURCE_TIMINGS_URL: &str = "/resource/timings";
+/// URL for streaming the events of the resources as json lines
+pub const RESOURCE_EVENTS_URL: &str = "/resource/events";
+/// URL for planning the resources of a container without setting them up
+pub const RESOURCE_PLAN_URL: &str = "/resource/plan";
 
 pub const ERR_NO_SHIM_SERVER: &str = "Failed to create shim management server";
@@ -10,7 +10,10 @@ use ttrpc::context as ttrpc_ctx;
 
 use kata_types::config::Agent as AgentConfig;
 
-use crate::{kata::KataAgent, Agent, AgentManager, HealthService};
+use crate::{
+    kata::{KataAgent, RequestCategory},
+    Agent, AgentManager, ConnectionLost, HealthService,
+};
 
 /// millisecond to nanosecond
 const MILLISECOND_TO_NANOSECOND: i64 = 1_000_000;
@@ -49,16 +52,25 @@ impl AgentManager for KataAgent {
     }
 }
 
-// implement for health service
+// implement for health service, the checks aren't retried but the next one
+// goes over a new connection if it's lost, e.g. until the agent listens
 macro_rules! impl_health_service {
     ($($name: tt | $req: ty | $resp: ty),*) => {
         #[async_trait]
         impl HealthService for KataAgent {
             $(async fn $name(&self, req: $req) -> Result<$resp> {
                 let r = req.into();
-                let (client, timeout, _) = self.get_health_client().await.context("get health client")?;
-                let resp = client.$name(new_ttrpc_ctx(timeout * MILLISECOND_TO_NANOSECOND), &r).await?;
-                Ok(resp.into())
+                let (client, timeout, fd) = self.get_health_client().await.context("get health client")?;
+                match client.$name(new_ttrpc_ctx(timeout * MILLISECOND_TO_NANOSECOND), &r).await {
+                    Ok(resp) => Ok(resp.into()),
+                    Err(err) if is_connection_error(&err) => {
+                        self.reconnect_agent_server(fd)
+                            .await
+                            .with_context(|| format!("{}: {:?}", stringify!($name), err))?;
+                        Err(err.into())
+                    }
+                    Err(err) => Err(err.into()),
+                }
             })*
         }
     };
@@ -69,53 +81,396 @@ impl_health_service!(
     version | crate::CheckRequest | crate::VersionCheckResponse
 );
 
+// the connection to the agent is lost, while the request is alive
+fn is_connection_error(e: &ttrpc::Error) -> bool {
+    matches!(
+        e,
+        ttrpc::Error::Socket(_) | ttrpc::Error::LocalClosed | ttrpc::Error::RemoteClosed
+    )
+}
+
+// The idempotent requests are retried after reconnecting to the agent if the
+// connection is lost, the others fail with ConnectionLost after reconnecting.
 macro_rules! impl_agent {
-    ($($name: tt | $req: ty | $resp: ty | $new_timeout: expr),*) => {
+    ($($name: tt | $req: ty | $resp: ty | $category: ident | $idempotent: expr),*) => {
         #[async_trait]
         impl Agent for KataAgent {
             $(async fn $name(&self, req: $req) -> Result<$resp> {
                 let r = req.into();
-                let (client, mut timeout, _) = self.get_agent_client().await.context("get client")?;
+                let mut delays = self.request_retry_policy().await.delays();
+                let mut retries = 0;
+                loop {
+                    let (client, timeout, fd) = self
+                        .get_agent_client(stringify!($name), RequestCategory::$category)
+                        .await
+                        .context("get client")?;
 
-                // update new timeout
-                if let Some(v) = $new_timeout {
-                    timeout = v;
-                }
+                    let err = match client.$name(new_ttrpc_ctx(timeout * MILLISECOND_TO_NANOSECOND), &r).await {
+                        Ok(resp) => return Ok(resp.into()),
+                        Err(err) if is_connection_error(&err) => err,
+                        Err(err) => return Err(err.into()),
+                    };
 
-                let resp = client.$name(new_ttrpc_ctx(timeout * MILLISECOND_TO_NANOSECOND), &r).await?;
-                Ok(resp.into())
+                    self.reconnect_agent_server(fd)
+                        .await
+                        .with_context(|| format!("{}: {:?}", stringify!($name), err))?;
+                    let delay = match delays.next() {
+                        Some(delay) if $idempotent => delay,
+                        _ => {
+                            return Err(anyhow::Error::from(err).context(ConnectionLost {
+                                request: stringify!($name),
+                            }))
+                        }
+                    };
+                    retries += 1;
+                    warn!(sl!(), "retry {} after reconnecting", stringify!($name); "retries" => retries);
+                    tokio::time::sleep(delay).await;
+                }
             })*
         }
     };
 }
 
 impl_agent!(
-    create_container | crate::CreateContainerRequest | crate::Empty | None,
-    start_container | crate::ContainerID | crate::Empty | None,
-    remove_container | crate::RemoveContainerRequest | crate::Empty | None,
-    exec_process | crate::ExecProcessRequest | crate::Empty | None,
-    signal_process | crate::SignalProcessRequest | crate::Empty | None,
-    wait_process | crate::WaitProcessRequest | crate::WaitProcessResponse | Some(0),
-    update_container | crate::UpdateContainerRequest | crate::Empty | None,
-    stats_container | crate::ContainerID | crate::StatsContainerResponse | None,
-    pause_container | crate::ContainerID | crate::Empty | None,
-    resume_container | crate::ContainerID | crate::Empty | None,
-    write_stdin | crate::WriteStreamRequest | crate::WriteStreamResponse | Some(0),
-    read_stdout | crate::ReadStreamRequest | crate::ReadStreamResponse | Some(0),
-    read_stderr | crate::ReadStreamRequest | crate::ReadStreamResponse | Some(0),
-    close_stdin | crate::CloseStdinRequest | crate::Empty | None,
-    tty_win_resize | crate::TtyWinResizeRequest | crate::Empty | None,
-    update_interface | crate::UpdateInterfaceRequest | crate::Interface | None,
-    update_routes | crate::UpdateRoutesRequest | crate::Routes | None,
-    add_arp_neighbors | crate::AddArpNeighborRequest | crate::Empty | None,
-    list_interfaces | crate::Empty | crate::Interfaces | None,
-    list_routes | crate::Empty | crate::Routes | None,
-    create_sandbox | crate::CreateSandboxRequest | crate::Empty | None,
-    destroy_sandbox | crate::Empty | crate::Empty | None,
-    copy_file | crate::CopyFileRequest | crate::Empty | None,
-    get_oom_event | crate::Empty | crate::OomEventResponse | Some(0),
-    get_ip_tables | crate::GetIPTablesRequest | crate::GetIPTablesResponse | None,
-    set_ip_tables | crate::SetIPTablesRequest | crate::SetIPTablesResponse | None,
-    get_volume_stats | crate::VolumeStatsRequest | crate::VolumeStatsResponse | None,
-    resize_volume | crate::ResizeVolumeRequest | crate::Empty | None
+    create_container | crate::CreateContainerRequest | crate::Empty | Container | false,
+    start_container | crate::ContainerID | crate::Empty | Container | false,
+    remove_container | crate::RemoveContainerRequest | crate::Empty | Container | false,
+    exec_process | crate::ExecProcessRequest | crate::Empty | Container | false,
+    signal_process | crate::SignalProcessRequest | crate::Empty | Container | false,
+    wait_process | crate::WaitProcessRequest | crate::WaitProcessResponse | Blocking | false,
+    update_container | crate::UpdateContainerRequest | crate::Empty | Container | false,
+    stats_container | crate::ContainerID | crate::StatsContainerResponse | Container | true,
+    pause_container | crate::ContainerID | crate::Empty | Container | false,
+    resume_container | crate::ContainerID | crate::Empty | Container | false,
+    write_stdin | crate::WriteStreamRequest | crate::WriteStreamResponse | Blocking | false,
+    read_stdout | crate::ReadStreamRequest | crate::ReadStreamResponse | Blocking | false,
+    read_stderr | crate::ReadStreamRequest | crate::ReadStreamResponse | Blocking | false,
+    close_stdin | crate::CloseStdinRequest | crate::Empty | Container | false,
+    tty_win_resize | crate::TtyWinResizeRequest | crate::Empty | Container | false,
+    update_interface | crate::UpdateInterfaceRequest | crate::Interface | Network | true,
+    update_routes | crate::UpdateRoutesRequest | crate::Routes | Network | true,
+    add_arp_neighbors | crate::AddArpNeighborRequest | crate::Empty | Network | false,
+    setup_network | crate::SetupNetworkRequest | crate::Empty | Network | false,
+    set_sysctls | crate::SetSysctlsRequest | crate::Empty | Network | true,
+    list_interfaces | crate::Empty | crate::Interfaces | Network | true,
+    list_routes | crate::Empty | crate::Routes | Network | true,
+    create_sandbox | crate::CreateSandboxRequest | crate::Empty | Default | false,
+    destroy_sandbox | crate::Empty | crate::Empty | Default | false,
+    online_cpu_mem | crate::OnlineCPUMemRequest | crate::Empty | Default | false,
+    offline_cpu | crate::OfflineCPURequest | crate::Empty | Default | false,
+    copy_file | crate::CopyFileRequest | crate::Empty | Default | false,
+    get_oom_event | crate::Empty | crate::OomEventResponse | Blocking | false,
+    get_ip_tables | crate::GetIPTablesRequest | crate::GetIPTablesResponse | Network | true,
+    set_ip_tables | crate::SetIPTablesRequest | crate::SetIPTablesResponse | Network | false,
+    get_volume_stats | crate::VolumeStatsRequest | crate::VolumeStatsResponse | Storage | true,
+    resize_volume | crate::ResizeVolumeRequest | crate::Empty | Storage | false,
+    remove_storage | crate::RemoveStorageRequest | crate::Empty | Storage | false,
+    add_swap | crate::AddSwapRequest | crate::Empty | Storage | false,
+    load_kernel_modules | crate::LoadKernelModulesRequest | crate::Empty | Default | true,
+    get_guest_details
+        | crate::GetGuestDetailsRequest
+        | crate::GuestDetailsResponse
+        | Default
+        | true,
+    get_metrics | crate::GetMetricsRequest | crate::MetricsResponse | Default | true
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{is_connection_lost, is_unsupported_error};
+    use kata_types::config::RetryPolicy;
+    use std::{
+        path::Path,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use protocols::{agent_ttrpc_async as agent_ttrpc, health_ttrpc_async as health_ttrpc};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{UnixListener, UnixStream},
+    };
+    use ttrpc::asynchronous::{Server, TtrpcContext};
+
+    const AGENT_DELAY_MS: u64 = 300;
+
+    // MockAgent answers the requests after a while, like an agent in a guest
+    // slow to boot
+    struct MockAgent;
+
+    #[async_trait]
+    impl agent_ttrpc::AgentService for MockAgent {
+        async fn update_interface(
+            &self,
+            _ctx: &TtrpcContext,
+            _req: protocols::agent::UpdateInterfaceRequest,
+        ) -> ttrpc::Result<protocols::types::Interface> {
+            tokio::time::sleep(Duration::from_millis(AGENT_DELAY_MS)).await;
+            Ok(protocols::types::Interface::new())
+        }
+
+        async fn remove_storage(
+            &self,
+            _ctx: &TtrpcContext,
+            _req: protocols::agent::RemoveStorageRequest,
+        ) -> ttrpc::Result<protocols::empty::Empty> {
+            tokio::time::sleep(Duration::from_millis(AGENT_DELAY_MS)).await;
+            Ok(protocols::empty::Empty::new())
+        }
+    }
+
+    #[async_trait]
+    impl health_ttrpc::Health for MockAgent {
+        async fn check(
+            &self,
+            _ctx: &TtrpcContext,
+            _req: protocols::health::CheckRequest,
+        ) -> ttrpc::Result<protocols::health::HealthCheckResponse> {
+            Ok(protocols::health::HealthCheckResponse::new())
+        }
+    }
+
+    // serve the hybrid vsock handshake and forward the connection to the
+    // ttrpc server of the mock agent, the first `drops` connections are
+    // dropped once a request comes, as well as the ones before the mock
+    // agent listens
+    async fn serve_hybrid_vsock(listener: UnixListener, ttrpc_path: String, drops: usize) {
+        let drops = Arc::new(AtomicUsize::new(drops));
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let ttrpc_path = ttrpc_path.clone();
+            let drops = drops.clone();
+            tokio::spawn(async move {
+                let mut line = String::new();
+                BufReader::new(&mut stream).read_line(&mut line).await?;
+                stream.write_all(b"OK 1\n").await?;
+                let drop_it = drops
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok();
+                if drop_it {
+                    stream.read_u8().await?;
+                    return Ok(());
+                }
+                let mut agent = match UnixStream::connect(&ttrpc_path).await {
+                    Ok(agent) => agent,
+                    Err(e) => {
+                        stream.read_u8().await?;
+                        return Err(e);
+                    }
+                };
+                tokio::io::copy_bidirectional(&mut stream, &mut agent).await?;
+                Ok::<(), std::io::Error>(())
+            });
+        }
+    }
+
+    async fn start_ttrpc_server(ttrpc_path: &str) -> Server {
+        let service =
+            Arc::new(Box::new(MockAgent) as Box<dyn agent_ttrpc::AgentService + Send + Sync>);
+        let health = Arc::new(Box::new(MockAgent) as Box<dyn health_ttrpc::Health + Send + Sync>);
+        let mut server = Server::new()
+            .bind(&format!("unix://{}", ttrpc_path))
+            .unwrap()
+            .register_service(agent_ttrpc::create_agent_service(service))
+            .register_service(health_ttrpc::create_health(health));
+        server.start().await.unwrap();
+        server
+    }
+
+    async fn start_mock_agent(dir: &Path, drops: usize) -> Server {
+        let ttrpc_path = dir.join("agent.sock").display().to_string();
+        let server = start_ttrpc_server(&ttrpc_path).await;
+
+        let listener = UnixListener::bind(dir.join("kata.hvsock")).unwrap();
+        tokio::spawn(serve_hybrid_vsock(listener, ttrpc_path, drops));
+        server
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_request_category_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let _server = start_mock_agent(dir.path(), 0).await;
+
+        let agent = KataAgent::new(AgentConfig {
+            network_request_timeout_ms: 100,
+            storage_request_timeout_ms: 1000,
+            ..Default::default()
+        });
+        agent
+            .set_socket_address(&format!(
+                "hvsock://{}",
+                dir.path().join("kata.hvsock").display()
+            ))
+            .await
+            .unwrap();
+        agent.connect_agent_server().await.unwrap();
+
+        // the network setup gives up before the agent answers
+        let result = agent
+            .update_interface(crate::UpdateInterfaceRequest::default())
+            .await;
+        assert!(result.is_err());
+
+        // while the storage request waits long enough
+        let result = agent
+            .remove_storage(crate::RemoveStorageRequest::default())
+            .await;
+        assert!(result.is_ok(), "{:?}", result);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reconnect_and_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let _server = start_mock_agent(dir.path(), 2).await;
+
+        let agent = KataAgent::new(AgentConfig::default());
+        agent
+            .set_request_retry_policy(RetryPolicy::fixed(3, 10))
+            .await;
+        agent
+            .set_socket_address(&format!(
+                "hvsock://{}",
+                dir.path().join("kata.hvsock").display()
+            ))
+            .await
+            .unwrap();
+        agent.connect_agent_server().await.unwrap();
+
+        // the non-idempotent request isn't retried
+        let result = agent
+            .remove_storage(crate::RemoveStorageRequest::default())
+            .await;
+        assert!(is_connection_lost(&result.unwrap_err()));
+
+        // the idempotent one is retried on the new connection
+        let result = agent
+            .update_interface(crate::UpdateInterfaceRequest::default())
+            .await;
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(agent.inner.read().await.reconnects, 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unsupported_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let _server = start_mock_agent(dir.path(), 0).await;
+
+        let agent = KataAgent::new(AgentConfig::default());
+        agent
+            .set_socket_address(&format!(
+                "hvsock://{}",
+                dir.path().join("kata.hvsock").display()
+            ))
+            .await
+            .unwrap();
+        agent.connect_agent_server().await.unwrap();
+
+        // the mock agent doesn't serve the batched network setup
+        let result = agent
+            .setup_network(crate::SetupNetworkRequest::default())
+            .await;
+        assert!(is_unsupported_error(&result.unwrap_err()));
+
+        let result = agent
+            .update_interface(crate::UpdateInterfaceRequest::default())
+            .await;
+        assert!(result.is_ok(), "{:?}", result);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wait_agent_ready() {
+        let dir = tempfile::tempdir().unwrap();
+        let ttrpc_path = dir.path().join("agent.sock").display().to_string();
+        // the VM is up, but the agent doesn't listen yet
+        let listener = UnixListener::bind(dir.path().join("kata.hvsock")).unwrap();
+        tokio::spawn(serve_hybrid_vsock(listener, ttrpc_path.clone(), 0));
+
+        let agent = KataAgent::new(AgentConfig::default());
+        agent
+            .set_socket_address(&format!(
+                "hvsock://{}",
+                dir.path().join("kata.hvsock").display()
+            ))
+            .await
+            .unwrap();
+        agent.connect_agent_server().await.unwrap();
+
+        let err = crate::wait_agent_ready(&agent, Duration::from_millis(200), None)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("isn't ready after waiting"),
+            "{:?}",
+            err
+        );
+
+        // it listens a while later, before the deadline
+        let server = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            start_ttrpc_server(&ttrpc_path).await
+        });
+        crate::wait_agent_ready(&agent, Duration::from_secs(10), None)
+            .await
+            .unwrap();
+        let _server = server.await.unwrap();
+    }
+
+    #[test]
+    fn test_request_category_timeout_ms() {
+        let config = AgentConfig {
+            request_timeout_ms: 30_000,
+            network_request_timeout_ms: 5_000,
+            ..Default::default()
+        };
+        assert_eq!(RequestCategory::Default.timeout_ms(&config), 30_000);
+        assert_eq!(RequestCategory::Network.timeout_ms(&config), 5_000);
+        assert_eq!(RequestCategory::Storage.timeout_ms(&config), 30_000);
+        assert_eq!(RequestCategory::Container.timeout_ms(&config), 30_000);
+        assert_eq!(RequestCategory::Blocking.timeout_ms(&config), 0);
+    }
+
+    #[test]
+    fn test_request_timeout_ms() {
+        let mut config = AgentConfig {
+            request_timeout_ms: 30_000,
+            storage_request_timeout_ms: 20_000,
+            ..Default::default()
+        };
+        config
+            .request_timeouts_ms
+            .insert("remove_storage".to_owned(), 90_000);
+        config
+            .request_timeouts_ms
+            .insert("wait_process".to_owned(), 1_000);
+
+        // the timeout of the request comes first
+        let storage = RequestCategory::Storage;
+        assert_eq!(
+            storage.request_timeout_ms("remove_storage", &config),
+            90_000
+        );
+        // then the one of its category
+        assert_eq!(storage.request_timeout_ms("resize_volume", &config), 20_000);
+        // then the default of the request
+        let network = RequestCategory::Network;
+        assert_eq!(network.request_timeout_ms("update_routes", &config), 10_000);
+        assert_eq!(network.request_timeout_ms("list_routes", &config), 30_000);
+        // the blocking requests never time out
+        let blocking = RequestCategory::Blocking;
+        assert_eq!(blocking.request_timeout_ms("wait_process", &config), 0);
+
+        // the request timeout set takes the place of the defaults
+        config.request_timeout_ms = 120_000;
+        assert_eq!(
+            network.request_timeout_ms("update_routes", &config),
+            120_000
+        );
+        assert_eq!(
+            RequestCategory::Container.request_timeout_ms("create_container", &config),
+            120_000
+        );
+    }
+}
@@ -13,13 +13,23 @@ use std::{
 };
 
 use anyhow::{Context, Result};
-use kata_types::config::Agent as AgentConfig;
+use kata_types::config::{
+    default::DEFAULT_AGENT_REQUEST_TIMEOUT_MS, Agent as AgentConfig, RetryPolicy,
+};
 use protocols::{agent_ttrpc_async as agent_ttrpc, health_ttrpc_async as health_ttrpc};
 use tokio::sync::RwLock;
 use ttrpc::asynchronous::Client;
 
 use crate::{log_forwarder::LogForwarder, sock};
 
+// reconnect and retry the idempotent requests a couple of times by default
+const DEFAULT_REQUEST_RETRY_POLICY: RetryPolicy = RetryPolicy {
+    max_attempts: 3,
+    base_delay_ms: 100,
+    max_delay_ms: 1000,
+    jitter: 20,
+};
+
 // https://github.com/firecracker-microvm/firecracker/blob/master/docs/vsock.md
 #[derive(Debug, Default)]
 pub struct Vsock {
@@ -27,6 +37,78 @@ pub struct Vsock {
     pub port: u32,
 }
 
+// the timeouts of the requests taking notably shorter or longer than the
+// others, used if neither the request nor its category has a timeout set
+// and the request timeout is left at its default
+const DEFAULT_REQUEST_TIMEOUTS_MS: &[(&str, u32)] = &[
+    ("update_interface", 10_000),
+    ("update_routes", 10_000),
+    // the storages and the devices of the container are added with it
+    ("create_container", 60_000),
+    ("resize_volume", 60_000),
+];
+
+/// Category of the agent requests, each of which has its own timeout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RequestCategory {
+    /// Requests using the agent request timeout
+    Default,
+    /// Network setup requests
+    Network,
+    /// Storage requests
+    Storage,
+    /// Container lifecycle requests
+    Container,
+    /// Requests blocking until something happens in the guest, never time out
+    Blocking,
+}
+
+impl RequestCategory {
+    /// timeout_ms returns the timeout of the requests in the category, 0
+    /// means no timeout.
+    pub fn timeout_ms(&self, config: &AgentConfig) -> i64 {
+        let timeout_ms = match self {
+            RequestCategory::Default => 0,
+            RequestCategory::Network => config.network_request_timeout_ms,
+            RequestCategory::Storage => config.storage_request_timeout_ms,
+            RequestCategory::Container => config.container_request_timeout_ms,
+            RequestCategory::Blocking => return 0,
+        };
+        match timeout_ms {
+            0 => config.request_timeout_ms as i64,
+            t => t as i64,
+        }
+    }
+
+    /// request_timeout_ms returns the timeout of the request in the category,
+    /// the timeout of the request is preferred to the one of the category.
+    pub fn request_timeout_ms(&self, request: &str, config: &AgentConfig) -> i64 {
+        if *self == RequestCategory::Blocking {
+            return 0;
+        }
+        if let Some(t) = config.request_timeouts_ms.get(request).filter(|t| **t > 0) {
+            return *t as i64;
+        }
+        let category_timeout_ms = match self {
+            RequestCategory::Network => config.network_request_timeout_ms,
+            RequestCategory::Storage => config.storage_request_timeout_ms,
+            RequestCategory::Container => config.container_request_timeout_ms,
+            _ => 0,
+        };
+        // the request timeout set applies to all the requests
+        if category_timeout_ms == 0 && config.request_timeout_ms == DEFAULT_AGENT_REQUEST_TIMEOUT_MS
+        {
+            if let Some((_, t)) = DEFAULT_REQUEST_TIMEOUTS_MS
+                .iter()
+                .find(|(name, _)| *name == request)
+            {
+                return *t as i64;
+            }
+        }
+        self.timeout_ms(config)
+    }
+}
+
 pub(crate) struct KataAgentInner {
     /// TTRPC client
     pub client: Option<Client>,
@@ -42,6 +124,34 @@ pub(crate) struct KataAgentInner {
 
     /// Log forwarder
     log_forwarder: LogForwarder,
+
+    /// Policy to retry connecting to the agent, derived from the agent
+    /// timeouts if unset
+    retry_policy: Option<RetryPolicy>,
+
+    /// Policy to retry the idempotent requests after reconnecting
+    request_retry_policy: RetryPolicy,
+
+    /// Times reconnected to the agent
+    reconnects: u64,
+}
+
+impl KataAgentInner {
+    async fn connect_agent_server(&mut self) -> Result<()> {
+        let config = sock::ConnectConfig::new(
+            self.config.dial_timeout_ms as u64,
+            self.config.reconnect_timeout_ms as u64,
+        )
+        .with_retry_policy(self.retry_policy);
+        let sock = sock::new(&self.socket_address, self.config.server_port).context("new sock")?;
+        let stream = sock.connect(&config).await.context("connect")?;
+        let fd = stream.into_raw_fd();
+        info!(sl!(), "get stream raw fd {:?}", fd);
+        let c = Client::new(fd);
+        self.client = Some(c);
+        self.client_fd = fd;
+        Ok(())
+    }
 }
 
 pub struct KataAgent {
@@ -57,6 +167,9 @@ impl KataAgent {
                 socket_address: "".to_string(),
                 config,
                 log_forwarder: LogForwarder::new(),
+                retry_policy: None,
+                request_retry_policy: DEFAULT_REQUEST_RETRY_POLICY,
+                reconnects: 0,
             })),
         }
     }
@@ -72,17 +185,35 @@ impl KataAgent {
         })
     }
 
-    pub async fn get_agent_client(&self) -> Option<(agent_ttrpc::AgentServiceClient, i64, RawFd)> {
+    pub async fn get_agent_client(
+        &self,
+        request: &str,
+        category: RequestCategory,
+    ) -> Option<(agent_ttrpc::AgentServiceClient, i64, RawFd)> {
         let inner = self.inner.read().await;
         inner.client.as_ref().map(|c| {
             (
                 agent_ttrpc::AgentServiceClient::new(c.clone()),
-                inner.config.request_timeout_ms as i64,
+                category.request_timeout_ms(request, &inner.config),
                 inner.client_fd,
             )
         })
     }
 
+    pub async fn set_retry_policy(&self, policy: RetryPolicy) {
+        let mut inner = self.inner.write().await;
+        inner.retry_policy = Some(policy);
+    }
+
+    pub async fn set_request_retry_policy(&self, policy: RetryPolicy) {
+        let mut inner = self.inner.write().await;
+        inner.request_retry_policy = policy;
+    }
+
+    pub(crate) async fn request_retry_policy(&self) -> RetryPolicy {
+        self.inner.read().await.request_retry_policy
+    }
+
     pub(crate) async fn set_socket_address(&self, address: &str) -> Result<()> {
         let mut inner = self.inner.write().await;
         inner.socket_address = address.to_string();
@@ -91,20 +222,29 @@ impl KataAgent {
 
     pub(crate) async fn connect_agent_server(&self) -> Result<()> {
         let mut inner = self.inner.write().await;
+        inner.connect_agent_server().await
+    }
 
-        let config = sock::ConnectConfig::new(
-            inner.config.dial_timeout_ms as u64,
-            inner.config.reconnect_timeout_ms as u64,
+    /// reconnect_agent_server re-dials the agent after the connection of
+    /// `stale_fd` is lost, unless it's done by another request already.
+    pub(crate) async fn reconnect_agent_server(&self, stale_fd: RawFd) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        if inner.client_fd != stale_fd {
+            return Ok(());
+        }
+
+        inner.reconnects += 1;
+        let reconnects = inner.reconnects;
+        warn!(
+            sl!(),
+            "connection to agent lost, reconnect";
+            "fd" => stale_fd,
+            "reconnects" => reconnects
         );
-        let sock =
-            sock::new(&inner.socket_address, inner.config.server_port).context("new sock")?;
-        let stream = sock.connect(&config).await.context("connect")?;
-        let fd = stream.into_raw_fd();
-        info!(sl!(), "get stream raw fd {:?}", fd);
-        let c = Client::new(fd);
-        inner.client = Some(c);
-        inner.client_fd = fd;
-        Ok(())
+        inner
+            .connect_agent_server()
+            .await
+            .with_context(|| format!("reconnect {} times", reconnects))
     }
 
     pub(crate) async fn start_log_forwarder(&self) -> Result<()> {
@@ -112,7 +252,8 @@ impl KataAgent {
         let config = sock::ConnectConfig::new(
             inner.config.dial_timeout_ms as u64,
             inner.config.reconnect_timeout_ms as u64,
-        );
+        )
+        .with_retry_policy(inner.retry_policy);
         let address = inner.socket_address.clone();
         let port = inner.config.log_port;
         inner
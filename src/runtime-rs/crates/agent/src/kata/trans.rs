@@ -13,19 +13,21 @@ use protocols::{
 
 use crate::{
     types::{
-        ARPNeighbor, ARPNeighbors, AddArpNeighborRequest, AgentDetails, BlkioStats,
+        ARPNeighbor, ARPNeighbors, AddArpNeighborRequest, AddSwapRequest, AgentDetails, BlkioStats,
         BlkioStatsEntry, CgroupStats, CheckRequest, CloseStdinRequest, ContainerID,
         CopyFileRequest, CpuStats, CpuUsage, CreateContainerRequest, CreateSandboxRequest, Device,
-        Empty, ExecProcessRequest, FSGroup, FSGroupChangePolicy, GetIPTablesRequest,
-        GetIPTablesResponse, GuestDetailsResponse, HealthCheckResponse, HugetlbStats, IPAddress,
-        IPFamily, Interface, Interfaces, KernelModule, MemHotplugByProbeRequest, MemoryData,
-        MemoryStats, NetworkStats, OnlineCPUMemRequest, PidsStats, ReadStreamRequest,
-        ReadStreamResponse, RemoveContainerRequest, ReseedRandomDevRequest, ResizeVolumeRequest,
-        Route, Routes, SetGuestDateTimeRequest, SetIPTablesRequest, SetIPTablesResponse,
+        Empty, ExecProcessRequest, FSGroup, FSGroupChangePolicy, GetGuestDetailsRequest,
+        GetIPTablesRequest, GetIPTablesResponse, GetMetricsRequest, GuestDetailsResponse,
+        HealthCheckResponse, HugetlbStats, IPAddress, IPFamily, Interface, Interfaces,
+        KernelModule, LoadKernelModulesRequest, MemHotplugByProbeRequest, MemoryData, MemoryStats,
+        MetricsResponse, NetworkStats, OfflineCPURequest, OnlineCPUMemRequest, PidsStats,
+        ReadStreamRequest, ReadStreamResponse, RemoveContainerRequest, RemoveStorageRequest,
+        ReseedRandomDevRequest, ResizeVolumeRequest, Route, Routes, SetGuestDateTimeRequest,
+        SetIPTablesRequest, SetIPTablesResponse, SetSysctlsRequest, SetupNetworkRequest,
         SignalProcessRequest, StatsContainerResponse, Storage, StringUser, ThrottlingData,
         TtyWinResizeRequest, UpdateContainerRequest, UpdateInterfaceRequest, UpdateRoutesRequest,
-        VersionCheckResponse, VolumeStatsRequest, VolumeStatsResponse, WaitProcessRequest,
-        WriteStreamRequest,
+        VersionCheckResponse, VolumeStatsRequest, VolumeStatsResponse, VolumeUsage,
+        VolumeUsageUnit, WaitProcessRequest, WriteStreamRequest,
     },
     OomEventResponse, WaitProcessResponse, WriteStreamResponse,
 };
@@ -178,6 +180,7 @@ impl From<Interface> for types::Interface {
             pciPath: from.pci_addr,
             type_: from.field_type,
             raw_flags: from.raw_flags,
+            queues: from.queues,
             ..Default::default()
         }
     }
@@ -194,6 +197,7 @@ impl From<types::Interface> for Interface {
             pci_addr: src.pciPath,
             field_type: src.type_,
             raw_flags: src.raw_flags,
+            queues: src.queues,
         }
     }
 }
@@ -215,6 +219,8 @@ impl From<Route> for types::Route {
             source: from.source,
             scope: from.scope,
             family: protobuf::EnumOrUnknown::new(from.family.into()),
+            flags: from.flags,
+            metric: from.metric,
             ..Default::default()
         }
     }
@@ -229,6 +235,8 @@ impl From<types::Route> for Route {
             source: src.source,
             scope: src.scope,
             family: src.family.unwrap().into(),
+            flags: src.flags,
+            metric: src.metric,
         }
     }
 }
@@ -647,6 +655,26 @@ impl From<AddArpNeighborRequest> for agent::AddARPNeighborsRequest {
     }
 }
 
+impl From<SetupNetworkRequest> for agent::SetupNetworkRequest {
+    fn from(from: SetupNetworkRequest) -> Self {
+        Self {
+            interfaces: trans_vec(from.interfaces),
+            neighbors: from_option(from.neighbors),
+            routes: from_option(from.routes),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<SetSysctlsRequest> for agent::SetSysctlsRequest {
+    fn from(from: SetSysctlsRequest) -> Self {
+        Self {
+            sysctls: from.sysctls,
+            ..Default::default()
+        }
+    }
+}
+
 impl From<CreateSandboxRequest> for agent::CreateSandboxRequest {
     fn from(from: CreateSandboxRequest) -> Self {
         Self {
@@ -681,6 +709,15 @@ impl From<OnlineCPUMemRequest> for agent::OnlineCPUMemRequest {
     }
 }
 
+impl From<OfflineCPURequest> for agent::OfflineCPURequest {
+    fn from(from: OfflineCPURequest) -> Self {
+        Self {
+            nb_cpus: from.nb_cpus,
+            ..Default::default()
+        }
+    }
+}
+
 impl From<ReseedRandomDevRequest> for agent::ReseedRandomDevRequest {
     fn from(from: ReseedRandomDevRequest) -> Self {
         Self {
@@ -721,6 +758,16 @@ impl From<agent::AgentDetails> for AgentDetails {
     }
 }
 
+impl From<GetGuestDetailsRequest> for agent::GuestDetailsRequest {
+    fn from(from: GetGuestDetailsRequest) -> Self {
+        Self {
+            mem_block_size: from.mem_block_size,
+            mem_hotplug_probe: from.mem_hotplug_probe,
+            ..Default::default()
+        }
+    }
+}
+
 impl From<agent::GuestDetailsResponse> for GuestDetailsResponse {
     fn from(src: agent::GuestDetailsResponse) -> Self {
         Self {
@@ -731,6 +778,20 @@ impl From<agent::GuestDetailsResponse> for GuestDetailsResponse {
     }
 }
 
+impl From<GetMetricsRequest> for agent::GetMetricsRequest {
+    fn from(_: GetMetricsRequest) -> Self {
+        Self::default()
+    }
+}
+
+impl From<agent::Metrics> for MetricsResponse {
+    fn from(src: agent::Metrics) -> Self {
+        Self {
+            metrics: src.metrics,
+        }
+    }
+}
+
 impl From<CopyFileRequest> for agent::CopyFileRequest {
     fn from(from: CopyFileRequest) -> Self {
         Self {
@@ -806,6 +867,22 @@ impl From<VolumeStatsRequest> for agent::VolumeStatsRequest {
     }
 }
 
+impl From<csi::VolumeUsage> for VolumeUsage {
+    fn from(from: csi::VolumeUsage) -> Self {
+        let unit = match from.unit.enum_value() {
+            Ok(csi::volume_usage::Unit::BYTES) => VolumeUsageUnit::Bytes,
+            Ok(csi::volume_usage::Unit::INODES) => VolumeUsageUnit::Inodes,
+            _ => VolumeUsageUnit::Unknown,
+        };
+        Self {
+            available: from.available,
+            total: from.total,
+            used: from.used,
+            unit,
+        }
+    }
+}
+
 impl From<csi::VolumeStatsResponse> for VolumeStatsResponse {
     fn from(from: csi::VolumeStatsResponse) -> Self {
         let result: String = format!(
@@ -813,7 +890,42 @@ impl From<csi::VolumeStatsResponse> for VolumeStatsResponse {
             from.usage(),
             from.volume_condition()
         );
-        Self { data: result }
+        Self {
+            data: result,
+            abnormal: from.volume_condition().abnormal,
+            message: from.volume_condition().message.clone(),
+            usage: trans_vec(from.usage),
+        }
+    }
+}
+
+impl From<AddSwapRequest> for agent::AddSwapRequest {
+    fn from(from: AddSwapRequest) -> Self {
+        Self {
+            PCIPath: from.pci_path,
+            device_path: from.device_path,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<LoadKernelModulesRequest> for agent::LoadKernelModulesRequest {
+    fn from(from: LoadKernelModulesRequest) -> Self {
+        Self {
+            modules: trans_vec(from.modules),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<RemoveStorageRequest> for agent::RemoveStorageRequest {
+    fn from(from: RemoveStorageRequest) -> Self {
+        Self {
+            mount_point: from.mount_point,
+            container_id: from.container_id,
+            container_paths: from.container_paths,
+            ..Default::default()
+        }
     }
 }
 
@@ -14,26 +14,39 @@ mod log_forwarder;
 mod sock;
 pub mod types;
 pub use types::{
-    ARPNeighbor, ARPNeighbors, AddArpNeighborRequest, BlkioStatsEntry, CheckRequest,
-    CloseStdinRequest, ContainerID, ContainerProcessID, CopyFileRequest, CreateContainerRequest,
-    CreateSandboxRequest, Empty, ExecProcessRequest, GetGuestDetailsRequest, GetIPTablesRequest,
-    GetIPTablesResponse, GuestDetailsResponse, HealthCheckResponse, IPAddress, IPFamily, Interface,
-    Interfaces, ListProcessesRequest, MemHotplugByProbeRequest, OnlineCPUMemRequest,
-    OomEventResponse, ReadStreamRequest, ReadStreamResponse, RemoveContainerRequest,
-    ReseedRandomDevRequest, ResizeVolumeRequest, Route, Routes, SetGuestDateTimeRequest,
-    SetIPTablesRequest, SetIPTablesResponse, SignalProcessRequest, StatsContainerResponse, Storage,
-    TtyWinResizeRequest, UpdateContainerRequest, UpdateInterfaceRequest, UpdateRoutesRequest,
-    VersionCheckResponse, VolumeStatsRequest, VolumeStatsResponse, WaitProcessRequest,
-    WaitProcessResponse, WriteStreamRequest, WriteStreamResponse,
+    ARPNeighbor, ARPNeighbors, AddArpNeighborRequest, AddSwapRequest, AgentDetails,
+    BlkioStatsEntry, CheckRequest, CloseStdinRequest, ContainerID, ContainerProcessID,
+    CopyFileRequest, CreateContainerRequest, CreateSandboxRequest, Empty, ExecProcessRequest,
+    GetGuestDetailsRequest, GetIPTablesRequest, GetIPTablesResponse, GetMetricsRequest,
+    GuestDetailsResponse, HealthCheckResponse, IPAddress, IPFamily, Interface, Interfaces,
+    KernelModule, ListProcessesRequest, LoadKernelModulesRequest, MemHotplugByProbeRequest,
+    MetricsResponse, OfflineCPURequest, OnlineCPUMemRequest, OomEventResponse, ReadStreamRequest,
+    ReadStreamResponse, RemoveContainerRequest, RemoveStorageRequest, ReseedRandomDevRequest,
+    ResizeVolumeRequest, Route, Routes, SetGuestDateTimeRequest, SetIPTablesRequest,
+    SetIPTablesResponse, SetSysctlsRequest, SetupNetworkRequest, SignalProcessRequest,
+    StatsContainerResponse, Storage, TtyWinResizeRequest, UpdateContainerRequest,
+    UpdateInterfaceRequest, UpdateRoutesRequest, VersionCheckResponse, VolumeStatsRequest,
+    VolumeStatsResponse, VolumeUsage, VolumeUsageUnit, WaitProcessRequest, WaitProcessResponse,
+    WriteStreamRequest, WriteStreamResponse,
 };
 
-use anyhow::Result;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 
-use kata_types::config::Agent as AgentConfig;
+use kata_types::config::{Agent as AgentConfig, RetryPolicy};
 
 pub const AGENT_KATA: &str = "kata";
 
+// check the agent again and again until the deadline, quickly at first
+const DEFAULT_READY_RETRY_POLICY: RetryPolicy = RetryPolicy {
+    max_attempts: u32::MAX,
+    base_delay_ms: 50,
+    max_delay_ms: 1000,
+    jitter: 20,
+};
+
 #[async_trait]
 pub trait AgentManager: Send + Sync {
     async fn start(&self, address: &str) -> Result<()>;
@@ -54,6 +67,8 @@ pub trait Agent: AgentManager + HealthService + Send + Sync {
     // sandbox
     async fn create_sandbox(&self, req: CreateSandboxRequest) -> Result<Empty>;
     async fn destroy_sandbox(&self, req: Empty) -> Result<Empty>;
+    async fn online_cpu_mem(&self, req: OnlineCPUMemRequest) -> Result<Empty>;
+    async fn offline_cpu(&self, req: OfflineCPURequest) -> Result<Empty>;
 
     // network
     async fn add_arp_neighbors(&self, req: AddArpNeighborRequest) -> Result<Empty>;
@@ -61,6 +76,8 @@ pub trait Agent: AgentManager + HealthService + Send + Sync {
     async fn list_routes(&self, req: Empty) -> Result<Routes>;
     async fn update_interface(&self, req: UpdateInterfaceRequest) -> Result<Interface>;
     async fn update_routes(&self, req: UpdateRoutesRequest) -> Result<Routes>;
+    async fn setup_network(&self, req: SetupNetworkRequest) -> Result<Empty>;
+    async fn set_sysctls(&self, req: SetSysctlsRequest) -> Result<Empty>;
 
     // container
     async fn create_container(&self, req: CreateContainerRequest) -> Result<Empty>;
@@ -90,4 +107,96 @@ pub trait Agent: AgentManager + HealthService + Send + Sync {
     async fn set_ip_tables(&self, req: SetIPTablesRequest) -> Result<SetIPTablesResponse>;
     async fn get_volume_stats(&self, req: VolumeStatsRequest) -> Result<VolumeStatsResponse>;
     async fn resize_volume(&self, req: ResizeVolumeRequest) -> Result<Empty>;
+    async fn remove_storage(&self, req: RemoveStorageRequest) -> Result<Empty>;
+    async fn add_swap(&self, req: AddSwapRequest) -> Result<Empty>;
+    async fn load_kernel_modules(&self, req: LoadKernelModulesRequest) -> Result<Empty>;
+    async fn get_guest_details(&self, req: GetGuestDetailsRequest) -> Result<GuestDetailsResponse>;
+    async fn get_metrics(&self, req: GetMetricsRequest) -> Result<MetricsResponse>;
+}
+
+/// ConnectionLost is the error of the request which isn't retried after the
+/// connection to the agent is lost, as it's not idempotent. The request may
+/// or may not have been handled by the agent, the caller decides what to do.
+#[derive(Debug)]
+pub struct ConnectionLost {
+    pub request: &'static str,
+}
+
+impl std::fmt::Display for ConnectionLost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "connection to agent lost during {}", self.request)
+    }
+}
+
+impl std::error::Error for ConnectionLost {}
+
+/// is_connection_lost tells if the request failed as the connection to the
+/// agent is lost.
+pub fn is_connection_lost(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<ConnectionLost>().is_some()
+}
+
+/// is_unsupported_error tells if the agent doesn't serve the request, e.g.
+/// it's older than the runtime or the request is blocked by its policy.
+pub fn is_unsupported_error(e: &anyhow::Error) -> bool {
+    matches!(
+        e.root_cause().downcast_ref::<ttrpc::Error>(),
+        Some(ttrpc::Error::RpcStatus(s))
+            if s.code.value() == ttrpc::Code::UNIMPLEMENTED as i32
+                || s.code.value() == ttrpc::Code::NOT_FOUND as i32
+    )
+}
+
+/// unsupported_error is the error of the agent not serving the request, the
+/// one is_unsupported_error tells, e.g. for the agents of the tests.
+pub fn unsupported_error(request: &str) -> anyhow::Error {
+    ttrpc::Error::RpcStatus(ttrpc::get_status(
+        ttrpc::Code::UNIMPLEMENTED,
+        format!("{} is unsupported", request),
+    ))
+    .into()
+}
+
+/// is_busy_error tells if the agent refused the request because the
+/// resource is still in use in the guest.
+pub fn is_busy_error(e: &anyhow::Error) -> bool {
+    matches!(
+        e.root_cause().downcast_ref::<ttrpc::Error>(),
+        Some(ttrpc::Error::RpcStatus(s))
+            if s.code.value() == ttrpc::Code::FAILED_PRECONDITION as i32
+    )
+}
+
+/// wait_agent_ready waits for the agent to answer the health check, e.g.
+/// while the guest still boots. The check is retried by the policy, the
+/// built-in one if unset, until the deadline or the attempts are over. The
+/// deadline cuts the check in progress as well whatever its timeout.
+pub async fn wait_agent_ready(
+    agent: &dyn Agent,
+    deadline: Duration,
+    policy: Option<RetryPolicy>,
+) -> Result<()> {
+    let start = Instant::now();
+    let mut delays = policy.unwrap_or(DEFAULT_READY_RETRY_POLICY).delays();
+    loop {
+        let remaining = deadline.saturating_sub(start.elapsed());
+        let err = match tokio::time::timeout(remaining, agent.check(CheckRequest::default())).await
+        {
+            Ok(Ok(_)) => return Ok(()),
+            Ok(Err(e)) => e,
+            Err(_) => anyhow!("health check timed out"),
+        };
+        let waited = start.elapsed();
+        let delay = match delays.next() {
+            Some(delay) if waited < deadline => delay,
+            _ => {
+                return Err(err.context(format!(
+                    "agent isn't ready after waiting {} ms",
+                    waited.as_millis()
+                )))
+            }
+        };
+        debug!(sl!(), "agent isn't ready yet: {:?}", err);
+        tokio::time::sleep(delay.min(deadline - waited)).await;
+    }
 }
@@ -33,8 +33,9 @@ impl HybridVsock {
 #[async_trait]
 impl Sock for HybridVsock {
     async fn connect(&self, config: &ConnectConfig) -> Result<Stream> {
-        let retry_times = config.reconnect_timeout_ms / config.dial_timeout_ms;
-        for i in 0..retry_times {
+        let mut delays = config.retry_policy.delays();
+        let mut i = 0;
+        loop {
             match connect_helper(&self.uds, self.port).await {
                 Ok(stream) => {
                     info!(
@@ -47,11 +48,13 @@ impl Sock for HybridVsock {
                 }
                 Err(err) => {
                     debug!(sl!(), "connect on {} err : {:?}", i, err);
-                    tokio::time::sleep(std::time::Duration::from_millis(config.dial_timeout_ms))
-                        .await;
-                    continue;
+                    match delays.next() {
+                        Some(delay) => tokio::time::sleep(delay).await,
+                        None => break,
+                    }
                 }
             }
+            i += 1;
         }
         Err(anyhow!("cannot connect to agent ttrpc server {:?}", config))
     }
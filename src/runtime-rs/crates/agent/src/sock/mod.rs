@@ -20,6 +20,7 @@ use std::{
 
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
+use kata_types::config::RetryPolicy;
 use tokio::{
     io::{AsyncRead, ReadBuf},
     net::UnixStream,
@@ -81,17 +82,27 @@ impl AsyncRead for Stream {
 /// Connect config
 #[derive(Debug)]
 pub struct ConnectConfig {
-    dial_timeout_ms: u64,
-    reconnect_timeout_ms: u64,
+    retry_policy: RetryPolicy,
 }
 
 impl ConnectConfig {
     pub fn new(dial_timeout_ms: u64, reconnect_timeout_ms: u64) -> Self {
+        // dial every dial_timeout_ms until reconnect_timeout_ms is reached by default
+        let attempts = reconnect_timeout_ms
+            .checked_div(dial_timeout_ms)
+            .unwrap_or(1)
+            .clamp(1, u32::MAX as u64) as u32;
         Self {
-            dial_timeout_ms,
-            reconnect_timeout_ms,
+            retry_policy: RetryPolicy::fixed(attempts, dial_timeout_ms),
         }
     }
+
+    pub fn with_retry_policy(mut self, retry_policy: Option<RetryPolicy>) -> Self {
+        if let Some(policy) = retry_policy {
+            self.retry_policy = policy;
+        }
+        self
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -142,7 +153,8 @@ fn parse(address: &str, port: u32) -> Result<SockType> {
 
 #[cfg(test)]
 mod test {
-    use super::{hybrid_vsock::HybridVsock, parse, vsock::Vsock, SockType};
+    use super::{hybrid_vsock::HybridVsock, parse, vsock::Vsock, ConnectConfig, SockType};
+    use kata_types::config::RetryPolicy;
 
     #[test]
     fn test_parse_url() {
@@ -157,4 +169,22 @@ mod test {
             SockType::HybridVsock(HybridVsock::new("/tmp/test.hvsock", 456))
         );
     }
+
+    #[test]
+    fn test_connect_config_retry_policy() {
+        let config = ConnectConfig::new(10, 3000);
+        assert_eq!(config.retry_policy, RetryPolicy::fixed(300, 10));
+
+        let config = ConnectConfig::new(0, 3000);
+        assert_eq!(config.retry_policy, RetryPolicy::fixed(1, 0));
+
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay_ms: 10,
+            max_delay_ms: 100,
+            jitter: 20,
+        };
+        let config = ConnectConfig::new(10, 3000).with_retry_policy(Some(policy));
+        assert_eq!(config.retry_policy, policy);
+    }
 }
@@ -4,10 +4,7 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::{
-    os::unix::prelude::{AsRawFd, FromRawFd},
-    time::Duration,
-};
+use std::os::unix::prelude::{AsRawFd, FromRawFd};
 
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
@@ -31,7 +28,7 @@ impl Vsock {
 #[async_trait]
 impl Sock for Vsock {
     async fn connect(&self, config: &ConnectConfig) -> Result<Stream> {
-        let retry_times = config.reconnect_timeout_ms / config.dial_timeout_ms;
+        let mut delays = config.retry_policy.delays();
         let sock_addr = VsockAddr::new(self.vsock_cid, self.port);
         let connect_once = || {
             // Create socket fd
@@ -58,7 +55,8 @@ impl Sock for Vsock {
             UnixStream::from_std(socket).context("from_std")
         };
 
-        for i in 0..retry_times {
+        let mut i = 0;
+        loop {
             match connect_once() {
                 Ok(stream) => {
                     info!(
@@ -69,10 +67,12 @@ impl Sock for Vsock {
                     );
                     return Ok(Stream::Vsock(stream));
                 }
-                Err(_) => {
-                    tokio::time::sleep(Duration::from_millis(config.dial_timeout_ms)).await;
-                }
+                Err(_) => match delays.next() {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => break,
+                },
             }
+            i += 1;
         }
         Err(anyhow!("cannot connect to agent ttrpc server {:?}", config))
     }
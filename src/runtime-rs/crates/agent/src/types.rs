@@ -87,6 +87,9 @@ pub struct Interface {
     pub field_type: String,
     #[serde(default)]
     pub raw_flags: u32,
+    /// virtio-net queue pairs the guest enables, 0 leaves them as they are
+    #[serde(default)]
+    pub queues: u32,
 }
 
 #[derive(PartialEq, Clone, Default)]
@@ -102,6 +105,10 @@ pub struct Route {
     pub source: String,
     pub scope: u32,
     pub family: IPFamily,
+    #[serde(default)]
+    pub flags: u32,
+    #[serde(default)]
+    pub metric: u32,
 }
 
 #[derive(Deserialize, Debug, PartialEq, Clone, Default)]
@@ -408,6 +415,18 @@ pub struct AddArpNeighborRequest {
     pub neighbors: Option<ARPNeighbors>,
 }
 
+#[derive(PartialEq, Clone, Default, Debug)]
+pub struct SetupNetworkRequest {
+    pub interfaces: Vec<Interface>,
+    pub neighbors: Option<ARPNeighbors>,
+    pub routes: Option<Routes>,
+}
+
+#[derive(PartialEq, Clone, Default, Debug)]
+pub struct SetSysctlsRequest {
+    pub sysctls: ::std::collections::HashMap<String, String>,
+}
+
 #[derive(PartialEq, Clone, Default)]
 pub struct KernelModule {
     pub name: String,
@@ -482,6 +501,11 @@ pub struct OnlineCPUMemRequest {
     pub cpu_only: bool,
 }
 
+#[derive(PartialEq, Clone, Default)]
+pub struct OfflineCPURequest {
+    pub nb_cpus: u32,
+}
+
 #[derive(PartialEq, Clone, Default)]
 pub struct ReseedRandomDevRequest {
     pub data: ::std::vec::Vec<u8>,
@@ -493,6 +517,15 @@ pub struct GetGuestDetailsRequest {
     pub mem_hotplug_probe: bool,
 }
 
+#[derive(PartialEq, Clone, Default, Debug)]
+pub struct GetMetricsRequest {}
+
+#[derive(PartialEq, Clone, Default, Debug)]
+pub struct MetricsResponse {
+    /// the metrics of the guest in the prometheus text format
+    pub metrics: String,
+}
+
 #[derive(PartialEq, Clone, Default)]
 pub struct MemHotplugByProbeRequest {
     pub mem_hotplug_probe_addr: ::std::vec::Vec<u64>,
@@ -569,14 +602,53 @@ pub struct ResizeVolumeRequest {
     pub size: u64,
 }
 
+#[derive(PartialEq, Clone, Default, Debug)]
+pub struct AddSwapRequest {
+    pub pci_path: Vec<u32>,
+    pub device_path: String,
+}
+
+#[derive(PartialEq, Clone, Default, Debug)]
+pub struct RemoveStorageRequest {
+    pub mount_point: String,
+    pub container_id: String,
+    /// the paths the storage is mounted at in the container, unmounted
+    /// before the storage
+    pub container_paths: Vec<String>,
+}
+
+#[derive(PartialEq, Clone, Default)]
+pub struct LoadKernelModulesRequest {
+    pub modules: Vec<KernelModule>,
+}
+
 #[derive(PartialEq, Clone, Default, Debug)]
 pub struct VolumeStatsRequest {
     pub volume_guest_path: String,
 }
 
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeUsageUnit {
+    #[default]
+    Unknown = 0,
+    Bytes = 1,
+    Inodes = 2,
+}
+
+#[derive(PartialEq, Clone, Default, Debug)]
+pub struct VolumeUsage {
+    pub available: u64,
+    pub total: u64,
+    pub used: u64,
+    pub unit: VolumeUsageUnit,
+}
+
 #[derive(PartialEq, Clone, Default, Debug)]
 pub struct VolumeStatsResponse {
     pub data: String,
+    pub usage: Vec<VolumeUsage>,
+    pub abnormal: bool,
+    pub message: String,
 }
 
 #[cfg(test)]
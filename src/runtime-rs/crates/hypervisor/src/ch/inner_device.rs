@@ -7,11 +7,12 @@
 use super::inner::CloudHypervisorInner;
 use crate::device::DeviceType;
 use crate::HybridVsockConfig;
+use crate::NetworkDevice;
 use crate::ShareFsDeviceConfig;
 use crate::VmmState;
 use anyhow::{anyhow, Context, Result};
 use ch_config::ch_api::cloud_hypervisor_vm_fs_add;
-use ch_config::FsConfig;
+use ch_config::{FsConfig, MacAddr, NetConfig};
 use safe_path::scoped_join;
 use std::convert::TryFrom;
 use std::path::PathBuf;
@@ -125,10 +126,15 @@ impl CloudHypervisorInner {
         Ok(())
     }
 
-    pub(crate) async fn get_shared_fs_devices(&mut self) -> Result<Option<Vec<FsConfig>>> {
+    /// Take the devices requested before the VM boots, they are part of the
+    /// VM config instead of being hotplugged.
+    pub(crate) async fn get_boot_devices(
+        &mut self,
+    ) -> Result<(Option<Vec<FsConfig>>, Option<Vec<NetConfig>>)> {
         let pending_root_devices = self.pending_devices.take();
 
         let mut root_devices = Vec::<FsConfig>::new();
+        let mut net_devices = Vec::<NetConfig>::new();
 
         if let Some(devices) = pending_root_devices {
             for dev in devices {
@@ -140,17 +146,56 @@ impl CloudHypervisorInner {
 
                         root_devices.push(fs_cfg);
                     }
+                    DeviceType::Network(dev) => {
+                        let net_cfg = NetConfig::try_from(dev)?;
+
+                        net_devices.push(net_cfg);
+                    }
                     _ => continue,
                 };
             }
 
-            Ok(Some(root_devices))
+            let net_devices = if net_devices.is_empty() {
+                None
+            } else {
+                Some(net_devices)
+            };
+
+            Ok((Some(root_devices), net_devices))
         } else {
-            Ok(None)
+            Ok((None, None))
         }
     }
 }
 
+impl TryFrom<NetworkDevice> for NetConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(dev: NetworkDevice) -> Result<Self, Self::Error> {
+        let cfg = dev.config;
+
+        let mac = cfg
+            .guest_mac
+            .map(|mac| MacAddr { bytes: mac.0 })
+            .ok_or_else(|| anyhow!("missing guest mac of network device {}", dev.id))?;
+
+        // each queue pair has a rx and a tx queue
+        let num_queues: usize = if cfg.queue_num > 1 {
+            cfg.queue_num * 2
+        } else {
+            0
+        };
+
+        Ok(NetConfig {
+            tap: Some(cfg.host_dev_name),
+            mac,
+            num_queues,
+            id: Some(dev.id),
+            ..Default::default()
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct ShareFsSettings {
     cfg: ShareFsDeviceConfig,
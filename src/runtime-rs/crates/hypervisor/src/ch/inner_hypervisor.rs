@@ -108,7 +108,7 @@ impl CloudHypervisorInner {
     }
 
     async fn boot_vm(&mut self) -> Result<()> {
-        let shared_fs_devices = self.get_shared_fs_devices().await?;
+        let (shared_fs_devices, network_devices) = self.get_boot_devices().await?;
 
         let socket = self
             .api_socket
@@ -148,6 +148,7 @@ impl CloudHypervisorInner {
             cfg: hypervisor_config.clone(),
             tdx_enabled,
             shared_fs_devices,
+            network_devices,
         };
 
         let cfg = VmConfig::try_from(named_cfg)?;
@@ -527,6 +528,22 @@ impl CloudHypervisorInner {
         Ok(root_path)
     }
 
+    pub(crate) async fn set_balloon_size(&self, _size_mb: u64) -> Result<()> {
+        Err(anyhow!("balloon is not supported by cloud-hypervisor yet"))
+    }
+
+    pub(crate) async fn resize_memory(&mut self, _new_mem_mb: u32) -> Result<u32> {
+        Err(anyhow!(
+            "memory hotplug is not supported by cloud-hypervisor yet"
+        ))
+    }
+
+    pub(crate) async fn resize_vcpu(&mut self, _new_vcpus: u32) -> Result<u32> {
+        Err(anyhow!(
+            "vcpu hotplug is not supported by cloud-hypervisor yet"
+        ))
+    }
+
     pub(crate) async fn capabilities(&self) -> Result<Capabilities> {
         let mut caps = Capabilities::default();
         caps.set(CapabilityBits::FsSharingSupport);
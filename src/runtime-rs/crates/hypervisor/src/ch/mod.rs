@@ -5,8 +5,8 @@
 
 use super::HypervisorState;
 use crate::device::DeviceType;
-use crate::{Hypervisor, VcpuThreadIds};
-use anyhow::{Context, Result};
+use crate::{Hypervisor, VcpuThreadIds, VmmState};
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use kata_types::capabilities::Capabilities;
 use kata_types::config::hypervisor::Hypervisor as HypervisorConfig;
@@ -29,6 +29,9 @@ mod utils;
 
 use inner::CloudHypervisorInner;
 
+// the cid of the hybrid vsock of the VM config, local to the VM
+const HYBRID_VSOCK_CID: u32 = 3;
+
 #[derive(Debug, Default, Clone)]
 pub struct CloudHypervisor {
     inner: Arc<RwLock<CloudHypervisorInner>>,
@@ -40,11 +43,6 @@ impl CloudHypervisor {
             inner: Arc::new(RwLock::new(CloudHypervisorInner::new())),
         }
     }
-
-    pub async fn set_hypervisor_config(&mut self, config: HypervisorConfig) {
-        let mut inner = self.inner.write().await;
-        inner.set_hypervisor_config(config)
-    }
 }
 
 #[async_trait]
@@ -89,6 +87,21 @@ impl Hypervisor for CloudHypervisor {
         inner.remove_device(device).await
     }
 
+    async fn set_balloon_size(&self, size_mb: u64) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.set_balloon_size(size_mb).await
+    }
+
+    async fn resize_memory(&self, new_mem_mb: u32) -> Result<u32> {
+        let mut inner = self.inner.write().await;
+        inner.resize_memory(new_mem_mb).await
+    }
+
+    async fn resize_vcpu(&self, new_vcpus: u32) -> Result<u32> {
+        let mut inner = self.inner.write().await;
+        inner.resize_vcpu(new_vcpus).await
+    }
+
     async fn get_agent_socket(&self) -> Result<String> {
         let inner = self.inner.write().await;
         inner.get_agent_socket().await
@@ -99,6 +112,11 @@ impl Hypervisor for CloudHypervisor {
         inner.disconnect().await
     }
 
+    async fn set_hypervisor_config(&self, config: HypervisorConfig) {
+        let mut inner = self.inner.write().await;
+        inner.set_hypervisor_config(config)
+    }
+
     async fn hypervisor_config(&self) -> HypervisorConfig {
         let inner = self.inner.write().await;
         inner.hypervisor_config()
@@ -147,6 +165,31 @@ impl Hypervisor for CloudHypervisor {
         let inner = self.inner.read().await;
         inner.capabilities().await
     }
+
+    // the VM running before the resources of the sandbox are prepared was
+    // started ahead from a pool
+    async fn is_pooled(&self) -> bool {
+        let inner = self.inner.read().await;
+        inner.state == VmmState::VmRunning
+    }
+
+    // the VM config of cloud hypervisor always takes a hybrid vsock with the
+    // cid local to the VM, the one allocated has to be the same
+    async fn set_guest_vsock(&self, guest_cid: u32, vhost_fd: Option<std::fs::File>) -> Result<()> {
+        if vhost_fd.is_some() {
+            return Err(anyhow!(
+                "cloud hypervisor takes a hybrid vsock, not vhost-vsock"
+            ));
+        }
+        if guest_cid != HYBRID_VSOCK_CID {
+            return Err(anyhow!(
+                "cloud hypervisor takes the hybrid vsock cid {}, not {}",
+                HYBRID_VSOCK_CID,
+                guest_cid
+            ));
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
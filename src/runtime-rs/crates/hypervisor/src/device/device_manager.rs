@@ -4,24 +4,34 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
 
 use anyhow::{anyhow, Context, Result};
 use kata_sys_util::rand::RandomBytes;
-use tokio::sync::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Mutex, RwLock};
 
 use super::{
+    scsi::{ScsiControllerPool, ScsiControllerState},
     util::{get_host_path, get_virt_drive_name},
     Device, DeviceConfig, DeviceType,
 };
 use crate::{
-    BlockConfig, BlockDevice, Hypervisor, KATA_BLK_DEV_TYPE, KATA_MMIO_BLK_DEV_TYPE,
-    VIRTIO_BLOCK_MMIO, VIRTIO_BLOCK_PCI,
+    BlockConfig, BlockDevice, Hypervisor, VfioConfig, VfioDevice, KATA_BLK_DEV_TYPE,
+    KATA_MMIO_BLK_DEV_TYPE, KATA_SCSI_DEV_TYPE, VIRTIO_BLOCK_MMIO, VIRTIO_BLOCK_PCI, VIRTIO_SCSI,
 };
 
 pub type ArcMutexDevice = Arc<Mutex<dyn Device>>;
 
 const DEVICE_TYPE_BLOCK: &str = "b";
+const DRIVE_ID_PREFIX: &str = "drive-";
+const PCIE_ROOT_BUS: &str = "pcie.0";
+const PCI_ROOT_BUS: &str = "pci.0";
+// the slots of a pci bus, the slot 0 is the host bridge
+const MAX_PCI_SLOT: u8 = 31;
 
 /// block_index and released_block_index are used to search an available block index
 /// in Sandbox.
@@ -58,13 +68,88 @@ impl SharedInfo {
         self.released_block_index.push(index);
         self.released_block_index.sort_by(|a, b| b.cmp(a));
     }
+
+    // take_device_index declares the index the device had before it was
+    // migrated, the ones skipped are left available
+    fn take_device_index(&mut self, index: u64) {
+        if let Some(pos) = self.released_block_index.iter().position(|i| *i == index) {
+            self.released_block_index.remove(pos);
+            return;
+        }
+        while self.block_index <= index {
+            if self.block_index != index {
+                self.released_block_index.push(self.block_index);
+            }
+            self.block_index += 1;
+        }
+        self.released_block_index.sort_by(|a, b| b.cmp(a));
+    }
+}
+
+/// PciSlotState is the guest pci slot of the device at the host path, it's
+/// saved so that the device gets the same slot back once attached again
+/// after a restore.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PciSlotState {
+    pub slot: u8,
+    pub host_path: String,
+}
+
+/// MigratedDevice is a block device detached for a live migration, it's
+/// attached again on the destination with the same id, config and
+/// references of the containers.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MigratedDevice {
+    pub device_id: String,
+    pub attach_count: u64,
+    pub config: BlockConfig,
+}
+
+/// MigrationDeviceState is the devices detached for a live migration, along
+/// with the guest pci slots and the scsi controllers, so that the devices
+/// are attached again at the same places in the guest.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct MigrationDeviceState {
+    pub devices: Vec<MigratedDevice>,
+    pub pci_slots: Vec<PciSlotState>,
+    pub scsi_controllers: Vec<ScsiControllerState>,
+}
+
+// PendingDevice is the device created by the manager, to be attached by the
+// caller once the lock of the manager is released
+struct PendingDevice {
+    device_id: String,
+    device: ArcMutexDevice,
+    hypervisor: Arc<dyn Hypervisor>,
+    // dropped once the device is attached or given up, which wakes up the
+    // others asking for the same device
+    _done: watch::Sender<()>,
+}
+
+enum NewDevice {
+    // the device attached already, shared with the caller
+    Attached(DeviceType),
+    // the device created, to be attached by the caller
+    Created(PendingDevice),
+    // the device being attached by another caller
+    Attaching(watch::Receiver<()>),
 }
 
 // Device manager will manage the lifecycle of sandbox device
 pub struct DeviceManager {
     devices: HashMap<String, ArcMutexDevice>,
+    // the ids of the block devices by their host path, so that a device is
+    // found without locking the ones being attached
+    host_paths: HashMap<String, String>,
+    // the devices being attached out of the lock of the manager, by id
+    attaching: HashMap<String, watch::Receiver<()>>,
     hypervisor: Arc<dyn Hypervisor>,
     shared_info: SharedInfo,
+    // the guest pci slots requested by the devices or taken for the ones
+    // passed through, by the host path of the device placed there
+    pci_slots: BTreeMap<u8, String>,
+    // the addresses of the block devices of the virtio-scsi driver
+    scsi_controllers: ScsiControllerPool,
 }
 
 impl DeviceManager {
@@ -72,56 +157,107 @@ impl DeviceManager {
         let devices = HashMap::<String, ArcMutexDevice>::new();
         Ok(DeviceManager {
             devices,
+            host_paths: HashMap::new(),
+            attaching: HashMap::new(),
             hypervisor,
             shared_info: SharedInfo::new(),
+            pci_slots: BTreeMap::new(),
+            scsi_controllers: ScsiControllerPool::default(),
         })
     }
 
-    async fn try_add_device(&mut self, device_id: &str) -> Result<()> {
-        // find the device
-        let device = self
-            .devices
-            .get(device_id)
-            .context("failed to find device")?;
+    // finish_attach marks the device created as attached, the one failed to
+    // be attached is given up along with the resources taken for it
+    fn finish_attach(&mut self, device_id: &str, info: &DeviceType, failed: bool) {
+        self.attaching.remove(device_id);
+        if !failed {
+            return;
+        }
+        self.release_device(info);
+        self.devices.remove(device_id);
+    }
 
-        // attach device
-        let mut device_guard = device.lock().await;
-        let result = device_guard.attach(self.hypervisor.as_ref()).await;
+    // give_up_abandoned gives up the devices the callers attaching them
+    // dropped before settling them, e.g. along with a request cancelled.
+    // Their senders are dropped while they're still being attached, which
+    // never happens otherwise, and the others would wait for them forever.
+    // The hotplug they may have started is undone.
+    async fn give_up_abandoned(&mut self) {
+        let abandoned: Vec<String> = self
+            .attaching
+            .iter()
+            .filter(|(_, done)| done.has_changed().is_err())
+            .map(|(id, _)| id.clone())
+            .collect();
+        for device_id in abandoned {
+            let dev = match self.devices.get(&device_id) {
+                Some(dev) => dev.clone(),
+                None => {
+                    self.attaching.remove(&device_id);
+                    continue;
+                }
+            };
+            warn!(
+                sl!(),
+                "device {} abandoned while attached, give it up", device_id
+            );
 
-        // handle attach error
-        if let Err(e) = result {
-            if let DeviceType::Block(device) = device_guard.get_device_info().await {
-                self.shared_info.release_device_index(device.config.index);
+            let mut device_guard = dev.lock().await;
+            let info = device_guard.get_device_info().await;
+            let attach_count = match &info {
+                DeviceType::Block(device) => device.attach_count,
+                DeviceType::Vfio(device) => device.attach_count,
+                _ => 0,
             };
+            if attach_count > 0 {
+                if let Err(e) = device_guard.detach(self.hypervisor.as_ref()).await {
+                    warn!(
+                        sl!(),
+                        "failed to detach device {} abandoned: {:?}", device_id, e
+                    );
+                }
+            }
             drop(device_guard);
-            self.devices.remove(device_id);
-            return Err(e);
+            self.finish_attach(&device_id, &info, true);
         }
+    }
 
-        Ok(())
+    // release_device gives back what the manager took for the device
+    fn release_device(&mut self, info: &DeviceType) {
+        match info {
+            DeviceType::Block(device) => {
+                self.shared_info.release_device_index(device.config.index);
+                self.release_pci_slot(&device.config);
+                self.release_scsi_addr(&device.config);
+                self.host_paths.remove(&device.config.path_on_host);
+            }
+            DeviceType::Vfio(device) => {
+                let host_path = &device.config.bus_slot_func;
+                if let Some(slot) = device.guest_pci_slot {
+                    self.release_slot(slot, host_path);
+                }
+                self.host_paths.remove(host_path);
+            }
+            _ => {}
+        }
     }
 
     pub async fn try_remove_device(&mut self, device_id: &str) -> Result<()> {
         if let Some(dev) = self.devices.get(device_id) {
             let mut device_guard = dev.lock().await;
-            let result = match device_guard.detach(self.hypervisor.as_ref()).await {
-                Ok(index) => {
-                    if let Some(i) = index {
-                        // release the declared block device index
-                        self.shared_info.release_device_index(i);
-                    }
-                    Ok(())
-                }
-                Err(e) => Err(e),
-            };
-
-            // if detach success, remove it from device manager
-            if result.is_ok() {
+            // the device is kept if it's still used by others
+            if device_guard
+                .detach(self.hypervisor.as_ref())
+                .await?
+                .is_some()
+            {
+                let info = device_guard.get_device_info().await;
                 drop(device_guard);
+                self.release_device(&info);
                 self.devices.remove(device_id);
             }
 
-            return result;
+            return Ok(());
         }
 
         Err(anyhow!(
@@ -141,17 +277,61 @@ impl DeviceManager {
         ))
     }
 
-    async fn find_device(&self, host_path: String) -> Option<String> {
-        for (device_id, dev) in &self.devices {
-            match dev.lock().await.get_device_info().await {
-                DeviceType::Block(device) => {
-                    if device.config.path_on_host == host_path {
-                        return Some(device_id.to_string());
-                    }
-                }
-                _ => {
-                    // TODO: support find other device type
-                    continue;
+    /// resize_block_device tells the guest the new size in bytes of the block
+    /// device, grown on the host already.
+    pub async fn resize_block_device(&self, device_id: &str, new_size: u64) -> Result<()> {
+        let info = self.get_device_info(device_id).await?;
+        match &info {
+            DeviceType::Block(device) if device.config.is_readonly => {
+                return Err(anyhow!("block device {} is read-only", device_id))
+            }
+            DeviceType::Block(_) => {}
+            _ => return Err(anyhow!("device {} isn't a block device", device_id)),
+        }
+        self.hypervisor
+            .resize_block_device(info, new_size)
+            .await
+            .with_context(|| format!("resize block device {}", device_id))
+    }
+
+    /// block_device_path returns the path on the host of the block device,
+    /// None if it isn't one.
+    pub async fn block_device_path(&self, device_id: &str) -> Option<String> {
+        match self.get_device_info(device_id).await {
+            Ok(DeviceType::Block(device)) => Some(device.config.path_on_host),
+            _ => None,
+        }
+    }
+
+    // attached_devices are the devices managed but the ones being attached,
+    // which are locked until the hypervisor is done with them
+    fn attached_devices(&self) -> impl Iterator<Item = &ArcMutexDevice> {
+        self.devices
+            .iter()
+            .filter(|(id, _)| !self.attaching.contains_key(*id))
+            .map(|(_, dev)| dev)
+    }
+
+    /// list_devices returns the info of all the devices attached.
+    pub async fn list_devices(&self) -> Vec<DeviceType> {
+        let mut devices = vec![];
+        for dev in self.attached_devices() {
+            devices.push(dev.lock().await.get_device_info().await);
+        }
+        devices
+    }
+
+    /// find_block_device returns the info of the block device attached with
+    /// the major and minor number, if any.
+    pub async fn find_block_device(&self, major: i64, minor: i64) -> Option<DeviceType> {
+        if major == 0 && minor == 0 {
+            return None;
+        }
+        for dev in self.attached_devices() {
+            let info = dev.lock().await.get_device_info().await;
+            if let DeviceType::Block(device) = &info {
+                if device.config.major == major && device.config.minor == minor {
+                    return Some(info);
                 }
             }
         }
@@ -175,37 +355,117 @@ impl DeviceManager {
         Ok(virt_path)
     }
 
-    async fn new_device(&mut self, device_config: &DeviceConfig) -> Result<String> {
+    // new_device creates the device of the config, unless it's managed
+    // already: the device attached is shared, the one being attached by
+    // another caller is waited for.
+    async fn new_device(&mut self, device_config: &DeviceConfig) -> Result<NewDevice> {
+        self.give_up_abandoned().await;
+
         // device ID must be generated by manager instead of device itself
         // in case of ID collision
         let device_id = self.new_device_id()?;
         let dev: ArcMutexDevice = match device_config {
             DeviceConfig::BlockCfg(config) => {
-                // try to find the device, found and just return id.
-                if let Some(dev_id_matched) = self.find_device(config.path_on_host.clone()).await {
-                    info!(
-                        sl!(),
-                        "device with host path:{:?} found. just return device id: {:?}",
-                        config.path_on_host.clone(),
-                        dev_id_matched
-                    );
+                // the device given by major and minor number is found by its
+                // host path as well, so that it's never attached twice
+                let host_path = if config.path_on_host.is_empty() {
+                    get_host_path(DEVICE_TYPE_BLOCK.to_owned(), config.major, config.minor)
+                        .context("failed to get host path")?
+                } else {
+                    config.path_on_host.clone()
+                };
+                if let Some(shared) = self.share_device(&host_path).await? {
+                    return Ok(shared);
+                }
 
-                    return Ok(dev_id_matched);
+                let dev = self
+                    .create_block_device(config, device_id.clone())
+                    .await
+                    .context("failed to create device")?;
+                self.host_paths.insert(host_path, device_id.clone());
+                dev
+            }
+            // the function passed through is found by its host address
+            DeviceConfig::VfioCfg(config) => {
+                let host_path = config.bus_slot_func.clone();
+                if let Some(shared) = self.share_device(&host_path).await? {
+                    return Ok(shared);
                 }
 
-                self.create_block_device(config, device_id.clone())
+                let dev = self
+                    .create_vfio_device(config, device_id.clone())
                     .await
-                    .context("failed to create device")?
+                    .context("failed to create vfio device")?;
+                self.host_paths.insert(host_path, device_id.clone());
+                dev
             }
             _ => {
                 return Err(anyhow!("invliad device type"));
             }
         };
 
-        // register device to devices
+        // register device to devices, it's attached by the caller
         self.devices.insert(device_id.clone(), dev.clone());
+        let (done, attaching) = watch::channel(());
+        self.attaching.insert(device_id.clone(), attaching);
+
+        Ok(NewDevice::Created(PendingDevice {
+            device_id,
+            device: dev,
+            hypervisor: self.hypervisor.clone(),
+            _done: done,
+        }))
+    }
 
-        Ok(device_id)
+    // share_device finds the device managed already at the host path, the
+    // one attached is shared by taking a reference of it
+    async fn share_device(&self, host_path: &str) -> Result<Option<NewDevice>> {
+        let dev_id_matched = match self.host_paths.get(host_path) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        info!(
+            sl!(),
+            "device with host path:{:?} found. just return device id: {:?}",
+            host_path,
+            dev_id_matched
+        );
+        if let Some(done) = self.attaching.get(dev_id_matched) {
+            return Ok(Some(NewDevice::Attaching(done.clone())));
+        }
+        let dev = self
+            .devices
+            .get(dev_id_matched)
+            .context("failed to find device")?;
+        // it only takes a reference of the device attached
+        let mut device_guard = dev.lock().await;
+        device_guard.attach(self.hypervisor.as_ref()).await?;
+        Ok(Some(NewDevice::Attached(
+            device_guard.get_device_info().await,
+        )))
+    }
+
+    // create_vfio_device places the function passed through at a guest pci
+    // slot of its own, so that the agent knows where to find it
+    async fn create_vfio_device(
+        &mut self,
+        config: &VfioConfig,
+        device_id: String,
+    ) -> Result<ArcMutexDevice> {
+        let topology_supported = self
+            .hypervisor
+            .capabilities()
+            .await
+            .context("get hypervisor capabilities")?
+            .is_pci_topology_supported();
+        let slot = self.take_free_pci_slot(&config.bus_slot_func)?;
+        Ok(Arc::new(Mutex::new(VfioDevice {
+            id: device_id,
+            placement: config.placement(topology_supported),
+            config: config.clone(),
+            guest_pci_slot: Some(slot),
+            attach_count: 0,
+        })))
     }
 
     async fn create_block_device(
@@ -214,22 +474,43 @@ impl DeviceManager {
         device_id: String,
     ) -> Result<ArcMutexDevice> {
         let mut block_config = config.clone();
+        let hypervisor_config = self.hypervisor.hypervisor_config().await;
         // get hypervisor block driver
-        let block_driver = match self
-            .hypervisor
-            .hypervisor_config()
-            .await
-            .blockdev_info
-            .block_device_driver
-            .as_str()
-        {
+        let block_driver = match hypervisor_config.blockdev_info.block_device_driver.as_str() {
             // convert the block driver to kata type
             VIRTIO_BLOCK_MMIO => KATA_MMIO_BLK_DEV_TYPE.to_string(),
             VIRTIO_BLOCK_PCI => KATA_BLK_DEV_TYPE.to_string(),
+            VIRTIO_SCSI => KATA_SCSI_DEV_TYPE.to_string(),
             _ => "".to_string(),
         };
         block_config.driver_option = block_driver;
 
+        // the queues given by the volume take precedence over the config,
+        // one queue per default vCPU if neither of them sets it
+        if block_config.num_queues == 0 {
+            block_config.num_queues = match hypervisor_config.blockdev_info.block_device_num_queues
+            {
+                0 => hypervisor_config.cpu_info.default_vcpus.max(1) as u32,
+                n => n,
+            };
+        }
+        if block_config.queue_size == 0 {
+            block_config.queue_size = hypervisor_config.blockdev_info.block_device_queue_size;
+        }
+        let capabilities = self
+            .hypervisor
+            .capabilities()
+            .await
+            .context("get hypervisor capabilities")?;
+        if block_config.num_queues > 1 && !capabilities.is_block_device_multi_queue_supported() {
+            warn!(
+                sl!(),
+                "hypervisor doesn't support multi-queue block devices, ignore {} queues",
+                block_config.num_queues
+            );
+            block_config.num_queues = 1;
+        }
+
         // generate block device index and virt path
         // safe here, Block device always has virt_path.
         if let Some(virt_path) = self.get_dev_virt_path(DEVICE_TYPE_BLOCK)? {
@@ -237,6 +518,31 @@ impl DeviceManager {
             block_config.virt_path = virt_path.1;
         }
 
+        // the io settings of the volume take precedence over the config
+        let blockdev_info = &hypervisor_config.blockdev_info;
+        if block_config.aio.is_empty() {
+            block_config.aio = blockdev_info.block_device_aio.clone();
+        }
+        block_config
+            .cache_direct
+            .get_or_insert(blockdev_info.block_device_cache_direct);
+        block_config
+            .cache_noflush
+            .get_or_insert(blockdev_info.block_device_cache_noflush);
+
+        // the drive is added before the device by some hypervisors, the
+        // device refers to it by id, the virt path seen by the agent is the
+        // same either way
+        if capabilities.is_separate_backend_hotplug() {
+            block_config.drive_id = format!("{}{}", DRIVE_ID_PREFIX, device_id);
+            if block_config.driver_option == KATA_BLK_DEV_TYPE {
+                block_config.bus = match hypervisor_config.machine_info.machine_type.as_str() {
+                    "q35" | "virt" => PCIE_ROOT_BUS.to_string(),
+                    _ => PCI_ROOT_BUS.to_string(),
+                };
+            }
+        }
+
         // if the path on host is empty, we need to get device host path from the device major and minor number
         // Otherwise, it might be rawfile based block device, the host path is already passed from the runtime,
         // so we don't need to do anything here
@@ -246,12 +552,288 @@ impl DeviceManager {
                     .context("failed to get host path")?;
         }
 
+        // the slot or the scsi address is taken last, nothing fails after it
+        let slot = if block_config.driver_option == KATA_BLK_DEV_TYPE
+            && capabilities.is_separate_backend_hotplug()
+        {
+            self.assign_pci_slot(block_config.guest_pci_slot, &block_config.path_on_host)
+        } else if let Some(slot) = block_config.guest_pci_slot {
+            Err(anyhow!(
+                "hypervisor can't place block device {} at guest pci slot {}",
+                block_config.path_on_host,
+                slot
+            ))
+        } else if block_config.driver_option == KATA_SCSI_DEV_TYPE {
+            self.assign_scsi_addr(
+                &mut block_config,
+                blockdev_info.block_device_scsi_controllers,
+            )
+            .map(|_| None)
+        } else {
+            Ok(None)
+        };
+        block_config.guest_pci_slot = match slot {
+            Ok(slot) => slot,
+            Err(e) => {
+                self.shared_info.release_device_index(block_config.index);
+                return Err(e);
+            }
+        };
+
         Ok(Arc::new(Mutex::new(BlockDevice::new(
             device_id,
             block_config,
         ))))
     }
 
+    // assign_pci_slot takes the guest pci slot requested for the device at
+    // the host path. A slot is never taken over: the request fails if the
+    // slot is held by another device, rather than moving either of them,
+    // and it's only released once the device is detached. Without a request
+    // the device gets back the slot it had before the restore, if any,
+    // otherwise the hypervisor chooses it, which fails the hotplug if it
+    // took the slot requested.
+    fn assign_pci_slot(&mut self, requested: Option<u8>, host_path: &str) -> Result<Option<u8>> {
+        let slot = match requested {
+            Some(slot) => slot,
+            None => {
+                return Ok(self
+                    .pci_slots
+                    .iter()
+                    .find(|(_, p)| p.as_str() == host_path)
+                    .map(|(slot, _)| *slot))
+            }
+        };
+
+        if slot == 0 || slot > MAX_PCI_SLOT {
+            return Err(anyhow!("invalid guest pci slot {}", slot));
+        }
+        match self.pci_slots.get(&slot) {
+            Some(p) if p != host_path => Err(anyhow!(
+                "guest pci slot {} is occupied by device {}",
+                slot,
+                p
+            )),
+            _ => {
+                self.pci_slots.insert(slot, host_path.to_string());
+                Ok(Some(slot))
+            }
+        }
+    }
+
+    // take_free_pci_slot takes the slot the device at the host path had
+    // before the restore, if any, otherwise the highest free one, away from
+    // the slots the hypervisor picks from the bottom of the bus
+    fn take_free_pci_slot(&mut self, host_path: &str) -> Result<u8> {
+        if let Some(slot) = self.assign_pci_slot(None, host_path)? {
+            return Ok(slot);
+        }
+        let slot = (1..=MAX_PCI_SLOT)
+            .rev()
+            .find(|slot| !self.pci_slots.contains_key(slot))
+            .ok_or_else(|| anyhow!("no free guest pci slot for device {}", host_path))?;
+        self.pci_slots.insert(slot, host_path.to_string());
+        Ok(slot)
+    }
+
+    /// check_free_pci_slots fails if the guest pci slots not taken yet are
+    /// fewer than the count, so that a batch of devices passed through is
+    /// refused before any of them is attached.
+    pub fn check_free_pci_slots(&self, count: usize) -> Result<()> {
+        let free = MAX_PCI_SLOT as usize - self.pci_slots.len();
+        if count > free {
+            return Err(anyhow!(
+                "{} guest pci slots asked for, {} are free",
+                count,
+                free
+            ));
+        }
+        Ok(())
+    }
+
+    fn release_pci_slot(&mut self, config: &BlockConfig) {
+        if let Some(slot) = config.guest_pci_slot {
+            self.release_slot(slot, &config.path_on_host);
+        }
+    }
+
+    fn release_slot(&mut self, slot: u8, host_path: &str) {
+        if self.pci_slots.get(&slot).map(|p| p.as_str()) == Some(host_path) {
+            self.pci_slots.remove(&slot);
+        }
+    }
+
+    // assign_scsi_addr takes the first free LUN of the scsi controllers the
+    // VM booted with for the device, its virt path is the scsi address the
+    // agent waits for.
+    fn assign_scsi_addr(&mut self, config: &mut BlockConfig, controllers: u32) -> Result<()> {
+        let addr = self
+            .scsi_controllers
+            .allocate(&config.path_on_host, controllers)?;
+        config.virt_path = addr.guest_addr();
+        config.scsi_addr = Some(addr);
+        Ok(())
+    }
+
+    fn release_scsi_addr(&mut self, config: &BlockConfig) {
+        if let Some(addr) = config.scsi_addr.as_ref() {
+            self.scsi_controllers.release(addr, &config.path_on_host);
+        }
+    }
+
+    /// save_pci_slots returns the guest pci slots taken by the devices.
+    pub fn save_pci_slots(&self) -> Vec<PciSlotState> {
+        self.pci_slots
+            .iter()
+            .map(|(slot, host_path)| PciSlotState {
+                slot: *slot,
+                host_path: host_path.clone(),
+            })
+            .collect()
+    }
+
+    /// restore_pci_slots takes the slots saved before the restore, the
+    /// devices still attached keep holding them.
+    pub fn restore_pci_slots(&mut self, slots: Vec<PciSlotState>) {
+        for s in slots {
+            self.pci_slots.insert(s.slot, s.host_path);
+        }
+    }
+
+    /// save_scsi_controllers returns the scsi controllers added with the
+    /// devices attached to them.
+    pub fn save_scsi_controllers(&self) -> Vec<ScsiControllerState> {
+        self.scsi_controllers.save()
+    }
+
+    /// restore_scsi_controllers takes the controllers saved before the
+    /// restore, they're in the VM already and aren't added again.
+    pub fn restore_scsi_controllers(&mut self, controllers: Vec<ScsiControllerState>) {
+        self.scsi_controllers.restore(controllers);
+    }
+
+    /// detach_all_for_migration unplugs all the devices from the VM for a
+    /// live migration, whatever the references of the containers to them.
+    /// Nothing is detached if a device can't be migrated, e.g. a device
+    /// passed through, or if a device is being attached. The devices
+    /// detached already are attached back if one fails to be detached.
+    pub async fn detach_all_for_migration(&mut self) -> Result<MigrationDeviceState> {
+        self.give_up_abandoned().await;
+        if let Some(id) = self.attaching.keys().next() {
+            return Err(anyhow!("device {} is being attached", id));
+        }
+        let mut devices = vec![];
+        for (id, dev) in self.devices.iter() {
+            match dev.lock().await.get_device_info().await {
+                DeviceType::Block(device) => devices.push(device),
+                _ => {
+                    return Err(anyhow!(
+                        "device {} is passed through to the VM, it can't be migrated",
+                        id
+                    ))
+                }
+            }
+        }
+        devices.sort_by_key(|d| d.config.index);
+
+        // the slots are saved before the devices release them
+        let mut state = MigrationDeviceState {
+            pci_slots: self.save_pci_slots(),
+            scsi_controllers: self.save_scsi_controllers(),
+            ..Default::default()
+        };
+        let mut detached: Vec<BlockDevice> = vec![];
+        for device in devices {
+            if let Err(e) = self
+                .hypervisor
+                .remove_device(DeviceType::Block(device.clone()))
+                .await
+            {
+                for d in detached.into_iter().rev() {
+                    if let Err(e) = self
+                        .hypervisor
+                        .add_device(DeviceType::Block(d.clone()))
+                        .await
+                    {
+                        warn!(
+                            sl!(),
+                            "failed to attach device {} back: {:?}", d.device_id, e
+                        );
+                    }
+                }
+                return Err(e).with_context(|| format!("detach device {}", device.device_id));
+            }
+            detached.push(device);
+        }
+
+        for device in detached {
+            self.shared_info.release_device_index(device.config.index);
+            self.release_pci_slot(&device.config);
+            self.release_scsi_addr(&device.config);
+            self.host_paths.remove(&device.config.path_on_host);
+            self.devices.remove(&device.device_id);
+            state.devices.push(MigratedDevice {
+                device_id: device.device_id,
+                attach_count: device.attach_count,
+                config: device.config,
+            });
+        }
+        Ok(state)
+    }
+
+    /// reattach_from_migration attaches the devices detached for a live
+    /// migration, in the guest pci slots and at the scsi addresses they had.
+    /// The ones failed are given up and returned as an error once the
+    /// others are attached.
+    pub async fn reattach_from_migration(&mut self, state: MigrationDeviceState) -> Result<()> {
+        if !self.devices.is_empty() {
+            return Err(anyhow!(
+                "{} devices are attached already, they may hold the slots of the migrated ones",
+                self.devices.len()
+            ));
+        }
+        self.restore_pci_slots(state.pci_slots);
+        self.restore_scsi_controllers(state.scsi_controllers);
+
+        let mut failed = vec![];
+        for d in state.devices {
+            self.shared_info.take_device_index(d.config.index);
+            let device = BlockDevice {
+                device_id: d.device_id,
+                attach_count: d.attach_count,
+                config: d.config,
+            };
+            if let Err(e) = self
+                .hypervisor
+                .add_device(DeviceType::Block(device.clone()))
+                .await
+            {
+                warn!(
+                    sl!(),
+                    "failed to attach migrated device {}: {:?}", device.device_id, e
+                );
+                self.shared_info.release_device_index(device.config.index);
+                self.release_pci_slot(&device.config);
+                self.release_scsi_addr(&device.config);
+                failed.push(device.device_id);
+                continue;
+            }
+            self.host_paths
+                .insert(device.config.path_on_host.clone(), device.device_id.clone());
+            let id = device.device_id.clone();
+            let dev: ArcMutexDevice = Arc::new(Mutex::new(device));
+            self.devices.insert(id, dev);
+        }
+
+        if !failed.is_empty() {
+            return Err(anyhow!(
+                "migrated devices {:?} couldn't be attached",
+                failed
+            ));
+        }
+        Ok(())
+    }
+
     // device ID must be generated by device manager instead of device itself
     // in case of ID collision
     fn new_device_id(&self) -> Result<String> {
@@ -272,32 +854,466 @@ impl DeviceManager {
 // Many scenarios have similar steps when adding devices. so to reduce duplicated code,
 // we should create a common method abstracted and use it in various scenarios.
 // do_handle_device:
-// (1) new_device with DeviceConfig and return device_id;
-// (2) try_add_device with device_id and do really add device;
+// (1) new_device with DeviceConfig, the device attached already is shared;
+// (2) attach the device created out of the lock of the manager, so that the
+//     devices of the containers created concurrently are attached in parallel;
 // (3) return device info of device's info;
 pub async fn do_handle_device(
     d: &RwLock<DeviceManager>,
     dev_info: &DeviceConfig,
 ) -> Result<DeviceType> {
-    let device_id = d
-        .write()
-        .await
-        .new_device(dev_info)
-        .await
-        .context("failed to create deviec")?;
+    // the device being attached by another caller is looked up again once
+    // it's done, it's created again if it failed
+    let pending = loop {
+        let new_device = d
+            .write()
+            .await
+            .new_device(dev_info)
+            .await
+            .context("failed to create deviec")?;
+        match new_device {
+            NewDevice::Attached(info) => return Ok(info),
+            NewDevice::Created(pending) => break pending,
+            NewDevice::Attaching(mut done) => {
+                // fails once the sender is dropped, which is what's waited for
+                let _ = done.changed().await;
+            }
+        }
+    };
 
-    d.write()
-        .await
-        .try_add_device(&device_id)
-        .await
-        .context("failed to add deivce")?;
+    let mut device_guard = pending.device.lock().await;
+    let result = device_guard.attach(pending.hypervisor.as_ref()).await;
+    let device_info = device_guard.get_device_info().await;
+    drop(device_guard);
 
-    let device_info = d
-        .read()
-        .await
-        .get_device_info(&device_id)
+    // the others waiting for the device are woken up once it's settled
+    d.write()
         .await
-        .context("failed to get device info")?;
+        .finish_attach(&pending.device_id, &device_info, result.is_err());
+    drop(pending);
+    result.context("failed to add deivce")?;
 
     Ok(device_info)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{device::scsi::MAX_SCSI_LUNS, mock::MockHypervisor, HypervisorConfig};
+    use kata_types::capabilities::CapabilityBits;
+    use std::time::Duration;
+
+    // the hotplugs take a while as with a real hypervisor, the ones of
+    // /dev/fail* fail
+    fn mock_hypervisor() -> MockHypervisor {
+        MockHypervisor::new()
+            .with_hotplug_delay(Duration::from_millis(100))
+            .fail_add(|device| {
+                matches!(device, DeviceType::Block(device)
+                    if device.config.path_on_host.starts_with("/dev/fail"))
+            })
+    }
+
+    // the hypervisor adding the drive before the device, as QEMU does
+    fn separate_backend_hypervisor(config: HypervisorConfig) -> Arc<MockHypervisor> {
+        Arc::new(
+            MockHypervisor::new()
+                .with_config(config)
+                .with_capabilities(CapabilityBits::SeparateBackendHotplug),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_attach() {
+        let hypervisor = Arc::new(mock_hypervisor());
+        let d = RwLock::new(DeviceManager::new(hypervisor.clone()).unwrap());
+        let config = |path: String| {
+            DeviceConfig::BlockCfg(BlockConfig {
+                path_on_host: path,
+                ..Default::default()
+            })
+        };
+
+        // the devices of 8 containers are hotplugged at the same time
+        let configs: Vec<_> = (0..8).map(|i| config(format!("/dev/loop{}", i))).collect();
+        let results =
+            futures::future::join_all(configs.iter().map(|c| do_handle_device(&d, c))).await;
+        assert!(results.iter().all(|r| r.is_ok()));
+        let hotplugs = hypervisor.hotplugs();
+        assert_eq!(hotplugs.len(), 8);
+        let last_start = hotplugs.iter().map(|(start, _)| start).max().unwrap();
+        let first_end = hotplugs.iter().map(|(_, end)| end).min().unwrap();
+        assert!(last_start < first_end, "hotplugs serialized");
+
+        // the same device asked for at the same time is hotplugged once
+        let configs: Vec<_> = (0..4).map(|_| config("/dev/sdb".to_string())).collect();
+        let results =
+            futures::future::join_all(configs.iter().map(|c| do_handle_device(&d, c))).await;
+        assert_eq!(hypervisor.hotplugs().len(), 9);
+        let ids: Vec<_> = results
+            .into_iter()
+            .map(|r| match r.unwrap() {
+                DeviceType::Block(device) => device.device_id,
+                _ => panic!("not a block device"),
+            })
+            .collect();
+        assert!(ids.iter().all(|id| id == &ids[0]));
+        match d.read().await.get_device_info(&ids[0]).await.unwrap() {
+            DeviceType::Block(device) => assert_eq!(device.attach_count, 4),
+            _ => panic!("not a block device"),
+        }
+
+        // the device failed is given up, the one waiting for it tries again
+        let configs: Vec<_> = (0..2).map(|_| config("/dev/fail".to_string())).collect();
+        let results =
+            futures::future::join_all(configs.iter().map(|c| do_handle_device(&d, c))).await;
+        assert!(results.iter().all(|r| r.is_err()));
+        assert_eq!(hypervisor.failures(), 2);
+        let manager = d.read().await;
+        assert_eq!(manager.list_devices().await.len(), 9);
+        assert!(manager.attaching.is_empty());
+        assert!(!manager.host_paths.contains_key("/dev/fail"));
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_attach() {
+        let hypervisor = Arc::new(mock_hypervisor());
+        let d = RwLock::new(DeviceManager::new(hypervisor.clone()).unwrap());
+        let config = DeviceConfig::BlockCfg(BlockConfig {
+            path_on_host: "/dev/sdb".to_string(),
+            ..Default::default()
+        });
+
+        // the caller is dropped in the middle of the hotplug
+        let dropped =
+            tokio::time::timeout(Duration::from_millis(10), do_handle_device(&d, &config)).await;
+        assert!(dropped.is_err());
+        assert_eq!(d.read().await.attaching.len(), 1);
+
+        // the device is attached again instead of being waited for forever
+        let info = tokio::time::timeout(Duration::from_secs(5), do_handle_device(&d, &config))
+            .await
+            .expect("waiting for the abandoned device")
+            .unwrap();
+        match info {
+            DeviceType::Block(device) => assert_eq!(device.attach_count, 1),
+            _ => panic!("not a block device"),
+        }
+        let manager = d.read().await;
+        assert!(manager.attaching.is_empty());
+        assert_eq!(manager.list_devices().await.len(), 1);
+        assert_eq!(manager.host_paths.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_create_block_device_separate_backend() {
+        let mut config = HypervisorConfig::default();
+        config.blockdev_info.block_device_driver = VIRTIO_BLOCK_PCI.to_string();
+        config.blockdev_info.block_device_aio = "io_uring".to_string();
+        config.machine_info.machine_type = "q35".to_string();
+        let mut manager = DeviceManager::new(separate_backend_hypervisor(config)).unwrap();
+        let block_config = BlockConfig {
+            path_on_host: "/dev/loop0".to_string(),
+            ..Default::default()
+        };
+        let dev = manager
+            .create_block_device(&block_config, "abc".to_string())
+            .await
+            .unwrap();
+        let info = dev.lock().await.get_device_info().await;
+        let config = match info {
+            DeviceType::Block(device) => device.config,
+            _ => panic!("not a block device"),
+        };
+        assert_eq!(config.drive_id, "drive-abc");
+        assert_eq!(config.aio, "io_uring");
+        assert_eq!(config.cache_direct, Some(false));
+        assert_eq!(config.bus, PCIE_ROOT_BUS);
+        assert_eq!(config.driver_option, KATA_BLK_DEV_TYPE);
+        assert_eq!(config.virt_path, "/dev/vdb");
+
+        // the settings of the volume win over the config
+        let block_config = BlockConfig {
+            path_on_host: "/dev/loop1".to_string(),
+            aio: "threads".to_string(),
+            cache_direct: Some(true),
+            ..Default::default()
+        };
+        let dev = manager
+            .create_block_device(&block_config, "def".to_string())
+            .await
+            .unwrap();
+        let info = dev.lock().await.get_device_info().await;
+        let config = match info {
+            DeviceType::Block(device) => device.config,
+            _ => panic!("not a block device"),
+        };
+        assert_eq!(config.aio, "threads");
+        assert_eq!(config.cache_direct, Some(true));
+        assert_eq!(config.cache_noflush, Some(false));
+    }
+
+    #[tokio::test]
+    async fn test_guest_pci_slot() {
+        let new_manager = || async {
+            let mut config = HypervisorConfig::default();
+            config.blockdev_info.block_device_driver = VIRTIO_BLOCK_PCI.to_string();
+            DeviceManager::new(separate_backend_hypervisor(config)).unwrap()
+        };
+        let mut manager = new_manager().await;
+
+        let new_config = |path: &str, slot: Option<u8>| BlockConfig {
+            path_on_host: path.to_string(),
+            guest_pci_slot: slot,
+            ..Default::default()
+        };
+        let slot_of = |dev: DeviceType| match dev {
+            DeviceType::Block(device) => device.config.guest_pci_slot,
+            _ => panic!("not a block device"),
+        };
+
+        let dev = manager
+            .create_block_device(&new_config("/dev/loop0", Some(5)), "abc".to_string())
+            .await
+            .unwrap();
+        assert_eq!(slot_of(dev.lock().await.get_device_info().await), Some(5));
+
+        // the slot isn't taken over
+        let err = manager
+            .create_block_device(&new_config("/dev/loop1", Some(5)), "def".to_string())
+            .await
+            .err()
+            .unwrap();
+        assert!(format!("{}", err).contains("occupied by device /dev/loop0"));
+        for slot in [0, 32] {
+            assert!(manager
+                .create_block_device(&new_config("/dev/loop1", Some(slot)), "def".to_string())
+                .await
+                .is_err());
+        }
+
+        // the slot saved is given back to the device after a restore
+        let saved = manager.save_pci_slots();
+        assert_eq!(
+            saved,
+            vec![PciSlotState {
+                slot: 5,
+                host_path: "/dev/loop0".to_string()
+            }]
+        );
+        let mut manager = new_manager().await;
+        manager.restore_pci_slots(saved);
+        let dev = manager
+            .create_block_device(&new_config("/dev/loop0", None), "abc".to_string())
+            .await
+            .unwrap();
+        assert_eq!(slot_of(dev.lock().await.get_device_info().await), Some(5));
+        let dev = manager
+            .create_block_device(&new_config("/dev/loop1", None), "def".to_string())
+            .await
+            .unwrap();
+        assert_eq!(slot_of(dev.lock().await.get_device_info().await), None);
+    }
+
+    #[tokio::test]
+    async fn test_vfio_devices() {
+        let d = RwLock::new(DeviceManager::new(Arc::new(mock_hypervisor())).unwrap());
+        let new_config = |bdf: &str| {
+            DeviceConfig::VfioCfg(VfioConfig {
+                sysfs_path: "".to_string(),
+                bus_slot_func: bdf.to_string(),
+                mode: crate::VfioBusMode::PCI,
+                topology_hint: None,
+            })
+        };
+        let vfio_of = |dev: DeviceType| match dev {
+            DeviceType::Vfio(device) => device,
+            _ => panic!("not a vfio device"),
+        };
+
+        // the functions are placed from the top of the bus
+        let first = vfio_of(
+            do_handle_device(&d, &new_config("0000:3b:00.0"))
+                .await
+                .unwrap(),
+        );
+        assert_eq!(first.guest_pci_slot, Some(MAX_PCI_SLOT));
+        let second = vfio_of(
+            do_handle_device(&d, &new_config("0000:3b:00.1"))
+                .await
+                .unwrap(),
+        );
+        assert_eq!(second.guest_pci_slot, Some(MAX_PCI_SLOT - 1));
+
+        // shared by another container, at the same slot
+        let shared = vfio_of(
+            do_handle_device(&d, &new_config("0000:3b:00.0"))
+                .await
+                .unwrap(),
+        );
+        assert_eq!(shared.id, first.id);
+        assert_eq!(shared.attach_count, 2);
+
+        let mut manager = d.write().await;
+        manager
+            .check_free_pci_slots(MAX_PCI_SLOT as usize - 2)
+            .unwrap();
+        assert!(manager
+            .check_free_pci_slots(MAX_PCI_SLOT as usize - 1)
+            .is_err());
+
+        // the slot is released once the last container detaches it
+        manager.try_remove_device(&first.id).await.unwrap();
+        assert_eq!(manager.save_pci_slots().len(), 2);
+        manager.try_remove_device(&first.id).await.unwrap();
+        assert_eq!(
+            manager.save_pci_slots(),
+            vec![PciSlotState {
+                slot: MAX_PCI_SLOT - 1,
+                host_path: "0000:3b:00.1".to_string()
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scsi_controllers() {
+        let new_manager = || async {
+            let mut config = HypervisorConfig::default();
+            config.blockdev_info.block_device_driver = VIRTIO_SCSI.to_string();
+            DeviceManager::new(separate_backend_hypervisor(config)).unwrap()
+        };
+        let new_config = |path: &str| BlockConfig {
+            path_on_host: path.to_string(),
+            ..Default::default()
+        };
+        let virt_path_of = |device: DeviceType| match device {
+            DeviceType::Block(device) => device.config.virt_path,
+            _ => panic!("not a block device"),
+        };
+
+        // the disk goes on the controller the VM booted with, nothing is
+        // added to the VM
+        let mut manager = new_manager().await;
+        let dev = manager
+            .create_block_device(&new_config("/dev/sdc"), "abc".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            virt_path_of(dev.lock().await.get_device_info().await),
+            "0:0"
+        );
+
+        // the controller of the VM restored is full
+        let mut luns = BTreeMap::new();
+        for lun in 0..MAX_SCSI_LUNS {
+            luns.insert(lun, format!("/dev/loop{}", lun));
+        }
+        let mut manager = new_manager().await;
+        manager.restore_scsi_controllers(vec![ScsiControllerState {
+            controller: 0,
+            luns,
+        }]);
+
+        // the device attached again keeps its address
+        let dev = manager
+            .create_block_device(&new_config("/dev/loop3"), "abc".to_string())
+            .await
+            .unwrap();
+        let config = match dev.lock().await.get_device_info().await {
+            DeviceType::Block(device) => device.config,
+            _ => panic!("not a block device"),
+        };
+        assert_eq!(config.driver_option, KATA_SCSI_DEV_TYPE);
+        assert_eq!(config.virt_path, "0:3");
+
+        // no LUN is left, the index of the device is given up
+        let err = manager
+            .create_block_device(&new_config("/dev/sda"), "def".to_string())
+            .await
+            .err()
+            .unwrap();
+        assert!(format!("{:?}", err).contains("LUNs of the 1 scsi controllers are taken"));
+        assert_eq!(manager.save_scsi_controllers().len(), 1);
+        assert_eq!(manager.shared_info.released_block_index, vec![2]);
+
+        // no pci slot for a scsi disk
+        let config = BlockConfig {
+            guest_pci_slot: Some(5),
+            ..new_config("/dev/sdb")
+        };
+        assert!(manager
+            .create_block_device(&config, "ghi".to_string())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_migration() {
+        let d = RwLock::new(DeviceManager::new(Arc::new(mock_hypervisor())).unwrap());
+        let config = |path: &str| {
+            DeviceConfig::BlockCfg(BlockConfig {
+                path_on_host: path.to_string(),
+                ..Default::default()
+            })
+        };
+        let id_of = |dev: DeviceType| match dev {
+            DeviceType::Block(device) => device.device_id,
+            _ => panic!("not a block device"),
+        };
+        // /dev/loop1 is shared by two containers
+        let loop0 = id_of(do_handle_device(&d, &config("/dev/loop0")).await.unwrap());
+        let loop1 = id_of(do_handle_device(&d, &config("/dev/loop1")).await.unwrap());
+        do_handle_device(&d, &config("/dev/loop1")).await.unwrap();
+
+        let state = d.write().await.detach_all_for_migration().await.unwrap();
+        assert!(d.read().await.list_devices().await.is_empty());
+        let detached: Vec<_> = state
+            .devices
+            .iter()
+            .map(|m| {
+                (
+                    m.device_id.clone(),
+                    m.attach_count,
+                    m.config.virt_path.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            detached,
+            vec![
+                (loop0.clone(), 1, "/dev/vdb".to_string()),
+                (loop1.clone(), 2, "/dev/vdc".to_string()),
+            ]
+        );
+
+        // the devices keep their ids and indexes on the destination, the
+        // next device takes the next index
+        let hypervisor = Arc::new(mock_hypervisor());
+        let d = RwLock::new(DeviceManager::new(hypervisor.clone()).unwrap());
+        d.write()
+            .await
+            .reattach_from_migration(state.clone())
+            .await
+            .unwrap();
+        assert_eq!(hypervisor.hotplugs().len(), 2);
+        match d.read().await.get_device_info(&loop1).await.unwrap() {
+            DeviceType::Block(device) => {
+                assert_eq!(device.attach_count, 2);
+                assert_eq!(device.config.virt_path, "/dev/vdc");
+            }
+            _ => panic!("not a block device"),
+        }
+        match do_handle_device(&d, &config("/dev/loop2")).await.unwrap() {
+            DeviceType::Block(device) => assert_eq!(device.config.virt_path, "/dev/vdd"),
+            _ => panic!("not a block device"),
+        }
+
+        // the devices attached already may hold the slots
+        assert!(d
+            .write()
+            .await
+            .reattach_from_migration(state)
+            .await
+            .is_err());
+    }
+}
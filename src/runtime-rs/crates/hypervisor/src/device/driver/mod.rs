@@ -7,13 +7,16 @@
 mod vhost_user;
 mod virtio_blk;
 pub use virtio_blk::{
-    BlockConfig, BlockDevice, KATA_BLK_DEV_TYPE, KATA_MMIO_BLK_DEV_TYPE, VIRTIO_BLOCK_MMIO,
-    VIRTIO_BLOCK_PCI,
+    BlockConfig, BlockDevice, ScsiAddr, KATA_BLK_DEV_TYPE, KATA_MMIO_BLK_DEV_TYPE,
+    KATA_SCSI_DEV_TYPE, VIRTIO_BLOCK_MMIO, VIRTIO_BLOCK_PCI, VIRTIO_SCSI,
 };
 mod virtio_net;
 pub use virtio_net::{Address, NetworkConfig, NetworkDevice};
 mod vfio;
-pub use vfio::{bind_device_to_host, bind_device_to_vfio, VfioBusMode, VfioConfig, VfioDevice};
+pub use vfio::{
+    bind_device_to_host, bind_device_to_vfio, PciTopology, VfioBusMode, VfioConfig, VfioDevice,
+    VFIO_PCI,
+};
 mod virtio_fs;
 pub use virtio_fs::{
     ShareFsDevice, ShareFsDeviceConfig, ShareFsMountConfig, ShareFsMountDevice, ShareFsMountType,
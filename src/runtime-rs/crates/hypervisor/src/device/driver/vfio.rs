@@ -9,9 +9,7 @@ use std::{fs, path::Path, process::Command};
 use crate::device::Device;
 use crate::device::DeviceType;
 use crate::Hypervisor as hypervisor;
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-use anyhow::anyhow;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 
 fn override_driver(bdf: &str, driver: &str) -> Result<()> {
@@ -44,6 +42,41 @@ impl VfioBusMode {
     }
 }
 
+/// Where the device is placed in the guest PCIe topology.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PciTopology {
+    /// NUMA node of the guest the device is close to
+    pub numa_node: Option<u32>,
+
+    /// PCIe root port of the guest the device is plugged into
+    pub root_port: Option<String>,
+
+    /// NUMA node of the host the device is on, the device is placed close
+    /// to the guest NUMA node backed by it
+    pub host_numa_node: Option<u32>,
+}
+
+impl PciTopology {
+    /// on_host_numa_node returns the placement of the device close to the
+    /// NUMA node of the host it's on, if the host has NUMA nodes. The guest
+    /// NUMA node is left to the hypervisor, which knows the nodes of the
+    /// host backing the ones of the guest.
+    pub fn on_host_numa_node(bdf: &str) -> Self {
+        let numa_node = Path::new(SYS_PCI_DEVICES_PATH).join(bdf).join("numa_node");
+        Self {
+            // -1 if the host isn't NUMA
+            host_numa_node: fs::read_to_string(numa_node)
+                .ok()
+                .and_then(|n| n.trim().parse::<u32>().ok()),
+            ..Default::default()
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.numa_node.is_none() && self.root_port.is_none() && self.host_numa_node.is_none()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct VfioConfig {
     /// Sysfs path for mdev bus type device
@@ -54,6 +87,29 @@ pub struct VfioConfig {
 
     /// Bus Mode, PCI or MMIO
     pub mode: VfioBusMode,
+
+    /// Preferred placement of the device in the guest, passed to the
+    /// hypervisor when choosing the guest slot
+    pub topology_hint: Option<PciTopology>,
+}
+
+impl VfioConfig {
+    /// placement returns the placement of the device the hypervisor is asked
+    /// for, the hint is dropped with a warning if the hypervisor can't honor
+    /// it.
+    pub fn placement(&self, topology_supported: bool) -> Option<PciTopology> {
+        let hint = self.topology_hint.as_ref().filter(|h| !h.is_empty())?;
+        if !topology_supported {
+            warn!(
+                sl!(),
+                "hypervisor can't place device {} at {:?}, fall back to any slot",
+                self.bus_slot_func,
+                hint
+            );
+            return None;
+        }
+        Some(hint.clone())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -63,6 +119,18 @@ pub struct VfioDevice {
 
     /// Config info for Vfio Device
     pub config: VfioConfig,
+
+    /// Placement of the device in the guest, None if it's left to the
+    /// hypervisor
+    pub placement: Option<PciTopology>,
+
+    /// Guest pci slot of the device on the root bus, taken by the device
+    /// manager so that the agent finds the device, None if it's left to
+    /// the hypervisor
+    pub guest_pci_slot: Option<u8>,
+
+    /// Count of the containers the device is attached for
+    pub attach_count: u64,
 }
 
 /// binds the device to vfio driver after unbinding from host.
@@ -156,6 +224,123 @@ pub fn bind_device_to_host(bdf: &str, host_driver: &str, _vendor_device_id: &str
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vfio_placement() {
+        let mut config = VfioConfig {
+            sysfs_path: "".to_string(),
+            bus_slot_func: "0000:3b:00.0".to_string(),
+            mode: VfioBusMode::PCI,
+            topology_hint: None,
+        };
+        assert_eq!(config.placement(true), None);
+
+        config.topology_hint = Some(PciTopology::default());
+        assert_eq!(config.placement(true), None);
+
+        let hint = PciTopology {
+            numa_node: Some(1),
+            root_port: Some("rp1".to_string()),
+            ..Default::default()
+        };
+        config.topology_hint = Some(hint.clone());
+        assert_eq!(config.placement(true), Some(hint));
+        // the hypervisor unable to honor the hint places the device anywhere
+        assert_eq!(config.placement(false), None);
+    }
+
+    #[test]
+    fn test_on_host_numa_node() {
+        let topology = PciTopology::on_host_numa_node("not-a-bdf");
+        assert!(topology.is_empty());
+
+        // the node of the host isn't taken for the one of the guest
+        let hint = PciTopology {
+            host_numa_node: Some(1),
+            ..Default::default()
+        };
+        let config = VfioConfig {
+            sysfs_path: "".to_string(),
+            bus_slot_func: "0000:3b:00.0".to_string(),
+            mode: VfioBusMode::PCI,
+            topology_hint: Some(hint.clone()),
+        };
+        let placement = config.placement(true).unwrap();
+        assert_eq!(placement.numa_node, None);
+        assert_eq!(placement, hint);
+    }
+}
+
+#[async_trait]
+impl Device for VfioDevice {
+    async fn attach(&mut self, h: &dyn hypervisor) -> Result<()> {
+        // the device passed through is shared by the containers asking for it
+        if self
+            .increase_attach_count()
+            .await
+            .context("failed to increase attach count")?
+        {
+            return Ok(());
+        }
+        if let Err(e) = h.add_device(DeviceType::Vfio(self.clone())).await {
+            self.decrease_attach_count().await?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn detach(&mut self, h: &dyn hypervisor) -> Result<Option<u64>> {
+        if self
+            .decrease_attach_count()
+            .await
+            .context("failed to decrease attach count")?
+        {
+            return Ok(None);
+        }
+        if let Err(e) = h.remove_device(DeviceType::Vfio(self.clone())).await {
+            self.increase_attach_count().await?;
+            return Err(e);
+        }
+        // there's no block index, the slot is released by the manager
+        Ok(Some(0))
+    }
+
+    async fn get_device_info(&self) -> DeviceType {
+        DeviceType::Vfio(self.clone())
+    }
+
+    async fn increase_attach_count(&mut self) -> Result<bool> {
+        match self.attach_count {
+            0 => {
+                self.attach_count += 1;
+                Ok(false)
+            }
+            std::u64::MAX => Err(anyhow!("device was attached too many times")),
+            _ => {
+                self.attach_count += 1;
+                Ok(true)
+            }
+        }
+    }
+
+    async fn decrease_attach_count(&mut self) -> Result<bool> {
+        match self.attach_count {
+            0 => Err(anyhow!("detaching a device that wasn't attached")),
+            1 => {
+                self.attach_count -= 1;
+                Ok(false)
+            }
+            _ => {
+                self.attach_count -= 1;
+                Ok(true)
+            }
+        }
+    }
+}
+
 #[async_trait]
 impl Device for VfioConfig {
     async fn attach(&mut self, _h: &dyn hypervisor) -> Result<()> {
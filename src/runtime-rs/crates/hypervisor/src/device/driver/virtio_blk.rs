@@ -10,12 +10,33 @@ use crate::device::DeviceType;
 use crate::Hypervisor as hypervisor;
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 /// VIRTIO_BLOCK_PCI indicates block driver is virtio-pci based
 pub const VIRTIO_BLOCK_PCI: &str = "virtio-blk-pci";
 pub const KATA_MMIO_BLK_DEV_TYPE: &str = "mmioblk";
 pub const KATA_BLK_DEV_TYPE: &str = "blk";
+/// VIRTIO_SCSI indicates block driver is virtio-scsi based
+pub const VIRTIO_SCSI: &str = "virtio-scsi";
+pub const KATA_SCSI_DEV_TYPE: &str = "scsi";
+
+/// ScsiAddr is the virtio-scsi controller and the LUN of the device. The
+/// controller is the scsi id of its devices as well, so that the address
+/// seen by the guest is unique across the controllers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScsiAddr {
+    pub controller: u32,
+    pub lun: u32,
+}
 
-#[derive(Debug, Clone, Default)]
+impl ScsiAddr {
+    /// guest_addr returns the "<scsi id>:<lun>" address of the device the
+    /// agent waits for.
+    pub fn guest_addr(&self) -> String {
+        format!("{}:{}", self.controller, self.lun)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct BlockConfig {
     /// Path of the drive.
     pub path_on_host: String,
@@ -44,6 +65,37 @@ pub struct BlockConfig {
 
     /// device minor number
     pub minor: i64,
+
+    /// number of virtio queues, 0 means the one of the hypervisor config
+    pub num_queues: u32,
+
+    /// size of each virtio queue, 0 means the one of the hypervisor config
+    pub queue_size: u32,
+
+    /// id of the drive backing the device, only for the hypervisors adding
+    /// the drive separately, e.g. QEMU
+    pub drive_id: String,
+
+    /// aio mode of the drive, empty means the one of the hypervisor config
+    pub aio: String,
+
+    /// open the drive with direct I/O, none means the hypervisor config
+    pub cache_direct: Option<bool>,
+
+    /// ignore the flush requests of the guest, none means the hypervisor
+    /// config
+    pub cache_noflush: Option<bool>,
+
+    /// bus the device is plugged into, empty means the hypervisor default
+    pub bus: String,
+
+    /// slot of the guest pci bus the device is plugged into for a stable
+    /// name in the guest, none lets the hypervisor choose it. A slot held by
+    /// another device fails the attach, neither device is moved.
+    pub guest_pci_slot: Option<u8>,
+
+    /// controller and LUN of the device, only for the virtio-scsi driver
+    pub scsi_addr: Option<ScsiAddr>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -4,7 +4,7 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::fmt;
+use std::{fmt, os::unix::io::RawFd};
 
 #[derive(Clone)]
 pub struct Address(pub [u8; 6]);
@@ -27,6 +27,13 @@ pub struct NetworkConfig {
 
     /// Guest MAC address.
     pub guest_mac: Option<Address>,
+
+    /// Number of queue pairs, 0 or 1 means a single queue pair.
+    pub queue_num: usize,
+
+    /// Queues of the tap opened by the runtime, the hypervisor taking them
+    /// doesn't open the tap by its name. Empty once they're handed over.
+    pub fds: Vec<RawFd>,
 }
 
 #[derive(Debug, Clone)]
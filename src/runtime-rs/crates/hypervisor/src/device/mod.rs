@@ -16,6 +16,7 @@ use async_trait::async_trait;
 
 pub mod device_manager;
 pub mod driver;
+pub mod scsi;
 pub mod util;
 
 #[derive(Debug)]
@@ -0,0 +1,146 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::ScsiAddr;
+
+// the LUNs of each virtio-scsi controller
+pub const MAX_SCSI_LUNS: u32 = 256;
+
+/// ScsiControllerState is a virtio-scsi controller with the host paths of
+/// the devices by their LUN, it's saved so that the devices get the same
+/// address back once attached again after a restore.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScsiControllerState {
+    pub controller: u32,
+    pub luns: BTreeMap<u32, String>,
+}
+
+/// ScsiControllerPool allocates the addresses of the block devices across
+/// the virtio-scsi controllers of the sandbox, they're all added to the VM
+/// at boot.
+#[derive(Debug, Default)]
+pub(crate) struct ScsiControllerPool {
+    // the host paths of the devices by the LUN, of each controller
+    controllers: BTreeMap<u32, BTreeMap<u32, String>>,
+}
+
+impl ScsiControllerPool {
+    /// allocate returns the address of the device at the host path among
+    /// the controllers of the VM. The device gets back the address it had
+    /// before the restore, if any, otherwise the first free LUN of the
+    /// controllers in order.
+    pub(crate) fn allocate(&mut self, host_path: &str, controllers: u32) -> Result<ScsiAddr> {
+        for (controller, luns) in self.controllers.iter() {
+            if let Some((lun, _)) = luns.iter().find(|(_, p)| p.as_str() == host_path) {
+                return Ok(ScsiAddr {
+                    controller: *controller,
+                    lun: *lun,
+                });
+            }
+        }
+
+        let controllers = controllers.max(1);
+        let addr = (0..controllers)
+            .find_map(|controller| {
+                let luns = self.controllers.get(&controller);
+                (0..MAX_SCSI_LUNS)
+                    .find(|lun| !luns.map_or(false, |l| l.contains_key(lun)))
+                    .map(|lun| ScsiAddr { controller, lun })
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "all the LUNs of the {} scsi controllers are taken",
+                    controllers
+                )
+            })?;
+        self.controllers
+            .entry(addr.controller)
+            .or_default()
+            .insert(addr.lun, host_path.to_string());
+        Ok(addr)
+    }
+
+    /// release frees the LUN of the device at the host path, unless it's
+    /// taken by another one.
+    pub(crate) fn release(&mut self, addr: &ScsiAddr, host_path: &str) {
+        if let Some(luns) = self.controllers.get_mut(&addr.controller) {
+            if luns.get(&addr.lun).map(|p| p.as_str()) == Some(host_path) {
+                luns.remove(&addr.lun);
+            }
+        }
+    }
+
+    pub(crate) fn save(&self) -> Vec<ScsiControllerState> {
+        self.controllers
+            .iter()
+            .map(|(controller, luns)| ScsiControllerState {
+                controller: *controller,
+                luns: luns.clone(),
+            })
+            .collect()
+    }
+
+    pub(crate) fn restore(&mut self, states: Vec<ScsiControllerState>) {
+        for s in states {
+            self.controllers.insert(s.controller, s.luns);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(controller: u32, lun: u32) -> ScsiAddr {
+        ScsiAddr { controller, lun }
+    }
+
+    #[test]
+    fn test_scsi_controller_pool() {
+        let mut pool = ScsiControllerPool::default();
+        let path = |i: u32| format!("/dev/loop{}", i);
+
+        // the first controller is filled before the second one
+        for i in 0..MAX_SCSI_LUNS {
+            assert_eq!(pool.allocate(&path(i), 2).unwrap(), addr(0, i));
+        }
+        let a = pool.allocate(&path(MAX_SCSI_LUNS), 2).unwrap();
+        assert_eq!(a, addr(1, 0));
+
+        // the device attached again keeps its address
+        assert_eq!(pool.allocate(&path(3), 2).unwrap(), addr(0, 3));
+
+        // the LUN released is taken first, on the first controller
+        pool.release(&addr(0, 3), "/dev/other");
+        pool.release(&addr(0, 3), &path(3));
+        assert_eq!(pool.allocate("/dev/sdz", 2).unwrap(), addr(0, 3));
+
+        // no more controller beyond the maximum
+        for i in 1..MAX_SCSI_LUNS {
+            pool.allocate(&format!("/dev/nbd{}", i), 2).unwrap();
+        }
+        let err = pool.allocate("/dev/nbd0", 2).unwrap_err();
+        assert!(err.to_string().contains("2 scsi controllers"));
+
+        // the controllers and their devices are saved and restored
+        let saved = pool.save();
+        assert_eq!(saved.len(), 2);
+        assert_eq!(saved[1].luns.get(&0), Some(&path(MAX_SCSI_LUNS)));
+        let mut pool = ScsiControllerPool::default();
+        pool.restore(saved);
+        let a = pool.allocate(&path(MAX_SCSI_LUNS), 2).unwrap();
+        assert_eq!(a, addr(1, 0));
+
+        // 0 is a single controller
+        let mut pool = ScsiControllerPool::default();
+        assert_eq!(pool.allocate(&path(0), 0).unwrap(), addr(0, 0));
+    }
+}
@@ -18,7 +18,7 @@ use dragonball::{
 };
 use kata_sys_util::mount;
 use kata_types::{
-    capabilities::{Capabilities, CapabilityBits},
+    capabilities::{Capabilities, CapabilityBits, VIRTIO_MEM_BLOCK_SIZE_MB},
     config::hypervisor::Hypervisor as HypervisorConfig,
 };
 use persist::sandbox_persist::Persist;
@@ -27,6 +27,8 @@ use std::{collections::HashSet, fs::create_dir_all, path::PathBuf};
 
 const DRAGONBALL_KERNEL: &str = "vmlinux";
 const DRAGONBALL_ROOT_FS: &str = "rootfs";
+// first usable cid above VMADDR_CID_HOST, the hybrid vsock is local to the VM
+const DEFAULT_GUEST_CID: u32 = 3;
 
 pub struct DragonballInner {
     /// sandbox id
@@ -64,6 +66,9 @@ pub struct DragonballInner {
 
     /// dragonball capabilities
     pub(crate) capabilities: Capabilities,
+
+    /// guest cid of the hybrid vsock, local to the VM
+    pub(crate) guest_cid: u32,
 }
 
 impl DragonballInner {
@@ -72,8 +77,14 @@ impl DragonballInner {
         capabilities.set(
             CapabilityBits::BlockDeviceSupport
                 | CapabilityBits::BlockDeviceHotplugSupport
-                | CapabilityBits::FsSharingSupport,
+                | CapabilityBits::MultiQueueSupport
+                | CapabilityBits::BlockDeviceMultiQueueSupport
+                | CapabilityBits::FsSharingSupport
+                | CapabilityBits::BalloonSupport
+                | CapabilityBits::NetworkDeviceHotplugSupport,
         );
+        // dragonball only hotplugs memory by virtio-mem
+        capabilities.set_memory_hotplug_granularity_mb(VIRTIO_MEM_BLOCK_SIZE_MB);
         DragonballInner {
             id: "".to_string(),
             vm_path: "".to_string(),
@@ -81,6 +92,7 @@ impl DragonballInner {
             netns: None,
             config: Default::default(),
             pending_devices: vec![],
+            guest_cid: DEFAULT_GUEST_CID,
             state: VmmState::NotReady,
             jailed: false,
             vmm_instance: VmmInstance::new(""),
@@ -373,6 +385,7 @@ impl Persist for DragonballInner {
             vmm_instance: VmmInstance::new(""),
             run_dir: hypervisor_state.run_dir,
             pending_devices: vec![],
+            guest_cid: DEFAULT_GUEST_CID,
             cached_block_devices: hypervisor_state.cached_block_devices,
             capabilities: Capabilities::new(),
         })
@@ -12,11 +12,12 @@ use dragonball::api::v1::{
     BlockDeviceConfigInfo, FsDeviceConfigInfo, FsMountConfigInfo, VirtioNetDeviceConfigInfo,
     VsockDeviceConfigInfo,
 };
+use kata_types::config::hypervisor::DRAGONBALL_BLOCK_DEVICE_AIO_MODES;
 
 use super::DragonballInner;
 use crate::{
-    device::DeviceType, HybridVsockConfig, NetworkConfig, ShareFsDeviceConfig, ShareFsMountConfig,
-    ShareFsMountType, ShareFsOperation, VmmState,
+    device::DeviceType, BlockConfig, HybridVsockConfig, NetworkConfig, ShareFsDeviceConfig,
+    ShareFsMountConfig, ShareFsMountType, ShareFsOperation, VfioDevice, VmmState,
 };
 
 const MB_TO_B: u32 = 1024 * 1024;
@@ -26,12 +27,17 @@ const DEFAULT_VIRTIO_FS_QUEUE_SIZE: i32 = 1024;
 const VIRTIO_FS: &str = "virtio-fs";
 const INLINE_VIRTIO_FS: &str = "inline-virtio-fs";
 
-pub(crate) fn drive_index_to_id(index: u64) -> String {
-    format!("drive_{}", index)
+fn unsupported_vfio(vfio: &VfioDevice) -> anyhow::Error {
+    anyhow!("dragonball doesn't support vfio device {} yet", vfio.id)
 }
 
 impl DragonballInner {
     pub(crate) async fn add_device(&mut self, device: DeviceType) -> Result<()> {
+        // the passthrough devices fail rather than bring the VMM down, before
+        // they're queued for the start of the VM
+        if let DeviceType::Vfio(vfio) = &device {
+            return Err(unsupported_vfio(vfio));
+        }
         if self.state == VmmState::NotReady {
             info!(sl!(), "VMM not ready, queueing device {}", device);
 
@@ -47,16 +53,9 @@ impl DragonballInner {
             DeviceType::Network(network) => self
                 .add_net_device(&network.config, network.id)
                 .context("add net device"),
-            DeviceType::Vfio(_) => {
-                todo!()
-            }
+            DeviceType::Vfio(vfio) => Err(unsupported_vfio(&vfio)),
             DeviceType::Block(block) => self
-                .add_block_device(
-                    block.config.path_on_host.as_str(),
-                    block.device_id.as_str(),
-                    block.config.is_readonly,
-                    block.config.no_drop,
-                )
+                .add_block_device(&block.config, block.device_id.as_str())
                 .context("add block device"),
             DeviceType::HybridVsock(hvsock) => self.add_hvsock(&hvsock.config).context("add vsock"),
             DeviceType::ShareFs(sharefs) => self
@@ -75,36 +74,55 @@ impl DragonballInner {
         info!(sl!(), "remove device {} ", device);
 
         match device {
-            DeviceType::Block(block) => {
-                let drive_id = drive_index_to_id(block.config.index);
-                self.remove_block_drive(drive_id.as_str())
-                    .context("remove block drive")
-            }
-            DeviceType::Vfio(_config) => {
-                todo!()
-            }
+            // the drive is inserted with the id of the device
+            DeviceType::Block(block) => self
+                .remove_block_drive(block.device_id.as_str())
+                .context("remove block drive"),
+            DeviceType::Vfio(vfio) => Err(unsupported_vfio(&vfio)),
             _ => Err(anyhow!("unsupported device {:?}", device)),
         }
     }
 
-    fn add_block_device(
-        &mut self,
-        path: &str,
-        id: &str,
-        read_only: bool,
-        no_drop: bool,
-    ) -> Result<()> {
-        let jailed_drive = self.get_resource(path, id).context("get resource")?;
+    fn add_block_device(&mut self, config: &BlockConfig, id: &str) -> Result<()> {
+        if !config.aio.is_empty()
+            && !DRAGONBALL_BLOCK_DEVICE_AIO_MODES.contains(&config.aio.as_str())
+        {
+            return Err(anyhow!(
+                "unsupported aio mode {} of block device {}, must be one of {:?}",
+                config.aio,
+                id,
+                DRAGONBALL_BLOCK_DEVICE_AIO_MODES
+            ));
+        }
+        if config.cache_noflush == Some(true) {
+            warn!(
+                sl!(),
+                "dragonball doesn't support cache_noflush, ignore it of block device {}", id
+            );
+        }
+
+        let jailed_drive = self
+            .get_resource(config.path_on_host.as_str(), id)
+            .context("get resource")?;
         self.cached_block_devices.insert(id.to_string());
 
-        let blk_cfg = BlockDeviceConfigInfo {
+        let mut blk_cfg = BlockDeviceConfigInfo {
             drive_id: id.to_string(),
             path_on_host: PathBuf::from(jailed_drive),
-            is_direct: self.config.blockdev_info.block_device_cache_direct,
-            no_drop,
-            is_read_only: read_only,
+            is_direct: config
+                .cache_direct
+                .unwrap_or(self.config.blockdev_info.block_device_cache_direct),
+            no_drop: config.no_drop,
+            is_read_only: config.is_readonly,
             ..Default::default()
         };
+        if config.num_queues > 0 {
+            blk_cfg.num_queues = config.num_queues as usize;
+        }
+        if config.queue_size > 0 {
+            blk_cfg.queue_size =
+                u16::try_from(config.queue_size).context("invalid block queue size")?;
+        }
         self.vmm_instance
             .insert_block_device(blk_cfg)
             .context("insert block device")
@@ -118,8 +136,8 @@ impl DragonballInner {
         if self.cached_block_devices.contains(id) && self.jailed {
             self.umount_jail_resource(id)
                 .context("umount jail resource")?;
-            self.cached_block_devices.remove(id);
         }
+        self.cached_block_devices.remove(id);
         Ok(())
     }
 
@@ -131,6 +149,12 @@ impl DragonballInner {
                 Some(mac) => MacAddr::from_bytes(&mac.0).ok(),
                 None => None,
             },
+            // each queue pair has a rx and a tx queue
+            num_queues: if config.queue_num > 1 {
+                config.queue_num * 2
+            } else {
+                0
+            },
             ..Default::default()
         };
 
@@ -231,7 +255,7 @@ impl DragonballInner {
             },
             cache_size: (self.config.shared_fs.virtio_fs_cache_size as u64)
                 .saturating_mul(MB_TO_B as u64),
-            xattr: true,
+            xattr: self.config.shared_fs.enable_xattr.unwrap_or(true),
             ..Default::default()
         };
         self.do_add_fs_device(&config.fs_type, &mut fs_cfg)
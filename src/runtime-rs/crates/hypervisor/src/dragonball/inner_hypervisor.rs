@@ -9,7 +9,8 @@ use std::{
     iter::FromIterator,
 };
 
-use anyhow::{Context, Ok, Result};
+use anyhow::{anyhow, Context, Ok, Result};
+use dragonball::api::v1::{BalloonDeviceConfigInfo, MemDeviceConfigInfo, VcpuResizeInfo};
 use kata_types::capabilities::Capabilities;
 
 use super::inner::DragonballInner;
@@ -18,6 +19,8 @@ use crate::{
 };
 use shim_interface::KATA_PATH;
 const DEFAULT_HYBRID_VSOCK_NAME: &str = "kata.hvsock";
+const DEFAULT_BALLOON_ID: &str = "balloon0";
+const DEFAULT_VIRTIO_MEM_ID: &str = "virtio_mem0";
 
 fn get_vsock_path(root: &str) -> String {
     [root, DEFAULT_HYBRID_VSOCK_NAME].join("/")
@@ -37,7 +40,7 @@ impl DragonballInner {
         let d = DeviceType::HybridVsock(HybridVsockDevice {
             id: format!("vsock-{}", &self.id),
             config: HybridVsockConfig {
-                guest_cid: 3,
+                guest_cid: self.guest_cid,
                 uds_path,
             },
         });
@@ -152,4 +155,90 @@ impl DragonballInner {
     pub(crate) async fn capabilities(&self) -> Result<Capabilities> {
         Ok(self.capabilities.clone())
     }
+
+    pub(crate) async fn set_balloon_size(&self, size_mb: u64) -> Result<()> {
+        if self.state != VmmState::VmRunning {
+            return Err(anyhow!(
+                "cannot resize balloon with VMM state {:?}",
+                self.state
+            ));
+        }
+
+        // the balloon device is inserted on first use and updated afterwards,
+        // deflate on oom is always enabled so that the guest could get its
+        // memory back before the OOM killer is involved.
+        let balloon_cfg = BalloonDeviceConfigInfo {
+            balloon_id: DEFAULT_BALLOON_ID.to_string(),
+            size_mib: size_mb,
+            use_shared_irq: None,
+            use_generic_irq: None,
+            f_deflate_on_oom: true,
+            f_reporting: false,
+        };
+        self.vmm_instance
+            .insert_balloon_device(balloon_cfg)
+            .context("insert balloon device")
+    }
+
+    pub(crate) async fn resize_memory(&mut self, new_mem_mb: u32) -> Result<u32> {
+        if self.state != VmmState::VmRunning {
+            return Err(anyhow!(
+                "cannot resize memory with VMM state {:?}",
+                self.state
+            ));
+        }
+
+        // dragonball only supports memory hotplug by virtio-mem, the config
+        // requires it unless the sandbox is sized statically
+        if !self.config.memory_info.enable_virtio_mem {
+            return Err(anyhow!(
+                "memory hotplug needs enable_virtio_mem with dragonball"
+            ));
+        }
+
+        // the boot memory can't be unplugged, only the memory of the virtio-mem
+        // device is resized
+        let boot_mem_mb = self.config.memory_info.default_memory;
+        let max_mem_mb = self.config.memory_info.default_maxmemory.max(boot_mem_mb);
+        let new_mem_mb = new_mem_mb.clamp(boot_mem_mb, max_mem_mb);
+
+        let mem_cfg = MemDeviceConfigInfo {
+            mem_id: DEFAULT_VIRTIO_MEM_ID.to_string(),
+            size_mib: (new_mem_mb - boot_mem_mb) as u64,
+            capacity_mib: (max_mem_mb - boot_mem_mb) as u64,
+            multi_region: true,
+            host_numa_node_id: None,
+            guest_numa_node_id: None,
+            use_shared_irq: None,
+            use_generic_irq: None,
+        };
+        self.vmm_instance
+            .insert_mem_device(mem_cfg)
+            .context("insert mem device")?;
+
+        Ok(new_mem_mb)
+    }
+
+    pub(crate) async fn resize_vcpu(&mut self, new_vcpus: u32) -> Result<u32> {
+        if self.state != VmmState::VmRunning {
+            return Err(anyhow!(
+                "cannot resize vcpu with VMM state {:?}",
+                self.state
+            ));
+        }
+
+        // the vcpus are hot-(un)plugged through the upcall channel, so the
+        // guest kernel offlines the vcpus before they are removed
+        let boot_vcpus = (self.config.cpu_info.default_vcpus as u32).max(1);
+        let max_vcpus = self.config.cpu_info.default_maxvcpus.max(boot_vcpus);
+        let new_vcpus = new_vcpus.clamp(boot_vcpus, max_vcpus);
+
+        self.vmm_instance
+            .resize_vcpu(VcpuResizeInfo {
+                vcpu_count: Some(new_vcpus as u8),
+            })
+            .context("resize vcpu")?;
+
+        Ok(new_vcpus)
+    }
 }
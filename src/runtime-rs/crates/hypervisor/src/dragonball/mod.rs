@@ -12,15 +12,15 @@ use inner::DragonballInner;
 use persist::sandbox_persist::Persist;
 pub mod vmm_instance;
 
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
-use kata_types::capabilities::Capabilities;
 use kata_types::config::hypervisor::Hypervisor as HypervisorConfig;
+use kata_types::{capabilities::Capabilities, config::RetryPolicy};
 use tokio::sync::RwLock;
 
-use crate::{DeviceType, Hypervisor, VcpuThreadIds};
+use crate::{DeviceType, Hypervisor, VcpuThreadIds, VmmState};
 
 pub struct Dragonball {
     inner: Arc<RwLock<DragonballInner>>,
@@ -39,9 +39,11 @@ impl Dragonball {
         }
     }
 
-    pub async fn set_hypervisor_config(&mut self, config: HypervisorConfig) {
+    /// set_retry_policy sets the policy to retry the requests to the VMM
+    /// while its upcall server isn't ready.
+    pub async fn set_retry_policy(&self, policy: RetryPolicy) {
         let mut inner = self.inner.write().await;
-        inner.set_hypervisor_config(config)
+        inner.vmm_instance.set_retry_policy(policy);
     }
 }
 
@@ -87,6 +89,21 @@ impl Hypervisor for Dragonball {
         inner.remove_device(device).await
     }
 
+    async fn set_balloon_size(&self, size_mb: u64) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.set_balloon_size(size_mb).await
+    }
+
+    async fn resize_memory(&self, new_mem_mb: u32) -> Result<u32> {
+        let mut inner = self.inner.write().await;
+        inner.resize_memory(new_mem_mb).await
+    }
+
+    async fn resize_vcpu(&self, new_vcpus: u32) -> Result<u32> {
+        let mut inner = self.inner.write().await;
+        inner.resize_vcpu(new_vcpus).await
+    }
+
     async fn get_agent_socket(&self) -> Result<String> {
         let inner = self.inner.read().await;
         inner.get_agent_socket().await
@@ -97,6 +114,11 @@ impl Hypervisor for Dragonball {
         inner.disconnect().await
     }
 
+    async fn set_hypervisor_config(&self, config: HypervisorConfig) {
+        let mut inner = self.inner.write().await;
+        inner.set_hypervisor_config(config)
+    }
+
     async fn hypervisor_config(&self) -> HypervisorConfig {
         let inner = self.inner.read().await;
         inner.hypervisor_config()
@@ -141,10 +163,32 @@ impl Hypervisor for Dragonball {
         self.save().await
     }
 
+    // the block devices are saved with the state of the hypervisor
+    async fn attached_devices(&self) -> Result<Option<HashSet<String>>> {
+        let inner = self.inner.read().await;
+        Ok(Some(inner.cached_block_devices.clone()))
+    }
+
     async fn capabilities(&self) -> Result<Capabilities> {
         let inner = self.inner.read().await;
         inner.capabilities().await
     }
+
+    // the VM running before the resources of the sandbox are prepared was
+    // started ahead from a pool
+    async fn is_pooled(&self) -> bool {
+        let inner = self.inner.read().await;
+        inner.state == VmmState::VmRunning
+    }
+
+    async fn set_guest_vsock(&self, guest_cid: u32, vhost_fd: Option<std::fs::File>) -> Result<()> {
+        if vhost_fd.is_some() {
+            return Err(anyhow!("dragonball takes a hybrid vsock, not vhost-vsock"));
+        }
+        let mut inner = self.inner.write().await;
+        inner.guest_cid = guest_cid;
+        Ok(())
+    }
 }
 
 #[async_trait]
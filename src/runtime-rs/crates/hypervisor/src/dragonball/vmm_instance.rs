@@ -15,13 +15,15 @@ use anyhow::{anyhow, Context, Result};
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use dragonball::{
     api::v1::{
-        BlockDeviceConfigInfo, BootSourceConfig, FsDeviceConfigInfo, FsMountConfigInfo,
-        InstanceInfo, InstanceState, VirtioNetDeviceConfigInfo, VmmAction, VmmActionError, VmmData,
-        VmmRequest, VmmResponse, VmmService, VsockDeviceConfigInfo,
+        BalloonDeviceConfigInfo, BlockDeviceConfigInfo, BootSourceConfig, FsDeviceConfigInfo,
+        FsMountConfigInfo, InstanceInfo, InstanceState, MemDeviceConfigInfo, VcpuResizeInfo,
+        VirtioNetDeviceConfigInfo, VmmAction, VmmActionError, VmmData, VmmRequest, VmmResponse,
+        VmmService, VsockDeviceConfigInfo,
     },
     vm::VmConfigInfo,
     Vmm,
 };
+use kata_types::config::RetryPolicy;
 use nix::sched::{setns, CloneFlags};
 use seccompiler::BpfProgram;
 use vmm_sys_util::eventfd::EventFd;
@@ -33,7 +35,8 @@ pub enum Request {
 }
 
 const DRAGONBALL_VERSION: &str = env!("CARGO_PKG_VERSION");
-const REQUEST_RETRY: u32 = 500;
+// retry every 10ms for 5s by default while the upcall server isn't ready
+const DEFAULT_REQUEST_RETRY_POLICY: RetryPolicy = RetryPolicy::fixed(500, 10);
 const KVM_DEVICE: &str = "/dev/kvm";
 
 pub struct VmmInstance {
@@ -44,6 +47,7 @@ pub struct VmmInstance {
     to_vmm_fd: EventFd,
     seccomp: BpfProgram,
     vmm_thread: Option<thread::JoinHandle<Result<i32>>>,
+    retry_policy: RetryPolicy,
 }
 
 impl VmmInstance {
@@ -63,9 +67,14 @@ impl VmmInstance {
             to_vmm_fd,
             seccomp: vec![],
             vmm_thread: None,
+            retry_policy: DEFAULT_REQUEST_RETRY_POLICY,
         }
     }
 
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
     pub fn get_shared_info(&self) -> Arc<RwLock<InstanceInfo>> {
         self.vmm_shared_info.clone()
     }
@@ -248,6 +257,26 @@ impl VmmInstance {
         Ok(())
     }
 
+    pub fn insert_balloon_device(&self, balloon_cfg: BalloonDeviceConfigInfo) -> Result<()> {
+        self.handle_request_with_retry(Request::Sync(VmmAction::InsertBalloonDevice(
+            balloon_cfg.clone(),
+        )))
+        .with_context(|| format!("Failed to insert balloon device {:?}", balloon_cfg))?;
+        Ok(())
+    }
+
+    pub fn insert_mem_device(&self, mem_cfg: MemDeviceConfigInfo) -> Result<()> {
+        self.handle_request_with_retry(Request::Sync(VmmAction::InsertMemDevice(mem_cfg.clone())))
+            .with_context(|| format!("Failed to insert mem device {:?}", mem_cfg))?;
+        Ok(())
+    }
+
+    pub fn resize_vcpu(&self, cfg: VcpuResizeInfo) -> Result<()> {
+        self.handle_request_with_retry(Request::Sync(VmmAction::ResizeVcpu(cfg.clone())))
+            .with_context(|| format!("Failed to resize vcpu {:?}", cfg))?;
+        Ok(())
+    }
+
     pub fn pause(&self) -> Result<()> {
         todo!()
     }
@@ -318,7 +347,9 @@ impl VmmInstance {
 
     fn handle_request_with_retry(&self, req: Request) -> Result<VmmData> {
         let Request::Sync(vmm_action) = req;
-        for count in 0..REQUEST_RETRY {
+        let mut delays = self.retry_policy.delays();
+        let mut count = 0;
+        loop {
             match self.send_request(vmm_action.clone()) {
                 Ok(vmm_outcome) => match *vmm_outcome {
                     Ok(vmm_data) => {
@@ -330,7 +361,11 @@ impl VmmInstance {
                     }
                     Err(vmm_action_error) => {
                         if let VmmActionError::UpcallServerNotReady = vmm_action_error {
-                            std::thread::sleep(std::time::Duration::from_millis(10));
+                            match delays.next() {
+                                Some(delay) => std::thread::sleep(delay),
+                                None => break,
+                            }
+                            count += 1;
                             continue;
                         } else {
                             return Err(vmm_action_error.into());
@@ -344,7 +379,7 @@ impl VmmInstance {
         }
         Err(anyhow::anyhow!(
             "After {} attempts, it still doesn't work.",
-            REQUEST_RETRY
+            count + 1
         ))
     }
 }
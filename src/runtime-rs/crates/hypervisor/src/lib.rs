@@ -15,15 +15,19 @@ pub use device::driver::*;
 use device::DeviceType;
 pub mod dragonball;
 mod kernel_param;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 pub mod qemu;
 pub use kernel_param::Param;
+mod protection;
+pub use protection::GuestProtection;
 mod utils;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 #[cfg(feature = "cloud-hypervisor")]
 pub mod ch;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use hypervisor_persist::HypervisorState;
 use kata_types::capabilities::Capabilities;
@@ -82,10 +86,29 @@ pub trait Hypervisor: Send + Sync {
     // device manager
     async fn add_device(&self, device: DeviceType) -> Result<()>;
     async fn remove_device(&self, device: DeviceType) -> Result<()>;
+    /// resize_block_device tells the guest the new size in bytes of the block
+    /// device, once its backing file or device is grown on the host.
+    async fn resize_block_device(&self, device: DeviceType, _new_size: u64) -> Result<()> {
+        Err(anyhow!("resizing block device {} is unsupported", device))
+    }
+    /// attached_devices returns the ids of the block devices attached to the
+    /// VM as the hypervisor tracks them, across a restore of the shim too,
+    /// none if it doesn't track them.
+    async fn attached_devices(&self) -> Result<Option<HashSet<String>>> {
+        Ok(None)
+    }
+
+    // memory manager
+    async fn set_balloon_size(&self, size_mb: u64) -> Result<()>;
+    async fn resize_memory(&self, new_mem_mb: u32) -> Result<u32>;
+
+    // cpu manager
+    async fn resize_vcpu(&self, new_vcpus: u32) -> Result<u32>;
 
     // utils
     async fn get_agent_socket(&self) -> Result<String>;
     async fn disconnect(&self);
+    async fn set_hypervisor_config(&self, config: HypervisorConfig);
     async fn hypervisor_config(&self) -> HypervisorConfig;
     async fn get_thread_ids(&self) -> Result<VcpuThreadIds>;
     async fn get_pids(&self) -> Result<Vec<u32>>;
@@ -96,4 +119,37 @@ pub trait Hypervisor: Send + Sync {
     async fn get_jailer_root(&self) -> Result<String>;
     async fn save_state(&self) -> Result<HypervisorState>;
     async fn capabilities(&self) -> Result<Capabilities>;
+
+    /// set_guest_vsock sets the vsock cid of the guest allocated by the
+    /// caller before the VM is prepared, with the vhost-vsock fd holding it
+    /// unless the vsock is a unix socket of the hypervisor. The hypervisors
+    /// without it allocate their own.
+    async fn set_guest_vsock(
+        &self,
+        _guest_cid: u32,
+        _vhost_fd: Option<std::fs::File>,
+    ) -> Result<()> {
+        Err(anyhow!("setting the guest vsock is unsupported"))
+    }
+
+    /// is_pooled tells if the VM is running already, started ahead from a
+    /// pool of VMs rather than for the sandbox: the devices are hotplugged
+    /// into it rather than configured before it boots.
+    async fn is_pooled(&self) -> bool {
+        false
+    }
+
+    /// guest_protection returns the hardware protection of the guest, only
+    /// the confidential guests are protected.
+    async fn guest_protection(&self) -> Result<GuestProtection> {
+        if !self
+            .hypervisor_config()
+            .await
+            .security_info
+            .confidential_guest
+        {
+            return Ok(GuestProtection::NoProtection);
+        }
+        Ok(protection::available_guest_protection())
+    }
 }
@@ -0,0 +1,208 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    collections::HashSet,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use kata_types::capabilities::{Capabilities, CapabilityBits};
+use kata_types::config::hypervisor::Hypervisor as HypervisorConfig;
+
+use crate::{hypervisor_persist::HypervisorState, DeviceType, Hypervisor, VcpuThreadIds};
+
+type FailAdd = Box<dyn Fn(&DeviceType) -> bool + Send + Sync>;
+
+/// MockHypervisor is the hypervisor of the tests: it records when the
+/// devices are added, each hotplug taking the delay set up as with a real
+/// hypervisor, and fails to add the ones set up to fail. It records the
+/// sizes the guest is resized to as well, e.g. "resize_vcpu 4", failing the
+/// memory resize if set up to. It tracks the block devices attached. Its VM
+/// is either to cold boot, or pooled and running already.
+#[derive(Default)]
+pub struct MockHypervisor {
+    pooled: bool,
+    capabilities: Capabilities,
+    config: HypervisorConfig,
+    hotplug_delay: Duration,
+    fail_add: Option<FailAdd>,
+    hotplugs: Mutex<Vec<(Instant, Instant)>>,
+    failures: Mutex<u32>,
+    resizes: Mutex<Vec<String>>,
+    fail_resize_memory: bool,
+    block_devices: Mutex<HashSet<String>>,
+}
+
+impl MockHypervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// pooled makes the VM the one adopted from a pool, running already.
+    pub fn pooled(mut self) -> Self {
+        self.pooled = true;
+        self
+    }
+
+    pub fn with_capabilities(mut self, flags: CapabilityBits) -> Self {
+        self.capabilities.set(flags);
+        self
+    }
+
+    pub fn with_config(mut self, config: HypervisorConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_hotplug_delay(mut self, delay: Duration) -> Self {
+        self.hotplug_delay = delay;
+        self
+    }
+
+    /// fail_add makes adding the devices the filter matches fail.
+    pub fn fail_add(
+        mut self,
+        filter: impl Fn(&DeviceType) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.fail_add = Some(Box::new(filter));
+        self
+    }
+
+    /// fail_resize_memory makes resizing the guest memory fail.
+    pub fn fail_resize_memory(mut self) -> Self {
+        self.fail_resize_memory = true;
+        self
+    }
+
+    fn record_resize(&self, resize: String) {
+        self.resizes.lock().unwrap().push(resize);
+    }
+
+    /// hotplugs returns when each device added started and ended.
+    pub fn hotplugs(&self) -> Vec<(Instant, Instant)> {
+        self.hotplugs.lock().unwrap().clone()
+    }
+
+    /// failures counts the devices failed to be added.
+    pub fn failures(&self) -> u32 {
+        *self.failures.lock().unwrap()
+    }
+
+    /// with_block_devices makes the block devices attached already, e.g.
+    /// before a restore.
+    pub fn with_block_devices(self, ids: &[&str]) -> Self {
+        self.block_devices
+            .lock()
+            .unwrap()
+            .extend(ids.iter().map(|id| id.to_string()));
+        self
+    }
+
+    /// resizes returns the resizes of the guest requested so far.
+    pub fn resizes(&self) -> Vec<String> {
+        self.resizes.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Hypervisor for MockHypervisor {
+    async fn prepare_vm(&self, _id: &str, _netns: Option<String>) -> Result<()> {
+        unimplemented!()
+    }
+    async fn start_vm(&self, _timeout: i32) -> Result<()> {
+        unimplemented!()
+    }
+    async fn stop_vm(&self) -> Result<()> {
+        unimplemented!()
+    }
+    async fn pause_vm(&self) -> Result<()> {
+        unimplemented!()
+    }
+    async fn save_vm(&self) -> Result<()> {
+        unimplemented!()
+    }
+    async fn resume_vm(&self) -> Result<()> {
+        unimplemented!()
+    }
+    async fn add_device(&self, device: DeviceType) -> Result<()> {
+        let start = Instant::now();
+        tokio::time::sleep(self.hotplug_delay).await;
+        if matches!(&self.fail_add, Some(filter) if filter(&device)) {
+            *self.failures.lock().unwrap() += 1;
+            return Err(anyhow!("injected failure"));
+        }
+        self.hotplugs.lock().unwrap().push((start, Instant::now()));
+        if let DeviceType::Block(block) = device {
+            self.block_devices.lock().unwrap().insert(block.device_id);
+        }
+        Ok(())
+    }
+    async fn remove_device(&self, device: DeviceType) -> Result<()> {
+        if let DeviceType::Block(block) = device {
+            self.block_devices.lock().unwrap().remove(&block.device_id);
+        }
+        Ok(())
+    }
+    async fn set_balloon_size(&self, size_mb: u64) -> Result<()> {
+        self.record_resize(format!("set_balloon_size {}", size_mb));
+        Ok(())
+    }
+    async fn resize_memory(&self, new_mem_mb: u32) -> Result<u32> {
+        self.record_resize(format!("resize_memory {}", new_mem_mb));
+        if self.fail_resize_memory {
+            return Err(anyhow!("injected failure"));
+        }
+        Ok(new_mem_mb)
+    }
+    async fn resize_vcpu(&self, new_vcpus: u32) -> Result<u32> {
+        self.record_resize(format!("resize_vcpu {}", new_vcpus));
+        Ok(new_vcpus)
+    }
+    async fn get_agent_socket(&self) -> Result<String> {
+        unimplemented!()
+    }
+    async fn disconnect(&self) {}
+    async fn set_hypervisor_config(&self, _config: HypervisorConfig) {}
+    async fn hypervisor_config(&self) -> HypervisorConfig {
+        self.config.clone()
+    }
+    async fn get_thread_ids(&self) -> Result<VcpuThreadIds> {
+        unimplemented!()
+    }
+    async fn get_pids(&self) -> Result<Vec<u32>> {
+        unimplemented!()
+    }
+    async fn get_vmm_master_tid(&self) -> Result<u32> {
+        unimplemented!()
+    }
+    async fn get_ns_path(&self) -> Result<String> {
+        unimplemented!()
+    }
+    async fn cleanup(&self) -> Result<()> {
+        unimplemented!()
+    }
+    async fn check(&self) -> Result<()> {
+        unimplemented!()
+    }
+    async fn get_jailer_root(&self) -> Result<String> {
+        unimplemented!()
+    }
+    async fn save_state(&self) -> Result<HypervisorState> {
+        unimplemented!()
+    }
+    async fn capabilities(&self) -> Result<Capabilities> {
+        Ok(self.capabilities.clone())
+    }
+    async fn attached_devices(&self) -> Result<Option<HashSet<String>>> {
+        Ok(Some(self.block_devices.lock().unwrap().clone()))
+    }
+    async fn is_pooled(&self) -> bool {
+        self.pooled
+    }
+}
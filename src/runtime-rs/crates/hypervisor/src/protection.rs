@@ -0,0 +1,69 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{fmt, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+const TDX_SYS_FIRMWARE_DIR: &str = "/sys/firmware/tdx";
+const SEV_KVM_PARAMETER_PATH: &str = "/sys/module/kvm_amd/parameters/sev";
+const SNP_KVM_PARAMETER_PATH: &str = "/sys/module/kvm_amd/parameters/sev_snp";
+const SE_SYS_FIRMWARE_PATH: &str = "/sys/firmware/uv/prot_virt_host";
+
+/// GuestProtection is the hardware protection of the guest memory and state
+/// from the host, the host isn't trusted by the protected guests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GuestProtection {
+    #[default]
+    #[serde(rename = "none")]
+    NoProtection,
+    Tdx,
+    Sev,
+    Snp,
+    Se,
+}
+
+impl GuestProtection {
+    pub fn is_protected(&self) -> bool {
+        *self != GuestProtection::NoProtection
+    }
+}
+
+impl fmt::Display for GuestProtection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            GuestProtection::NoProtection => "none",
+            GuestProtection::Tdx => "tdx",
+            GuestProtection::Sev => "sev",
+            GuestProtection::Snp => "snp",
+            GuestProtection::Se => "se",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+fn is_parameter_enabled(path: &str) -> bool {
+    fs::read_to_string(path)
+        .map(|v| matches!(v.trim(), "1" | "Y" | "y"))
+        .unwrap_or(false)
+}
+
+/// available_guest_protection returns the strongest guest protection the
+/// host supports.
+pub fn available_guest_protection() -> GuestProtection {
+    if Path::new(TDX_SYS_FIRMWARE_DIR).exists() {
+        GuestProtection::Tdx
+    } else if is_parameter_enabled(SNP_KVM_PARAMETER_PATH) {
+        GuestProtection::Snp
+    } else if is_parameter_enabled(SEV_KVM_PARAMETER_PATH) {
+        GuestProtection::Sev
+    } else if is_parameter_enabled(SE_SYS_FIRMWARE_PATH) {
+        GuestProtection::Se
+    } else {
+        GuestProtection::NoProtection
+    }
+}
@@ -0,0 +1,379 @@
+// Copyright (c) 2022 Red Hat
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::os::unix::io::RawFd;
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Map, Value};
+
+use crate::{BlockConfig, NetworkDevice, KATA_BLK_DEV_TYPE};
+
+const NETDEV_ID_PREFIX: &str = "netdev-";
+const SCSI_CONTROLLER_ID_PREFIX: &str = "scsi";
+const VIRTIO_BLK_PCI_DRIVER: &str = "virtio-blk-pci";
+const VIRTIO_NET_PCI_DRIVER: &str = "virtio-net-pci";
+const VIRTIO_SCSI_PCI_DRIVER: &str = "virtio-scsi-pci";
+const VHOST_VSOCK_PCI_DRIVER: &str = "vhost-vsock-pci";
+const SCSI_HD_DRIVER: &str = "scsi-hd";
+
+/// QmpCommand is a QMP command with its arguments, QEMU adds the backend of
+/// a device, e.g. the drive or the netdev, and the device itself with
+/// separate commands.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct QmpCommand {
+    pub(crate) execute: &'static str,
+    pub(crate) arguments: Value,
+    /// fd sent along with the command, e.g. by getfd
+    pub(crate) fd: Option<RawFd>,
+}
+
+impl QmpCommand {
+    fn new(execute: &'static str, arguments: Value) -> Self {
+        Self {
+            execute,
+            arguments,
+            fd: None,
+        }
+    }
+
+    fn with_fd(execute: &'static str, arguments: Value, fd: RawFd) -> Self {
+        Self {
+            execute,
+            arguments,
+            fd: Some(fd),
+        }
+    }
+}
+
+/// block_device_add_commands returns the commands adding the drive of the
+/// block device and then the device.
+pub(crate) fn block_device_add_commands(
+    device_id: &str,
+    config: &BlockConfig,
+) -> Result<Vec<QmpCommand>> {
+    if config.drive_id.is_empty() {
+        return Err(anyhow!("no drive id of block device {}", device_id));
+    }
+
+    let mut file = Map::new();
+    file.insert("driver".to_string(), json!("host_device"));
+    file.insert("filename".to_string(), json!(config.path_on_host));
+    if !config.aio.is_empty() {
+        file.insert("aio".to_string(), json!(config.aio));
+    }
+    let blockdev = json!({
+        "node-name": config.drive_id,
+        "driver": "raw",
+        "read-only": config.is_readonly,
+        "cache": {
+            "direct": config.cache_direct.unwrap_or_default(),
+            "no-flush": config.cache_noflush.unwrap_or_default(),
+        },
+        "file": file,
+    });
+
+    // the queues are the ones of the controller of a scsi disk
+    let mut device = if let Some(addr) = config.scsi_addr {
+        json!({
+            "driver": SCSI_HD_DRIVER,
+            "id": device_id,
+            "drive": config.drive_id,
+            "bus": format!("{}{}.0", SCSI_CONTROLLER_ID_PREFIX, addr.controller),
+            "scsi-id": addr.controller,
+            "lun": addr.lun,
+        })
+    } else {
+        // the virtio-mmio devices are only on the command line, there's no
+        // bus to hotplug them into
+        if config.driver_option != KATA_BLK_DEV_TYPE {
+            return Err(anyhow!(
+                "block device {} of driver {} can't be hotplugged",
+                device_id,
+                config.driver_option
+            ));
+        }
+        json!({
+            "driver": VIRTIO_BLK_PCI_DRIVER,
+            "id": device_id,
+            "drive": config.drive_id,
+            "num-queues": config.num_queues.max(1),
+        })
+    };
+    if config.queue_size != 0 && config.scsi_addr.is_none() {
+        device["queue-size"] = json!(config.queue_size);
+    }
+    if !config.bus.is_empty() {
+        device["bus"] = json!(config.bus);
+    }
+    if let Some(slot) = config.guest_pci_slot {
+        device["addr"] = json!(format!("{:#04x}", slot));
+    }
+
+    Ok(vec![
+        QmpCommand::new("blockdev-add", blockdev),
+        QmpCommand::new("device_add", device),
+    ])
+}
+
+/// scsi_controller_device returns the device of the virtio-scsi controller
+/// of the index on the command line, the bus of its disks is named after it.
+pub(crate) fn scsi_controller_device(controller: u32) -> String {
+    format!(
+        "{},id={}{}",
+        VIRTIO_SCSI_PCI_DRIVER, SCSI_CONTROLLER_ID_PREFIX, controller
+    )
+}
+
+/// vhost_vsock_device returns the vhost-vsock device of the guest on the
+/// command line, with the fd inherited holding its cid on the host.
+pub(crate) fn vhost_vsock_device(guest_cid: u32, vhost_fd: i32) -> String {
+    format!(
+        "{},id=vsock-{},guest-cid={},vhostfd={}",
+        VHOST_VSOCK_PCI_DRIVER, guest_cid, guest_cid, vhost_fd
+    )
+}
+
+/// block_device_del_commands returns the commands removing the block device
+/// and then its drive.
+pub(crate) fn block_device_del_commands(
+    device_id: &str,
+    config: &BlockConfig,
+) -> Result<Vec<QmpCommand>> {
+    if config.drive_id.is_empty() {
+        return Err(anyhow!("no drive id of block device {}", device_id));
+    }
+
+    Ok(vec![
+        QmpCommand::new("device_del", json!({ "id": device_id })),
+        QmpCommand::new("blockdev-del", json!({ "node-name": config.drive_id })),
+    ])
+}
+
+/// block_device_resize_commands returns the command growing the drive of
+/// the block device to the size in bytes, the guest is notified of the new
+/// capacity.
+pub(crate) fn block_device_resize_commands(
+    device_id: &str,
+    config: &BlockConfig,
+    size: u64,
+) -> Result<Vec<QmpCommand>> {
+    if config.drive_id.is_empty() {
+        return Err(anyhow!("no drive id of block device {}", device_id));
+    }
+
+    Ok(vec![QmpCommand::new(
+        "block_resize",
+        json!({ "node-name": config.drive_id, "size": size }),
+    )])
+}
+
+/// net_device_add_commands returns the commands adding the tap netdev of
+/// the network device and then the device. The queues of the tap opened by
+/// the runtime are passed with getfd first, QEMU opens the tap by its name
+/// without them.
+pub(crate) fn net_device_add_commands(device: &NetworkDevice) -> Vec<QmpCommand> {
+    let netdev_id = format!("{}{}", NETDEV_ID_PREFIX, device.id);
+    let mut commands = vec![];
+
+    let mut netdev = json!({
+        "type": "tap",
+        "id": netdev_id,
+        "vhost": true,
+    });
+    let queues = if device.config.fds.is_empty() {
+        let queues = device.config.queue_num.max(1);
+        netdev["ifname"] = json!(device.config.host_dev_name);
+        netdev["script"] = json!("no");
+        netdev["downscript"] = json!("no");
+        if queues > 1 {
+            netdev["queues"] = json!(queues);
+        }
+        queues
+    } else {
+        // the queues are the ones of the fds, ifname and queues are invalid
+        // along with them
+        let mut fdnames = vec![];
+        for (i, fd) in device.config.fds.iter().enumerate() {
+            let fdname = format!("{}-{}", netdev_id, i);
+            commands.push(QmpCommand::with_fd(
+                "getfd",
+                json!({ "fdname": fdname }),
+                *fd,
+            ));
+            fdnames.push(fdname);
+        }
+        netdev["fds"] = json!(fdnames.join(":"));
+        fdnames.len()
+    };
+    let mut net = json!({
+        "driver": VIRTIO_NET_PCI_DRIVER,
+        "id": device.id,
+        "netdev": netdev_id,
+    });
+    if queues > 1 {
+        net["mq"] = json!(true);
+        // a pair of vectors per queue pair, one for the config and one for control
+        net["vectors"] = json!(2 * queues + 2);
+    }
+    if let Some(mac) = &device.config.guest_mac {
+        net["mac"] = json!(format!("{:?}", mac));
+    }
+
+    commands.push(QmpCommand::new("netdev_add", netdev));
+    commands.push(QmpCommand::new("device_add", net));
+    commands
+}
+
+/// net_device_del_commands returns the commands removing the network device
+/// and then its netdev.
+pub(crate) fn net_device_del_commands(device: &NetworkDevice) -> Vec<QmpCommand> {
+    vec![
+        QmpCommand::new("device_del", json!({ "id": device.id })),
+        QmpCommand::new(
+            "netdev_del",
+            json!({ "id": format!("{}{}", NETDEV_ID_PREFIX, device.id) }),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, NetworkConfig, ScsiAddr, KATA_MMIO_BLK_DEV_TYPE, KATA_SCSI_DEV_TYPE};
+
+    #[test]
+    fn test_vhost_vsock_device() {
+        assert_eq!(
+            vhost_vsock_device(5, 12),
+            "vhost-vsock-pci,id=vsock-5,guest-cid=5,vhostfd=12"
+        );
+    }
+
+    #[test]
+    fn test_block_device_commands() {
+        let mut config = BlockConfig {
+            path_on_host: "/dev/loop0".to_string(),
+            driver_option: KATA_BLK_DEV_TYPE.to_string(),
+            num_queues: 2,
+            aio: "native".to_string(),
+            cache_direct: Some(true),
+            bus: "pcie.0".to_string(),
+            ..Default::default()
+        };
+        assert!(block_device_add_commands("abc", &config).is_err());
+
+        config.drive_id = "drive-abc".to_string();
+        let cmds = block_device_add_commands("abc", &config).unwrap();
+        assert_eq!(cmds[0].execute, "blockdev-add");
+        assert_eq!(cmds[0].arguments["node-name"], "drive-abc");
+        assert_eq!(cmds[0].arguments["file"]["filename"], "/dev/loop0");
+        assert_eq!(cmds[0].arguments["file"]["aio"], "native");
+        assert_eq!(cmds[0].arguments["cache"]["direct"], true);
+        assert_eq!(cmds[0].arguments["cache"]["no-flush"], false);
+        assert_eq!(cmds[1].execute, "device_add");
+        assert_eq!(cmds[1].arguments["driver"], VIRTIO_BLK_PCI_DRIVER);
+        assert_eq!(cmds[1].arguments["drive"], "drive-abc");
+        assert_eq!(cmds[1].arguments["bus"], "pcie.0");
+        assert_eq!(cmds[1].arguments["num-queues"], 2);
+        assert!(cmds[1].arguments.get("addr").is_none());
+
+        config.guest_pci_slot = Some(5);
+        let cmds = block_device_add_commands("abc", &config).unwrap();
+        assert_eq!(cmds[1].arguments["addr"], "0x05");
+
+        let cmds = block_device_del_commands("abc", &config).unwrap();
+        assert_eq!(cmds[0].execute, "device_del");
+        assert_eq!(cmds[1].execute, "blockdev-del");
+        assert_eq!(cmds[1].arguments["node-name"], "drive-abc");
+
+        let cmds = block_device_resize_commands("abc", &config, 10 << 30).unwrap();
+        assert_eq!(cmds[0].execute, "block_resize");
+        assert_eq!(cmds[0].arguments["node-name"], "drive-abc");
+        assert_eq!(cmds[0].arguments["size"], 10u64 << 30);
+
+        // a virtio-mmio disk can't be hotplugged
+        config.driver_option = KATA_MMIO_BLK_DEV_TYPE.to_string();
+        assert!(block_device_add_commands("abc", &config).is_err());
+    }
+
+    #[test]
+    fn test_scsi_device_commands() {
+        assert_eq!(scsi_controller_device(1), "virtio-scsi-pci,id=scsi1");
+
+        let config = BlockConfig {
+            path_on_host: "/dev/loop0".to_string(),
+            driver_option: KATA_SCSI_DEV_TYPE.to_string(),
+            drive_id: "drive-abc".to_string(),
+            num_queues: 2,
+            scsi_addr: Some(ScsiAddr {
+                controller: 1,
+                lun: 7,
+            }),
+            ..Default::default()
+        };
+        let cmds = block_device_add_commands("abc", &config).unwrap();
+        assert_eq!(cmds[1].arguments["driver"], SCSI_HD_DRIVER);
+        assert_eq!(cmds[1].arguments["bus"], "scsi1.0");
+        assert_eq!(cmds[1].arguments["scsi-id"], 1);
+        assert_eq!(cmds[1].arguments["lun"], 7);
+        assert!(cmds[1].arguments.get("num-queues").is_none());
+    }
+
+    #[test]
+    fn test_net_device_commands() {
+        let device = NetworkDevice {
+            id: "eth0".to_string(),
+            config: NetworkConfig {
+                host_dev_name: "tap0_kata".to_string(),
+                guest_mac: Some(Address([0x02, 0, 0, 0, 0, 0x01])),
+                queue_num: 2,
+                fds: vec![],
+            },
+        };
+        let cmds = net_device_add_commands(&device);
+        assert_eq!(cmds[0].execute, "netdev_add");
+        assert_eq!(cmds[0].arguments["id"], "netdev-eth0");
+        assert_eq!(cmds[0].arguments["ifname"], "tap0_kata");
+        assert_eq!(cmds[0].arguments["queues"], 2);
+        assert_eq!(cmds[1].execute, "device_add");
+        assert_eq!(cmds[1].arguments["netdev"], "netdev-eth0");
+        assert_eq!(cmds[1].arguments["mac"], "02:00:00:00:00:01");
+        assert_eq!(cmds[1].arguments["mq"], true);
+
+        let cmds = net_device_del_commands(&device);
+        assert_eq!(cmds[1].execute, "netdev_del");
+        assert_eq!(cmds[1].arguments["id"], "netdev-eth0");
+    }
+
+    #[test]
+    fn test_net_device_commands_with_fds() {
+        let mut device = NetworkDevice {
+            id: "eth0".to_string(),
+            config: NetworkConfig {
+                host_dev_name: "tap0_kata".to_string(),
+                guest_mac: None,
+                queue_num: 2,
+                fds: vec![10, 11],
+            },
+        };
+        let cmds = net_device_add_commands(&device);
+        assert_eq!(cmds.len(), 4);
+        assert_eq!(cmds[0].execute, "getfd");
+        assert_eq!(cmds[0].arguments["fdname"], "netdev-eth0-0");
+        assert_eq!(cmds[0].fd, Some(10));
+        assert_eq!(cmds[1].fd, Some(11));
+        assert_eq!(cmds[2].execute, "netdev_add");
+        assert_eq!(cmds[2].arguments["fds"], "netdev-eth0-0:netdev-eth0-1");
+        assert!(cmds[2].arguments.get("ifname").is_none());
+        assert!(cmds[2].arguments.get("queues").is_none());
+        assert_eq!(cmds[3].arguments["mq"], true);
+
+        // the tap fell back to a single queue
+        device.config.fds = vec![10];
+        let cmds = net_device_add_commands(&device);
+        assert_eq!(cmds[1].arguments["fds"], "netdev-eth0-0");
+        assert!(cmds[2].arguments.get("mq").is_none());
+    }
+}
@@ -3,10 +3,15 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use anyhow::Result;
+use std::os::unix::io::AsRawFd;
 
-use crate::{HypervisorConfig, VcpuThreadIds};
-use kata_types::capabilities::{Capabilities, CapabilityBits};
+use anyhow::{anyhow, Context, Result};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+
+use crate::{HypervisorConfig, VcpuThreadIds, VsockConfig, VIRTIO_SCSI};
+use kata_types::capabilities::{
+    Capabilities, CapabilityBits, ACPI_MEMORY_SLOT_SIZE_MB, VIRTIO_MEM_BLOCK_SIZE_MB,
+};
 
 const VSOCK_SCHEME: &str = "vsock";
 const VSOCK_AGENT_CID: u32 = 3;
@@ -14,12 +19,17 @@ const VSOCK_AGENT_PORT: u32 = 1024;
 
 pub struct QemuInner {
     config: HypervisorConfig,
+
+    /// The vhost-vsock of the guest allocated by the caller, the guest goes
+    /// without a vsock otherwise
+    pub(crate) guest_vsock: Option<VsockConfig>,
 }
 
 impl QemuInner {
     pub fn new() -> QemuInner {
         QemuInner {
             config: Default::default(),
+            guest_vsock: None,
         }
     }
 
@@ -45,6 +55,31 @@ impl QemuInner {
             .arg("-nodefaults")
             .arg("-nographic");
 
+        // the disks of virtio-scsi are attached to the controllers added at
+        // boot, they can't be hotplugged yet
+        if self.config.blockdev_info.block_device_driver == VIRTIO_SCSI {
+            for controller in 0..self
+                .config
+                .blockdev_info
+                .block_device_scsi_controllers
+                .max(1)
+            {
+                command
+                    .arg("-device")
+                    .arg(hotplug::scsi_controller_device(controller));
+            }
+        }
+
+        // the vhost-vsock fd holding the cid of the guest is inherited by
+        // qemu, so that no other VM takes the cid meanwhile
+        if let Some(vsock) = self.guest_vsock.as_ref() {
+            let fd = vsock.vhost_fd.as_raw_fd();
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty())).context("pass vhost-vsock fd")?;
+            command
+                .arg("-device")
+                .arg(hotplug::vhost_vsock_device(vsock.guest_cid, fd));
+        }
+
         command.spawn()?;
 
         Ok(())
@@ -69,14 +104,14 @@ impl QemuInner {
         todo!()
     }
 
-    /// TODO: using a single hardcoded CID is clearly not adequate in the long
-    /// run. Use the recently added VsockConfig infrastructure to fix this.
     pub(crate) async fn get_agent_socket(&self) -> Result<String> {
         info!(sl!(), "QemuInner::get_agent_socket()");
-        Ok(format!(
-            "{}://{}:{}",
-            VSOCK_SCHEME, VSOCK_AGENT_CID, VSOCK_AGENT_PORT
-        ))
+        let cid = self
+            .guest_vsock
+            .as_ref()
+            .map(|vsock| vsock.guest_cid)
+            .unwrap_or(VSOCK_AGENT_CID);
+        Ok(format!("{}://{}:{}", VSOCK_SCHEME, cid, VSOCK_AGENT_PORT))
     }
 
     pub(crate) async fn disconnect(&mut self) {
@@ -86,7 +121,7 @@ impl QemuInner {
 
     pub(crate) async fn get_thread_ids(&self) -> Result<VcpuThreadIds> {
         info!(sl!(), "QemuInner::get_thread_ids()");
-        todo!()
+        Err(anyhow!("get_thread_ids isn't supported by qemu yet"))
     }
 
     pub(crate) async fn get_vmm_master_tid(&self) -> Result<u32> {
@@ -106,7 +141,7 @@ impl QemuInner {
 
     pub(crate) async fn get_pids(&self) -> Result<Vec<u32>> {
         info!(sl!(), "QemuInner::get_pids()");
-        todo!()
+        Err(anyhow!("get_pids isn't supported by qemu yet"))
     }
 
     pub(crate) async fn check(&self) -> Result<()> {
@@ -117,9 +152,32 @@ impl QemuInner {
         todo!()
     }
 
+    pub(crate) async fn set_balloon_size(&self, size_mb: u64) -> Result<()> {
+        info!(sl!(), "QemuInner::set_balloon_size() {}", size_mb);
+        Err(anyhow!("set_balloon_size isn't supported by qemu yet"))
+    }
+
+    pub(crate) async fn resize_memory(&mut self, new_mem_mb: u32) -> Result<u32> {
+        info!(sl!(), "QemuInner::resize_memory() {}", new_mem_mb);
+        Err(anyhow!("resize_memory isn't supported by qemu yet"))
+    }
+
+    pub(crate) async fn resize_vcpu(&mut self, new_vcpus: u32) -> Result<u32> {
+        info!(sl!(), "QemuInner::resize_vcpu() {}", new_vcpus);
+        Err(anyhow!("resize_vcpu isn't supported by qemu yet"))
+    }
+
     pub(crate) async fn capabilities(&self) -> Result<Capabilities> {
         let mut caps = Capabilities::default();
-        caps.set(CapabilityBits::FsSharingSupport);
+        // the drives are added before the devices once the QMP client
+        // runs the commands, SeparateBackendHotplug isn't advertised until
+        // then
+        caps.set(CapabilityBits::FsSharingSupport | CapabilityBits::NetworkDeviceHotplugSupport);
+        caps.set_memory_hotplug_granularity_mb(if self.config.memory_info.enable_virtio_mem {
+            VIRTIO_MEM_BLOCK_SIZE_MB
+        } else {
+            ACPI_MEMORY_SLOT_SIZE_MB
+        });
         Ok(caps)
     }
 
@@ -133,17 +191,58 @@ impl QemuInner {
     }
 }
 
+use super::hotplug::{self, QmpCommand};
 use crate::device::DeviceType;
 
 // device manager part of Hypervisor
 impl QemuInner {
     pub(crate) async fn add_device(&mut self, device: DeviceType) -> Result<()> {
         info!(sl!(), "QemuInner::add_device() {}", device);
-        todo!()
+        let commands = match &device {
+            DeviceType::Block(block) => {
+                hotplug::block_device_add_commands(&block.device_id, &block.config)?
+            }
+            DeviceType::Network(network) => hotplug::net_device_add_commands(network),
+            _ => return Err(anyhow!("unsupported device {:?}", device)),
+        };
+        self.execute_qmp_commands(commands)
     }
 
     pub(crate) async fn remove_device(&mut self, device: DeviceType) -> Result<()> {
         info!(sl!(), "QemuInner::remove_device() {} ", device);
-        todo!()
+        let commands = match &device {
+            DeviceType::Block(block) => {
+                hotplug::block_device_del_commands(&block.device_id, &block.config)?
+            }
+            DeviceType::Network(network) => hotplug::net_device_del_commands(network),
+            _ => return Err(anyhow!("unsupported device {:?}", device)),
+        };
+        self.execute_qmp_commands(commands)
+    }
+
+    pub(crate) async fn resize_block_device(
+        &self,
+        device: DeviceType,
+        new_size: u64,
+    ) -> Result<()> {
+        info!(
+            sl!(),
+            "QemuInner::resize_block_device() {} {}", device, new_size
+        );
+        let commands = match &device {
+            DeviceType::Block(block) => {
+                hotplug::block_device_resize_commands(&block.device_id, &block.config, new_size)?
+            }
+            _ => return Err(anyhow!("unsupported device {:?}", device)),
+        };
+        self.execute_qmp_commands(commands)
+    }
+
+    // TODO: execute the commands once the QMP client is in place
+    fn execute_qmp_commands(&self, commands: Vec<QmpCommand>) -> Result<()> {
+        for cmd in commands.iter() {
+            info!(sl!(), "qmp command {} {}", cmd.execute, cmd.arguments);
+        }
+        Err(anyhow!("qmp client isn't implemented yet"))
     }
 }
@@ -3,16 +3,17 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+mod hotplug;
 mod inner;
 
 use crate::device::DeviceType;
 use crate::hypervisor_persist::HypervisorState;
 use crate::Hypervisor;
-use crate::{HypervisorConfig, VcpuThreadIds};
+use crate::{HypervisorConfig, VcpuThreadIds, VsockConfig};
 use inner::QemuInner;
 use kata_types::capabilities::Capabilities;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 
 use std::sync::Arc;
@@ -34,11 +35,6 @@ impl Qemu {
             inner: Arc::new(RwLock::new(QemuInner::new())),
         }
     }
-
-    pub async fn set_hypervisor_config(&mut self, config: HypervisorConfig) {
-        let mut inner = self.inner.write().await;
-        inner.set_hypervisor_config(config)
-    }
 }
 
 #[async_trait]
@@ -83,6 +79,26 @@ impl Hypervisor for Qemu {
         inner.remove_device(device).await
     }
 
+    async fn resize_block_device(&self, device: DeviceType, new_size: u64) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.resize_block_device(device, new_size).await
+    }
+
+    async fn set_balloon_size(&self, size_mb: u64) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.set_balloon_size(size_mb).await
+    }
+
+    async fn resize_memory(&self, new_mem_mb: u32) -> Result<u32> {
+        let mut inner = self.inner.write().await;
+        inner.resize_memory(new_mem_mb).await
+    }
+
+    async fn resize_vcpu(&self, new_vcpus: u32) -> Result<u32> {
+        let mut inner = self.inner.write().await;
+        inner.resize_vcpu(new_vcpus).await
+    }
+
     async fn get_agent_socket(&self) -> Result<String> {
         let inner = self.inner.read().await;
         inner.get_agent_socket().await
@@ -93,6 +109,11 @@ impl Hypervisor for Qemu {
         inner.disconnect().await
     }
 
+    async fn set_hypervisor_config(&self, config: HypervisorConfig) {
+        let mut inner = self.inner.write().await;
+        inner.set_hypervisor_config(config)
+    }
+
     async fn hypervisor_config(&self) -> HypervisorConfig {
         let inner = self.inner.read().await;
         inner.hypervisor_config()
@@ -141,4 +162,15 @@ impl Hypervisor for Qemu {
         let inner = self.inner.read().await;
         inner.capabilities().await
     }
+
+    async fn set_guest_vsock(&self, guest_cid: u32, vhost_fd: Option<std::fs::File>) -> Result<()> {
+        let vhost_fd =
+            vhost_fd.ok_or_else(|| anyhow!("qemu takes a vhost-vsock, not a hybrid vsock"))?;
+        let mut inner = self.inner.write().await;
+        inner.guest_vsock = Some(VsockConfig {
+            guest_cid,
+            vhost_fd: tokio::fs::File::from_std(vhost_fd),
+        });
+        Ok(())
+    }
 }
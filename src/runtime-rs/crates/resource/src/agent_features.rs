@@ -0,0 +1,261 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{collections::BTreeSet, fmt};
+
+use agent::{
+    Agent, CheckRequest, CopyFileRequest, GetGuestDetailsRequest, LoadKernelModulesRequest,
+    SetupNetworkRequest,
+};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+// the storage handler of the watchable mounts the agent lists in its details
+const WATCHABLE_BIND_HANDLER: &str = "watchable-bind";
+
+/// AgentFeature is a behavior of the agent in the guest the runtime relies
+/// on, which the older agents lack or their policy may block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentFeature {
+    /// the watchable-bind storages of the watchable mounts, e.g. configmaps
+    WatchableMounts,
+    /// copy_file of the files copied into the guest
+    CopyFile,
+    /// setup_network of the interfaces, neighbors and routes in one request
+    SetupNetwork,
+    /// load_kernel_modules of the kernel modules of the devices
+    LoadKernelModules,
+}
+
+impl AgentFeature {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AgentFeature::WatchableMounts => "watchable mounts",
+            AgentFeature::CopyFile => "copy file",
+            AgentFeature::SetupNetwork => "setup network",
+            AgentFeature::LoadKernelModules => "load kernel modules",
+        }
+    }
+}
+
+/// AgentFeatures is what the agent of the sandbox supports, as it answered
+/// the probes. Until the agent is asked, or if it can't tell, it's assumed
+/// to support everything, as the runtime did before. The features are saved
+/// with the sandbox, the agent isn't asked again on restore.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AgentFeatures {
+    // the version the agent reported, None until it's asked
+    #[serde(default)]
+    version: Option<String>,
+    // the features the agent lacks
+    #[serde(default)]
+    missing: BTreeSet<AgentFeature>,
+}
+
+impl AgentFeatures {
+    /// probe asks the agent what it serves. The requests probed are the
+    /// empty ones the agent does nothing for, they fail as unsupported if
+    /// the agent is older or its policy blocks them. The watchable mounts
+    /// are among the storage handlers of the guest details.
+    pub async fn probe(agent: &dyn Agent) -> Self {
+        let version = match agent.version(CheckRequest::default()).await {
+            Ok(resp) => Some(resp.agent_version),
+            Err(e) => {
+                warn!(sl!(), "failed to get agent version: {:?}", e);
+                None
+            }
+        };
+
+        let mut missing = BTreeSet::new();
+        let probes = [
+            (
+                AgentFeature::CopyFile,
+                agent.copy_file(CopyFileRequest::default()).await.err(),
+            ),
+            (
+                AgentFeature::SetupNetwork,
+                agent
+                    .setup_network(SetupNetworkRequest::default())
+                    .await
+                    .err(),
+            ),
+            (
+                AgentFeature::LoadKernelModules,
+                agent
+                    .load_kernel_modules(LoadKernelModulesRequest::default())
+                    .await
+                    .err(),
+            ),
+        ];
+        for (feature, err) in probes {
+            // the other errors are of the empty request, e.g. copy_file of
+            // no path, the agent serves it
+            if matches!(err, Some(e) if agent::is_unsupported_error(&e)) {
+                missing.insert(feature);
+            }
+        }
+
+        match agent
+            .get_guest_details(GetGuestDetailsRequest::default())
+            .await
+        {
+            Ok(details) => {
+                let handlers = details
+                    .agent_details
+                    .map(|d| d.storage_handlers)
+                    .unwrap_or_default();
+                if !handlers.iter().any(|h| h == WATCHABLE_BIND_HANDLER) {
+                    missing.insert(AgentFeature::WatchableMounts);
+                }
+            }
+            Err(e) => warn!(
+                sl!(),
+                "failed to get guest details, assume the agent supports {}: {:?}",
+                AgentFeature::WatchableMounts.name(),
+                e
+            ),
+        }
+
+        let features = Self { version, missing };
+        info!(sl!(), "agent features: {}", features);
+        features
+    }
+
+    pub fn supports(&self, feature: AgentFeature) -> bool {
+        !self.missing.contains(&feature)
+    }
+
+    /// require fails if the agent lacks the feature.
+    pub fn require(&self, feature: AgentFeature) -> Result<()> {
+        if self.supports(feature) {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "agent {} doesn't support {}, it's too old or its policy blocks it",
+            self.version.as_deref().unwrap_or("unknown"),
+            feature.name()
+        ))
+    }
+
+    /// copy_file_max_size returns the size up to which the files are copied
+    /// into the guest, 0 to share all of them if the agent can't copy files.
+    pub fn copy_file_max_size(&self, max_size: u64) -> u64 {
+        if max_size != 0 && !self.supports(AgentFeature::CopyFile) {
+            info!(
+                sl!(),
+                "agent doesn't support {}, share the small files",
+                AgentFeature::CopyFile.name()
+            );
+            return 0;
+        }
+        max_size
+    }
+}
+
+impl fmt::Display for AgentFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "version {}",
+            self.version.as_deref().unwrap_or("unknown")
+        )?;
+        if self.missing.is_empty() {
+            return write!(f, ", all features supported");
+        }
+        let missing: Vec<&str> = self.missing.iter().map(|m| m.name()).collect();
+        write!(f, ", missing {}", missing.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_agent::MockAgent;
+
+    #[tokio::test]
+    async fn test_agent_supports_all() {
+        let agent = MockAgent::new("3.2.0-alpha3");
+        let features = AgentFeatures::probe(&agent).await;
+        assert_eq!(
+            features.to_string(),
+            "version 3.2.0-alpha3, all features supported"
+        );
+        assert_eq!(features.copy_file_max_size(4096), 4096);
+        // the probes are the empty requests
+        assert_eq!(
+            agent.calls(),
+            vec![
+                "copy_file",
+                "setup_network",
+                "load_kernel_modules",
+                "get_guest_details"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_old_agent_falls_back() {
+        let agent = MockAgent::new("3.1.2");
+        agent.unsupported("setup_network");
+        agent.unsupported("load_kernel_modules");
+        let features = AgentFeatures::probe(&agent).await;
+        assert!(features.supports(AgentFeature::CopyFile));
+        assert!(!features.supports(AgentFeature::SetupNetwork));
+        assert_eq!(
+            features.to_string(),
+            "version 3.1.2, missing setup network, load kernel modules"
+        );
+        assert_eq!(
+            features
+                .require(AgentFeature::LoadKernelModules)
+                .unwrap_err()
+                .to_string(),
+            "agent 3.1.2 doesn't support load kernel modules, it's too old or its policy blocks it"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_agent_policy_blocks() {
+        let agent = MockAgent::new("3.2.0");
+        agent.unsupported("copy_file");
+        agent.set_storage_handlers(vec![]);
+        let features = AgentFeatures::probe(&agent).await;
+        // the small files are shared instead
+        assert_eq!(features.copy_file_max_size(4096), 0);
+        assert!(features.require(AgentFeature::WatchableMounts).is_err());
+        assert!(features.supports(AgentFeature::SetupNetwork));
+    }
+
+    #[tokio::test]
+    async fn test_agent_probe_errors() {
+        // the failures other than unsupported don't tell the feature is
+        // missing, e.g. copy_file of no path
+        let agent = MockAgent::new("devel");
+        agent.fail("copy_file", "does not start with");
+        agent.fail("get_guest_details", "timeout");
+        let features = AgentFeatures::probe(&agent).await;
+        assert_eq!(
+            features.to_string(),
+            "version devel, all features supported"
+        );
+        assert_eq!(
+            AgentFeatures::default().to_string(),
+            "version unknown, all features supported"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_agent_features_persist() {
+        let agent = MockAgent::new("3.1.2");
+        agent.unsupported("setup_network");
+        let features = AgentFeatures::probe(&agent).await;
+        let saved = serde_json::to_string(&features).unwrap();
+        let restored: AgentFeatures = serde_json::from_str(&saved).unwrap();
+        assert!(!restored.supports(AgentFeature::SetupNetwork));
+        assert_eq!(restored.to_string(), features.to_string());
+    }
+}
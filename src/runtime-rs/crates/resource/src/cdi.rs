@@ -0,0 +1,196 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! The devices a container asks for by the Container Device Interface, e.g.
+//! by the device plugins, resolved to the device nodes of the container by the
+//! CDI specs on the host.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+/// The annotations of the CDI devices of the container, their values are the
+/// comma separated qualified names of the devices, e.g. vendor.com/class=name.
+pub const CDI_ANNOTATION_PREFIX: &str = "cdi.k8s.io/";
+/// The directories of the CDI specs, the specs of the later ones take
+/// precedence.
+pub const CDI_SPEC_DIRS: &[&str] = &["/etc/cdi", "/var/run/cdi"];
+
+#[derive(Debug, Default, Deserialize)]
+struct Spec {
+    kind: String,
+    #[serde(default)]
+    devices: Vec<SpecDevice>,
+    #[serde(default, rename = "containerEdits")]
+    container_edits: ContainerEdits,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SpecDevice {
+    name: String,
+    #[serde(default, rename = "containerEdits")]
+    container_edits: ContainerEdits,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ContainerEdits {
+    #[serde(default, rename = "deviceNodes")]
+    device_nodes: Vec<DeviceNode>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DeviceNode {
+    path: String,
+}
+
+/// requested_devices returns the qualified names of the CDI devices the
+/// container asks for by its annotations.
+fn requested_devices(annotations: &HashMap<String, String>) -> BTreeSet<&str> {
+    annotations
+        .iter()
+        .filter(|(k, _)| k.starts_with(CDI_ANNOTATION_PREFIX))
+        .flat_map(|(_, v)| v.split(','))
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .collect()
+}
+
+/// device_nodes returns the paths in the container of the device nodes of the
+/// CDI devices the container asks for, a device which isn't in the specs of
+/// spec_dirs is refused.
+pub(crate) fn device_nodes(
+    spec_dirs: &[&str],
+    annotations: &HashMap<String, String>,
+) -> Result<BTreeSet<String>> {
+    let requested = requested_devices(annotations);
+    if requested.is_empty() {
+        return Ok(BTreeSet::new());
+    }
+    let specs = load_specs(spec_dirs)?;
+
+    let mut nodes = BTreeSet::new();
+    for name in requested {
+        let (kind, device) = name
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid CDI device name {}", name))?;
+        // the specs of the later directories are searched first
+        let (spec, device) = specs
+            .iter()
+            .rev()
+            .filter(|s| s.kind == kind)
+            .find_map(|s| s.devices.iter().find(|d| d.name == device).map(|d| (s, d)))
+            .ok_or_else(|| anyhow!("CDI device {} not found", name))?;
+        nodes.extend(
+            spec.container_edits
+                .device_nodes
+                .iter()
+                .chain(device.container_edits.device_nodes.iter())
+                .map(|n| n.path.clone()),
+        );
+    }
+    Ok(nodes)
+}
+
+// load_specs reads the json specs of the directories in order, the yaml ones
+// are skipped as the runtime doesn't parse yaml
+fn load_specs(spec_dirs: &[&str]) -> Result<Vec<Spec>> {
+    let mut specs = vec![];
+    for dir in spec_dirs.iter().map(Path::new).filter(|d| d.is_dir()) {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .with_context(|| format!("read CDI spec dir {}", dir.display()))?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .collect();
+        paths.sort();
+        for path in paths {
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("json") => {
+                    let content = fs::read_to_string(&path)
+                        .with_context(|| format!("read CDI spec {}", path.display()))?;
+                    let spec = serde_json::from_str(&content)
+                        .with_context(|| format!("parse CDI spec {}", path.display()))?;
+                    specs.push(spec);
+                }
+                Some("yaml") | Some("yml") => {
+                    warn!(
+                        sl!(),
+                        "CDI spec {} skipped, only json is supported",
+                        path.display()
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(specs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_nodes() {
+        let etc = tempfile::tempdir().unwrap();
+        let run = tempfile::tempdir().unwrap();
+        fs::write(
+            etc.path().join("vendor.json"),
+            r#"{
+                "cdiVersion": "0.5.0",
+                "kind": "vendor.com/disk",
+                "devices": [
+                    {"name": "a", "containerEdits": {"deviceNodes": [{"path": "/dev/sdc"}]}},
+                    {"name": "b", "containerEdits": {"deviceNodes": [{"path": "/dev/sdd"}]}}
+                ]
+            }"#,
+        )
+        .unwrap();
+        // the spec of the later dir takes precedence
+        fs::write(
+            run.path().join("vendor.json"),
+            r#"{
+                "cdiVersion": "0.5.0",
+                "kind": "vendor.com/disk",
+                "devices": [
+                    {"name": "b", "containerEdits": {"deviceNodes": [{"path": "/dev/sde"}]}}
+                ],
+                "containerEdits": {"deviceNodes": [{"path": "/dev/vendor-ctl"}]}
+            }"#,
+        )
+        .unwrap();
+        fs::write(run.path().join("other.yaml"), "kind: other.com/disk").unwrap();
+        let dirs = [etc.path().to_str().unwrap(), run.path().to_str().unwrap()];
+
+        let annotations = HashMap::from([
+            (
+                format!("{}disk-plugin", CDI_ANNOTATION_PREFIX),
+                "vendor.com/disk=a, vendor.com/disk=b".to_string(),
+            ),
+            (
+                "io.kubernetes.cri.container-type".to_string(),
+                "container".to_string(),
+            ),
+        ]);
+        let nodes: Vec<String> = device_nodes(&dirs, &annotations)
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(nodes, vec!["/dev/sdc", "/dev/sde", "/dev/vendor-ctl"]);
+
+        assert!(device_nodes(&dirs, &HashMap::new()).unwrap().is_empty());
+        for name in ["other.com/disk=a", "vendor.com/disk=c", "vendor.com/disk"] {
+            let annotations = HashMap::from([(
+                format!("{}disk-plugin", CDI_ANNOTATION_PREFIX),
+                name.to_string(),
+            )]);
+            assert!(device_nodes(&dirs, &annotations).is_err(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::{anyhow, Context, Result};
+use cgroups_rs::{BlkIoDeviceResource, BlkIoResources, Resources};
+use oci::LinuxResources;
+
+pub(crate) const SYS_DEV_BLOCK: &str = "/sys/dev/block";
+
+/// calc_blkio_resources takes the weights the container gives to the
+/// devices, by their major/minor in the spec.
+pub(crate) fn calc_blkio_resources(linux_resources: Option<&LinuxResources>) -> BlkIoResources {
+    let block_io = match linux_resources.and_then(|r| r.block_io.as_ref()) {
+        Some(block_io) => block_io,
+        None => return BlkIoResources::default(),
+    };
+
+    BlkIoResources {
+        weight_device: block_io
+            .weight_device
+            .iter()
+            .filter(|d| d.weight.is_some())
+            .map(|d| BlkIoDeviceResource {
+                major: d.blk.major as u64,
+                minor: d.blk.minor as u64,
+                weight: d.weight,
+                leaf_weight: None,
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// merge_device_weights returns the weights of the devices attached, the
+/// highest one if several containers set it. The others are left until
+/// they're attached.
+pub(crate) fn merge_device_weights<'a>(
+    resources: impl Iterator<Item = &'a Resources>,
+    attached: &HashSet<(i64, i64)>,
+) -> BTreeMap<(i64, i64), u16> {
+    let mut weights = BTreeMap::new();
+    for d in resources.flat_map(|r| r.blkio.weight_device.iter()) {
+        let device = (d.major as i64, d.minor as i64);
+        if let (Some(weight), true) = (d.weight, attached.contains(&device)) {
+            let w = weights.entry(device).or_insert(weight);
+            *w = (*w).max(weight);
+        }
+    }
+    weights
+}
+
+/// backing_disk returns the major/minor of the disk on the host the device
+/// is on, the weights only apply to the whole disks.
+pub(crate) fn backing_disk(sys_dev_block: &Path, major: i64, minor: i64) -> Result<(u64, u64)> {
+    let dev = sys_dev_block.join(format!("{}:{}", major, minor));
+    if !dev.exists() {
+        return Err(anyhow!("no block device {}:{} on the host", major, minor));
+    }
+    if !dev.join("partition").exists() {
+        return Ok((major as u64, minor as u64));
+    }
+
+    // the partition is a child of its disk in sysfs
+    let partition = fs::canonicalize(&dev).with_context(|| format!("resolve {:?}", dev))?;
+    let disk = partition
+        .parent()
+        .map(|p| p.join("dev"))
+        .ok_or_else(|| anyhow!("no disk of partition {:?}", partition))?;
+    let content = fs::read_to_string(&disk).with_context(|| format!("read {:?}", disk))?;
+    parse_dev(content.trim()).with_context(|| format!("parse {:?}", disk))
+}
+
+fn parse_dev(dev: &str) -> Result<(u64, u64)> {
+    let (major, minor) = dev
+        .split_once(':')
+        .ok_or_else(|| anyhow!("invalid device number {}", dev))?;
+    Ok((major.parse()?, minor.parse()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_resources(weights: &[(i64, i64, Option<u16>)]) -> Resources {
+        let linux_resources = LinuxResources {
+            block_io: Some(oci::LinuxBlockIo {
+                weight_device: weights
+                    .iter()
+                    .map(|(major, minor, weight)| oci::LinuxWeightDevice {
+                        blk: oci::LinuxBlockIoDevice {
+                            major: *major,
+                            minor: *minor,
+                        },
+                        weight: *weight,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        Resources {
+            blkio: calc_blkio_resources(Some(&linux_resources)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_merge_device_weights() {
+        let resources = vec![
+            new_resources(&[(8, 0, Some(100)), (8, 16, Some(300)), (8, 32, None)]),
+            new_resources(&[(8, 0, Some(500))]),
+        ];
+        assert_eq!(resources[0].blkio.weight_device.len(), 2);
+
+        // 8:16 isn't attached yet
+        let attached: HashSet<(i64, i64)> = vec![(8, 0), (8, 32)].into_iter().collect();
+        let weights = merge_device_weights(resources.iter(), &attached);
+        assert_eq!(weights.into_iter().collect::<Vec<_>>(), vec![((8, 0), 500)]);
+
+        let attached: HashSet<(i64, i64)> = vec![(8, 0), (8, 16)].into_iter().collect();
+        let weights = merge_device_weights(resources.iter(), &attached);
+        assert_eq!(
+            weights.into_iter().collect::<Vec<_>>(),
+            vec![((8, 0), 500), ((8, 16), 300)]
+        );
+    }
+
+    #[test]
+    fn test_backing_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let sda = dir.path().join("devices").join("sda");
+        fs::create_dir_all(sda.join("sda1")).unwrap();
+        fs::write(sda.join("dev"), "8:0\n").unwrap();
+        fs::write(sda.join("sda1").join("partition"), "1\n").unwrap();
+        let sys_dev_block = dir.path().join("block");
+        fs::create_dir_all(&sys_dev_block).unwrap();
+        std::os::unix::fs::symlink(&sda, sys_dev_block.join("8:0")).unwrap();
+        std::os::unix::fs::symlink(sda.join("sda1"), sys_dev_block.join("8:1")).unwrap();
+
+        assert_eq!(backing_disk(&sys_dev_block, 8, 0).unwrap(), (8, 0));
+        assert_eq!(backing_disk(&sys_dev_block, 8, 1).unwrap(), (8, 0));
+        assert!(backing_disk(&sys_dev_block, 8, 2).is_err());
+    }
+}
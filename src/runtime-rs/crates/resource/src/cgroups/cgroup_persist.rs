@@ -10,4 +10,19 @@ pub struct CgroupState {
     pub path: Option<String>,
     pub overhead_path: Option<String>,
     pub sandbox_cgroup_only: bool,
+    /// the settings applied to the sandbox cgroup, reconciled once restored
+    #[serde(default)]
+    pub settings: Vec<CgroupSetting>,
+    /// the block devices attached, by their major/minor in the spec
+    #[serde(default)]
+    pub attached_devices: Vec<(i64, i64)>,
+}
+
+/// CgroupSetting is the value written into a file of a controller of the
+/// sandbox cgroup.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CgroupSetting {
+    pub controller: String,
+    pub file: String,
+    pub value: String,
 }
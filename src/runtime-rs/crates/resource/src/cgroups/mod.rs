@@ -4,33 +4,46 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+mod blkio;
 pub mod cgroup_persist;
+mod reconcile;
+pub use reconcile::{CgroupDrift, CgroupReconciliation};
+mod threads;
 mod utils;
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     error::Error,
-    io,
+    fs, io,
     iter::FromIterator,
+    path::Path,
+    str::FromStr,
     sync::Arc,
 };
 
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
-use cgroup_persist::CgroupState;
-use cgroups_rs::{cgroup_builder::CgroupBuilder, Cgroup, CgroupPid, CpuResources, Resources};
+use cgroup_persist::{CgroupSetting, CgroupState};
+use cgroups_rs::{
+    blkio::BlkIoController, cgroup_builder::CgroupBuilder, cpuset::CpuSetController, Cgroup,
+    CgroupPid, Controller, CpuResources, Resources,
+};
 use hypervisor::Hypervisor;
 use kata_sys_util::spec::load_oci_spec;
-use kata_types::config::TomlConfig;
+use kata_types::{config::TomlConfig, cpu::CpuSet};
 use oci::LinuxResources;
 use persist::sandbox_persist::Persist;
 use tokio::sync::RwLock;
 
 const OS_ERROR_NO_SUCH_PROCESS: i32 = 3;
+// the period of the cpu quota if the container doesn't set it, in us
+const DEFAULT_CPU_PERIOD: u64 = 100_000;
+// the quota of cpu.cfs_quota_us and cpu.max setting no limit
+const UNLIMITED_CPU_QUOTA: i64 = -1;
 
 pub struct CgroupArgs {
     pub sid: String,
-    pub config: TomlConfig,
+    pub config: Arc<TomlConfig>,
 }
 
 pub struct CgroupConfig {
@@ -55,13 +68,29 @@ impl CgroupConfig {
             sandbox_cgroup_only: toml_config.runtime.sandbox_cgroup_only,
         })
     }
+
+    // restore takes the path of the sandbox cgroup saved, the spec of the
+    // bundle isn't read again
+    fn restore(sid: &str, toml_config: &TomlConfig, path: String) -> Self {
+        Self {
+            path,
+            overhead_path: utils::gen_overhead_path(sid),
+            sandbox_cgroup_only: toml_config.runtime.sandbox_cgroup_only,
+        }
+    }
 }
 
 pub struct CgroupsResource {
     resources: Arc<RwLock<HashMap<String, Resources>>>,
+    // the block devices attached, by their major/minor in the spec
+    attached_devices: Arc<RwLock<HashSet<(i64, i64)>>>,
+    // the settings last applied to the sandbox cgroup
+    settings: Arc<RwLock<Vec<CgroupSetting>>>,
     cgroup_manager: Cgroup,
     overhead_cgroup_manager: Option<Cgroup>,
     cgroup_config: CgroupConfig,
+    // the id of the sandbox, which is the one of the sandbox container
+    sid: String,
 }
 
 impl CgroupsResource {
@@ -104,29 +133,37 @@ impl CgroupsResource {
         Ok(Self {
             cgroup_manager,
             resources: Arc::new(RwLock::new(HashMap::new())),
+            attached_devices: Arc::new(RwLock::new(HashSet::new())),
+            settings: Arc::new(RwLock::new(vec![])),
             overhead_cgroup_manager,
             cgroup_config: config,
+            sid: sid.to_string(),
         })
     }
 
     /// delete will move the running processes in the cgroup_manager and
     /// overhead_cgroup_manager to the parent and then delete the cgroups.
+    /// The cgroups deleted already are skipped, so that it could be retried.
     pub async fn delete(&self) -> Result<()> {
+        // both are tried, the overhead cgroup isn't left for the sandbox one
+        let mut result = Ok(());
+        if self.cgroup_manager.exists() {
+            result = self.delete_sandbox_cgroup();
+        }
+        if let Some(overhead) = self.overhead_cgroup_manager.as_ref().filter(|o| o.exists()) {
+            result = result.and(delete_overhead_cgroup(overhead).context("delete overhead"));
+        }
+        result
+    }
+
+    fn delete_sandbox_cgroup(&self) -> Result<()> {
         for cg_pid in self.cgroup_manager.tasks() {
             // For now, we can't guarantee that the thread in cgroup_manager does still
             // exist. Once it exit, we should ignore that error returned by remove_task
             // to let it go.
             if let Err(error) = self.cgroup_manager.remove_task(cg_pid) {
-                match error.source() {
-                    Some(err) => match err.downcast_ref::<io::Error>() {
-                        Some(e) => {
-                            if e.raw_os_error() != Some(OS_ERROR_NO_SUCH_PROCESS) {
-                                return Err(error.into());
-                            }
-                        }
-                        None => return Err(error.into()),
-                    },
-                    None => return Err(error.into()),
+                if !is_no_such_process(&error) {
+                    return Err(error.into());
                 }
             }
         }
@@ -134,15 +171,62 @@ impl CgroupsResource {
         self.cgroup_manager
             .delete()
             .context("delete cgroup manager")?;
+        Ok(())
+    }
 
+    /// missing_cgroups returns the paths of the sandbox cgroups which don't
+    /// exist on the host.
+    pub fn missing_cgroups(&self) -> Vec<String> {
+        let mut missing = vec![];
+        if !self.cgroup_manager.exists() {
+            missing.push(self.cgroup_config.path.clone());
+        }
         if let Some(overhead) = self.overhead_cgroup_manager.as_ref() {
-            for cg_pid in overhead.tasks() {
-                overhead.remove_task(cg_pid)?;
+            if !overhead.exists() {
+                missing.push(self.cgroup_config.overhead_path.clone());
             }
-            overhead.delete().context("delete overhead")?;
         }
+        missing
+    }
 
-        Ok(())
+    /// reconcile reads back the settings applied to the sandbox cgroup and
+    /// writes again the ones which drifted, e.g. after systemd reloaded or
+    /// someone changed the hierarchy, the sandbox would run unconstrained
+    /// otherwise. The directories of the sandbox cgroup gone are recreated.
+    pub async fn reconcile(&self) -> Result<CgroupReconciliation> {
+        let settings = self.settings.read().await;
+        let reconciliation = reconcile::reconcile_settings(
+            Path::new(reconcile::CGROUP_ROOT),
+            cgroups_rs::hierarchies::is_cgroup2_unified_mode(),
+            &self.cgroup_config.path,
+            &settings,
+        )?;
+        if !reconciliation.is_empty() {
+            info!(
+                sl!(),
+                "reconciled sandbox cgroup {}: {:?}", self.cgroup_config.path, reconciliation
+            );
+        }
+        Ok(reconciliation)
+    }
+
+    /// sandbox_cpuset returns the host CPUs the sandbox cgroup lets its
+    /// threads run on, None without the cpuset controller.
+    pub fn sandbox_cpuset(&self) -> Result<Option<CpuSet>> {
+        let controller: &CpuSetController = match self.cgroup_manager.controller_of() {
+            Some(controller) => controller,
+            None => return Ok(None),
+        };
+        let file = if cgroups_rs::hierarchies::is_cgroup2_unified_mode() {
+            "cpuset.cpus.effective"
+        } else {
+            "cpuset.effective_cpus"
+        };
+        let path = controller.path().join(file);
+        let content = fs::read_to_string(&path).with_context(|| format!("read {:?}", path))?;
+        let cpuset =
+            CpuSet::from_str(content.trim()).with_context(|| format!("parse {:?}", path))?;
+        Ok(Some(cpuset))
     }
 
     pub async fn update_cgroups(
@@ -161,6 +245,16 @@ impl CgroupsResource {
         self.do_update_cgroups(h).await
     }
 
+    /// delete_container removes the resources of the container from the
+    /// sandbox cgroups.
+    pub async fn delete_container(&self, cid: &str, h: &dyn Hypervisor) -> Result<()> {
+        if self.resources.write().await.remove(cid).is_none() {
+            return Ok(());
+        }
+
+        self.do_update_cgroups(h).await
+    }
+
     async fn update_resources(&self, cid: &str, new_resource: Resources) -> bool {
         let mut resources = self.resources.write().await;
         let old_resource = resources.insert(cid.to_owned(), new_resource.clone());
@@ -179,76 +273,243 @@ impl CgroupsResource {
         self.cgroup_manager
             .apply(&merged_resources)
             .map_err(|e| anyhow!(e))?;
+        *self.settings.write().await = reconcile::cpu_settings(
+            &merged_resources.cpu,
+            cgroups_rs::hierarchies::is_cgroup2_unified_mode(),
+        );
+        self.apply_device_weights()
+            .await
+            .context("apply device weights")?;
 
         if self.overhead_cgroup_manager.is_some() {
             // If we have an overhead controller, new vCPU threads would start there,
             // as being children of the VMM PID.
             // We need to constrain them by moving them into the sandbox controller.
-            self.constrain_hypervisor(h).await?
+            self.resync_threads(h).await?
         }
 
         Ok(())
     }
 
-    /// constrain_hypervisor will place the VMM and vCPU threads into resource controllers (cgroups on Linux).
-    async fn constrain_hypervisor(&self, h: &dyn Hypervisor) -> Result<()> {
-        let tids = h.get_thread_ids().await?;
-        let tids = tids.vcpus.values();
+    /// device_attached sets the weights the containers give to the block
+    /// device once it's attached, the kernel rejects the weight of a device
+    /// it doesn't know of, e.g. the one hotplugged later.
+    pub async fn device_attached(&self, major: i64, minor: i64) -> Result<()> {
+        self.attached_devices.write().await.insert((major, minor));
+        self.apply_device_weights().await
+    }
+
+    /// device_detached forgets the block device detached, its weights aren't
+    /// set anymore.
+    pub async fn device_detached(&self, major: i64, minor: i64) {
+        self.attached_devices.write().await.remove(&(major, minor));
+    }
+
+    // apply_device_weights writes the weights of the devices attached for
+    // the disks backing them on the host, the VMM does the I/O of the
+    // sandbox there. The weights written are kept with the settings to be
+    // reconciled.
+    async fn apply_device_weights(&self) -> Result<()> {
+        let weights = {
+            let resources = self.resources.read().await;
+            let attached = self.attached_devices.read().await;
+            blkio::merge_device_weights(resources.values(), &attached)
+        };
+        let mut applied = BTreeMap::new();
+        let result = self.write_device_weights(weights, &mut applied);
+
+        let cgroup_v2 = cgroups_rs::hierarchies::is_cgroup2_unified_mode();
+        let mut settings = self.settings.write().await;
+        settings.retain(|s| !reconcile::is_device_weight(s));
+        settings.extend(applied.into_iter().map(|((major, minor), weight)| {
+            reconcile::device_weight_setting(major, minor, weight, cgroup_v2)
+        }));
+        result
+    }
 
-        // All vCPU threads move to the sandbox controller.
-        for tid in tids {
-            self.cgroup_manager
-                .add_task(CgroupPid { pid: *tid as u64 })?
+    // write_device_weights writes the weights for the disks, the ones
+    // written are put in applied, the last one of a disk wins
+    fn write_device_weights(
+        &self,
+        weights: BTreeMap<(i64, i64), u16>,
+        applied: &mut BTreeMap<(u64, u64), u16>,
+    ) -> Result<()> {
+        if weights.is_empty() {
+            return Ok(());
         }
 
+        let controller: &BlkIoController = self
+            .cgroup_manager
+            .controller_of()
+            .context("no blkio controller of the sandbox cgroup")?;
+        for ((major, minor), weight) in weights {
+            let (disk_major, disk_minor) =
+                blkio::backing_disk(Path::new(blkio::SYS_DEV_BLOCK), major, minor)?;
+            info!(
+                sl!(),
+                "set weight {} of device {}:{} on disk {}:{}",
+                weight,
+                major,
+                minor,
+                disk_major,
+                disk_minor
+            );
+            controller
+                .set_weight_for_device(disk_major, disk_minor, weight as u64)
+                .with_context(|| {
+                    format!(
+                        "set weight {} of disk {}:{}",
+                        weight, disk_major, disk_minor
+                    )
+                })?;
+            applied.insert((disk_major, disk_minor), weight);
+        }
         Ok(())
     }
 
-    async fn merge_resources(&self) -> Resources {
-        let resources = self.resources.read().await;
-
-        let mut cpu_list: HashSet<String> = HashSet::new();
-        let mut mem_list: HashSet<String> = HashSet::new();
+    /// resync_threads moves the threads the hypervisor created since they
+    /// were placed into their cgroup, e.g. the vCPUs hotplugged, the workers
+    /// of virtiofsd and the vhost workers: the vCPU threads are constrained
+    /// by the sandbox cgroup, the others go to the overhead one if any. The
+    /// vhost workers are found with the threads of the hypervisor on the
+    /// kernels running them as such, by their name as kernel threads on the
+    /// older ones.
+    pub async fn resync_threads(&self, h: &dyn Hypervisor) -> Result<()> {
+        let vcpus: HashSet<u32> = h
+            .get_thread_ids()
+            .await
+            .context("get vcpu threads")?
+            .vcpus
+            .values()
+            .copied()
+            .collect();
+        let pids = h.get_pids().await.context("get hypervisor pids")?;
+        let threads = threads::hypervisor_threads(Path::new(threads::PROC_ROOT), &pids);
+
+        let in_overhead = self.overhead_cgroup_manager.as_ref().map(tasks_of);
+        let placement = threads::place_threads(
+            &threads,
+            &vcpus,
+            &tasks_of(&self.cgroup_manager),
+            in_overhead.as_ref(),
+        );
+        if placement == threads::Placement::default() {
+            return Ok(());
+        }
+        info!(sl!(), "resync threads into cgroups: {:?}", placement);
 
-        resources.values().for_each(|r| {
-            if let Some(cpus) = &r.cpu.cpus {
-                cpu_list.insert(cpus.clone());
-            }
-            if let Some(mems) = &r.cpu.mems {
-                mem_list.insert(mems.clone());
-            }
-        });
+        let mut failed = move_threads(&self.cgroup_manager, &placement.to_sandbox);
+        if let Some(overhead) = self.overhead_cgroup_manager.as_ref() {
+            failed += move_threads(overhead, &placement.to_overhead);
+        }
+        if failed > 0 {
+            return Err(anyhow!(
+                "{} threads couldn't be moved into the sandbox cgroups",
+                failed
+            ));
+        }
+        Ok(())
+    }
 
-        let cpu_resource = CpuResources {
-            cpus: Some(Vec::from_iter(cpu_list.into_iter()).join(",")),
-            mems: Some(Vec::from_iter(mem_list.into_iter()).join(",")),
+    async fn merge_resources(&self) -> Resources {
+        let resources = self.resources.read().await;
+        Resources {
+            cpu: merge_cpu_resources(
+                &resources,
+                &self.sid,
+                cgroups_rs::hierarchies::is_cgroup2_unified_mode(),
+            ),
             ..Default::default()
-        };
+        }
+    }
 
+    fn calc_resource(&self, linux_resources: Option<&LinuxResources>) -> Resources {
         Resources {
-            cpu: cpu_resource,
+            cpu: calc_cpu_resources(linux_resources),
+            blkio: blkio::calc_blkio_resources(linux_resources),
             ..Default::default()
         }
     }
+}
 
-    fn calc_cpu_resources(&self, linux_resources: Option<&LinuxResources>) -> CpuResources {
-        let cpu = || -> Option<oci::LinuxCpu> { linux_resources.as_ref()?.cpu.clone() }();
+// calc_cpu_resources takes the shares, i.e. the cpu request, and the quota,
+// i.e. the cpu limit, of the container independently, the container isn't
+// throttled without a quota.
+fn calc_cpu_resources(linux_resources: Option<&LinuxResources>) -> CpuResources {
+    let cpu = match linux_resources.and_then(|r| r.cpu.as_ref()) {
+        Some(cpu) => cpu,
+        None => return CpuResources::default(),
+    };
+    let quota = cpu.quota.filter(|q| *q > 0);
+
+    CpuResources {
+        cpus: Some(cpu.cpus.clone()),
+        mems: Some(cpu.mems.clone()),
+        shares: cpu.shares.filter(|s| *s > 0),
+        quota,
+        period: quota.map(|_| cpu.period.filter(|p| *p > 0).unwrap_or(DEFAULT_CPU_PERIOD)),
+        ..Default::default()
+    }
+}
 
-        CpuResources {
-            cpus: cpu.clone().map(|cpu| cpu.cpus),
-            mems: cpu.map(|cpu| cpu.mems),
-            ..Default::default()
+// merge_cpu_resources sums the shares and the quotas of the containers for
+// the sandbox cgroup. The sandbox is unlimited if a container other than the
+// sandbox one has no quota, as nothing bounds the cpus it could use. The
+// shares are converted to cpu.weight on cgroup v2.
+fn merge_cpu_resources(
+    resources: &HashMap<String, Resources>,
+    sandbox_cid: &str,
+    cgroup_v2: bool,
+) -> CpuResources {
+    let mut cpu_list: HashSet<String> = HashSet::new();
+    let mut mem_list: HashSet<String> = HashSet::new();
+    let mut shares: Option<u64> = None;
+    // the sum of the quotas in cpus and the longest period
+    let mut quota_cpus = 0f64;
+    let mut period: Option<u64> = None;
+    let mut unlimited = false;
+
+    for (cid, r) in resources {
+        if let Some(cpus) = &r.cpu.cpus {
+            cpu_list.insert(cpus.clone());
+        }
+        if let Some(mems) = &r.cpu.mems {
+            mem_list.insert(mems.clone());
+        }
+        if let Some(s) = r.cpu.shares {
+            shares = Some(shares.unwrap_or_default() + s);
+        }
+        match (r.cpu.quota, r.cpu.period) {
+            (Some(q), Some(p)) => {
+                quota_cpus += q as f64 / p as f64;
+                period = Some(period.unwrap_or_default().max(p));
+            }
+            _ if cid != sandbox_cid => unlimited = true,
+            _ => {}
         }
     }
 
-    fn calc_resource(&self, linux_resources: Option<&LinuxResources>) -> Resources {
-        Resources {
-            cpu: self.calc_cpu_resources(linux_resources),
-            ..Default::default()
-        }
+    let (quota, period) = match period {
+        Some(p) if !unlimited => ((quota_cpus * p as f64).ceil() as i64, Some(p)),
+        _ => (UNLIMITED_CPU_QUOTA, None),
+    };
+    CpuResources {
+        cpus: Some(Vec::from_iter(cpu_list.into_iter()).join(",")),
+        mems: Some(Vec::from_iter(mem_list.into_iter()).join(",")),
+        shares: shares.map(|s| if cgroup_v2 { shares_to_weight(s) } else { s }),
+        quota: Some(quota),
+        period,
+        ..Default::default()
     }
 }
 
+// shares_to_weight maps the cpu.shares of cgroup v1 in [2, 262144] to the
+// cpu.weight of cgroup v2 in [1, 10000], as runc does.
+fn shares_to_weight(shares: u64) -> u64 {
+    let shares = shares.clamp(2, 262144);
+    1 + ((shares - 2) * 9999) / 262142
+}
+
 #[async_trait]
 impl Persist for CgroupsResource {
     type State = CgroupState;
@@ -259,22 +520,167 @@ impl Persist for CgroupsResource {
             path: Some(self.cgroup_config.path.clone()),
             overhead_path: Some(self.cgroup_config.overhead_path.clone()),
             sandbox_cgroup_only: self.cgroup_config.sandbox_cgroup_only,
+            settings: self.settings.read().await.clone(),
+            attached_devices: self.attached_devices.read().await.iter().copied().collect(),
         })
     }
-    /// Restore a component from a specified state.
+    /// Restore a component from a specified state, the settings of the
+    /// sandbox cgroup are reconciled with the ones saved.
     async fn restore(
         cgroup_args: Self::ConstructorArgs,
         cgroup_state: Self::State,
     ) -> Result<Self> {
         let hier = cgroups_rs::hierarchies::auto();
-        let config = CgroupConfig::new(&cgroup_args.sid, &cgroup_args.config)?;
         let path = cgroup_state.path.unwrap_or_default();
         let cgroup_manager = Cgroup::load(hier, path.as_str());
-        Ok(Self {
+        // the settings are reconciled where they were applied
+        let config = CgroupConfig::restore(&cgroup_args.sid, &cgroup_args.config, path);
+        let resource = Self {
             cgroup_manager,
             resources: Arc::new(RwLock::new(HashMap::new())),
+            attached_devices: Arc::new(RwLock::new(
+                cgroup_state.attached_devices.into_iter().collect(),
+            )),
+            settings: Arc::new(RwLock::new(cgroup_state.settings)),
             overhead_cgroup_manager: None,
             cgroup_config: config,
+            sid: cgroup_args.sid,
+        };
+        // best effort, the sandbox is restored even if the host refuses them
+        if let Err(e) = resource.reconcile().await {
+            warn!(sl!(), "couldn't reconcile sandbox cgroup: {:?}", e);
+        }
+        Ok(resource)
+    }
+}
+
+fn tasks_of(cgroup: &Cgroup) -> HashSet<u32> {
+    cgroup.tasks().into_iter().map(|p| p.pid as u32).collect()
+}
+
+// move_threads moves the threads into the cgroup, the ones exited already are
+// skipped, it returns how many of the others failed to move
+fn move_threads(cgroup: &Cgroup, tids: &[u32]) -> usize {
+    let mut failed = 0;
+    for tid in tids {
+        if let Err(e) = cgroup.add_task(CgroupPid { pid: *tid as u64 }) {
+            if !is_no_such_process(&e) {
+                warn!(sl!(), "couldn't move thread {} into cgroup: {:?}", tid, e);
+                failed += 1;
+            }
+        }
+    }
+    failed
+}
+
+fn is_no_such_process(error: &cgroups_rs::error::Error) -> bool {
+    error
+        .source()
+        .and_then(|e| e.downcast_ref::<io::Error>())
+        .map_or(false, |e| {
+            e.raw_os_error() == Some(OS_ERROR_NO_SUCH_PROCESS)
         })
+}
+
+fn delete_overhead_cgroup(overhead: &Cgroup) -> Result<()> {
+    for cg_pid in overhead.tasks() {
+        overhead.remove_task(cg_pid)?;
+    }
+    overhead.delete()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_linux_resources(
+        shares: Option<u64>,
+        quota: Option<i64>,
+        period: Option<u64>,
+    ) -> LinuxResources {
+        LinuxResources {
+            cpu: Some(oci::LinuxCpu {
+                shares,
+                quota,
+                period,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn new_resources(linux_resources: &LinuxResources) -> Resources {
+        Resources {
+            cpu: calc_cpu_resources(Some(linux_resources)),
+            ..Default::default()
+        }
+    }
+
+    // the resources of the containers by their ids, the sandbox container
+    // is the one of "sandbox"
+    fn by_cid(resources: &[(&str, &Resources)]) -> HashMap<String, Resources> {
+        resources
+            .iter()
+            .map(|(cid, r)| (cid.to_string(), (*r).clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_cpu_shares_only() {
+        let r = new_resources(&new_linux_resources(Some(1024), None, Some(100000)));
+        assert_eq!(r.cpu.shares, Some(1024));
+        assert_eq!(r.cpu.quota, None);
+        assert_eq!(r.cpu.period, None);
+
+        // no throttling of the sandbox
+        let cpu = merge_cpu_resources(&by_cid(&[("a", &r)]), "sandbox", false);
+        assert_eq!(cpu.shares, Some(1024));
+        assert_eq!(cpu.quota, Some(UNLIMITED_CPU_QUOTA));
+        assert_eq!(cpu.period, None);
+
+        let cpu = merge_cpu_resources(&by_cid(&[("a", &r)]), "sandbox", true);
+        assert_eq!(cpu.shares, Some(39));
+        assert_eq!(cpu.quota, Some(UNLIMITED_CPU_QUOTA));
+    }
+
+    #[test]
+    fn test_cpu_shares_and_quota() {
+        // the pause container has no quota
+        let pause = new_resources(&new_linux_resources(Some(2), None, None));
+        let a = new_resources(&new_linux_resources(Some(512), Some(50000), None));
+        let b = new_resources(&new_linux_resources(Some(1024), Some(100000), Some(200000)));
+        assert_eq!(a.cpu.period, Some(DEFAULT_CPU_PERIOD));
+
+        // half a cpu and another half a cpu
+        let resources = by_cid(&[("sandbox", &pause), ("a", &a), ("b", &b)]);
+        let cpu = merge_cpu_resources(&resources, "sandbox", false);
+        assert_eq!(cpu.shares, Some(1538));
+        assert_eq!(cpu.quota, Some(200000));
+        assert_eq!(cpu.period, Some(200000));
+
+        assert_eq!(shares_to_weight(2), 1);
+        assert_eq!(shares_to_weight(262144), 10000);
+        assert_eq!(shares_to_weight(u64::MAX), 10000);
+    }
+
+    #[test]
+    fn test_cpu_quota_and_unlimited() {
+        let pause = new_resources(&new_linux_resources(Some(2), None, None));
+        let limited = new_resources(&new_linux_resources(Some(512), Some(50000), None));
+        let unlimited = new_resources(&new_linux_resources(Some(1024), None, None));
+
+        // the limited container alone bounds the sandbox
+        let resources = by_cid(&[("sandbox", &pause), ("a", &limited)]);
+        let cpu = merge_cpu_resources(&resources, "sandbox", false);
+        assert_eq!(cpu.quota, Some(50000));
+        assert_eq!(cpu.period, Some(DEFAULT_CPU_PERIOD));
+
+        // the unlimited one isn't throttled by the quota of the other
+        let resources = by_cid(&[("sandbox", &pause), ("a", &limited), ("b", &unlimited)]);
+        let cpu = merge_cpu_resources(&resources, "sandbox", false);
+        assert_eq!(cpu.shares, Some(1538));
+        assert_eq!(cpu.quota, Some(UNLIMITED_CPU_QUOTA));
+        assert_eq!(cpu.period, None);
     }
 }
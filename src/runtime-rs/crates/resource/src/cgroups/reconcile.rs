@@ -0,0 +1,347 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Result};
+use cgroups_rs::CpuResources;
+use kata_types::cpu::CpuSet;
+
+use super::cgroup_persist::CgroupSetting;
+
+pub(crate) const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+const CPU: &str = "cpu";
+const CPUSET: &str = "cpuset";
+const CPU_SHARES: &str = "cpu.shares";
+const CPU_CFS_QUOTA: &str = "cpu.cfs_quota_us";
+const CPU_CFS_PERIOD: &str = "cpu.cfs_period_us";
+const CPU_WEIGHT: &str = "cpu.weight";
+const CPU_MAX: &str = "cpu.max";
+const CPUSET_CPUS: &str = "cpuset.cpus";
+const CPUSET_MEMS: &str = "cpuset.mems";
+const BLKIO: &str = "blkio";
+const BLKIO_WEIGHT_DEVICE: &str = "blkio.weight_device";
+const IO_BFQ_WEIGHT: &str = "io.bfq.weight";
+
+/// CgroupDrift is a setting of the sandbox cgroup found changed on the host,
+/// and written back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CgroupDrift {
+    pub path: String,
+    pub found: String,
+    pub expected: String,
+}
+
+/// CgroupReconciliation is what was fixed of the sandbox cgroup: the
+/// directories recreated and the settings written back.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CgroupReconciliation {
+    pub recreated: Vec<String>,
+    pub fixed: Vec<CgroupDrift>,
+}
+
+impl CgroupReconciliation {
+    pub fn is_empty(&self) -> bool {
+        self.recreated.is_empty() && self.fixed.is_empty()
+    }
+}
+
+/// cpu_settings returns the files the cpu resources of the sandbox are
+/// applied with, the values unset aren't written.
+pub(crate) fn cpu_settings(cpu: &CpuResources, cgroup_v2: bool) -> Vec<CgroupSetting> {
+    let mut settings = vec![];
+    if cgroup_v2 {
+        if let Some(weight) = cpu.shares {
+            settings.push(setting(CPU, CPU_WEIGHT, weight));
+        }
+        if let Some(quota) = cpu.quota {
+            let quota = if quota < 0 {
+                "max".to_string()
+            } else {
+                quota.to_string()
+            };
+            let value = match cpu.period {
+                Some(period) => format!("{} {}", quota, period),
+                None => quota,
+            };
+            settings.push(setting(CPU, CPU_MAX, value));
+        }
+    } else {
+        if let Some(shares) = cpu.shares {
+            settings.push(setting(CPU, CPU_SHARES, shares));
+        }
+        if let Some(quota) = cpu.quota {
+            settings.push(setting(CPU, CPU_CFS_QUOTA, quota));
+        }
+        if let Some(period) = cpu.period {
+            settings.push(setting(CPU, CPU_CFS_PERIOD, period));
+        }
+    }
+    for (file, value) in [(CPUSET_CPUS, &cpu.cpus), (CPUSET_MEMS, &cpu.mems)] {
+        if let Some(value) = value.as_ref().filter(|v| !v.is_empty()) {
+            settings.push(setting(CPUSET, file, value));
+        }
+    }
+    settings
+}
+
+/// device_weight_setting returns the file the weight of the disk is applied
+/// with.
+pub(crate) fn device_weight_setting(
+    major: u64,
+    minor: u64,
+    weight: u16,
+    cgroup_v2: bool,
+) -> CgroupSetting {
+    let file = if cgroup_v2 {
+        IO_BFQ_WEIGHT
+    } else {
+        BLKIO_WEIGHT_DEVICE
+    };
+    setting(BLKIO, file, format!("{}:{} {}", major, minor, weight))
+}
+
+/// is_device_weight tells if the setting is the weight of a disk.
+pub(crate) fn is_device_weight(s: &CgroupSetting) -> bool {
+    s.file == BLKIO_WEIGHT_DEVICE || s.file == IO_BFQ_WEIGHT
+}
+
+fn setting(controller: &str, file: &str, value: impl ToString) -> CgroupSetting {
+    CgroupSetting {
+        controller: controller.to_string(),
+        file: file.to_string(),
+        value: value.to_string(),
+    }
+}
+
+// controller_dir returns the directory of the cgroup for the controller,
+// there's one hierarchy by controller on cgroup v1
+fn controller_dir(root: &Path, cgroup_v2: bool, controller: &str, path: &str) -> PathBuf {
+    if cgroup_v2 {
+        root.join(path)
+    } else {
+        root.join(controller).join(path)
+    }
+}
+
+/// reconcile_settings reads back the settings of the cgroup at path, and
+/// writes again the ones which drifted. The directories of the cgroup gone
+/// are recreated first. All the settings are tried, it fails if one of them
+/// couldn't be fixed.
+pub(crate) fn reconcile_settings(
+    root: &Path,
+    cgroup_v2: bool,
+    path: &str,
+    settings: &[CgroupSetting],
+) -> Result<CgroupReconciliation> {
+    let mut reconciliation = CgroupReconciliation::default();
+    let mut failed = 0;
+    for s in settings {
+        let dir = controller_dir(root, cgroup_v2, &s.controller, path);
+        if !dir.exists() {
+            if let Err(e) = fs::create_dir_all(&dir) {
+                warn!(sl!(), "couldn't recreate cgroup {:?}: {:?}", dir, e);
+                failed += 1;
+                continue;
+            }
+            reconciliation
+                .recreated
+                .push(dir.to_string_lossy().to_string());
+        }
+
+        let file = dir.join(&s.file);
+        // a file the kernel doesn't have, e.g. of a controller disabled, is
+        // read as empty and fails to be written
+        let found = fs::read_to_string(&file).unwrap_or_default();
+        let found = found.trim();
+        if is_effective(&s.file, found, &s.value) {
+            continue;
+        }
+        if let Err(e) = fs::write(&file, &s.value).with_context(|| format!("write {:?}", file)) {
+            warn!(sl!(), "couldn't reapply {} to {:?}: {:?}", s.value, file, e);
+            failed += 1;
+            continue;
+        }
+        reconciliation.fixed.push(CgroupDrift {
+            path: file.to_string_lossy().to_string(),
+            found: found.to_string(),
+            expected: s.value.clone(),
+        });
+    }
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} settings of cgroup {} couldn't be reapplied",
+            failed,
+            path
+        ));
+    }
+    Ok(reconciliation)
+}
+
+// is_effective tells if the value read back is the one written, as the
+// kernel shows it: the cpusets are shown as ranges, cpu.max keeps its
+// period if only the quota is written, and the weights of all the disks
+// are shown one by line
+fn is_effective(file: &str, found: &str, expected: &str) -> bool {
+    match file {
+        BLKIO_WEIGHT_DEVICE | IO_BFQ_WEIGHT => found
+            .lines()
+            .any(|l| l.split_whitespace().eq(expected.split_whitespace())),
+        CPUSET_CPUS | CPUSET_MEMS => match (CpuSet::from_str(found), CpuSet::from_str(expected)) {
+            (Ok(found), Ok(expected)) => !found.is_empty() && found == expected,
+            _ => found == expected,
+        },
+        CPU_MAX => {
+            let found: Vec<&str> = found.split_whitespace().collect();
+            let expected: Vec<&str> = expected.split_whitespace().collect();
+            found.len() >= expected.len() && found.iter().zip(expected.iter()).all(|(f, e)| f == e)
+        }
+        _ => found == expected,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_cpu(shares: u64, quota: i64, period: Option<u64>, cpus: &str) -> CpuResources {
+        CpuResources {
+            cpus: Some(cpus.to_string()),
+            mems: Some("".to_string()),
+            shares: Some(shares),
+            quota: Some(quota),
+            period,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_cpu_settings() {
+        let cpu = new_cpu(1024, 50000, Some(100000), "0-1");
+        let files: Vec<(String, String)> = cpu_settings(&cpu, false)
+            .into_iter()
+            .map(|s| (s.file, s.value))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                (CPU_SHARES.to_string(), "1024".to_string()),
+                (CPU_CFS_QUOTA.to_string(), "50000".to_string()),
+                (CPU_CFS_PERIOD.to_string(), "100000".to_string()),
+                (CPUSET_CPUS.to_string(), "0-1".to_string()),
+            ]
+        );
+
+        let cpu = new_cpu(39, -1, None, "");
+        let files: Vec<(String, String)> = cpu_settings(&cpu, true)
+            .into_iter()
+            .map(|s| (s.file, s.value))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                (CPU_WEIGHT.to_string(), "39".to_string()),
+                (CPU_MAX.to_string(), "max".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reconcile_settings() {
+        let root = tempfile::tempdir().unwrap();
+        let cpu = new_cpu(1024, 50000, Some(100000), "0,1");
+        let settings = cpu_settings(&cpu, false);
+        let cpu_dir = root.path().join("cpu/kata/sb");
+        fs::create_dir_all(&cpu_dir).unwrap();
+        fs::write(cpu_dir.join(CPU_SHARES), "1024\n").unwrap();
+        // tampered with
+        fs::write(cpu_dir.join(CPU_CFS_QUOTA), "-1\n").unwrap();
+        fs::write(cpu_dir.join(CPU_CFS_PERIOD), "100000\n").unwrap();
+
+        // the cpuset cgroup is gone
+        let reconciliation = reconcile_settings(root.path(), false, "kata/sb", &settings).unwrap();
+        let cpuset_dir = root.path().join("cpuset/kata/sb");
+        assert_eq!(
+            reconciliation.recreated,
+            vec![cpuset_dir.to_string_lossy().to_string()]
+        );
+        assert_eq!(
+            reconciliation.fixed,
+            vec![
+                CgroupDrift {
+                    path: cpu_dir.join(CPU_CFS_QUOTA).to_string_lossy().to_string(),
+                    found: "-1".to_string(),
+                    expected: "50000".to_string(),
+                },
+                CgroupDrift {
+                    path: cpuset_dir.join(CPUSET_CPUS).to_string_lossy().to_string(),
+                    found: "".to_string(),
+                    expected: "0,1".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(cpu_dir.join(CPU_CFS_QUOTA)).unwrap(),
+            "50000"
+        );
+
+        // the kernel shows the cpuset as a range
+        fs::write(cpuset_dir.join(CPUSET_CPUS), "0-1\n").unwrap();
+        let reconciliation = reconcile_settings(root.path(), false, "kata/sb", &settings).unwrap();
+        assert!(reconciliation.is_empty());
+    }
+
+    #[test]
+    fn test_is_effective() {
+        assert!(is_effective(CPU_MAX, "max 100000", "max"));
+        assert!(is_effective(CPU_MAX, "50000 100000", "50000 100000"));
+        assert!(!is_effective(CPU_MAX, "max 100000", "50000 100000"));
+        assert!(!is_effective(CPU_MAX, "", "max"));
+        assert!(is_effective(CPUSET_CPUS, "0-3", "0,1,2,3"));
+        assert!(!is_effective(CPUSET_CPUS, "0-2", "0,1,2,3"));
+        assert!(!is_effective(CPU_SHARES, "2", "1024"));
+        assert!(is_effective(
+            IO_BFQ_WEIGHT,
+            "default 100\n8:0 200\n8:16 300",
+            "8:16 300"
+        ));
+        assert!(is_effective(BLKIO_WEIGHT_DEVICE, "8:0\t200", "8:0 200"));
+        assert!(!is_effective(BLKIO_WEIGHT_DEVICE, "8:0\t100", "8:0 200"));
+        assert!(!is_effective(IO_BFQ_WEIGHT, "default 100", "8:0 200"));
+    }
+
+    #[test]
+    fn test_reconcile_device_weights() {
+        let root = tempfile::tempdir().unwrap();
+        let settings = vec![
+            device_weight_setting(8, 0, 200, false),
+            device_weight_setting(8, 16, 300, false),
+        ];
+        assert!(settings.iter().all(is_device_weight));
+        let blkio_dir = root.path().join("blkio/kata/sb");
+        fs::create_dir_all(&blkio_dir).unwrap();
+        // the weight of 8:16 was reset
+        fs::write(blkio_dir.join(BLKIO_WEIGHT_DEVICE), "8:0\t200\n").unwrap();
+
+        let reconciliation = reconcile_settings(root.path(), false, "kata/sb", &settings).unwrap();
+        assert_eq!(
+            reconciliation.fixed,
+            vec![CgroupDrift {
+                path: blkio_dir
+                    .join(BLKIO_WEIGHT_DEVICE)
+                    .to_string_lossy()
+                    .to_string(),
+                found: "8:0\t200".to_string(),
+                expected: "8:16 300".to_string(),
+            }]
+        );
+    }
+}
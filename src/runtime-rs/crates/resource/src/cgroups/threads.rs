@@ -0,0 +1,164 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs,
+    path::Path,
+};
+
+pub(crate) const PROC_ROOT: &str = "/proc";
+// the vhost workers are kernel threads named after the pid of their owner,
+// before they became threads of the owner on the recent kernels
+const VHOST_WORKER_PREFIX: &str = "vhost-";
+
+/// Placement is the threads of the hypervisor out of their cgroup, to be
+/// moved into it.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Placement {
+    pub to_sandbox: Vec<u32>,
+    pub to_overhead: Vec<u32>,
+}
+
+/// hypervisor_threads returns the threads of the processes of the
+/// hypervisor and their vhost workers, the processes gone are skipped.
+pub(crate) fn hypervisor_threads(proc_root: &Path, pids: &[u32]) -> BTreeSet<u32> {
+    let mut threads = BTreeSet::new();
+    for pid in pids {
+        let tasks = match fs::read_dir(proc_root.join(pid.to_string()).join("task")) {
+            Ok(tasks) => tasks,
+            Err(_) => continue,
+        };
+        threads.extend(
+            tasks
+                .flatten()
+                .filter_map(|t| t.file_name().to_str().and_then(|t| t.parse().ok())),
+        );
+    }
+    threads.extend(vhost_workers(proc_root, pids));
+    threads
+}
+
+// vhost_workers returns the kernel threads of vhost working for the pids
+fn vhost_workers(proc_root: &Path, pids: &[u32]) -> Vec<u32> {
+    let entries = match fs::read_dir(proc_root) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    entries
+        .flatten()
+        .filter_map(|e| e.file_name().to_str().and_then(|p| p.parse::<u32>().ok()))
+        .filter(|pid| {
+            fs::read_to_string(proc_root.join(pid.to_string()).join("comm"))
+                .ok()
+                .and_then(|comm| {
+                    comm.trim()
+                        .strip_prefix(VHOST_WORKER_PREFIX)
+                        .and_then(|owner| owner.parse::<u32>().ok())
+                })
+                .map_or(false, |owner| pids.contains(&owner))
+        })
+        .collect()
+}
+
+/// place_threads returns the threads out of their cgroup: the vCPU threads
+/// belong to the sandbox cgroup, the others to the overhead cgroup if any,
+/// to the sandbox one otherwise.
+pub(crate) fn place_threads(
+    threads: &BTreeSet<u32>,
+    vcpus: &HashSet<u32>,
+    in_sandbox: &HashSet<u32>,
+    in_overhead: Option<&HashSet<u32>>,
+) -> Placement {
+    let mut placement = Placement::default();
+    let all: BTreeSet<u32> = threads.iter().chain(vcpus.iter()).copied().collect();
+    for tid in all {
+        match in_overhead {
+            Some(in_overhead) if !vcpus.contains(&tid) => {
+                if !in_overhead.contains(&tid) {
+                    placement.to_overhead.push(tid);
+                }
+            }
+            _ => {
+                if !in_sandbox.contains(&tid) {
+                    placement.to_sandbox.push(tid);
+                }
+            }
+        }
+    }
+    placement
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // fake_proc lays out the tasks of the processes and the comm of the
+    // kernel threads as procfs does
+    fn fake_proc(root: &Path, tasks: &[(u32, &[u32])], kthreads: &[(u32, &str)]) {
+        for (pid, tids) in tasks {
+            for tid in tids.iter() {
+                fs::create_dir_all(
+                    root.join(pid.to_string())
+                        .join("task")
+                        .join(tid.to_string()),
+                )
+                .unwrap();
+            }
+            fs::write(root.join(pid.to_string()).join("comm"), "dragonball\n").unwrap();
+        }
+        for (pid, comm) in kthreads {
+            fs::create_dir_all(root.join(pid.to_string())).unwrap();
+            fs::write(
+                root.join(pid.to_string()).join("comm"),
+                format!("{}\n", comm),
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_hypervisor_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        fake_proc(
+            dir.path(),
+            &[(100, &[100, 101, 102, 105]), (200, &[200, 201])],
+            &[(300, "vhost-100"), (301, "vhost-999"), (302, "kworker/0:1")],
+        );
+
+        let threads = hypervisor_threads(dir.path(), &[100, 200, 404]);
+        assert_eq!(
+            threads.into_iter().collect::<Vec<_>>(),
+            vec![100, 101, 102, 105, 200, 201, 300]
+        );
+    }
+
+    #[test]
+    fn test_place_threads() {
+        let threads: BTreeSet<u32> = vec![100, 101, 102, 105, 300].into_iter().collect();
+        // 105 is a vCPU hotplugged, 300 a vhost worker started later
+        let vcpus: HashSet<u32> = vec![101, 102, 105].into_iter().collect();
+        let in_sandbox: HashSet<u32> = vec![101, 102].into_iter().collect();
+        let in_overhead: HashSet<u32> = vec![100].into_iter().collect();
+
+        assert_eq!(
+            place_threads(&threads, &vcpus, &in_sandbox, Some(&in_overhead)),
+            Placement {
+                to_sandbox: vec![105],
+                to_overhead: vec![300],
+            }
+        );
+
+        // everything is in the sandbox cgroup without the overhead one
+        assert_eq!(
+            place_threads(&threads, &vcpus, &in_sandbox, None),
+            Placement {
+                to_sandbox: vec![100, 105, 300],
+                to_overhead: vec![],
+            }
+        );
+    }
+}
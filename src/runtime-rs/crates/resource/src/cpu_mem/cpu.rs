@@ -0,0 +1,326 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    sync::Arc,
+};
+
+use agent::{is_unsupported_error, Agent, OfflineCPURequest, OnlineCPUMemRequest};
+use anyhow::{anyhow, Context, Result};
+use hypervisor::Hypervisor;
+use kata_types::{config::TomlConfig, cpu::LinuxContainerCpuResources};
+use oci::LinuxResources;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+#[derive(Debug, Default)]
+struct CpuResourceInner {
+    /// vcpus of the guest, including the vcpus hotplugged
+    current_vcpus: u32,
+    /// vcpus required by each container
+    container_vcpus: HashMap<String, u32>,
+}
+
+/// CpuState is the sizing of the guest vcpus, saved so that the restored
+/// sandbox keeps resizing the guest from where it is.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuState {
+    pub boot_vcpus: u32,
+    pub current_vcpus: u32,
+    /// vcpus required by each container
+    pub container_vcpus: BTreeMap<String, u32>,
+}
+
+#[derive(Default)]
+pub struct CpuResource {
+    /// vcpus the guest boots with
+    boot_vcpus: u32,
+    /// vcpus the guest could grow to
+    max_vcpus: u32,
+    inner: Arc<RwLock<CpuResourceInner>>,
+}
+
+impl CpuResource {
+    pub fn new(toml_config: &TomlConfig) -> Self {
+        let cpu_info = toml_config
+            .hypervisor
+            .get(&toml_config.runtime.hypervisor_name)
+            .map(|h| h.cpu_info.clone())
+            .unwrap_or_default();
+
+        let boot_vcpus = cpu_info.default_vcpus.max(1) as u32;
+        Self {
+            boot_vcpus,
+            max_vcpus: cpu_info.default_maxvcpus.max(boot_vcpus),
+            inner: Arc::new(RwLock::new(CpuResourceInner {
+                current_vcpus: boot_vcpus,
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// set_boot_vcpus updates the vcpus the guest boots with, which the guest
+    /// never shrinks below. It must be called before the VM starts.
+    pub async fn set_boot_vcpus(&mut self, boot_vcpus: u32) {
+        self.boot_vcpus = boot_vcpus;
+        self.max_vcpus = self.max_vcpus.max(boot_vcpus);
+        self.inner.write().await.current_vcpus = boot_vcpus;
+    }
+
+    /// save returns the sizing of the guest vcpus, the vcpus of the
+    /// containers included.
+    pub async fn save(&self) -> CpuState {
+        let inner = self.inner.read().await;
+        CpuState {
+            boot_vcpus: self.boot_vcpus,
+            current_vcpus: inner.current_vcpus,
+            container_vcpus: inner
+                .container_vcpus
+                .iter()
+                .map(|(cid, vcpus)| (cid.clone(), *vcpus))
+                .collect(),
+        }
+    }
+
+    /// restore takes the sizing of the guest vcpus saved, so that the guest
+    /// isn't shrunk under the containers still running.
+    pub async fn restore(&mut self, state: &CpuState) {
+        self.boot_vcpus = state.boot_vcpus;
+        self.max_vcpus = self.max_vcpus.max(state.boot_vcpus);
+        let mut inner = self.inner.write().await;
+        inner.current_vcpus = state.current_vcpus;
+        inner.container_vcpus = state
+            .container_vcpus
+            .iter()
+            .map(|(cid, vcpus)| (cid.clone(), *vcpus))
+            .collect();
+    }
+
+    /// update_cpu_resources records the vcpus required by the container and
+    /// resizes the guest to the boot vcpus plus the ones of all the containers.
+    /// It returns the vcpus the container required before, which
+    /// revert_cpu_resources takes to roll the update back.
+    pub async fn update_cpu_resources(
+        &self,
+        cid: &str,
+        linux_resources: Option<&LinuxResources>,
+        h: &dyn Hypervisor,
+        agent: &dyn Agent,
+    ) -> Result<Option<u32>> {
+        let mut inner = self.inner.write().await;
+
+        let old_vcpus = inner
+            .container_vcpus
+            .insert(cid.to_owned(), calc_vcpus(linux_resources));
+        if let Err(e) = self.do_update_cpu_resources(&mut inner, h, agent).await {
+            // roll back so that the failed container doesn't count
+            match old_vcpus {
+                Some(v) => inner.container_vcpus.insert(cid.to_owned(), v),
+                None => inner.container_vcpus.remove(cid),
+            };
+            return Err(e);
+        }
+
+        Ok(old_vcpus)
+    }
+
+    /// revert_cpu_resources gives the container back the vcpus it required
+    /// before the update, e.g. once the rest of the update failed, and
+    /// resizes the guest to them.
+    pub async fn revert_cpu_resources(
+        &self,
+        cid: &str,
+        old_vcpus: Option<u32>,
+        h: &dyn Hypervisor,
+        agent: &dyn Agent,
+    ) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        match old_vcpus {
+            Some(v) => inner.container_vcpus.insert(cid.to_owned(), v),
+            None => inner.container_vcpus.remove(cid),
+        };
+
+        self.do_update_cpu_resources(&mut inner, h, agent).await
+    }
+
+    /// remove_cpu_resources forgets the vcpus of the removed container and
+    /// shrinks the guest. The container is forgotten even if the guest fails
+    /// to shrink, so that it's retried on the next change.
+    pub async fn remove_cpu_resources(
+        &self,
+        cid: &str,
+        h: &dyn Hypervisor,
+        agent: &dyn Agent,
+    ) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        if inner.container_vcpus.remove(cid).is_none() {
+            return Ok(());
+        }
+
+        self.do_update_cpu_resources(&mut inner, h, agent).await
+    }
+
+    async fn do_update_cpu_resources(
+        &self,
+        inner: &mut CpuResourceInner,
+        h: &dyn Hypervisor,
+        agent: &dyn Agent,
+    ) -> Result<()> {
+        let target_vcpus = self.target_vcpus(inner);
+        if target_vcpus > self.max_vcpus {
+            return Err(anyhow!(
+                "{} vcpus required by the sandbox exceed default_maxvcpus {}",
+                target_vcpus,
+                self.max_vcpus
+            ));
+        }
+
+        if target_vcpus == inner.current_vcpus {
+            return Ok(());
+        }
+
+        info!(
+            sl!(),
+            "resize vcpus from {} to {}", inner.current_vcpus, target_vcpus
+        );
+        if target_vcpus < inner.current_vcpus {
+            // offline the vcpus in the guest first, so that they're unplugged
+            // without the guest still running tasks on them
+            match agent
+                .offline_cpu(OfflineCPURequest {
+                    nb_cpus: inner.current_vcpus - target_vcpus,
+                })
+                .await
+            {
+                Ok(_) => {}
+                Err(e) if is_unsupported_error(&e) => {
+                    warn!(sl!(), "agent can't offline vcpus, unplug them anyway")
+                }
+                Err(e) => return Err(e).context("offline vcpus"),
+            }
+        }
+        let new_vcpus = h.resize_vcpu(target_vcpus).await.context("resize vcpu")?;
+
+        if new_vcpus > inner.current_vcpus {
+            // online the vcpus hotplugged in the guest
+            agent
+                .online_cpu_mem(OnlineCPUMemRequest {
+                    wait: false,
+                    nb_cpus: new_vcpus - inner.current_vcpus,
+                    cpu_only: true,
+                })
+                .await
+                .context("online vcpus")?;
+        }
+        inner.current_vcpus = new_vcpus;
+
+        Ok(())
+    }
+
+    fn target_vcpus(&self, inner: &CpuResourceInner) -> u32 {
+        self.boot_vcpus + inner.container_vcpus.values().sum::<u32>()
+    }
+
+    /// current_vcpus returns the vcpus of the guest.
+    pub async fn current_vcpus(&self) -> u32 {
+        self.inner.read().await.current_vcpus
+    }
+}
+
+fn calc_vcpus(linux_resources: Option<&LinuxResources>) -> u32 {
+    let cpu = || -> Option<&oci::LinuxCpu> { linux_resources?.cpu.as_ref() }();
+
+    cpu.and_then(|cpu| LinuxContainerCpuResources::try_from(cpu).ok())
+        .and_then(|cpu| cpu.get_vcpus())
+        .unwrap_or(0) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oci::LinuxCpu;
+
+    fn resources(quota: i64) -> LinuxResources {
+        LinuxResources {
+            cpu: Some(LinuxCpu {
+                quota: Some(quota),
+                period: Some(100_000),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_calc_vcpus() {
+        assert_eq!(calc_vcpus(None), 0);
+        assert_eq!(calc_vcpus(Some(&LinuxResources::default())), 0);
+        assert_eq!(calc_vcpus(Some(&resources(-1))), 0);
+        assert_eq!(calc_vcpus(Some(&resources(100_000))), 1);
+        assert_eq!(calc_vcpus(Some(&resources(150_000))), 2);
+    }
+
+    #[test]
+    fn test_target_vcpus() {
+        let cpu = CpuResource {
+            boot_vcpus: 1,
+            max_vcpus: 8,
+            ..Default::default()
+        };
+        let mut inner = CpuResourceInner::default();
+
+        // create container a and b, then delete a
+        let steps = [
+            ("a", Some(resources(200_000)), 3),
+            ("b", Some(resources(150_000)), 5),
+            ("a", None, 3),
+        ];
+        for (cid, resources, expected) in steps {
+            match resources {
+                Some(r) => inner
+                    .container_vcpus
+                    .insert(cid.to_owned(), calc_vcpus(Some(&r))),
+                None => inner.container_vcpus.remove(cid),
+            };
+            assert_eq!(cpu.target_vcpus(&inner), expected);
+        }
+
+        // never below the boot vcpus
+        inner.container_vcpus.clear();
+        assert_eq!(cpu.target_vcpus(&inner), 1);
+    }
+
+    #[tokio::test]
+    async fn test_save_restore() {
+        let cpu = CpuResource {
+            boot_vcpus: 2,
+            max_vcpus: 8,
+            ..Default::default()
+        };
+        {
+            let mut inner = cpu.inner.write().await;
+            inner.current_vcpus = 5;
+            inner.container_vcpus.insert("a".to_owned(), 2);
+            inner.container_vcpus.insert("b".to_owned(), 1);
+        }
+        let state = cpu.save().await;
+        let state: CpuState =
+            serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+
+        let mut restored = CpuResource {
+            boot_vcpus: 1,
+            max_vcpus: 8,
+            ..Default::default()
+        };
+        restored.restore(&state).await;
+        assert_eq!(restored.save().await, state);
+        assert_eq!(restored.current_vcpus().await, 5);
+        // the containers still running keep their vcpus
+        assert_eq!(restored.target_vcpus(&*restored.inner.read().await), 5);
+    }
+}
@@ -0,0 +1,482 @@
+// Copyright (c) 2019-2021 Alibaba Cloud
+// Copyright (c) 2019-2021 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, Context, Result};
+
+use super::mem_reservation::dax_window_mb;
+use kata_types::{
+    annotations::Annotation, config::hypervisor::Hypervisor as HypervisorConfig,
+    container::ContainerType, cpu::LinuxContainerCpuResources, k8s::container_type,
+};
+
+// the cgroup cpu shares of one whole cpu
+const CPU_SHARES_PER_CPU: u64 = 1024;
+
+// initial resource that InitialSizeManager needs, this is the spec for the
+// sandbox/container's workload
+#[derive(Clone, Copy, Debug)]
+struct InitialSize {
+    vcpu: u32,
+    mem_mb: u32,
+}
+
+// generate initial resource(vcpu and memory in MiB) from spec's information
+// used for static resource management
+impl TryFrom<&oci::Spec> for InitialSize {
+    type Error = anyhow::Error;
+    fn try_from(spec: &oci::Spec) -> Result<Self> {
+        let mut vcpu: u32 = 0;
+        let mut mem_mb: u32 = 0;
+        match container_type(spec) {
+            // podsandbox, from annotation
+            ContainerType::PodSandbox => {
+                let annotation = Annotation::new(spec.annotations.clone());
+                let (period, quota, shares, memory) =
+                    get_sizing_info(&annotation).context("failed to get sizing info")?;
+                let containers = InitialSize {
+                    vcpu: get_nr_vcpu_from_cfs(period, quota, shares),
+                    mem_mb: convert_memory_to_mb(memory),
+                };
+
+                // init containers run one by one before the app containers,
+                // so the pod needs the larger of the largest init container
+                // and the sum of the app containers, plus the pod overhead.
+                let (quota, period) = annotation.get_pod_init_cpu();
+                let init = InitialSize {
+                    vcpu: get_nr_vcpu_from_cfs(period, quota, 0),
+                    mem_mb: convert_memory_to_mb(annotation.get_pod_init_mem()),
+                };
+                let (quota, period) = annotation.get_pod_overhead_cpu();
+                let overhead = InitialSize {
+                    vcpu: get_nr_vcpu_from_cfs(period, quota, 0),
+                    mem_mb: convert_memory_to_mb(annotation.get_pod_overhead_mem()),
+                };
+                debug!(
+                    sl!(),
+                    "static resource mgmt of pod: containers {:?}, init containers {:?}, overhead {:?}",
+                    containers,
+                    init,
+                    overhead
+                );
+
+                vcpu = containers.vcpu.max(init.vcpu) + overhead.vcpu;
+                mem_mb = containers.mem_mb.max(init.mem_mb) + overhead.mem_mb;
+            }
+            // single container, from container spec
+            _ => {
+                if let Some(linux) = &spec.linux {
+                    if let Some(resource) = &linux.resources {
+                        if let Some(cpu) = &resource.cpu {
+                            if let Ok(cpu_resource) = LinuxContainerCpuResources::try_from(cpu) {
+                                vcpu = get_nr_vcpu(&cpu_resource);
+                            }
+                        }
+                        if let Some(mem) = &resource.memory {
+                            let memory = mem.limit.unwrap_or(0);
+                            mem_mb = convert_memory_to_mb(memory);
+                        }
+                    }
+                }
+            }
+        }
+        info!(
+            sl!(),
+            "static resource mgmt result: vcpu={}, mem_mb={}", vcpu, mem_mb
+        );
+        Ok(Self { vcpu, mem_mb })
+    }
+}
+
+// InitialSizeManager is responsible for static resource management
+//
+// static resource management sizing information is optionally provided, either by
+// upper layer runtime (containerd / crio) or by the container spec itself (when it
+// is a standalone single container such as the one started with *docker run*)
+//
+// the sizing information uses cpu quota, cpu period, cpu shares and memory limit,
+// and with above values it calculates the # vcpus and memory for the workload and
+// add them to default value of the config, which is taken as the overhead of the
+// sandbox
+#[derive(Clone, Copy, Debug)]
+pub struct InitialSizeManager {
+    resource: InitialSize,
+}
+
+impl InitialSizeManager {
+    pub fn new(spec: &oci::Spec) -> Result<Self> {
+        Ok(Self {
+            resource: InitialSize::try_from(spec).context("failed to construct static resource")?,
+        })
+    }
+
+    /// setup_config adds the workload sizing to the hypervisor config, it fails
+    /// if the result is beyond default_maxvcpus or default_maxmemory.
+    pub fn setup_config(&self, hv: &mut HypervisorConfig) -> Result<()> {
+        let vcpus = hv.cpu_info.default_vcpus as u32 + self.resource.vcpu;
+        if hv.cpu_info.default_maxvcpus != 0 && vcpus > hv.cpu_info.default_maxvcpus {
+            return Err(anyhow!(
+                "{} vcpus required by the sandbox exceed default_maxvcpus {}",
+                vcpus,
+                hv.cpu_info.default_maxvcpus
+            ));
+        }
+
+        // the DAX window of virtio-fs takes the guest memory as well
+        let dax_mb = dax_window_mb(hv) as u32;
+        let mem_mb = hv.memory_info.default_memory + self.resource.mem_mb + dax_mb;
+        if hv.memory_info.default_maxmemory != 0 && mem_mb > hv.memory_info.default_maxmemory {
+            return Err(anyhow!(
+                "memory {} MiB required by the sandbox exceeds default_maxmemory {} MiB: boot {} MiB, workload {} MiB, DAX window {} MiB",
+                mem_mb,
+                hv.memory_info.default_maxmemory,
+                hv.memory_info.default_memory,
+                self.resource.mem_mb,
+                dax_mb
+            ));
+        }
+
+        hv.cpu_info.default_vcpus = vcpus as i32;
+        hv.memory_info.default_memory = mem_mb;
+        Ok(())
+    }
+
+    /// mem_mb returns the memory of the workload in MiB, including the pod
+    /// overhead.
+    pub fn mem_mb(&self) -> u32 {
+        self.resource.mem_mb
+    }
+}
+
+fn get_nr_vcpu_from_cfs(period: u64, quota: i64, shares: u64) -> u32 {
+    let cpu = oci::LinuxCpu {
+        period: Some(period),
+        quota: Some(quota),
+        shares: Some(shares),
+        ..Default::default()
+    };
+    // although it may not be actually a linux container, we are only using the calculation inside
+    // LinuxContainerCpuResources::try_from to generate our vcpu number
+    LinuxContainerCpuResources::try_from(&cpu)
+        .map(|cpu_resource| get_nr_vcpu(&cpu_resource))
+        .unwrap_or(0)
+}
+
+fn get_nr_vcpu(resource: &LinuxContainerCpuResources) -> u32 {
+    if let Some(v) = resource.get_vcpus() {
+        v as u32
+    } else {
+        // cpu shares are relative weights, a sandbox asking for 1024 shares
+        // is given one whole vcpu
+        (resource.shares().saturating_add(CPU_SHARES_PER_CPU - 1) / CPU_SHARES_PER_CPU) as u32
+    }
+}
+
+fn convert_memory_to_mb(memory_in_byte: i64) -> u32 {
+    if memory_in_byte < 0 {
+        0
+    } else {
+        (memory_in_byte / 1024 / 1024) as u32
+    }
+}
+
+// from the upper layer runtime's annotation (e.g. crio, k8s), get the *cpu quota,
+// cpu period, cpu shares and memory limit* for a sandbox/container
+fn get_sizing_info(annotation: &Annotation) -> Result<(u64, i64, u64, i64)> {
+    // since we are *adding* our result to the config, a value of 0 will cause no change
+    // and if the annotation is not assigned (but static resource management is), we will
+    // log a *warning* to fill that with zero value
+    let period = annotation.get_sandbox_cpu_period();
+    let quota = annotation.get_sandbox_cpu_quota();
+    let shares = annotation.get_sandbox_cpu_shares();
+    let memory = annotation.get_sandbox_mem();
+    Ok((period, quota, shares, memory))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kata_types::annotations::{
+        cri_containerd, KATA_ANNO_POD_INIT_CPU_PERIOD, KATA_ANNO_POD_INIT_CPU_QUOTA,
+        KATA_ANNO_POD_INIT_MEMORY, KATA_ANNO_POD_OVERHEAD_CPU_PERIOD,
+        KATA_ANNO_POD_OVERHEAD_CPU_QUOTA, KATA_ANNO_POD_OVERHEAD_MEMORY,
+    };
+    use std::collections::HashMap;
+
+    #[derive(Clone)]
+    struct InputData {
+        period: Option<u64>,
+        quota: Option<i64>,
+        shares: Option<u64>,
+        memory: Option<i64>,
+    }
+
+    #[derive(Clone)]
+    struct TestData<'a> {
+        desc: &'a str,
+        input: InputData,
+        result: InitialSize,
+    }
+
+    fn get_test_data() -> Vec<TestData<'static>> {
+        [
+            TestData {
+                desc: "no resource limit",
+                input: InputData {
+                    period: None,
+                    quota: None,
+                    shares: None,
+                    memory: None,
+                },
+                result: InitialSize { vcpu: 0, mem_mb: 0 },
+            },
+            TestData {
+                desc: "normal resource limit",
+                // data below should result in 2200 mCPU(round up to 3 vcpus) and 512 MiB of memory
+                input: InputData {
+                    period: Some(100_000),
+                    quota: Some(220_000),
+                    shares: None,
+                    memory: Some(1024 * 1024 * 512),
+                },
+                result: InitialSize {
+                    vcpu: 3,
+                    mem_mb: 512,
+                },
+            },
+            TestData {
+                desc: "cpu shares only",
+                // 1536 shares round up to 2 vcpus
+                input: InputData {
+                    period: None,
+                    quota: None,
+                    shares: Some(1536),
+                    memory: Some(1024 * 1024 * 256),
+                },
+                result: InitialSize {
+                    vcpu: 2,
+                    mem_mb: 256,
+                },
+            },
+        ]
+        .to_vec()
+    }
+
+    #[test]
+    fn test_initial_size_mgmt_sandbox() {
+        let tests = get_test_data();
+
+        // run tests
+        for (i, d) in tests.iter().enumerate() {
+            let spec = oci::Spec {
+                annotations: HashMap::from([
+                    (
+                        cri_containerd::CONTAINER_TYPE_LABEL_KEY.to_string(),
+                        cri_containerd::SANDBOX.to_string(),
+                    ),
+                    (
+                        cri_containerd::SANDBOX_CPU_PERIOD_KEY.to_string(),
+                        d.input.period.map_or(String::new(), |v| format!("{}", v)),
+                    ), // CPU period
+                    (
+                        cri_containerd::SANDBOX_CPU_QUOTA_KEY.to_string(),
+                        d.input.quota.map_or(String::new(), |v| format!("{}", v)),
+                    ), // CPU quota
+                    (
+                        cri_containerd::SANDBOX_CPU_SHARE_KEY.to_string(),
+                        d.input.shares.map_or(String::new(), |v| format!("{}", v)),
+                    ), // CPU shares
+                    (
+                        cri_containerd::SANDBOX_MEM_KEY.to_string(),
+                        d.input.memory.map_or(String::new(), |v| format!("{}", v)),
+                    ), // memory in bytes
+                ]),
+                ..Default::default()
+            };
+
+            let initial_size = InitialSize::try_from(&spec);
+            assert!(
+                initial_size.is_ok(),
+                "test[{}]: {:?} should be ok",
+                i,
+                d.desc
+            );
+
+            let initial_size = initial_size.unwrap();
+            assert_eq!(
+                initial_size.vcpu, d.result.vcpu,
+                "test[{}]: {:?} vcpu should be {}",
+                i, d.desc, d.result.vcpu,
+            );
+            assert_eq!(
+                initial_size.mem_mb, d.result.mem_mb,
+                "test[{}]: {:?} memory should be {}",
+                i, d.desc, d.result.mem_mb,
+            );
+        }
+    }
+
+    #[test]
+    fn test_initial_size_mgmt_init_and_overhead() {
+        let annotations = |init_mb: i64| {
+            HashMap::from([
+                (
+                    cri_containerd::CONTAINER_TYPE_LABEL_KEY.to_string(),
+                    cri_containerd::SANDBOX.to_string(),
+                ),
+                (
+                    cri_containerd::SANDBOX_CPU_PERIOD_KEY.to_string(),
+                    "100000".to_string(),
+                ),
+                (
+                    cri_containerd::SANDBOX_CPU_QUOTA_KEY.to_string(),
+                    "200000".to_string(),
+                ),
+                (
+                    cri_containerd::SANDBOX_MEM_KEY.to_string(),
+                    format!("{}", 1024 * 1024 * 512),
+                ),
+                (
+                    KATA_ANNO_POD_INIT_CPU_PERIOD.to_string(),
+                    "100000".to_string(),
+                ),
+                (
+                    KATA_ANNO_POD_INIT_CPU_QUOTA.to_string(),
+                    "400000".to_string(),
+                ),
+                (
+                    KATA_ANNO_POD_INIT_MEMORY.to_string(),
+                    format!("{}", 1024 * 1024 * init_mb),
+                ),
+                (
+                    KATA_ANNO_POD_OVERHEAD_CPU_PERIOD.to_string(),
+                    "100000".to_string(),
+                ),
+                (
+                    KATA_ANNO_POD_OVERHEAD_CPU_QUOTA.to_string(),
+                    "50000".to_string(),
+                ),
+                (
+                    KATA_ANNO_POD_OVERHEAD_MEMORY.to_string(),
+                    format!("{}", 1024 * 1024 * 128),
+                ),
+            ])
+        };
+
+        // the init container needs more than the app containers
+        let spec = oci::Spec {
+            annotations: annotations(1024),
+            ..Default::default()
+        };
+        let initial_size = InitialSize::try_from(&spec).unwrap();
+        assert_eq!(initial_size.vcpu, 5);
+        assert_eq!(initial_size.mem_mb, 1152);
+
+        // the app containers need more than the init container
+        let spec = oci::Spec {
+            annotations: annotations(256),
+            ..Default::default()
+        };
+        let initial_size = InitialSize::try_from(&spec).unwrap();
+        assert_eq!(initial_size.mem_mb, 640);
+    }
+
+    #[test]
+    fn test_initial_size_mgmt_container() {
+        let tests = get_test_data();
+
+        // run tests
+        for (i, d) in tests.iter().enumerate() {
+            let spec = oci::Spec {
+                annotations: HashMap::from([(
+                    cri_containerd::CONTAINER_TYPE_LABEL_KEY.to_string(),
+                    cri_containerd::CONTAINER.to_string(),
+                )]),
+                linux: Some(oci::Linux {
+                    resources: Some(oci::LinuxResources {
+                        cpu: Some(oci::LinuxCpu {
+                            period: d.input.period,
+                            quota: d.input.quota,
+                            shares: d.input.shares,
+                            ..Default::default()
+                        }),
+                        memory: Some(oci::LinuxMemory {
+                            limit: d.input.memory,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+
+            let initial_size = InitialSize::try_from(&spec);
+            assert!(
+                initial_size.is_ok(),
+                "test[{}]: {:?} should be ok",
+                i,
+                d.desc
+            );
+
+            let initial_size = initial_size.unwrap();
+            assert_eq!(
+                initial_size.vcpu, d.result.vcpu,
+                "test[{}]: {:?} vcpu should be {}",
+                i, d.desc, d.result.vcpu,
+            );
+            assert_eq!(
+                initial_size.mem_mb, d.result.mem_mb,
+                "test[{}]: {:?} memory should be {}",
+                i, d.desc, d.result.mem_mb,
+            );
+        }
+    }
+
+    #[test]
+    fn test_initial_size_setup_config() {
+        let manager = InitialSizeManager {
+            resource: InitialSize {
+                vcpu: 3,
+                mem_mb: 512,
+            },
+        };
+
+        let mut hv = HypervisorConfig::default();
+        hv.cpu_info.default_vcpus = 1;
+        hv.cpu_info.default_maxvcpus = 4;
+        hv.memory_info.default_memory = 256;
+        hv.memory_info.default_maxmemory = 1024;
+        manager.setup_config(&mut hv).unwrap();
+        assert_eq!(hv.cpu_info.default_vcpus, 4);
+        assert_eq!(hv.memory_info.default_memory, 768);
+
+        // vcpus beyond default_maxvcpus
+        let mut hv = HypervisorConfig::default();
+        hv.cpu_info.default_vcpus = 2;
+        hv.cpu_info.default_maxvcpus = 4;
+        assert!(manager.setup_config(&mut hv).is_err());
+
+        // memory beyond default_maxmemory
+        let mut hv = HypervisorConfig::default();
+        hv.memory_info.default_memory = 1024;
+        hv.memory_info.default_maxmemory = 1024;
+        assert!(manager.setup_config(&mut hv).is_err());
+
+        // with the DAX window
+        let mut hv = HypervisorConfig::default();
+        hv.memory_info.default_memory = 256;
+        hv.memory_info.default_maxmemory = 2048;
+        hv.shared_fs.virtio_fs_is_dax = true;
+        hv.shared_fs.virtio_fs_cache_size = 1024;
+        manager.setup_config(&mut hv).unwrap();
+        assert_eq!(hv.memory_info.default_memory, 1792);
+        hv.memory_info.default_memory = 1024;
+        let err = manager.setup_config(&mut hv).unwrap_err();
+        assert!(err.to_string().contains("DAX window 1024 MiB"));
+    }
+}
@@ -0,0 +1,859 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use agent::{Agent, GetMetricsRequest, OnlineCPUMemRequest};
+use anyhow::{anyhow, Context, Result};
+use hypervisor::Hypervisor;
+use kata_types::config::{hypervisor::get_host_memory_mb, TomlConfig};
+use oci::LinuxResources;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use super::mem_reservation::{dax_window_mb, ContainerReservation, MemReservations};
+
+const MIB: u64 = 1024 * 1024;
+
+// The memory that the guest kernel, kata-agent and the page cache of the
+// guest rootfs need at least. Ballooning the guest below it will make the
+// guest unusable, so it's left to the guest if no reclaim floor is configured.
+pub const MIN_GUEST_MEMORY_MB: u64 = 128;
+
+// The memory available in the guest in bytes, among the metrics of the agent.
+const GUEST_MEM_AVAILABLE: &str = "kata_guest_meminfo{item=\"mem_available\"}";
+
+#[derive(Debug, Default)]
+struct MemResourceInner {
+    /// memory of the guest in MiB, including the memory hotplugged. It's
+    /// aligned to the hotplug granularity of the hypervisor.
+    current_mem_mb: u64,
+    /// memory in MiB last requested for the guest by the containers
+    requested_mem_mb: u64,
+    /// memory taken away from the guest by the balloon in MiB
+    balloon_mb: u64,
+    /// memory limit of each container in MiB
+    container_mem_mb: HashMap<String, u64>,
+    /// memory taken by the sandbox on top of the container limits
+    reservations: MemReservations,
+}
+
+/// MemState is the sizing of the guest memory, saved so that the restored
+/// sandbox keeps resizing the guest from where it is.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemState {
+    pub boot_mem_mb: u64,
+    pub boot_reserved_mb: u64,
+    pub workload_mem_mb: u64,
+    pub current_mem_mb: u64,
+    pub requested_mem_mb: u64,
+    pub balloon_mb: u64,
+    /// memory limit of each container in MiB
+    pub container_mem_mb: BTreeMap<String, u64>,
+}
+
+#[derive(Default)]
+pub struct MemResource {
+    /// memory the guest boots with in MiB
+    boot_mem_mb: u64,
+    /// memory of the reservations included in the boot memory in MiB
+    boot_reserved_mb: u64,
+    /// memory the guest could grow to in MiB
+    max_mem_mb: u64,
+    /// memory always left to the guest on top of the container limits when
+    /// the balloon inflates in MiB
+    reclaim_floor_mb: u64,
+    /// memory of the workload the sandbox is sized for at boot in MiB, which
+    /// the balloon never takes back even before the containers are created
+    workload_mem_mb: u64,
+    /// the hotplugged memory could only be unplugged with virtio-mem
+    enable_virtio_mem: bool,
+    inner: Arc<RwLock<MemResourceInner>>,
+}
+
+impl MemResource {
+    pub fn new(toml_config: &TomlConfig) -> Self {
+        let hv = toml_config
+            .hypervisor
+            .get(&toml_config.runtime.hypervisor_name)
+            .cloned()
+            .unwrap_or_default();
+        let memory_info = &hv.memory_info;
+
+        let boot_mem_mb = memory_info.default_memory as u64;
+        let max_mem_mb = if memory_info.default_maxmemory != 0 {
+            memory_info.default_maxmemory as u64
+        } else {
+            get_host_memory_mb().unwrap_or_default() as u64
+        };
+
+        let reclaim_floor_mb = match memory_info.memory_reclaim_floor {
+            0 => MIN_GUEST_MEMORY_MB,
+            n => n as u64,
+        };
+
+        Self {
+            boot_mem_mb,
+            max_mem_mb: max_mem_mb.max(boot_mem_mb),
+            reclaim_floor_mb,
+            enable_virtio_mem: memory_info.enable_virtio_mem,
+            inner: Arc::new(RwLock::new(MemResourceInner {
+                current_mem_mb: boot_mem_mb,
+                requested_mem_mb: boot_mem_mb,
+                reservations: MemReservations::new(dax_window_mb(&hv)),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// set_boot_mem_mb updates the memory the guest boots with, it must be
+    /// called before the VM starts.
+    pub async fn set_boot_mem_mb(&mut self, boot_mem_mb: u64) {
+        self.boot_mem_mb = boot_mem_mb;
+        self.max_mem_mb = self.max_mem_mb.max(boot_mem_mb);
+        let mut inner = self.inner.write().await;
+        inner.current_mem_mb = boot_mem_mb;
+        inner.requested_mem_mb = boot_mem_mb;
+    }
+
+    /// set_boot_reserved_mb sets the memory of the reservations the boot
+    /// memory is sized with, e.g. the DAX window with the static resource
+    /// management, so that they aren't hotplugged again.
+    pub fn set_boot_reserved_mb(&mut self, boot_reserved_mb: u64) {
+        self.boot_reserved_mb = boot_reserved_mb;
+    }
+
+    /// reserve_container_mem records the guest memory the volumes of the
+    /// container take, it fails if the sandbox would need more than
+    /// default_maxmemory. The guest is resized with the memory limit of the
+    /// container.
+    pub async fn reserve_container_mem(
+        &self,
+        cid: &str,
+        reservation: &ContainerReservation,
+    ) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        let mut reservations = inner.reservations.clone();
+        reservations.add_container(cid, reservation);
+
+        let required_mb = self.required_mem_mb(&inner.container_mem_mb, &reservations);
+        if required_mb > self.max_mem_mb {
+            return Err(anyhow!(
+                "memory {} MiB required by the sandbox exceeds default_maxmemory {} MiB: {}",
+                required_mb,
+                self.max_mem_mb,
+                self.breakdown(&inner.container_mem_mb, &reservations)
+            ));
+        }
+        inner.reservations = reservations;
+        Ok(())
+    }
+
+    /// save returns the sizing of the guest memory, the memory limits of the
+    /// containers and the balloon included.
+    pub async fn save(&self) -> MemState {
+        let inner = self.inner.read().await;
+        MemState {
+            boot_mem_mb: self.boot_mem_mb,
+            boot_reserved_mb: self.boot_reserved_mb,
+            workload_mem_mb: self.workload_mem_mb,
+            current_mem_mb: inner.current_mem_mb,
+            requested_mem_mb: inner.requested_mem_mb,
+            balloon_mb: inner.balloon_mb,
+            container_mem_mb: inner
+                .container_mem_mb
+                .iter()
+                .map(|(cid, mb)| (cid.clone(), *mb))
+                .collect(),
+        }
+    }
+
+    /// restore takes the sizing of the guest memory saved, so that the guest
+    /// isn't shrunk under the containers still running, nor the balloon
+    /// left inflated.
+    pub async fn restore(&mut self, state: &MemState) {
+        self.boot_mem_mb = state.boot_mem_mb;
+        self.boot_reserved_mb = state.boot_reserved_mb;
+        self.workload_mem_mb = state.workload_mem_mb;
+        self.max_mem_mb = self.max_mem_mb.max(state.boot_mem_mb);
+        let mut inner = self.inner.write().await;
+        inner.current_mem_mb = state.current_mem_mb;
+        inner.requested_mem_mb = state.requested_mem_mb;
+        inner.balloon_mb = state.balloon_mb;
+        inner.container_mem_mb = state
+            .container_mem_mb
+            .iter()
+            .map(|(cid, mb)| (cid.clone(), *mb))
+            .collect();
+    }
+
+    /// container_reservations returns the guest memory the volumes of each
+    /// container take, to be restored.
+    pub async fn container_reservations(&self) -> BTreeMap<String, ContainerReservation> {
+        self.inner.read().await.reservations.containers()
+    }
+
+    /// restore_container_reservations records again the guest memory the
+    /// volumes of the containers take, as saved.
+    pub async fn restore_container_reservations(
+        &self,
+        containers: &BTreeMap<String, ContainerReservation>,
+    ) {
+        let mut inner = self.inner.write().await;
+        for (cid, reservation) in containers.iter() {
+            inner.reservations.add_container(cid, reservation);
+        }
+    }
+
+    /// release_container_mem forgets the guest memory the volumes of the
+    /// container take, the guest shrinks with its memory limit.
+    pub async fn release_container_mem(&self, cid: &str) {
+        self.inner.write().await.reservations.remove_container(cid);
+    }
+
+    /// set_workload_mem_mb sets the memory the sandbox is statically sized
+    /// for, it's left to the guest as the container limits are.
+    pub fn set_workload_mem_mb(&mut self, workload_mem_mb: u64) {
+        self.workload_mem_mb = workload_mem_mb;
+    }
+
+    /// update_mem_resources records the memory limit of the container and
+    /// resizes the guest memory to the boot memory plus the limits of all
+    /// the containers.
+    pub async fn update_mem_resources(
+        &self,
+        cid: &str,
+        linux_resources: Option<&LinuxResources>,
+        h: &dyn Hypervisor,
+        agent: &dyn Agent,
+    ) -> Result<()> {
+        let mut inner = self.inner.write().await;
+
+        let old_mem_mb = inner
+            .container_mem_mb
+            .insert(cid.to_owned(), calc_mem_mb(linux_resources));
+        if let Err(e) = self.do_update_mem_resources(&mut inner, h, agent).await {
+            // roll back so that the failed container doesn't count
+            match old_mem_mb {
+                Some(m) => inner.container_mem_mb.insert(cid.to_owned(), m),
+                None => inner.container_mem_mb.remove(cid),
+            };
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// remove_mem_resources forgets the memory limit of the removed container
+    /// and shrinks the guest. The container is forgotten even if the guest
+    /// fails to shrink, so that it's retried on the next change.
+    pub async fn remove_mem_resources(
+        &self,
+        cid: &str,
+        h: &dyn Hypervisor,
+        agent: &dyn Agent,
+    ) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        if inner.container_mem_mb.remove(cid).is_none() {
+            return Ok(());
+        }
+
+        self.do_update_mem_resources(&mut inner, h, agent).await
+    }
+
+    async fn do_update_mem_resources(
+        &self,
+        inner: &mut MemResourceInner,
+        h: &dyn Hypervisor,
+        agent: &dyn Agent,
+    ) -> Result<()> {
+        let target_mb = self.target_mem_mb(inner);
+        if target_mb > self.max_mem_mb {
+            return Err(anyhow!(
+                "memory {} MiB required by the sandbox exceeds default_maxmemory {} MiB: {}",
+                target_mb,
+                self.max_mem_mb,
+                self.breakdown(&inner.container_mem_mb, &inner.reservations)
+            ));
+        }
+
+        self.resize_guest_mem(inner, target_mb, h, agent).await?;
+        inner.requested_mem_mb = target_mb;
+
+        Ok(())
+    }
+
+    async fn resize_guest_mem(
+        &self,
+        inner: &mut MemResourceInner,
+        target_mb: u64,
+        h: &dyn Hypervisor,
+        agent: &dyn Agent,
+    ) -> Result<()> {
+        // take the memory back from the balloon before growing the guest
+        let available_mb = inner.current_mem_mb - inner.balloon_mb;
+        if target_mb > available_mb && inner.balloon_mb > 0 {
+            let balloon_mb = inner.balloon_mb.saturating_sub(target_mb - available_mb);
+            info!(
+                sl!(),
+                "deflate balloon from {} MiB to {} MiB", inner.balloon_mb, balloon_mb
+            );
+            h.set_balloon_size(balloon_mb)
+                .await
+                .context("deflate balloon")?;
+            inner.balloon_mb = balloon_mb;
+        }
+
+        if target_mb <= inner.current_mem_mb && inner.balloon_mb > 0 {
+            // shrinking with the balloon inflated leaves the guest less than
+            // the target
+            return Ok(());
+        }
+
+        // the hypervisor hotplugs memory in slots or blocks, round the
+        // request up so that the guest gets at least what's required
+        let granularity_mb = h
+            .capabilities()
+            .await
+            .context("get capabilities")?
+            .memory_hotplug_granularity_mb() as u64;
+        let aligned_mb =
+            align_mem_mb(self.boot_mem_mb, target_mb, granularity_mb).min(self.max_mem_mb);
+        if aligned_mb != target_mb {
+            info!(
+                sl!(),
+                "align memory from {} MiB to {} MiB by the hotplug granularity {} MiB",
+                target_mb,
+                aligned_mb,
+                granularity_mb
+            );
+        }
+
+        if aligned_mb == inner.current_mem_mb {
+            return Ok(());
+        }
+        // the memory hotplugged by ACPI, as qemu does without virtio-mem,
+        // can't be taken back from the guest
+        if aligned_mb < inner.current_mem_mb && !self.enable_virtio_mem {
+            return Ok(());
+        }
+
+        info!(
+            sl!(),
+            "resize memory from {} MiB to {} MiB", inner.current_mem_mb, aligned_mb
+        );
+        let new_mem_mb = h
+            .resize_memory(aligned_mb as u32)
+            .await
+            .context("resize memory")? as u64;
+
+        if new_mem_mb > inner.current_mem_mb {
+            // online the memory hotplugged in the guest
+            agent
+                .online_cpu_mem(OnlineCPUMemRequest {
+                    wait: false,
+                    nb_cpus: 0,
+                    cpu_only: false,
+                })
+                .await
+                .context("online memory")?;
+        }
+        inner.current_mem_mb = new_mem_mb;
+
+        Ok(())
+    }
+
+    fn target_mem_mb(&self, inner: &MemResourceInner) -> u64 {
+        self.required_mem_mb(&inner.container_mem_mb, &inner.reservations)
+    }
+
+    // required_mem_mb returns the boot memory plus the container limits and
+    // the reservations, the ones the guest boots with count once
+    fn required_mem_mb(
+        &self,
+        container_mem_mb: &HashMap<String, u64>,
+        reservations: &MemReservations,
+    ) -> u64 {
+        self.boot_mem_mb.saturating_sub(self.boot_reserved_mb)
+            + container_mem_mb.values().sum::<u64>()
+            + reservations.total_mb()
+    }
+
+    fn breakdown(
+        &self,
+        container_mem_mb: &HashMap<String, u64>,
+        reservations: &MemReservations,
+    ) -> String {
+        format!(
+            "boot {} MiB, containers {} MiB, {}",
+            self.boot_mem_mb.saturating_sub(self.boot_reserved_mb),
+            container_mem_mb.values().sum::<u64>(),
+            reservations
+        )
+    }
+
+    /// set_balloon_target inflates or deflates the balloon so that it holds
+    /// `bytes` of guest memory, the value is rounded down to MiB.
+    pub async fn set_balloon_target(&self, bytes: u64, h: &dyn Hypervisor) -> Result<()> {
+        self.reclaim_memory(bytes / MIB, h).await
+    }
+
+    /// reclaim_memory inflates or deflates the balloon so that `target_mb`
+    /// of guest memory is given back to the host. The memory limits of the
+    /// containers, or the workload memory if larger, plus the reclaim floor
+    /// are always left to the guest.
+    pub async fn reclaim_memory(&self, target_mb: u64, h: &dyn Hypervisor) -> Result<()> {
+        if !h.capabilities().await?.is_balloon_supported() {
+            return Err(anyhow!("hypervisor doesn't support memory balloon"));
+        }
+
+        let mut inner = self.inner.write().await;
+        self.resize_balloon(&mut inner, target_mb, h).await
+    }
+
+    /// reclaim_free_memory sizes the balloon after the memory the guest
+    /// reports as available, so that only the free memory is taken back and
+    /// the reclaim floor is still left free in the guest. The balloon is
+    /// deflated if a container other than the sandbox one has no memory
+    /// limit, as nothing bounds the memory it could use.
+    pub async fn reclaim_free_memory(
+        &self,
+        sandbox_cid: &str,
+        h: &dyn Hypervisor,
+        agent: &dyn Agent,
+    ) -> Result<()> {
+        if !h.capabilities().await?.is_balloon_supported() {
+            return Err(anyhow!("hypervisor doesn't support memory balloon"));
+        }
+
+        let metrics = agent
+            .get_metrics(GetMetricsRequest {})
+            .await
+            .context("get guest metrics")?;
+        let available_mb = parse_mem_available_mb(&metrics.metrics)?;
+
+        let mut inner = self.inner.write().await;
+        let target_mb = self.free_reclaim_target_mb(&inner, sandbox_cid, available_mb);
+        self.resize_balloon(&mut inner, target_mb, h).await
+    }
+
+    fn free_reclaim_target_mb(
+        &self,
+        inner: &MemResourceInner,
+        sandbox_cid: &str,
+        available_mb: u64,
+    ) -> u64 {
+        let unlimited: Vec<&String> = inner
+            .container_mem_mb
+            .iter()
+            .filter(|(cid, mem_mb)| cid.as_str() != sandbox_cid && **mem_mb == 0)
+            .map(|(cid, _)| cid)
+            .collect();
+        if !unlimited.is_empty() {
+            debug!(
+                sl!(),
+                "no memory reclaimed, containers {:?} have no memory limit", unlimited
+            );
+            return 0;
+        }
+
+        // the memory held by the balloon is free once it's deflated
+        (inner.balloon_mb + available_mb)
+            .saturating_sub(self.reclaim_floor_mb)
+            .min(self.reclaimable_mb(inner))
+    }
+
+    async fn resize_balloon(
+        &self,
+        inner: &mut MemResourceInner,
+        target_mb: u64,
+        h: &dyn Hypervisor,
+    ) -> Result<()> {
+        let reclaimable_mb = self.reclaimable_mb(inner);
+        if target_mb > reclaimable_mb {
+            return Err(anyhow!(
+                "balloon target {} MiB is beyond the reclaimable {} MiB of the guest of {} MiB",
+                target_mb,
+                reclaimable_mb,
+                inner.current_mem_mb
+            ));
+        }
+        if target_mb == inner.balloon_mb {
+            return Ok(());
+        }
+
+        info!(
+            sl!(),
+            "resize balloon from {} MiB to {} MiB", inner.balloon_mb, target_mb
+        );
+        h.set_balloon_size(target_mb)
+            .await
+            .context("set balloon size")?;
+        inner.balloon_mb = target_mb;
+
+        Ok(())
+    }
+
+    fn reclaimable_mb(&self, inner: &MemResourceInner) -> u64 {
+        let container_mem_mb = inner.container_mem_mb.values().sum::<u64>();
+        let reserved_mb = container_mem_mb.max(self.workload_mem_mb)
+            + inner.reservations.total_mb()
+            + self.reclaim_floor_mb;
+        inner.current_mem_mb.saturating_sub(reserved_mb)
+    }
+
+    /// balloon_mb returns the memory currently held by the balloon in MiB.
+    pub async fn balloon_mb(&self) -> u64 {
+        self.inner.read().await.balloon_mb
+    }
+
+    /// requested_mem_mb returns the memory in MiB last requested for the
+    /// guest, before it's aligned to the hotplug granularity.
+    pub async fn requested_mem_mb(&self) -> u64 {
+        self.inner.read().await.requested_mem_mb
+    }
+
+    /// current_mem_mb returns the memory of the guest in MiB.
+    pub async fn current_mem_mb(&self) -> u64 {
+        self.inner.read().await.current_mem_mb
+    }
+
+    pub async fn dump(&self) {
+        let inner = self.inner.read().await;
+        info!(
+            sl!(),
+            "memory: current {} MiB, requested {} MiB, balloon {} MiB, containers {:?}, reserved {} MiB ({})",
+            inner.current_mem_mb,
+            inner.requested_mem_mb,
+            inner.balloon_mb,
+            inner.container_mem_mb,
+            inner.reservations.total_mb(),
+            inner.reservations
+        );
+    }
+}
+
+// parse_mem_available_mb reads the memory available in the guest from the
+// metrics of the agent in the prometheus text format.
+fn parse_mem_available_mb(metrics: &str) -> Result<u64> {
+    let value = metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(GUEST_MEM_AVAILABLE))
+        .ok_or_else(|| anyhow!("guest metrics have no {}", GUEST_MEM_AVAILABLE))?;
+    let bytes = value
+        .trim()
+        .parse::<f64>()
+        .with_context(|| format!("parse {}", value.trim()))?;
+
+    Ok(bytes as u64 / MIB)
+}
+
+fn calc_mem_mb(linux_resources: Option<&LinuxResources>) -> u64 {
+    let limit = || -> Option<i64> { linux_resources?.memory.as_ref()?.limit }();
+
+    match limit {
+        Some(limit) if limit > 0 => limit as u64 / MIB,
+        _ => 0,
+    }
+}
+
+// align_mem_mb rounds the memory hotplugged on top of the boot memory up to
+// the next multiple of the granularity.
+fn align_mem_mb(boot_mem_mb: u64, target_mb: u64, granularity_mb: u64) -> u64 {
+    if target_mb <= boot_mem_mb || granularity_mb <= 1 {
+        return target_mb.max(boot_mem_mb);
+    }
+
+    let hotplug_mb = target_mb - boot_mem_mb;
+    boot_mem_mb + (hotplug_mb + granularity_mb - 1) / granularity_mb * granularity_mb
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_agent::MockAgent;
+    use hypervisor::mock::MockHypervisor;
+    use kata_types::capabilities::CapabilityBits;
+    use oci::LinuxMemory;
+
+    fn resources(limit_mb: i64) -> LinuxResources {
+        LinuxResources {
+            memory: Some(LinuxMemory {
+                limit: Some(limit_mb * MIB as i64),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_calc_mem_mb() {
+        let tests = [
+            (None, 0),
+            (Some(-1), 0),
+            (Some(0), 0),
+            (Some(512 * 1024 * 1024), 512),
+            (Some(512 * 1024 * 1024 + 1), 512),
+        ];
+
+        for (limit, expected) in tests {
+            let resources = LinuxResources {
+                memory: Some(LinuxMemory {
+                    limit,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            assert_eq!(calc_mem_mb(Some(&resources)), expected);
+        }
+        assert_eq!(calc_mem_mb(None), 0);
+    }
+
+    #[test]
+    fn test_target_mem_mb() {
+        let mem = MemResource {
+            boot_mem_mb: 256,
+            max_mem_mb: 4096,
+            ..Default::default()
+        };
+        let mut inner = MemResourceInner::default();
+
+        // create container a and b, then delete a
+        let steps = [
+            ("a", Some(resources(512)), 768),
+            ("b", Some(resources(1024)), 1792),
+            ("a", None, 1280),
+        ];
+        for (cid, resources, expected) in steps {
+            match resources {
+                Some(r) => inner
+                    .container_mem_mb
+                    .insert(cid.to_owned(), calc_mem_mb(Some(&r))),
+                None => inner.container_mem_mb.remove(cid),
+            };
+            assert_eq!(mem.target_mem_mb(&inner), expected);
+        }
+
+        // never below the boot memory
+        inner.container_mem_mb.clear();
+        assert_eq!(mem.target_mem_mb(&inner), 256);
+    }
+
+    #[tokio::test]
+    async fn test_reserve_container_mem() {
+        let mem = MemResource {
+            boot_mem_mb: 2048,
+            max_mem_mb: 4096,
+            ..Default::default()
+        };
+        mem.inner.write().await.reservations = MemReservations::new(1024);
+        let reservation = ContainerReservation {
+            shm_mb: Some(64),
+            empty_dir_mb: vec![("/cache".to_string(), 512)],
+        };
+        mem.reserve_container_mem("a", &reservation).await.unwrap();
+        assert_eq!(mem.target_mem_mb(&*mem.inner.read().await), 3648);
+
+        // beyond default_maxmemory with the breakdown
+        let reservation = ContainerReservation {
+            shm_mb: None,
+            empty_dir_mb: vec![("/scratch".to_string(), 1024)],
+        };
+        let err = mem
+            .reserve_container_mem("b", &reservation)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "memory 4672 MiB required by the sandbox exceeds default_maxmemory 4096 MiB: \
+             boot 2048 MiB, containers 0 MiB, DAX window 1024 MiB, shm 64 MiB, emptyDirs 1536 MiB"
+        );
+        assert_eq!(mem.target_mem_mb(&*mem.inner.read().await), 3648);
+
+        mem.release_container_mem("a").await;
+        assert_eq!(mem.target_mem_mb(&*mem.inner.read().await), 3072);
+    }
+
+    #[tokio::test]
+    async fn test_save_restore() {
+        let mut mem = MemResource {
+            boot_mem_mb: 2048,
+            max_mem_mb: 8192,
+            ..Default::default()
+        };
+        mem.set_workload_mem_mb(512);
+        {
+            let mut inner = mem.inner.write().await;
+            inner.current_mem_mb = 3072;
+            inner.requested_mem_mb = 3000;
+            inner.balloon_mb = 256;
+            inner.container_mem_mb.insert("a".to_owned(), 512);
+            inner.container_mem_mb.insert("b".to_owned(), 440);
+        }
+        let state = mem.save().await;
+        let state: MemState =
+            serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+
+        // the restored one starts from the boot memory of the config
+        let mut restored = MemResource {
+            boot_mem_mb: 1024,
+            max_mem_mb: 8192,
+            ..Default::default()
+        };
+        restored.restore(&state).await;
+        assert_eq!(restored.save().await, state);
+        assert_eq!(restored.current_mem_mb().await, 3072);
+        assert_eq!(restored.balloon_mb().await, 256);
+        // the containers still running keep their memory
+        assert_eq!(restored.target_mem_mb(&*restored.inner.read().await), 3000);
+    }
+
+    #[test]
+    fn test_reclaimable_mb() {
+        let mut mem = MemResource {
+            boot_mem_mb: 2048,
+            max_mem_mb: 4096,
+            reclaim_floor_mb: 128,
+            ..Default::default()
+        };
+        let mut inner = MemResourceInner {
+            current_mem_mb: 2048,
+            ..Default::default()
+        };
+        assert_eq!(mem.reclaimable_mb(&inner), 1920);
+
+        // the statically sized workload is left to the guest
+        mem.set_workload_mem_mb(1024);
+        assert_eq!(mem.reclaimable_mb(&inner), 896);
+
+        // so are the containers beyond it
+        inner.container_mem_mb.insert("a".to_owned(), 1536);
+        assert_eq!(mem.reclaimable_mb(&inner), 384);
+    }
+
+    #[test]
+    fn test_parse_mem_available_mb() {
+        let metrics = "# HELP kata_guest_meminfo Statistics about memory usage in the system.\n\
+                       # TYPE kata_guest_meminfo gauge\n\
+                       kata_guest_meminfo{item=\"mem_available\"} 1.073741824e+09\n\
+                       kata_guest_meminfo{item=\"mem_free\"} 536870912\n";
+        assert_eq!(parse_mem_available_mb(metrics).unwrap(), 1024);
+        assert!(parse_mem_available_mb("kata_guest_meminfo{item=\"mem_free\"} 1\n").is_err());
+        assert!(parse_mem_available_mb("kata_guest_meminfo{item=\"mem_available\"} x\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reclaim_free_memory() {
+        let h = MockHypervisor::new().with_capabilities(CapabilityBits::BalloonSupport);
+        let agent = MockAgent::new("3.2.0");
+        let mem = MemResource {
+            boot_mem_mb: 4096,
+            max_mem_mb: 4096,
+            reclaim_floor_mb: 128,
+            ..Default::default()
+        };
+        {
+            let mut inner = mem.inner.write().await;
+            inner.current_mem_mb = 4096;
+            // the sandbox container has no limit, as usual
+            inner.container_mem_mb.insert("sandbox".to_owned(), 0);
+            inner.container_mem_mb.insert("a".to_owned(), 1024);
+        }
+        let available = |mb: u64| format!("{} {}\n", GUEST_MEM_AVAILABLE, mb * MIB);
+
+        // only the free memory is taken back, the reclaim floor is left
+        agent.set_metrics(&available(1024));
+        mem.reclaim_free_memory("sandbox", &h, &agent)
+            .await
+            .unwrap();
+        assert_eq!(mem.balloon_mb().await, 896);
+
+        // the memory the balloon holds already counts as free
+        agent.set_metrics(&available(256));
+        mem.reclaim_free_memory("sandbox", &h, &agent)
+            .await
+            .unwrap();
+        assert_eq!(mem.balloon_mb().await, 1024);
+
+        // never beyond the memory of the containers
+        agent.set_metrics(&available(4000));
+        mem.reclaim_free_memory("sandbox", &h, &agent)
+            .await
+            .unwrap();
+        assert_eq!(mem.balloon_mb().await, 2944);
+
+        // a guest not reporting its memory keeps the balloon as it is
+        agent.set_metrics("");
+        assert!(mem
+            .reclaim_free_memory("sandbox", &h, &agent)
+            .await
+            .is_err());
+        assert_eq!(mem.balloon_mb().await, 2944);
+
+        // the balloon deflates for a container without a limit
+        mem.inner
+            .write()
+            .await
+            .container_mem_mb
+            .insert("b".to_owned(), 0);
+        agent.set_metrics(&available(4000));
+        mem.reclaim_free_memory("sandbox", &h, &agent)
+            .await
+            .unwrap();
+        assert_eq!(mem.balloon_mb().await, 0);
+
+        assert_eq!(
+            h.resizes(),
+            vec![
+                "set_balloon_size 896",
+                "set_balloon_size 1024",
+                "set_balloon_size 2944",
+                "set_balloon_size 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_align_mem_mb() {
+        use kata_types::capabilities::{ACPI_MEMORY_SLOT_SIZE_MB, VIRTIO_MEM_BLOCK_SIZE_MB};
+
+        let acpi = ACPI_MEMORY_SLOT_SIZE_MB as u64;
+        let virtio_mem = VIRTIO_MEM_BLOCK_SIZE_MB as u64;
+        // (boot, target, granularity, expected)
+        let tests = [
+            (2048, 2048, acpi, 2048),
+            (2048, 1024, acpi, 2048),
+            (2048, 2049, acpi, 2176),
+            (2048, 2175, acpi, 2176),
+            (2048, 2176, acpi, 2176),
+            (2048, 2177, acpi, 2304),
+            (2048, 3000, acpi, 3072),
+            (2000, 2001, acpi, 2128),
+            (2048, 2049, virtio_mem, 2050),
+            (2048, 2050, virtio_mem, 2050),
+            (2048, 2051, virtio_mem, 2052),
+            (2048, 3000, virtio_mem, 3000),
+            (2047, 3000, virtio_mem, 3001),
+            (2048, 3001, 1, 3001),
+            (2048, 3001, 0, 3001),
+        ];
+
+        for (boot, target, granularity, expected) in tests {
+            assert_eq!(
+                align_mem_mb(boot, target, granularity),
+                expected,
+                "boot {} target {} granularity {}",
+                boot,
+                target,
+                granularity
+            );
+        }
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+};
+
+use anyhow::{Context, Result};
+use kata_types::{config::hypervisor::Hypervisor as HypervisorConfig, mount};
+use nix::sys::statfs::statfs;
+use serde::{Deserialize, Serialize};
+
+use crate::volume::{is_shim_volume, ShmLimits};
+
+const MIB: u64 = 1024 * 1024;
+const PROC_MOUNTS: &str = "/proc/mounts";
+
+/// dax_window_mb returns the DAX window of virtio-fs in MiB, 0 without DAX.
+pub fn dax_window_mb(hv: &HypervisorConfig) -> u64 {
+    if hv.shared_fs.virtio_fs_is_dax {
+        hv.shared_fs.virtio_fs_cache_size as u64
+    } else {
+        0
+    }
+}
+
+/// ContainerReservation is the guest memory the volumes of a container
+/// take: its /dev/shm and its emptyDirs of the memory medium, which are
+/// tmpfs of the guest.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContainerReservation {
+    pub shm_mb: Option<u64>,
+    /// the size of the emptyDirs by their source
+    pub empty_dir_mb: Vec<(String, u64)>,
+}
+
+impl ContainerReservation {
+    pub(crate) fn new(spec: &oci::Spec, shm_limits: &ShmLimits) -> Result<Self> {
+        let mut reservation = Self::default();
+        let mut mounts = None;
+        for m in spec.mounts.iter() {
+            if is_shim_volume(m) {
+                reservation.shm_mb = Some(shm_limits.size(m)? / MIB);
+            } else if m.r#type == mount::KATA_EPHEMERAL_VOLUME_TYPE {
+                if mounts.is_none() {
+                    mounts = Some(
+                        std::fs::read_to_string(PROC_MOUNTS)
+                            .with_context(|| format!("read {}", PROC_MOUNTS))?,
+                    );
+                }
+                // without sizeLimit the tmpfs of the guest is only bounded by
+                // the guest memory, nothing is reserved for it
+                if !is_size_limited(mounts.as_deref().unwrap_or_default(), &m.source) {
+                    continue;
+                }
+                // the limit of the emptyDir is the size of its tmpfs on the
+                // host
+                let stat = statfs(m.source.as_str())
+                    .with_context(|| format!("statfs emptyDir {}", m.source))?;
+                let size = stat.blocks() as u64 * stat.block_size() as u64;
+                reservation
+                    .empty_dir_mb
+                    .push((m.source.clone(), size / MIB));
+            }
+        }
+        Ok(reservation)
+    }
+}
+
+// is_size_limited tells if the tmpfs of the emptyDir is mounted with a size
+// on the host, which kubelet only does for the sizeLimit of the emptyDir
+fn is_size_limited(mounts: &str, mount_point: &str) -> bool {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [_, path, _, options, ..] if *path == mount_point => Some(*options),
+                _ => None,
+            }
+        })
+        // the last one mounted over the others is the one seen
+        .last()
+        .map(|options| options.split(',').any(|opt| opt.starts_with("size=")))
+        .unwrap_or_default()
+}
+
+/// MemReservations is the guest memory the sandbox takes on top of the
+/// memory limits of the containers, by component.
+#[derive(Clone, Debug, Default)]
+pub struct MemReservations {
+    /// the DAX window of virtio-fs
+    dax_mb: u64,
+    /// the /dev/shm of each container, they share the one of the sandbox
+    shm_mb: HashMap<String, u64>,
+    /// the emptyDirs by their source, with the containers mounting them
+    empty_dir_mb: HashMap<String, (u64, HashSet<String>)>,
+}
+
+impl MemReservations {
+    pub fn new(dax_mb: u64) -> Self {
+        Self {
+            dax_mb,
+            ..Default::default()
+        }
+    }
+
+    /// add_container adds the reservation of the container, replacing the
+    /// one it had.
+    pub fn add_container(&mut self, cid: &str, reservation: &ContainerReservation) {
+        self.remove_container(cid);
+        if let Some(shm_mb) = reservation.shm_mb {
+            self.shm_mb.insert(cid.to_string(), shm_mb);
+        }
+        for (source, size_mb) in reservation.empty_dir_mb.iter() {
+            let (mb, cids) = self
+                .empty_dir_mb
+                .entry(source.clone())
+                .or_insert_with(|| (*size_mb, HashSet::new()));
+            *mb = (*mb).max(*size_mb);
+            cids.insert(cid.to_string());
+        }
+    }
+
+    /// remove_container removes the reservation of the container, the
+    /// emptyDirs are kept while another container mounts them.
+    pub fn remove_container(&mut self, cid: &str) {
+        self.shm_mb.remove(cid);
+        self.empty_dir_mb.retain(|_, (_, cids)| {
+            cids.remove(cid);
+            !cids.is_empty()
+        });
+    }
+
+    /// containers returns the reservation of each container, the way they
+    /// were added.
+    pub fn containers(&self) -> BTreeMap<String, ContainerReservation> {
+        let mut containers: BTreeMap<String, ContainerReservation> = BTreeMap::new();
+        for (cid, shm_mb) in self.shm_mb.iter() {
+            containers.entry(cid.clone()).or_default().shm_mb = Some(*shm_mb);
+        }
+        for (source, (mb, cids)) in self.empty_dir_mb.iter() {
+            for cid in cids {
+                containers
+                    .entry(cid.clone())
+                    .or_default()
+                    .empty_dir_mb
+                    .push((source.clone(), *mb));
+            }
+        }
+        for reservation in containers.values_mut() {
+            reservation.empty_dir_mb.sort();
+        }
+        containers
+    }
+
+    pub fn dax_mb(&self) -> u64 {
+        self.dax_mb
+    }
+
+    pub fn shm_mb(&self) -> u64 {
+        self.shm_mb.values().copied().max().unwrap_or_default()
+    }
+
+    pub fn empty_dir_mb(&self) -> u64 {
+        self.empty_dir_mb.values().map(|(mb, _)| mb).sum()
+    }
+
+    pub fn total_mb(&self) -> u64 {
+        self.dax_mb() + self.shm_mb() + self.empty_dir_mb()
+    }
+}
+
+impl fmt::Display for MemReservations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "DAX window {} MiB, shm {} MiB, emptyDirs {} MiB",
+            self.dax_mb(),
+            self.shm_mb(),
+            self.empty_dir_mb()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reservation(shm_mb: Option<u64>, empty_dirs: &[(&str, u64)]) -> ContainerReservation {
+        ContainerReservation {
+            shm_mb,
+            empty_dir_mb: empty_dirs
+                .iter()
+                .map(|(source, mb)| (source.to_string(), *mb))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_mem_reservations() {
+        let mut reservations = MemReservations::new(1024);
+        assert_eq!(reservations.total_mb(), 1024);
+
+        reservations.add_container("a", &reservation(Some(64), &[("/cache", 256)]));
+        // the emptyDir shared with a counts once, so does the shm
+        reservations.add_container(
+            "b",
+            &reservation(Some(128), &[("/cache", 256), ("/scratch", 512)]),
+        );
+        assert_eq!(reservations.shm_mb(), 128);
+        assert_eq!(reservations.empty_dir_mb(), 768);
+        assert_eq!(reservations.total_mb(), 1920);
+        assert_eq!(
+            reservations.to_string(),
+            "DAX window 1024 MiB, shm 128 MiB, emptyDirs 768 MiB"
+        );
+
+        // the emptyDir mounted by a is kept
+        reservations.remove_container("b");
+        assert_eq!(reservations.shm_mb(), 64);
+        assert_eq!(reservations.empty_dir_mb(), 256);
+
+        reservations.remove_container("a");
+        assert_eq!(reservations.total_mb(), 1024);
+    }
+
+    #[test]
+    fn test_containers() {
+        let mut reservations = MemReservations::new(0);
+        let a = reservation(Some(64), &[("/cache", 256)]);
+        let b = reservation(None, &[("/cache", 256), ("/scratch", 512)]);
+        reservations.add_container("a", &a);
+        reservations.add_container("b", &b);
+
+        // added again on restore, they add up the same
+        let containers = reservations.containers();
+        assert_eq!(containers.get("a"), Some(&a));
+        assert_eq!(containers.get("b"), Some(&b));
+        let mut restored = MemReservations::new(0);
+        for (cid, reservation) in containers.iter() {
+            restored.add_container(cid, reservation);
+        }
+        assert_eq!(restored.total_mb(), reservations.total_mb());
+    }
+
+    #[test]
+    fn test_is_size_limited() {
+        let mounts = "tmpfs /pods/a/volumes/kubernetes.io~empty-dir/cache tmpfs rw,relatime,size=262144k 0 0\n\
+                      tmpfs /pods/a/volumes/kubernetes.io~empty-dir/scratch tmpfs rw,relatime 0 0\n\
+                      tmpfs /pods/a/volumes/kubernetes.io~empty-dir/data tmpfs rw,size=1024k 0 0\n\
+                      tmpfs /pods/a/volumes/kubernetes.io~empty-dir/data tmpfs rw 0 0\n";
+        assert!(is_size_limited(
+            mounts,
+            "/pods/a/volumes/kubernetes.io~empty-dir/cache"
+        ));
+        // no sizeLimit
+        assert!(!is_size_limited(
+            mounts,
+            "/pods/a/volumes/kubernetes.io~empty-dir/scratch"
+        ));
+        // mounted over without a size
+        assert!(!is_size_limited(
+            mounts,
+            "/pods/a/volumes/kubernetes.io~empty-dir/data"
+        ));
+        assert!(!is_size_limited(mounts, "/pods/b"));
+    }
+
+    #[test]
+    fn test_dax_window_mb() {
+        let mut hv = HypervisorConfig::default();
+        hv.shared_fs.virtio_fs_cache_size = 1024;
+        assert_eq!(dax_window_mb(&hv), 0);
+        hv.shared_fs.virtio_fs_is_dax = true;
+        assert_eq!(dax_window_mb(&hv), 1024);
+    }
+}
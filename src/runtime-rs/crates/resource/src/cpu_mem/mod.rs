@@ -0,0 +1,11 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+pub mod cpu;
+pub mod initial_size;
+pub mod mem;
+pub mod mem_reservation;
+pub(crate) mod vcpu_pinning;
@@ -0,0 +1,198 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Result};
+use kata_types::cpu::CpuSet;
+use nix::{sched, unistd::Pid};
+
+pub(crate) const SYS_CPU_ONLINE: &str = "/sys/devices/system/cpu/online";
+
+/// online_cpus returns the CPUs online on the host.
+pub(crate) fn online_cpus(sys_cpu_online: &Path) -> Result<CpuSet> {
+    let content =
+        fs::read_to_string(sys_cpu_online).with_context(|| format!("read {:?}", sys_cpu_online))?;
+    CpuSet::from_str(content.trim()).with_context(|| format!("parse {:?}", sys_cpu_online))
+}
+
+/// validate_pinning checks the mapping of the guest vCPUs to the host CPUs:
+/// each vCPU is pinned once and exists, each host CPU is online and in the
+/// cpuset of the sandbox cgroup if it has one, the vCPU threads couldn't
+/// run on the others.
+pub(crate) fn validate_pinning(
+    mapping: &[(u32, u32)],
+    vcpus: &HashMap<u32, u32>,
+    online: &CpuSet,
+    sandbox_cpuset: Option<&CpuSet>,
+) -> Result<()> {
+    let mut pinned = HashSet::new();
+    for (vcpu, cpu) in mapping.iter() {
+        if !pinned.insert(*vcpu) {
+            return Err(anyhow!("vcpu {} is pinned more than once", vcpu));
+        }
+        if !vcpus.contains_key(vcpu) {
+            return Err(anyhow!("no vcpu {} in the sandbox", vcpu));
+        }
+        if !online.contains(cpu) {
+            return Err(anyhow!("host cpu {} of vcpu {} isn't online", cpu, vcpu));
+        }
+        if let Some(cpuset) = sandbox_cpuset.filter(|c| !c.is_empty()) {
+            if !cpuset.contains(cpu) {
+                return Err(anyhow!(
+                    "host cpu {} of vcpu {} isn't in the cpuset {:?} of the sandbox cgroup",
+                    cpu,
+                    vcpu,
+                    cpuset.iter().collect::<Vec<_>>()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// pinning_by_cpuset maps each guest vCPU to a host CPU of the cpuset, in
+/// order, if there are as many of them. Otherwise the vCPUs aren't pinned.
+pub(crate) fn pinning_by_cpuset(
+    vcpus: &HashMap<u32, u32>,
+    cpuset: &CpuSet,
+) -> Option<Vec<(u32, u32)>> {
+    if vcpus.is_empty() || vcpus.len() != cpuset.len() {
+        return None;
+    }
+    let mut ids: Vec<u32> = vcpus.keys().copied().collect();
+    ids.sort_unstable();
+    Some(ids.into_iter().zip(cpuset.iter().copied()).collect())
+}
+
+/// pin_threads sets the affinity of each thread to its host cpu, all of them
+/// or none: the ones pinned get their affinity back if one fails.
+pub(crate) fn pin_threads(pins: &[(u32, u32)]) -> Result<()> {
+    let mut pinned = vec![];
+    for (tid, cpu) in pins.iter().copied() {
+        let pid = Pid::from_raw(tid as i32);
+        let result = sched::sched_getaffinity(pid)
+            .with_context(|| format!("get affinity of thread {}", tid))
+            .and_then(|previous| {
+                set_affinity(tid, &[cpu])?;
+                Ok(previous)
+            });
+        match result {
+            Ok(previous) => pinned.push((pid, previous)),
+            Err(e) => {
+                for (pid, previous) in pinned.into_iter().rev() {
+                    if let Err(err) = sched::sched_setaffinity(pid, &previous) {
+                        warn!(
+                            sl!(),
+                            "couldn't restore affinity of thread {}: {:?}", pid, err
+                        );
+                    }
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// set_affinity lets the thread run on the host cpus.
+pub(crate) fn set_affinity(tid: u32, cpus: &[u32]) -> Result<()> {
+    let mut cpuset = sched::CpuSet::new();
+    for cpu in cpus {
+        cpuset
+            .set(*cpu as usize)
+            .with_context(|| format!("invalid host cpu {}", cpu))?;
+    }
+    sched::sched_setaffinity(Pid::from_raw(tid as i32), &cpuset)
+        .with_context(|| format!("set affinity of thread {} to cpus {:?}", tid, cpus))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_online_cpus() {
+        let dir = tempfile::tempdir().unwrap();
+        let online = dir.path().join("online");
+        fs::write(&online, "0-3,6\n").unwrap();
+        assert_eq!(
+            online_cpus(&online).unwrap(),
+            CpuSet::from(vec![0, 1, 2, 3, 6])
+        );
+        assert!(online_cpus(&dir.path().join("offline")).is_err());
+    }
+
+    #[test]
+    fn test_pinning_by_cpuset() {
+        let vcpus: HashMap<u32, u32> = vec![(1, 102), (0, 101)].into_iter().collect();
+        assert_eq!(
+            pinning_by_cpuset(&vcpus, &CpuSet::from_str("4,6").unwrap()),
+            Some(vec![(0, 4), (1, 6)])
+        );
+        assert_eq!(
+            pinning_by_cpuset(&vcpus, &CpuSet::from_str("4-6").unwrap()),
+            None
+        );
+        assert_eq!(pinning_by_cpuset(&HashMap::new(), &CpuSet::new()), None);
+    }
+
+    #[test]
+    fn test_pin_threads() {
+        let tid = nix::unistd::gettid().as_raw() as u32;
+        let previous = sched::sched_getaffinity(Pid::from_raw(0)).unwrap();
+        let cpu = (0..sched::CpuSet::count())
+            .find(|c| previous.is_set(*c).unwrap_or(false))
+            .unwrap() as u32;
+
+        // the thread pinned first gets its affinity back
+        assert!(pin_threads(&[(tid, cpu), (tid, sched::CpuSet::count() as u32)]).is_err());
+        assert_eq!(
+            sched::sched_getaffinity(Pid::from_raw(0)).unwrap(),
+            previous
+        );
+
+        pin_threads(&[(tid, cpu)]).unwrap();
+        let pinned = sched::sched_getaffinity(Pid::from_raw(0)).unwrap();
+        assert!(pinned.is_set(cpu as usize).unwrap());
+        sched::sched_setaffinity(Pid::from_raw(0), &previous).unwrap();
+    }
+
+    #[test]
+    fn test_validate_pinning() {
+        // the vcpus by their threads
+        let vcpus: HashMap<u32, u32> = vec![(0, 101), (1, 102)].into_iter().collect();
+        let online = CpuSet::from_str("0-7").unwrap();
+        let cpuset = CpuSet::from_str("2-3").unwrap();
+
+        validate_pinning(&[(0, 2), (1, 3)], &vcpus, &online, Some(&cpuset)).unwrap();
+        // no cpuset, or an empty one, doesn't restrict the host cpus
+        validate_pinning(&[(0, 6)], &vcpus, &online, None).unwrap();
+        validate_pinning(&[(0, 6)], &vcpus, &online, Some(&CpuSet::new())).unwrap();
+
+        for (mapping, err) in vec![
+            (vec![(0, 2), (0, 3)], "vcpu 0 is pinned more than once"),
+            (vec![(2, 2)], "no vcpu 2 in the sandbox"),
+            (vec![(0, 8)], "host cpu 8 of vcpu 0 isn't online"),
+            (
+                vec![(1, 4)],
+                "host cpu 4 of vcpu 1 isn't in the cpuset [2, 3] of the sandbox cgroup",
+            ),
+        ] {
+            assert_eq!(
+                validate_pinning(&mapping, &vcpus, &online, Some(&cpuset))
+                    .unwrap_err()
+                    .to_string(),
+                err
+            );
+        }
+    }
+}
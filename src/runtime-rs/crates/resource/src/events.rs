@@ -0,0 +1,278 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::Stream;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// the events kept for the subscribers, the oldest ones are overwritten
+// beyond it for the subscribers lagging behind
+const EVENTS_CAPACITY: usize = 256;
+
+/// ResourceEventKind is what happened to the resources of the sandbox.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type")]
+pub enum ResourceEventKind {
+    DeviceAttached {
+        container_id: String,
+        device_id: String,
+    },
+    DeviceDetached {
+        container_id: String,
+        device_id: String,
+    },
+    VolumeMounted {
+        container_id: String,
+        source: String,
+        destination: String,
+    },
+    VolumeUnmounted {
+        container_id: String,
+        source: String,
+        destination: String,
+    },
+    EndpointAdded {
+        name: String,
+        hw_addr: String,
+    },
+    EndpointRemoved {
+        name: String,
+    },
+    ContainerOom {
+        container_id: String,
+    },
+    /// the daemon of the share fs started, or exited while the sandbox runs
+    ShareFsHealthChanged {
+        healthy: bool,
+        reason: String,
+    },
+    CleanupCompleted,
+    /// the events overwritten before the subscriber got them, only seen by
+    /// the streams
+    Lagged {
+        dropped: u64,
+    },
+}
+
+/// ResourceEvent is an event of the resources of the sandbox, serialized
+/// as a flat json object with the type of the event.
+#[derive(Clone, Debug, Serialize)]
+pub struct ResourceEvent {
+    pub sandbox_id: String,
+    // milliseconds since the unix epoch
+    pub timestamp_ms: u64,
+    #[serde(flatten)]
+    pub kind: ResourceEventKind,
+}
+
+/// ResourceEvents broadcasts the events of the resources to the
+/// subscribers. Emitting never blocks the resource operations: nothing is
+/// kept without subscribers, and the oldest events are overwritten for the
+/// subscribers lagging behind, which count them as dropped.
+pub struct ResourceEvents {
+    sid: String,
+    sender: broadcast::Sender<ResourceEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl ResourceEvents {
+    pub fn new(sid: &str) -> Self {
+        let (sender, _) = broadcast::channel(EVENTS_CAPACITY);
+        Self {
+            sid: sid.to_string(),
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub(crate) fn emit(&self, kind: ResourceEventKind) {
+        // only fails without subscribers
+        let _ = self.sender.send(new_event(&self.sid, kind));
+    }
+
+    /// subscribe returns a subscriber of the events emitted from now on.
+    pub fn subscribe(&self) -> EventSubscriber {
+        EventSubscriber {
+            sid: self.sid.clone(),
+            receiver: self.sender.subscribe(),
+            dropped: self.dropped.clone(),
+        }
+    }
+
+    /// dropped returns the number of the events overwritten before the
+    /// subscribers got them, of all the subscribers.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn new_event(sid: &str, kind: ResourceEventKind) -> ResourceEvent {
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default();
+    ResourceEvent {
+        sandbox_id: sid.to_string(),
+        timestamp_ms,
+        kind,
+    }
+}
+
+pub struct EventSubscriber {
+    sid: String,
+    receiver: broadcast::Receiver<ResourceEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventSubscriber {
+    /// recv returns the next event, None once the events are closed with
+    /// the resource manager. The events overwritten are skipped.
+    pub async fn recv(&mut self) -> Option<ResourceEvent> {
+        loop {
+            match self.next().await? {
+                ResourceEvent {
+                    kind: ResourceEventKind::Lagged { .. },
+                    ..
+                } => continue,
+                event => return Some(event),
+            }
+        }
+    }
+
+    /// into_stream turns the subscriber into a stream of the events, which
+    /// yields a Lagged event in place of the events overwritten.
+    pub fn into_stream(self) -> impl Stream<Item = ResourceEvent> + Send + 'static {
+        futures::stream::unfold(self, |mut subscriber| async move {
+            subscriber.next().await.map(|event| (event, subscriber))
+        })
+    }
+
+    // next returns the next event, or a Lagged event if some were overwritten
+    async fn next(&mut self) -> Option<ResourceEvent> {
+        match self.receiver.recv().await {
+            Ok(event) => Some(event),
+            Err(RecvError::Lagged(n)) => {
+                warn!(
+                    sl!(),
+                    "resource event subscriber lagged, {} events dropped", n
+                );
+                self.dropped.fetch_add(n, Ordering::Relaxed);
+                Some(new_event(
+                    &self.sid,
+                    ResourceEventKind::Lagged { dropped: n },
+                ))
+            }
+            Err(RecvError::Closed) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    // a container create as the resource manager emits it, with more
+    // volumes than the subscriber keeps up with
+    async fn create_container(events: &ResourceEvents, volumes: usize) {
+        events.emit(ResourceEventKind::DeviceAttached {
+            container_id: "c1".to_string(),
+            device_id: "blk1".to_string(),
+        });
+        for i in 0..volumes {
+            events.emit(ResourceEventKind::VolumeMounted {
+                container_id: "c1".to_string(),
+                source: format!("/vol{}", i),
+                destination: format!("/data{}", i),
+            });
+            tokio::task::yield_now().await;
+        }
+        events.emit(ResourceEventKind::CleanupCompleted);
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let events = ResourceEvents::new("sid");
+        // nobody subscribes, nothing is kept
+        events.emit(ResourceEventKind::CleanupCompleted);
+
+        let mut subscriber = events.subscribe();
+        let collect = tokio::spawn(async move {
+            let mut received = vec![];
+            while let Some(event) = subscriber.recv().await {
+                received.push(event);
+            }
+            received
+        });
+        create_container(&events, 3).await;
+        drop(events);
+        let received = collect.await.unwrap();
+        assert_eq!(received.len(), 5);
+        assert_eq!(received[0].sandbox_id, "sid");
+        assert_eq!(
+            received[0].kind,
+            ResourceEventKind::DeviceAttached {
+                container_id: "c1".to_string(),
+                device_id: "blk1".to_string(),
+            }
+        );
+        assert_eq!(received[4].kind, ResourceEventKind::CleanupCompleted);
+
+        let json = serde_json::to_value(&received[1]).unwrap();
+        assert_eq!(json["type"], "VolumeMounted");
+        assert_eq!(json["source"], "/vol0");
+        assert_eq!(json["sandbox_id"], "sid");
+
+        // the subscriber not reading doesn't block the create, the oldest
+        // events are dropped and counted
+        let events = ResourceEvents::new("sid");
+        let mut subscriber = events.subscribe();
+        create_container(&events, EVENTS_CAPACITY + 8).await;
+        let first = subscriber.recv().await.unwrap();
+        assert!(matches!(
+            first.kind,
+            ResourceEventKind::VolumeMounted { .. }
+        ));
+        assert_eq!(events.dropped(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_event_streams() {
+        let events = ResourceEvents::new("sid");
+        let mut fast = Box::pin(events.subscribe().into_stream());
+        let slow = events.subscribe().into_stream();
+
+        // the fast stream keeps up, the slow one isn't read meanwhile
+        for i in 0..EVENTS_CAPACITY + 8 {
+            let kind = ResourceEventKind::ContainerOom {
+                container_id: format!("c{}", i),
+            };
+            events.emit(kind.clone());
+            assert_eq!(fast.next().await.unwrap().kind, kind);
+        }
+        drop(events);
+        assert!(fast.next().await.is_none());
+
+        // the slow one is told about the events it lost
+        let slow: Vec<ResourceEvent> = slow.collect().await;
+        assert_eq!(slow.len(), EVENTS_CAPACITY + 1);
+        assert_eq!(slow[0].kind, ResourceEventKind::Lagged { dropped: 8 });
+        assert_eq!(
+            slow[1].kind,
+            ResourceEventKind::ContainerOom {
+                container_id: "c8".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{collections::HashSet, fs, path::Path};
+
+use agent::{Agent, CopyFileRequest};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+
+use crate::{share_fs::DEFAULT_KATA_GUEST_SANDBOX_DIR, trace};
+
+// HOST_NAME_MAX of linux, sethostname fails with EINVAL beyond it
+const HOST_NAME_MAX: usize = 64;
+const ETC_HOSTS: &str = "/etc/hosts";
+// the files in the sandbox dir of the guest, the agent only writes there
+const GUEST_HOSTNAME_FILE: &str = "hostname";
+const GUEST_HOSTS_FILE: &str = "hosts";
+const FILE_MODE: u32 = 0o644;
+const DIR_MODE: u32 = 0o755;
+
+/// HostEntry is a line of the hosts file, the names the ip is known by.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostEntry {
+    pub ip: String,
+    pub names: Vec<String>,
+}
+
+/// HostnameConfig is the hostname of the pod and the entries of its hosts
+/// file, e.g. the host aliases, to be reflected by the guest.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostnameConfig {
+    pub hostname: String,
+    pub hosts: Vec<HostEntry>,
+}
+
+impl HostnameConfig {
+    /// new returns the hostname of the spec and the entries of the hosts
+    /// file mounted to /etc/hosts, none if neither of them is set.
+    pub fn new(spec: &oci::Spec) -> Result<Option<Self>> {
+        let hosts = match spec.mounts.iter().find(|m| m.destination == ETC_HOSTS) {
+            Some(m) => {
+                let content = fs::read_to_string(&m.source)
+                    .with_context(|| format!("read hosts file {}", m.source))?;
+                parse_hosts(&content)
+            }
+            None => vec![],
+        };
+        if spec.hostname.is_empty() && hosts.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            hostname: spec.hostname.clone(),
+            hosts,
+        }))
+    }
+}
+
+/// truncate_hostname returns the hostname cut to the limit of the kernel,
+/// on a character boundary.
+pub fn truncate_hostname(hostname: &str) -> &str {
+    if hostname.len() <= HOST_NAME_MAX {
+        return hostname;
+    }
+    let mut end = HOST_NAME_MAX;
+    while !hostname.is_char_boundary(end) {
+        end -= 1;
+    }
+    &hostname[..end]
+}
+
+// parse_hosts returns the entries of the hosts file, without the comments
+fn parse_hosts(content: &str) -> Vec<HostEntry> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let ip = fields.next()?;
+            Some(HostEntry {
+                ip: ip.to_string(),
+                names: fields.map(|n| n.to_string()).collect(),
+            })
+        })
+        .filter(|e| !e.names.is_empty())
+        .collect()
+}
+
+// dedup_hosts drops the names given to the same ip again, the entries left
+// without a name are dropped, the others are kept in order
+fn dedup_hosts(entries: &[HostEntry]) -> Vec<HostEntry> {
+    let mut seen = HashSet::new();
+    entries
+        .iter()
+        .filter_map(|e| {
+            let names: Vec<String> = e
+                .names
+                .iter()
+                .filter(|n| seen.insert((e.ip.clone(), n.to_string())))
+                .cloned()
+                .collect();
+            if names.is_empty() {
+                return None;
+            }
+            Some(HostEntry {
+                ip: e.ip.clone(),
+                names,
+            })
+        })
+        .collect()
+}
+
+fn render_hosts(entries: &[HostEntry]) -> String {
+    dedup_hosts(entries)
+        .iter()
+        .map(|e| format!("{}\t{}\n", e.ip, e.names.join(" ")))
+        .collect()
+}
+
+/// setup_hostname writes the hostname into the sandbox dir of the guest,
+/// it's truncated to the limit of the kernel with a warning.
+pub(crate) async fn setup_hostname(agent: &dyn Agent, hostname: &str) -> Result<()> {
+    let truncated = truncate_hostname(hostname);
+    if truncated.len() != hostname.len() {
+        warn!(
+            sl!(),
+            "hostname {} exceeds {} bytes, truncated to {}", hostname, HOST_NAME_MAX, truncated
+        );
+    }
+    copy_to_guest(agent, GUEST_HOSTNAME_FILE, format!("{}\n", truncated))
+        .await
+        .context("copy hostname")
+}
+
+/// setup_hosts writes the hosts file into the sandbox dir of the guest, the
+/// duplicate entries are dropped.
+pub(crate) async fn setup_hosts(agent: &dyn Agent, entries: &[HostEntry]) -> Result<()> {
+    copy_to_guest(agent, GUEST_HOSTS_FILE, render_hosts(entries))
+        .await
+        .context("copy hosts file")
+}
+
+// the file is replaced as a whole, so that it's written again as is after
+// a restore
+async fn copy_to_guest(agent: &dyn Agent, name: &str, content: String) -> Result<()> {
+    let path = Path::new(DEFAULT_KATA_GUEST_SANDBOX_DIR).join(name);
+    let data = content.into_bytes();
+    agent
+        .copy_file(CopyFileRequest {
+            path: path.display().to_string(),
+            file_size: data.len() as i64,
+            file_mode: FILE_MODE,
+            dir_mode: DIR_MODE,
+            data,
+            ..Default::default()
+        })
+        .instrument(trace::agent_span("copy_file", ""))
+        .await
+        .with_context(|| format!("copy {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_agent::MockAgent;
+
+    fn entry(ip: &str, names: &[&str]) -> HostEntry {
+        HostEntry {
+            ip: ip.to_string(),
+            names: names.iter().map(|n| n.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_truncate_hostname() {
+        assert_eq!(truncate_hostname("pod-1"), "pod-1");
+        let long = "a".repeat(70);
+        assert_eq!(truncate_hostname(&long).len(), HOST_NAME_MAX);
+        // never in the middle of a character
+        let long = format!("{}é", "a".repeat(63));
+        assert_eq!(truncate_hostname(&long), "a".repeat(63));
+    }
+
+    #[test]
+    fn test_hosts() {
+        let entries = parse_hosts(
+            "# Kubernetes-managed hosts file.\n\
+             127.0.0.1\tlocalhost\n\
+             \n\
+             10.0.0.5\tpod-1 # the pod\n\
+             10.0.0.9\tdb cache\n\
+             10.0.0.9\tcache\n\
+             10.0.0.7\tdb\n\
+             192.168.0.1\n",
+        );
+        assert_eq!(
+            entries,
+            vec![
+                entry("127.0.0.1", &["localhost"]),
+                entry("10.0.0.5", &["pod-1"]),
+                entry("10.0.0.9", &["db", "cache"]),
+                entry("10.0.0.9", &["cache"]),
+                entry("10.0.0.7", &["db"]),
+            ]
+        );
+
+        // the same name of another ip is kept, as the hosts file does
+        assert_eq!(
+            render_hosts(&entries),
+            "127.0.0.1\tlocalhost\n\
+             10.0.0.5\tpod-1\n\
+             10.0.0.9\tdb cache\n\
+             10.0.0.7\tdb\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_setup_hostname() {
+        let agent = MockAgent::new("3.2.0");
+        setup_hostname(&agent, "pod-1").await.unwrap();
+        setup_hosts(&agent, &[entry("10.0.0.5", &["pod-1"])])
+            .await
+            .unwrap();
+        assert_eq!(agent.calls(), vec!["copy_file", "copy_file"]);
+
+        agent.fail("copy_file", "no space left");
+        let err = setup_hostname(&agent, "pod-1").await.unwrap_err();
+        assert!(format!("{:#}", err).starts_with("copy hostname: "));
+    }
+
+    #[test]
+    fn test_hostname_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let hosts = dir.path().join("etc-hosts");
+        fs::write(&hosts, "10.0.0.5 pod-1\n").unwrap();
+
+        let mut spec = oci::Spec::default();
+        assert_eq!(HostnameConfig::new(&spec).unwrap(), None);
+
+        spec.hostname = "pod-1".to_string();
+        spec.mounts.push(oci::Mount {
+            destination: ETC_HOSTS.to_string(),
+            source: hosts.display().to_string(),
+            ..Default::default()
+        });
+        let config = HostnameConfig::new(&spec).unwrap().unwrap();
+        assert_eq!(config.hostname, "pod-1");
+        assert_eq!(config.hosts, vec![entry("10.0.0.5", &["pod-1"])]);
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+// the parameters whose value is a comma separated list, the lists asked by
+// several devices are merged rather than conflicting
+const LIST_PARAMS: [&str; 2] = ["vfio-pci.ids", "modprobe.blacklist"];
+
+// split_param splits "key=value" into its key and value, a flag has no value
+fn split_param(param: &str) -> (&str, Option<&str>) {
+    match param.split_once('=') {
+        Some((key, value)) => (key, Some(value)),
+        None => (param, None),
+    }
+}
+
+fn is_list_param(key: &str) -> bool {
+    LIST_PARAMS.contains(&key)
+}
+
+// list_items returns the items of the value of a list parameter
+fn list_items(value: Option<&str>) -> Vec<&str> {
+    value
+        .unwrap_or_default()
+        .split(',')
+        .filter(|i| !i.is_empty())
+        .collect()
+}
+
+/// merge_kernel_params returns the parameters to add to the kernel command
+/// line for the devices, in the order they're asked. requested is the device
+/// asking for a parameter and the parameter. The parameters asked several
+/// times are added once, and the items of the list parameters are merged.
+/// The ones the command line already has are left out, it fails if a
+/// parameter is given different values, by two devices or by a device and
+/// the command line.
+pub(crate) fn merge_kernel_params(
+    cmdline: &str,
+    requested: &[(String, String)],
+) -> Result<Vec<String>> {
+    let existing: HashMap<&str, Option<&str>> =
+        cmdline.split_whitespace().map(split_param).collect();
+
+    // the key of each parameter in order, with its value and who asked it
+    let mut merged: Vec<(&str, Option<String>, &str)> = vec![];
+    for (device, param) in requested {
+        let (key, value) = split_param(param);
+        let index = match merged.iter().position(|(k, _, _)| *k == key) {
+            Some(index) => index,
+            None => {
+                merged.push((key, value.map(|v| v.to_string()), device.as_str()));
+                continue;
+            }
+        };
+        let (_, merged_value, first) = &mut merged[index];
+        if merged_value.as_deref() == value {
+            continue;
+        }
+        if !is_list_param(key) {
+            return Err(anyhow!(
+                "kernel parameter {} of {} conflicts with {}={} of {}",
+                param,
+                device,
+                key,
+                merged_value.as_deref().unwrap_or_default(),
+                first
+            ));
+        }
+        let mut items = list_items(merged_value.as_deref());
+        for item in list_items(value) {
+            if !items.contains(&item) {
+                items.push(item);
+            }
+        }
+        *merged_value = Some(items.join(","));
+    }
+
+    let mut params = vec![];
+    for (key, value, device) in merged {
+        let param = match value.as_deref() {
+            Some(value) => format!("{}={}", key, value),
+            None => key.to_string(),
+        };
+        let current = match existing.get(key) {
+            Some(current) => *current,
+            None => {
+                params.push(param);
+                continue;
+            }
+        };
+        let covered = if is_list_param(key) {
+            let items = list_items(current);
+            list_items(value.as_deref())
+                .iter()
+                .all(|i| items.contains(i))
+        } else {
+            current == value.as_deref()
+        };
+        if !covered {
+            return Err(anyhow!(
+                "kernel parameter {} of {} conflicts with {}={} of the kernel command line",
+                param,
+                device,
+                key,
+                current.unwrap_or_default()
+            ));
+        }
+    }
+    Ok(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requested(params: &[(&str, &str)]) -> Vec<(String, String)> {
+        params
+            .iter()
+            .map(|(d, p)| (d.to_string(), p.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_kernel_params() {
+        // the duplicates are added once, the lists merged
+        let params = merge_kernel_params(
+            "console=hvc0 iommu=pt",
+            &requested(&[
+                ("eth0", "intel_iommu=on"),
+                ("eth0", "iommu=pt"),
+                ("eth0", "vfio-pci.ids=8086:1572"),
+                ("eth1", "intel_iommu=on"),
+                ("eth1", "vfio-pci.ids=8086:1572,15b3:1017"),
+                ("eth1", "nopti"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            vec![
+                "intel_iommu=on".to_string(),
+                "vfio-pci.ids=8086:1572,15b3:1017".to_string(),
+                "nopti".to_string(),
+            ]
+        );
+
+        // conflicting devices
+        let e = merge_kernel_params(
+            "",
+            &requested(&[("eth0", "intel_iommu=on"), ("eth1", "intel_iommu=off")]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "kernel parameter intel_iommu=off of eth1 conflicts with intel_iommu=on of eth0"
+        );
+
+        // conflicting with the command line
+        let e = merge_kernel_params("iommu=nopt", &requested(&[("eth0", "iommu=pt")])).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "kernel parameter iommu=pt of eth0 conflicts with iommu=nopt of the kernel command line"
+        );
+        assert!(merge_kernel_params(
+            "vfio-pci.ids=8086:1572",
+            &requested(&[("eth0", "vfio-pci.ids=15b3:1017")])
+        )
+        .is_err());
+        assert!(merge_kernel_params(
+            "vfio-pci.ids=8086:1572,15b3:1017",
+            &requested(&[("eth0", "vfio-pci.ids=15b3:1017")])
+        )
+        .unwrap()
+        .is_empty());
+    }
+}
@@ -12,21 +12,64 @@ extern crate slog;
 
 logging::logger_with_subsystem!(sl, "resource");
 
+pub mod agent_features;
+mod cdi;
 pub mod cgroups;
+pub mod cpu_mem;
+pub mod events;
+pub mod hostname;
+mod kernel_params;
+mod limits;
 pub mod manager;
 mod manager_inner;
+pub mod metrics;
+#[cfg(test)]
+mod mock_agent;
 pub mod network;
+pub mod overrides;
+mod path_jail;
+pub use path_jail::{is_path_violation, PathViolation};
+pub mod plan;
+mod pooled_vm;
+pub use pooled_vm::{is_pooled_vm_rejected, PooledVmRejected};
+mod quiesce;
+pub use quiesce::{is_quiesced_error, Quiesced};
 pub mod resource_persist;
 use network::NetworkConfig;
+mod rollback;
 pub mod rootfs;
 pub mod share_fs;
+pub mod sriov;
+pub mod swap;
+pub mod timings;
+mod trace;
 pub mod volume;
+pub mod vsock;
 pub use manager::ResourceManager;
 
+use cpu_mem::initial_size::InitialSizeManager;
+use hostname::HostnameConfig;
 use kata_types::config::hypervisor::SharedFsInfo;
+use std::collections::HashMap;
 
 #[derive(Debug)]
 pub enum ResourceConfig {
     Network(NetworkConfig),
     ShareFs(SharedFsInfo),
+    InitialSize(InitialSizeManager),
+    Hostname(HostnameConfig),
+    NetSysctls(HashMap<String, String>),
+}
+
+impl ResourceConfig {
+    /// name names the kind of the resource config.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResourceConfig::Network(_) => "network",
+            ResourceConfig::ShareFs(_) => "share fs",
+            ResourceConfig::InitialSize(_) => "initial size",
+            ResourceConfig::Hostname(_) => "hostname",
+            ResourceConfig::NetSysctls(_) => "net sysctls",
+        }
+    }
 }
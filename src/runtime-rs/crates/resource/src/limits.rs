@@ -0,0 +1,404 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{collections::HashMap, fmt, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use kata_types::config::Runtime;
+use serde::{Deserialize, Serialize};
+
+use crate::volume::VolumeState;
+
+/// LimitCounts is what the containers take against the limits, saved with
+/// the sandbox so that a restored one keeps counting them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LimitCounts {
+    /// the devices by container
+    #[serde(default)]
+    devices: HashMap<String, usize>,
+    /// the block devices of the volumes by container
+    #[serde(default)]
+    volume_devices: HashMap<String, usize>,
+    /// the volumes by container
+    #[serde(default)]
+    volumes: HashMap<String, usize>,
+    /// the guest mounts of the rootfs by container
+    #[serde(default)]
+    rootfs_mounts: HashMap<String, usize>,
+}
+
+impl LimitCounts {
+    /// from_volumes counts the volumes restored, and their block devices, for
+    /// a sandbox saved without the counts. The devices of the containers
+    /// weren't saved, they can't be counted.
+    pub(crate) fn from_volumes(volumes: &[VolumeState]) -> Self {
+        let mut counts = Self::default();
+        for v in volumes {
+            *counts.volumes.entry(v.cid.clone()).or_default() += 1;
+            if v.device_id.is_some() {
+                *counts.volume_devices.entry(v.cid.clone()).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    fn sandbox_devices(&self) -> usize {
+        self.devices.values().sum::<usize>() + self.volume_devices.values().sum::<usize>()
+    }
+}
+
+/// ResourceLimits caps the devices and the volumes a container asks for, and
+/// the devices of all the containers of the sandbox, so that a container
+/// can't exhaust the guest PCI slots or the mounts of the others. The counts
+/// are taken before anything is attached, and given back if the container
+/// fails to be created or is deleted. The guest mounts of the sandbox are
+/// checked once the volumes are set up, they're only known by then. 0 is no
+/// limit.
+#[derive(Default)]
+pub(crate) struct ResourceLimits {
+    max_devices_per_container: usize,
+    max_volumes_per_container: usize,
+    max_devices_per_sandbox: usize,
+    max_mounts_per_sandbox: usize,
+    counts: Mutex<LimitCounts>,
+}
+
+impl ResourceLimits {
+    pub(crate) fn new(runtime: &Runtime) -> Self {
+        Self {
+            max_devices_per_container: runtime.max_devices_per_container as usize,
+            max_volumes_per_container: runtime.max_volumes_per_container as usize,
+            max_devices_per_sandbox: runtime.max_devices_per_sandbox as usize,
+            max_mounts_per_sandbox: runtime.max_mounts_per_sandbox as usize,
+            counts: Mutex::new(LimitCounts::default()),
+        }
+    }
+
+    /// restore sets the limits of the config with the counts saved.
+    pub(crate) fn restore(runtime: &Runtime, counts: LimitCounts) -> Self {
+        let limits = Self::new(runtime);
+        *limits.counts.lock().unwrap() = counts;
+        limits
+    }
+
+    pub(crate) fn save(&self) -> LimitCounts {
+        self.counts.lock().unwrap().clone()
+    }
+
+    /// take_devices counts the devices of the container, it fails without
+    /// counting them if the container or the sandbox would be over its limit.
+    pub(crate) fn take_devices(&self, cid: &str, count: usize) -> Result<()> {
+        let mut counts = self.counts.lock().unwrap();
+        self.fit_devices(&counts, cid, count)?;
+        *counts.devices.entry(cid.to_string()).or_default() += count;
+        Ok(())
+    }
+
+    /// check_devices fails if the container or the sandbox would be over its
+    /// limit with the devices, without counting them.
+    pub(crate) fn check_devices(&self, cid: &str, count: usize) -> Result<()> {
+        self.fit_devices(&self.counts.lock().unwrap(), cid, count)
+    }
+
+    fn fit_devices(&self, counts: &LimitCounts, cid: &str, count: usize) -> Result<()> {
+        let container = counts.devices.get(cid).copied().unwrap_or_default() + count;
+        if self.max_devices_per_container != 0 && container > self.max_devices_per_container {
+            return Err(anyhow!(
+                "container {} asks for {} devices, over max_devices_per_container {}",
+                cid,
+                container,
+                self.max_devices_per_container
+            ));
+        }
+        let sandbox = counts.sandbox_devices() + count;
+        if self.max_devices_per_sandbox != 0 && sandbox > self.max_devices_per_sandbox {
+            return Err(anyhow!(
+                "sandbox would have {} devices with the {} of container {}, over max_devices_per_sandbox {}",
+                sandbox,
+                count,
+                cid,
+                self.max_devices_per_sandbox
+            ));
+        }
+        Ok(())
+    }
+
+    /// give_back_devices gives back the devices of the container which
+    /// failed to be attached.
+    pub(crate) fn give_back_devices(&self, cid: &str, count: usize) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(devices) = counts.devices.get_mut(cid) {
+            *devices = devices.saturating_sub(count);
+            if *devices == 0 {
+                counts.devices.remove(cid);
+            }
+        }
+    }
+
+    /// take_volume_devices counts the block devices the volumes of the
+    /// container attach, it fails without counting them if the sandbox would
+    /// be over its limit. They aren't devices the container asks for.
+    pub(crate) fn take_volume_devices(&self, cid: &str, count: usize) -> Result<()> {
+        let mut counts = self.counts.lock().unwrap();
+        let sandbox = counts.sandbox_devices() + count;
+        if self.max_devices_per_sandbox != 0 && sandbox > self.max_devices_per_sandbox {
+            return Err(anyhow!(
+                "sandbox would have {} devices with the {} of the volumes of container {}, over max_devices_per_sandbox {}",
+                sandbox,
+                count,
+                cid,
+                self.max_devices_per_sandbox
+            ));
+        }
+        *counts.volume_devices.entry(cid.to_string()).or_default() += count;
+        Ok(())
+    }
+
+    /// give_back_volume_devices gives back the block devices of the volumes
+    /// of the container which failed to be set up.
+    pub(crate) fn give_back_volume_devices(&self, cid: &str, count: usize) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(devices) = counts.volume_devices.get_mut(cid) {
+            *devices = devices.saturating_sub(count);
+            if *devices == 0 {
+                counts.volume_devices.remove(cid);
+            }
+        }
+    }
+
+    /// take_volumes counts the volumes of the container, it fails without
+    /// counting them if the container would be over its limit.
+    pub(crate) fn take_volumes(&self, cid: &str, count: usize) -> Result<()> {
+        let mut counts = self.counts.lock().unwrap();
+        self.fit_volumes(&counts, cid, count)?;
+        *counts.volumes.entry(cid.to_string()).or_default() += count;
+        Ok(())
+    }
+
+    /// check_volumes fails if the container would be over its limit with the
+    /// volumes, without counting them.
+    pub(crate) fn check_volumes(&self, cid: &str, count: usize) -> Result<()> {
+        self.fit_volumes(&self.counts.lock().unwrap(), cid, count)
+    }
+
+    fn fit_volumes(&self, counts: &LimitCounts, cid: &str, count: usize) -> Result<()> {
+        let container = counts.volumes.get(cid).copied().unwrap_or_default() + count;
+        if self.max_volumes_per_container != 0 && container > self.max_volumes_per_container {
+            return Err(anyhow!(
+                "container {} mounts {} volumes, over max_volumes_per_container {}",
+                cid,
+                container,
+                self.max_volumes_per_container
+            ));
+        }
+        Ok(())
+    }
+
+    /// give_back_volumes gives back the volumes of the container which
+    /// failed to be set up.
+    pub(crate) fn give_back_volumes(&self, cid: &str, count: usize) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(volumes) = counts.volumes.get_mut(cid) {
+            *volumes = volumes.saturating_sub(count);
+            if *volumes == 0 {
+                counts.volumes.remove(cid);
+            }
+        }
+    }
+
+    /// add_rootfs_mounts counts the guest mounts of the rootfs of the
+    /// container, the rootfs is never refused.
+    pub(crate) fn add_rootfs_mounts(&self, cid: &str, count: usize) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts.rootfs_mounts.entry(cid.to_string()).or_default() += count;
+    }
+
+    /// rootfs_mounts returns the guest mounts of the rootfs of all the
+    /// containers.
+    pub(crate) fn rootfs_mounts(&self) -> usize {
+        self.counts.lock().unwrap().rootfs_mounts.values().sum()
+    }
+
+    /// check_mounts fails if the guest mounts of the sandbox, with the ones
+    /// the volumes of the container just added, are over the limit.
+    pub(crate) fn check_mounts(&self, cid: &str, mounts: usize, added: usize) -> Result<()> {
+        if self.max_mounts_per_sandbox != 0 && mounts > self.max_mounts_per_sandbox {
+            return Err(anyhow!(
+                "sandbox would have {} guest mounts with the {} of the volumes of container {}, over max_mounts_per_sandbox {}",
+                mounts,
+                added,
+                cid,
+                self.max_mounts_per_sandbox
+            ));
+        }
+        Ok(())
+    }
+
+    /// remove_container gives back all the devices, the volumes and the
+    /// rootfs mounts of the container deleted.
+    pub(crate) fn remove_container(&self, cid: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.devices.remove(cid);
+        counts.volume_devices.remove(cid);
+        counts.volumes.remove(cid);
+        counts.rootfs_mounts.remove(cid);
+    }
+}
+
+impl fmt::Display for ResourceLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts = self.counts.lock().unwrap();
+        write!(
+            f,
+            "devices {}/{} in the sandbox, by container {:?} of {}, of the volumes by container {:?}, volumes by container {:?} of {}, rootfs mounts by container {:?}, max mounts {}",
+            counts.sandbox_devices(),
+            self.max_devices_per_sandbox,
+            counts.devices,
+            self.max_devices_per_container,
+            counts.volume_devices,
+            counts.volumes,
+            self.max_volumes_per_container,
+            counts.rootfs_mounts,
+            self.max_mounts_per_sandbox
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resource_limits() {
+        let runtime = Runtime {
+            max_devices_per_container: 4,
+            max_volumes_per_container: 8,
+            max_devices_per_sandbox: 6,
+            ..Default::default()
+        };
+        let limits = ResourceLimits::new(&runtime);
+
+        limits.take_devices("a", 3).unwrap();
+        assert_eq!(
+            limits.take_devices("a", 2).unwrap_err().to_string(),
+            "container a asks for 5 devices, over max_devices_per_container 4"
+        );
+        limits.take_devices("b", 3).unwrap();
+        // checked without being counted
+        limits.check_devices("c", 0).unwrap();
+        assert!(limits.check_devices("c", 1).is_err());
+        assert_eq!(
+            limits.take_devices("c", 1).unwrap_err().to_string(),
+            "sandbox would have 7 devices with the 1 of container c, over max_devices_per_sandbox 6"
+        );
+
+        // the devices of the container deleted are given back to the sandbox
+        limits.remove_container("b");
+        limits.take_devices("c", 1).unwrap();
+        limits.give_back_devices("c", 1);
+        limits.take_devices("d", 3).unwrap();
+
+        limits.check_volumes("a", 8).unwrap();
+        limits.take_volumes("a", 8).unwrap();
+        assert!(limits.check_volumes("a", 1).is_err());
+        assert_eq!(
+            limits.take_volumes("a", 1).unwrap_err().to_string(),
+            "container a mounts 9 volumes, over max_volumes_per_container 8"
+        );
+        limits.give_back_volumes("a", 8);
+        limits.take_volumes("a", 1).unwrap();
+
+        // no limit
+        let limits = ResourceLimits::new(&Runtime::default());
+        limits.take_devices("a", 1000).unwrap();
+        limits.take_volumes("a", 1000).unwrap();
+        limits.check_mounts("a", 100000, 1000).unwrap();
+    }
+
+    #[test]
+    fn test_volume_devices() {
+        let runtime = Runtime {
+            max_devices_per_container: 1,
+            max_devices_per_sandbox: 3,
+            ..Default::default()
+        };
+        let limits = ResourceLimits::new(&runtime);
+
+        // not against the limit of the container
+        limits.take_volume_devices("a", 2).unwrap();
+        limits.take_devices("a", 1).unwrap();
+        assert_eq!(
+            limits.take_volume_devices("b", 1).unwrap_err().to_string(),
+            "sandbox would have 4 devices with the 1 of the volumes of container b, over max_devices_per_sandbox 3"
+        );
+        assert!(limits.take_devices("b", 1).is_err());
+        limits.give_back_volume_devices("a", 1);
+        limits.take_devices("b", 1).unwrap();
+
+        limits.remove_container("a");
+        limits.take_volume_devices("b", 2).unwrap();
+    }
+
+    #[test]
+    fn test_restore_limits() {
+        let runtime = Runtime {
+            max_devices_per_sandbox: 2,
+            max_volumes_per_container: 2,
+            ..Default::default()
+        };
+        let limits = ResourceLimits::new(&runtime);
+        limits.take_devices("a", 1).unwrap();
+        limits.take_volume_devices("a", 1).unwrap();
+        limits.take_volumes("a", 2).unwrap();
+
+        // the limits of the config, with the counts taken before
+        let restored = ResourceLimits::restore(&runtime, limits.save());
+        assert!(restored.take_devices("b", 1).is_err());
+        assert!(restored.take_volumes("a", 1).is_err());
+
+        // saved without the counts, the volumes are counted again
+        let volumes = vec![
+            VolumeState {
+                cid: "a".to_string(),
+                source: "/dev/sdb".to_string(),
+                device_id: Some("blk1".to_string()),
+                size: None,
+            },
+            VolumeState {
+                cid: "a".to_string(),
+                source: "/data".to_string(),
+                device_id: None,
+                size: None,
+            },
+        ];
+        let restored = ResourceLimits::restore(&runtime, LimitCounts::from_volumes(&volumes));
+        assert!(restored.take_volumes("a", 1).is_err());
+        restored.take_devices("b", 1).unwrap();
+        assert!(restored.take_devices("b", 1).is_err());
+    }
+
+    #[test]
+    fn test_mount_limits() {
+        let runtime = Runtime {
+            max_mounts_per_sandbox: 10,
+            ..Default::default()
+        };
+        let limits = ResourceLimits::new(&runtime);
+
+        limits.add_rootfs_mounts("a", 2);
+        limits.add_rootfs_mounts("b", 1);
+        assert_eq!(limits.rootfs_mounts(), 3);
+        limits.check_mounts("a", 10, 4).unwrap();
+        assert_eq!(
+            limits.check_mounts("b", 11, 5).unwrap_err().to_string(),
+            "sandbox would have 11 guest mounts with the 5 of the volumes of container b, over max_mounts_per_sandbox 10"
+        );
+
+        // the rootfs of the container deleted isn't mounted anymore
+        limits.remove_container("a");
+        assert_eq!(limits.rootfs_mounts(), 1);
+    }
+}
@@ -4,20 +4,31 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use crate::cgroups::CgroupReconciliation;
+use crate::events::{EventSubscriber, ResourceEvent, ResourceEventKind, ResourceEvents};
 use crate::network::NetworkConfig;
-use crate::resource_persist::ResourceState;
-use crate::{manager_inner::ResourceManagerInner, rootfs::Rootfs, volume::Volume, ResourceConfig};
+use crate::plan::PlanReport;
+use crate::resource_persist::{Inconsistency, ResourceState};
+use crate::timings::{TimingSpan, Timings};
+use crate::vsock::{VsockAllocation, VsockBackend};
+use crate::{
+    manager_inner::ResourceManagerInner,
+    rootfs::Rootfs,
+    volume::{Volume, VolumeStats},
+    ResourceConfig,
+};
 use agent::types::Device;
 use agent::{Agent, Storage};
 use anyhow::Result;
 use async_trait::async_trait;
-use hypervisor::device::device_manager::DeviceManager;
+use futures::Stream;
+use hypervisor::device::device_manager::{DeviceManager, MigrationDeviceState};
 use hypervisor::Hypervisor;
 use kata_types::config::TomlConfig;
 use kata_types::mount::Mount;
-use oci::{Linux, LinuxResources};
+use oci::{Linux, LinuxCapabilities, LinuxDevice, LinuxResources};
 use persist::sandbox_persist::Persist;
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 use tokio::sync::RwLock;
 
 pub struct ManagerArgs {
@@ -29,6 +40,10 @@ pub struct ManagerArgs {
 
 pub struct ResourceManager {
     inner: Arc<RwLock<ResourceManagerInner>>,
+    // kept out of the lock, so that the timings could be queried and the
+    // events subscribed while the resources are being set up
+    timings: Arc<Timings>,
+    events: Arc<ResourceEvents>,
 }
 
 impl ResourceManager {
@@ -38,13 +53,11 @@ impl ResourceManager {
         hypervisor: Arc<dyn Hypervisor>,
         toml_config: Arc<TomlConfig>,
     ) -> Result<Self> {
+        let inner = ResourceManagerInner::new(sid, agent, hypervisor, toml_config)?;
         Ok(Self {
-            inner: Arc::new(RwLock::new(ResourceManagerInner::new(
-                sid,
-                agent,
-                hypervisor,
-                toml_config,
-            )?)),
+            timings: inner.timings(),
+            events: inner.events(),
+            inner: Arc::new(RwLock::new(inner)),
         })
     }
 
@@ -53,6 +66,11 @@ impl ResourceManager {
         inner.config()
     }
 
+    pub async fn is_share_fs_enabled(&self) -> bool {
+        let inner = self.inner.read().await;
+        inner.is_share_fs_enabled()
+    }
+
     pub async fn get_device_manager(&self) -> Arc<RwLock<DeviceManager>> {
         let inner = self.inner.read().await;
         inner.get_device_manager()
@@ -65,7 +83,7 @@ impl ResourceManager {
 
     pub async fn handle_network(&self, network_config: NetworkConfig) -> Result<()> {
         let mut inner = self.inner.write().await;
-        inner.handle_network(network_config).await
+        inner.handle_network(network_config, false).await
     }
 
     pub async fn setup_after_start_vm(&self) -> Result<()> {
@@ -73,21 +91,32 @@ impl ResourceManager {
         inner.setup_after_start_vm().await
     }
 
+    pub async fn reapply_network(&self) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.reapply_network().await
+    }
+
     pub async fn get_storage_for_sandbox(&self) -> Result<Vec<Storage>> {
         let inner = self.inner.read().await;
         inner.get_storage_for_sandbox().await
     }
 
+    pub async fn get_storage_for_container(&self, cid: &str) -> Result<Vec<Storage>> {
+        let inner = self.inner.read().await;
+        inner.get_storage_for_container(cid).await
+    }
+
     pub async fn handler_rootfs(
         &self,
         cid: &str,
         root: &oci::Root,
         bundle_path: &str,
         rootfs_mounts: &[Mount],
+        annotations: &HashMap<String, String>,
     ) -> Result<Arc<dyn Rootfs>> {
         let inner = self.inner.read().await;
         inner
-            .handler_rootfs(cid, root, bundle_path, rootfs_mounts)
+            .handler_rootfs(cid, root, bundle_path, rootfs_mounts, annotations)
             .await
     }
 
@@ -100,9 +129,72 @@ impl ResourceManager {
         inner.handler_volumes(cid, spec).await
     }
 
-    pub async fn handler_devices(&self, cid: &str, linux: &Linux) -> Result<Vec<Device>> {
+    pub async fn fallback_volumes(
+        &self,
+        cid: &str,
+        annotations: &HashMap<String, String>,
+        error: &str,
+    ) -> Result<Option<Vec<Arc<dyn Volume>>>> {
+        let inner = self.inner.read().await;
+        inner.fallback_volumes(cid, annotations, error).await
+    }
+
+    pub async fn handler_devices(
+        &self,
+        cid: &str,
+        linux: &mut Linux,
+        annotations: &HashMap<String, String>,
+        capabilities: Option<&LinuxCapabilities>,
+    ) -> Result<Vec<Device>> {
+        let inner = self.inner.read().await;
+        inner
+            .handler_devices(cid, linux, annotations, capabilities)
+            .await
+    }
+
+    pub async fn handler_devices_bulk(
+        &self,
+        cid: &str,
+        devices: &[LinuxDevice],
+        capabilities: Option<&LinuxCapabilities>,
+    ) -> Result<Vec<Device>> {
+        let inner = self.inner.read().await;
+        inner.handler_devices_bulk(cid, devices, capabilities).await
+    }
+
+    pub async fn verify(&self) -> Result<Vec<Inconsistency>> {
+        let inner = self.inner.read().await;
+        inner.verify().await
+    }
+
+    pub async fn ready(&self) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.ready().await
+    }
+
+    pub async fn volume_stats(&self, volume_path: &str) -> Result<VolumeStats> {
+        let inner = self.inner.read().await;
+        inner.volume_stats(volume_path).await
+    }
+
+    pub async fn wait_share_fs_ready(&self, timeout: Duration) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.wait_share_fs_ready(timeout).await
+    }
+
+    pub async fn non_migratable_volumes(&self) -> Vec<(String, String)> {
+        let inner = self.inner.read().await;
+        inner.non_migratable_volumes().await
+    }
+
+    pub async fn detach_all_for_migration(&self) -> Result<MigrationDeviceState> {
         let inner = self.inner.read().await;
-        inner.handler_devices(cid, linux).await
+        inner.detach_all_for_migration().await
+    }
+
+    pub async fn reattach_from_migration(&self, state: MigrationDeviceState) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.reattach_from_migration(state).await
     }
 
     pub async fn dump(&self) {
@@ -110,6 +202,30 @@ impl ResourceManager {
         inner.dump().await
     }
 
+    pub async fn validate_plan(&self, spec: &oci::Spec, rootfs_mounts: &[Mount]) -> PlanReport {
+        let inner = self.inner.read().await;
+        inner.validate_plan(spec, rootfs_mounts).await
+    }
+
+    pub async fn guest_mount_count(&self) -> usize {
+        let inner = self.inner.read().await;
+        inner.guest_mount_count().await
+    }
+
+    pub async fn handler_oom_score_adj(
+        &self,
+        cid: &str,
+        process: Option<&mut oci::Process>,
+    ) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.handler_oom_score_adj(cid, process)
+    }
+
+    pub async fn handler_block_io(&self, cid: &str, resources: Option<&mut LinuxResources>) {
+        let inner = self.inner.read().await;
+        inner.handler_block_io(cid, resources).await
+    }
+
     pub async fn update_cgroups(
         &self,
         cid: &str,
@@ -119,6 +235,102 @@ impl ResourceManager {
         inner.update_cgroups(cid, linux_resources).await
     }
 
+    pub async fn resync_cgroup_threads(&self) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.resync_cgroup_threads().await
+    }
+
+    pub async fn reconcile_cgroups(&self) -> Result<CgroupReconciliation> {
+        let inner = self.inner.read().await;
+        inner.reconcile_cgroups().await
+    }
+
+    pub async fn pin_vcpus(&self, mapping: Vec<(u32, u32)>) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.pin_vcpus(mapping).await
+    }
+
+    pub async fn network_namespace(&self) -> Option<String> {
+        let inner = self.inner.read().await;
+        inner.network_namespace().await
+    }
+
+    pub async fn delete_container_resources(&self, cid: &str) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.delete_container_resources(cid).await
+    }
+
+    pub async fn remove_volume(&self, cid: &str, volume_source: &str) -> Result<Arc<dyn Volume>> {
+        let inner = self.inner.read().await;
+        inner.remove_volume(cid, volume_source).await
+    }
+
+    pub async fn resize_direct_volume(&self, volume_path: &str, new_size: u64) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.resize_direct_volume(volume_path, new_size).await
+    }
+
+    pub async fn allocate_vsock(&self, backend: VsockBackend) -> Result<VsockAllocation> {
+        let inner = self.inner.read().await;
+        inner.allocate_vsock(backend).await
+    }
+
+    pub async fn set_balloon_target(&self, bytes: u64) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.set_balloon_target(bytes).await
+    }
+
+    pub async fn reclaim_memory(&self, target_mb: u64) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.reclaim_memory(target_mb).await
+    }
+
+    pub async fn reclaim_free_memory(&self) -> Result<()> {
+        let inner = self.inner.read().await;
+        inner.reclaim_free_memory().await
+    }
+
+    pub fn timings(&self) -> Vec<TimingSpan> {
+        self.timings.spans()
+    }
+
+    /// subscribe_events subscribes to the events of the resources, e.g. the
+    /// devices attached and the volumes mounted.
+    pub fn subscribe_events(&self) -> EventSubscriber {
+        self.events.subscribe()
+    }
+
+    /// subscribe returns a stream of the events of the resources, e.g. the
+    /// devices attached, the network changes and the OOMs. A subscriber
+    /// lagging behind gets a Lagged event in place of the events it lost.
+    pub async fn subscribe(&self) -> impl Stream<Item = ResourceEvent> + Send {
+        let inner = self.inner.read().await;
+        inner.subscribe()
+    }
+
+    /// report_oom tells the subscribers a container ran out of memory, as
+    /// the agent reported it.
+    pub fn report_oom(&self, cid: &str) {
+        self.events.emit(ResourceEventKind::ContainerOom {
+            container_id: cid.to_string(),
+        });
+    }
+
+    pub async fn quiesce(&self) {
+        let inner = self.inner.read().await;
+        inner.quiesce().await
+    }
+
+    pub async fn resume(&self) {
+        let inner = self.inner.read().await;
+        inner.resume()
+    }
+
+    pub async fn is_quiesced(&self) -> bool {
+        let inner = self.inner.read().await;
+        inner.is_quiesced()
+    }
+
     pub async fn cleanup(&self) -> Result<()> {
         let inner = self.inner.read().await;
         inner.cleanup().await
@@ -143,6 +355,8 @@ impl Persist for ResourceManager {
     ) -> Result<Self> {
         let inner = ResourceManagerInner::restore(resource_args, resource_state).await?;
         Ok(Self {
+            timings: inner.timings(),
+            events: inner.events(),
             inner: Arc::new(RwLock::new(inner)),
         })
     }
@@ -4,36 +4,140 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::{sync::Arc, thread, vec};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs,
+    future::Future,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+    vec,
+};
 
-use crate::{network::NetworkConfig, resource_persist::ResourceState};
-use agent::{types::Device, Agent, Storage};
+use crate::{
+    network::{EndpointState, NetworkConfig},
+    resource_persist::{Inconsistency, ResourceState},
+};
+use agent::{
+    is_busy_error, is_unsupported_error, types::Device, Agent, Interface, KernelModule,
+    LoadKernelModulesRequest, RemoveStorageRequest, ResizeVolumeRequest, Storage,
+};
 use anyhow::{anyhow, Context, Ok, Result};
 use async_trait::async_trait;
+use byte_unit::Byte;
+use futures::{future, Stream};
 
 use hypervisor::{
     device::{
-        device_manager::{do_handle_device, DeviceManager},
+        device_manager::{DeviceManager, MigrationDeviceState},
+        util::get_host_path,
         DeviceConfig, DeviceType,
     },
-    BlockConfig, Hypervisor,
+    BlockConfig, GuestProtection, Hypervisor, PciTopology, VfioBusMode, VfioConfig, HUGETLBFS,
+    VFIO_PCI,
+};
+use kata_types::capabilities::Capabilities;
+use kata_types::config::{
+    hypervisor::SharedFsInfo, validate_violations, RetryPolicy, Runtime, TomlConfig, Violation,
+    DEVICE_CLASS_BLOCK, DEVICE_CLASS_CHAR, DEVICE_CLASS_VFIO, MISSING_DEVICE_POLICY_SKIP,
 };
-use kata_types::config::TomlConfig;
+use kata_types::device::DeviceNodeAttrs;
+use kata_types::k8s::is_watchable_mount;
 use kata_types::mount::Mount;
-use oci::{Linux, LinuxResources};
+use nix::{errno::Errno, sys::stat};
+use oci::{Linux, LinuxCapabilities, LinuxDevice, LinuxDeviceCgroup, LinuxResources};
 use persist::sandbox_persist::Persist;
-use tokio::{runtime, sync::RwLock};
+use shim_interface::KATA_PATH;
+use tokio::{
+    runtime,
+    sync::{oneshot, Mutex, RwLock},
+    time,
+};
+use tracing::{Instrument, Span};
 
 use crate::{
-    cgroups::{CgroupArgs, CgroupsResource},
+    agent_features::{AgentFeature, AgentFeatures},
+    cdi,
+    cgroups::{CgroupArgs, CgroupReconciliation, CgroupsResource},
+    cpu_mem::{
+        cpu::CpuResource,
+        initial_size::InitialSizeManager,
+        mem::MemResource,
+        mem_reservation::{dax_window_mb, ContainerReservation},
+        vcpu_pinning,
+    },
+    events::{ResourceEvent, ResourceEventKind, ResourceEvents},
+    hostname::{self, HostnameConfig},
+    kernel_params,
+    limits::{LimitCounts, ResourceLimits},
     manager::ManagerArgs,
+    metrics,
     network::{self, Network},
-    rootfs::{RootFsResource, Rootfs},
-    share_fs::{self, sandbox_bind_mounts::SandboxBindMounts, ShareFs},
-    volume::{Volume, VolumeResource},
+    path_jail::PathJail,
+    plan::{self, PlanReport, PlannedResource},
+    pooled_vm::{self, VmState},
+    quiesce::QuiesceGate,
+    rollback,
+    rootfs::{self, RootFsResource, Rootfs, UpperConfig, UpperStorage},
+    share_fs::{
+        self,
+        sandbox_bind_mounts::{effective_bind_mounts, SandboxBindMounts},
+        ContainerExports, ShareFs,
+    },
+    sriov::{self, SriovResource},
+    swap::{self, SwapResource},
+    timings::{self, Timings},
+    trace,
+    volume::{self, Volume, VolumeNotFound, VolumeResource, VolumeStats},
+    vsock::{VsockAllocation, VsockAllocator, VsockBackend},
     ResourceConfig,
 };
 
+const DEFAULT_RESOURCE_SETUP_TIMEOUT: Duration = Duration::from_secs(60);
+// the block devices a privileged container is allowed to attach, beyond the
+// pci slots left for hotplug
+const MAX_PRIVILEGED_BLOCK_DEVICES: usize = 16;
+const OOM_SCORE_ADJ_MIN: i32 = -1000;
+const OOM_SCORE_ADJ_MAX: i32 = 1000;
+// OOMScoreAdjust of the agent service in the guest
+const AGENT_OOM_SCORE_ADJ: i32 = -997;
+// how long the readiness of the resources is checked at most
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+// check the readiness again and again until READY_TIMEOUT, quickly at first
+const DEFAULT_READY_RETRY_POLICY: RetryPolicy = RetryPolicy {
+    max_attempts: u32::MAX,
+    base_delay_ms: 10,
+    max_delay_ms: 500,
+    jitter: 0,
+};
+// IFF_UP of the flags of the interfaces
+const IFF_UP: u32 = 0x1;
+// the container planned isn't created, nothing is counted for it yet
+const PLAN_CID: &str = "planned";
+// the devices of the host by their major:minor
+const SYS_DEV: &str = "/sys/dev";
+// the devices of the host by their IOMMU group
+const SYS_IOMMU_GROUPS: &str = "/sys/kernel/iommu_groups";
+// the huge pages of the host by their size
+const SYS_HUGEPAGES: &str = "/sys/kernel/mm/hugepages";
+// the drivers the devices of an IOMMU group may be bound to for the group to
+// be passed through, the bridges are left to their host driver
+const VFIO_GROUP_DRIVERS: &[&str] = &["vfio-pci", "pcieport"];
+
+// the steps setting up the host resources before the VM starts
+#[derive(Debug)]
+pub(crate) enum SetupStep {
+    ShareFs,
+    ContainerExports,
+    SandboxBindMounts,
+    Network,
+}
+
 pub(crate) struct ResourceManagerInner {
     sid: String,
     toml_config: Arc<TomlConfig>,
@@ -42,10 +146,62 @@ pub(crate) struct ResourceManagerInner {
     device_manager: Arc<RwLock<DeviceManager>>,
     network: Option<Arc<dyn Network>>,
     share_fs: Option<Arc<dyn ShareFs>>,
+    // the virtio-fs exports of the containers with per_container_sharefs,
+    // the share fs of the sandbox keeps the sandbox bind mounts
+    container_exports: Option<Arc<ContainerExports>>,
+    // the sizing of the sandbox when static resource management is enabled
+    initial_size: Option<InitialSizeManager>,
+    // the endpoints saved before restore, the network isn't restored yet
+    restored_endpoints: Vec<EndpointState>,
+    // the netns saved before restore
+    restored_netns_path: Option<String>,
+    // the swap device of the guest when enable_guest_swap is set
+    swap: Option<SwapResource>,
+    // the hostname and the hosts file of the pod, if it sets them
+    hostname: Option<HostnameConfig>,
+    // the network sysctls of the pod, applied in the guest
+    net_sysctls: HashMap<String, String>,
+    // the sandbox bind mounts of the config and of the pod annotation, set up
+    // with the share fs
+    sandbox_bind_mounts: Vec<String>,
+    // the timings of the resource setup phases
+    timings: Arc<Timings>,
+    // the events of the resources for the subscribers out of the shim
+    events: Arc<ResourceEvents>,
+    // nothing is shared with the guest from the host, e.g. with the remote
+    // hypervisors, the hypervisor supports neither fs sharing nor block devices
+    no_host_sharing: bool,
+    // the hardware protection of the guest from the host, nothing is shared
+    // from the host with the protected guests
+    guest_protection: GuestProtection,
+    // the vsock cid of the guest and the ports of its channels
+    vsock: VsockAllocator,
+    // the VM is cold booted, or running already from a pool and the devices
+    // are hotplugged into it
+    vm_state: VmState,
+    // set for the resources created by this process, which are torn down if
+    // they're dropped without cleanup. It's cleared once cleanup succeeded,
+    // and never set for the restored ones, which the sandbox still uses
+    // after the process is gone, nor in the tests
+    teardown_armed: AtomicBool,
+    // the device classes whose guest kernel modules are loaded already
+    loaded_device_classes: Mutex<HashSet<&'static str>>,
+    // what the agent supports, asked once the VM is started
+    agent_features: AgentFeatures,
+    // refuses the resource mutations while quiesced
+    quiesce_gate: QuiesceGate,
+    // the SR-IOV VFs passed through to the guest by container
+    sriov_resource: SriovResource,
+    // the devices and the volumes the containers take against their limits
+    limits: ResourceLimits,
+    // the host prefixes the mount sources must stay under
+    path_jail: PathJail,
 
     pub rootfs_resource: RootFsResource,
     pub volume_resource: VolumeResource,
     pub cgroups_resource: CgroupsResource,
+    pub cpu_resource: CpuResource,
+    pub mem_resource: MemResource,
 }
 
 impl ResourceManagerInner {
@@ -55,11 +211,25 @@ impl ResourceManagerInner {
         hypervisor: Arc<dyn Hypervisor>,
         toml_config: Arc<TomlConfig>,
     ) -> Result<Self> {
+        validate_config(toml_config.check_consistency(), &toml_config)?;
+        trace::set_enabled(toml_config.runtime.enable_tracing);
+        metrics::init(sid, toml_config.runtime.metrics_sandbox_id_label);
+        share_fs::set_host_shared_root(sid, &toml_config.runtime.shared_dir_root);
         let cgroups_resource = CgroupsResource::new(sid, &toml_config)?;
+        let cpu_resource = CpuResource::new(&toml_config);
+        let mem_resource = MemResource::new(&toml_config);
+        if toml_config.runtime.privileged_sandbox {
+            warn!(
+                sl!(),
+                "sandbox {} is privileged, privileged containers get the host devices", sid
+            );
+        }
 
         // create device manager
         let dev_manager =
             DeviceManager::new(hypervisor.clone()).context("failed to create device manager")?;
+        let limits = ResourceLimits::new(&toml_config.runtime);
+        let path_jail = PathJail::new(&toml_config.runtime);
 
         Ok(Self {
             sid: sid.to_string(),
@@ -69,9 +239,32 @@ impl ResourceManagerInner {
             device_manager: Arc::new(RwLock::new(dev_manager)),
             network: None,
             share_fs: None,
+            container_exports: None,
+            initial_size: None,
+            restored_endpoints: vec![],
+            restored_netns_path: None,
+            swap: None,
+            hostname: None,
+            net_sysctls: HashMap::new(),
+            sandbox_bind_mounts: vec![],
+            timings: Arc::new(Timings::new(sid)),
+            events: Arc::new(ResourceEvents::new(sid)),
+            vsock: VsockAllocator::new(sid),
+            no_host_sharing: false,
+            guest_protection: GuestProtection::NoProtection,
+            vm_state: VmState::ColdBoot,
+            teardown_armed: AtomicBool::new(!cfg!(test)),
+            loaded_device_classes: Mutex::new(HashSet::new()),
+            agent_features: AgentFeatures::default(),
+            quiesce_gate: QuiesceGate::new(),
+            sriov_resource: SriovResource::new(sid),
+            limits,
+            path_jail,
             rootfs_resource: RootFsResource::new(),
             volume_resource: VolumeResource::new(),
             cgroups_resource,
+            cpu_resource,
+            mem_resource,
         })
     }
 
@@ -83,47 +276,311 @@ impl ResourceManagerInner {
         self.device_manager.clone()
     }
 
+    /// is_share_fs_enabled tells if the fs sharing is set up for the sandbox,
+    /// the rootfs and the volumes are passed through as block devices if not.
+    pub fn is_share_fs_enabled(&self) -> bool {
+        self.share_fs.is_some()
+    }
+
+    // the images of the containers are pulled in the guest, either forced or
+    // because there's no other way without host sharing
+    fn is_guest_pull(&self) -> bool {
+        self.toml_config.runtime.experimental_force_guest_pull
+            || self.no_host_sharing
+            || self.guest_protection.is_protected()
+    }
+
+    pub fn timings(&self) -> Arc<Timings> {
+        self.timings.clone()
+    }
+
+    pub fn events(&self) -> Arc<ResourceEvents> {
+        self.events.clone()
+    }
+
+    /// subscribe returns a stream of the events of the resources emitted
+    /// from now on, each subscriber gets its own one.
+    pub fn subscribe(&self) -> impl Stream<Item = ResourceEvent> + Send {
+        self.events.subscribe().into_stream()
+    }
+
+    // emit_endpoints_removed tells the subscribers the interfaces of the
+    // network are gone
+    fn emit_endpoints_removed(&self, names: Vec<String>) {
+        for name in names {
+            self.events
+                .emit(ResourceEventKind::EndpointRemoved { name });
+        }
+    }
+
     pub async fn prepare_before_start_vm(
         &mut self,
         device_configs: Vec<ResourceConfig>,
     ) -> Result<()> {
-        for dc in device_configs {
-            match dc {
-                ResourceConfig::ShareFs(c) => {
-                    self.share_fs = if self
-                        .hypervisor
-                        .capabilities()
-                        .await?
-                        .is_fs_sharing_supported()
-                    {
-                        let share_fs = share_fs::new(&self.sid, &c).context("new share fs")?;
-                        share_fs
-                            .setup_device_before_start_vm(self.hypervisor.as_ref())
-                            .await
-                            .context("setup share fs device before start vm")?;
-
-                        // setup sandbox bind mounts: setup = true
-                        self.handle_sandbox_bindmounts(true)
-                            .await
-                            .context("failed setup sandbox bindmounts")?;
-
-                        Some(share_fs)
-                    } else {
-                        None
-                    };
-                }
-                ResourceConfig::Network(c) => {
-                    self.handle_network(c)
-                        .await
-                        .context("failed to handle network")?;
-                }
-            };
+        let span = trace::span("prepare_before_start_vm", &self.sid, "", "");
+        self.do_prepare_before_start_vm(device_configs)
+            .instrument(span)
+            .await
+    }
+
+    async fn do_prepare_before_start_vm(
+        &mut self,
+        device_configs: Vec<ResourceConfig>,
+    ) -> Result<()> {
+        // the pooled VM is rejected before anything is set up
+        self.vm_state =
+            pooled_vm::vm_state(self.hypervisor.as_ref(), &self.toml_config, &device_configs)
+                .await
+                .context("check vm state")?;
+        if self.vm_state == VmState::Pooled {
+            info!(
+                sl!(),
+                "sandbox {} adopts a pooled vm, the devices are hotplugged", self.sid
+            );
+        }
+        let capabilities = self.hypervisor.capabilities().await?;
+        validate_config(
+            self.toml_config.check_capabilities(&capabilities),
+            &self.toml_config,
+        )?;
+        self.no_host_sharing = no_host_sharing(&capabilities);
+        if self.no_host_sharing {
+            info!(
+                sl!(),
+                "hypervisor {} shares nothing from the host, the images are pulled in the guest",
+                self.toml_config.runtime.hypervisor_name
+            );
+        }
+        self.guest_protection = self
+            .hypervisor
+            .guest_protection()
+            .await
+            .context("get guest protection")?;
+        if self.guest_protection.is_protected() {
+            info!(
+                sl!(),
+                "guest protected by {}, nothing is shared from the host: the images are pulled in the guest, the volumes are passed as devices or kept in the guest",
+                self.guest_protection
+            );
+            self.volume_resource.set_trusted_storage(true).await;
+        }
+
+        // what's set up on the host is undone if any of the steps fails
+        let timeout = self.resource_setup_timeout();
+        let done = rollback::setup_with_rollback(self, device_configs, timeout).await?;
+        if let Err(e) = self.check_guest_delivery().await {
+            rollback::rollback(self, done).await;
+            return Err(e).context("check how the rootfs and volumes get into the guest");
+        }
+        if let Err(e) = self.add_device_kernel_params().await {
+            rollback::rollback(self, done).await;
+            return Err(e).context("add the kernel parameters of the devices");
+        }
+        Ok(())
+    }
+
+    fn resource_setup_timeout(&self) -> Duration {
+        match self.toml_config.runtime.resource_setup_timeout {
+            0 => DEFAULT_RESOURCE_SETUP_TIMEOUT,
+            secs => Duration::from_secs(secs),
+        }
+    }
+
+    // add the kernel parameters of device_kernel_params the cold plugged
+    // devices need to the boot config of the VM, the devices hotplugged once
+    // it's booted go without them
+    async fn add_device_kernel_params(&self) -> Result<()> {
+        let class_params = &self.toml_config.runtime.device_kernel_params;
+        let vfio_params = match class_params.get(DEVICE_CLASS_VFIO) {
+            Some(params) if !params.is_empty() => params,
+            _ => return Ok(()),
+        };
+        if self.vm_state == VmState::Pooled {
+            warn!(
+                sl!(),
+                "pooled vm is booted already, the vfio devices go without the kernel parameters {:?}",
+                vfio_params
+            );
+            return Ok(());
+        }
+        let endpoints = match self.network.as_ref() {
+            Some(network) => network
+                .save()
+                .await
+                .ok_or_else(|| anyhow!("no state of the network endpoints"))?,
+            None => vec![],
+        };
+
+        let requested = vfio_kernel_params(vfio_params, &endpoints);
+        if requested.is_empty() {
+            return Ok(());
+        }
+
+        let mut hypervisor_config = self.hypervisor.hypervisor_config().await;
+        let params = kernel_params::merge_kernel_params(
+            &hypervisor_config.boot_info.kernel_params,
+            &requested,
+        )?;
+        if params.is_empty() {
+            return Ok(());
+        }
+        info!(
+            sl!(),
+            "add kernel parameters {:?} of the cold plugged devices", params
+        );
+        hypervisor_config.boot_info.add_kernel_params(params);
+        self.hypervisor
+            .set_hypervisor_config(hypervisor_config)
+            .await;
+        Ok(())
+    }
+
+    // fail the sandbox creation early if the rootfs and the volumes of the
+    // containers have no way into the guest, rather than deep inside the
+    // container creation
+    async fn check_guest_delivery(&self) -> Result<()> {
+        if self.is_share_fs_enabled() || self.is_guest_pull() {
+            return Ok(());
+        }
+        let capabilities = self.hypervisor.capabilities().await?;
+        if capabilities.is_block_device_hotplug_supported() {
+            return Ok(());
+        }
+
+        let fs_sharing = if capabilities.is_fs_sharing_supported() {
+            "filesystem sharing (disabled by shared_fs)"
+        } else {
+            "filesystem sharing"
+        };
+        Err(anyhow!(
+            "hypervisor {} supports neither {} nor block device hotplug to deliver the rootfs and volumes of the containers, enable experimental_force_guest_pull to pull the images inside the guest",
+            self.toml_config.runtime.hypervisor_name,
+            fs_sharing
+        ))
+    }
+
+    async fn handle_share_fs(&mut self, c: SharedFsInfo, done: &mut Vec<SetupStep>) -> Result<()> {
+        if !share_fs::is_allowed_by_protection(self.guest_protection, c.shared_fs.as_deref())? {
+            return Ok(());
+        }
+        if !self
+            .hypervisor
+            .capabilities()
+            .await?
+            .is_fs_sharing_supported()
+        {
+            return Ok(());
+        }
+
+        share_fs::validate_host_shared_root(&self.toml_config.runtime.shared_dir_root)
+            .context("validate shared dir root")?;
+        let share_fs = share_fs::new(&self.sid, &c, self.events.clone()).context("new share fs")?;
+        self.share_fs = Some(share_fs.clone());
+        done.push(SetupStep::ShareFs);
+        // the device is hotplugged into the pooled VM running already
+        match self.vm_state {
+            VmState::ColdBoot => share_fs
+                .setup_device_before_start_vm(self.hypervisor.as_ref())
+                .await
+                .context("setup share fs device before start vm")?,
+            VmState::Pooled => share_fs
+                .hotplug_device(self.hypervisor.as_ref())
+                .await
+                .context("hotplug share fs device")?,
+        }
+
+        // each container gets its own export, set up along with the one of
+        // the sandbox rather than when the container is created
+        if self.toml_config.runtime.per_container_sharefs {
+            let exports = ContainerExports::new(
+                &self.sid,
+                &c,
+                self.toml_config.runtime.max_sharefs_instances as usize,
+                self.events.clone(),
+            )
+            .context("new container exports")?;
+            let exports = Arc::new(exports);
+            self.container_exports = Some(exports.clone());
+            done.push(SetupStep::ContainerExports);
+            match self.vm_state {
+                VmState::ColdBoot => exports
+                    .setup_before_start_vm(self.hypervisor.as_ref())
+                    .await
+                    .context("setup container exports before start vm")?,
+                VmState::Pooled => exports
+                    .hotplug(self.hypervisor.as_ref())
+                    .await
+                    .context("hotplug container exports")?,
+            }
+        }
+
+        // setup sandbox bind mounts: setup = true
+        self.sandbox_bind_mounts =
+            effective_bind_mounts(&self.toml_config).context("sandbox bind mounts")?;
+        done.push(SetupStep::SandboxBindMounts);
+        self.handle_sandbox_bindmounts(true)
+            .await
+            .context("failed setup sandbox bindmounts")?;
+
+        Ok(())
+    }
+
+    async fn handle_initial_size(&mut self, initial_size: InitialSizeManager) -> Result<()> {
+        let mut hypervisor_config = self.hypervisor.hypervisor_config().await;
+        // the pooled VM booted with its own sizing, large enough for the
+        // sandbox as checked before
+        if self.vm_state == VmState::ColdBoot {
+            initial_size
+                .setup_config(&mut hypervisor_config)
+                .context("setup initial size")?;
         }
+        info!(
+            sl!(),
+            "static resource management sizes the sandbox to {} vcpus and {} MiB memory",
+            hypervisor_config.cpu_info.default_vcpus,
+            hypervisor_config.memory_info.default_memory
+        );
 
+        self.cpu_resource
+            .set_boot_vcpus(hypervisor_config.cpu_info.default_vcpus as u32)
+            .await;
+        self.mem_resource
+            .set_boot_mem_mb(hypervisor_config.memory_info.default_memory as u64)
+            .await;
+        // the DAX window is in the boot memory sized by the initial size
+        self.mem_resource
+            .set_boot_reserved_mb(dax_window_mb(&hypervisor_config));
+        self.mem_resource
+            .set_workload_mem_mb(initial_size.mem_mb() as u64);
+        if self.vm_state == VmState::ColdBoot {
+            self.hypervisor
+                .set_hypervisor_config(hypervisor_config)
+                .await;
+        }
+        self.initial_size = Some(initial_size);
         Ok(())
     }
 
-    pub async fn handle_network(&mut self, network_config: NetworkConfig) -> Result<()> {
+    /// handle_network sets up the network of the sandbox, the primary interface
+    /// is cold plugged if cold_plug is set, the VM isn't started yet.
+    pub async fn handle_network(
+        &mut self,
+        mut network_config: NetworkConfig,
+        cold_plug: bool,
+    ) -> Result<()> {
+        let NetworkConfig::NetworkResourceWithNetNs(c) = &mut network_config;
+        let multi_queue = self
+            .hypervisor
+            .capabilities()
+            .await?
+            .is_multi_queue_supported();
+        let hypervisor_config = self.hypervisor.hypervisor_config().await;
+        c.queues = network_queues(
+            c.queues,
+            hypervisor_config.cpu_info.default_vcpus,
+            multi_queue,
+        );
+
         // 1. When using Rust asynchronous programming, we use .await to
         //    allow other task to run instead of waiting for the completion of the current task.
         // 2. Also, when handling the pod network, we need to set the shim threads
@@ -137,86 +594,325 @@ impl ResourceManagerInner {
         //    but it is not in netns. So, the previous thread would still remain in the pod netns.
         // The solution is to block the future on the current thread, it is enabled by spawn an os thread, create a
         // tokio runtime, and block the task on it.
+        let network = block_on_thread(move || async move { network::new(&network_config).await })
+            .await
+            .context("new network")?;
+        // kept before it's set up, so that the endpoints attached are
+        // detached by the undo even if the setup times out
+        self.network = Some(network.clone());
         let hypervisor = self.hypervisor.clone();
-        let network = thread::spawn(move || -> Result<Arc<dyn Network>> {
-            let rt = runtime::Builder::new_current_thread().enable_io().build()?;
-            let d = rt
-                .block_on(network::new(&network_config))
-                .context("new network")?;
-            rt.block_on(d.setup(hypervisor.as_ref()))
-                .context("setup network")?;
-            Ok(d)
-        })
-        .join()
-        .map_err(|e| anyhow!("{:?}", e))
-        .context("Couldn't join on the associated thread")?
-        .context("failed to set up network")?;
-        self.network = Some(network);
+        let d = network.clone();
+        block_on_thread(move || async move { d.setup(hypervisor.as_ref(), cold_plug).await })
+            .await
+            .context("failed to set up network")?;
+        for interface in network.interfaces().await.unwrap_or_default() {
+            self.events.emit(ResourceEventKind::EndpointAdded {
+                name: interface.name,
+                hw_addr: interface.hw_addr,
+            });
+        }
+        // the vhost workers of the endpoints hotplugged are started by now
+        if !cold_plug {
+            self.resync_cgroup_threads_after("hotplug network").await;
+        }
         Ok(())
     }
 
-    async fn handle_interfaces(&self, network: &dyn Network) -> Result<()> {
-        for i in network.interfaces().await.context("get interfaces")? {
-            // update interface
-            info!(sl!(), "update interface {:?}", i);
-            self.agent
-                .update_interface(agent::UpdateInterfaceRequest { interface: Some(i) })
-                .await
-                .context("update interface")?;
+    /// network_namespace returns the netns the endpoints of the sandbox are
+    /// set up in, None if the sandbox has no network.
+    pub async fn network_namespace(&self) -> Option<String> {
+        match self.network.as_ref() {
+            Some(network) => network.netns_path().await,
+            None => self.restored_netns_path.clone(),
         }
+    }
 
-        Ok(())
+    // hotplug the interfaces left by the cold plug before the VM started
+    async fn hotplug_network(&self, network: Arc<dyn Network>) -> Result<()> {
+        let hypervisor = self.hypervisor.clone();
+        block_on_thread(move || async move { network.setup(hypervisor.as_ref(), false).await })
+            .await
     }
 
-    async fn handle_neighbours(&self, network: &dyn Network) -> Result<()> {
-        let neighbors = network.neighs().await.context("neighs")?;
-        if !neighbors.is_empty() {
-            info!(sl!(), "update neighbors {:?}", neighbors);
-            self.agent
-                .add_arp_neighbors(agent::AddArpNeighborRequest {
-                    neighbors: Some(agent::ARPNeighbors { neighbors }),
-                })
+    // undo_network detaches the endpoints attached and deletes their taps,
+    // whoever created the netns, then removes the netns created by us. The
+    // setup timed out may still be holding the network, it's waited for
+    // within the setup timeout.
+    async fn undo_network(&mut self) -> Result<()> {
+        let network = match self.network.take() {
+            Some(network) => network,
+            None => return Ok(()),
+        };
+        let hypervisor = self.hypervisor.clone();
+        let undo = block_on_thread(move || async move {
+            let names = interface_names(network.as_ref()).await;
+            let result = network
+                .detach(hypervisor.as_ref())
                 .await
-                .context("update neighbors")?;
-        }
+                .context("detach endpoints");
+            if let Err(e) = network.delete().await {
+                warn!(sl!(), "couldn't delete the endpoints: {:?}", e);
+            }
+            network
+                .remove(hypervisor.as_ref())
+                .await
+                .context("remove network")?;
+            result.map(|_| names)
+        });
+        let timeout = self.resource_setup_timeout();
+        let names = tokio::time::timeout(timeout, undo)
+            .await
+            .map_err(|_| anyhow!("network still being set up after {:?}", timeout))??;
+        self.emit_endpoints_removed(names);
         Ok(())
     }
 
-    async fn handle_routes(&self, network: &dyn Network) -> Result<()> {
-        let routes = network.routes().await.context("routes")?;
-        if !routes.is_empty() {
-            info!(sl!(), "update routes {:?}", routes);
-            self.agent
-                .update_routes(agent::UpdateRoutesRequest {
-                    route: Some(agent::Routes { routes }),
-                })
-                .await
-                .context("update routes")?;
-        }
-        Ok(())
+    // setup_network sends the interfaces, neighbors and routes to the agent
+    async fn setup_network(&self, req: agent::SetupNetworkRequest) -> Result<()> {
+        send_network(self.agent.as_ref(), &self.agent_features, &self.sid, req).await
     }
 
     pub async fn setup_after_start_vm(&mut self) -> Result<()> {
+        let timings = self.timings.clone();
         if let Some(share_fs) = self.share_fs.as_ref() {
-            share_fs
-                .setup_device_after_start_vm(self.hypervisor.as_ref())
+            timings
+                .time(
+                    timings::PHASE_SHARE_FS_AFTER_START,
+                    "",
+                    share_fs.setup_device_after_start_vm(self.hypervisor.as_ref()),
+                )
                 .await
                 .context("setup share fs device after start vm")?;
         }
 
-        if let Some(network) = self.network.as_ref() {
-            let network = network.as_ref();
-            self.handle_interfaces(network)
+        // the agent is asked once what it supports, the volumes, the network
+        // and the devices choose their fallbacks by it
+        self.agent_features = timings
+            .time(
+                timings::PHASE_AGENT_FEATURES,
+                "",
+                self.probe_agent_features(),
+            )
+            .await
+            .context("probe agent features")?;
+
+        timings
+            .time(
+                timings::PHASE_NETWORK_AFTER_START,
+                "",
+                self.setup_network_after_start_vm(self.network.clone()),
+            )
+            .await?;
+
+        if let Some(config) = self.hostname.as_ref() {
+            timings
+                .time(
+                    timings::PHASE_HOSTNAME,
+                    "",
+                    self.setup_hostname_config(config),
+                )
                 .await
-                .context("handle interfaces")?;
-            self.handle_neighbours(network)
+                .context("setup hostname")?;
+        }
+
+        if let Some(memory_info) = self
+            .toml_config
+            .hypervisor
+            .get(&self.toml_config.runtime.hypervisor_name)
+            .map(|h| &h.memory_info)
+            .filter(|m| m.enable_guest_swap)
+        {
+            let pod_mem_mb = self.initial_size.as_ref().map(|s| s.mem_mb());
+            let swap = SwapResource::new(&self.sid, swap::swap_size_mb(memory_info, pod_mem_mb));
+            // keep it to remove the backing file even if the setup fails
+            let result = timings
+                .time(
+                    timings::PHASE_GUEST_SWAP,
+                    "",
+                    swap.setup(self.device_manager.as_ref(), self.agent.as_ref()),
+                )
                 .await
-                .context("handle neighbors")?;
-            self.handle_routes(network).await.context("handle routes")?;
+                .context("setup guest swap");
+            self.swap = Some(swap);
+            result?;
+        }
+
+        // the workers of virtiofsd and the vhost workers started with the
+        // devices above join the cgroups of the sandbox
+        self.resync_cgroup_threads_after("setup after start vm")
+            .await;
+        self.check_vcpus_pinning().await;
+        Ok(())
+    }
+
+    /// resync_cgroup_threads moves the threads the hypervisor created since
+    /// the last time into the cgroups of the sandbox, e.g. the vCPUs
+    /// hotplugged, the workers of virtiofsd and the vhost workers.
+    pub async fn resync_cgroup_threads(&self) -> Result<()> {
+        self.cgroups_resource
+            .resync_threads(self.hypervisor.as_ref())
+            .await
+            .context("resync cgroup threads")
+    }
+
+    /// reconcile_cgroups writes back the settings of the sandbox cgroup which
+    /// drifted on the host, it's done on restore already.
+    pub async fn reconcile_cgroups(&self) -> Result<CgroupReconciliation> {
+        let _in_flight = self.quiesce_gate.enter()?;
+        self.cgroups_resource
+            .reconcile()
+            .await
+            .context("reconcile cgroups")
+    }
+
+    // the resync is best effort after the operations creating threads, the
+    // threads left out are moved by the next one
+    async fn resync_cgroup_threads_after(&self, op: &str) {
+        if let Err(e) = self.resync_cgroup_threads().await {
+            warn!(
+                sl!(),
+                "couldn't resync cgroup threads after {}: {:?}", op, e
+            );
+        }
+    }
+
+    /// pin_vcpus sets the affinity of the threads of the guest vCPUs to the
+    /// host CPUs they're mapped to. The host CPUs must be online and in the
+    /// cpuset of the sandbox cgroup, nothing is pinned otherwise or if one
+    /// of them fails.
+    pub async fn pin_vcpus(&self, mapping: Vec<(u32, u32)>) -> Result<()> {
+        let _in_flight = self.quiesce_gate.enter()?;
+        self.do_pin_vcpus(mapping).await
+    }
+
+    async fn do_pin_vcpus(&self, mapping: Vec<(u32, u32)>) -> Result<()> {
+        let vcpus = self
+            .hypervisor
+            .get_thread_ids()
+            .await
+            .context("get vcpu threads")?
+            .vcpus;
+        let online = vcpu_pinning::online_cpus(Path::new(vcpu_pinning::SYS_CPU_ONLINE))?;
+        let sandbox_cpuset = self
+            .cgroups_resource
+            .sandbox_cpuset()
+            .context("get cpuset of the sandbox cgroup")?;
+        vcpu_pinning::validate_pinning(&mapping, &vcpus, &online, sandbox_cpuset.as_ref())?;
+
+        info!(sl!(), "pin vcpus to host cpus {:?}", mapping);
+        let pins: Vec<(u32, u32)> = mapping
+            .iter()
+            .map(|(vcpu, cpu)| (vcpus[vcpu], *cpu))
+            .collect();
+        vcpu_pinning::pin_threads(&pins).context("pin vcpus")
+    }
+
+    // check_vcpus_pinning pins each vCPU to a host CPU of the cpuset of the
+    // sandbox cgroup with enable_vcpus_pinning, if there are as many of them,
+    // the vCPUs run on all of them otherwise. It's best effort after the
+    // vCPUs or the cpuset change.
+    async fn check_vcpus_pinning(&self) {
+        if !self.toml_config.runtime.enable_vcpus_pinning {
+            return;
+        }
+        if let Err(e) = self.do_check_vcpus_pinning().await {
+            warn!(sl!(), "couldn't check vcpus pinning: {:?}", e);
+        }
+    }
+
+    async fn do_check_vcpus_pinning(&self) -> Result<()> {
+        let vcpus = self
+            .hypervisor
+            .get_thread_ids()
+            .await
+            .context("get vcpu threads")?
+            .vcpus;
+        let cpuset = match self
+            .cgroups_resource
+            .sandbox_cpuset()
+            .context("get cpuset of the sandbox cgroup")?
+        {
+            Some(cpuset) if !cpuset.is_empty() => cpuset,
+            _ => vcpu_pinning::online_cpus(Path::new(vcpu_pinning::SYS_CPU_ONLINE))?,
+        };
+
+        match vcpu_pinning::pinning_by_cpuset(&vcpus, &cpuset) {
+            Some(mapping) => self.do_pin_vcpus(mapping).await,
+            None => {
+                let cpus: Vec<u32> = cpuset.iter().copied().collect();
+                for tid in vcpus.values() {
+                    vcpu_pinning::set_affinity(*tid, &cpus).context("unpin vcpus")?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // setup_hostname_config writes the hostname and the hosts file of the
+    // pod into the guest, as a whole so that they're the same again after a
+    // restore
+    async fn setup_hostname_config(&self, config: &HostnameConfig) -> Result<()> {
+        if !config.hostname.is_empty() {
+            hostname::setup_hostname(self.agent.as_ref(), &config.hostname).await?;
+        }
+        if !config.hosts.is_empty() {
+            hostname::setup_hosts(self.agent.as_ref(), &config.hosts).await?;
         }
         Ok(())
     }
 
+    // probe_agent_features asks the agent what it serves, the guest may
+    // still be booting, the agent not listening yet
+    async fn probe_agent_features(&self) -> Result<AgentFeatures> {
+        let ready_timeout_ms = self.agent.agent_config().await.ready_timeout_ms;
+        if ready_timeout_ms != 0 {
+            agent::wait_agent_ready(
+                self.agent.as_ref(),
+                Duration::from_millis(ready_timeout_ms as u64),
+                self.toml_config.runtime.retry.agent_ready,
+            )
+            .await
+            .context("wait for agent")?;
+        }
+        Ok(AgentFeatures::probe(self.agent.as_ref()).await)
+    }
+
+    // setup_network_after_start_vm sets up the endpoints if there's a network
+    // and applies the net sysctls of the pod even without one, e.g. with an
+    // empty result of the CNI
+    async fn setup_network_after_start_vm(&self, network: Option<Arc<dyn Network>>) -> Result<()> {
+        if let Some(network) = network.as_ref() {
+            self.hotplug_network(network.clone())
+                .await
+                .context("hotplug network")?;
+        }
+        setup_guest_network(
+            self.agent.as_ref(),
+            &self.agent_features,
+            &self.sid,
+            network.as_deref(),
+            &self.net_sysctls,
+        )
+        .await
+    }
+
+    /// reapply_network pushes the current interfaces, neighbors and routes of
+    /// the sandbox to the agent again, e.g. after the agent restarted in the
+    /// guest. Nothing is hotplugged and the share fs isn't touched, so it's
+    /// safe to call any number of times: the agent updates the interfaces and
+    /// replaces the neighbors and the routes rather than adding them again.
+    pub async fn reapply_network(&self) -> Result<()> {
+        let network = match self.network.as_ref() {
+            Some(network) => network.clone(),
+            None => {
+                info!(sl!(), "no network to reapply for sandbox {}", self.sid);
+                return Ok(());
+            }
+        };
+        let req = network_request(network.as_ref()).await?;
+        self.setup_network(req).await.context("reapply network")
+    }
+
     pub async fn get_storage_for_sandbox(&self) -> Result<Vec<Storage>> {
         let mut storages = vec![];
         if let Some(d) = self.share_fs.as_ref() {
@@ -226,133 +922,2708 @@ impl ResourceManagerInner {
         Ok(storages)
     }
 
+    /// get_storage_for_container returns the storage of the export of the
+    /// container with per_container_sharefs, mounted before its rootfs and
+    /// its volumes. There's none without.
+    pub async fn get_storage_for_container(&self, cid: &str) -> Result<Vec<Storage>> {
+        match self.container_exports.as_ref() {
+            Some(exports) => exports.storages(cid).await.context("get storage"),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// wait_share_fs_ready polls the agent until the storages of the share fs
+    /// are mounted in the guest, the ones of the sandbox and the exports
+    /// given to the containers: the guest mounts them asynchronously once
+    /// the devices are set up. It fails with the storages still missing
+    /// after the timeout. An agent which can't stat them isn't waited for.
+    pub async fn wait_share_fs_ready(&self, timeout: Duration) -> Result<()> {
+        let mut storages = self.get_storage_for_sandbox().await?;
+        if let Some(exports) = self.container_exports.as_ref() {
+            let mut s = exports
+                .storages_in_use()
+                .await
+                .context("get storage of container exports")?;
+            storages.append(&mut s);
+        }
+        let mount_points: Vec<String> = storages.into_iter().map(|s| s.mount_point).collect();
+
+        let agent = self.agent.as_ref();
+        let policy = self.toml_config.runtime.retry.share_fs_mount;
+        share_fs::wait_mounted(
+            &mount_points,
+            timeout,
+            policy,
+            move |mount_point| async move {
+                match share_fs::stat_mounted(agent, &mount_point).await {
+                    std::result::Result::Ok(_) => Ok(()),
+                    Err(e) if is_unsupported_error(&e) => {
+                        warn!(
+                            sl!(),
+                            "agent can't stat {}, not waiting for it: {:?}", mount_point, e
+                        );
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            },
+        )
+        .await
+        .with_context(|| format!("share fs of sandbox {} not ready", self.sid))
+    }
+
+    // share_fs_of returns the share fs the rootfs and the volumes of the
+    // container are shared through: its own export with
+    // per_container_sharefs, the one of the sandbox otherwise
+    fn share_fs_of(&self, cid: &str) -> Result<Option<Arc<dyn ShareFs>>> {
+        match self.container_exports.as_ref() {
+            Some(exports) => exports.acquire(cid).map(Some),
+            None => Ok(self.share_fs.clone()),
+        }
+    }
+
     pub async fn handler_rootfs(
         &self,
         cid: &str,
         root: &oci::Root,
         bundle_path: &str,
         rootfs_mounts: &[Mount],
+        annotations: &HashMap<String, String>,
     ) -> Result<Arc<dyn Rootfs>> {
-        self.rootfs_resource
-            .handler_rootfs(
-                &self.share_fs,
-                self.device_manager.as_ref(),
-                self.hypervisor.as_ref(),
-                &self.sid,
-                cid,
-                root,
-                bundle_path,
-                rootfs_mounts,
-            )
-            .await
+        let _in_flight = self.quiesce_gate.enter()?;
+        let rootfs = self
+            .do_handler_rootfs(cid, root, bundle_path, rootfs_mounts, annotations)
+            .await?;
+        // the storages of the rootfs and the rootfs of the container itself
+        self.limits
+            .add_rootfs_mounts(cid, rootfs.get_storages().await.len() + 1);
+        self.emit_rootfs_devices(cid, &[rootfs.clone()], true).await;
+        Ok(rootfs)
     }
 
-    pub async fn handler_volumes(
+    async fn do_handler_rootfs(
         &self,
         cid: &str,
-        spec: &oci::Spec,
-    ) -> Result<Vec<Arc<dyn Volume>>> {
-        self.volume_resource
-            .handler_volumes(
-                &self.share_fs,
-                cid,
-                spec,
-                self.device_manager.as_ref(),
-                &self.sid,
-                self.agent.clone(),
-            )
-            .await
-    }
-
-    pub async fn handler_devices(&self, _cid: &str, linux: &Linux) -> Result<Vec<Device>> {
-        let mut devices = vec![];
-        for d in linux.devices.iter() {
-            match d.r#type.as_str() {
-                "b" => {
-                    let dev_info = DeviceConfig::BlockCfg(BlockConfig {
-                        major: d.major,
-                        minor: d.minor,
-                        ..Default::default()
-                    });
+        root: &oci::Root,
+        bundle_path: &str,
+        rootfs_mounts: &[Mount],
+        annotations: &HashMap<String, String>,
+    ) -> Result<Arc<dyn Rootfs>> {
+        // the image pulled in the guest is written to in the guest already
+        if self.is_guest_pull() {
+            if !self.toml_config.runtime.rootfs_lower_layer.is_empty() {
+                return Err(anyhow!(
+                    "rootfs_lower_layer needs the rootfs shared from the host, the image of container {} is pulled in the guest",
+                    cid
+                ));
+            }
+            let rootfs = self
+                .rootfs_resource
+                .handler_guest_pull_rootfs(cid, annotations);
+            return self.timings.time(timings::PHASE_ROOTFS, cid, rootfs).await;
+        }
+        let rootfs_path = Path::new(bundle_path).join(&root.path);
+        // the paths resolved are the ones mounted, not the ones of the spec
+        // whose symlinks may have changed since
+        let (rootfs_path, rootfs_mounts) = self
+            .check_rootfs_sources(Some(&rootfs_path), rootfs_mounts)
+            .with_context(|| format!("rootfs of container {}", cid))?;
+        let root = &oci::Root {
+            path: rootfs_path.unwrap_or_else(|| root.path.clone()),
+            ..root.clone()
+        };
+        let rootfs_mounts = rootfs_mounts.as_slice();
+        if !self.is_share_fs_enabled() && rootfs::needs_share_fs(rootfs_mounts) {
+            return Err(anyhow!(
+                "rootfs of container {} isn't a block device, which is needed without filesystem sharing",
+                cid
+            ));
+        }
 
-                    let device_info = do_handle_device(&self.device_manager, &dev_info)
-                        .await
-                        .context("do handle device")?;
-
-                    // create agent device
-                    if let DeviceType::Block(device) = device_info {
-                        let agent_device = Device {
-                            id: device.device_id.clone(),
-                            container_path: d.path.clone(),
-                            field_type: device.config.driver_option,
-                            vm_path: device.config.virt_path,
-                            ..Default::default()
-                        };
-                        devices.push(agent_device);
-                    }
+        let upper =
+            UpperStorage::new(&self.toml_config.runtime.rootfs_upper_storage)?.map(|storage| {
+                let runtime = &self.toml_config.runtime;
+                UpperConfig {
+                    storage,
+                    size_mb: runtime.rootfs_upper_size_mb,
+                    mkfs_path: runtime.rootfs_upper_mkfs_path.clone(),
+                    host_dir: Path::new(KATA_PATH).join(&self.sid),
                 }
-                _ => {
-                    // TODO enable other devices type
-                    continue;
+            });
+        let share_fs = self.share_fs_of(cid)?;
+        let rootfs = async {
+            let rootfs = self
+                .rootfs_resource
+                .handler_rootfs(
+                    &share_fs,
+                    self.device_manager.as_ref(),
+                    self.hypervisor.as_ref(),
+                    &self.sid,
+                    cid,
+                    root,
+                    bundle_path,
+                    rootfs_mounts,
+                    Some(self.toml_config.runtime.rootfs_lower_layer.as_str())
+                        .filter(|l| !l.is_empty()),
+                    &self.toml_config.runtime.rootfs_mount_options,
+                )
+                .await?;
+            match upper.as_ref() {
+                Some(upper) => {
+                    self.rootfs_resource
+                        .handler_upper_rootfs(self.device_manager.as_ref(), cid, rootfs, upper)
+                        .await
                 }
+                None => Ok(rootfs),
             }
-        }
-        Ok(devices)
+        };
+        self.timings.time(timings::PHASE_ROOTFS, cid, rootfs).await
     }
 
-    async fn handle_sandbox_bindmounts(&self, setup: bool) -> Result<()> {
-        let bindmounts = self.toml_config.runtime.sandbox_bind_mounts.clone();
-        if bindmounts.is_empty() {
-            info!(sl!(), "sandbox bindmounts empty, just skip it.");
-            return Ok(());
-        }
-
-        let sb_bindmnt = SandboxBindMounts::new(self.sid.clone(), bindmounts)?;
-
-        if setup {
-            sb_bindmnt.setup_sandbox_bind_mounts()
-        } else {
-            sb_bindmnt.cleanup_sandbox_bind_mounts()
+    // check_rootfs_sources keeps the rootfs and the host paths it's mounted
+    // from under the allowed prefixes, before anything is mounted. It
+    // returns the rootfs and the mounts with their paths resolved, to be
+    // mounted instead of the ones of the spec.
+    fn check_rootfs_sources(
+        &self,
+        rootfs: Option<&Path>,
+        rootfs_mounts: &[Mount],
+    ) -> Result<(Option<String>, Vec<Mount>)> {
+        let rootfs = match rootfs {
+            Some(rootfs) => Some(self.path_jail.resolve_source(&rootfs.to_string_lossy())?),
+            None => None,
+        };
+        let mut mounts = rootfs_mounts.to_vec();
+        for m in mounts
+            .iter_mut()
+            .filter(|m| Path::new(&m.source).is_absolute())
+        {
+            m.source = self.path_jail.resolve_source(&m.source)?;
         }
+        Ok((rootfs, mounts))
     }
 
-    pub async fn update_cgroups(
+    pub async fn handler_volumes(
         &self,
         cid: &str,
-        linux_resources: Option<&LinuxResources>,
-    ) -> Result<()> {
-        self.cgroups_resource
-            .update_cgroups(cid, linux_resources, self.hypervisor.as_ref())
-            .await
+        spec: &oci::Spec,
+    ) -> Result<Vec<Arc<dyn Volume>>> {
+        let _in_flight = self.quiesce_gate.enter()?;
+        let count = spec
+            .mounts
+            .iter()
+            .filter(|m| volume::is_host_volume(m))
+            .count();
+        self.limits.take_volumes(cid, count)?;
+        // the block devices of the volumes take the slots of the sandbox
+        let device_count = spec
+            .mounts
+            .iter()
+            .filter(|m| volume::is_device_volume(m))
+            .count();
+        if let Err(e) = self.limits.take_volume_devices(cid, device_count) {
+            self.limits.give_back_volumes(cid, count);
+            return Err(e);
+        }
+        // the tmpfs of the volumes take the guest memory, the container
+        // fails early if the sandbox can't grow to it
+        let reserved = match ContainerReservation::new(spec, &self.shm_limits().await)
+            .context("memory reservation of volumes")
+        {
+            std::result::Result::Ok(reservation) => {
+                self.mem_resource
+                    .reserve_container_mem(cid, &reservation)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = reserved {
+            self.limits.give_back_volume_devices(cid, device_count);
+            self.limits.give_back_volumes(cid, count);
+            return Err(e);
+        }
+        let volumes = match self.do_handler_volumes(cid, spec).await {
+            std::result::Result::Ok(volumes) => volumes,
+            Err(e) => {
+                self.mem_resource.release_container_mem(cid).await;
+                self.limits.give_back_volume_devices(cid, device_count);
+                self.limits.give_back_volumes(cid, count);
+                return Err(e);
+            }
+        };
+        // the guest mounts of the volumes are only known once they're set
+        // up, the storages shared with the other containers are counted once
+        let mounts = self.guest_mount_count().await;
+        let added = volumes
+            .iter()
+            .map(|v| {
+                v.get_volume_mount().map(|m| m.len()).unwrap_or_default()
+                    + v.get_storage().map(|s| s.len()).unwrap_or_default()
+            })
+            .sum();
+        if let Err(e) = self.limits.check_mounts(cid, mounts, added) {
+            self.volume_resource
+                .rollback(&volumes, self.device_manager.as_ref())
+                .await;
+            self.mem_resource.release_container_mem(cid).await;
+            self.limits.give_back_volume_devices(cid, device_count);
+            self.limits.give_back_volumes(cid, count);
+            return Err(e);
+        }
+        self.emit_volumes(cid, &volumes, true);
+        Ok(volumes)
     }
 
-    pub async fn cleanup(&self) -> Result<()> {
-        // clean up cgroup
+    // emit_volumes tells the subscribers the mounts of the volumes of the
+    // container, and their devices, are added or removed
+    fn emit_volumes(&self, cid: &str, volumes: &[Arc<dyn Volume>], mounted: bool) {
+        for volume in volumes {
+            if let std::result::Result::Ok(Some(id)) = volume.get_device_id() {
+                self.emit_devices(cid, &[id], mounted);
+            }
+            for m in volume.get_volume_mount().unwrap_or_default() {
+                let (container_id, source, destination) =
+                    (cid.to_string(), m.source, m.destination);
+                self.events.emit(if mounted {
+                    ResourceEventKind::VolumeMounted {
+                        container_id,
+                        source,
+                        destination,
+                    }
+                } else {
+                    ResourceEventKind::VolumeUnmounted {
+                        container_id,
+                        source,
+                        destination,
+                    }
+                });
+            }
+        }
+    }
+
+    // emit_rootfs_devices tells the subscribers the devices of the rootfs of
+    // the container are attached or detached
+    async fn emit_rootfs_devices(&self, cid: &str, rootfs: &[Arc<dyn Rootfs>], attached: bool) {
+        for r in rootfs {
+            if let std::result::Result::Ok(Some(id)) = r.get_device_id().await {
+                self.emit_devices(cid, &[id], attached);
+            }
+        }
+    }
+
+    // check_volume_mounts refuses the mounts of the container the guest
+    // can't get, before any of its volumes is set up. It returns the mounts
+    // with the host sources bound resolved, to be bound instead of the ones
+    // of the spec whose symlinks may have changed since they're checked.
+    fn check_volume_mounts(&self, cid: &str, spec: &oci::Spec) -> Result<Vec<oci::Mount>> {
+        // nothing but the regular files copied gets into the guest without
+        // the fs sharing, rather than leaving the volume out
+        if !self.is_share_fs_enabled() && self.toml_config.runtime.experimental_force_guest_pull {
+            for m in spec.mounts.iter() {
+                if volume::needs_share_fs(m)? {
+                    return Err(anyhow!(
+                        "volume {} of container {} needs filesystem sharing, which is unavailable with guest pull",
+                        m.source,
+                        cid
+                    ));
+                }
+            }
+        }
+
+        // the sources are checked before they're bound or attached, nothing
+        // is on the host without host sharing. The source of a direct volume
+        // is the key of its mount info, it's checked but never bound.
+        let mut mounts = spec.mounts.clone();
+        if !self.no_host_sharing {
+            for m in mounts.iter_mut().filter(|m| volume::has_host_source(m)) {
+                let resolved = self
+                    .path_jail
+                    .resolve_source(&m.source)
+                    .with_context(|| format!("volume {} of container {}", m.destination, cid))?;
+                if !volume::is_direct_volume(m) {
+                    m.source = resolved;
+                }
+            }
+        }
+
+        for m in spec.mounts.iter() {
+            if let Some(propagation) =
+                volume::unsupported_propagation(m, self.is_share_fs_enabled())?
+            {
+                let msg = format!(
+                    "mount propagation not supported: {} of mount {} of container {}",
+                    propagation, m.destination, cid
+                );
+                if !self.toml_config.runtime.allow_unsupported_mount_propagation {
+                    return Err(anyhow!(msg));
+                }
+                warn!(sl!(), "{}, the mounts on the host won't show up", msg);
+            }
+        }
+        Ok(mounts)
+    }
+
+    async fn do_handler_volumes(
+        &self,
+        cid: &str,
+        spec: &oci::Spec,
+    ) -> Result<Vec<Arc<dyn Volume>>> {
+        let spec = &oci::Spec {
+            mounts: self.check_volume_mounts(cid, spec)?,
+            ..spec.clone()
+        };
+
+        if self.no_host_sharing {
+            // the files can't be shared instead
+            self.agent_features
+                .require(AgentFeature::CopyFile)
+                .context("copy the files of the volumes into the guest")?;
+            let volumes = self.volume_resource.handler_unshared_volumes(
+                cid,
+                spec,
+                self.agent.clone(),
+                self.toml_config.runtime.copy_file_max_size,
+            );
+            return self
+                .timings
+                .time(timings::PHASE_VOLUMES, cid, volumes)
+                .await;
+        }
+
+        // the watchable mounts are only looked for if the agent can't do them
+        if !self.agent_features.supports(AgentFeature::WatchableMounts) {
+            if let Some(m) = spec.mounts.iter().find(|m| is_watchable_mount(&m.source)) {
+                self.agent_features
+                    .require(AgentFeature::WatchableMounts)
+                    .with_context(|| format!("watchable mount {}", m.destination))?;
+            }
+        }
+
+        // 0 disables copying the small files into the guest
+        let copy_file_max_size = if self.toml_config.runtime.enable_copy_small_files {
+            self.agent_features
+                .copy_file_max_size(self.toml_config.runtime.copy_file_max_size)
+        } else {
+            0
+        };
+        let shm_limits = self.shm_limits().await;
+        let share_fs = self.share_fs_of(cid)?;
+        // the agent watches the watchable mounts under the share dir of the
+        // sandbox only, they aren't put in the export of the container
+        let volumes = self.volume_resource.handler_volumes(
+            &share_fs,
+            &self.share_fs,
+            cid,
+            spec,
+            self.device_manager.as_ref(),
+            &self.sid,
+            self.agent.clone(),
+            copy_file_max_size,
+            shm_limits,
+        );
+        self.timings
+            .time(timings::PHASE_VOLUMES, cid, volumes)
+            .await
+    }
+
+    /// fallback_volumes degrades the volumes of the container the agent failed
+    /// to mount, the eligible ones by their filesystem type on the host or by
+    /// the annotation of the container. It returns the volumes of the
+    /// container to create it again with, none if no volume is degraded.
+    pub async fn fallback_volumes(
+        &self,
+        cid: &str,
+        annotations: &HashMap<String, String>,
+        error: &str,
+    ) -> Result<Option<Vec<Arc<dyn Volume>>>> {
+        let eligibility = volume::FallbackEligibility::new(
+            &self.toml_config.runtime.share_fs_fallback_fstypes,
+            annotations,
+        );
+        if eligibility.is_empty() {
+            return Ok(None);
+        }
+        // the small files are copied as a last resort, if the agent can
+        let copy_file_max_size = self
+            .agent_features
+            .copy_file_max_size(self.toml_config.runtime.copy_file_max_size);
+        self.volume_resource
+            .fallback_volumes(
+                cid,
+                error,
+                &eligibility,
+                self.agent.as_ref(),
+                copy_file_max_size,
+            )
+            .await
+    }
+
+    async fn shm_limits(&self) -> volume::ShmLimits {
+        volume::ShmLimits {
+            default_size: (self.toml_config.runtime.default_shm_size_mb as u64) << 20,
+            guest_memory: self.mem_resource.current_mem_mb().await << 20,
+        }
+    }
+
+    /// validate_plan tells what the rootfs, the devices and the volumes of
+    /// the container of the spec would be set up with, and why they couldn't,
+    /// picked the same way as by handler_rootfs, handler_devices and
+    /// handler_volumes. Nothing is set up or changed, on the host or in the
+    /// guest.
+    pub async fn validate_plan(&self, spec: &oci::Spec, rootfs_mounts: &[Mount]) -> PlanReport {
+        let mut report = PlanReport::default();
+        report.push(self.plan_rootfs(spec, rootfs_mounts));
+        if let Some(exports) = self.container_exports.as_ref() {
+            if let Err(e) = exports.check_free() {
+                report.fail(e);
+            }
+        }
+        self.plan_devices(spec, &mut report).await;
+        self.plan_volumes(spec, &mut report).await;
+        info!(
+            sl!(),
+            "resource plan admissible {}: {:?}",
+            report.is_admissible(),
+            report
+        );
+        report
+    }
+
+    fn plan_rootfs(&self, spec: &oci::Spec, rootfs_mounts: &[Mount]) -> PlannedResource {
+        let source = match rootfs_mounts.first() {
+            Some(m) => m.source.as_str(),
+            None => spec
+                .root
+                .as_ref()
+                .map(|r| r.path.as_str())
+                .unwrap_or_default(),
+        };
+        // the bundle of the rootfs isn't known yet
+        let root = spec
+            .root
+            .as_ref()
+            .map(|r| Path::new(&r.path))
+            .filter(|p| p.is_absolute());
+        let runtime = &self.toml_config.runtime;
+        let lower_layer = Some(runtime.rootfs_lower_layer.as_str()).filter(|l| !l.is_empty());
+        let backend = if self.is_guest_pull() {
+            match lower_layer {
+                Some(_) => Err(anyhow!(
+                    "rootfs_lower_layer needs the rootfs shared from the host, the image is pulled in the guest"
+                )),
+                None => Ok("guest_pull"),
+            }
+        } else if let Err(e) = self.check_rootfs_sources(root, rootfs_mounts) {
+            Err(e)
+        } else if !self.is_share_fs_enabled() && rootfs::needs_share_fs(rootfs_mounts) {
+            Err(anyhow!(
+                "rootfs isn't a block device, which is needed without filesystem sharing"
+            ))
+        } else {
+            UpperStorage::new(&runtime.rootfs_upper_storage).and_then(|_| {
+                rootfs::rootfs_backend(self.is_share_fs_enabled(), rootfs_mounts, lower_layer)
+            })
+        };
+        PlannedResource::new(plan::KIND_ROOTFS, source, "").with_result(backend)
+    }
+
+    async fn plan_devices(&self, spec: &oci::Spec, report: &mut PlanReport) {
+        let mut linux = spec.linux.clone().unwrap_or_default();
+        let capabilities = spec.process.as_ref().and_then(|p| p.capabilities.as_ref());
+        if is_privileged(&linux) {
+            if let Err(e) = handle_privileged_devices(
+                &self.toml_config.runtime,
+                PLAN_CID,
+                &mut linux,
+                &spec.annotations,
+            ) {
+                report.fail(e);
+            }
+        }
+        if let Err(e) = handle_missing_devices(
+            &self.toml_config.runtime,
+            Path::new(SYS_DEV),
+            PLAN_CID,
+            &mut linux,
+        ) {
+            report.fail(e);
+        }
+        if let Err(e) = self.check_device_capabilities(PLAN_CID, &linux.devices, capabilities) {
+            report.fail(e);
+        }
+        let vfs =
+            match sriov::vf_configs(&self.toml_config, &spec.annotations).context("SR-IOV VFs") {
+                std::result::Result::Ok(vfs) => vfs,
+                Err(e) => {
+                    report.fail(e);
+                    vec![]
+                }
+            };
+        let count = slot_device_count(&linux.devices) + vfs.len();
+        if let Err(e) = self.limits.check_devices(PLAN_CID, count) {
+            report.fail(e);
+        }
+
+        for (config, vf) in vfs.iter().zip(self.sriov_resource.plan(&vfs).await) {
+            report.push(
+                PlannedResource::new(plan::KIND_VF, &config.pf, "").with_result(vf.map(|_| "vfio")),
+            );
+        }
+        // only the block devices are attached, the others are left out
+        for d in linux.devices.iter().filter(|d| d.r#type == "b") {
+            let attached = self
+                .device_manager
+                .read()
+                .await
+                .find_block_device(d.major, d.minor)
+                .await;
+            // the device attached already, e.g. as the block rootfs, is shared
+            let backend = match attached {
+                Some(_) => Ok("block"),
+                None => get_host_path("b".to_string(), d.major, d.minor)
+                    .map(|_| "block")
+                    .with_context(|| format!("host block device {}:{}", d.major, d.minor)),
+            };
+            let source = format!("{}:{}", d.major, d.minor);
+            report.push(
+                PlannedResource::new(plan::KIND_DEVICE, &source, &d.path).with_result(backend),
+            );
+        }
+        for d in linux
+            .devices
+            .iter()
+            .filter(|d| device_class(d) == Some(DEVICE_CLASS_VFIO))
+        {
+            let backend = check_vfio_group(Path::new(SYS_IOMMU_GROUPS), &d.path).map(|_| "vfio");
+            report.push(
+                PlannedResource::new(plan::KIND_DEVICE, &d.path, &d.path).with_result(backend),
+            );
+        }
+        let hugepage_limits = linux
+            .resources
+            .as_ref()
+            .map(|r| r.hugepage_limits.as_slice())
+            .unwrap_or_default();
+        for l in hugepage_limits {
+            let backend =
+                check_hugepages(Path::new(SYS_HUGEPAGES), &l.page_size, l.limit).map(|_| HUGETLBFS);
+            report.push(
+                PlannedResource::new(plan::KIND_HUGEPAGES, &l.page_size, "").with_result(backend),
+            );
+        }
+    }
+
+    async fn plan_volumes(&self, spec: &oci::Spec, report: &mut PlanReport) {
+        let count = spec
+            .mounts
+            .iter()
+            .filter(|m| volume::is_host_volume(m))
+            .count();
+        if let Err(e) = self.limits.check_volumes(PLAN_CID, count) {
+            report.fail(e);
+        }
+        let shm_limits = self.shm_limits().await;
+        if let Err(e) =
+            ContainerReservation::new(spec, &shm_limits).context("memory reservation of volumes")
+        {
+            report.fail(e);
+        }
+        if let Err(e) = self.check_volume_mounts(PLAN_CID, spec) {
+            report.fail(e);
+        }
+        let required = if self.no_host_sharing {
+            self.agent_features
+                .require(AgentFeature::CopyFile)
+                .context("copy the files of the volumes into the guest")
+        } else {
+            match spec.mounts.iter().find(|m| is_watchable_mount(&m.source)) {
+                Some(m) if !self.agent_features.supports(AgentFeature::WatchableMounts) => self
+                    .agent_features
+                    .require(AgentFeature::WatchableMounts)
+                    .with_context(|| format!("watchable mount {}", m.destination)),
+                _ => Ok(()),
+            }
+        };
+        if let Err(e) = required {
+            report.fail(e);
+        }
+
+        for volume in self
+            .volume_resource
+            .plan(
+                spec,
+                self.is_share_fs_enabled(),
+                !self.no_host_sharing,
+                self.toml_config.runtime.copy_file_max_size,
+                &shm_limits,
+            )
+            .await
+        {
+            report.push(volume);
+        }
+    }
+
+    pub async fn handler_devices(
+        &self,
+        cid: &str,
+        linux: &mut Linux,
+        annotations: &HashMap<String, String>,
+        capabilities: Option<&LinuxCapabilities>,
+    ) -> Result<Vec<Device>> {
+        let _in_flight = self.quiesce_gate.enter()?;
+        self.timings
+            .time(
+                timings::PHASE_DEVICES,
+                cid,
+                self.do_handler_devices(cid, linux, annotations, capabilities),
+            )
+            .await
+    }
+
+    async fn do_handler_devices(
+        &self,
+        cid: &str,
+        linux: &mut Linux,
+        annotations: &HashMap<String, String>,
+        capabilities: Option<&LinuxCapabilities>,
+    ) -> Result<Vec<Device>> {
+        if is_privileged(linux) {
+            let requested =
+                cdi::device_nodes(cdi::CDI_SPEC_DIRS, annotations).context("CDI devices")?;
+            handle_privileged_devices(&self.toml_config.runtime, cid, linux, &requested)?;
+        }
+        handle_missing_devices(&self.toml_config.runtime, Path::new(SYS_DEV), cid, linux)?;
+        self.check_device_capabilities(cid, &linux.devices, capabilities)?;
+        let vfs = sriov::vf_configs(&self.toml_config, annotations).context("SR-IOV VFs")?;
+
+        let count = slot_device_count(&linux.devices) + vfs.len();
+        self.limits.take_devices(cid, count)?;
+        let result = self.attach_devices_and_vfs(cid, &linux.devices, vfs).await;
+        if result.is_err() {
+            self.limits.give_back_devices(cid, count);
+        }
+        result
+    }
+
+    async fn attach_devices_and_vfs(
+        &self,
+        cid: &str,
+        devices: &[LinuxDevice],
+        vfs: Vec<sriov::VfConfig>,
+    ) -> Result<Vec<Device>> {
+        // the VFs are configured on their PFs and passed to the guest, they
+        // show up as network interfaces of the guest
+        let ids = self
+            .sriov_resource
+            .attach(cid, vfs, self.hypervisor.as_ref())
+            .await
+            .context("attach SR-IOV VFs")?;
+        self.emit_devices(cid, &ids, true);
+        // the vfio groups are only passed through in bulk
+        match self.attach_batch(cid, devices, &HashMap::new()).await {
+            std::result::Result::Ok(devices) => Ok(devices),
+            Err(e) => {
+                self.detach_sriov_vfs(cid).await;
+                Err(e)
+            }
+        }
+    }
+
+    // detach_sriov_vfs detaches the VFs of the container, they're released on
+    // the host whatever fails
+    async fn detach_sriov_vfs(&self, cid: &str) {
+        match self
+            .sriov_resource
+            .detach(cid, self.hypervisor.as_ref())
+            .await
+        {
+            std::result::Result::Ok(ids) => self.emit_devices(cid, &ids, false),
+            Err(e) => warn!(
+                sl!(),
+                "couldn't detach SR-IOV VFs of container {}: {:?}", cid, e
+            ),
+        }
+    }
+
+    fn emit_devices(&self, cid: &str, ids: &[String], attached: bool) {
+        for id in ids {
+            let (container_id, device_id) = (cid.to_string(), id.clone());
+            self.events.emit(if attached {
+                ResourceEventKind::DeviceAttached {
+                    container_id,
+                    device_id,
+                }
+            } else {
+                ResourceEventKind::DeviceDetached {
+                    container_id,
+                    device_id,
+                }
+            });
+        }
+    }
+
+    // check_device_capabilities refuses the devices the container lacks the
+    // capabilities for, before any of them is attached
+    fn check_device_capabilities(
+        &self,
+        cid: &str,
+        devices: &[LinuxDevice],
+        capabilities: Option<&LinuxCapabilities>,
+    ) -> Result<()> {
+        let runtime = &self.toml_config.runtime;
+        if !runtime.enforce_device_capabilities {
+            return Ok(());
+        }
+        check_device_capabilities(&runtime.device_capabilities, cid, devices, capabilities)
+    }
+
+    /// handler_devices_bulk attaches the devices of the container as one
+    /// batch, e.g. the GPUs of a pod: the batch is validated before any of
+    /// them is attached, and all of them are detached if one fails.
+    pub async fn handler_devices_bulk(
+        &self,
+        cid: &str,
+        devices: &[LinuxDevice],
+        capabilities: Option<&LinuxCapabilities>,
+    ) -> Result<Vec<Device>> {
+        let _in_flight = self.quiesce_gate.enter()?;
+        self.timings
+            .time(timings::PHASE_DEVICES, cid, async {
+                let groups = group_bulk_devices(devices).context("validate devices")?;
+                self.check_device_capabilities(cid, devices, capabilities)?;
+                // each function of the vfio groups takes a guest pci slot,
+                // the batch is refused if they don't all fit
+                let mut vfio = HashMap::new();
+                for d in devices
+                    .iter()
+                    .filter(|d| device_class(d) == Some(DEVICE_CLASS_VFIO))
+                {
+                    let functions = vfio_group_functions(Path::new(SYS_IOMMU_GROUPS), &d.path)?;
+                    if !functions.is_empty() {
+                        vfio.insert(d.path.clone(), functions);
+                    }
+                }
+                let functions = vfio.values().map(|f| f.len()).sum();
+                self.device_manager
+                    .read()
+                    .await
+                    .check_free_pci_slots(functions)
+                    .context("validate devices")?;
+                info!(
+                    sl!(),
+                    "attach {} devices of container {} in bulk: {:?}",
+                    devices.len(),
+                    cid,
+                    groups
+                );
+                let count = slot_device_count(devices);
+                self.limits.take_devices(cid, count)?;
+                let result = self.attach_batch(cid, devices, &vfio).await;
+                if result.is_err() {
+                    self.limits.give_back_devices(cid, count);
+                }
+                result
+            })
+            .await
+    }
+
+    // attach_batch attaches the devices after their kernel modules are
+    // loaded in the guest, the ones attached are detached on a failure
+    async fn attach_batch(
+        &self,
+        cid: &str,
+        devices: &[LinuxDevice],
+        vfio: &HashMap<String, Vec<String>>,
+    ) -> Result<Vec<Device>> {
+        self.load_device_kernel_modules(devices)
+            .await
+            .context("load device kernel modules")?;
+
+        let mut attached = vec![];
+        match self.attach_devices(cid, devices, vfio, &mut attached).await {
+            std::result::Result::Ok(devices) => Ok(devices),
+            Err(e) => {
+                self.detach_devices(cid, attached).await;
+                Err(e)
+            }
+        }
+    }
+
+    // attach_devices attaches the devices of the container concurrently,
+    // each one out of the lock of the device manager, the ids of the ones
+    // attached are added to attached so that they're detached on a failure.
+    // The vfio groups are passed through with their functions in vfio, the
+    // other ones are left to the container.
+    async fn attach_devices(
+        &self,
+        cid: &str,
+        linux_devices: &[LinuxDevice],
+        vfio: &HashMap<String, Vec<String>>,
+        attached: &mut Vec<String>,
+    ) -> Result<Vec<Device>> {
+        let results = future::join_all(linux_devices.iter().map(|d| async move {
+            let mut ids = vec![];
+            let result = match (d.r#type.as_str(), vfio.get(&d.path)) {
+                ("b", _) => self.attach_block_device(cid, d, &mut ids).await,
+                (_, Some(functions)) => self
+                    .attach_vfio_group(cid, d, functions, &mut ids)
+                    .await
+                    .map(Some),
+                // TODO enable other devices type
+                _ => Ok(None),
+            };
+            (result, ids)
+        }))
+        .await;
+
+        let mut devices = vec![];
+        let mut first_error = None;
+        for (result, mut ids) in results {
+            attached.append(&mut ids);
+            match result {
+                std::result::Result::Ok(device) => devices.extend(device),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(devices),
+        }
+    }
+
+    async fn attach_block_device(
+        &self,
+        cid: &str,
+        d: &LinuxDevice,
+        attached: &mut Vec<String>,
+    ) -> Result<Option<Device>> {
+        // the device may be attached already, e.g. as the block rootfs of
+        // the container, which owns and detaches it
+        let found = self
+            .device_manager
+            .read()
+            .await
+            .find_block_device(d.major, d.minor)
+            .await;
+        let device_info = match found {
+            Some(device_info) => {
+                info!(
+                    sl!(),
+                    "device {} of container {} is attached already", d.path, cid
+                );
+                device_info
+            }
+            None => {
+                let dev_info = DeviceConfig::BlockCfg(BlockConfig {
+                    major: d.major,
+                    minor: d.minor,
+                    ..Default::default()
+                });
+                let target = format!("{}:{}", d.major, d.minor);
+                let device_info = self
+                    .timings
+                    .time_resource(
+                        timings::PHASE_DEVICE_ATTACH,
+                        cid,
+                        &target,
+                        metrics::handle_device(&self.device_manager, &dev_info),
+                    )
+                    .await
+                    .context("do handle device")?;
+                if let DeviceType::Block(device) = &device_info {
+                    attached.push(device.device_id.clone());
+                    self.events.emit(ResourceEventKind::DeviceAttached {
+                        container_id: cid.to_string(),
+                        device_id: device.device_id.clone(),
+                    });
+                }
+                device_info
+            }
+        };
+
+        // the weights given to the device are set on the host only once
+        // it's attached
+        self.cgroups_resource
+            .device_attached(d.major, d.minor)
+            .await
+            .with_context(|| format!("set weight of device {}", d.path))?;
+
+        // create agent device
+        if let DeviceType::Block(device) = device_info {
+            // the agent finds a scsi disk by its address
+            let id = match device.config.scsi_addr {
+                Some(addr) => addr.guest_addr(),
+                None => device.device_id.clone(),
+            };
+            let agent_device = Device {
+                id,
+                container_path: d.path.clone(),
+                field_type: device.config.driver_option,
+                vm_path: device.config.virt_path,
+                options: device_node_options(d),
+            };
+            return Ok(Some(agent_device));
+        }
+        Ok(None)
+    }
+
+    // attach_vfio_group passes the functions of the IOMMU group through, the
+    // agent finds each of them at the guest pci slot it's placed at
+    async fn attach_vfio_group(
+        &self,
+        cid: &str,
+        d: &LinuxDevice,
+        functions: &[String],
+        attached: &mut Vec<String>,
+    ) -> Result<Device> {
+        let mut options = vec![];
+        for bdf in functions {
+            let dev_info = DeviceConfig::VfioCfg(VfioConfig {
+                sysfs_path: "".to_string(),
+                bus_slot_func: bdf.clone(),
+                mode: VfioBusMode::PCI,
+                topology_hint: Some(PciTopology::on_host_numa_node(bdf)),
+            });
+            let device_info = self
+                .timings
+                .time_resource(
+                    timings::PHASE_DEVICE_ATTACH,
+                    cid,
+                    bdf,
+                    metrics::handle_device(&self.device_manager, &dev_info),
+                )
+                .await
+                .with_context(|| format!("pass through {} of {}", bdf, d.path))?;
+            if let DeviceType::Vfio(device) = device_info {
+                attached.push(device.id.clone());
+                self.events.emit(ResourceEventKind::DeviceAttached {
+                    container_id: cid.to_string(),
+                    device_id: device.id.clone(),
+                });
+                let slot = device
+                    .guest_pci_slot
+                    .ok_or_else(|| anyhow!("{} has no guest pci slot", bdf))?;
+                options.push(format!("{}={:02x}", bdf, slot));
+            }
+        }
+        Ok(Device {
+            id: d.path.clone(),
+            container_path: d.path.clone(),
+            field_type: VFIO_PCI.to_string(),
+            vm_path: "".to_string(),
+            options,
+        })
+    }
+
+    // detach_devices detaches the devices attached for a container which
+    // fails to be created, the same way as the block volumes are detached.
+    // The weights of the block devices gone from the guest aren't set anymore.
+    async fn detach_devices(&self, cid: &str, attached: Vec<String>) {
+        let mut device_manager = self.device_manager.write().await;
+        let mut detached = vec![];
+        for id in attached.into_iter().rev() {
+            let numbers =
+                device_manager
+                    .list_devices()
+                    .await
+                    .into_iter()
+                    .find_map(|info| match info {
+                        DeviceType::Block(device) if device.device_id == id => {
+                            Some((device.config.major, device.config.minor))
+                        }
+                        _ => None,
+                    });
+            info!(sl!(), "roll back device {}", id);
+            if let Err(e) = device_manager.try_remove_device(&id).await {
+                warn!(sl!(), "couldn't roll back device {}: {:?}", id, e);
+                continue;
+            }
+            self.events.emit(ResourceEventKind::DeviceDetached {
+                container_id: cid.to_string(),
+                device_id: id,
+            });
+            // the device shared with another container is still attached
+            if let Some((major, minor)) = numbers {
+                if device_manager
+                    .find_block_device(major, minor)
+                    .await
+                    .is_none()
+                {
+                    detached.push((major, minor));
+                }
+            }
+        }
+        drop(device_manager);
+
+        for (major, minor) in detached {
+            self.cgroups_resource.device_detached(major, minor).await;
+        }
+    }
+
+    // the guest kernel modules of the device classes are loaded once before
+    // the first device of the class is handed to a container
+    async fn load_device_kernel_modules(&self, devices: &[LinuxDevice]) -> Result<()> {
+        let modules = match self
+            .toml_config
+            .agent
+            .get(&self.toml_config.runtime.agent_name)
+        {
+            Some(agent) if !agent.device_kernel_modules.is_empty() => &agent.device_kernel_modules,
+            _ => return Ok(()),
+        };
+
+        let mut loaded = self.loaded_device_classes.lock().await;
+        for class in pending_device_classes(modules, devices, &loaded) {
+            self.agent_features
+                .require(AgentFeature::LoadKernelModules)
+                .with_context(|| format!("load kernel modules of {} devices", class))?;
+            info!(
+                sl!(),
+                "load guest kernel modules {:?} of {} devices", modules[class], class
+            );
+            let req = LoadKernelModulesRequest {
+                modules: KernelModule::set_kernel_modules(modules[class].clone())?,
+            };
+            self.agent
+                .load_kernel_modules(req)
+                .await
+                .with_context(|| format!("load kernel modules of {} devices", class))?;
+            loaded.insert(class);
+        }
+        Ok(())
+    }
+
+    async fn handle_sandbox_bindmounts(&self, setup: bool) -> Result<()> {
+        let bindmounts = self.sandbox_bind_mounts.clone();
+        if bindmounts.is_empty() {
+            info!(sl!(), "sandbox bindmounts empty, just skip it.");
+            return Ok(());
+        }
+
+        let sb_bindmnt = SandboxBindMounts::new(self.sid.clone(), bindmounts)?;
+
+        if setup {
+            sb_bindmnt.setup_sandbox_bind_mounts(&self.path_jail)
+        } else {
+            sb_bindmnt.cleanup_sandbox_bind_mounts()
+        }
+    }
+
+    /// handler_oom_score_adj forwards the oom_score_adj of the container
+    /// process, e.g. set by kubernetes for the eviction order, to the agent
+    /// with the spec. It's the order of the containers killed by the OOM
+    /// killer of the guest only, kept above the one of the agent. The VM
+    /// process on the host is a separate concern: it keeps the oom_score_adj
+    /// it inherited from the shim whatever the containers ask for.
+    pub fn handler_oom_score_adj(
+        &self,
+        cid: &str,
+        process: Option<&mut oci::Process>,
+    ) -> Result<()> {
+        let process = match process {
+            Some(process) => process,
+            None => return Ok(()),
+        };
+        let oom_score_adj = guest_oom_score_adj(process.oom_score_adj)
+            .with_context(|| format!("oom_score_adj of container {}", cid))?;
+        if oom_score_adj != process.oom_score_adj {
+            info!(
+                sl!(),
+                "oom_score_adj {:?} of container {} raised to {:?} above the agent",
+                process.oom_score_adj,
+                cid,
+                oom_score_adj
+            );
+        }
+        process.oom_score_adj = oom_score_adj;
+        Ok(())
+    }
+
+    /// handler_block_io keeps the parts of the block io of the container
+    /// which apply to its cgroup in the guest: the weight, and the weights
+    /// of the block devices attached to the guest, which the agent
+    /// translates to their numbers in the guest. The weights of the other
+    /// devices are dropped with a warning, the throttling is left to the
+    /// rate limiters on the host.
+    pub async fn handler_block_io(&self, cid: &str, resources: Option<&mut LinuxResources>) {
+        let block_io = match resources.and_then(|r| r.block_io.as_mut()) {
+            Some(block_io) => block_io,
+            None => return,
+        };
+
+        let device_manager = self.device_manager.read().await;
+        let mut weight_device = vec![];
+        for d in block_io.weight_device.drain(..) {
+            if device_manager
+                .find_block_device(d.blk.major, d.blk.minor)
+                .await
+                .is_none()
+            {
+                warn!(
+                    sl!(),
+                    "drop weight of device {}:{} of container {} not attached to the guest",
+                    d.blk.major,
+                    d.blk.minor,
+                    cid
+                );
+                continue;
+            }
+            weight_device.push(d);
+        }
+        block_io.weight_device = weight_device;
+
+        block_io.throttle_read_bps_device.clear();
+        block_io.throttle_write_bps_device.clear();
+        block_io.throttle_read_iops_device.clear();
+        block_io.throttle_write_iops_device.clear();
+    }
+
+    pub async fn update_cgroups(
+        &self,
+        cid: &str,
+        linux_resources: Option<&LinuxResources>,
+    ) -> Result<()> {
+        let _in_flight = self.quiesce_gate.enter()?;
+
+        // the sandbox is sized at boot with static resource management
+        if !self.toml_config.runtime.static_sandbox_resource_mgmt {
+            let h = self.hypervisor.as_ref();
+            let agent = self.agent.as_ref();
+            let old_vcpus = self
+                .cpu_resource
+                .update_cpu_resources(cid, linux_resources, h, agent)
+                .await
+                .context("update cpu resources")?;
+            if let Err(e) = self
+                .mem_resource
+                .update_mem_resources(cid, linux_resources, h, agent)
+                .await
+            {
+                // the vcpus go back too, the sandbox is left as it was
+                if let Err(err) = self
+                    .cpu_resource
+                    .revert_cpu_resources(cid, old_vcpus, h, agent)
+                    .await
+                {
+                    warn!(
+                        sl!(),
+                        "failed to revert the vcpus of container {}: {:?}", cid, err
+                    );
+                }
+                return Err(e).context("update mem resources");
+            }
+        }
+
         self.cgroups_resource
-            .delete()
+            .update_cgroups(cid, linux_resources, self.hypervisor.as_ref())
+            .await?;
+        // the vCPUs and the cpuset are resized with the containers
+        self.check_vcpus_pinning().await;
+        Ok(())
+    }
+
+    /// delete_container_resources forgets the resources of the removed
+    /// container and shrinks the sandbox to what the rest of the containers
+    /// need.
+    pub async fn delete_container_resources(&self, cid: &str) -> Result<()> {
+        let h = self.hypervisor.as_ref();
+        let agent = self.agent.as_ref();
+
+        // the volumes and the rootfs are cleaned up by the container already
+        let volumes = self.volume_resource.delete_container(cid).await;
+        self.emit_volumes(cid, &volumes, false);
+        let rootfs = self.rootfs_resource.delete_container(cid).await;
+        self.emit_rootfs_devices(cid, &rootfs, false).await;
+        self.detach_sriov_vfs(cid).await;
+        self.limits.remove_container(cid);
+        // the rootfs and the volumes are unshared by now
+        let export_result = match self.container_exports.as_ref() {
+            Some(exports) => exports
+                .release(cid)
+                .await
+                .context("release share fs export"),
+            None => Ok(()),
+        };
+        self.mem_resource.release_container_mem(cid).await;
+        let mut result = self.cgroups_resource.delete_container(cid, h).await;
+        if !self.toml_config.runtime.static_sandbox_resource_mgmt {
+            // both are tried so that neither keeps the removed container
+            let cpu_result = self.cpu_resource.remove_cpu_resources(cid, h, agent).await;
+            let mem_result = self.mem_resource.remove_mem_resources(cid, h, agent).await;
+            result = result
+                .and(cpu_result.context("remove cpu resources"))
+                .and(mem_result.context("remove mem resources"));
+        }
+        self.check_vcpus_pinning().await;
+        result.and(export_result)
+    }
+
+    /// remove_volume unmounts the volume from the guest and detaches its
+    /// device while the container is running. The volume is unmounted from
+    /// the container before its storage, a volume still in use by the
+    /// container isn't forced to unmount, EBUSY is returned instead. The
+    /// removed volume is returned so that the container stops cleaning it
+    /// up.
+    pub async fn remove_volume(&self, cid: &str, volume_source: &str) -> Result<Arc<dyn Volume>> {
+        let _in_flight = self.quiesce_gate.enter()?;
+
+        let volume = self.volume_resource.get_volume(cid, volume_source).await?;
+
+        // the storages shared with other containers are left in the guest
+        let mount_points = self
+            .volume_resource
+            .removable_storages(volume.as_ref())
+            .await
+            .context("get storage")?;
+        let mounts = volume.get_volume_mount().context("get volume mount")?;
+        for mount_point in mount_points {
+            // the storage stays mounted in the guest through the mounts of
+            // the container, which are unmounted first
+            let container_paths = mounts
+                .iter()
+                .filter(|m| Path::new(&m.source).starts_with(&mount_point))
+                .map(|m| m.destination.clone())
+                .collect();
+            let req = RemoveStorageRequest {
+                mount_point: mount_point.clone(),
+                container_id: cid.to_string(),
+                container_paths,
+            };
+            if let Err(e) = self.agent.remove_storage(req).await {
+                if is_busy_error(&e) {
+                    return Err(anyhow!(Errno::EBUSY))
+                        .with_context(|| format!("volume {} is busy", volume_source));
+                }
+                return Err(e).with_context(|| format!("remove storage {}", mount_point));
+            }
+        }
+
+        // the device shared by other volumes is only detached by the last one
+        volume
+            .cleanup(self.device_manager.as_ref())
+            .await
+            .with_context(|| format!("clean up volume {}", volume_source))?;
+        self.volume_resource.remove_volume(cid, volume_source).await;
+        self.emit_volumes(cid, &[volume.clone()], false);
+
+        info!(
+            sl!(),
+            "volume {} of container {} removed", volume_source, cid
+        );
+        Ok(volume)
+    }
+
+    /// resize_direct_volume grows the block volume mounted from the path on
+    /// the host, its source or the path of its device, to the new size in
+    /// bytes once its device is grown on the host. The volume shared by
+    /// several containers is grown once, and its size is kept for all of
+    /// them. A path of no volume set up by the sandbox is handed to the
+    /// agent as is.
+    pub async fn resize_direct_volume(&self, volume_path: &str, new_size: u64) -> Result<()> {
+        let _in_flight = self.quiesce_gate.enter()?;
+
+        let mut volumes = vec![];
+        for v in self.volume_resource.save().await {
+            let device_path = match v.device_id.as_ref() {
+                Some(device_id) => {
+                    self.device_manager
+                        .read()
+                        .await
+                        .block_device_path(device_id)
+                        .await
+                }
+                None => None,
+            };
+            if Path::new(&v.source) == Path::new(volume_path)
+                || device_path.as_deref() == Some(volume_path)
+            {
+                volumes.push(v);
+            }
+        }
+        // the volumes restored aren't set up again, only their size is kept
+        let mut live = None;
+        for v in volumes.iter() {
+            if self
+                .volume_resource
+                .get_volume(&v.cid, &v.source)
+                .await
+                .is_ok()
+            {
+                live = Some(v);
+                break;
+            }
+        }
+        match live {
+            Some(v) => self.resize_volume(&v.cid, &v.source, new_size).await?,
+            None => {
+                let req = ResizeVolumeRequest {
+                    volume_guest_path: volume_path.to_string(),
+                    size: new_size,
+                };
+                self.agent
+                    .resize_volume(req)
+                    .await
+                    .with_context(|| format!("resize volume {}", volume_path))?;
+            }
+        }
+        for v in volumes.iter() {
+            self.volume_resource
+                .set_volume_size(&v.cid, &v.source, new_size)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // resize_volume grows the block volume of the container mounted from the
+    // source on the host: the hypervisor tells the guest the new size of the
+    // device, then the agent grows the filesystem online
+    async fn resize_volume(&self, cid: &str, volume_source: &str, new_size: u64) -> Result<()> {
+        let volume = self.volume_resource.get_volume(cid, volume_source).await?;
+        let device_id = volume
+            .get_device_id()?
+            .ok_or_else(|| anyhow!("volume {} isn't a block volume", volume_source))?;
+        // the storage is added to the agent by the first container mounting it
+        let storage = volume
+            .get_referenced_storage()?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("no storage of volume {}", volume_source))?;
+        if storage.options.iter().any(|o| o == "ro") {
+            return Err(anyhow!("volume {} is read-only", volume_source));
+        }
+
+        // the guest may see the new size by itself, e.g. the device rescanned,
+        // the agent checks the size of the device before growing the
+        // filesystem anyway
+        if let Err(e) = self
+            .device_manager
+            .read()
+            .await
+            .resize_block_device(&device_id, new_size)
+            .await
+        {
+            warn!(
+                sl!(),
+                "hypervisor can't resize the device of volume {}: {:?}", volume_source, e
+            );
+        }
+        // the device passed through to the container has no filesystem to grow
+        if storage.fs_type != "bind" {
+            let req = ResizeVolumeRequest {
+                volume_guest_path: storage.mount_point.clone(),
+                size: new_size,
+            };
+            self.agent
+                .resize_volume(req)
+                .await
+                .with_context(|| format!("grow filesystem of volume {}", volume_source))?;
+        }
+        self.volume_resource
+            .set_volume_size(cid, volume_source, new_size)
+            .await?;
+
+        info!(
+            sl!(),
+            "volume {} of container {} resized to {} bytes", volume_source, cid, new_size
+        );
+        Ok(())
+    }
+
+    /// volume_stats returns the usage of the filesystem of the volume mounted
+    /// from the path on the host, e.g. the publish path of the kubelet, as
+    /// the containers see it. The filesystems in the guest, e.g. of the block
+    /// volumes or of the emptyDir volumes in the guest, are stat'ed by the
+    /// agent by the driver of their storage, the volumes shared from the host
+    /// are stat'ed on the host. It fails with a VolumeNotFound if no
+    /// container mounts it.
+    pub async fn volume_stats(&self, volume_path: &str) -> Result<VolumeStats> {
+        let volume = self
+            .volume_resource
+            .find_volume(volume_path)
+            .await
+            .ok_or_else(|| VolumeNotFound {
+                path: volume_path.to_string(),
+            })?;
+        volume::volume_stats(self.agent.as_ref(), volume_path, volume.as_ref()).await
+    }
+
+    pub async fn set_balloon_target(&self, bytes: u64) -> Result<()> {
+        let _in_flight = self.quiesce_gate.enter()?;
+        self.mem_resource
+            .set_balloon_target(bytes, self.hypervisor.as_ref())
+            .await
+    }
+
+    pub async fn reclaim_memory(&self, target_mb: u64) -> Result<()> {
+        let _in_flight = self.quiesce_gate.enter()?;
+        self.mem_resource
+            .reclaim_memory(target_mb, self.hypervisor.as_ref())
+            .await
+    }
+
+    /// reclaim_free_memory takes back the memory the guest reports as free,
+    /// the sandbox container is the one of the sandbox id.
+    pub async fn reclaim_free_memory(&self) -> Result<()> {
+        let _in_flight = self.quiesce_gate.enter()?;
+        self.mem_resource
+            .reclaim_free_memory(&self.sid, self.hypervisor.as_ref(), self.agent.as_ref())
+            .await
+    }
+
+    /// quiesce refuses the new resource mutations with Quiesced, e.g. the
+    /// devices, the volumes and the hotplug, and waits for the ones in
+    /// flight to complete, so that the state saved afterwards is stable.
+    /// The queries, save and cleanup are still allowed. Quiescing again is
+    /// harmless, the state isn't saved and a restored sandbox is resumed.
+    pub async fn quiesce(&self) {
+        self.quiesce_gate.quiesce().await;
+        info!(sl!(), "resources of sandbox {} quiesced", self.sid);
+    }
+
+    pub fn resume(&self) {
+        self.quiesce_gate.resume();
+        info!(sl!(), "resources of sandbox {} resumed", self.sid);
+    }
+
+    /// allocate_vsock allocates the vsock cid of the guest before the VM is
+    /// prepared, it's released with the cleanup of the sandbox.
+    pub async fn allocate_vsock(&self, backend: VsockBackend) -> Result<VsockAllocation> {
+        self.vsock
+            .allocate_cid(backend)
+            .await
+            .context("allocate vsock cid")
+    }
+
+    pub fn is_quiesced(&self) -> bool {
+        self.quiesce_gate.is_quiesced()
+    }
+
+    /// cleanup runs every step regardless of the earlier failures, which are
+    /// returned together. The resources gone already are skipped, so that a
+    /// failed cleanup could be retried.
+    pub async fn cleanup(&self) -> Result<()> {
+        let mut errors = CleanupErrors::default();
+        // the host side of the network goes before the cgroups, so that the
+        // vhost threads are gone
+        errors.check("cleanup network", self.cleanup_network().await);
+        errors.check("delete cgroup", self.cgroups_resource.delete().await);
+
+        // cleanup sandbox bind mounts: setup = false, there are none to
+        // clean up if nothing is shared from the host
+        if self.has_sandbox_bindmounts() {
+            errors.check(
+                "cleanup sandbox bindmounts",
+                self.handle_sandbox_bindmounts(false).await,
+            );
+        }
+
+        // the exports are under the shared directory of the sandbox
+        errors.check(
+            "cleanup container exports",
+            self.cleanup_container_exports().await,
+        );
+        errors.check("cleanup share fs", self.cleanup_share_fs().await);
+        // remove the backing file of the guest swap
+        if let Some(swap) = &self.swap {
+            errors.check("cleanup guest swap", swap.cleanup().await);
+        }
+        errors.check("release vsock", self.vsock.release());
+        // the VFs left are bound back to their host drivers and reset on
+        // their PFs, the VM is gone
+        self.sriov_resource.release().await;
+        // TODO cleanup other resources
+        errors.into_result()?;
+        self.teardown_armed.store(false, Ordering::SeqCst);
+        share_fs::remove_host_shared_root(&self.sid);
+        self.events.emit(ResourceEventKind::CleanupCompleted);
+        Ok(())
+    }
+
+    // remove the host artifacts of the endpoints, the live ones or the
+    // restored ones, the netns may outlive the sandbox
+    async fn cleanup_network(&self) -> Result<()> {
+        let network = self.network.clone();
+        let names = match network.as_ref() {
+            Some(network) => interface_names(network.as_ref()).await,
+            None => vec![],
+        };
+        let endpoints = self.restored_endpoints.clone();
+        block_on_thread(move || delete_network(network, endpoints)).await?;
+        self.emit_endpoints_removed(names);
+        Ok(())
+    }
+
+    // clean up share fs mount
+    async fn cleanup_share_fs(&self) -> Result<()> {
+        if let Some(share_fs) = &self.share_fs {
+            share_fs
+                .get_share_fs_mount()
+                .cleanup(&self.sid)
+                .await
+                .context("failed to cleanup host path")?;
+        }
+        Ok(())
+    }
+
+    async fn cleanup_container_exports(&self) -> Result<()> {
+        if let Some(exports) = &self.container_exports {
+            exports.cleanup().await?;
+        }
+        Ok(())
+    }
+
+    fn has_sandbox_bindmounts(&self) -> bool {
+        !self.no_host_sharing && !self.guest_protection.is_protected()
+    }
+
+    // the blocking best-effort part of cleanup, for the sandbox dropped
+    // without cleanup, e.g. on a panic, what can't be cleaned is logged
+    fn teardown_on_drop(&self) {
+        let network = self.network.clone();
+        let endpoints = self.restored_endpoints.clone();
+        if let Err(e) = thread::spawn(move || {
+            runtime::Builder::new_current_thread()
+                .enable_io()
+                .build()
+                .map_err(|e| anyhow!(e))
+                .and_then(|rt| rt.block_on(delete_network(network, endpoints)))
+        })
+        .join()
+        .map_err(|e| anyhow!("{:?}", e))
+        .and_then(|r| r)
+        {
+            warn!(sl!(), "couldn't clean up network: {:?}", e);
+        }
+
+        if self.has_sandbox_bindmounts() && !self.sandbox_bind_mounts.is_empty() {
+            if let Err(e) =
+                SandboxBindMounts::new(self.sid.clone(), self.sandbox_bind_mounts.clone())
+                    .and_then(|b| b.cleanup_sandbox_bind_mounts())
+            {
+                warn!(sl!(), "couldn't clean up sandbox bindmounts: {:?}", e);
+            }
+        }
+
+        if let Some(exports) = &self.container_exports {
+            if let Err(e) = exports.kill_daemons() {
+                warn!(sl!(), "couldn't kill container export daemons: {:?}", e);
+            }
+            if let Err(e) = exports.lazy_umount() {
+                warn!(sl!(), "couldn't umount container export paths: {:?}", e);
+            }
+        }
+        if let Some(share_fs) = &self.share_fs {
+            if let Err(e) = share_fs.kill_daemon() {
+                warn!(sl!(), "couldn't kill share fs daemon: {:?}", e);
+            }
+            if let Err(e) = share_fs::lazy_umount_shared_path(&self.sid) {
+                warn!(sl!(), "couldn't umount share fs path: {:?}", e);
+            }
+        }
+
+        if let Err(e) = self.vsock.release() {
+            warn!(sl!(), "couldn't release vsock: {:?}", e);
+        }
+        share_fs::remove_host_shared_root(&self.sid);
+    }
+
+    /// verify checks the resources restored against the guest and the host,
+    /// the discrepancies found are returned instead of failing, so that the
+    /// caller could decide to reconcile or abort.
+    pub async fn verify(&self) -> Result<Vec<Inconsistency>> {
+        let mut inconsistencies = vec![];
+
+        // every saved endpoint maps to a live guest interface
+        let endpoints = match self.network.as_ref() {
+            Some(network) => network.save().await.unwrap_or_default(),
+            None => self.restored_endpoints.clone(),
+        };
+        if !endpoints.is_empty() {
+            let interfaces = self
+                .agent
+                .list_interfaces(agent::Empty::new())
+                .await
+                .context("list interfaces")?
+                .interfaces;
+            let names: HashSet<&str> = interfaces.iter().map(|i| i.name.as_str()).collect();
+            let hw_addrs: HashSet<&str> = interfaces.iter().map(|i| i.hw_addr.as_str()).collect();
+            for ep in endpoints.iter() {
+                if let Some(physical) = ep.physical_endpoint.as_ref() {
+                    if !hw_addrs.contains(physical.hard_addr.as_str()) {
+                        inconsistencies.push(Inconsistency::EndpointWithoutInterface(
+                            physical.hard_addr.clone(),
+                        ));
+                    }
+                    continue;
+                }
+                let if_name = ep
+                    .veth_endpoint
+                    .as_ref()
+                    .map(|e| &e.if_name)
+                    .or_else(|| ep.ipvlan_endpoint.as_ref().map(|e| &e.if_name))
+                    .or_else(|| ep.macvlan_endpoint.as_ref().map(|e| &e.if_name))
+                    .or_else(|| ep.macvtap_endpoint.as_ref().map(|e| &e.if_name))
+                    .or_else(|| ep.vlan_endpoint.as_ref().map(|e| &e.if_name));
+                if let Some(if_name) = if_name {
+                    if !names.contains(if_name.as_str()) {
+                        inconsistencies
+                            .push(Inconsistency::EndpointWithoutInterface(if_name.clone()));
+                    }
+                }
+            }
+        }
+
+        // every device is still attached, the hypervisors don't report the
+        // devices plugged, so the attach count and the backend are checked
+        let device_manager = self.device_manager.read().await;
+        let mut checked_ids = HashSet::new();
+        let mut checked_paths = HashSet::new();
+        for device in device_manager.list_devices().await {
+            if let DeviceType::Block(block) = device {
+                checked_ids.insert(block.device_id.clone());
+                checked_paths.insert(block.config.path_on_host.clone());
+                if block.attach_count == 0 {
+                    inconsistencies.push(Inconsistency::DeviceNotAttached(block.device_id));
+                    continue;
+                }
+                let path = block.config.path_on_host;
+                if !path.is_empty() && !Path::new(&path).exists() {
+                    inconsistencies.push(Inconsistency::DeviceBackendMissing {
+                        device_id: block.device_id,
+                        path,
+                    });
+                }
+            }
+        }
+        // the device manager knows none of the devices after a restore, the
+        // ones of the saved volumes are checked against the hypervisor and
+        // the ones holding guest pci slots by their backend
+        if let Some(attached) = self
+            .hypervisor
+            .attached_devices()
             .await
-            .context("delete cgroup")?;
+            .context("attached devices")?
+        {
+            let saved_ids: BTreeSet<String> = self
+                .volume_resource
+                .save()
+                .await
+                .into_iter()
+                .filter_map(|v| v.device_id)
+                .collect();
+            for device_id in saved_ids {
+                if !checked_ids.contains(&device_id) && !attached.contains(&device_id) {
+                    inconsistencies.push(Inconsistency::DeviceNotAttached(device_id));
+                }
+            }
+        }
+        for s in device_manager.save_pci_slots() {
+            if !checked_paths.contains(&s.host_path) && !Path::new(&s.host_path).exists() {
+                inconsistencies.push(Inconsistency::PciSlotBackendMissing {
+                    slot: s.slot,
+                    path: s.host_path,
+                });
+            }
+        }
+        drop(device_manager);
+
+        // the cgroup paths exist
+        for path in self.cgroups_resource.missing_cgroups() {
+            inconsistencies.push(Inconsistency::CgroupMissing(path));
+        }
+
+        if !inconsistencies.is_empty() {
+            warn!(sl!(), "resources inconsistent: {:?}", inconsistencies);
+        }
+        Ok(inconsistencies)
+    }
+
+    /// ready checks the resources of the sandbox are ready once the VM is
+    /// started, before the pod is marked running: the share fs is healthy,
+    /// the interfaces are up in the guest, the storages of the sandbox are
+    /// mounted and the devices are attached. They're checked again until
+    /// they're all met by the resource_ready retry policy, it gives up after
+    /// READY_TIMEOUT or the attempts with the first condition unmet. Unlike
+    /// verify, it's for the fresh startup, not for the restore.
+    pub async fn ready(&self) -> Result<()> {
+        let start = time::Instant::now();
+        let mut delays = self
+            .toml_config
+            .runtime
+            .retry
+            .resource_ready
+            .unwrap_or(DEFAULT_READY_RETRY_POLICY)
+            .delays();
+        loop {
+            let remaining = READY_TIMEOUT.saturating_sub(start.elapsed());
+            let err = match time::timeout(remaining, self.check_ready()).await {
+                std::result::Result::Ok(std::result::Result::Ok(())) => return Ok(()),
+                std::result::Result::Ok(Err(e)) => e,
+                Err(_) => anyhow!("check timed out"),
+            };
+            let waited = start.elapsed();
+            let delay = match delays.next() {
+                Some(delay) if waited < READY_TIMEOUT => delay,
+                _ => {
+                    return Err(err.context(format!(
+                        "resources of sandbox {} not ready after {:?}",
+                        self.sid, waited
+                    )))
+                }
+            };
+            debug!(
+                sl!(),
+                "resources of sandbox {} not ready yet: {:?}", self.sid, err
+            );
+            time::sleep(delay.min(READY_TIMEOUT - waited)).await;
+        }
+    }
+
+    async fn check_ready(&self) -> Result<()> {
+        if let Some(share_fs) = self.share_fs.as_ref() {
+            share_fs
+                .check_health()
+                .await
+                .context("share fs isn't healthy")?;
+        }
+        if let Some(exports) = self.container_exports.as_ref() {
+            exports
+                .check_health()
+                .await
+                .context("container exports aren't healthy")?;
+        }
+
+        if let Some(network) = self.network.as_ref() {
+            let expected = interface_names(network.as_ref()).await;
+            if !expected.is_empty() {
+                let interfaces = self
+                    .agent
+                    .list_interfaces(agent::Empty::new())
+                    .await
+                    .context("list interfaces")?
+                    .interfaces;
+                check_interfaces_up(&expected, &interfaces)?;
+            }
+        }
+
+        // an agent which can't stat the storages isn't asked for them
+        for storage in self.get_storage_for_sandbox().await? {
+            match share_fs::stat_mounted(self.agent.as_ref(), &storage.mount_point).await {
+                std::result::Result::Ok(_) => {}
+                Err(e) if is_unsupported_error(&e) => break,
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("storage {} isn't mounted", storage.mount_point))
+                }
+            }
+        }
+
+        for device in self.device_manager.read().await.list_devices().await {
+            if let DeviceType::Block(block) = device {
+                if block.attach_count == 0 {
+                    return Err(anyhow!("device {} isn't attached", block.device_id));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// non_migratable_volumes returns the container ids and the sources of
+    /// the volumes local to the host, the sandbox can't be migrated with
+    /// them unless they're handled by the migration.
+    pub async fn non_migratable_volumes(&self) -> Vec<(String, String)> {
+        self.volume_resource.non_migratable_volumes().await
+    }
+
+    /// detach_all_for_migration unplugs the devices of the sandbox before a
+    /// live migration, the state returned attaches them again on the
+    /// destination. The resources must be quiesced, so that no device is
+    /// attached meanwhile, and the devices passed through refuse the
+    /// migration.
+    pub async fn detach_all_for_migration(&self) -> Result<MigrationDeviceState> {
+        if !self.quiesce_gate.is_quiesced() {
+            return Err(anyhow!(
+                "resources of sandbox {} must be quiesced to detach the devices for migration",
+                self.sid
+            ));
+        }
+        let state = self
+            .device_manager
+            .write()
+            .await
+            .detach_all_for_migration()
+            .await
+            .context("detach devices for migration")?;
+        info!(
+            sl!(),
+            "{} devices of sandbox {} detached for migration",
+            state.devices.len(),
+            self.sid
+        );
+        Ok(state)
+    }
+
+    /// reattach_from_migration attaches the devices detached for the live
+    /// migration in the same guest slots, the weights given to them by the
+    /// containers are set again on the host for the ones attached, and the
+    /// ones which failed are forgotten.
+    pub async fn reattach_from_migration(&self, state: MigrationDeviceState) -> Result<()> {
+        let devices: Vec<(i64, i64)> = state
+            .devices
+            .iter()
+            .map(|d| (d.config.major, d.config.minor))
+            .filter(|(major, minor)| *major != 0 || *minor != 0)
+            .collect();
+        let mut attached = vec![];
+        let mut failed = vec![];
+        let result = {
+            let mut device_manager = self.device_manager.write().await;
+            let result = device_manager
+                .reattach_from_migration(state)
+                .await
+                .context("reattach devices from migration");
+            for (major, minor) in devices {
+                if device_manager
+                    .find_block_device(major, minor)
+                    .await
+                    .is_some()
+                {
+                    attached.push((major, minor));
+                } else {
+                    failed.push((major, minor));
+                }
+            }
+            result
+        };
+
+        for (major, minor) in attached {
+            if let Err(e) = self.cgroups_resource.device_attached(major, minor).await {
+                warn!(
+                    sl!(),
+                    "failed to set weight of device {}:{}: {:?}", major, minor, e
+                );
+            }
+        }
+        for (major, minor) in failed {
+            self.cgroups_resource.device_detached(major, minor).await;
+        }
+        result
+    }
+
+    /// guest_mount_count returns the mounts of the sandbox in the guest: the
+    /// storages of the share fs and of the exports of the containers, and the
+    /// mounts of the rootfs and of the volumes of the containers.
+    pub async fn guest_mount_count(&self) -> usize {
+        let share_fs = match self.share_fs.as_ref() {
+            Some(share_fs) => share_fs
+                .get_storages()
+                .await
+                .map(|s| s.len())
+                .unwrap_or_default(),
+            None => 0,
+        };
+        let exports = self
+            .container_exports
+            .as_ref()
+            .map(|e| e.in_use())
+            .unwrap_or_default();
+        share_fs
+            + exports
+            + self.limits.rootfs_mounts()
+            + self.volume_resource.guest_mount_count().await
+    }
+
+    pub async fn dump(&self) {
+        if self.guest_protection.is_protected() {
+            info!(
+                sl!(),
+                "guest protected by {}, share fs disabled", self.guest_protection
+            );
+        }
+        if let Some(initial_size) = &self.initial_size {
+            info!(sl!(), "initial size {:?}", initial_size);
+        }
+        info!(sl!(), "agent features: {}", self.agent_features);
+        for device in self.device_manager.read().await.list_devices().await {
+            info!(sl!(), "device {:?}", device);
+        }
+        info!(sl!(), "resource limits: {}", self.limits);
+        info!(sl!(), "guest mounts: {}", self.guest_mount_count().await);
+        self.rootfs_resource.dump().await;
+        self.volume_resource.dump().await;
+        self.mem_resource.dump().await;
+    }
+}
+
+#[async_trait]
+impl rollback::Setup for ResourceManagerInner {
+    type Config = ResourceConfig;
+    type Step = SetupStep;
+
+    fn describe(config: &ResourceConfig) -> String {
+        config.name().to_string()
+    }
+
+    async fn setup(&mut self, config: ResourceConfig, done: &mut Vec<SetupStep>) -> Result<()> {
+        let timings = self.timings.clone();
+        match config {
+            ResourceConfig::ShareFs(c) => {
+                timings
+                    .time(timings::PHASE_SHARE_FS, "", self.handle_share_fs(c, done))
+                    .await
+            }
+            ResourceConfig::Network(c) => {
+                done.push(SetupStep::Network);
+                // all the interfaces are hotplugged into the pooled VM
+                let cold_plug = self.vm_state == VmState::ColdBoot;
+                timings
+                    .time(
+                        timings::PHASE_NETWORK,
+                        "",
+                        self.handle_network(c, cold_plug),
+                    )
+                    .await
+                    .context("failed to handle network")
+            }
+            ResourceConfig::InitialSize(c) => timings
+                .time(timings::PHASE_INITIAL_SIZE, "", self.handle_initial_size(c))
+                .await
+                .context("failed to handle initial size"),
+            // written into the guest once the agent is connected
+            ResourceConfig::Hostname(c) => {
+                self.hostname = Some(c);
+                Ok(())
+            }
+            ResourceConfig::NetSysctls(c) => {
+                self.net_sysctls = c;
+                Ok(())
+            }
+        }
+    }
+
+    async fn undo(&mut self, step: SetupStep) -> Result<()> {
+        match step {
+            SetupStep::ShareFs => {
+                let result = self.cleanup_share_fs().await;
+                // the daemon isn't stopped with the VM never started
+                if let Some(share_fs) = self.share_fs.take() {
+                    share_fs.kill_daemon().context("kill share fs daemon")?;
+                }
+                result
+            }
+            SetupStep::ContainerExports => {
+                let result = self.cleanup_container_exports().await;
+                // the daemons aren't stopped with the VM never started
+                if let Some(exports) = self.container_exports.take() {
+                    exports
+                        .kill_daemons()
+                        .context("kill container export daemons")?;
+                }
+                result
+            }
+            SetupStep::SandboxBindMounts => self
+                .handle_sandbox_bindmounts(false)
+                .await
+                .context("cleanup sandbox bindmounts"),
+            SetupStep::Network => self.undo_network().await.context("undo network"),
+        }
+    }
+}
+
+// the failures of the cleanup steps, reported as one error
+#[derive(Default)]
+struct CleanupErrors(Vec<anyhow::Error>);
+
+impl CleanupErrors {
+    fn check(&mut self, step: &str, result: Result<()>) {
+        if let Err(e) = result {
+            warn!(sl!(), "{} failed: {:?}", step, e);
+            self.0.push(e.context(step.to_string()));
+        }
+    }
+
+    fn into_result(self) -> Result<()> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        let errors: Vec<String> = self.0.iter().map(|e| format!("{:#}", e)).collect();
+        Err(anyhow!(
+            "{} cleanup steps failed: {}",
+            errors.len(),
+            errors.join("; ")
+        ))
+    }
+}
+
+// setup_guest_network sets up the interfaces, neighbors and routes of the
+// network in the guest, and applies the net sysctls of the pod. The loopback
+// is left to the agent, which brings it up on its own.
+async fn setup_guest_network(
+    agent: &dyn Agent,
+    features: &AgentFeatures,
+    sid: &str,
+    network: Option<&dyn Network>,
+    net_sysctls: &HashMap<String, String>,
+) -> Result<()> {
+    if let Some(network) = network {
+        let req = network_request(network).await?;
+        send_network(agent, features, sid, req)
+            .await
+            .context("setup network")?;
+    }
+    if let Some(req) = sysctls_request(net_sysctls) {
+        send_sysctls(agent, sid, req)
+            .await
+            .context("set net sysctls")?;
+    }
+    Ok(())
+}
+
+// send_network sends the interfaces, neighbors and routes to the agent in
+// one request, or one by one if the agent doesn't support it.
+async fn send_network(
+    agent: &dyn Agent,
+    features: &AgentFeatures,
+    sid: &str,
+    req: agent::SetupNetworkRequest,
+) -> Result<()> {
+    info!(sl!(), "setup network {:?}", req);
+    if features.supports(AgentFeature::SetupNetwork) {
+        let e = match agent
+            .setup_network(req.clone())
+            .instrument(trace::agent_span("setup_network", sid))
+            .await
+        {
+            Err(e) => e,
+            _ => return Ok(()),
+        };
+        if !is_unsupported_error(&e) {
+            return Err(e);
+        }
+        info!(sl!(), "agent doesn't support setup network: {:?}", e);
+    }
+
+    for i in req.interfaces {
+        let name = i.name.clone();
+        agent
+            .update_interface(agent::UpdateInterfaceRequest { interface: Some(i) })
+            .instrument(trace::agent_span("update_interface", sid))
+            .await
+            .with_context(|| format!("update interface {}", name))?;
+    }
+    if let Some(neighbors) = req.neighbors {
+        agent
+            .add_arp_neighbors(agent::AddArpNeighborRequest {
+                neighbors: Some(neighbors),
+            })
+            .instrument(trace::agent_span("add_arp_neighbors", sid))
+            .await
+            .context("update neighbors")?;
+    }
+    if let Some(routes) = req.routes {
+        agent
+            .update_routes(agent::UpdateRoutesRequest {
+                route: Some(routes),
+            })
+            .instrument(trace::agent_span("update_routes", sid))
+            .await
+            .context("update routes")?;
+    }
+    Ok(())
+}
+
+async fn send_sysctls(agent: &dyn Agent, sid: &str, req: agent::SetSysctlsRequest) -> Result<()> {
+    info!(sl!(), "set sysctls {:?}", req.sysctls);
+    let e = match agent
+        .set_sysctls(req)
+        .instrument(trace::agent_span("set_sysctls", sid))
+        .await
+    {
+        Err(e) => e,
+        _ => return Ok(()),
+    };
+    if !is_unsupported_error(&e) {
+        return Err(e);
+    }
+    // the agent still applies them in the sandbox container
+    warn!(
+        sl!(),
+        "agent doesn't support set sysctls, left to the sandbox container: {:?}", e
+    );
+    Ok(())
+}
+
+// network_request collects the current interfaces, neighbors and routes of
+// the network for the agent
+async fn network_request(network: &dyn Network) -> Result<agent::SetupNetworkRequest> {
+    let mut req = agent::SetupNetworkRequest {
+        interfaces: network.interfaces().await.context("get interfaces")?,
+        ..Default::default()
+    };
+    let neighbors = network.neighs().await.context("neighs")?;
+    // the invalid neighbors are left out rather than failing the others
+    let neighbors = network::valid_neighbors(neighbors, &req.interfaces);
+    if !neighbors.is_empty() {
+        req.neighbors = Some(agent::ARPNeighbors { neighbors });
+    }
+    let routes = network.routes().await.context("routes")?;
+    if !routes.is_empty() {
+        req.routes = Some(agent::Routes { routes });
+    }
+    Ok(req)
+}
+
+// sysctls_request is the request of the net sysctls of the pod, none if it
+// has none
+fn sysctls_request(net_sysctls: &HashMap<String, String>) -> Option<agent::SetSysctlsRequest> {
+    if net_sysctls.is_empty() {
+        return None;
+    }
+    Some(agent::SetSysctlsRequest {
+        sysctls: net_sysctls.clone(),
+    })
+}
 
-        // cleanup sandbox bind mounts: setup = false
-        self.handle_sandbox_bindmounts(false)
-            .await
-            .context("failed to cleanup sandbox bindmounts")?;
+// the names of the interfaces of the network, for the events once it's
+// removed
+// network_queues returns the virtio-net queue pairs of each interface, one
+// per vCPU if the number isn't configured. The guest negotiates them down to
+// what the device offers, so a single queue is used if the hypervisor can't
+// provide multi-queue devices.
+fn network_queues(configured: usize, default_vcpus: i32, multi_queue: bool) -> usize {
+    if !multi_queue {
+        return 1;
+    }
+    match configured {
+        0 => default_vcpus.max(1) as usize,
+        n => n,
+    }
+}
 
-        // clean up share fs mount
-        if let Some(share_fs) = &self.share_fs {
-            share_fs
-                .get_share_fs_mount()
-                .cleanup(&self.sid)
-                .await
-                .context("failed to cleanup host path")?;
+async fn interface_names(network: &dyn Network) -> Vec<String> {
+    network
+        .interfaces()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|i| i.name)
+        .collect()
+}
+
+// check_interfaces_up checks the interfaces set up in the guest are there
+// and up
+fn check_interfaces_up(expected: &[String], interfaces: &[Interface]) -> Result<()> {
+    for name in expected {
+        match interfaces.iter().find(|i| &i.name == name) {
+            Some(i) if i.raw_flags & IFF_UP != 0 => {}
+            Some(_) => return Err(anyhow!("interface {} isn't up in the guest", name)),
+            None => return Err(anyhow!("no interface {} in the guest", name)),
         }
-        // TODO cleanup other resources
-        Ok(())
     }
+    Ok(())
+}
 
-    pub async fn dump(&self) {
-        self.rootfs_resource.dump().await;
-        self.volume_resource.dump().await;
+// the endpoints restored are deleted by their saved state, the network isn't
+// restored
+async fn delete_network(
+    network: Option<Arc<dyn Network>>,
+    endpoints: Vec<EndpointState>,
+) -> Result<()> {
+    match network {
+        Some(network) => network.delete().await,
+        None => network::delete_endpoints(&endpoints).await,
+    }
+}
+
+// validate_config fails with all the violations of the configuration at once,
+// the warnings are only logged unless strict_config_validation is set
+fn validate_config(violations: Vec<Violation>, toml_config: &TomlConfig) -> Result<()> {
+    let strict = toml_config.runtime.strict_config_validation;
+    let warnings = validate_violations(violations, strict).context("validate config")?;
+    for w in warnings.iter() {
+        warn!(sl!(), "config: {}", w);
+    }
+    Ok(())
+}
+
+// vfio_kernel_params returns the kernel parameters of the vfio devices cold
+// plugged, by device: the physical interfaces passed through with vfio are
+// the only devices cold plugged
+fn vfio_kernel_params(
+    vfio_params: &[String],
+    endpoints: &[EndpointState],
+) -> Vec<(String, String)> {
+    let mut requested = vec![];
+    for ep in endpoints.iter() {
+        let physical = match ep.physical_endpoint.as_ref() {
+            Some(physical) => physical,
+            None => continue,
+        };
+        if !ep.cold_plugged {
+            warn!(
+                sl!(),
+                "vfio device {} is hotplugged, it goes without the kernel parameters {:?}",
+                physical.bdf,
+                vfio_params
+            );
+            continue;
+        }
+        for param in vfio_params.iter() {
+            requested.push((format!("vfio device {}", physical.bdf), param.clone()));
+        }
+    }
+    requested
+}
+
+// saved_netns_path returns the netns of the saved state, the states saved
+// without it have the netns in the endpoints
+fn saved_netns_path(netns_path: Option<String>, endpoints: &[EndpointState]) -> Option<String> {
+    netns_path.or_else(|| {
+        endpoints
+            .iter()
+            .map(|e| e.netns_path.clone())
+            .find(|p| !p.is_empty())
+    })
+}
+
+// no_host_sharing tells if the hypervisor shares nothing from the host,
+// neither a filesystem nor block devices
+fn no_host_sharing(capabilities: &Capabilities) -> bool {
+    !capabilities.is_fs_sharing_supported() && !capabilities.is_block_device_supported()
+}
+
+// restore_no_host_sharing returns if nothing was shared from the host as the
+// sandbox was set up, the states saved without it have it derived from the
+// hypervisor again
+async fn restore_no_host_sharing(h: &dyn Hypervisor, saved: Option<bool>) -> Result<bool> {
+    match saved {
+        Some(no_host_sharing) => Ok(no_host_sharing),
+        None => Ok(no_host_sharing(&h.capabilities().await?)),
+    }
+}
+
+// restore_guest_protection returns the protection the guest was set up
+// with, the states saved without it have it probed again
+async fn restore_guest_protection(
+    h: &dyn Hypervisor,
+    saved: Option<GuestProtection>,
+) -> Result<GuestProtection> {
+    match saved {
+        Some(protection) => Ok(protection),
+        None => h.guest_protection().await,
+    }
+}
+
+// block_on_thread runs the future made by f in a new os thread, so that
+// the netns entered by the future never leaks to the other tasks. The
+// thread is left running if the caller gives up waiting for it.
+async fn block_on_thread<T, F, Fut>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<T>>,
+{
+    let (tx, rx) = oneshot::channel();
+    // the span isn't inherited by the new thread, it's passed explicitly
+    let span = Span::current();
+    thread::spawn(move || {
+        let result = runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .map_err(|e| anyhow!(e))
+            .and_then(|rt| rt.block_on(f().instrument(span)));
+        // the receiver is gone if the caller gave up
+        let _ = tx.send(result);
+    });
+    rx.await
+        .map_err(|e| anyhow!("{:?}", e))
+        .context("Couldn't join on the associated thread")?
+}
+
+// guest_oom_score_adj returns the oom_score_adj of the container process in
+// the guest, the agent is the last one killed by the OOM killer
+fn guest_oom_score_adj(oom_score_adj: Option<i32>) -> Result<Option<i32>> {
+    match oom_score_adj {
+        Some(adj) if !(OOM_SCORE_ADJ_MIN..=OOM_SCORE_ADJ_MAX).contains(&adj) => Err(anyhow!(
+            "{} isn't between {} and {}",
+            adj,
+            OOM_SCORE_ADJ_MIN,
+            OOM_SCORE_ADJ_MAX
+        )),
+        Some(adj) => Ok(Some(adj.max(AGENT_OOM_SCORE_ADJ + 1))),
+        None => Ok(None),
+    }
+}
+
+// a privileged container is allowed to access all the devices
+fn is_privileged(linux: &Linux) -> bool {
+    linux
+        .resources
+        .as_ref()
+        .map(|r| r.devices.iter().any(|d| *d == allow_all_devices()))
+        .unwrap_or(false)
+}
+
+// handle_privileged_devices leaves the host block devices listed in the spec
+// of the privileged container out, but the device nodes of the CDI devices it
+// asks for, with privileged_without_host_devices. Otherwise they're all
+// attached, refused beyond MAX_PRIVILEGED_BLOCK_DEVICES rather than
+// hotplugging them one by one until the hypervisor runs out of slots.
+fn handle_privileged_devices(
+    runtime: &Runtime,
+    cid: &str,
+    linux: &mut Linux,
+    requested: &BTreeSet<String>,
+) -> Result<()> {
+    if runtime.privileged_without_host_devices {
+        let count = linux.devices.len();
+        linux
+            .devices
+            .retain(|d| d.r#type != "b" || requested.contains(&d.path));
+        warn!(
+            sl!(),
+            "container {} is privileged, {} host block devices left out",
+            cid,
+            count - linux.devices.len()
+        );
+        return Ok(());
+    }
+
+    if runtime.privileged_sandbox {
+        warn!(
+            sl!(),
+            "container {} is privileged, attach the host block devices {:?}", cid, requested
+        );
+        add_host_block_devices(linux, requested).context("add host block devices")?;
+        // the agent applies the device cgroup in the guest
+        if let Some(resources) = linux.resources.as_mut() {
+            resources.devices = vec![allow_all_devices()];
+        }
+    }
+    let blocks = linux.devices.iter().filter(|d| d.r#type == "b").count();
+    if blocks > MAX_PRIVILEGED_BLOCK_DEVICES {
+        return Err(anyhow!(
+            "privileged container {} has {} block devices, more than {} could be attached, set privileged_without_host_devices to leave the host devices out",
+            cid,
+            blocks,
+            MAX_PRIVILEGED_BLOCK_DEVICES
+        ));
+    }
+    Ok(())
+}
+
+// handle_missing_devices checks the block and the char devices of the
+// container are on the host, before anything is attached: by default the
+// container fails if one is missing rather than the attach failing opaquely,
+// or they're left out with missing_device_policy "skip".
+fn handle_missing_devices(
+    runtime: &Runtime,
+    sys_dev: &Path,
+    cid: &str,
+    linux: &mut Linux,
+) -> Result<()> {
+    let missing: Vec<&LinuxDevice> = linux
+        .devices
+        .iter()
+        .filter(|d| !is_host_device_present(sys_dev, d))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if runtime.missing_device_policy != MISSING_DEVICE_POLICY_SKIP {
+        let d = missing[0];
+        return Err(anyhow!(
+            "device {} ({}:{}) of container {} not found on host",
+            d.path,
+            d.major,
+            d.minor,
+            cid
+        ));
+    }
+    for d in missing {
+        warn!(
+            sl!(),
+            "device {} ({}:{}) of container {} not found on host, left out",
+            d.path,
+            d.major,
+            d.minor,
+            cid
+        );
+    }
+    linux.devices.retain(|d| is_host_device_present(sys_dev, d));
+    Ok(())
+}
+
+// is_host_device_present tells if the block or the char device is on the
+// host by its major:minor, the devices of the other types aren't checked
+fn is_host_device_present(sys_dev: &Path, d: &LinuxDevice) -> bool {
+    let class = match d.r#type.as_str() {
+        "b" => "block",
+        "c" | "u" => "char",
+        _ => return true,
+    };
+    sys_dev
+        .join(class)
+        .join(format!("{}:{}", d.major, d.minor))
+        .exists()
+}
+
+// check_vfio_group checks the IOMMU group of the vfio device of the
+// container could be passed through: its devices are on the host, bound to
+// vfio-pci or left unbound. The container device of vfio isn't a group.
+fn check_vfio_group(iommu_groups: &Path, path: &str) -> Result<()> {
+    let group = match Path::new(path).file_name().and_then(|n| n.to_str()) {
+        Some("vfio") => return Ok(()),
+        Some(group) => group,
+        None => return Err(anyhow!("invalid vfio device {}", path)),
+    };
+    let devices_dir = iommu_groups.join(group).join("devices");
+    let devices = fs::read_dir(&devices_dir)
+        .with_context(|| format!("IOMMU group {} of {} not found on host", group, path))?;
+    let mut found = false;
+    for entry in devices {
+        let entry = entry.with_context(|| format!("read {:?}", devices_dir))?;
+        found = true;
+        let driver = match fs::read_link(entry.path().join("driver")) {
+            std::result::Result::Ok(driver) => driver,
+            // left unbound, vfio-pci binds it
+            Err(_) => continue,
+        };
+        let driver = driver.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if !VFIO_GROUP_DRIVERS.contains(&driver) {
+            return Err(anyhow!(
+                "device {} of IOMMU group {} is bound to {}, not vfio-pci",
+                entry.file_name().to_string_lossy(),
+                group,
+                driver
+            ));
+        }
+    }
+    if !found {
+        return Err(anyhow!("IOMMU group {} of {} has no device", group, path));
+    }
+    Ok(())
+}
+
+// vfio_group_functions returns the functions of the IOMMU group of the vfio
+// device to pass through, once the group is checked. The bridges are left
+// to the host, the container device of vfio has none.
+fn vfio_group_functions(iommu_groups: &Path, path: &str) -> Result<Vec<String>> {
+    check_vfio_group(iommu_groups, path)?;
+    let group = match Path::new(path).file_name().and_then(|n| n.to_str()) {
+        Some("vfio") | None => return Ok(vec![]),
+        Some(group) => group,
+    };
+    let devices_dir = iommu_groups.join(group).join("devices");
+    let mut functions = vec![];
+    for entry in fs::read_dir(&devices_dir).with_context(|| format!("read {:?}", devices_dir))? {
+        let entry = entry.with_context(|| format!("read {:?}", devices_dir))?;
+        let driver = fs::read_link(entry.path().join("driver")).unwrap_or_default();
+        if driver.file_name().and_then(|n| n.to_str()) == Some("pcieport") {
+            continue;
+        }
+        functions.push(entry.file_name().to_string_lossy().to_string());
+    }
+    functions.sort();
+    Ok(functions)
+}
+
+// check_hugepages checks the host has the huge pages of the size, e.g.
+// "2MB", free for the limit in bytes of the container
+fn check_hugepages(hugepages: &Path, page_size: &str, limit: u64) -> Result<()> {
+    // the page size of the oci spec is in MB or GB, Mi and Gi actually
+    let size = Byte::from_str(page_size.replace('B', "i"))
+        .map_err(|e| anyhow!("invalid huge page size {}: {:?}", page_size, e))?
+        .get_bytes() as u64;
+    let free_path = hugepages
+        .join(format!("hugepages-{}kB", size >> 10))
+        .join("free_hugepages");
+    let free: u64 = fs::read_to_string(&free_path)
+        .with_context(|| format!("huge pages of {} not supported on host", page_size))?
+        .trim()
+        .parse()
+        .with_context(|| format!("parse {:?}", free_path))?;
+    if free.saturating_mul(size) < limit {
+        return Err(anyhow!(
+            "{} free huge pages of {} on host, short of the limit of {} bytes",
+            free,
+            page_size,
+            limit
+        ));
+    }
+    Ok(())
+}
+
+fn allow_all_devices() -> LinuxDeviceCgroup {
+    LinuxDeviceCgroup {
+        allow: true,
+        access: "rwm".to_string(),
+        ..Default::default()
+    }
+}
+
+// the class of the device in device_kernel_modules of the agent config
+fn device_class(d: &LinuxDevice) -> Option<&'static str> {
+    match d.r#type.as_str() {
+        "b" => Some(DEVICE_CLASS_BLOCK),
+        "c" | "u" if d.path.starts_with("/dev/vfio/") => Some(DEVICE_CLASS_VFIO),
+        "c" | "u" => Some(DEVICE_CLASS_CHAR),
+        _ => None,
+    }
+}
+
+// slot_device_count counts the devices taking a guest PCI slot, against the
+// device limits
+fn slot_device_count(devices: &[LinuxDevice]) -> usize {
+    devices
+        .iter()
+        .filter(|d| {
+            matches!(
+                device_class(d),
+                Some(DEVICE_CLASS_BLOCK) | Some(DEVICE_CLASS_VFIO)
+            )
+        })
+        .count()
+}
+
+// group_bulk_devices groups the devices of a bulk attach by class, in the
+// order of the devices. The batch is refused as a whole if a device can't be
+// attached: only the block devices and the vfio groups are attached by the
+// device manager.
+fn group_bulk_devices(devices: &[LinuxDevice]) -> Result<BTreeMap<&'static str, Vec<&str>>> {
+    let mut groups: BTreeMap<&'static str, Vec<&str>> = BTreeMap::new();
+    let mut seen = HashSet::new();
+    for d in devices {
+        let class = device_class(d)
+            .ok_or_else(|| anyhow!("device {} of type {:?} is unknown", d.path, d.r#type))?;
+        if class != DEVICE_CLASS_BLOCK && class != DEVICE_CLASS_VFIO {
+            return Err(anyhow!(
+                "device {} of class {} can't be attached in bulk",
+                d.path,
+                class
+            ));
+        }
+        if !seen.insert((d.major, d.minor)) {
+            return Err(anyhow!(
+                "device {}:{} is duplicated at {}",
+                d.major,
+                d.minor,
+                d.path
+            ));
+        }
+        groups.entry(class).or_default().push(d.path.as_str());
+    }
+    Ok(groups)
+}
+
+// device_node_options passes the ownership and the mode of the node of the
+// device in the spec to the agent, e.g. of the raw block volumeDevices of
+// the pods, the agent keeps its defaults for the ones unset or 0
+fn device_node_options(d: &LinuxDevice) -> Vec<String> {
+    DeviceNodeAttrs {
+        file_mode: d.file_mode,
+        uid: d.uid,
+        gid: d.gid,
+    }
+    .to_options()
+}
+
+// check_device_capabilities checks that the container has the capability
+// each device needs by its class in its effective set
+fn check_device_capabilities(
+    required: &HashMap<String, String>,
+    cid: &str,
+    devices: &[LinuxDevice],
+    capabilities: Option<&LinuxCapabilities>,
+) -> Result<()> {
+    let effective: HashSet<&str> = capabilities
+        .map(|c| c.effective.iter().map(|c| c.as_str()).collect())
+        .unwrap_or_default();
+    for d in devices {
+        let class = match device_class(d) {
+            Some(class) => class,
+            None => continue,
+        };
+        if let Some(capability) = required.get(class) {
+            if !effective.contains(capability.as_str()) {
+                return Err(anyhow!(
+                    "device {} of class {} requires {} which container {} doesn't have",
+                    d.path,
+                    class,
+                    capability,
+                    cid
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// the classes of the devices with kernel modules configured, which are not
+// loaded yet, in the order of the devices
+fn pending_device_classes(
+    modules: &HashMap<String, Vec<String>>,
+    devices: &[LinuxDevice],
+    loaded: &HashSet<&'static str>,
+) -> Vec<&'static str> {
+    let mut classes = vec![];
+    for class in devices.iter().filter_map(device_class) {
+        if modules.contains_key(class) && !loaded.contains(class) && !classes.contains(&class) {
+            classes.push(class);
+        }
+    }
+    classes
+}
+
+// add the block devices of the host the container asks for which are not in
+// the spec yet, the ones which aren't block devices are refused
+fn add_host_block_devices(linux: &mut Linux, requested: &BTreeSet<String>) -> Result<()> {
+    let known: HashSet<(i64, i64)> = linux.devices.iter().map(|d| (d.major, d.minor)).collect();
+
+    for path in requested.iter().map(Path::new) {
+        if linux.devices.iter().any(|d| Path::new(&d.path) == path) {
+            continue;
+        }
+        let metadata = fs::metadata(path)
+            .with_context(|| format!("host block device {:?} not found", path))?;
+        if !metadata.file_type().is_block_device() {
+            return Err(anyhow!("host device {:?} is not a block device", path));
+        }
+
+        let (major, minor) = (
+            stat::major(metadata.rdev()) as i64,
+            stat::minor(metadata.rdev()) as i64,
+        );
+        if known.contains(&(major, minor)) {
+            continue;
+        }
+        info!(sl!(), "add host block device {:?}", path);
+        linux.devices.push(LinuxDevice {
+            path: path.display().to_string(),
+            r#type: "b".to_string(),
+            major,
+            minor,
+            file_mode: Some(metadata.mode() & 0o777),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+        });
+    }
+
+    Ok(())
+}
+
+impl Drop for ResourceManagerInner {
+    fn drop(&mut self) {
+        if !self.teardown_armed.load(Ordering::SeqCst) {
+            return;
+        }
+        warn!(
+            sl!(),
+            "sandbox {} dropped without cleanup, tear it down", self.sid
+        );
+        self.teardown_on_drop();
     }
 }
 
@@ -373,6 +3644,22 @@ impl Persist for ResourceManagerInner {
         Ok(ResourceState {
             endpoint: endpoint_state,
             cgroup_state: Some(cgroup_state),
+            volumes: self.volume_resource.save().await,
+            netns_path: self.network_namespace().await,
+            pci_slots: self.device_manager.read().await.save_pci_slots(),
+            scsi_controllers: self.device_manager.read().await.save_scsi_controllers(),
+            sandbox_bind_mounts: Some(self.sandbox_bind_mounts.clone()),
+            vsock: Some(self.vsock.state()),
+            hostname: self.hostname.clone(),
+            sriov_vfs: self.sriov_resource.save().await,
+            container_exports: self.container_exports.as_ref().map(|e| e.owners()),
+            mem_reservations: Some(self.mem_resource.container_reservations().await),
+            cpu_state: Some(self.cpu_resource.save().await),
+            mem_state: Some(self.mem_resource.save().await),
+            limit_counts: Some(self.limits.save()),
+            no_host_sharing: Some(self.no_host_sharing),
+            guest_protection: Some(self.guest_protection),
+            agent_features: Some(self.agent_features.clone()),
         })
     }
 
@@ -381,25 +3668,955 @@ impl Persist for ResourceManagerInner {
         resource_args: Self::ConstructorArgs,
         resource_state: Self::State,
     ) -> Result<Self> {
+        let mut cpu_resource = CpuResource::new(&resource_args.config);
+        let mut mem_resource = MemResource::new(&resource_args.config);
+        // the guest keeps the size it was resized to for the containers
+        // still running, saved before it was, it's the boot size
+        if let Some(state) = resource_state.cpu_state.as_ref() {
+            cpu_resource.restore(state).await;
+        }
+        if let Some(state) = resource_state.mem_state.as_ref() {
+            mem_resource.restore(state).await;
+        }
+        // the volumes of the containers still take the guest memory
+        if let Some(reservations) = resource_state.mem_reservations.as_ref() {
+            mem_resource
+                .restore_container_reservations(reservations)
+                .await;
+        }
+        // only the backing file is left to clean up for the restored swap
+        let swap = resource_args
+            .config
+            .hypervisor
+            .get(&resource_args.config.runtime.hypervisor_name)
+            .filter(|h| h.memory_info.enable_guest_swap)
+            .map(|_| SwapResource::new(&resource_args.sid, 0));
+        trace::set_enabled(resource_args.config.runtime.enable_tracing);
+        metrics::init(
+            &resource_args.sid,
+            resource_args.config.runtime.metrics_sandbox_id_label,
+        );
+        // the shared directories of the sandbox are cleaned up where they were created
+        share_fs::set_host_shared_root(
+            &resource_args.sid,
+            &resource_args.config.runtime.shared_dir_root,
+        );
+        let timings = Arc::new(Timings::new(&resource_args.sid));
+        // the devices attached again get the guest pci slots and the scsi
+        // addresses they had
+        let mut device_manager = DeviceManager::new(resource_args.hypervisor.clone())?;
+        device_manager.restore_pci_slots(resource_state.pci_slots);
+        device_manager.restore_scsi_controllers(resource_state.scsi_controllers);
+        // saved before the annotation was supported, only the config ones
+        let sandbox_bind_mounts = resource_state
+            .sandbox_bind_mounts
+            .unwrap_or_else(|| resource_args.config.runtime.sandbox_bind_mounts.clone());
+        let path_jail = PathJail::new(&resource_args.config.runtime);
+        let sriov_resource = SriovResource::restore(&resource_args.sid, resource_state.sriov_vfs);
+        let events = Arc::new(ResourceEvents::new(&resource_args.sid));
+        // the restored containers keep counting against the limits
+        let limit_counts = resource_state
+            .limit_counts
+            .unwrap_or_else(|| LimitCounts::from_volumes(&resource_state.volumes));
+        let limits = ResourceLimits::restore(&resource_args.config.runtime, limit_counts);
+        // the containers keep the exports they were given
+        let container_exports = match resource_state.container_exports {
+            Some(owners) => {
+                let shared_fs = resource_args
+                    .config
+                    .hypervisor
+                    .get(&resource_args.config.runtime.hypervisor_name)
+                    .map(|h| h.shared_fs.clone())
+                    .unwrap_or_default();
+                let exports = ContainerExports::restore(
+                    &resource_args.sid,
+                    &shared_fs,
+                    owners,
+                    events.clone(),
+                )
+                .context("restore container exports")?;
+                Some(Arc::new(exports))
+            }
+            None => None,
+        };
+        // the images of the containers are still pulled in the guest
+        let no_host_sharing = restore_no_host_sharing(
+            resource_args.hypervisor.as_ref(),
+            resource_state.no_host_sharing,
+        )
+        .await
+        .context("restore no host sharing")?;
+        // the volumes of the protected guest are still kept in the guest
+        let guest_protection = restore_guest_protection(
+            resource_args.hypervisor.as_ref(),
+            resource_state.guest_protection,
+        )
+        .await
+        .context("restore guest protection")?;
+        let volume_resource = VolumeResource::restore(resource_state.volumes);
+        if guest_protection.is_protected() {
+            volume_resource.set_trusted_storage(true).await;
+        }
+        // the restored sandbox keeps behaving as configured, e.g. with the
+        // static sizing or the vcpus pinning
+        let toml_config = Arc::new(resource_args.config);
         let args = CgroupArgs {
             sid: resource_args.sid.clone(),
-            config: resource_args.config,
+            config: toml_config.clone(),
         };
         Ok(Self {
             sid: resource_args.sid,
             agent: resource_args.agent,
             hypervisor: resource_args.hypervisor.clone(),
-            device_manager: Arc::new(RwLock::new(DeviceManager::new(resource_args.hypervisor)?)),
+            device_manager: Arc::new(RwLock::new(device_manager)),
             network: None,
             share_fs: None,
+            container_exports,
+            initial_size: None,
+            restored_netns_path: saved_netns_path(
+                resource_state.netns_path,
+                &resource_state.endpoint,
+            ),
+            restored_endpoints: resource_state.endpoint,
+            swap,
+            hostname: resource_state.hostname,
+            net_sysctls: HashMap::new(),
+            sandbox_bind_mounts,
+            timings,
+            events,
+            vsock: VsockAllocator::restore(
+                &resource_args.sid,
+                resource_state.vsock.unwrap_or_default(),
+            ),
+            no_host_sharing,
+            guest_protection,
+            vm_state: VmState::ColdBoot,
+            teardown_armed: AtomicBool::new(false),
+            loaded_device_classes: Mutex::new(HashSet::new()),
+            // saved before they were, the agent is assumed to support
+            // everything as it was then
+            agent_features: resource_state.agent_features.unwrap_or_default(),
+            quiesce_gate: QuiesceGate::new(),
+            sriov_resource,
+            limits,
+            path_jail,
             rootfs_resource: RootFsResource::new(),
-            volume_resource: VolumeResource::new(),
+            volume_resource,
             cgroups_resource: CgroupsResource::restore(
                 args,
                 resource_state.cgroup_state.unwrap_or_default(),
             )
             .await?,
-            toml_config: Arc::new(TomlConfig::default()),
+            cpu_resource,
+            mem_resource,
+            toml_config,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_agent::MockAgent;
+    use hypervisor::mock::MockHypervisor;
+    use kata_types::capabilities::CapabilityBits;
+
+    fn new_device(path: &str, r#type: &str) -> LinuxDevice {
+        LinuxDevice {
+            path: path.to_string(),
+            r#type: r#type.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_cleanup_partially_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = (0..4).map(|i| dir.path().join(i.to_string())).collect();
+        // the half of the resources are removed already, the last one fails
+        fs::create_dir(&paths[0]).unwrap();
+        fs::create_dir(&paths[2]).unwrap();
+        fs::write(&paths[3], "").unwrap();
+
+        let cleanup = || {
+            let mut errors = CleanupErrors::default();
+            for (i, path) in paths.iter().enumerate() {
+                errors.check(
+                    &format!("step {}", i),
+                    share_fs::remove_dir_all_if_exists(path).map_err(|e| anyhow!(e)),
+                );
+            }
+            // the sandbox bind mounts of a sandbox without them
+            errors.check(
+                "cleanup sandbox bindmounts",
+                SandboxBindMounts::new("no-such-sandbox".to_string(), vec!["/tmp".to_string()])
+                    .and_then(|b| b.cleanup_sandbox_bind_mounts()),
+            );
+            errors.into_result()
+        };
+
+        // every step is run, the failure is reported alone
+        let err = format!("{:#}", cleanup().unwrap_err());
+        assert!(err.starts_with("1 cleanup steps failed: step 3"), "{}", err);
+        assert!(!paths[0].exists() && !paths[2].exists());
+
+        // the retry is the same
+        let err = format!("{:#}", cleanup().unwrap_err());
+        assert!(err.starts_with("1 cleanup steps failed: step 3"), "{}", err);
+
+        fs::remove_file(&paths[3]).unwrap();
+        cleanup().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_no_host_sharing() {
+        let h = MockHypervisor::new().with_capabilities(CapabilityBits::FsSharingSupport);
+        // the saved one is kept whatever the hypervisor tells now
+        assert!(restore_no_host_sharing(&h, Some(true)).await.unwrap());
+        assert!(!restore_no_host_sharing(&h, Some(false)).await.unwrap());
+
+        // saved before it was, derived from the hypervisor
+        assert!(!restore_no_host_sharing(&h, None).await.unwrap());
+        let h = MockHypervisor::new();
+        assert!(restore_no_host_sharing(&h, None).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_restore_guest_protection() {
+        let h = MockHypervisor::new();
+        assert_eq!(
+            restore_guest_protection(&h, Some(GuestProtection::Tdx))
+                .await
+                .unwrap(),
+            GuestProtection::Tdx
+        );
+        // saved before it was, the guest that isn't confidential isn't
+        // protected
+        assert_eq!(
+            restore_guest_protection(&h, None).await.unwrap(),
+            GuestProtection::NoProtection
+        );
+
+        let state = ResourceState {
+            guest_protection: Some(GuestProtection::Snp),
+            ..Default::default()
+        };
+        let state: ResourceState =
+            serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(state.guest_protection, Some(GuestProtection::Snp));
+        let state: ResourceState = serde_json::from_str(r#"{"endpoint":[]}"#).unwrap();
+        assert_eq!(state.guest_protection, None);
+    }
+
+    #[tokio::test]
+    async fn test_restore_keeps_config() {
+        let mut config = TomlConfig::default();
+        config.runtime.static_sandbox_resource_mgmt = true;
+        config.runtime.experimental_force_guest_pull = true;
+        let args = ManagerArgs {
+            sid: "test-restore-keeps-config".to_string(),
+            agent: Arc::new(MockAgent::new("3.2.0")),
+            hypervisor: Arc::new(MockHypervisor::new()),
+            config,
+        };
+        let state = ResourceState {
+            no_host_sharing: Some(false),
+            guest_protection: Some(GuestProtection::NoProtection),
+            ..Default::default()
+        };
+        let inner = ResourceManagerInner::restore(args, state).await.unwrap();
+
+        // the gates of the config apply to the restored sandbox too
+        assert!(inner.config().runtime.static_sandbox_resource_mgmt);
+        assert!(inner.is_guest_pull());
+        // the sandbox still uses what's restored, it isn't torn down on drop
+        assert!(!inner.teardown_armed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_update_cgroups_reverts_vcpus() {
+        let mut hv = kata_types::config::hypervisor::Hypervisor::default();
+        hv.cpu_info.default_vcpus = 1;
+        hv.cpu_info.default_maxvcpus = 4;
+        hv.memory_info.default_memory = 1024;
+        hv.memory_info.default_maxmemory = 4096;
+        let mut config = TomlConfig::default();
+        config.runtime.hypervisor_name = "mock".to_string();
+        config.hypervisor.insert("mock".to_string(), hv);
+        let h = Arc::new(MockHypervisor::new().fail_resize_memory());
+        let args = ManagerArgs {
+            sid: "test-update-cgroups-reverts-vcpus".to_string(),
+            agent: Arc::new(MockAgent::new("3.2.0")),
+            hypervisor: h.clone(),
+            config,
+        };
+        let state = ResourceState {
+            no_host_sharing: Some(false),
+            guest_protection: Some(GuestProtection::NoProtection),
+            ..Default::default()
+        };
+        let inner = ResourceManagerInner::restore(args, state).await.unwrap();
+
+        // 2 vcpus and 512 MiB, the memory fails to be hotplugged
+        let resources = LinuxResources {
+            cpu: Some(oci::LinuxCpu {
+                quota: Some(200_000),
+                period: Some(100_000),
+                ..Default::default()
+            }),
+            memory: Some(oci::LinuxMemory {
+                limit: Some(512 * 1024 * 1024),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(inner.update_cgroups("a", Some(&resources)).await.is_err());
+
+        // the vcpus hotplugged are taken back
+        assert_eq!(
+            h.resizes(),
+            vec!["resize_vcpu 3", "resize_memory 1536", "resize_vcpu 1"]
+        );
+        assert_eq!(inner.cpu_resource.current_vcpus().await, 1);
+        assert!(inner.cpu_resource.save().await.container_vcpus.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resize_sandbox_with_containers() {
+        let mut hv = kata_types::config::hypervisor::Hypervisor::default();
+        hv.cpu_info.default_vcpus = 1;
+        hv.cpu_info.default_maxvcpus = 4;
+        hv.memory_info.default_memory = 1024;
+        hv.memory_info.default_maxmemory = 4096;
+        hv.memory_info.enable_virtio_mem = true;
+        let mut config = TomlConfig::default();
+        config.runtime.hypervisor_name = "mock".to_string();
+        config.hypervisor.insert("mock".to_string(), hv);
+        let h = Arc::new(MockHypervisor::new());
+        let agent = Arc::new(MockAgent::new("3.2.0"));
+        let args = ManagerArgs {
+            sid: "test-resize-sandbox-with-containers".to_string(),
+            agent: agent.clone(),
+            hypervisor: h.clone(),
+            config,
+        };
+        let state = ResourceState {
+            no_host_sharing: Some(false),
+            guest_protection: Some(GuestProtection::NoProtection),
+            ..Default::default()
+        };
+        let inner = ResourceManagerInner::restore(args, state).await.unwrap();
+
+        let resources = |quota: i64, limit_mb: i64| LinuxResources {
+            cpu: Some(oci::LinuxCpu {
+                quota: Some(quota),
+                period: Some(100_000),
+                ..Default::default()
+            }),
+            memory: Some(oci::LinuxMemory {
+                limit: Some(limit_mb * 1024 * 1024),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        // the cgroups of the host are left out, the guest is sized as
+        // update_cgroups and delete_container_resources do
+        let (hv, ag) = (h.as_ref(), agent.as_ref());
+        for (cid, r) in [
+            ("a", resources(100_000, 256)),
+            ("b", resources(200_000, 512)),
+        ] {
+            inner
+                .cpu_resource
+                .update_cpu_resources(cid, Some(&r), hv, ag)
+                .await
+                .unwrap();
+            inner
+                .mem_resource
+                .update_mem_resources(cid, Some(&r), hv, ag)
+                .await
+                .unwrap();
+        }
+        inner
+            .cpu_resource
+            .remove_cpu_resources("a", hv, ag)
+            .await
+            .unwrap();
+        inner
+            .mem_resource
+            .remove_mem_resources("a", hv, ag)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            h.resizes(),
+            vec![
+                "resize_vcpu 2",
+                "resize_memory 1280",
+                "resize_vcpu 4",
+                "resize_memory 1792",
+                "resize_vcpu 3",
+                "resize_memory 1536",
+            ]
+        );
+        // the vcpu of a is offlined in the guest before it's unplugged
+        let offlined: Vec<_> = agent
+            .calls()
+            .into_iter()
+            .filter(|c| c.starts_with("offline_cpu"))
+            .collect();
+        assert_eq!(offlined, vec!["offline_cpu 1"]);
+    }
+
+    #[tokio::test]
+    async fn test_verify_restored_devices() {
+        use hypervisor::device::device_manager::PciSlotState;
+
+        let dir = tempfile::tempdir().unwrap();
+        let present = dir.path().join("present").display().to_string();
+        let gone = dir.path().join("gone").display().to_string();
+        fs::write(&present, "").unwrap();
+
+        let volume = |cid: &str, device_id: &str| crate::volume::VolumeState {
+            cid: cid.to_string(),
+            source: format!("/volumes/{}", device_id),
+            device_id: Some(device_id.to_string()),
+            size: None,
+        };
+        // dev2 is gone from the VM, dev1 is shared by two containers
+        let h = Arc::new(MockHypervisor::new().with_block_devices(&["dev1", "dev3"]));
+        let args = ManagerArgs {
+            sid: "test-verify-restored-devices".to_string(),
+            agent: Arc::new(MockAgent::new("3.2.0")),
+            hypervisor: h.clone(),
+            config: TomlConfig::default(),
+        };
+        let state = ResourceState {
+            volumes: vec![
+                volume("c1", "dev1"),
+                volume("c2", "dev1"),
+                volume("c2", "dev2"),
+            ],
+            pci_slots: vec![
+                PciSlotState {
+                    slot: 30,
+                    host_path: present,
+                },
+                PciSlotState {
+                    slot: 31,
+                    host_path: gone.clone(),
+                },
+            ],
+            no_host_sharing: Some(false),
+            guest_protection: Some(GuestProtection::NoProtection),
+            ..Default::default()
+        };
+        let inner = ResourceManagerInner::restore(args, state).await.unwrap();
+
+        // the cgroups of the sandbox aren't created by the test
+        let inconsistencies: Vec<_> = inner
+            .verify()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|i| !matches!(i, Inconsistency::CgroupMissing(_)))
+            .collect();
+        assert_eq!(
+            inconsistencies,
+            vec![
+                Inconsistency::DeviceNotAttached("dev2".to_string()),
+                Inconsistency::PciSlotBackendMissing {
+                    slot: 31,
+                    path: gone,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_network_queues() {
+        assert_eq!(network_queues(0, 4, true), 4);
+        assert_eq!(network_queues(0, 0, true), 1);
+        assert_eq!(network_queues(2, 4, true), 2);
+        // a single queue without multi-queue devices, whatever is configured
+        assert_eq!(network_queues(0, 4, false), 1);
+        assert_eq!(network_queues(2, 4, false), 1);
+    }
+
+    #[test]
+    fn test_saved_netns_path() {
+        let netns = "/var/run/netns/cni-1234".to_string();
+        let endpoints = vec![
+            EndpointState::default(),
+            EndpointState {
+                netns_path: netns.clone(),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            saved_netns_path(Some(netns.clone()), &[]),
+            Some(netns.clone())
+        );
+        // saved before the netns was
+        assert_eq!(saved_netns_path(None, &endpoints), Some(netns));
+        // no network
+        assert_eq!(saved_netns_path(None, &endpoints[..1]), None);
+        assert_eq!(saved_netns_path(None, &[]), None);
+    }
+
+    #[test]
+    fn test_check_interfaces_up() {
+        let interface = |name: &str, raw_flags: u32| Interface {
+            name: name.to_string(),
+            raw_flags,
+            ..Default::default()
+        };
+        let interfaces = vec![interface("eth0", IFF_UP | 0x1000), interface("eth1", 0)];
+
+        check_interfaces_up(&["eth0".to_string()], &interfaces).unwrap();
+        assert_eq!(
+            check_interfaces_up(&["eth0".to_string(), "eth1".to_string()], &interfaces)
+                .unwrap_err()
+                .to_string(),
+            "interface eth1 isn't up in the guest"
+        );
+        assert_eq!(
+            check_interfaces_up(&["eth2".to_string()], &interfaces)
+                .unwrap_err()
+                .to_string(),
+            "no interface eth2 in the guest"
+        );
+    }
+
+    // a network whose CNI result is empty
+    struct NoEndpoints;
+
+    #[async_trait]
+    impl Network for NoEndpoints {
+        async fn setup(&self, _h: &dyn Hypervisor, _cold_plug: bool) -> Result<()> {
+            Ok(())
+        }
+        async fn interfaces(&self) -> Result<Vec<agent::Interface>> {
+            Ok(vec![])
+        }
+        async fn routes(&self) -> Result<Vec<agent::Route>> {
+            Ok(vec![])
+        }
+        async fn neighs(&self) -> Result<Vec<agent::ARPNeighbor>> {
+            Ok(vec![])
+        }
+        async fn save(&self) -> Option<Vec<EndpointState>> {
+            None
+        }
+        async fn detach(&self, _h: &dyn Hypervisor) -> Result<()> {
+            Ok(())
+        }
+        async fn remove(&self, _h: &dyn Hypervisor) -> Result<()> {
+            Ok(())
+        }
+        async fn delete(&self) -> Result<()> {
+            Ok(())
+        }
+        async fn netns_path(&self) -> Option<String> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn test_setup_guest_network() {
+        let net_sysctls: HashMap<String, String> = vec![(
+            "net.ipv4.ip_unprivileged_port_start".to_string(),
+            "0".to_string(),
+        )]
+        .into_iter()
+        .collect();
+
+        // the loopback is left to the agent, only the sysctls are applied
+        // without a network
+        let features = AgentFeatures::probe(&MockAgent::new("3.2.0")).await;
+        let agent = MockAgent::new("3.2.0");
+        setup_guest_network(&agent, &features, "sid", None, &net_sysctls)
+            .await
+            .unwrap();
+        assert_eq!(agent.calls(), vec!["set_sysctls"]);
+
+        // no endpoints, an empty request
+        let agent = MockAgent::new("3.2.0");
+        setup_guest_network(
+            &agent,
+            &features,
+            "sid",
+            Some(&NoEndpoints as &dyn Network),
+            &HashMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(agent.calls(), vec!["setup_network"]);
+
+        // an old agent gets no interface to update, the loopback isn't
+        // touched
+        let old_agent = MockAgent::new("3.1.0");
+        old_agent.unsupported("setup_network");
+        let features = AgentFeatures::probe(&old_agent).await;
+        let agent = MockAgent::new("3.1.0");
+        setup_guest_network(
+            &agent,
+            &features,
+            "sid",
+            Some(&NoEndpoints as &dyn Network),
+            &net_sysctls,
+        )
+        .await
+        .unwrap();
+        assert_eq!(agent.calls(), vec!["set_sysctls"]);
+
+        // the failure of the agent fails the setup
+        let agent = MockAgent::new("3.2.0");
+        agent.fail("set_sysctls", "permission denied");
+        assert!(
+            setup_guest_network(&agent, &features, "sid", None, &net_sysctls)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_vfio_kernel_params() {
+        use crate::network::PhysicalEndpointState;
+
+        let physical = |bdf: &str, cold_plugged: bool| EndpointState {
+            physical_endpoint: Some(PhysicalEndpointState {
+                bdf: bdf.to_string(),
+                ..Default::default()
+            }),
+            cold_plugged,
+            ..Default::default()
+        };
+        let params = vec!["intel_iommu=on".to_string(), "iommu=pt".to_string()];
+        let endpoints = vec![
+            physical("0000:01:00.0", true),
+            // hotplugged, too late for the kernel command line
+            physical("0000:02:00.0", false),
+            EndpointState {
+                cold_plugged: true,
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            vfio_kernel_params(&params, &endpoints),
+            vec![
+                (
+                    "vfio device 0000:01:00.0".to_string(),
+                    "intel_iommu=on".to_string()
+                ),
+                (
+                    "vfio device 0000:01:00.0".to_string(),
+                    "iommu=pt".to_string()
+                ),
+            ]
+        );
+        assert!(vfio_kernel_params(&params, &endpoints[1..]).is_empty());
+    }
+
+    #[test]
+    fn test_slot_device_count() {
+        let devices = vec![
+            new_device("/dev/vdb", "b"),
+            new_device("/dev/vfio/12", "c"),
+            new_device("/dev/ttyS1", "c"),
+        ];
+        assert_eq!(slot_device_count(&devices), 2);
+    }
+
+    #[test]
+    fn test_group_bulk_devices() {
+        let mut devices = vec![new_device("/dev/vdb", "b"), new_device("/dev/vdc", "b")];
+        devices[1].minor = 1;
+        devices.push(new_device("/dev/vfio/12", "c"));
+        devices[2].major = 241;
+        let groups = group_bulk_devices(&devices).unwrap();
+        assert_eq!(groups[DEVICE_CLASS_BLOCK], vec!["/dev/vdb", "/dev/vdc"]);
+        assert_eq!(groups[DEVICE_CLASS_VFIO], vec!["/dev/vfio/12"]);
+
+        // refused as a whole
+        for (path, r#type) in [("/dev/ttyS1", "c"), ("/dev/fifo", "p")] {
+            let mut batch = devices.clone();
+            let mut device = new_device(path, r#type);
+            device.major = 4;
+            batch.push(device);
+            assert!(group_bulk_devices(&batch).is_err(), "{}", path);
+        }
+        let mut batch = devices.clone();
+        batch.push(devices[0].clone());
+        assert!(group_bulk_devices(&batch).is_err());
+    }
+
+    #[test]
+    fn test_pending_device_classes() {
+        let devices = vec![
+            new_device("/dev/vdb", "b"),
+            new_device("/dev/vfio/12", "c"),
+            new_device("/dev/vdc", "b"),
+            new_device("/dev/ttyS1", "c"),
+            new_device("/dev/fifo", "p"),
+        ];
+        let classes: Vec<_> = devices.iter().map(device_class).collect();
+        assert_eq!(
+            classes,
+            vec![
+                Some(DEVICE_CLASS_BLOCK),
+                Some(DEVICE_CLASS_VFIO),
+                Some(DEVICE_CLASS_BLOCK),
+                Some(DEVICE_CLASS_CHAR),
+                None
+            ]
+        );
+
+        // off by default
+        let mut loaded = HashSet::new();
+        assert!(pending_device_classes(&HashMap::new(), &devices, &loaded).is_empty());
+
+        // each class once, only the configured ones
+        let modules = HashMap::from([
+            (DEVICE_CLASS_BLOCK.to_string(), vec!["nvme".to_string()]),
+            (DEVICE_CLASS_VFIO.to_string(), vec!["nvidia".to_string()]),
+        ]);
+        assert_eq!(
+            pending_device_classes(&modules, &devices, &loaded),
+            vec![DEVICE_CLASS_BLOCK, DEVICE_CLASS_VFIO]
+        );
+
+        // not again once loaded
+        loaded.insert(DEVICE_CLASS_BLOCK);
+        assert_eq!(
+            pending_device_classes(&modules, &devices, &loaded),
+            vec![DEVICE_CLASS_VFIO]
+        );
+    }
+
+    #[test]
+    fn test_device_node_options() {
+        let mut d = new_device("/dev/xvda", "b");
+        assert!(device_node_options(&d).is_empty());
+
+        d.file_mode = Some(0o660);
+        d.uid = Some(0);
+        d.gid = Some(107);
+        assert_eq!(device_node_options(&d), vec!["file_mode=0660", "gid=107"]);
+    }
+
+    #[test]
+    fn test_check_device_capabilities() {
+        let devices = vec![new_device("/dev/vdb", "b"), new_device("/dev/ttyS1", "c")];
+        let required = HashMap::from([
+            (DEVICE_CLASS_BLOCK.to_string(), "CAP_SYS_RAWIO".to_string()),
+            (DEVICE_CLASS_VFIO.to_string(), "CAP_SYS_ADMIN".to_string()),
+        ]);
+        let mut capabilities = LinuxCapabilities {
+            effective: vec!["CAP_CHOWN".to_string()],
+            // only the effective set counts
+            permitted: vec!["CAP_SYS_RAWIO".to_string()],
+            ..Default::default()
+        };
+
+        check_device_capabilities(&HashMap::new(), "c1", &devices, None).unwrap();
+        let err =
+            check_device_capabilities(&required, "c1", &devices, Some(&capabilities)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "device /dev/vdb of class block requires CAP_SYS_RAWIO which container c1 doesn't have"
+        );
+        check_device_capabilities(&required, "c1", &devices, None).unwrap_err();
+
+        capabilities.effective.push("CAP_SYS_RAWIO".to_string());
+        check_device_capabilities(&required, "c1", &devices, Some(&capabilities)).unwrap();
+    }
+
+    #[test]
+    fn test_guest_oom_score_adj() {
+        assert_eq!(guest_oom_score_adj(None).unwrap(), None);
+        // kubernetes: besteffort, burstable and guaranteed
+        for adj in [1000, 500, -996] {
+            assert_eq!(guest_oom_score_adj(Some(adj)).unwrap(), Some(adj));
+        }
+        // the guaranteed containers and the pause one stay above the agent
+        assert_eq!(guest_oom_score_adj(Some(-997)).unwrap(), Some(-996));
+        assert_eq!(guest_oom_score_adj(Some(-998)).unwrap(), Some(-996));
+        guest_oom_score_adj(Some(1001)).unwrap_err();
+        guest_oom_score_adj(Some(-1001)).unwrap_err();
+    }
+
+    #[test]
+    fn test_check_vfio_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let bind = |group: &str, bdf: &str, driver: Option<&str>| {
+            let device = dir.path().join(group).join("devices").join(bdf);
+            fs::create_dir_all(&device).unwrap();
+            if let Some(driver) = driver {
+                std::os::unix::fs::symlink(
+                    format!("../../bus/pci/drivers/{}", driver),
+                    device.join("driver"),
+                )
+                .unwrap();
+            }
+        };
+        bind("12", "0000:3b:00.0", Some("vfio-pci"));
+        bind("12", "0000:3a:00.0", Some("pcieport"));
+        bind("13", "0000:5e:00.0", None);
+        bind("14", "0000:af:00.0", Some("nvidia"));
+        fs::create_dir_all(dir.path().join("15/devices")).unwrap();
+
+        check_vfio_group(dir.path(), "/dev/vfio/vfio").unwrap();
+        check_vfio_group(dir.path(), "/dev/vfio/12").unwrap();
+        check_vfio_group(dir.path(), "/dev/vfio/13").unwrap();
+        assert_eq!(
+            check_vfio_group(dir.path(), "/dev/vfio/14")
+                .unwrap_err()
+                .to_string(),
+            "device 0000:af:00.0 of IOMMU group 14 is bound to nvidia, not vfio-pci"
+        );
+        assert!(check_vfio_group(dir.path(), "/dev/vfio/15").is_err());
+        assert!(check_vfio_group(dir.path(), "/dev/vfio/16").is_err());
+
+        // the bridge stays on the host
+        bind("12", "0000:3b:00.1", None);
+        assert_eq!(
+            vfio_group_functions(dir.path(), "/dev/vfio/12").unwrap(),
+            vec!["0000:3b:00.0", "0000:3b:00.1"]
+        );
+        assert!(vfio_group_functions(dir.path(), "/dev/vfio/vfio")
+            .unwrap()
+            .is_empty());
+        assert!(vfio_group_functions(dir.path(), "/dev/vfio/14").is_err());
+    }
+
+    #[test]
+    fn test_check_hugepages() {
+        let dir = tempfile::tempdir().unwrap();
+        let pages = dir.path().join("hugepages-2048kB");
+        fs::create_dir_all(&pages).unwrap();
+        fs::write(pages.join("free_hugepages"), "512\n").unwrap();
+
+        check_hugepages(dir.path(), "2MB", 1 << 30).unwrap();
+        assert_eq!(
+            check_hugepages(dir.path(), "2MB", 2 << 30)
+                .unwrap_err()
+                .to_string(),
+            "512 free huge pages of 2MB on host, short of the limit of 2147483648 bytes"
+        );
+        assert!(check_hugepages(dir.path(), "1GB", 1 << 30).is_err());
+        assert!(check_hugepages(dir.path(), "2XB", 1 << 30).is_err());
+    }
+
+    #[test]
+    fn test_handle_missing_devices() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("block/8:16")).unwrap();
+        fs::create_dir_all(dir.path().join("char/1:3")).unwrap();
+        let device = |path: &str, r#type: &str, major: i64, minor: i64| LinuxDevice {
+            major,
+            minor,
+            ..new_device(path, r#type)
+        };
+        let linux = || Linux {
+            devices: vec![
+                device("/dev/sdb", "b", 8, 16),
+                device("/dev/sdc", "b", 8, 32),
+                device("/dev/null", "c", 1, 3),
+                device("/dev/fifo", "p", 0, 0),
+            ],
+            ..Default::default()
+        };
+
+        // all the devices are on the host
+        let mut runtime = Runtime::default();
+        let mut present = linux();
+        present.devices.remove(1);
+        handle_missing_devices(&runtime, dir.path(), "c1", &mut present).unwrap();
+        assert_eq!(present.devices.len(), 3);
+
+        // fails fast by default
+        let mut missing = linux();
+        let err = handle_missing_devices(&runtime, dir.path(), "c1", &mut missing).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "device /dev/sdc (8:32) of container c1 not found on host"
+        );
+        assert_eq!(missing.devices.len(), 4);
+
+        runtime.missing_device_policy = MISSING_DEVICE_POLICY_SKIP.to_string();
+        handle_missing_devices(&runtime, dir.path(), "c1", &mut missing).unwrap();
+        let paths: Vec<&str> = missing.devices.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, vec!["/dev/sdb", "/dev/null", "/dev/fifo"]);
+    }
+
+    #[test]
+    fn test_handle_privileged_devices() {
+        // a privileged container with the 20 block devices of the host
+        let privileged = || {
+            let mut devices: Vec<LinuxDevice> = (0..20)
+                .map(|i| new_device(&format!("/dev/sd{}", (b'a' + i) as char), "b"))
+                .collect();
+            devices.push(new_device("/dev/fuse", "c"));
+            Linux {
+                devices,
+                resources: Some(LinuxResources {
+                    devices: vec![allow_all_devices()],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+        };
+        // the device nodes of the CDI devices asked for
+        let requested: BTreeSet<String> = ["/dev/sdc", "/dev/nvme0n1"]
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+
+        // only the block device asked for is kept
+        let mut runtime = Runtime {
+            privileged_without_host_devices: true,
+            ..Default::default()
+        };
+        let mut linux = privileged();
+        handle_privileged_devices(&runtime, "c1", &mut linux, &requested).unwrap();
+        let paths: Vec<&str> = linux.devices.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, vec!["/dev/sdc", "/dev/fuse"]);
+        let mut linux = privileged();
+        handle_privileged_devices(&runtime, "c1", &mut linux, &BTreeSet::new()).unwrap();
+        assert_eq!(linux.devices.len(), 1);
+
+        // only the block devices asked for are added, the ones in the spec
+        // already aren't added again
+        runtime.privileged_without_host_devices = false;
+        runtime.privileged_sandbox = true;
+        let mut linux = privileged();
+        linux.devices.truncate(4);
+        let sdc = BTreeSet::from(["/dev/sdc".to_string()]);
+        handle_privileged_devices(&runtime, "c1", &mut linux, &sdc).unwrap();
+        assert_eq!(linux.devices.len(), 4);
+        // the device cgroup is left to the agent
+        assert_eq!(
+            linux.resources.as_ref().unwrap().devices,
+            vec![allow_all_devices()]
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("sdz");
+        fs::write(&file, "").unwrap();
+        for path in [file.display().to_string(), "/dev/nonexistent".to_string()] {
+            let mut linux = privileged();
+            let path = BTreeSet::from([path]);
+            assert!(handle_privileged_devices(&runtime, "c1", &mut linux, &path).is_err());
+        }
+        runtime.privileged_sandbox = false;
+
+        // refused rather than hotplugging them all
+        let mut linux = privileged();
+        let err = handle_privileged_devices(&runtime, "c1", &mut linux, &requested).unwrap_err();
+        assert!(err.to_string().contains("20 block devices"), "{}", err);
+        assert!(err.to_string().contains("privileged_without_host_devices"));
+        linux.devices.truncate(MAX_PRIVILEGED_BLOCK_DEVICES);
+        handle_privileged_devices(&runtime, "c1", &mut linux, &requested).unwrap();
+        assert_eq!(linux.devices.len(), MAX_PRIVILEGED_BLOCK_DEVICES);
+    }
+}
@@ -0,0 +1,218 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use hypervisor::device::{
+    device_manager::{do_handle_device, DeviceManager},
+    DeviceConfig, DeviceType,
+};
+use tokio::sync::RwLock;
+
+/// LATENCY_BUCKETS are the upper bounds of the latency histograms in
+/// seconds, fixed so that the series of the nodes could be aggregated: from
+/// a block device hotplugged in a few milliseconds, to a virtiofsd started
+/// or a volume copied into the guest in seconds.
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+pub const OP_DEVICE_HOTPLUG: &str = "device_hotplug";
+pub const OP_VIRTIOFSD_START: &str = "virtiofsd_start";
+pub const OP_VOLUME_SETUP: &str = "volume_setup";
+
+const DURATION_METRIC: &str = "kata_resource_operation_duration_seconds";
+const FAILURES_METRIC: &str = "kata_resource_operation_failures_total";
+
+#[derive(Default)]
+struct Series {
+    // the observations in each bucket, not cumulative, the last one is +Inf
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+    failures: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    // the sandbox id label, only if allowed by the config, since it makes
+    // a new series for each sandbox
+    sandbox_id: Option<String>,
+    // by the operation and the type of the resource
+    series: BTreeMap<(&'static str, String), Series>,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+/// init sets the sandbox served by the shim, the series are labeled with
+/// its id if sandbox_label is set.
+pub(crate) fn init(sid: &str, sandbox_label: bool) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.sandbox_id = Some(sid.to_string()).filter(|_| sandbox_label);
+}
+
+/// observe records how long the operation on the resource of the type took,
+/// and whether it failed.
+pub(crate) fn observe(op: &'static str, r#type: &str, elapsed: Duration, succeeded: bool) {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .observe(op, r#type, elapsed, succeeded);
+}
+
+/// time runs the operation on the resource of the type and observes it.
+pub(crate) async fn time<T, F>(op: &'static str, r#type: &str, f: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let start = Instant::now();
+    let result = f.await;
+    observe(op, r#type, start.elapsed(), result.is_ok());
+    result
+}
+
+/// handle_device hotplugs the device through the device manager, the
+/// latency and the failures are observed by the type of the device.
+pub(crate) async fn handle_device(
+    d: &RwLock<DeviceManager>,
+    dev_info: &DeviceConfig,
+) -> Result<DeviceType> {
+    time(
+        OP_DEVICE_HOTPLUG,
+        device_type(dev_info),
+        do_handle_device(d, dev_info),
+    )
+    .await
+}
+
+fn device_type(dev_info: &DeviceConfig) -> &'static str {
+    match dev_info {
+        DeviceConfig::BlockCfg(_) => "block",
+        DeviceConfig::NetworkCfg(_) => "network",
+        DeviceConfig::ShareFsCfg(_) | DeviceConfig::ShareFsMountCfg(_) => "share_fs",
+        DeviceConfig::VfioCfg(_) => "vfio",
+        DeviceConfig::VsockCfg(_) | DeviceConfig::HybridVsockCfg(_) => "vsock",
+    }
+}
+
+/// gather returns the metrics of the resources in the Prometheus text
+/// format, for the metrics handler of the shim to serve.
+pub fn gather() -> String {
+    REGISTRY.lock().unwrap().encode()
+}
+
+impl Registry {
+    fn observe(&mut self, op: &'static str, r#type: &str, elapsed: Duration, succeeded: bool) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|b| secs <= *b)
+            .unwrap_or(LATENCY_BUCKETS.len());
+
+        let series = self.series.entry((op, r#type.to_string())).or_default();
+        series.buckets[bucket] += 1;
+        series.sum += secs;
+        series.count += 1;
+        if !succeeded {
+            series.failures += 1;
+        }
+    }
+
+    fn encode(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP {} How long the resource operations took.",
+            DURATION_METRIC
+        );
+        let _ = writeln!(out, "# TYPE {} histogram", DURATION_METRIC);
+        for ((op, r#type), series) in self.series.iter() {
+            let labels = labels(&self.sandbox_id, op, r#type);
+            let mut cumulative = 0;
+            for (i, count) in series.buckets.iter().enumerate() {
+                cumulative += count;
+                let le = LATENCY_BUCKETS
+                    .get(i)
+                    .map(|b| b.to_string())
+                    .unwrap_or_else(|| "+Inf".to_string());
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    DURATION_METRIC, labels, le, cumulative
+                );
+            }
+            let _ = writeln!(out, "{}_sum{{{}}} {}", DURATION_METRIC, labels, series.sum);
+            let _ = writeln!(
+                out,
+                "{}_count{{{}}} {}",
+                DURATION_METRIC, labels, series.count
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP {} The resource operations failed.",
+            FAILURES_METRIC
+        );
+        let _ = writeln!(out, "# TYPE {} counter", FAILURES_METRIC);
+        for ((op, r#type), series) in self.series.iter() {
+            let labels = labels(&self.sandbox_id, op, r#type);
+            let _ = writeln!(out, "{}{{{}}} {}", FAILURES_METRIC, labels, series.failures);
+        }
+        out
+    }
+}
+
+fn labels(sandbox_id: &Option<String>, op: &str, r#type: &str) -> String {
+    let mut labels = format!("op=\"{}\",type=\"{}\"", op, r#type);
+    if let Some(sid) = sandbox_id {
+        labels = format!("sandbox_id=\"{}\",{}", sid, labels);
+    }
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let mut registry = Registry {
+            sandbox_id: Some("sid".to_string()),
+            ..Default::default()
+        };
+        registry.observe(OP_DEVICE_HOTPLUG, "block", Duration::from_millis(3), true);
+        registry.observe(OP_DEVICE_HOTPLUG, "block", Duration::from_millis(30), false);
+        registry.observe(OP_DEVICE_HOTPLUG, "block", Duration::from_secs(60), true);
+
+        let out = registry.encode();
+        let labels = "sandbox_id=\"sid\",op=\"device_hotplug\",type=\"block\"";
+        for line in [
+            format!("{}_bucket{{{},le=\"0.005\"}} 1", DURATION_METRIC, labels),
+            format!("{}_bucket{{{},le=\"0.05\"}} 2", DURATION_METRIC, labels),
+            format!("{}_bucket{{{},le=\"30\"}} 2", DURATION_METRIC, labels),
+            format!("{}_bucket{{{},le=\"+Inf\"}} 3", DURATION_METRIC, labels),
+            format!("{}_count{{{}}} 3", DURATION_METRIC, labels),
+            format!("{}{{{}}} 1", FAILURES_METRIC, labels),
+        ] {
+            assert!(out.lines().any(|l| l == line), "{} not in {}", line, out);
+        }
+
+        // no sandbox id unless allowed
+        registry.sandbox_id = None;
+        assert!(!registry.encode().contains("sandbox_id"));
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use agent::*;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use kata_types::config::Agent as AgentConfig;
+
+/// MockAgent is the agent of the tests: it records the requests it gets, by
+/// their name, and answers them with the defaults, the volume stats and the
+/// metrics set up, or the errors set up by request. It lists the
+/// watchable-bind storage handler in its details, as the agent does.
+pub(crate) struct MockAgent {
+    version: String,
+    calls: Mutex<Vec<String>>,
+    errors: Mutex<HashMap<&'static str, String>>,
+    unsupported: Mutex<HashSet<&'static str>>,
+    volume_stats: Mutex<HashMap<String, VolumeStatsResponse>>,
+    storage_handlers: Mutex<Vec<String>>,
+    metrics: Mutex<String>,
+}
+
+impl MockAgent {
+    pub(crate) fn new(version: &str) -> Self {
+        Self {
+            version: version.to_string(),
+            calls: Mutex::new(vec![]),
+            errors: Mutex::new(HashMap::new()),
+            unsupported: Mutex::new(HashSet::new()),
+            volume_stats: Mutex::new(HashMap::new()),
+            storage_handlers: Mutex::new(vec!["watchable-bind".to_string()]),
+            metrics: Mutex::new(String::new()),
+        }
+    }
+
+    /// calls returns the requests received so far, the updated interfaces
+    /// and the interfaces set up are named after them, e.g.
+    /// "update_interface eth0", the cpus offlined are counted, e.g.
+    /// "offline_cpu 2".
+    pub(crate) fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// fail makes the request fail with the message from now on.
+    pub(crate) fn fail(&self, request: &'static str, message: &str) {
+        self.errors
+            .lock()
+            .unwrap()
+            .insert(request, message.to_string());
+    }
+
+    /// unsupported makes the agent not serve the request from now on, as an
+    /// older agent or one its policy blocks the request of.
+    pub(crate) fn unsupported(&self, request: &'static str) {
+        self.unsupported.lock().unwrap().insert(request);
+    }
+
+    /// set_storage_handlers sets the storage handlers of the agent details.
+    pub(crate) fn set_storage_handlers(&self, handlers: Vec<&str>) {
+        *self.storage_handlers.lock().unwrap() = handlers.iter().map(|h| h.to_string()).collect();
+    }
+
+    /// set_volume_stats sets the stats of the volume at the guest path.
+    pub(crate) fn set_volume_stats(&self, guest_path: &str, stats: VolumeStatsResponse) {
+        self.volume_stats
+            .lock()
+            .unwrap()
+            .insert(guest_path.to_string(), stats);
+    }
+
+    /// set_metrics sets the metrics of the guest in the prometheus text format.
+    pub(crate) fn set_metrics(&self, metrics: &str) {
+        *self.metrics.lock().unwrap() = metrics.to_string();
+    }
+
+    fn record(&self, request: &'static str, call: String) -> Result<()> {
+        self.calls.lock().unwrap().push(call);
+        if self.unsupported.lock().unwrap().contains(request) {
+            return Err(agent::unsupported_error(request));
+        }
+        match self.errors.lock().unwrap().get(request) {
+            Some(message) => Err(anyhow!("{}", message)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl AgentManager for MockAgent {
+    async fn start(&self, _address: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn stop(&self) {}
+
+    async fn agent_sock(&self) -> Result<String> {
+        Ok(String::new())
+    }
+
+    async fn agent_config(&self) -> AgentConfig {
+        AgentConfig::default()
+    }
+}
+
+#[async_trait]
+impl HealthService for MockAgent {
+    async fn check(&self, _req: CheckRequest) -> Result<HealthCheckResponse> {
+        Ok(HealthCheckResponse::default())
+    }
+
+    async fn version(&self, _req: CheckRequest) -> Result<VersionCheckResponse> {
+        Ok(VersionCheckResponse {
+            agent_version: self.version.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+macro_rules! impl_mock_agent {
+    ($($name: ident | $req: ty | $resp: ty),*) => {
+        #[async_trait]
+        impl Agent for MockAgent {
+            $(async fn $name(&self, _req: $req) -> Result<$resp> {
+                self.record(stringify!($name), stringify!($name).to_string())?;
+                Ok(<$resp>::default())
+            })*
+
+            async fn update_interface(&self, req: UpdateInterfaceRequest) -> Result<Interface> {
+                let name = req.interface.map(|i| i.name).unwrap_or_default();
+                self.record("update_interface", format!("update_interface {}", name))?;
+                Ok(Interface::default())
+            }
+
+            async fn setup_network(&self, req: SetupNetworkRequest) -> Result<Empty> {
+                let names: Vec<String> = req.interfaces.into_iter().map(|i| i.name).collect();
+                self.record("setup_network", format!("setup_network {}", names.join(" ")).trim().to_string())?;
+                Ok(Empty::default())
+            }
+
+            async fn get_volume_stats(&self, req: VolumeStatsRequest) -> Result<VolumeStatsResponse> {
+                self.record("get_volume_stats", format!("get_volume_stats {}", req.volume_guest_path))?;
+                self.volume_stats
+                    .lock()
+                    .unwrap()
+                    .get(&req.volume_guest_path)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("no such file or directory"))
+            }
+
+            async fn get_guest_details(&self, _req: GetGuestDetailsRequest) -> Result<GuestDetailsResponse> {
+                self.record("get_guest_details", "get_guest_details".to_string())?;
+                Ok(GuestDetailsResponse {
+                    agent_details: Some(AgentDetails {
+                        version: self.version.clone(),
+                        storage_handlers: self.storage_handlers.lock().unwrap().clone(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+            }
+
+            async fn offline_cpu(&self, req: OfflineCPURequest) -> Result<Empty> {
+                self.record("offline_cpu", format!("offline_cpu {}", req.nb_cpus))?;
+                Ok(Empty::default())
+            }
+
+            async fn get_metrics(&self, _req: GetMetricsRequest) -> Result<MetricsResponse> {
+                self.record("get_metrics", "get_metrics".to_string())?;
+                Ok(MetricsResponse {
+                    metrics: self.metrics.lock().unwrap().clone(),
+                })
+            }
+        }
+    };
+}
+
+impl_mock_agent!(
+    create_sandbox | CreateSandboxRequest | Empty,
+    destroy_sandbox | Empty | Empty,
+    online_cpu_mem | OnlineCPUMemRequest | Empty,
+    add_arp_neighbors | AddArpNeighborRequest | Empty,
+    list_interfaces | Empty | Interfaces,
+    list_routes | Empty | Routes,
+    update_routes | UpdateRoutesRequest | Routes,
+    set_sysctls | SetSysctlsRequest | Empty,
+    create_container | CreateContainerRequest | Empty,
+    pause_container | ContainerID | Empty,
+    remove_container | RemoveContainerRequest | Empty,
+    resume_container | ContainerID | Empty,
+    start_container | ContainerID | Empty,
+    stats_container | ContainerID | StatsContainerResponse,
+    update_container | UpdateContainerRequest | Empty,
+    exec_process | ExecProcessRequest | Empty,
+    signal_process | SignalProcessRequest | Empty,
+    wait_process | WaitProcessRequest | WaitProcessResponse,
+    close_stdin | CloseStdinRequest | Empty,
+    read_stderr | ReadStreamRequest | ReadStreamResponse,
+    read_stdout | ReadStreamRequest | ReadStreamResponse,
+    tty_win_resize | TtyWinResizeRequest | Empty,
+    write_stdin | WriteStreamRequest | WriteStreamResponse,
+    copy_file | CopyFileRequest | Empty,
+    get_oom_event | Empty | OomEventResponse,
+    get_ip_tables | GetIPTablesRequest | GetIPTablesResponse,
+    set_ip_tables | SetIPTablesRequest | SetIPTablesResponse,
+    resize_volume | ResizeVolumeRequest | Empty,
+    remove_storage | RemoveStorageRequest | Empty,
+    add_swap | AddSwapRequest | Empty,
+    load_kernel_modules | LoadKernelModulesRequest | Empty
+);
@@ -21,6 +21,11 @@ pub struct MacvlanEndpointState {
     pub network_qos: bool,
 }
 
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct MacvtapEndpointState {
+    pub if_name: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, Default)]
 pub struct VlanEndpointState {
     pub if_name: String,
@@ -46,5 +51,15 @@ pub struct EndpointState {
     pub ipvlan_endpoint: Option<IpVlanEndpointState>,
     pub macvlan_endpoint: Option<MacvlanEndpointState>,
     pub vlan_endpoint: Option<VlanEndpointState>,
+    pub macvtap_endpoint: Option<MacvtapEndpointState>,
     // TODO : other endpoint
+    /// the device was attached before the VM booted instead of hotplugged
+    #[serde(default)]
+    pub cold_plugged: bool,
+    /// the tap of the endpoint on the host, empty if there's none
+    #[serde(default)]
+    pub tap_name: String,
+    /// the netns of the endpoint, to delete its tap after restore
+    #[serde(default)]
+    pub netns_path: String,
 }
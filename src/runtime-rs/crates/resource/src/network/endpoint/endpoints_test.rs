@@ -7,13 +7,19 @@
 #[cfg(test)]
 mod tests {
     use anyhow::Context;
-    use netlink_packet_route::MACVLAN_MODE_PRIVATE;
+    use futures::stream::TryStreamExt;
+    use hypervisor::mock::MockHypervisor;
+    use netlink_packet_route::{tc, MACVLAN_MODE_PRIVATE};
     use scopeguard::defer;
+    use test_utils::skip_if_not_root;
 
     use std::sync::Arc;
 
     use crate::network::{
-        endpoint::{IPVlanEndpoint, MacVlanEndpoint, VlanEndpoint},
+        endpoint::{
+            check_endpoint_type, endpoint_type, Endpoint, EndpointType, IPVlanEndpoint,
+            MacVlanEndpoint, VethEndpoint, VlanEndpoint,
+        },
         network_model::{
             self,
             tc_filter_model::{fetch_index, TcFilterModel},
@@ -84,6 +90,8 @@ mod tests {
                                 },
                                 model: Arc::new(TcFilterModel::new().unwrap()), // impossible to panic
                                 network_qos: false,
+                                queues: 5,
+                                tap_queues: Default::default(),
                             },
                         };
 
@@ -207,6 +215,8 @@ mod tests {
                                 model: network_model::new(model_str)
                                     .expect("failed to create new network model"),
                                 network_qos: false,
+                                queues: 5,
+                                tap_queues: Default::default(),
                             },
                         };
 
@@ -307,6 +317,8 @@ mod tests {
                             },
                             model: Arc::new(TcFilterModel::new().unwrap()), // impossible to panic
                             network_qos: false,
+                            queues: 5,
+                            tap_queues: Default::default(),
                         },
                     };
 
@@ -355,4 +367,113 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_endpoint_type() {
+        assert_eq!(endpoint_type("").unwrap(), None);
+        assert_eq!(endpoint_type("tap").unwrap(), Some(EndpointType::Tap));
+        assert_eq!(
+            endpoint_type("macvtap").unwrap(),
+            Some(EndpointType::Macvtap)
+        );
+        assert_eq!(endpoint_type("ipvlan").unwrap(), Some(EndpointType::Ipvlan));
+        assert!(endpoint_type("veth").is_err());
+
+        // tap works with any link
+        assert!(check_endpoint_type(EndpointType::Tap, "veth", 0).is_ok());
+        assert!(check_endpoint_type(EndpointType::Tap, "macvlan", 2).is_ok());
+
+        // macvtap needs a macvtap link with a parent link
+        assert!(check_endpoint_type(EndpointType::Macvtap, "macvtap", 2).is_ok());
+        assert!(check_endpoint_type(EndpointType::Macvtap, "macvtap", 0).is_err());
+        assert!(check_endpoint_type(EndpointType::Macvtap, "macvlan", 2).is_err());
+
+        // ipvlan needs an ipvlan link with a parent link
+        assert!(check_endpoint_type(EndpointType::Ipvlan, "ipvlan", 2).is_ok());
+        assert!(check_endpoint_type(EndpointType::Ipvlan, "ipvlan", 0).is_err());
+        assert!(check_endpoint_type(EndpointType::Ipvlan, "vlan", 2).is_err());
+    }
+
+    // ingress_qdiscs counts the ingress qdiscs of the link
+    async fn ingress_qdiscs(handle: &rtnetlink::Handle, name: &str) -> usize {
+        let index = fetch_index(handle, name).await.unwrap();
+        let mut qdiscs = handle.qdisc().get().execute();
+        let mut count = 0;
+        while let Some(msg) = qdiscs.try_next().await.unwrap() {
+            if msg.header.index == index as i32
+                && msg
+                    .nlas
+                    .iter()
+                    .any(|nla| matches!(nla, tc::Nla::Kind(kind) if kind == "ingress"))
+            {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    // the endpoints whose device isn't added leave no tc rules, and no tap
+    // once deleted
+    #[actix_rt::test]
+    async fn test_attach_undo() {
+        skip_if_not_root!();
+
+        let (conn, handle, _) = rtnetlink::new_connection().unwrap();
+        let thread_handler = tokio::spawn(conn);
+        defer!({
+            thread_handler.abort();
+        });
+
+        for (i, endpoint_type) in vec!["veth", "vlan", "macvlan", "ipvlan"]
+            .into_iter()
+            .enumerate()
+        {
+            let idx = 8200 + i as u32;
+            let virt_iface_name = format!("eth{}", idx);
+            let tap_iface_name = format!("tap{}_kata", idx);
+            handle
+                .link()
+                .add()
+                .veth(format!("peer{}", idx), virt_iface_name.clone())
+                .execute()
+                .await
+                .unwrap();
+
+            let endpoint: Arc<dyn Endpoint> = match endpoint_type {
+                "veth" => Arc::new(
+                    VethEndpoint::new(&handle, "", idx, TC_FILTER_NET_MODEL_STR, 1)
+                        .await
+                        .unwrap(),
+                ),
+                "vlan" => Arc::new(VlanEndpoint::new(&handle, "", idx, 1).await.unwrap()),
+                "macvlan" => Arc::new(
+                    MacVlanEndpoint::new(&handle, "", idx, TC_FILTER_NET_MODEL_STR, 1)
+                        .await
+                        .unwrap(),
+                ),
+                _ => Arc::new(IPVlanEndpoint::new(&handle, "", idx, 1).await.unwrap()),
+            };
+
+            assert!(endpoint
+                .attach(&MockHypervisor::new().fail_add(|_| true))
+                .await
+                .is_err());
+            assert_eq!(
+                ingress_qdiscs(&handle, &tap_iface_name).await,
+                0,
+                "{}",
+                endpoint_type
+            );
+            assert_eq!(
+                ingress_qdiscs(&handle, &virt_iface_name).await,
+                0,
+                "{}",
+                endpoint_type
+            );
+
+            endpoint.delete().await.unwrap();
+            assert!(fetch_index(&handle, &tap_iface_name).await.is_err());
+            assert!(delete_link(&handle, &virt_iface_name).await.is_ok());
+        }
+    }
 }
@@ -45,6 +45,8 @@ impl MacVlanEndpoint {
         Ok(NetworkConfig {
             host_dev_name: iface.name.clone(),
             guest_mac: Some(guest_mac),
+            queue_num: self.net_pair.queues,
+            fds: self.net_pair.tap_fds(),
         })
     }
 }
@@ -59,44 +61,60 @@ impl Endpoint for MacVlanEndpoint {
         self.net_pair.tap.tap_iface.hard_addr.clone()
     }
 
+    async fn queues(&self) -> usize {
+        self.net_pair.queues
+    }
+
     async fn attach(&self, h: &dyn Hypervisor) -> Result<()> {
+        let config = self.get_network_config().context("get network config")?;
         self.net_pair
             .add_network_model()
             .await
             .context("add network model")?;
-        let config = self.get_network_config().context("get network config")?;
-        h.add_device(DeviceType::Network(NetworkDevice {
-            id: self.net_pair.virt_iface.name.clone(),
-            config,
-        }))
-        .await
-        .context("error adding device by hypervisor")?;
-
+        // the tc rules are removed if the device isn't added
+        if let Err(e) = h
+            .add_device(DeviceType::Network(NetworkDevice {
+                id: self.net_pair.virt_iface.name.clone(),
+                config,
+            }))
+            .await
+        {
+            self.net_pair.undo_network_model().await;
+            return Err(e).context("error adding device by hypervisor");
+        }
+        self.net_pair.release_tap_queues();
         Ok(())
     }
 
-    async fn detach(&self, h: &dyn Hypervisor) -> Result<()> {
+    async fn detach(&self, h: &dyn Hypervisor, hotplugged: bool) -> Result<()> {
         self.net_pair
             .del_network_model()
             .await
             .context("del network model")?;
-        let config = self.get_network_config().context("get network config")?;
-        h.remove_device(DeviceType::Network(NetworkDevice {
-            id: self.net_pair.virt_iface.name.clone(),
-            config,
-        }))
-        .await
-        .context("error removing device by hypervisor")?;
+        if hotplugged {
+            let config = self.get_network_config().context("get network config")?;
+            h.remove_device(DeviceType::Network(NetworkDevice {
+                id: self.net_pair.virt_iface.name.clone(),
+                config,
+            }))
+            .await
+            .context("error removing device by hypervisor")?;
+        }
 
         Ok(())
     }
 
+    async fn delete(&self) -> Result<()> {
+        self.net_pair.delete().await.context("delete network pair")
+    }
+
     async fn save(&self) -> Option<EndpointState> {
         Some(EndpointState {
             macvlan_endpoint: Some(MacvlanEndpointState {
                 if_name: self.net_pair.virt_iface.name.clone(),
                 network_qos: self.net_pair.network_qos,
             }),
+            tap_name: self.net_pair.tap.tap_iface.name.clone(),
             ..Default::default()
         })
     }
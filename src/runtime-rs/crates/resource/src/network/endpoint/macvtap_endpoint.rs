@@ -0,0 +1,133 @@
+// Copyright (c) 2019-2022 Alibaba Cloud
+// Copyright (c) 2019-2022 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Error};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Mutex;
+
+use super::endpoint_persist::{EndpointState, MacvtapEndpointState};
+use super::Endpoint;
+use crate::network::utils;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use hypervisor::device::DeviceType;
+use hypervisor::NetworkDevice;
+use hypervisor::{device::driver::NetworkConfig, Hypervisor};
+
+// the character device of a macvtap link is named after its index
+const MACVTAP_DEVICE_PREFIX: &str = "/dev/tap";
+
+// MacVtapEndpoint is a macvtap link of the netns handed to the VM as it is,
+// the hypervisor reads and writes the queues of its character device, so
+// neither a tap nor a network model is needed.
+#[derive(Debug)]
+pub struct MacVtapEndpoint {
+    name: String,
+    hard_addr: String,
+    queues: usize,
+    // the queues are closed once the hypervisor took them, see attach
+    queue_files: Mutex<Vec<File>>,
+}
+
+impl MacVtapEndpoint {
+    pub fn new(name: &str, index: u32, hardware_addr: &[u8], queues: usize) -> Result<Self> {
+        let path = format!("{}{}", MACVTAP_DEVICE_PREFIX, index);
+        let queues = queues.max(1);
+        let mut queue_files = Vec::with_capacity(queues);
+        for _ in 0..queues {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .with_context(|| format!("open macvtap queue {}", &path))?;
+            queue_files.push(file);
+        }
+        Ok(MacVtapEndpoint {
+            name: name.to_string(),
+            hard_addr: utils::get_mac_addr(hardware_addr).context("get mac addr")?,
+            queues,
+            queue_files: Mutex::new(queue_files),
+        })
+    }
+
+    fn get_network_config(&self) -> Result<NetworkConfig> {
+        let guest_mac = utils::parse_mac(&self.hard_addr).ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::InvalidData,
+                format!("hard_addr {}", &self.hard_addr),
+            )
+        })?;
+        let fds: Vec<RawFd> = self
+            .queue_files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|f| f.as_raw_fd())
+            .collect();
+        Ok(NetworkConfig {
+            host_dev_name: self.name.clone(),
+            guest_mac: Some(guest_mac),
+            queue_num: self.queues,
+            fds,
+        })
+    }
+}
+
+#[async_trait]
+impl Endpoint for MacVtapEndpoint {
+    async fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    async fn hardware_addr(&self) -> String {
+        self.hard_addr.clone()
+    }
+
+    async fn queues(&self) -> usize {
+        self.queues
+    }
+
+    async fn attach(&self, h: &dyn Hypervisor) -> Result<()> {
+        let config = self.get_network_config().context("get network config")?;
+        h.add_device(DeviceType::Network(NetworkDevice {
+            id: self.name.clone(),
+            config,
+        }))
+        .await
+        .context("error adding device by hypervisor")?;
+        self.queue_files.lock().unwrap().clear();
+        Ok(())
+    }
+
+    async fn detach(&self, h: &dyn Hypervisor, hotplugged: bool) -> Result<()> {
+        if hotplugged {
+            let config = self.get_network_config().context("get network config")?;
+            h.remove_device(DeviceType::Network(NetworkDevice {
+                id: self.name.clone(),
+                config,
+            }))
+            .await
+            .context("error removing device by hypervisor")?;
+        }
+
+        Ok(())
+    }
+
+    async fn delete(&self) -> Result<()> {
+        // the macvtap link belongs to the netns, nothing was created for it
+        Ok(())
+    }
+
+    async fn save(&self) -> Option<EndpointState> {
+        Some(EndpointState {
+            macvtap_endpoint: Some(MacvtapEndpointState {
+                if_name: self.name.clone(),
+            }),
+            ..Default::default()
+        })
+    }
+}
@@ -14,20 +14,96 @@ mod vlan_endpoint;
 pub use vlan_endpoint::VlanEndpoint;
 mod macvlan_endpoint;
 pub use macvlan_endpoint::MacVlanEndpoint;
+mod macvtap_endpoint;
+pub use macvtap_endpoint::MacVtapEndpoint;
 pub mod endpoint_persist;
 mod endpoints_test;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use hypervisor::Hypervisor;
 
 use super::EndpointState;
 
+pub(crate) const TAP_ENDPOINT_STR: &str = "tap";
+pub(crate) const MACVTAP_ENDPOINT_STR: &str = "macvtap";
+pub(crate) const IPVLAN_ENDPOINT_STR: &str = "ipvlan";
+
+/// EndpointType forces the way the interfaces are connected to the VM instead
+/// of inferring it from the type of the link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointType {
+    /// a tap device redirected to the link by the network model
+    Tap,
+    /// the queues of a macvtap link with a real parent link
+    Macvtap,
+    /// a tap device redirected to an ipvlan link
+    Ipvlan,
+}
+
+/// parse the endpoint type, an empty string means inferring it.
+pub fn endpoint_type(endpoint_type: &str) -> Result<Option<EndpointType>> {
+    match endpoint_type {
+        "" => Ok(None),
+        TAP_ENDPOINT_STR => Ok(Some(EndpointType::Tap)),
+        MACVTAP_ENDPOINT_STR => Ok(Some(EndpointType::Macvtap)),
+        IPVLAN_ENDPOINT_STR => Ok(Some(EndpointType::Ipvlan)),
+        _ => Err(anyhow!("unsupported endpoint type {}", endpoint_type)),
+    }
+}
+
+/// check that the endpoint type could be used with the link.
+pub fn check_endpoint_type(
+    endpoint_type: EndpointType,
+    link_type: &str,
+    parent_index: u32,
+) -> Result<()> {
+    match endpoint_type {
+        EndpointType::Tap => Ok(()),
+        EndpointType::Macvtap => {
+            if link_type != "macvtap" {
+                return Err(anyhow!(
+                    "macvtap endpoint needs a macvtap link, got {} link",
+                    link_type
+                ));
+            }
+            if parent_index == 0 {
+                return Err(anyhow!("macvtap endpoint needs a real parent link"));
+            }
+            Ok(())
+        }
+        EndpointType::Ipvlan => {
+            if link_type != "ipvlan" {
+                return Err(anyhow!(
+                    "ipvlan endpoint needs an ipvlan link, got {} link",
+                    link_type
+                ));
+            }
+            if parent_index == 0 {
+                return Err(anyhow!("ipvlan endpoint needs a real parent link"));
+            }
+            Ok(())
+        }
+    }
+}
+
 #[async_trait]
 pub trait Endpoint: std::fmt::Debug + Send + Sync {
     async fn name(&self) -> String;
     async fn hardware_addr(&self) -> String;
+    /// queues returns the virtio-net queue pairs of the device, which the
+    /// guest is told to enable, 0 leaves the device as it comes up.
+    async fn queues(&self) -> usize;
+    /// attach the endpoint to the VM. If a step fails, the ones done before
+    /// are undone, e.g. the tc rules of a device the hypervisor didn't add
+    /// are removed, so a failed attach leaves nothing behind.
     async fn attach(&self, hypervisor: &dyn Hypervisor) -> Result<()>;
-    async fn detach(&self, hypervisor: &dyn Hypervisor) -> Result<()>;
+    /// detach the endpoint, the device is removed from the hypervisor only if
+    /// it was hotplugged, a cold plugged one goes away with the VM.
+    async fn detach(&self, hypervisor: &dyn Hypervisor, hotplugged: bool) -> Result<()>;
+    /// delete removes the host artifacts of the endpoint, e.g. the tap and
+    /// the tc rules, which outlive the VM if the netns isn't ours. It's done
+    /// in the netns of the endpoint, the artifacts gone already are skipped.
+    async fn delete(&self) -> Result<()>;
     async fn save(&self) -> Option<EndpointState>;
 }
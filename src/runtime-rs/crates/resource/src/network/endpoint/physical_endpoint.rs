@@ -82,6 +82,40 @@ impl PhysicalEndpoint {
             bdf,
         })
     }
+
+    // add_vfio_device adds the interface bound to vfio to the hypervisor
+    async fn add_vfio_device(&self, hypervisor: &dyn Hypervisor) -> Result<()> {
+        // set vfio's bus type, pci or mmio. Mostly use pci by default.
+        let mode = match self.driver.as_str() {
+            "virtio-pci" => "mmio",
+            _ => "pci",
+        };
+
+        // keep the interface close to the NUMA node of the host it's on
+        let config = VfioConfig {
+            sysfs_path: "".to_string(),
+            bus_slot_func: self.bdf.clone(),
+            mode: driver::VfioBusMode::new(mode)
+                .with_context(|| format!("new vfio bus mode {:?}", mode))?,
+            topology_hint: Some(driver::PciTopology::on_host_numa_node(&self.bdf)),
+        };
+        let topology_supported = hypervisor
+            .capabilities()
+            .await
+            .context("get capabilities")?
+            .is_pci_topology_supported();
+
+        // add vfio device
+        let d = DeviceType::Vfio(VfioDevice {
+            id: format!("physical_nic_{}", self.iface_name),
+            placement: config.placement(topology_supported),
+            config,
+            guest_pci_slot: None,
+            attach_count: 0,
+        });
+        hypervisor.add_device(d).await.context("add device")?;
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -94,6 +128,11 @@ impl Endpoint for PhysicalEndpoint {
         self.hard_addr.clone()
     }
 
+    async fn queues(&self) -> usize {
+        // the queues of the device passed through are left to its driver
+        0
+    }
+
     async fn attach(&self, hypervisor: &dyn Hypervisor) -> Result<()> {
         // bind physical interface from host driver and bind to vfio
         driver::bind_device_to_vfio(
@@ -103,29 +142,29 @@ impl Endpoint for PhysicalEndpoint {
         )
         .with_context(|| format!("bind physical endpoint from {} to vfio", &self.driver))?;
 
-        // set vfio's bus type, pci or mmio. Mostly use pci by default.
-        let mode = match self.driver.as_str() {
-            "virtio-pci" => "mmio",
-            _ => "pci",
-        };
-
-        // add vfio device
-        let d = DeviceType::Vfio(VfioDevice {
-            id: format!("physical_nic_{}", self.name().await),
-            config: VfioConfig {
-                sysfs_path: "".to_string(),
-                bus_slot_func: self.bdf.clone(),
-                mode: driver::VfioBusMode::new(mode)
-                    .with_context(|| format!("new vfio bus mode {:?}", mode))?,
-            },
-        });
-        hypervisor.add_device(d).await.context("add device")?;
+        // the interface is bound back to the host driver if it isn't added
+        if let Err(e) = self.add_vfio_device(hypervisor).await {
+            if let Err(err) = driver::bind_device_to_host(
+                &self.bdf,
+                &self.driver,
+                &self.vendor_device_id.vendor_device_id(),
+            ) {
+                warn!(
+                    sl!(),
+                    "couldn't bind physical endpoint {} back to {}: {:?}",
+                    self.bdf,
+                    self.driver,
+                    err
+                );
+            }
+            return Err(e);
+        }
         Ok(())
     }
 
     // detach for physical endpoint unbinds the physical network interface from vfio-pci
     // and binds it back to the saved host driver.
-    async fn detach(&self, _hypervisor: &dyn Hypervisor) -> Result<()> {
+    async fn detach(&self, _hypervisor: &dyn Hypervisor, _hotplugged: bool) -> Result<()> {
         // bind back the physical network interface to host.
         // we need to do this even if a new network namespace has not
         // been created by virt-containers.
@@ -146,6 +185,17 @@ impl Endpoint for PhysicalEndpoint {
         Ok(())
     }
 
+    async fn delete(&self) -> Result<()> {
+        // bound back to the host driver on detach, which is skipped with a
+        // netns that isn't ours
+        driver::bind_device_to_host(
+            &self.bdf,
+            &self.driver,
+            &self.vendor_device_id.vendor_device_id(),
+        )
+        .context("bind physical endpoint device to host")
+    }
+
     async fn save(&self) -> Option<EndpointState> {
         Some(EndpointState {
             physical_endpoint: Some(PhysicalEndpointState {
@@ -5,22 +5,22 @@
 //
 
 mod endpoint;
-pub use endpoint::endpoint_persist::EndpointState;
-pub use endpoint::Endpoint;
+pub use endpoint::endpoint_persist::{EndpointState, PhysicalEndpointState};
+pub use endpoint::{Endpoint, EndpointType};
 mod network_entity;
 mod network_info;
 pub use network_info::NetworkInfo;
 mod network_model;
 pub use network_model::NetworkModel;
 mod network_with_netns;
-pub use network_with_netns::NetworkWithNetNsConfig;
 use network_with_netns::NetworkWithNetns;
+pub use network_with_netns::{delete_endpoints, NetworkWithNetNsConfig};
 mod network_pair;
 use network_pair::NetworkPair;
 mod utils;
 pub use utils::netns::{generate_netns_name, NetnsGuard};
 
-use std::sync::Arc;
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
 
 use anyhow::{Context, Result};
 use async_trait::async_trait;
@@ -33,12 +33,25 @@ pub enum NetworkConfig {
 
 #[async_trait]
 pub trait Network: Send + Sync {
-    async fn setup(&self, h: &dyn Hypervisor) -> Result<()>;
+    /// setup attaches the interfaces not attached yet. Only the primary one is
+    /// attached if cold_plug is set, it's part of the devices the VM boots
+    /// with, the others are hotplugged once the VM is started.
+    async fn setup(&self, h: &dyn Hypervisor, cold_plug: bool) -> Result<()>;
     async fn interfaces(&self) -> Result<Vec<agent::Interface>>;
     async fn routes(&self) -> Result<Vec<agent::Route>>;
     async fn neighs(&self) -> Result<Vec<agent::ARPNeighbor>>;
     async fn save(&self) -> Option<Vec<EndpointState>>;
+    /// detach detaches the endpoints attached, whoever created the netns,
+    /// it undoes a setup that failed or timed out.
+    async fn detach(&self, h: &dyn Hypervisor) -> Result<()>;
     async fn remove(&self, h: &dyn Hypervisor) -> Result<()>;
+    /// delete removes the host artifacts of the endpoints, which are left
+    /// by remove if the netns isn't created by us, it tolerates the netns
+    /// gone already.
+    async fn delete(&self) -> Result<()>;
+    /// netns_path returns the netns the endpoints are set up in, None if
+    /// the network has no netns.
+    async fn netns_path(&self) -> Option<String>;
 }
 
 pub async fn new(config: &NetworkConfig) -> Result<Arc<dyn Network>> {
@@ -50,3 +63,112 @@ pub async fn new(config: &NetworkConfig) -> Result<Arc<dyn Network>> {
         )),
     }
 }
+
+// the prefix of the network sysctls, applied in the guest rather than in the
+// netns of the host
+const NET_SYSCTL_PREFIX: &str = "net.";
+
+/// net_sysctls returns the network sysctls of the spec of the sandbox.
+pub fn net_sysctls(spec: &oci::Spec) -> HashMap<String, String> {
+    spec.linux
+        .as_ref()
+        .map(|linux| {
+            linux
+                .sysctl
+                .iter()
+                .filter(|(key, _)| key.starts_with(NET_SYSCTL_PREFIX))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// valid_neighbors drops the neighbors the agent would reject, with a
+/// warning, so that one of them doesn't fail the whole batch: the ip address
+/// has to be valid, the mac address too if any, and the device has to be one
+/// of the interfaces of the guest.
+pub(crate) fn valid_neighbors(
+    neighbors: Vec<agent::ARPNeighbor>,
+    interfaces: &[agent::Interface],
+) -> Vec<agent::ARPNeighbor> {
+    neighbors
+        .into_iter()
+        .filter(|n| {
+            let ip = n.to_ip_address.as_ref().map(|ip| ip.address.as_str());
+            let invalid = if ip.and_then(|ip| ip.parse::<IpAddr>().ok()).is_none() {
+                "ip address"
+            } else if !n.ll_addr.is_empty() && utils::parse_mac(&n.ll_addr).is_none() {
+                "mac address"
+            } else if !interfaces.iter().any(|i| i.name == n.device) {
+                "device"
+            } else {
+                return true;
+            };
+            warn!(sl!(), "skip neighbor with invalid {}: {:?}", invalid, n);
+            false
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn neighbor(ip: &str, mac: &str, device: &str) -> agent::ARPNeighbor {
+        agent::ARPNeighbor {
+            to_ip_address: Some(agent::IPAddress {
+                address: ip.to_string(),
+                ..Default::default()
+            }),
+            device: device.to_string(),
+            ll_addr: mac.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_net_sysctls() {
+        let spec = oci::Spec {
+            linux: Some(oci::Linux {
+                sysctl: vec![
+                    ("net.ipv4.ip_unprivileged_port_start", "0"),
+                    ("kernel.shm_rmid_forced", "1"),
+                ]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let sysctls = net_sysctls(&spec);
+        assert_eq!(sysctls.len(), 1);
+        assert_eq!(sysctls["net.ipv4.ip_unprivileged_port_start"], "0");
+        assert!(net_sysctls(&oci::Spec::default()).is_empty());
+    }
+
+    #[test]
+    fn test_valid_neighbors() {
+        let interfaces = vec![agent::Interface {
+            name: "eth0".to_string(),
+            ..Default::default()
+        }];
+        let neighbors = vec![
+            neighbor("10.0.0.1", "02:42:0a:00:00:01", "eth0"),
+            neighbor("10.0.0.256", "02:42:0a:00:00:02", "eth0"),
+            neighbor("fe80::1", "", "eth0"),
+            neighbor("10.0.0.3", "02:42:0a:00:00", "eth0"),
+            neighbor("10.0.0.4", "02:42:0a:00:00:04", "eth1"),
+            agent::ARPNeighbor {
+                to_ip_address: None,
+                ..neighbor("", "", "eth0")
+            },
+        ];
+        let valid = valid_neighbors(neighbors.clone(), &interfaces);
+        assert_eq!(valid, vec![neighbors[0].clone(), neighbors[2].clone()]);
+
+        // none valid is the same as none at all
+        assert!(valid_neighbors(neighbors[3..].to_vec(), &interfaces).is_empty());
+        assert!(valid_neighbors(neighbors, &[]).is_empty());
+    }
+}
@@ -12,6 +12,9 @@ use super::{Endpoint, NetworkInfo};
 pub(crate) struct NetworkEntity {
     pub(crate) endpoint: Arc<dyn Endpoint>,
     pub(crate) network_info: Arc<dyn NetworkInfo>,
+    pub(crate) attached: bool,
+    // the device is part of the VM boot config, it's never hot-unplugged
+    pub(crate) cold_plugged: bool,
 }
 
 impl NetworkEntity {
@@ -19,6 +22,8 @@ impl NetworkEntity {
         Self {
             endpoint,
             network_info,
+            attached: false,
+            cold_plugged: false,
         }
     }
 }
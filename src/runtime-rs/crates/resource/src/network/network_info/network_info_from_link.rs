@@ -4,14 +4,17 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::convert::TryFrom;
+use std::{cmp::Reverse, collections::BTreeSet, convert::TryFrom};
 
 use agent::{ARPNeighbor, IPAddress, IPFamily, Interface, Route};
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use futures::stream::TryStreamExt;
 use netlink_packet_route::{
-    self, neighbour::NeighbourMessage, nlas::neighbour::Nla, route::RouteMessage,
+    self,
+    neighbour::NeighbourMessage,
+    nlas::{neighbour::Nla, route::Nla as RouteNla},
+    route::{RouteFlags, RouteMessage},
 };
 
 use super::NetworkInfo;
@@ -28,10 +31,16 @@ pub(crate) struct NetworkInfoFromLink {
 }
 
 impl NetworkInfoFromLink {
+    /// new scans the addresses, the neighbors and the routes of the link,
+    /// the attributes of its routes the guest doesn't get are added to
+    /// unsupported. The interface gets the queues of the endpoint of the
+    /// link.
     pub async fn new(
         handle: &rtnetlink::Handle,
         link: &dyn link::Link,
         hw_addr: &str,
+        queues: usize,
+        unsupported: &mut BTreeSet<String>,
     ) -> Result<Self> {
         let attrs = link.attrs();
         let name = &attrs.name;
@@ -48,11 +57,12 @@ impl NetworkInfoFromLink {
                 pci_addr: Default::default(),
                 field_type: link.r#type().to_string(),
                 raw_flags: attrs.flags & libc::IFF_NOARP as u32,
+                queues: queues as u32,
             },
             neighs: handle_neighbors(handle, attrs)
                 .await
                 .context("handle neighbours")?,
-            routes: handle_routes(handle, attrs)
+            routes: handle_routes(handle, attrs, unsupported)
                 .await
                 .context("handle routes")?,
         })
@@ -155,11 +165,42 @@ async fn handle_neighbors(
     Ok(neighs)
 }
 
-fn generate_route(name: &str, route: &RouteMessage) -> Result<Option<Route>> {
+// generate_route converts the route of the link, the kernel routes are
+// left to the guest kernel. The attributes the agent can't set, e.g. the
+// nexthops of multipath routes, are added to unsupported, the route is
+// installed without them.
+fn generate_route(
+    name: &str,
+    route: &RouteMessage,
+    unsupported: &mut BTreeSet<String>,
+) -> Result<Option<Route>> {
     if route.header.protocol == libc::RTPROT_KERNEL {
         return Ok(None);
     }
 
+    if route.header.table != libc::RT_TABLE_MAIN {
+        unsupported.insert(format!("table {}", route.header.table));
+    }
+    if route.header.kind != libc::RTN_UNICAST {
+        unsupported.insert(format!("type {}", route.header.kind));
+    }
+    let mut metric = 0;
+    for nla in route.nlas.iter() {
+        match nla {
+            RouteNla::Priority(priority) => metric = *priority,
+            RouteNla::MultiPath(_) => {
+                unsupported.insert("multipath".to_string());
+            }
+            RouteNla::Encap(_) => {
+                unsupported.insert("encap".to_string());
+            }
+            RouteNla::Pref(_) => {
+                unsupported.insert("pref".to_string());
+            }
+            _ => {}
+        }
+    }
+
     Ok(Some(Route {
         dest: route
             .destination_prefix()
@@ -177,14 +218,34 @@ fn generate_route(name: &str, route: &RouteMessage) -> Result<Option<Route>> {
         } else {
             IPFamily::V6
         },
+        flags: (route.header.flags & RouteFlags::RTNH_F_ONLINK).bits(),
+        metric,
     }))
 }
 
+// prefix_len returns the prefix length of the destination, 0 for the
+// default route
+fn prefix_len(route: &Route) -> u8 {
+    route
+        .dest
+        .split_once('/')
+        .and_then(|(_, len)| len.parse().ok())
+        .unwrap_or_default()
+}
+
+/// sort_routes orders the routes as the guest installs them: the device
+/// routes before the gateway routes depending on them, the more specific
+/// first, then the lower metric, i.e. the higher priority, first.
+pub(crate) fn sort_routes(routes: &mut [Route]) {
+    routes.sort_by_key(|r| (!r.gateway.is_empty(), Reverse(prefix_len(r)), r.metric));
+}
+
 async fn get_route_from_msg(
     routes: &mut Vec<Route>,
     handle: &rtnetlink::Handle,
     attrs: &LinkAttrs,
     ip_version: rtnetlink::IpVersion,
+    unsupported: &mut BTreeSet<String>,
 ) -> Result<()> {
     let name = &attrs.name;
     let mut route_msg_list = handle.route().get(ip_version).execute();
@@ -192,7 +253,9 @@ async fn get_route_from_msg(
         // get route filter with index
         if let Some(index) = route.output_interface() {
             if index == attrs.index {
-                if let Some(route) = generate_route(name, &route).context("generate route")? {
+                if let Some(route) =
+                    generate_route(name, &route, unsupported).context("generate route")?
+                {
                     routes.push(route);
                 }
             }
@@ -201,14 +264,31 @@ async fn get_route_from_msg(
     Ok(())
 }
 
-async fn handle_routes(handle: &rtnetlink::Handle, attrs: &LinkAttrs) -> Result<Vec<Route>> {
+async fn handle_routes(
+    handle: &rtnetlink::Handle,
+    attrs: &LinkAttrs,
+    unsupported: &mut BTreeSet<String>,
+) -> Result<Vec<Route>> {
     let mut routes = vec![];
-    get_route_from_msg(&mut routes, handle, attrs, rtnetlink::IpVersion::V4)
-        .await
-        .context("get ip v4 route")?;
-    get_route_from_msg(&mut routes, handle, attrs, rtnetlink::IpVersion::V6)
-        .await
-        .context("get ip v6 route")?;
+    get_route_from_msg(
+        &mut routes,
+        handle,
+        attrs,
+        rtnetlink::IpVersion::V4,
+        unsupported,
+    )
+    .await
+    .context("get ip v4 route")?;
+    get_route_from_msg(
+        &mut routes,
+        handle,
+        attrs,
+        rtnetlink::IpVersion::V6,
+        unsupported,
+    )
+    .await
+    .context("get ip v6 route")?;
+    sort_routes(&mut routes);
     Ok(routes)
 }
 
@@ -226,3 +306,124 @@ impl NetworkInfo for NetworkInfoFromLink {
         Ok(self.neighs.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    // route_msg builds a route of eth0 as the netns dumps it
+    fn route_msg(
+        dest: Option<(Ipv4Addr, u8)>,
+        gateway: Option<Ipv4Addr>,
+        extra: Vec<RouteNla>,
+    ) -> RouteMessage {
+        let mut msg = RouteMessage::default();
+        msg.header.address_family = libc::AF_INET as u8;
+        msg.header.table = libc::RT_TABLE_MAIN;
+        msg.header.kind = libc::RTN_UNICAST;
+        msg.header.protocol = libc::RTPROT_BOOT;
+        msg.nlas.push(RouteNla::Oif(2));
+        if let Some((addr, len)) = dest {
+            msg.header.destination_prefix_length = len;
+            msg.nlas.push(RouteNla::Destination(addr.octets().to_vec()));
+        }
+        if let Some(gateway) = gateway {
+            msg.nlas.push(RouteNla::Gateway(gateway.octets().to_vec()));
+        }
+        msg.nlas.extend(extra);
+        msg
+    }
+
+    fn route(dest: &str, gateway: &str, scope: u8, flags: u32, metric: u32) -> Route {
+        Route {
+            dest: dest.to_string(),
+            gateway: gateway.to_string(),
+            device: "eth0".to_string(),
+            scope: scope as u32,
+            family: IPFamily::V4,
+            flags,
+            metric,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_generate_routes() {
+        // default via 10.0.0.1 dev eth0 metric 200
+        let low = route_msg(
+            None,
+            Some(Ipv4Addr::new(10, 0, 0, 1)),
+            vec![RouteNla::Priority(200)],
+        );
+        // default via 10.0.0.254 dev eth0 metric 100
+        let high = route_msg(
+            None,
+            Some(Ipv4Addr::new(10, 0, 0, 254)),
+            vec![RouteNla::Priority(100)],
+        );
+        // 10.0.0.0/24 dev eth0 proto kernel scope link src 10.0.0.5
+        let mut kernel = route_msg(Some((Ipv4Addr::new(10, 0, 0, 0), 24)), None, vec![]);
+        kernel.header.protocol = libc::RTPROT_KERNEL;
+        kernel.header.scope = libc::RT_SCOPE_LINK;
+        // 172.16.0.0/16 via 169.254.1.1 dev eth0 onlink
+        let mut onlink = route_msg(
+            Some((Ipv4Addr::new(172, 16, 0, 0), 16)),
+            Some(Ipv4Addr::new(169, 254, 1, 1)),
+            vec![],
+        );
+        onlink.header.flags = RouteFlags::RTNH_F_ONLINK;
+        // 169.254.1.1 dev eth0 scope link
+        let mut device = route_msg(Some((Ipv4Addr::new(169, 254, 1, 1), 32)), None, vec![]);
+        device.header.scope = libc::RT_SCOPE_LINK;
+
+        // the netns routes in the order of the dump
+        let mut unsupported = BTreeSet::new();
+        let mut routes = vec![];
+        for msg in vec![low, high, kernel, onlink, device].iter() {
+            if let Some(r) = generate_route("eth0", msg, &mut unsupported).unwrap() {
+                routes.push(r);
+            }
+        }
+        sort_routes(&mut routes);
+        assert!(unsupported.is_empty());
+
+        let universe = libc::RT_SCOPE_UNIVERSE;
+        let onlink = RouteFlags::RTNH_F_ONLINK.bits();
+        assert_eq!(
+            routes,
+            vec![
+                route("169.254.1.1/32", "", libc::RT_SCOPE_LINK, 0, 0),
+                route("172.16.0.0/16", "169.254.1.1", universe, onlink, 0),
+                route("", "10.0.0.254", universe, 0, 100),
+                route("", "10.0.0.1", universe, 0, 200),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_route_attributes() {
+        // 10.1.0.0/16 via 10.0.0.1 dev eth0 table 100
+        let mut table = route_msg(
+            Some((Ipv4Addr::new(10, 1, 0, 0), 16)),
+            Some(Ipv4Addr::new(10, 0, 0, 1)),
+            vec![],
+        );
+        table.header.table = 100;
+        // default dev eth0 with the nexthops of a multipath route
+        let multipath = route_msg(None, None, vec![RouteNla::MultiPath(vec![])]);
+
+        let mut unsupported = BTreeSet::new();
+        for msg in vec![table, multipath].iter() {
+            // the routes are still installed, without the attributes
+            assert!(generate_route("eth0", msg, &mut unsupported)
+                .unwrap()
+                .is_some());
+        }
+        assert_eq!(
+            unsupported.into_iter().collect::<Vec<_>>(),
+            vec!["multipath", "table 100"]
+        );
+    }
+}
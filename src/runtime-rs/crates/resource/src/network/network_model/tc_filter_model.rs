@@ -42,45 +42,21 @@ impl NetworkModel for TcFilterModel {
             .await
             .context("fetch virt by index")?;
 
-        handle
-            .qdisc()
-            .add(tap_index as i32)
-            .ingress()
-            .execute()
-            .await
-            .context("add tap ingress")?;
-
-        handle
-            .qdisc()
-            .add(virt_index as i32)
-            .ingress()
-            .execute()
-            .await
-            .context("add virt ingress")?;
-
-        handle
-            .traffic_filter(tap_index as i32)
-            .add()
-            .parent(0xffff0000)
-            // get protocol with network byte order
-            .protocol(0x0003_u16.to_be())
-            .redirect(virt_index)
-            .execute()
-            .await
-            .context("add redirect for tap")?;
-
-        handle
-            .traffic_filter(virt_index as i32)
-            .add()
-            .parent(0xffff0000)
-            // get protocol with network byte order
-            .protocol(0x0003_u16.to_be())
-            .redirect(tap_index)
-            .execute()
-            .await
-            .context("add redirect for virt")?;
-
-        Ok(())
+        // the ingress qdiscs added are deleted if a later step fails, the
+        // filters on them go along
+        let mut added = vec![];
+        let result = add_redirect(&handle, tap_index, virt_index, &mut added).await;
+        if result.is_err() {
+            for index in added {
+                if let Err(e) = handle.qdisc().del(index as i32).execute().await {
+                    warn!(
+                        sl!(),
+                        "couldn't delete ingress qdisc of link {}: {:?}", index, e
+                    );
+                }
+            }
+        }
+        result
     }
 
     async fn del(&self, pair: &NetworkPair) -> Result<()> {
@@ -95,6 +71,57 @@ impl NetworkModel for TcFilterModel {
     }
 }
 
+// add_redirect redirects the traffic between the tap and the virt link, the
+// links whose ingress qdisc is added are recorded in added
+async fn add_redirect(
+    handle: &Handle,
+    tap_index: u32,
+    virt_index: u32,
+    added: &mut Vec<u32>,
+) -> Result<()> {
+    handle
+        .qdisc()
+        .add(tap_index as i32)
+        .ingress()
+        .execute()
+        .await
+        .context("add tap ingress")?;
+    added.push(tap_index);
+
+    handle
+        .qdisc()
+        .add(virt_index as i32)
+        .ingress()
+        .execute()
+        .await
+        .context("add virt ingress")?;
+    added.push(virt_index);
+
+    handle
+        .traffic_filter(tap_index as i32)
+        .add()
+        .parent(0xffff0000)
+        // get protocol with network byte order
+        .protocol(0x0003_u16.to_be())
+        .redirect(virt_index)
+        .execute()
+        .await
+        .context("add redirect for tap")?;
+
+    handle
+        .traffic_filter(virt_index as i32)
+        .add()
+        .parent(0xffff0000)
+        // get protocol with network byte order
+        .protocol(0x0003_u16.to_be())
+        .redirect(tap_index)
+        .execute()
+        .await
+        .context("add redirect for virt")?;
+
+    Ok(())
+}
+
 pub async fn fetch_index(handle: &Handle, name: &str) -> Result<u32> {
     let link = crate::network::network_pair::get_link_by_name(handle, name)
         .await
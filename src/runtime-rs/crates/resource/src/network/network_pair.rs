@@ -4,10 +4,17 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::{convert::TryFrom, sync::Arc, usize};
+use std::{
+    convert::TryFrom,
+    fs::File,
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{Arc, Mutex},
+    usize,
+};
 
 use anyhow::{anyhow, Context, Result};
 use futures::stream::TryStreamExt;
+use scopeguard::defer;
 
 use super::{
     network_model,
@@ -38,8 +45,15 @@ pub struct NetworkPair {
     pub virt_iface: NetworkInterface,
     pub model: Arc<dyn network_model::NetworkModel>,
     pub network_qos: bool,
+    /// number of queues negotiated for the tap, which is also the number of
+    /// virtio-net queue pairs in the guest
+    pub queues: usize,
+    /// the queues of the tap, kept open until the hypervisor takes them
+    pub tap_queues: Mutex<Vec<File>>,
 }
 impl NetworkPair {
+    /// new creates the tap of the pair, which is deleted if a later step
+    /// fails, the pair isn't left half built.
     pub(crate) async fn new(
         handle: &rtnetlink::Handle,
         idx: u32,
@@ -47,13 +61,44 @@ impl NetworkPair {
         model: &str,
         queues: usize,
     ) -> Result<Self> {
-        let unique_id = kata_sys_util::rand::UUID::new();
         let model = network_model::new(model).context("new network model")?;
         let tap_iface_name = format!("tap{}{}", idx, TAP_SUFFIX);
-        let virt_iface_name = format!("eth{}", idx);
-        let tap_link = create_link(handle, &tap_iface_name, queues)
+        let (tap_link, tap_queues) = create_link(handle, &tap_iface_name, queues)
             .await
             .context("create link")?;
+        info!(
+            sl!(),
+            "tap {} created with {} queues",
+            &tap_iface_name,
+            tap_queues.len()
+        );
+
+        match Self::with_tap(handle, idx, name, model, tap_link.as_ref(), tap_queues).await {
+            Ok(pair) => Ok(pair),
+            Err(e) => {
+                if let Err(err) = delete_tap(&tap_iface_name).await {
+                    warn!(
+                        sl!(),
+                        "couldn't delete tap {} of the pair: {:?}", tap_iface_name, err
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // with_tap builds the pair on the tap created
+    async fn with_tap(
+        handle: &rtnetlink::Handle,
+        idx: u32,
+        name: &str,
+        model: Arc<dyn network_model::NetworkModel>,
+        tap_link: &dyn link::Link,
+        tap_queues: Vec<File>,
+    ) -> Result<Self> {
+        let unique_id = kata_sys_util::rand::UUID::new();
+        let tap_iface_name = format!("tap{}{}", idx, TAP_SUFFIX);
+        let virt_iface_name = format!("eth{}", idx);
 
         let virt_link = get_link_by_name(handle, virt_iface_name.clone().as_str())
             .await
@@ -118,6 +163,8 @@ impl NetworkPair {
             },
             model,
             network_qos: false,
+            queues: tap_queues.len(),
+            tap_queues: Mutex::new(tap_queues),
         };
 
         if !name.is_empty() {
@@ -127,6 +174,24 @@ impl NetworkPair {
         Ok(net_pair)
     }
 
+    /// tap_fds returns the fds of the queues of the tap, the hypervisor
+    /// gets them with the device instead of opening the tap by its name.
+    pub(crate) fn tap_fds(&self) -> Vec<RawFd> {
+        self.tap_queues
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|f| f.as_raw_fd())
+            .collect()
+    }
+
+    /// release_tap_queues closes the queues of the tap once the device is
+    /// added, the hypervisor holds the queues it took by then and the ones
+    /// left open by the runtime would get a share of the traffic nobody reads.
+    pub(crate) fn release_tap_queues(&self) {
+        self.tap_queues.lock().unwrap().clear();
+    }
+
     pub(crate) async fn add_network_model(&self) -> Result<()> {
         let model = self.model.clone();
         model.add(self).await.context("add")?;
@@ -138,14 +203,64 @@ impl NetworkPair {
         model.del(self).await.context("del")?;
         Ok(())
     }
+
+    /// undo_network_model removes the tc rules added for the endpoint which
+    /// failed to be attached afterwards, the failure of the attach is the
+    /// one returned.
+    pub(crate) async fn undo_network_model(&self) {
+        if let Err(e) = self.del_network_model().await {
+            warn!(
+                sl!(),
+                "couldn't undo network model of {}: {:?}", self.virt_iface.name, e
+            );
+        }
+    }
+
+    /// delete removes the host artifacts of the pair in the current netns,
+    /// the tc rules and the tap, the ones gone already are skipped.
+    pub(crate) async fn delete(&self) -> Result<()> {
+        if let Err(e) = self.del_network_model().await {
+            warn!(
+                sl!(),
+                "couldn't del network model of {}: {:?}", self.virt_iface.name, e
+            );
+        }
+        delete_tap(&self.tap.tap_iface.name).await
+    }
+}
+
+/// delete_tap deletes the tap in the current netns with the tc rules on it,
+/// a missing tap is deleted already.
+pub(crate) async fn delete_tap(name: &str) -> Result<()> {
+    let (connection, handle, _) = rtnetlink::new_connection().context("new connection")?;
+    let thread_handler = tokio::spawn(connection);
+    defer!({
+        thread_handler.abort();
+    });
+
+    let link = match get_link_by_name(&handle, name).await {
+        Ok(link) => link,
+        Err(_) => {
+            info!(sl!(), "tap {} is gone already", name);
+            return Ok(());
+        }
+    };
+    handle
+        .link()
+        .del(link.attrs().index)
+        .execute()
+        .await
+        .with_context(|| format!("delete tap {}", name))?;
+    info!(sl!(), "tap {} deleted", name);
+    Ok(())
 }
 
 pub async fn create_link(
     handle: &rtnetlink::Handle,
     name: &str,
     queues: usize,
-) -> Result<Box<dyn link::Link>> {
-    link::create_link(name, link::LinkType::Tap, queues)?;
+) -> Result<(Box<dyn link::Link>, Vec<File>)> {
+    let queues = link::create_link(name, link::LinkType::Tap, queues)?;
 
     let link = get_link_by_name(handle, name)
         .await
@@ -161,7 +276,7 @@ pub async fn create_link(
             .await
             .context("set index")?;
     }
-    Ok(link)
+    Ok((link, queues))
 }
 
 pub async fn get_link_by_name(
@@ -180,8 +295,6 @@ pub async fn get_link_by_name(
 
 #[cfg(test)]
 mod tests {
-    use scopeguard::defer;
-
     use super::*;
     use crate::network::network_model::TC_FILTER_NET_MODEL_STR;
     use test_utils::skip_if_not_root;
@@ -258,4 +371,27 @@ mod tests {
             }
         }
     }
+
+    #[actix_rt::test]
+    async fn test_network_pair_undo() {
+        let idx = 123457;
+        let tap_name = format!("tap{}{}", idx, TAP_SUFFIX);
+
+        skip_if_not_root!();
+
+        let (conn, handle, _) = rtnetlink::new_connection().unwrap();
+        let thread_handler = tokio::spawn(conn);
+        defer!({
+            thread_handler.abort();
+        });
+
+        // the pair fails without its virt link, after the tap is created
+        assert!(
+            NetworkPair::new(&handle, idx, "", TC_FILTER_NET_MODEL_STR, 1)
+                .await
+                .is_err()
+        );
+        // the tap is deleted
+        assert!(get_link_by_name(&handle, tap_name.as_str()).await.is_err());
+    }
 }
@@ -5,7 +5,9 @@
 //
 
 use std::{
+    collections::BTreeSet,
     fs,
+    path::Path,
     sync::{
         atomic::{AtomicU32, Ordering},
         Arc,
@@ -20,22 +22,28 @@ use hypervisor::Hypervisor;
 use netns_rs::get_from_path;
 use scopeguard::defer;
 use tokio::sync::RwLock;
+use tracing::Instrument;
 
 use super::{
     endpoint::{
-        Endpoint, IPVlanEndpoint, MacVlanEndpoint, PhysicalEndpoint, VethEndpoint, VlanEndpoint,
+        self, Endpoint, EndpointType, IPVlanEndpoint, MacVlanEndpoint, MacVtapEndpoint,
+        PhysicalEndpoint, VethEndpoint, VlanEndpoint,
     },
     network_entity::NetworkEntity,
-    network_info::network_info_from_link::NetworkInfoFromLink,
+    network_info::network_info_from_link::{sort_routes, NetworkInfoFromLink},
+    network_pair,
     utils::{link, netns},
     Network,
 };
-use crate::network::NetworkInfo;
+use crate::{network::NetworkInfo, trace};
 
 #[derive(Debug)]
 pub struct NetworkWithNetNsConfig {
     pub network_model: String,
+    /// type of the endpoints, empty means inferring it from the links
+    pub endpoint_type: String,
     pub netns_path: String,
+    /// number of queue pairs of each interface, 0 means one per vCPU
     pub queues: usize,
     pub network_created: bool,
 }
@@ -79,13 +87,47 @@ impl NetworkWithNetns {
 
 #[async_trait]
 impl Network for NetworkWithNetns {
-    async fn setup(&self, h: &dyn Hypervisor) -> Result<()> {
-        let inner = self.inner.read().await;
+    async fn setup(&self, h: &dyn Hypervisor, cold_plug: bool) -> Result<()> {
+        let hotplug = h
+            .capabilities()
+            .await
+            .context("capabilities")?
+            .is_network_device_hotplug_supported();
+        let mut inner = self.inner.write().await;
+        let inner = &mut *inner;
+        let states: Vec<bool> = inner.entity_list.iter().map(|e| e.attached).collect();
+        let to_attach = endpoints_to_attach(&states, cold_plug, hotplug)?;
         let _netns_guard = netns::NetnsGuard::new(&inner.netns_path).context("net netns guard")?;
-        for e in &inner.entity_list {
-            e.endpoint.attach(h).await.context("attach")?;
+        // the endpoints attached by this call, detached again if a later one
+        // fails, the failed one undoes its own steps
+        let mut attached = vec![];
+        let mut result: Result<()> = Ok(());
+        for i in to_attach {
+            let e = &mut inner.entity_list[i];
+            let span = trace::span("endpoint_attach", "", "", &e.endpoint.name().await);
+            if let Err(err) = e.endpoint.attach(h).instrument(span).await {
+                result = Err(err).context("attach");
+                break;
+            }
+            e.attached = true;
+            e.cold_plugged = cold_plug;
+            attached.push(i);
         }
-        Ok(())
+        if result.is_err() {
+            for i in attached.into_iter().rev() {
+                let e = &mut inner.entity_list[i];
+                if let Err(err) = e.endpoint.detach(h, !cold_plug).await {
+                    warn!(
+                        sl!(),
+                        "couldn't detach endpoint {}: {:?}",
+                        e.endpoint.name().await,
+                        err
+                    );
+                }
+                e.attached = false;
+            }
+        }
+        result
     }
 
     async fn interfaces(&self) -> Result<Vec<agent::Interface>> {
@@ -104,6 +146,7 @@ impl Network for NetworkWithNetns {
             let mut list = e.network_info.routes().await.context("routes")?;
             routes.append(&mut list);
         }
+        sort_routes(&mut routes);
         Ok(routes)
     }
 
@@ -121,13 +164,36 @@ impl Network for NetworkWithNetns {
         let inner = self.inner.read().await;
         let mut endpoint = vec![];
         for e in &inner.entity_list {
-            if let Some(state) = e.endpoint.save().await {
+            if let Some(mut state) = e.endpoint.save().await {
+                state.cold_plugged = e.cold_plugged;
+                state.netns_path = inner.netns_path.clone();
                 endpoint.push(state);
             }
         }
         Some(endpoint)
     }
 
+    async fn detach(&self, h: &dyn Hypervisor) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        let inner = &mut *inner;
+        let _netns_guard = netns::NetnsGuard::new(&inner.netns_path).context("net netns guard")?;
+        // all of them are detached, the last failure is returned
+        let mut result = Ok(());
+        for e in inner.entity_list.iter_mut().filter(|e| e.attached) {
+            if let Err(err) = e.endpoint.detach(h, !e.cold_plugged).await {
+                warn!(
+                    sl!(),
+                    "couldn't detach endpoint {}: {:?}",
+                    e.endpoint.name().await,
+                    err
+                );
+                result = Err(err).context("detach");
+            }
+            e.attached = false;
+        }
+        result
+    }
+
     async fn remove(&self, h: &dyn Hypervisor) -> Result<()> {
         let inner = self.inner.read().await;
         // The network namespace would have been deleted at this point
@@ -138,8 +204,11 @@ impl Network for NetworkWithNetns {
         {
             let _netns_guard =
                 netns::NetnsGuard::new(&inner.netns_path).context("net netns guard")?;
-            for e in &inner.entity_list {
-                e.endpoint.detach(h).await.context("detach")?;
+            for e in inner.entity_list.iter().filter(|e| e.attached) {
+                e.endpoint
+                    .detach(h, !e.cold_plugged)
+                    .await
+                    .context("detach")?;
             }
         }
         let netns = get_from_path(inner.netns_path.clone())?;
@@ -147,6 +216,90 @@ impl Network for NetworkWithNetns {
         fs::remove_dir_all(inner.netns_path.clone()).context("failed to remove netns path")?;
         Ok(())
     }
+
+    async fn delete(&self) -> Result<()> {
+        let inner = self.inner.read().await;
+        // the artifacts are gone with the netns removed
+        if !is_netns_alive(&inner.netns_path) {
+            info!(
+                sl!(),
+                "netns {} is gone, nothing to delete", inner.netns_path
+            );
+            return Ok(());
+        }
+        let _netns_guard = netns::NetnsGuard::new(&inner.netns_path).context("net netns guard")?;
+        for e in inner.entity_list.iter() {
+            if let Err(e) = e.endpoint.delete().await {
+                warn!(sl!(), "couldn't delete endpoint: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn netns_path(&self) -> Option<String> {
+        let inner = self.inner.read().await;
+        Some(inner.netns_path.clone()).filter(|p| !p.is_empty())
+    }
+}
+
+// endpoints_to_attach returns the endpoints to attach by their index, given
+// the ones attached already: only the primary one is cold plugged, the
+// others are hotplugged once the VM is started, unless the hypervisor can't
+// hotplug them, then they're all cold plugged.
+fn endpoints_to_attach(attached: &[bool], cold_plug: bool, hotplug: bool) -> Result<Vec<usize>> {
+    let pending = attached
+        .iter()
+        .enumerate()
+        .filter(|(_, attached)| !**attached)
+        .map(|(i, _)| i);
+    if cold_plug {
+        return Ok(pending.filter(|i| !hotplug || *i == 0).collect());
+    }
+    let pending: Vec<usize> = pending.collect();
+    if !pending.is_empty() && !hotplug {
+        return Err(anyhow!(
+            "hypervisor can't hotplug the {} network interfaces left",
+            pending.len()
+        ));
+    }
+    Ok(pending)
+}
+
+fn is_netns_alive(netns_path: &str) -> bool {
+    netns_path.is_empty() || Path::new(netns_path).exists()
+}
+
+/// delete_endpoints removes the host artifacts of the endpoints restored
+/// from the saved state, the ones whose netns is gone are skipped.
+pub async fn delete_endpoints(endpoints: &[EndpointState]) -> Result<()> {
+    for ep in endpoints.iter() {
+        if let Some(physical) = ep.physical_endpoint.as_ref() {
+            let vendor_device_id = format!("{}_{}", physical.vendor_id, physical.device_id);
+            if let Err(e) = hypervisor::device::driver::bind_device_to_host(
+                &physical.bdf,
+                &physical.driver,
+                &vendor_device_id,
+            ) {
+                warn!(sl!(), "couldn't bind {} to host: {:?}", physical.bdf, e);
+            }
+            continue;
+        }
+        if ep.tap_name.is_empty() {
+            continue;
+        }
+        if !is_netns_alive(&ep.netns_path) {
+            info!(
+                sl!(),
+                "netns {} of tap {} is gone", ep.netns_path, ep.tap_name
+            );
+            continue;
+        }
+        let _netns_guard = netns::NetnsGuard::new(&ep.netns_path).context("net netns guard")?;
+        if let Err(e) = network_pair::delete_tap(&ep.tap_name).await {
+            warn!(sl!(), "couldn't delete tap {}: {:?}", ep.tap_name, e);
+        }
+    }
+    Ok(())
 }
 
 async fn get_entity_from_netns(config: &NetworkWithNetNsConfig) -> Result<Vec<NetworkEntity>> {
@@ -169,8 +322,18 @@ async fn get_entity_from_netns(config: &NetworkWithNetNsConfig) -> Result<Vec<Ne
     let mut links = handle.link().get().execute();
 
     let idx = AtomicU32::new(0);
-    while let Some(link) = links.try_next().await? {
-        let link = link::get_link_from_message(link);
+    // the route attributes the guest doesn't get, logged once for all links
+    let mut unsupported = BTreeSet::new();
+    let mut result: Result<()> = Ok(());
+    loop {
+        let link = match links.try_next().await {
+            Ok(Some(link)) => link::get_link_from_message(link),
+            Ok(None) => break,
+            Err(e) => {
+                result = Err(e.into());
+                break;
+            }
+        };
         let attrs = link.attrs();
 
         if (attrs.flags & libc::IFF_LOOPBACK as u32) != 0 {
@@ -178,11 +341,34 @@ async fn get_entity_from_netns(config: &NetworkWithNetNsConfig) -> Result<Vec<Ne
         }
 
         let idx = idx.fetch_add(1, Ordering::Relaxed);
-        let (endpoint, network_info) = create_endpoint(&handle, link.as_ref(), idx, config)
-            .await
-            .context("create endpoint")?;
+        match create_endpoint(&handle, link.as_ref(), idx, config, &mut unsupported).await {
+            Ok((endpoint, network_info)) => {
+                entity_list.push(NetworkEntity::new(endpoint, network_info))
+            }
+            Err(e) => {
+                result = Err(e).context("create endpoint");
+                break;
+            }
+        }
+    }
+    // the endpoints created before the failure are deleted, the failed one
+    // cleans up after itself
+    if let Err(e) = result {
+        for entity in entity_list.iter().rev() {
+            if let Err(err) = entity.endpoint.delete().await {
+                warn!(sl!(), "couldn't delete endpoint: {:?}", err);
+            }
+        }
+        return Err(e);
+    }
 
-        entity_list.push(NetworkEntity::new(endpoint, network_info));
+    if !unsupported.is_empty() {
+        warn!(
+            sl!(),
+            "the guest routes lack the unsupported attributes {:?} of the routes in netns {}",
+            unsupported,
+            config.netns_path
+        );
     }
 
     Ok(entity_list)
@@ -193,13 +379,60 @@ async fn create_endpoint(
     link: &dyn link::Link,
     idx: u32,
     config: &NetworkWithNetNsConfig,
+    unsupported: &mut BTreeSet<String>,
 ) -> Result<(Arc<dyn Endpoint>, Arc<dyn NetworkInfo>)> {
     let _netns_guard = netns::NetnsGuard::new(&config.netns_path)
         .context("net netns guard")
         .unwrap();
     let attrs = link.attrs();
     let link_type = link.r#type();
-    let endpoint: Arc<dyn Endpoint> = if is_physical_iface(&attrs.name)? {
+    let endpoint_type =
+        endpoint::endpoint_type(&config.endpoint_type).context("get endpoint type")?;
+    let endpoint: Arc<dyn Endpoint> = if let Some(endpoint_type) = endpoint_type {
+        info!(
+            sl!(),
+            "{} network interface found: {}, endpoint type {:?}",
+            &link_type,
+            &attrs.name,
+            endpoint_type
+        );
+        if is_physical_iface(&attrs.name)? {
+            return Err(anyhow!(
+                "physical interface {} can't be used as {:?} endpoint",
+                &attrs.name,
+                endpoint_type
+            ));
+        }
+        endpoint::check_endpoint_type(endpoint_type, link_type, attrs.parent_index)
+            .with_context(|| format!("interface {}", &attrs.name))?;
+        match endpoint_type {
+            EndpointType::Tap => Arc::new(
+                VethEndpoint::new(
+                    handle,
+                    &attrs.name,
+                    idx,
+                    &config.network_model,
+                    config.queues,
+                )
+                .await
+                .context("tap endpoint")?,
+            ),
+            EndpointType::Macvtap => Arc::new(
+                MacVtapEndpoint::new(
+                    &attrs.name,
+                    attrs.index,
+                    &attrs.hardware_addr,
+                    config.queues,
+                )
+                .context("macvtap endpoint")?,
+            ),
+            EndpointType::Ipvlan => Arc::new(
+                IPVlanEndpoint::new(handle, &attrs.name, idx, config.queues)
+                    .await
+                    .context("ipvlan endpoint")?,
+            ),
+        }
+    } else if is_physical_iface(&attrs.name)? {
         info!(
             sl!(),
             "physical network interface found: {} {:?}",
@@ -251,15 +484,38 @@ async fn create_endpoint(
                 .context("macvlan endpoint")?;
                 Arc::new(ret)
             }
+            "macvtap" => {
+                let ret = MacVtapEndpoint::new(
+                    &attrs.name,
+                    attrs.index,
+                    &attrs.hardware_addr,
+                    config.queues,
+                )
+                .context("macvtap endpoint")?;
+                Arc::new(ret)
+            }
             _ => return Err(anyhow!("unsupported link type: {}", link_type)),
         }
     };
 
-    let network_info = Arc::new(
-        NetworkInfoFromLink::new(handle, link, &endpoint.hardware_addr().await)
-            .await
-            .context("network info from link")?,
-    );
+    let network_info = match NetworkInfoFromLink::new(
+        handle,
+        link,
+        &endpoint.hardware_addr().await,
+        endpoint.queues().await,
+        unsupported,
+    )
+    .await
+    {
+        Ok(network_info) => Arc::new(network_info),
+        Err(e) => {
+            // the tap of the endpoint created is deleted
+            if let Err(err) = endpoint.delete().await {
+                warn!(sl!(), "couldn't delete endpoint: {:?}", err);
+            }
+            return Err(e).context("network info from link");
+        }
+    };
 
     info!(sl!(), "network info {:?}", network_info);
 
@@ -276,3 +532,56 @@ fn is_physical_iface(name: &str) -> Result<bool> {
     }
     Ok(true)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoints_to_attach() {
+        // the primary interface is cold plugged, the others hotplugged
+        assert_eq!(
+            endpoints_to_attach(&[false, false, false], true, true).unwrap(),
+            vec![0]
+        );
+        assert_eq!(
+            endpoints_to_attach(&[true, false, false], false, true).unwrap(),
+            vec![1, 2]
+        );
+        // all of them are cold plugged if they can't be hotplugged
+        assert_eq!(
+            endpoints_to_attach(&[false, false, false], true, false).unwrap(),
+            vec![0, 1, 2]
+        );
+        assert!(endpoints_to_attach(&[true, true], false, false)
+            .unwrap()
+            .is_empty());
+        // the sandbox started already can't get them
+        assert!(endpoints_to_attach(&[true, false], false, false).is_err());
+        assert!(endpoints_to_attach(&[false], false, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delete_endpoints_of_gone_netns() {
+        let dir = tempfile::tempdir().unwrap();
+        let netns_path = dir.path().join("netns").display().to_string();
+        assert!(is_netns_alive(""));
+        assert!(!is_netns_alive(&netns_path));
+
+        // the state saved before the tap was recorded has nothing to delete
+        let old: EndpointState =
+            serde_json::from_str(r#"{"veth_endpoint":{"if_name":"eth0","network_qos":false}}"#)
+                .unwrap();
+        assert!(old.tap_name.is_empty());
+
+        let endpoints = vec![
+            old,
+            EndpointState {
+                tap_name: "tap0_kata".to_string(),
+                netns_path,
+                ..Default::default()
+            },
+        ];
+        delete_endpoints(&endpoints).await.unwrap();
+    }
+}
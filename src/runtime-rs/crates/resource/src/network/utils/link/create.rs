@@ -84,7 +84,11 @@ pub enum LinkType {
     Tap,
 }
 
-pub fn create_link(name: &str, link_type: LinkType, queues: usize) -> Result<()> {
+/// create_link creates a persistent tun/tap link with the requested number of
+/// queues and returns the files of the queues actually created, which are
+/// kept for the hypervisor. If the kernel can't provide all of them, the link
+/// is created with fewer queues instead of failing.
+pub fn create_link(name: &str, link_type: LinkType, queues: usize) -> Result<Vec<File>> {
     let mut flags = libc::IFF_VNET_HDR;
     flags |= match link_type {
         LinkType::Tun => libc::IFF_TUN,
@@ -92,29 +96,54 @@ pub fn create_link(name: &str, link_type: LinkType, queues: usize) -> Result<()>
     };
 
     let queues = if queues == 0 { 1 } else { queues };
-    if queues > 1 {
-        flags |= libc::IFF_MULTI_QUEUE | libc::IFF_NO_PI;
-    } else {
-        flags |= libc::IFF_ONE_QUEUE;
-    };
+    let multi_queue_flags = flags | libc::IFF_MULTI_QUEUE | libc::IFF_NO_PI;
+    let single_queue_flags = flags | libc::IFF_ONE_QUEUE;
 
     // create first queue
     let mut files = vec![];
-    let (file, result_name) = create_queue(name, flags)?;
+    let (file, result_name, flags) = if queues > 1 {
+        match create_queue(name, multi_queue_flags) {
+            Ok((file, result_name)) => (file, result_name, multi_queue_flags),
+            Err(e) => {
+                warn!(
+                    sl!(),
+                    "failed to create multi queue link {}, fallback to single queue: {:?}", name, e
+                );
+                let (file, result_name) = create_queue(name, single_queue_flags)?;
+                (file, result_name, single_queue_flags)
+            }
+        }
+    } else {
+        let (file, result_name) = create_queue(name, single_queue_flags)?;
+        (file, result_name, single_queue_flags)
+    };
     unsafe {
         tun_set_persist(file.as_raw_fd(), &1).context("tun set persist")?;
     }
     files.push(file);
 
     // create other queues
-    if queues > 1 {
+    if flags == multi_queue_flags {
         for _ in 0..queues - 1 {
-            files.push(create_queue(&result_name, flags)?.0);
+            match create_queue(&result_name, flags) {
+                Ok((file, _)) => files.push(file),
+                Err(e) => {
+                    warn!(
+                        sl!(),
+                        "only {} of {} queues created for link {}: {:?}",
+                        files.len(),
+                        queues,
+                        result_name,
+                        e
+                    );
+                    break;
+                }
+            }
         }
     }
 
     info!(sl!(), "create link with fds {:?}", files);
-    Ok(())
+    Ok(files)
 }
 
 fn create_queue(name: &str, flags: libc::c_int) -> Result<(File, String)> {
@@ -175,7 +204,7 @@ mod tests {
             });
 
             assert!(create_link(name_tun, LinkType::Tun, 2).is_ok());
-            assert!(create_link(name_tap, LinkType::Tap, 2).is_ok());
+            assert_eq!(create_link(name_tap, LinkType::Tap, 2).unwrap().len(), 2);
             assert!(get_link_by_name(&handle, name_tap).await.is_ok());
             assert!(get_link_by_name(&handle, name_tun).await.is_ok());
             assert!(delete_link(&handle, name_tun).await.is_ok());
@@ -184,6 +213,11 @@ mod tests {
             // link does not present
             assert!(get_link_by_name(&handle, name_tun).await.is_err());
             assert!(get_link_by_name(&handle, name_tap).await.is_err());
+
+            // a single queue is created without the multi queue flag
+            let files = create_link(name_tap, LinkType::Tap, 0).unwrap();
+            assert_eq!(files.len(), 1);
+            assert!(delete_link(&handle, name_tap).await.is_ok());
         }
     }
 }
@@ -110,6 +110,11 @@ fn link_info(mut infos: Vec<Info>) -> Box<dyn Link> {
                         link = Some(Box::new(MacVlan::default()));
                     }
                 }
+                InfoKind::MacVtap => {
+                    if link.is_none() {
+                        link = Some(Box::new(MacVtap::default()));
+                    }
+                }
                 InfoKind::Vlan => {
                     if link.is_none() {
                         link = Some(Box::new(Vlan::default()));
@@ -139,6 +144,9 @@ fn link_info(mut infos: Vec<Info>) -> Box<dyn Link> {
                 InfoData::MacVlan(_) => {
                     link = Some(Box::new(MacVlan::default()));
                 }
+                InfoData::MacVtap(_) => {
+                    link = Some(Box::new(MacVtap::default()));
+                }
                 InfoData::Vlan(_) => {
                     link = Some(Box::new(Vlan::default()));
                 }
@@ -216,6 +224,7 @@ define_and_impl_network_dev!("tuntap", Tuntap);
 define_and_impl_network_dev!("veth", Veth);
 define_and_impl_network_dev!("ipvlan", IpVlan);
 define_and_impl_network_dev!("macvlan", MacVlan);
+define_and_impl_network_dev!("macvtap", MacVtap);
 define_and_impl_network_dev!("vlan", Vlan);
 
 #[derive(Debug, PartialEq, Eq, Clone, Default)]
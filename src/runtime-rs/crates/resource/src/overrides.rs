@@ -0,0 +1,219 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    collections::HashMap,
+    path::{Component, Path},
+};
+
+use anyhow::{anyhow, Context, Result};
+use byte_unit::{Byte, ByteUnit};
+use kata_types::{
+    annotations::*,
+    config::{default::DEFAULT_HYPERVISOR, Hypervisor, TomlConfig},
+};
+
+/// validate_overrides checks the hypervisor settings the annotations of the
+/// pod override, before they're merged into the node configuration: each
+/// value against the allowlist and the limits of the node, so that a pod
+/// can't get more than the node allows, e.g. by raising a limit as well.
+pub fn validate_overrides(
+    config: &TomlConfig,
+    annotations: &HashMap<String, String>,
+) -> Result<()> {
+    // the hypervisor the annotations pick, as they're merged
+    let hv_name = match annotations.get(KATA_ANNO_CFG_RUNTIME_HYPERVISOR) {
+        Some(name) if config.hypervisor.contains_key(name) => name.as_str(),
+        _ if config.runtime.hypervisor_name.is_empty() => DEFAULT_HYPERVISOR,
+        _ => config.runtime.hypervisor_name.as_str(),
+    };
+    let overrides: Vec<_> = annotations
+        .iter()
+        .filter(|(key, _)| key.starts_with(KATA_ANNO_CFG_HYPERVISOR_PREFIX))
+        .collect();
+    if overrides.is_empty() {
+        return Ok(());
+    }
+    let hv = config
+        .hypervisor
+        .get(hv_name)
+        .ok_or_else(|| anyhow!("no hypervisor {}", hv_name))?;
+
+    for (key, value) in overrides {
+        validate_override(hv, key, value.trim()).with_context(|| format!("annotation {}", key))?;
+    }
+    Ok(())
+}
+
+fn validate_override(hv: &Hypervisor, key: &str, value: &str) -> Result<()> {
+    if !hv.security_info.is_annotation_enabled(key) {
+        return Err(anyhow!("not allowed by enable_annotations"));
+    }
+
+    match key {
+        KATA_ANNO_CFG_HYPERVISOR_DEFAULT_VCPUS => {
+            let vcpus: i32 = value
+                .parse()
+                .with_context(|| format!("invalid vcpus {}", value))?;
+            let max = hv.cpu_info.default_maxvcpus;
+            if vcpus < 0 || vcpus as u32 > max {
+                return Err(anyhow!(
+                    "vcpus {} out of range, default_maxvcpus is {}",
+                    vcpus,
+                    max
+                ));
+            }
+        }
+        KATA_ANNO_CFG_HYPERVISOR_DEFAULT_MAX_VCPUS => {
+            let max_vcpus: u32 = value
+                .parse()
+                .with_context(|| format!("invalid max vcpus {}", value))?;
+            let max = hv.cpu_info.default_maxvcpus;
+            if max != 0 && max_vcpus > max {
+                return Err(anyhow!(
+                    "max vcpus {} out of range, default_maxvcpus is {}",
+                    max_vcpus,
+                    max
+                ));
+            }
+        }
+        KATA_ANNO_CFG_HYPERVISOR_DEFAULT_MEMORY => {
+            // in MiB, the way the annotation is merged
+            let memory = Byte::from_str(value)
+                .map_err(|e| anyhow!("invalid memory {}: {}", value, e))?
+                .get_adjusted_unit(ByteUnit::MiB)
+                .get_value() as u32;
+            let max = hv.memory_info.default_maxmemory;
+            if max != 0 && memory > max {
+                return Err(anyhow!(
+                    "memory {} MiB out of range, default_maxmemory is {} MiB",
+                    memory,
+                    max
+                ));
+            }
+        }
+        KATA_ANNO_CFG_HYPERVISOR_PATH => validate_override_path(value, &hv.valid_hypervisor_paths)?,
+        KATA_ANNO_CFG_HYPERVISOR_CTLPATH => validate_override_path(value, &hv.valid_ctlpaths)?,
+        KATA_ANNO_CFG_HYPERVISOR_JAILER_PATH => {
+            validate_override_path(value, &hv.valid_jailer_paths)?
+        }
+        KATA_ANNO_CFG_HYPERVISOR_VHOSTUSER_STORE_PATH => {
+            validate_override_path(value, &hv.blockdev_info.valid_vhost_user_store_paths)?
+        }
+        KATA_ANNO_CFG_HYPERVISOR_ENTROPY_SOURCE => {
+            validate_override_path(value, &hv.machine_info.valid_entropy_sources)?
+        }
+        KATA_ANNO_CFG_HYPERVISOR_FILE_BACKED_MEM_ROOT_DIR => {
+            validate_override_path(value, &hv.memory_info.valid_file_mem_backends)?
+        }
+        KATA_ANNO_CFG_HYPERVISOR_VIRTIO_FS_DAEMON => {
+            validate_override_path(value, &hv.shared_fs.valid_virtio_fs_daemon_paths)?
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// the glob patterns match the ".." components too, e.g. "/usr/bin/*"
+// matches "/usr/bin/../../tmp/qemu", only the plain absolute paths are taken
+fn validate_override_path(path: &str, patterns: &[String]) -> Result<()> {
+    let p = Path::new(path);
+    if !p.is_absolute()
+        || p.components()
+            .any(|c| matches!(c, Component::ParentDir | Component::CurDir))
+    {
+        return Err(anyhow!("path {} isn't a plain absolute path", path));
+    }
+    kata_types::config::validate_path_pattern(patterns, p)
+        .map_err(|_| anyhow!("path {} is outside of {:?}", path, patterns))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_config() -> TomlConfig {
+        let mut hv = Hypervisor::default();
+        hv.security_info.enable_annotations = vec![
+            "default_vcpus".to_string(),
+            "default_max_vcpus".to_string(),
+            "path".to_string(),
+        ];
+        hv.cpu_info.default_vcpus = 2;
+        hv.cpu_info.default_maxvcpus = 4;
+        hv.memory_info.default_memory = 1024;
+        hv.memory_info.default_maxmemory = 2048;
+        hv.valid_hypervisor_paths = vec!["/usr/bin/*".to_string()];
+
+        let mut config = TomlConfig::default();
+        config.runtime.hypervisor_name = "dragonball".to_string();
+        config.hypervisor.insert("dragonball".to_string(), hv);
+        config
+    }
+
+    fn annotations(annotations: &[(&str, &str)]) -> HashMap<String, String> {
+        annotations
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_overrides() {
+        let config = new_config();
+
+        // allowed
+        let allowed = annotations(&[
+            (KATA_ANNO_CFG_HYPERVISOR_DEFAULT_VCPUS, "2"),
+            (KATA_ANNO_CFG_HYPERVISOR_PATH, "/usr/bin/qemu"),
+            (KATA_ANNO_CFG_RUNTIME_NAME, "virt-container"),
+        ]);
+        validate_overrides(&config, &allowed).unwrap();
+
+        // disallowed
+        let disallowed = annotations(&[(KATA_ANNO_CFG_HYPERVISOR_DEFAULT_MEMORY, "1Gi")]);
+        let err = validate_overrides(&config, &disallowed).unwrap_err();
+        assert!(format!("{:#}", err).contains(KATA_ANNO_CFG_HYPERVISOR_DEFAULT_MEMORY));
+
+        // out of the range of the node, even with the limit raised along
+        for vcpus in [
+            annotations(&[(KATA_ANNO_CFG_HYPERVISOR_DEFAULT_VCPUS, "8")]),
+            annotations(&[
+                (KATA_ANNO_CFG_HYPERVISOR_DEFAULT_VCPUS, "8"),
+                (KATA_ANNO_CFG_HYPERVISOR_DEFAULT_MAX_VCPUS, "8"),
+            ]),
+        ] {
+            let err = validate_overrides(&config, &vcpus).unwrap_err();
+            assert!(format!("{:#}", err).contains("out of range"));
+        }
+        let invalid = annotations(&[(KATA_ANNO_CFG_HYPERVISOR_DEFAULT_VCPUS, "two")]);
+        assert!(validate_overrides(&config, &invalid).is_err());
+
+        // outside of the configured prefixes
+        for path in ["/tmp/qemu", "/usr/bin/../../tmp/qemu", "qemu"] {
+            let path = annotations(&[(KATA_ANNO_CFG_HYPERVISOR_PATH, path)]);
+            assert!(validate_overrides(&config, &path).is_err());
+        }
+    }
+
+    #[test]
+    fn test_validate_memory_override() {
+        let mut config = new_config();
+        config
+            .hypervisor
+            .get_mut("dragonball")
+            .unwrap()
+            .security_info
+            .enable_annotations
+            .push("default_memory".to_string());
+
+        let memory = annotations(&[(KATA_ANNO_CFG_HYPERVISOR_DEFAULT_MEMORY, "2Gi")]);
+        validate_overrides(&config, &memory).unwrap();
+        let memory = annotations(&[(KATA_ANNO_CFG_HYPERVISOR_DEFAULT_MEMORY, "4Gi")]);
+        let err = validate_overrides(&config, &memory).unwrap_err();
+        assert!(format!("{:#}", err).contains("4096 MiB out of range"));
+    }
+}
@@ -0,0 +1,322 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use kata_types::config::Runtime;
+
+// the symlinks followed before giving up, as the kernel does
+const MAX_SYMLINKS: usize = 40;
+
+/// PathViolation is the mount source whose path leaves the allowed host
+/// prefixes once resolved, by a symlink or "..", or isn't under any of them.
+#[derive(Debug)]
+pub struct PathViolation {
+    pub source: String,
+    /// where the resolution left the prefixes
+    pub resolved: PathBuf,
+    pub reason: String,
+}
+
+impl fmt::Display for PathViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "mount source {} resolves to {}: {}",
+            self.source,
+            self.resolved.display(),
+            self.reason
+        )
+    }
+}
+
+impl std::error::Error for PathViolation {}
+
+/// is_path_violation tells if the error is of a mount source out of the
+/// allowed prefixes.
+pub fn is_path_violation(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| e.is::<PathViolation>())
+}
+
+/// PathJail keeps the mount sources of the sandbox under the host prefixes
+/// allowed by mount_source_prefixes, nothing is checked without them.
+#[derive(Debug, Default)]
+pub(crate) struct PathJail {
+    roots: Vec<PathBuf>,
+    disabled: bool,
+}
+
+impl PathJail {
+    pub(crate) fn new(runtime: &Runtime) -> Self {
+        Self {
+            roots: runtime
+                .mount_source_prefixes
+                .iter()
+                .map(PathBuf::from)
+                .collect(),
+            disabled: runtime.disable_mount_source_jail || runtime.mount_source_prefixes.is_empty(),
+        }
+    }
+
+    /// safe_resolve resolves the symlinks of the mount source beneath the
+    /// allowed prefix it's under, it fails with a PathViolation if neither a
+    /// symlink nor ".." stays beneath it. The parts of the path missing on
+    /// the host are taken as they are.
+    pub(crate) fn safe_resolve(&self, source: &str) -> Result<PathBuf> {
+        if self.disabled {
+            return Ok(PathBuf::from(source));
+        }
+        let path = Path::new(source);
+        if !path.is_absolute() {
+            return Err(anyhow!("mount source {} isn't an absolute path", source));
+        }
+
+        let mut violation = None;
+        for root in self.roots.iter() {
+            let rest = match path.strip_prefix(root) {
+                Ok(rest) => rest,
+                Err(_) => continue,
+            };
+            match resolve_beneath(root, rest) {
+                Ok(Resolution::Beneath(resolved)) => return Ok(resolved),
+                Ok(Resolution::Escaped(resolved, reason)) => {
+                    violation.get_or_insert(PathViolation {
+                        source: source.to_string(),
+                        resolved,
+                        reason,
+                    });
+                }
+                Err(e) => return Err(e).with_context(|| format!("resolve {}", source)),
+            }
+        }
+        let violation = violation.unwrap_or_else(|| PathViolation {
+            source: source.to_string(),
+            resolved: fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()),
+            reason: format!("not under the allowed prefixes {:?}", self.roots),
+        });
+        Err(violation.into())
+    }
+
+    /// resolve_source is safe_resolve for the mount sources of the spec, the
+    /// path returned is the one to mount instead of the source, so that the
+    /// symlinks changed after the check aren't followed.
+    pub(crate) fn resolve_source(&self, source: &str) -> Result<String> {
+        self.safe_resolve(source)?
+            .into_os_string()
+            .into_string()
+            .map_err(|p| anyhow!("mount source {} resolves to invalid path {:?}", source, p))
+    }
+}
+
+// Resolution is where the path resolved beneath the root is, or where and why
+// it left the root
+enum Resolution {
+    Beneath(PathBuf),
+    Escaped(PathBuf, String),
+}
+
+// resolve_beneath walks the path from the root following its symlinks
+fn resolve_beneath(root: &Path, path: &Path) -> Result<Resolution> {
+    let mut current = root.to_path_buf();
+    let mut rest: VecDeque<OsString> = components(path);
+    let mut symlinks = 0;
+    // the path below is missing, there's no symlink to follow
+    let mut missing = false;
+
+    while let Some(name) = rest.pop_front() {
+        if name == ".." {
+            if current == root {
+                let escaped = root.parent().unwrap_or(root).join(lexical(&rest));
+                let reason = format!("\"..\" leaves {}", root.display());
+                return Ok(Resolution::Escaped(escaped, reason));
+            }
+            current.pop();
+            continue;
+        }
+
+        let next = current.join(&name);
+        if missing {
+            current = next;
+            continue;
+        }
+        let metadata = match fs::symlink_metadata(&next) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                missing = true;
+                current = next;
+                continue;
+            }
+            Err(e) => return Err(e).with_context(|| format!("stat {:?}", next)),
+        };
+        if !metadata.file_type().is_symlink() {
+            current = next;
+            continue;
+        }
+
+        symlinks += 1;
+        if symlinks > MAX_SYMLINKS {
+            let reason = "too many levels of symbolic links".to_string();
+            return Ok(Resolution::Escaped(next, reason));
+        }
+        let target = fs::read_link(&next).with_context(|| format!("read link {:?}", next))?;
+        let mut followed = if target.is_absolute() {
+            match target.strip_prefix(root) {
+                Ok(inner) => {
+                    current = root.to_path_buf();
+                    components(inner)
+                }
+                Err(_) => {
+                    let reason = format!("symlink {} leaves {}", next.display(), root.display());
+                    return Ok(Resolution::Escaped(target.clone(), reason));
+                }
+            }
+        } else {
+            components(&target)
+        };
+        followed.extend(rest);
+        rest = followed;
+    }
+    Ok(Resolution::Beneath(current))
+}
+
+// components returns the names of the path to walk, "." is dropped
+fn components(path: &Path) -> VecDeque<OsString> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+// lexical joins the names left to walk for the message
+fn lexical(rest: &VecDeque<OsString>) -> PathBuf {
+    rest.iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    fn new_jail(roots: &[&Path]) -> PathJail {
+        PathJail {
+            roots: roots.iter().map(|r| r.to_path_buf()).collect(),
+            disabled: false,
+        }
+    }
+
+    fn violation(e: anyhow::Error) -> PathViolation {
+        assert!(is_path_violation(&e), "{:?}", e);
+        e.downcast::<PathViolation>().unwrap()
+    }
+
+    #[test]
+    fn test_safe_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("kubelet");
+        let pods = root.join("pods");
+        fs::create_dir_all(pods.join("a/volumes/data")).unwrap();
+        fs::create_dir_all(dir.path().join("etc")).unwrap();
+        let jail = new_jail(&[&root]);
+        let source = |p: &Path| p.to_str().unwrap().to_string();
+
+        let data = pods.join("a/volumes/data");
+        assert_eq!(jail.safe_resolve(&source(&data)).unwrap(), data);
+        // missing on the host, taken as is
+        let missing = pods.join("b/volumes");
+        assert_eq!(jail.safe_resolve(&source(&missing)).unwrap(), missing);
+
+        // a chain of symlinks staying beneath
+        symlink("../volumes/data", pods.join("a/volumes/link1")).unwrap();
+        symlink(pods.join("a/volumes/link1"), pods.join("a/link2")).unwrap();
+        assert_eq!(
+            jail.safe_resolve(&source(&pods.join("a/link2"))).unwrap(),
+            data
+        );
+
+        // a chain of symlinks leaving the root at its end
+        symlink(dir.path().join("etc"), pods.join("a/escape")).unwrap();
+        symlink("escape", pods.join("a/link3")).unwrap();
+        let v = violation(
+            jail.safe_resolve(&source(&pods.join("a/link3")))
+                .unwrap_err(),
+        );
+        assert_eq!(v.resolved, dir.path().join("etc"));
+
+        // a relative symlink climbing out
+        symlink("../../../etc", pods.join("a/up")).unwrap();
+        let v = violation(jail.safe_resolve(&source(&pods.join("a/up"))).unwrap_err());
+        assert!(v.reason.contains("\"..\" leaves"), "{}", v);
+        assert_eq!(v.resolved, dir.path().join("etc"));
+
+        // ".." in the source itself
+        let traversal = format!("{}/pods/../../etc", root.display());
+        let v = violation(jail.safe_resolve(&traversal).unwrap_err());
+        assert_eq!(v.resolved, dir.path().join("etc"));
+        let inner = format!("{}/a/../a/volumes/data", pods.display());
+        assert_eq!(jail.safe_resolve(&inner).unwrap(), data);
+
+        // not under any prefix
+        let v = violation(
+            jail.safe_resolve(&source(&dir.path().join("etc")))
+                .unwrap_err(),
+        );
+        assert!(v.reason.contains("not under the allowed prefixes"));
+        assert!(v.to_string().contains(&source(&dir.path().join("etc"))));
+        // compared by components, kubelet-other isn't under kubelet
+        let other = format!("{}-other/x", root.display());
+        assert!(is_path_violation(&jail.safe_resolve(&other).unwrap_err()));
+
+        // a loop
+        symlink("loop2", pods.join("loop1")).unwrap();
+        symlink("loop1", pods.join("loop2")).unwrap();
+        let v = violation(jail.safe_resolve(&source(&pods.join("loop1"))).unwrap_err());
+        assert!(v.reason.contains("too many levels"));
+
+        // the escape hatch
+        let jail = PathJail {
+            disabled: true,
+            ..new_jail(&[&root])
+        };
+        assert!(jail.safe_resolve(&source(&pods.join("a/up"))).is_ok());
+    }
+
+    #[test]
+    fn test_resolve_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("kubelet");
+        let data = root.join("pods/a/volumes/data");
+        fs::create_dir_all(&data).unwrap();
+        symlink(&data, root.join("pods/a/link")).unwrap();
+        let link = root.join("pods/a/link");
+
+        // the path mounted is the one resolved, not the symlink
+        let jail = new_jail(&[&root]);
+        assert_eq!(
+            jail.resolve_source(link.to_str().unwrap()).unwrap(),
+            data.to_str().unwrap()
+        );
+
+        // opt-in, nothing is checked without prefixes
+        let mut runtime = Runtime::default();
+        let jail = PathJail::new(&runtime);
+        assert_eq!(jail.resolve_source("/etc/passwd").unwrap(), "/etc/passwd");
+        runtime.mount_source_prefixes = vec![root.to_str().unwrap().to_string()];
+        let jail = PathJail::new(&runtime);
+        assert!(is_path_violation(
+            &jail.resolve_source("/etc/passwd").unwrap_err()
+        ));
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use anyhow::Result;
+use kata_types::mount::Mount;
+use serde::{Deserialize, Serialize};
+
+pub const KIND_ROOTFS: &str = "rootfs";
+pub const KIND_DEVICE: &str = "device";
+pub const KIND_HUGEPAGES: &str = "hugepages";
+pub const KIND_VF: &str = "vf";
+pub const KIND_VOLUME: &str = "volume";
+
+/// PlanRequest is the container whose resources are planned: its spec, and
+/// the mounts of its rootfs as passed to the shim.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PlanRequest {
+    pub spec: oci::Spec,
+    #[serde(default)]
+    pub rootfs_mounts: Vec<Mount>,
+}
+
+/// PlannedResource is a resource of the container and the backend it would
+/// be set up with, or why it couldn't be.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PlannedResource {
+    pub kind: String,
+    /// the source on the host, e.g. the device or the volume path
+    pub source: String,
+    /// where it shows up in the container, empty for the rootfs
+    pub destination: String,
+    /// the backend it would be set up with, e.g. "block", empty if unknown
+    pub backend: String,
+    /// the hard failure, the container can't be created if there's one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl PlannedResource {
+    pub(crate) fn new(kind: &str, source: &str, destination: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            source: source.to_string(),
+            destination: destination.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// with_result sets the backend or the failure of the resource.
+    pub(crate) fn with_result(mut self, backend: Result<&str>) -> Self {
+        match backend {
+            Ok(backend) => self.backend = backend.to_string(),
+            Err(e) => self.error = Some(format!("{:#}", e)),
+        }
+        self
+    }
+}
+
+/// PlanReport is what the resources of a container would be set up with,
+/// found without setting up any of them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PlanReport {
+    pub resources: Vec<PlannedResource>,
+    /// the failures of the container rather than of one of its resources,
+    /// e.g. over a limit of the sandbox
+    pub errors: Vec<String>,
+}
+
+impl PlanReport {
+    pub(crate) fn push(&mut self, resource: PlannedResource) {
+        self.resources.push(resource);
+    }
+
+    pub(crate) fn fail(&mut self, e: anyhow::Error) {
+        self.errors.push(format!("{:#}", e));
+    }
+
+    /// is_admissible tells if the container could be created, there's no
+    /// hard failure.
+    pub fn is_admissible(&self) -> bool {
+        self.errors.is_empty() && self.resources.iter().all(|r| r.error.is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_plan_report() {
+        let mut report = PlanReport::default();
+        report
+            .push(PlannedResource::new(KIND_VOLUME, "/data", "/data").with_result(Ok("share_fs")));
+        assert!(report.is_admissible());
+
+        report.push(
+            PlannedResource::new(KIND_DEVICE, "/dev/sdb", "/dev/sdb")
+                .with_result(Err(anyhow!("no such device"))),
+        );
+        assert!(!report.is_admissible());
+        assert_eq!(report.resources[1].backend, "");
+        assert_eq!(report.resources[1].error.as_deref(), Some("no such device"));
+
+        let json = serde_json::to_string(&report.resources[0]).unwrap();
+        assert!(!json.contains("error"));
+
+        let mut report = PlanReport::default();
+        report.fail(anyhow!("over max_devices_per_sandbox"));
+        assert!(!report.is_admissible());
+    }
+}
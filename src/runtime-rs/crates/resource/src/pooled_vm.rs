@@ -0,0 +1,249 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::fmt;
+
+use anyhow::{anyhow, Context, Result};
+use hypervisor::Hypervisor;
+use kata_types::config::{hypervisor::SHARED_FS_AUTO, TomlConfig};
+
+use crate::ResourceConfig;
+
+/// VmState is how the VM of the sandbox is set up when its resources are
+/// prepared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum VmState {
+    /// the VM boots once the resources are prepared, the devices are
+    /// configured before it starts
+    ColdBoot,
+    /// the VM is running already, adopted from a pool of VMs started ahead:
+    /// the devices are hotplugged
+    Pooled,
+}
+
+/// PooledVmRejected is the error the pooled VM is rejected with if it
+/// doesn't match the requirements of the sandbox, nothing is set up then so
+/// that the caller could cold boot a VM instead.
+#[derive(Debug)]
+pub struct PooledVmRejected(pub String);
+
+impl fmt::Display for PooledVmRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pooled vm rejected: {}", self.0)
+    }
+}
+
+impl std::error::Error for PooledVmRejected {}
+
+/// is_pooled_vm_rejected tells if the resources weren't prepared because
+/// the pooled VM doesn't match the sandbox.
+pub fn is_pooled_vm_rejected(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| e.is::<PooledVmRejected>())
+}
+
+// what the sandbox needs from the pooled VM
+#[derive(Debug, PartialEq, Eq)]
+struct Requirements {
+    vcpus: u32,
+    mem_mb: u32,
+    share_fs: bool,
+    network: bool,
+    protected: bool,
+}
+
+impl Requirements {
+    // the sizing of the VM the sandbox would cold boot, with the workload
+    // of the static resource management
+    fn new(toml_config: &TomlConfig, device_configs: &[ResourceConfig]) -> Result<Self> {
+        let name = &toml_config.runtime.hypervisor_name;
+        let mut config = toml_config
+            .hypervisor
+            .get(name)
+            .ok_or_else(|| anyhow!("hypervisor {} isn't configured", name))?
+            .clone();
+        let mut share_fs = false;
+        let mut network = false;
+        for c in device_configs {
+            match c {
+                ResourceConfig::InitialSize(initial_size) => initial_size
+                    .setup_config(&mut config)
+                    .context("setup initial size")?,
+                // auto shares nothing if the hypervisor can't
+                ResourceConfig::ShareFs(c) => {
+                    share_fs = matches!(c.shared_fs.as_deref(), Some(fs) if fs != SHARED_FS_AUTO)
+                }
+                ResourceConfig::Network(_) => network = true,
+                ResourceConfig::Hostname(_) | ResourceConfig::NetSysctls(_) => {}
+            }
+        }
+        Ok(Self {
+            vcpus: config.cpu_info.default_vcpus.max(0) as u32,
+            mem_mb: config.memory_info.default_memory,
+            share_fs,
+            network,
+            protected: config.security_info.confidential_guest,
+        })
+    }
+}
+
+/// vm_state tells if the VM of the hypervisor is cold booted or adopted from
+/// a pool, the pooled VM is rejected if its capabilities or its sizing don't
+/// match the requirements of the sandbox.
+pub(crate) async fn vm_state(
+    h: &dyn Hypervisor,
+    toml_config: &TomlConfig,
+    device_configs: &[ResourceConfig],
+) -> Result<VmState> {
+    if !h.is_pooled().await {
+        return Ok(VmState::ColdBoot);
+    }
+
+    let required =
+        Requirements::new(toml_config, device_configs).context("requirements of sandbox")?;
+    let reject = |reason: String| Err(anyhow!(PooledVmRejected(reason)));
+    let capabilities = h.capabilities().await?;
+    if required.share_fs && !capabilities.is_fs_sharing_supported() {
+        return reject("filesystem sharing isn't supported".to_string());
+    }
+    // all the interfaces are hotplugged into the VM running already
+    if required.network && !capabilities.is_network_device_hotplug_supported() {
+        return reject("network device hotplug isn't supported".to_string());
+    }
+    // nothing is shared with a protected guest, it can't stand in for the
+    // other one either way
+    let protected = h.guest_protection().await?.is_protected();
+    if protected != required.protected {
+        return reject(format!(
+            "guest protection {}, {} required",
+            protected, required.protected
+        ));
+    }
+
+    let config = h.hypervisor_config().await;
+    let vcpus = config.cpu_info.default_vcpus.max(0) as u32;
+    if vcpus < required.vcpus {
+        return reject(format!("{} vcpus, {} required", vcpus, required.vcpus));
+    }
+    let mem_mb = config.memory_info.default_memory;
+    if mem_mb < required.mem_mb {
+        return reject(format!(
+            "{} MiB memory, {} MiB required",
+            mem_mb, required.mem_mb
+        ));
+    }
+    Ok(VmState::Pooled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{NetworkConfig, NetworkWithNetNsConfig};
+    use hypervisor::mock::MockHypervisor;
+    use kata_types::{
+        capabilities::CapabilityBits,
+        config::hypervisor::{Hypervisor as HypervisorConfig, SharedFsInfo},
+    };
+
+    fn hypervisor_config(vcpus: i32, mem_mb: u32) -> HypervisorConfig {
+        let mut config = HypervisorConfig::default();
+        config.cpu_info.default_vcpus = vcpus;
+        config.memory_info.default_memory = mem_mb;
+        config
+    }
+
+    fn mock(pooled: bool, flags: CapabilityBits, vcpus: i32, mem_mb: u32) -> MockHypervisor {
+        let h = MockHypervisor::new()
+            .with_capabilities(flags)
+            .with_config(hypervisor_config(vcpus, mem_mb));
+        if pooled {
+            h.pooled()
+        } else {
+            h
+        }
+    }
+
+    #[tokio::test]
+    async fn test_vm_state() {
+        let mut toml_config = TomlConfig::default();
+        toml_config.runtime.hypervisor_name = "dragonball".to_string();
+        toml_config
+            .hypervisor
+            .insert("dragonball".to_string(), hypervisor_config(2, 2048));
+        let configs = vec![ResourceConfig::ShareFs(SharedFsInfo {
+            shared_fs: Some("virtio-fs".to_string()),
+            ..Default::default()
+        })];
+        let all = CapabilityBits::BlockDeviceHotplugSupport | CapabilityBits::FsSharingSupport;
+
+        // nothing is checked for the VM to cold boot
+        let h = mock(false, CapabilityBits::BlockDeviceSupport, 1, 512);
+        assert_eq!(
+            vm_state(&h, &toml_config, &configs).await.unwrap(),
+            VmState::ColdBoot
+        );
+
+        // the pooled VM matching the sandbox, or larger, is adopted
+        for (vcpus, mem_mb) in [(2, 2048), (4, 4096)] {
+            let h = mock(true, all, vcpus, mem_mb);
+            assert_eq!(
+                vm_state(&h, &toml_config, &configs).await.unwrap(),
+                VmState::Pooled
+            );
+        }
+
+        // the pooled VM too small or without filesystem sharing is rejected
+        for (flags, vcpus, mem_mb, reason) in [
+            (all, 1, 2048, "1 vcpus"),
+            (all, 2, 1024, "1024 MiB"),
+            (
+                CapabilityBits::BlockDeviceHotplugSupport,
+                2,
+                2048,
+                "sharing",
+            ),
+        ] {
+            let h = mock(true, flags, vcpus, mem_mb);
+            let e = vm_state(&h, &toml_config, &configs).await.unwrap_err();
+            assert!(is_pooled_vm_rejected(&e), "{:?}", e);
+            assert!(e.to_string().contains(reason), "{}", e);
+        }
+
+        // no filesystem sharing is needed with auto
+        let configs = vec![ResourceConfig::ShareFs(SharedFsInfo {
+            shared_fs: Some(SHARED_FS_AUTO.to_string()),
+            ..Default::default()
+        })];
+        let h = mock(true, CapabilityBits::BlockDeviceHotplugSupport, 2, 2048);
+        assert_eq!(
+            vm_state(&h, &toml_config, &configs).await.unwrap(),
+            VmState::Pooled
+        );
+
+        // the interfaces of the sandbox are hotplugged
+        let configs = vec![ResourceConfig::Network(
+            NetworkConfig::NetworkResourceWithNetNs(NetworkWithNetNsConfig {
+                network_model: "tcfilter".to_string(),
+                endpoint_type: String::new(),
+                netns_path: "/var/run/netns/cni-a".to_string(),
+                queues: 0,
+                network_created: false,
+            }),
+        )];
+        let h = mock(true, all, 2, 2048);
+        let e = vm_state(&h, &toml_config, &configs).await.unwrap_err();
+        assert!(e.to_string().contains("network device hotplug"), "{}", e);
+        let h = mock(
+            true,
+            all | CapabilityBits::NetworkDeviceHotplugSupport,
+            2,
+            2048,
+        );
+        assert_eq!(
+            vm_state(&h, &toml_config, &configs).await.unwrap(),
+            VmState::Pooled
+        );
+    }
+}
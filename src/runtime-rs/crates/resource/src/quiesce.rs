@@ -0,0 +1,133 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use anyhow::Result;
+use tokio::sync::Notify;
+
+/// Quiesced is the error of the resource mutations refused while the
+/// resources of the sandbox are quiesced, e.g. for a live migration.
+#[derive(Debug)]
+pub struct Quiesced;
+
+impl std::fmt::Display for Quiesced {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "resources of the sandbox are quiesced")
+    }
+}
+
+impl std::error::Error for Quiesced {}
+
+/// is_quiesced_error tells if the resource mutation was refused because the
+/// resources of the sandbox are quiesced.
+pub fn is_quiesced_error(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| e.is::<Quiesced>())
+}
+
+/// QuiesceGate admits the resource mutations until it's quiesced, the ones
+/// in flight are left to complete. It's reentrant: quiesce and resume could
+/// be called any number of times, and a mutation admitted may enter again.
+#[derive(Default)]
+pub(crate) struct QuiesceGate {
+    quiesced: AtomicBool,
+    in_flight: AtomicUsize,
+    drained: Notify,
+}
+
+/// InFlight keeps the mutation admitted in flight until it's dropped.
+pub(crate) struct InFlight<'a> {
+    gate: &'a QuiesceGate,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if self.gate.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.gate.drained.notify_waiters();
+        }
+    }
+}
+
+impl QuiesceGate {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// enter admits a mutation, Quiesced is returned if the gate is
+    /// quiesced.
+    pub(crate) fn enter(&self) -> Result<InFlight<'_>> {
+        // counted before the check, so that quiesce either refuses the
+        // mutation or waits for it
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlight { gate: self };
+        if self.quiesced.load(Ordering::SeqCst) {
+            return Err(Quiesced.into());
+        }
+        Ok(guard)
+    }
+
+    /// quiesce refuses the new mutations and waits for the ones in flight
+    /// to complete. It must not be called by a mutation in flight.
+    pub(crate) async fn quiesce(&self) {
+        self.quiesced.store(true, Ordering::SeqCst);
+        loop {
+            // registered before the check not to miss the notification
+            let drained = self.drained.notified();
+            if self.in_flight.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            drained.await;
+        }
+    }
+
+    pub(crate) fn resume(&self) {
+        self.quiesced.store(false, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_quiesced(&self) -> bool {
+        self.quiesced.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_quiesce_gate() {
+        let gate = Arc::new(QuiesceGate::new());
+
+        // the mutation in flight completes before quiesce returns, and may
+        // enter again
+        let in_flight = gate.enter().unwrap();
+        let nested = gate.enter().unwrap();
+        let g = gate.clone();
+        let quiesce = tokio::spawn(async move { g.quiesce().await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(gate.is_quiesced());
+        assert!(!quiesce.is_finished());
+        drop(nested);
+        drop(in_flight);
+        tokio::time::timeout(Duration::from_secs(1), quiesce)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // the new mutations are refused, quiesce again returns at once
+        let err = gate.enter().err().unwrap();
+        assert!(is_quiesced_error(&err.context("handle devices")));
+        tokio::time::timeout(Duration::from_secs(1), gate.quiesce())
+            .await
+            .unwrap();
+
+        gate.resume();
+        gate.resume();
+        assert!(!gate.is_quiesced());
+        assert!(gate.enter().is_ok());
+    }
+}
@@ -4,12 +4,91 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use std::collections::{BTreeMap, HashMap};
+
 use crate::network::EndpointState;
+use hypervisor::{
+    device::{device_manager::PciSlotState, scsi::ScsiControllerState},
+    GuestProtection,
+};
 use serde::{Deserialize, Serialize};
 
+use crate::agent_features::AgentFeatures;
 use crate::cgroups::cgroup_persist::CgroupState;
+use crate::cpu_mem::cpu::CpuState;
+use crate::cpu_mem::mem::MemState;
+use crate::cpu_mem::mem_reservation::ContainerReservation;
+use crate::hostname::HostnameConfig;
+use crate::limits::LimitCounts;
+use crate::sriov::SriovVf;
+use crate::volume::VolumeState;
+use crate::vsock::VsockState;
 #[derive(Serialize, Deserialize, Default)]
 pub struct ResourceState {
     pub endpoint: Vec<EndpointState>,
     pub cgroup_state: Option<CgroupState>,
+    #[serde(default)]
+    pub volumes: Vec<VolumeState>,
+    /// netns the endpoints are set up in
+    #[serde(default)]
+    pub netns_path: Option<String>,
+    /// guest pci slots taken by the devices
+    #[serde(default)]
+    pub pci_slots: Vec<PciSlotState>,
+    /// scsi controllers added with the devices attached to them
+    #[serde(default)]
+    pub scsi_controllers: Vec<ScsiControllerState>,
+    /// sandbox bind mounts set up, of the config and of the pod annotation
+    #[serde(default)]
+    pub sandbox_bind_mounts: Option<Vec<String>>,
+    /// vsock cid and ports allocated to the sandbox
+    #[serde(default)]
+    pub vsock: Option<VsockState>,
+    /// hostname and hosts file of the pod written into the guest
+    #[serde(default)]
+    pub hostname: Option<HostnameConfig>,
+    /// SR-IOV VFs passed through to the guest by container
+    #[serde(default)]
+    pub sriov_vfs: HashMap<String, Vec<SriovVf>>,
+    /// container given each share fs export with per_container_sharefs
+    #[serde(default)]
+    pub container_exports: Option<Vec<Option<String>>>,
+    /// guest memory taken by the volumes of each container
+    #[serde(default)]
+    pub mem_reservations: Option<BTreeMap<String, ContainerReservation>>,
+    /// sizing of the guest vcpus, the vcpus of the containers included
+    #[serde(default)]
+    pub cpu_state: Option<CpuState>,
+    /// sizing of the guest memory, the memory limits of the containers and
+    /// the balloon included
+    #[serde(default)]
+    pub mem_state: Option<MemState>,
+    /// devices, volumes and rootfs mounts counted against the limits
+    #[serde(default)]
+    pub limit_counts: Option<LimitCounts>,
+    /// nothing is shared from the host, the images are pulled in the guest
+    #[serde(default)]
+    pub no_host_sharing: Option<bool>,
+    /// hardware protection of the guest, nothing is shared with it
+    #[serde(default)]
+    pub guest_protection: Option<GuestProtection>,
+    /// what the agent was found to serve, not asked again
+    #[serde(default)]
+    pub agent_features: Option<AgentFeatures>,
+}
+
+/// Inconsistency is a discrepancy found between the resources restored and
+/// the actual state of the guest or the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// the saved endpoint has no interface in the guest
+    EndpointWithoutInterface(String),
+    /// the device isn't attached to the VM any more
+    DeviceNotAttached(String),
+    /// the host path backing the device is gone
+    DeviceBackendMissing { device_id: String, path: String },
+    /// the host path of the device holding the guest pci slot is gone
+    PciSlotBackendMissing { slot: u8, path: String },
+    /// the cgroup of the sandbox doesn't exist
+    CgroupMissing(String),
 }
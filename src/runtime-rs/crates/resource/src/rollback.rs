@@ -0,0 +1,159 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{fmt::Debug, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+
+/// Setup sets up the host resources of the configs one by one, the steps
+/// done are recorded so that they could be undone on a failure.
+#[async_trait]
+pub(crate) trait Setup: Send {
+    type Config: Send;
+    type Step: Debug + Send;
+
+    /// describe names the config in the errors.
+    fn describe(config: &Self::Config) -> String;
+
+    /// setup handles the config, a step is recorded in done before it's
+    /// started, so that the partial steps are undone as well.
+    async fn setup(&mut self, config: Self::Config, done: &mut Vec<Self::Step>) -> Result<()>;
+
+    /// undo undoes the step through the same code path as the teardown.
+    async fn undo(&mut self, step: Self::Step) -> Result<()>;
+}
+
+/// setup_with_rollback handles the configs in order, each of them within
+/// the timeout, the steps done are undone on the first failure. The steps
+/// done are returned on success, for the caller to roll back if a later
+/// check fails.
+pub(crate) async fn setup_with_rollback<S: Setup>(
+    s: &mut S,
+    configs: Vec<S::Config>,
+    timeout: Duration,
+) -> Result<Vec<S::Step>> {
+    let mut done = vec![];
+    for config in configs {
+        let desc = S::describe(&config);
+        let result = match tokio::time::timeout(timeout, s.setup(config, &mut done)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("{} timed out after {:?}", desc, timeout)),
+        };
+        if let Err(e) = result {
+            rollback(s, done).await;
+            return Err(e);
+        }
+    }
+    Ok(done)
+}
+
+/// rollback undoes the steps in reverse order, the failures are logged and
+/// the rest of the steps are undone anyway.
+pub(crate) async fn rollback<S: Setup>(s: &mut S, mut done: Vec<S::Step>) {
+    while let Some(step) = done.pop() {
+        info!(sl!(), "roll back {:?}", step);
+        let desc = format!("{:?}", step);
+        if let Err(e) = s.undo(step).await {
+            warn!(sl!(), "couldn't roll back {}: {:?}", desc, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    // the host resources are files in the directory, the setup of a config
+    // creates a file and a second one, or hangs or fails in between
+    enum Fault {
+        None,
+        Fail,
+        Hang,
+    }
+
+    struct FakeHost {
+        dir: PathBuf,
+    }
+
+    #[async_trait]
+    impl Setup for FakeHost {
+        type Config = (&'static str, Fault);
+        type Step = PathBuf;
+
+        fn describe(config: &Self::Config) -> String {
+            config.0.to_string()
+        }
+
+        async fn setup(&mut self, config: Self::Config, done: &mut Vec<PathBuf>) -> Result<()> {
+            for i in 0..2 {
+                let path = self.dir.join(format!("{}-{}", config.0, i));
+                done.push(path.clone());
+                std::fs::write(&path, "")?;
+                match config.1 {
+                    Fault::Fail => return Err(anyhow!("injected failure")),
+                    Fault::Hang => futures::future::pending::<()>().await,
+                    Fault::None => {}
+                }
+            }
+            Ok(())
+        }
+
+        async fn undo(&mut self, step: PathBuf) -> Result<()> {
+            std::fs::remove_file(step)?;
+            Ok(())
+        }
+    }
+
+    fn is_empty(dir: &PathBuf) -> bool {
+        std::fs::read_dir(dir).unwrap().next().is_none()
+    }
+
+    #[tokio::test]
+    async fn test_setup_with_rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut host = FakeHost {
+            dir: dir.path().to_path_buf(),
+        };
+        let timeout = Duration::from_millis(100);
+
+        // the host is pristine after the second config fails
+        let err = setup_with_rollback(
+            &mut host,
+            vec![("share-fs", Fault::None), ("network", Fault::Fail)],
+            timeout,
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{:?}", err).contains("injected failure"));
+        assert!(is_empty(&host.dir));
+
+        // and after it hangs
+        let err = setup_with_rollback(
+            &mut host,
+            vec![("share-fs", Fault::None), ("network", Fault::Hang)],
+            timeout,
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{:?}", err).contains("network timed out"));
+        assert!(is_empty(&host.dir));
+
+        // the steps done are kept on success
+        let done = setup_with_rollback(
+            &mut host,
+            vec![("share-fs", Fault::None), ("network", Fault::None)],
+            timeout,
+        )
+        .await
+        .unwrap();
+        assert_eq!(done.len(), 4);
+        rollback(&mut host, done).await;
+        assert!(is_empty(&host.dir));
+    }
+}
@@ -5,15 +5,13 @@
 //
 
 use super::{Rootfs, ROOTFS};
+use crate::metrics;
 use crate::share_fs::{do_get_guest_path, do_get_host_path};
 use agent::Storage;
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use hypervisor::{
-    device::{
-        device_manager::{do_handle_device, DeviceManager},
-        DeviceConfig, DeviceType,
-    },
+    device::{device_manager::DeviceManager, DeviceConfig, DeviceType},
     BlockConfig,
 };
 use kata_types::mount::Mount;
@@ -50,9 +48,10 @@ impl BlockRootfs {
         };
 
         // create and insert block device into Kata VM
-        let device_info = do_handle_device(d, &DeviceConfig::BlockCfg(block_device_config.clone()))
-            .await
-            .context("do handle device failed.")?;
+        let device_info =
+            metrics::handle_device(d, &DeviceConfig::BlockCfg(block_device_config.clone()))
+                .await
+                .context("do handle device failed.")?;
 
         let mut storage = Storage {
             fs_type: rootfs.fs_type.clone(),
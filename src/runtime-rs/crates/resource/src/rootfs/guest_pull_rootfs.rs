@@ -0,0 +1,96 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::collections::HashMap;
+
+use agent::Storage;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use hypervisor::device::device_manager::DeviceManager;
+use kata_types::annotations::{cri_containerd, crio};
+use tokio::sync::RwLock;
+
+use super::{Rootfs, ROOTFS, TYPE_OVERLAY_FS};
+use crate::share_fs::do_get_guest_path;
+
+// the storage driver asking the agent to pull the image of the storage source
+const GUEST_PULL_DRIVER: &str = "image_guest_pull";
+
+/// GuestPullRootfs is the rootfs of the container pulled inside the guest by
+/// the agent, nothing is shared from the host.
+pub(crate) struct GuestPullRootfs {
+    guest_path: String,
+    storage: Storage,
+}
+
+impl GuestPullRootfs {
+    pub(crate) fn new(cid: &str, annotations: &HashMap<String, String>) -> Result<Self> {
+        let image = [cri_containerd::IMAGE_NAME_KEY, crio::IMAGE_NAME_KEY]
+            .iter()
+            .find_map(|k| annotations.get(*k))
+            .ok_or_else(|| anyhow!("no image name of container {} to pull in the guest", cid))?;
+        let guest_path = do_get_guest_path(ROOTFS, cid, false, false);
+        let storage = Storage {
+            driver: GUEST_PULL_DRIVER.to_string(),
+            source: image.clone(),
+            fs_type: TYPE_OVERLAY_FS.to_string(),
+            mount_point: guest_path.clone(),
+            ..Default::default()
+        };
+
+        Ok(Self {
+            guest_path,
+            storage,
+        })
+    }
+}
+
+#[async_trait]
+impl Rootfs for GuestPullRootfs {
+    async fn get_guest_rootfs_path(&self) -> Result<String> {
+        Ok(self.guest_path.clone())
+    }
+
+    async fn get_rootfs_mount(&self) -> Result<Vec<oci::Mount>> {
+        Ok(vec![])
+    }
+
+    async fn get_storage(&self) -> Option<Storage> {
+        Some(self.storage.clone())
+    }
+
+    async fn get_device_id(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    async fn cleanup(&self, _device_manager: &RwLock<DeviceManager>) -> Result<()> {
+        // the agent removes the image mount with the container
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_guest_pull_rootfs() {
+        assert!(GuestPullRootfs::new("c1", &HashMap::new()).is_err());
+
+        let annotations = HashMap::from([(
+            cri_containerd::IMAGE_NAME_KEY.to_string(),
+            "docker.io/library/busybox:latest".to_string(),
+        )]);
+        let rootfs = GuestPullRootfs::new("c1", &annotations).unwrap();
+        let storage = rootfs.get_storage().await.unwrap();
+        assert_eq!(storage.driver, GUEST_PULL_DRIVER);
+        assert_eq!(storage.source, "docker.io/library/busybox:latest");
+        assert_eq!(
+            storage.mount_point,
+            rootfs.get_guest_rootfs_path().await.unwrap()
+        );
+    }
+}
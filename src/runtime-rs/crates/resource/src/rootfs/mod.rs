@@ -4,36 +4,50 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+mod guest_pull_rootfs;
 mod nydus_rootfs;
 mod share_fs_rootfs;
+mod upper_rootfs;
+pub(crate) use upper_rootfs::{UpperConfig, UpperStorage};
+
 use agent::Storage;
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
-use kata_types::mount::Mount;
+use kata_types::mount::{mount_option_key, Mount};
 mod block_rootfs;
 use hypervisor::{device::device_manager::DeviceManager, Hypervisor};
-use std::{sync::Arc, vec::Vec};
+use std::{collections::HashMap, sync::Arc, vec::Vec};
 use tokio::sync::RwLock;
 
 use crate::share_fs::ShareFs;
 
-use self::{block_rootfs::is_block_rootfs, nydus_rootfs::NYDUS_ROOTFS_TYPE};
+use self::{
+    block_rootfs::is_block_rootfs, guest_pull_rootfs::GuestPullRootfs,
+    nydus_rootfs::NYDUS_ROOTFS_TYPE,
+};
 
 const ROOTFS: &str = "rootfs";
 const HYBRID_ROOTFS_LOWER_DIR: &str = "rootfs_lower";
 const TYPE_OVERLAY_FS: &str = "overlay";
+const LOWER_DIR_OPTION: &str = "lowerdir=";
 #[async_trait]
 pub trait Rootfs: Send + Sync {
     async fn get_guest_rootfs_path(&self) -> Result<String>;
     async fn get_rootfs_mount(&self) -> Result<Vec<oci::Mount>>;
     async fn get_storage(&self) -> Option<Storage>;
+    /// get_storages returns the storages of the rootfs in the order the agent
+    /// mounts them, a storage may be mounted on the ones before it.
+    async fn get_storages(&self) -> Vec<Storage> {
+        self.get_storage().await.into_iter().collect()
+    }
     async fn cleanup(&self, device_manager: &RwLock<DeviceManager>) -> Result<()>;
     async fn get_device_id(&self) -> Result<Option<String>>;
 }
 
 #[derive(Default)]
 struct RootFsResourceInner {
-    rootfs: Vec<Arc<dyn Rootfs>>,
+    // the rootfs with the ids of their containers
+    rootfs: Vec<(String, Arc<dyn Rootfs>)>,
 }
 
 pub struct RootFsResource {
@@ -64,10 +78,19 @@ impl RootFsResource {
         root: &oci::Root,
         bundle_path: &str,
         rootfs_mounts: &[Mount],
+        lower_layer: Option<&str>,
+        mount_options: &HashMap<String, Vec<String>>,
     ) -> Result<Arc<dyn Rootfs>> {
         match rootfs_mounts {
             // if rootfs_mounts is empty
             mounts_vec if mounts_vec.is_empty() => {
+                if let Some(lower_layer) = lower_layer {
+                    return Err(anyhow!(
+                        "no overlay rootfs of container {} to stack {} under, the rootfs is mounted already",
+                        cid,
+                        lower_layer
+                    ));
+                }
                 if let Some(share_fs) = share_fs {
                     // handle share fs rootfs
                     Ok(Arc::new(
@@ -86,13 +109,21 @@ impl RootFsResource {
             }
             mounts_vec if is_single_layer_rootfs(mounts_vec) => {
                 // Safe as single_layer_rootfs must have one layer
-                let layer = &mounts_vec[0];
+                let layer = &match lower_layer {
+                    Some(lower_layer) => stack_lower_layer(&mounts_vec[0], lower_layer)
+                        .with_context(|| format!("stack {} under rootfs", lower_layer))?,
+                    None => mounts_vec[0].clone(),
+                };
                 let mut inner = self.inner.write().await;
                 let rootfs = if let Some(dev_id) = is_block_rootfs(&layer.source) {
                     // handle block rootfs
                     info!(sl!(), "block device: {}", dev_id);
+                    let layer = Mount {
+                        options: merge_mount_options(&layer.fs_type, &layer.options, mount_options),
+                        ..layer.clone()
+                    };
                     let block_rootfs: Arc<dyn Rootfs> = Arc::new(
-                        block_rootfs::BlockRootfs::new(device_manager, sid, cid, dev_id, layer)
+                        block_rootfs::BlockRootfs::new(device_manager, sid, cid, dev_id, &layer)
                             .await
                             .context("new block rootfs")?,
                     );
@@ -123,7 +154,7 @@ impl RootFsResource {
                 } else {
                     Err(anyhow!("unsupported rootfs {:?}", &layer))
                 }?;
-                inner.rootfs.push(rootfs.clone());
+                inner.rootfs.push((cid.to_string(), rootfs.clone()));
                 Ok(rootfs)
             }
             _ => Err(anyhow!(
@@ -133,9 +164,66 @@ impl RootFsResource {
         }
     }
 
+    /// handler_upper_rootfs stacks the writable layer of the container on the
+    /// upper storage over its rootfs, the rootfs is cleaned up on failure.
+    /// The rootfs is replaced by the stacked one, which cleans it up.
+    pub async fn handler_upper_rootfs(
+        &self,
+        device_manager: &RwLock<DeviceManager>,
+        cid: &str,
+        lower: Arc<dyn Rootfs>,
+        config: &UpperConfig,
+    ) -> Result<Arc<dyn Rootfs>> {
+        let upper =
+            upper_rootfs::UpperRootfs::new(device_manager, cid, lower.clone(), config).await;
+        let mut inner = self.inner.write().await;
+        inner
+            .rootfs
+            .retain(|(_, r)| Arc::as_ptr(r) as *const u8 != Arc::as_ptr(&lower) as *const u8);
+        let upper = match upper {
+            Ok(upper) => upper,
+            Err(e) => {
+                if let Err(e) = lower.cleanup(device_manager).await {
+                    warn!(sl!(), "couldn't clean up rootfs of {}: {:?}", cid, e);
+                }
+                return Err(e).context("new upper rootfs");
+            }
+        };
+        let rootfs: Arc<dyn Rootfs> = Arc::new(upper);
+        inner.rootfs.push((cid.to_string(), rootfs.clone()));
+        Ok(rootfs)
+    }
+
+    /// handler_guest_pull_rootfs has the agent pull the image of the container
+    /// inside the guest instead of sharing the rootfs from the host.
+    pub async fn handler_guest_pull_rootfs(
+        &self,
+        cid: &str,
+        annotations: &HashMap<String, String>,
+    ) -> Result<Arc<dyn Rootfs>> {
+        let rootfs: Arc<dyn Rootfs> =
+            Arc::new(GuestPullRootfs::new(cid, annotations).context("new guest pull rootfs")?);
+        self.inner
+            .write()
+            .await
+            .rootfs
+            .push((cid.to_string(), rootfs.clone()));
+        Ok(rootfs)
+    }
+
+    /// delete_container forgets the rootfs of the deleted container, they're
+    /// cleaned up with the container. The rootfs forgotten are returned.
+    pub async fn delete_container(&self, cid: &str) -> Vec<Arc<dyn Rootfs>> {
+        let mut inner = self.inner.write().await;
+        let (deleted, rootfs): (Vec<_>, Vec<_>) =
+            inner.rootfs.drain(..).partition(|(c, _)| c == cid);
+        inner.rootfs = rootfs;
+        deleted.into_iter().map(|(_, r)| r).collect()
+    }
+
     pub async fn dump(&self) {
         let inner = self.inner.read().await;
-        for r in &inner.rootfs {
+        for (_, r) in &inner.rootfs {
             info!(
                 sl!(),
                 "rootfs {:?}: count {}",
@@ -146,6 +234,180 @@ impl RootFsResource {
     }
 }
 
+/// needs_share_fs tells if the rootfs gets into the guest only by the fs
+/// sharing, which the block rootfs doesn't need.
+pub(crate) fn needs_share_fs(rootfs_mounts: &[Mount]) -> bool {
+    !(is_single_layer_rootfs(rootfs_mounts) && is_block_rootfs(&rootfs_mounts[0].source).is_some())
+}
+
+/// rootfs_backend tells what the rootfs of the mounts would be set up with,
+/// the same way as handler_rootfs picks it, without setting it up.
+pub(crate) fn rootfs_backend(
+    share_fs: bool,
+    rootfs_mounts: &[Mount],
+    lower_layer: Option<&str>,
+) -> Result<&'static str> {
+    if rootfs_mounts.is_empty() {
+        if let Some(lower_layer) = lower_layer {
+            return Err(anyhow!(
+                "no overlay rootfs to stack {} under, the rootfs is mounted already",
+                lower_layer
+            ));
+        }
+        return match share_fs {
+            true => Ok("share_fs"),
+            false => Err(anyhow!("share fs is unavailable")),
+        };
+    }
+    if !is_single_layer_rootfs(rootfs_mounts) {
+        return Err(anyhow!(
+            "unsupported rootfs mounts count {}",
+            rootfs_mounts.len()
+        ));
+    }
+    let layer = match lower_layer {
+        Some(lower_layer) => stack_lower_layer(&rootfs_mounts[0], lower_layer)
+            .with_context(|| format!("stack {} under rootfs", lower_layer))?,
+        None => rootfs_mounts[0].clone(),
+    };
+    if is_block_rootfs(&layer.source).is_some() {
+        Ok("block")
+    } else if !share_fs {
+        Err(anyhow!("unsupported rootfs {:?}", &layer))
+    } else if layer.fs_type == NYDUS_ROOTFS_TYPE {
+        Ok("nydus")
+    } else {
+        Ok("share_fs")
+    }
+}
+
 fn is_single_layer_rootfs(rootfs_mounts: &[Mount]) -> bool {
     rootfs_mounts.len() == 1
 }
+
+// the default mount options of the filesystem type of the rootfs are added to
+// its options, its own options win on conflicts, e.g. atime over noatime
+fn merge_mount_options(
+    fs_type: &str,
+    options: &[String],
+    defaults: &HashMap<String, Vec<String>>,
+) -> Vec<String> {
+    let mut merged = options.to_vec();
+    for default in defaults.get(fs_type).into_iter().flatten() {
+        let key = mount_option_key(default);
+        match options.iter().find(|o| mount_option_key(o) == key) {
+            Some(option) if option != default => debug!(
+                sl!(),
+                "rootfs mount option {} overrides the default {}", option, default
+            ),
+            Some(_) => {}
+            None => merged.push(default.clone()),
+        }
+    }
+    merged
+}
+
+// the layer is appended as the last lowerdir of the overlay, the lowest one,
+// so that it never shadows the files of the image, and the lower layers of
+// an overlay are never written to
+fn stack_lower_layer(rootfs: &Mount, lower_layer: &str) -> Result<Mount> {
+    if rootfs.fs_type != TYPE_OVERLAY_FS {
+        return Err(anyhow!(
+            "rootfs {} of type {} isn't an overlay",
+            rootfs.source,
+            rootfs.fs_type
+        ));
+    }
+    let mut stacked = rootfs.clone();
+    let lowerdir = stacked
+        .options
+        .iter_mut()
+        .find(|o| o.starts_with(LOWER_DIR_OPTION))
+        .ok_or_else(|| anyhow!("no lowerdir of overlay rootfs {}", rootfs.source))?;
+    lowerdir.push(':');
+    lowerdir.push_str(lower_layer);
+    Ok(stacked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack_lower_layer() {
+        let rootfs = Mount {
+            source: "overlay".to_string(),
+            fs_type: TYPE_OVERLAY_FS.to_string(),
+            options: vec![
+                "workdir=/snapshots/3/work".to_string(),
+                "upperdir=/snapshots/3/fs".to_string(),
+                "lowerdir=/snapshots/2/fs:/snapshots/1/fs".to_string(),
+            ],
+            ..Default::default()
+        };
+        let stacked = stack_lower_layer(&rootfs, "/opt/tools").unwrap();
+        assert_eq!(
+            stacked.options[2],
+            "lowerdir=/snapshots/2/fs:/snapshots/1/fs:/opt/tools"
+        );
+        assert_eq!(stacked.options[..2], rootfs.options[..2]);
+
+        let block = Mount {
+            source: "/dev/vdb".to_string(),
+            fs_type: "ext4".to_string(),
+            ..Default::default()
+        };
+        assert!(stack_lower_layer(&block, "/opt/tools").is_err());
+    }
+
+    #[test]
+    fn test_rootfs_backend() {
+        let overlay = Mount {
+            source: "overlay".to_string(),
+            fs_type: TYPE_OVERLAY_FS.to_string(),
+            options: vec!["lowerdir=/snapshots/1/fs".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(rootfs_backend(true, &[], None).unwrap(), "share_fs");
+        assert!(rootfs_backend(false, &[], None).is_err());
+        assert!(rootfs_backend(true, &[], Some("/opt/tools")).is_err());
+        assert_eq!(
+            rootfs_backend(true, &[overlay.clone()], Some("/opt/tools")).unwrap(),
+            "share_fs"
+        );
+        assert!(rootfs_backend(false, &[overlay.clone()], None).is_err());
+        assert!(rootfs_backend(true, &[overlay.clone(), overlay.clone()], None).is_err());
+
+        let nydus = Mount {
+            fs_type: NYDUS_ROOTFS_TYPE.to_string(),
+            ..overlay
+        };
+        assert_eq!(rootfs_backend(true, &[nydus], None).unwrap(), "nydus");
+    }
+
+    #[test]
+    fn test_merge_mount_options() {
+        let defaults: HashMap<String, Vec<String>> = vec![(
+            "ext4".to_string(),
+            vec![
+                "noatime".to_string(),
+                "discard".to_string(),
+                "commit=30".to_string(),
+            ],
+        )]
+        .into_iter()
+        .collect();
+        let options = vec![
+            "rw".to_string(),
+            "relatime".to_string(),
+            "commit=30".to_string(),
+        ];
+
+        // relatime wins over noatime, commit=30 isn't repeated
+        assert_eq!(
+            merge_mount_options("ext4", &options, &defaults),
+            vec!["rw", "relatime", "commit=30", "discard"]
+        );
+        assert_eq!(merge_mount_options("xfs", &options, &defaults), options);
+    }
+}
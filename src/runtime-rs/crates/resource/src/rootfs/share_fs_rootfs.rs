@@ -50,10 +50,18 @@ impl ShareFsRootfs {
             is_rafs: false,
         };
 
-        let mount_result = share_fs_mount
-            .share_rootfs(&config)
-            .await
-            .context("share rootfs")?;
+        let mount_result = match share_fs_mount.share_rootfs(&config).await {
+            Ok(mount_result) => mount_result,
+            Err(e) => {
+                // the bundle rootfs mounted above is undone as on cleanup
+                if rootfs.is_some() {
+                    if let Err(err) = umount_bundle_rootfs(&bundle_rootfs) {
+                        warn!(sl!(), "couldn't roll back bundle rootfs: {:?}", err);
+                    }
+                }
+                return Err(e).context("share rootfs");
+            }
+        };
 
         Ok(ShareFsRootfs {
             guest_path: mount_result.guest_path,
@@ -89,8 +97,10 @@ impl Rootfs for ShareFsRootfs {
             .await
             .context("umount shared rootfs")?;
 
-        // Umount the bundle rootfs
-        umount_timeout(&self.config.source, 0).context("umount bundle rootfs")?;
-        Ok(())
+        umount_bundle_rootfs(&self.config.source)
     }
 }
+
+fn umount_bundle_rootfs(bundle_rootfs: &str) -> Result<()> {
+    umount_timeout(bundle_rootfs, 0).context("umount bundle rootfs")
+}
@@ -0,0 +1,377 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    fs::{self, OpenOptions},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use agent::Storage;
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use hypervisor::{
+    device::{device_manager::DeviceManager, DeviceConfig, DeviceType},
+    BlockConfig,
+};
+use kata_types::mount::KATA_EPHEMERAL_VOLUME_TYPE;
+use tokio::{process::Command, sync::RwLock};
+
+use super::{Rootfs, TYPE_OVERLAY_FS};
+use crate::{metrics, share_fs::DEFAULT_KATA_GUEST_SANDBOX_DIR};
+
+const KATA_OVERLAY_DEV_TYPE: &str = "overlayfs";
+// the mount points in the guest, by the container id
+const UPPER_DIR: &str = "upper";
+const OVERLAY_DIR: &str = "rootfs";
+const UPPER_FS_DIR: &str = "fs";
+const UPPER_WORK_DIR: &str = "work";
+const UPPER_FILE_PREFIX: &str = "rootfs-upper-";
+const UPPER_FS_TYPE: &str = "ext4";
+// the mkfs of the upper fs type, found in PATH unless it's configured
+const MKFS_CMD: &str = "mkfs.ext4";
+const MIB: u64 = 1024 * 1024;
+
+/// UpperStorage is the storage in the guest of the writable layer of the
+/// containers, rather than the rootfs shared from the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum UpperStorage {
+    Tmpfs,
+    // a dedicated block device of each container, backed by a file on the host
+    Block,
+}
+
+impl UpperStorage {
+    /// new returns the upper storage set by rootfs_upper_storage, none keeps
+    /// the writable layer in the rootfs.
+    pub(crate) fn new(storage: &str) -> Result<Option<Self>> {
+        match storage {
+            "" => Ok(None),
+            "tmpfs" => Ok(Some(Self::Tmpfs)),
+            "block" => Ok(Some(Self::Block)),
+            _ => Err(anyhow!("unsupported rootfs upper storage {}", storage)),
+        }
+    }
+}
+
+/// UpperConfig is the storage of the writable layer of the containers.
+pub(crate) struct UpperConfig {
+    pub storage: UpperStorage,
+    pub size_mb: u32,
+    /// the mkfs formatting the block storage, the one in PATH if empty
+    pub mkfs_path: String,
+    /// the host directory of the files backing the block storages
+    pub host_dir: PathBuf,
+}
+
+/// UpperRootfs stacks the writable layer of the container on a dedicated
+/// storage over its rootfs, which is left as the only lower layer. The size of
+/// the storage limits the writes, beyond it they fail with ENOSPC in the
+/// container as on a full disk, the rootfs isn't affected.
+pub(crate) struct UpperRootfs {
+    lower: Arc<dyn Rootfs>,
+    guest_path: String,
+    // the upper storage then the overlay, mounted in order by the agent
+    storages: Vec<Storage>,
+    // the device and the backing file of the block upper storage
+    device_id: Option<String>,
+    host_file: Option<PathBuf>,
+}
+
+impl UpperRootfs {
+    pub async fn new(
+        d: &RwLock<DeviceManager>,
+        cid: &str,
+        lower: Arc<dyn Rootfs>,
+        config: &UpperConfig,
+    ) -> Result<Self> {
+        let lower_path = lower
+            .get_guest_rootfs_path()
+            .await
+            .context("get lower rootfs path")?;
+        let upper_path = Path::new(DEFAULT_KATA_GUEST_SANDBOX_DIR)
+            .join(UPPER_DIR)
+            .join(cid);
+        let guest_path = Path::new(DEFAULT_KATA_GUEST_SANDBOX_DIR)
+            .join(OVERLAY_DIR)
+            .join(cid)
+            .display()
+            .to_string();
+
+        let mut rootfs = Self {
+            lower,
+            guest_path: guest_path.clone(),
+            storages: vec![],
+            device_id: None,
+            host_file: None,
+        };
+        let upper = match config.storage {
+            UpperStorage::Tmpfs => tmpfs_storage(&upper_path, config.size_mb),
+            UpperStorage::Block => {
+                let host_file = config
+                    .host_dir
+                    .join(format!("{}{}", UPPER_FILE_PREFIX, cid));
+                // the file is removed on failure as on cleanup
+                rootfs.host_file = Some(host_file.clone());
+                match rootfs
+                    .block_storage(d, &host_file, &upper_path, config)
+                    .await
+                {
+                    Ok(storage) => storage,
+                    Err(e) => {
+                        if let Err(e) = rootfs.cleanup_upper(d).await {
+                            warn!(sl!(), "couldn't clean up upper storage: {:?}", e);
+                        }
+                        return Err(e).context("setup block upper storage");
+                    }
+                }
+            }
+        };
+
+        // the agent creates the upperdir and the workdir on the upper storage
+        let overlay = Storage {
+            driver: KATA_OVERLAY_DEV_TYPE.to_string(),
+            source: TYPE_OVERLAY_FS.to_string(),
+            fs_type: TYPE_OVERLAY_FS.to_string(),
+            options: vec![
+                format!("lowerdir={}", lower_path),
+                format!("upperdir={}", upper_path.join(UPPER_FS_DIR).display()),
+                format!("workdir={}", upper_path.join(UPPER_WORK_DIR).display()),
+                "index=off".to_string(),
+            ],
+            mount_point: guest_path,
+            ..Default::default()
+        };
+        rootfs.storages = vec![upper, overlay];
+        Ok(rootfs)
+    }
+
+    async fn block_storage(
+        &mut self,
+        d: &RwLock<DeviceManager>,
+        host_file: &Path,
+        upper_path: &Path,
+        config: &UpperConfig,
+    ) -> Result<Storage> {
+        create_upper_file(host_file, config.size_mb, &config.mkfs_path)
+            .await
+            .context("create upper file")?;
+        let block_config = BlockConfig {
+            path_on_host: host_file.display().to_string(),
+            ..Default::default()
+        };
+        let device = match metrics::handle_device(d, &DeviceConfig::BlockCfg(block_config))
+            .await
+            .context("attach upper device")?
+        {
+            DeviceType::Block(device) => device,
+            _ => return Err(anyhow!("upper device isn't a block device")),
+        };
+        self.device_id = Some(device.device_id);
+
+        Ok(Storage {
+            driver: device.config.driver_option,
+            source: device.config.virt_path,
+            fs_type: UPPER_FS_TYPE.to_string(),
+            mount_point: upper_path.display().to_string(),
+            ..Default::default()
+        })
+    }
+
+    // the block device is detached and its backing file removed, the tmpfs
+    // goes with the container in the guest
+    async fn cleanup_upper(&self, d: &RwLock<DeviceManager>) -> Result<()> {
+        if let Some(device_id) = self.device_id.as_ref() {
+            d.write()
+                .await
+                .try_remove_device(device_id)
+                .await
+                .context("detach upper device")?;
+        }
+        if let Some(host_file) = self.host_file.as_ref().filter(|f| f.exists()) {
+            fs::remove_file(host_file)
+                .with_context(|| format!("remove upper file {}", host_file.display()))?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Rootfs for UpperRootfs {
+    async fn get_guest_rootfs_path(&self) -> Result<String> {
+        Ok(self.guest_path.clone())
+    }
+
+    async fn get_rootfs_mount(&self) -> Result<Vec<oci::Mount>> {
+        self.lower.get_rootfs_mount().await
+    }
+
+    async fn get_storage(&self) -> Option<Storage> {
+        self.storages.last().cloned()
+    }
+
+    async fn get_storages(&self) -> Vec<Storage> {
+        let mut storages = self.lower.get_storages().await;
+        storages.extend(self.storages.iter().cloned());
+        storages
+    }
+
+    async fn cleanup(&self, device_manager: &RwLock<DeviceManager>) -> Result<()> {
+        // the lower rootfs is cleaned up even if the upper storage isn't
+        let upper = self.cleanup_upper(device_manager).await;
+        self.lower.cleanup(device_manager).await?;
+        upper
+    }
+
+    async fn get_device_id(&self) -> Result<Option<String>> {
+        self.lower.get_device_id().await
+    }
+}
+
+fn tmpfs_storage(upper_path: &Path, size_mb: u32) -> Storage {
+    let mut options = vec!["nodev".to_string(), "mode=0755".to_string()];
+    if size_mb != 0 {
+        options.push(format!("size={}m", size_mb));
+    }
+    Storage {
+        driver: KATA_EPHEMERAL_VOLUME_TYPE.to_string(),
+        source: "tmpfs".to_string(),
+        fs_type: "tmpfs".to_string(),
+        options,
+        mount_point: upper_path.display().to_string(),
+        ..Default::default()
+    }
+}
+
+async fn create_upper_file(path: &Path, size_mb: u32, mkfs_path: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("create dir {:?}", parent))?;
+    }
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("open {}", path.display()))?;
+    file.set_len(size_mb as u64 * MIB)
+        .with_context(|| format!("truncate {}", path.display()))?;
+
+    // no blocks reserved for root, the container gets the whole size
+    let mkfs = Some(mkfs_path)
+        .filter(|p| !p.is_empty())
+        .unwrap_or(MKFS_CMD);
+    let output = Command::new(mkfs)
+        .args(["-F", "-q", "-m", "0"])
+        .arg(path)
+        .output()
+        .await
+        .with_context(|| format!("run {}", mkfs))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "mkfs {} failed: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hypervisor::mock::MockHypervisor;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[derive(Default)]
+    struct LowerRootfs {
+        cleaned: AtomicBool,
+    }
+
+    #[async_trait]
+    impl Rootfs for LowerRootfs {
+        async fn get_guest_rootfs_path(&self) -> Result<String> {
+            Ok("/run/kata-containers/c1/rootfs".to_string())
+        }
+        async fn get_rootfs_mount(&self) -> Result<Vec<oci::Mount>> {
+            Ok(vec![])
+        }
+        async fn get_storage(&self) -> Option<Storage> {
+            None
+        }
+        async fn cleanup(&self, _device_manager: &RwLock<DeviceManager>) -> Result<()> {
+            self.cleaned.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+        async fn get_device_id(&self) -> Result<Option<String>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_upper_storage() {
+        assert_eq!(UpperStorage::new("").unwrap(), None);
+        assert_eq!(
+            UpperStorage::new("tmpfs").unwrap(),
+            Some(UpperStorage::Tmpfs)
+        );
+        assert_eq!(
+            UpperStorage::new("block").unwrap(),
+            Some(UpperStorage::Block)
+        );
+        assert!(UpperStorage::new("nvme").is_err());
+
+        let storage = tmpfs_storage(Path::new("/run/kata-containers/sandbox/upper/c1"), 512);
+        assert_eq!(storage.driver, KATA_EPHEMERAL_VOLUME_TYPE);
+        assert!(storage.options.contains(&"size=512m".to_string()));
+        let storage = tmpfs_storage(Path::new("/run/kata-containers/sandbox/upper/c1"), 0);
+        assert!(!storage.options.iter().any(|o| o.starts_with("size=")));
+    }
+
+    #[tokio::test]
+    async fn test_block_upper_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let d = RwLock::new(DeviceManager::new(Arc::new(MockHypervisor::new())).unwrap());
+        let host_file = dir.path().join(format!("{}c1", UPPER_FILE_PREFIX));
+        let mut config = UpperConfig {
+            storage: UpperStorage::Block,
+            size_mb: 1,
+            // the file is left unformatted
+            mkfs_path: "/bin/true".to_string(),
+            host_dir: dir.path().to_path_buf(),
+        };
+
+        let lower = Arc::new(LowerRootfs::default());
+        let rootfs = UpperRootfs::new(&d, "c1", lower.clone(), &config)
+            .await
+            .unwrap();
+        assert_eq!(d.read().await.list_devices().await.len(), 1);
+        assert_eq!(host_file.metadata().unwrap().len(), MIB);
+        let storages = rootfs.get_storages().await;
+        assert_eq!(storages.len(), 2);
+        assert_eq!(storages[0].fs_type, UPPER_FS_TYPE);
+        assert_eq!(storages[1].fs_type, TYPE_OVERLAY_FS);
+        assert!(storages[1]
+            .options
+            .contains(&"lowerdir=/run/kata-containers/c1/rootfs".to_string()));
+
+        // the device is detached, the file removed and the lower cleaned up
+        rootfs.cleanup(&d).await.unwrap();
+        assert!(!host_file.exists());
+        assert!(d.read().await.list_devices().await.is_empty());
+        assert!(lower.cleaned.load(Ordering::SeqCst));
+
+        // the file isn't left behind when it can't be formatted
+        config.mkfs_path = "/bin/false".to_string();
+        let lower = Arc::new(LowerRootfs::default());
+        assert!(UpperRootfs::new(&d, "c1", lower.clone(), &config)
+            .await
+            .is_err());
+        assert!(!host_file.exists());
+        // the lower one is left to the caller
+        assert!(!lower.cleaned.load(Ordering::SeqCst));
+    }
+}
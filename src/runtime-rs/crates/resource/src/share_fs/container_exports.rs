@@ -0,0 +1,405 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use agent::Storage;
+use anyhow::{anyhow, Context, Result};
+use hypervisor::Hypervisor;
+use kata_sys_util::mount::umount_all;
+use kata_types::config::{default::DEFAULT_MAX_SHAREFS_INSTANCES, hypervisor::SharedFsInfo};
+
+use super::{
+    share_virtio_fs::ShareFsExport,
+    share_virtio_fs_standalone::ShareVirtioFsStandalone,
+    utils::{get_host_ro_shared_path, get_host_rw_shared_path, remove_dir_all_if_exists},
+    ShareFs, PASSTHROUGH_FS_DIR, SHARED_FS_AUTO, VIRTIO_FS,
+};
+use crate::events::ResourceEvents;
+
+const PROC_SELF_MOUNTINFO: &str = "/proc/self/mountinfo";
+// the directory of the exports of the containers in the one of the sandbox
+const EXPORTS_DIR: &str = "exports";
+
+// export_id is the id of the export, its host directories are under the ones
+// of the sandbox
+fn export_id(sid: &str, index: usize) -> String {
+    format!("{}/{}/{}", sid, EXPORTS_DIR, index)
+}
+
+/// ContainerExports are the virtio-fs exports of the containers with
+/// per_container_sharefs: each one is served by its own virtiofsd from its
+/// own directory, under its own device tag. They're all set up before the VM
+/// starts, the virtio-fs devices can't be hotplugged. A container is given
+/// the first export free, and keeps it until it's deleted.
+pub(crate) struct ContainerExports {
+    sid: String,
+    exports: Vec<Arc<dyn ShareFs>>,
+    // the container given each export, None if it's free
+    owners: Mutex<Vec<Option<String>>>,
+}
+
+impl ContainerExports {
+    pub(crate) fn new(
+        sid: &str,
+        config: &SharedFsInfo,
+        count: usize,
+        events: Arc<ResourceEvents>,
+    ) -> Result<Self> {
+        let shared_fs = config.shared_fs.as_deref().unwrap_or_default();
+        if shared_fs != VIRTIO_FS && shared_fs != SHARED_FS_AUTO {
+            return Err(anyhow!(
+                "per_container_sharefs needs a virtiofsd by export, shared_fs {} has none",
+                shared_fs
+            ));
+        }
+        // the config isn't adjusted, e.g. in the tests
+        let count = match count {
+            0 => DEFAULT_MAX_SHAREFS_INSTANCES as usize,
+            count => count,
+        };
+
+        let mut exports: Vec<Arc<dyn ShareFs>> = vec![];
+        for index in 0..count {
+            let export = ShareVirtioFsStandalone::new(
+                &export_id(sid, index),
+                config,
+                ShareFsExport::container(index),
+                events.clone(),
+            )
+            .with_context(|| format!("new share fs export {}", index))?;
+            exports.push(Arc::new(export));
+        }
+        Ok(Self {
+            sid: sid.to_string(),
+            exports,
+            owners: Mutex::new(vec![None; count]),
+        })
+    }
+
+    /// restore rebuilds the exports of the sandbox restored, each one given
+    /// to the container it was given to before.
+    pub(crate) fn restore(
+        sid: &str,
+        config: &SharedFsInfo,
+        owners: Vec<Option<String>>,
+        events: Arc<ResourceEvents>,
+    ) -> Result<Self> {
+        let exports = Self::new(sid, config, owners.len(), events)?;
+        *exports.owners.lock().unwrap() = owners;
+        Ok(exports)
+    }
+
+    /// owners returns the container given each export, to be restored.
+    pub(crate) fn owners(&self) -> Vec<Option<String>> {
+        self.owners.lock().unwrap().clone()
+    }
+
+    /// setup_before_start_vm adds the devices of the exports to the VM, and
+    /// starts their virtiofsd.
+    pub(crate) async fn setup_before_start_vm(&self, h: &dyn Hypervisor) -> Result<()> {
+        for (index, export) in self.exports.iter().enumerate() {
+            export
+                .setup_device_before_start_vm(h)
+                .await
+                .with_context(|| format!("setup share fs export {}", index))?;
+        }
+        Ok(())
+    }
+
+    /// hotplug adds the devices of the exports to the VM running already,
+    /// once their virtiofsd is started.
+    pub(crate) async fn hotplug(&self, h: &dyn Hypervisor) -> Result<()> {
+        for (index, export) in self.exports.iter().enumerate() {
+            export
+                .hotplug_device(h)
+                .await
+                .with_context(|| format!("hotplug share fs export {}", index))?;
+        }
+        Ok(())
+    }
+
+    /// acquire returns the export of the container, it's given the first
+    /// one free if it has none yet. It fails if they're all taken.
+    pub(crate) fn acquire(&self, cid: &str) -> Result<Arc<dyn ShareFs>> {
+        let mut owners = self.owners.lock().unwrap();
+        let index = assign(&mut owners, cid).ok_or_else(|| {
+            anyhow!(
+                "container {} gets no share fs export, the {} of max_sharefs_instances are taken",
+                cid,
+                owners.len()
+            )
+        })?;
+        info!(sl!(), "container {} has share fs export {}", cid, index);
+        Ok(self.exports[index].clone())
+    }
+
+    /// check_free fails if a new container would get no export.
+    pub(crate) fn check_free(&self) -> Result<()> {
+        let owners = self.owners.lock().unwrap();
+        if owners.iter().any(|o| o.is_none()) {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "no share fs export is free, the {} of max_sharefs_instances are taken",
+            owners.len()
+        ))
+    }
+
+    fn index_of(&self, cid: &str) -> Option<usize> {
+        let owners = self.owners.lock().unwrap();
+        owners.iter().position(|o| o.as_deref() == Some(cid))
+    }
+
+    /// storages returns the storage the guest mounts the export of the
+    /// container from, none if it has no export.
+    pub(crate) async fn storages(&self, cid: &str) -> Result<Vec<Storage>> {
+        match self.index_of(cid) {
+            Some(index) => self.exports[index].get_storages().await,
+            None => Ok(vec![]),
+        }
+    }
+
+    /// storages_in_use returns the storages of the exports given to the
+    /// containers.
+    pub(crate) async fn storages_in_use(&self) -> Result<Vec<Storage>> {
+        let in_use: Vec<usize> = {
+            let owners = self.owners.lock().unwrap();
+            (0..owners.len()).filter(|i| owners[*i].is_some()).collect()
+        };
+        let mut storages = vec![];
+        for index in in_use {
+            let mut s = self.exports[index].get_storages().await?;
+            storages.append(&mut s);
+        }
+        Ok(storages)
+    }
+
+    /// in_use returns the exports given to the containers, each one is
+    /// mounted in the guest.
+    pub(crate) fn in_use(&self) -> usize {
+        let owners = self.owners.lock().unwrap();
+        owners.iter().filter(|o| o.is_some()).count()
+    }
+
+    /// release tears down what's left in the export of the deleted container,
+    /// and frees it for the next one. virtiofsd keeps serving it, its device
+    /// stays in the VM.
+    pub(crate) async fn release(&self, cid: &str) -> Result<()> {
+        let index = match self.index_of(cid) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        self.clear(index)
+            .await
+            .with_context(|| format!("clear share fs export {} of {}", index, cid))?;
+        self.owners.lock().unwrap()[index] = None;
+        info!(
+            sl!(),
+            "container {} released share fs export {}", cid, index
+        );
+        Ok(())
+    }
+
+    // clear detaches the mounts left in the shared directory of the export,
+    // then removes it, so that nothing of the container shows up in the next
+    // one. Nothing is removed through a mount left.
+    async fn clear(&self, index: usize) -> Result<()> {
+        let id = export_id(&self.sid, index);
+        let rw = get_host_rw_shared_path(&id).join(PASSTHROUGH_FS_DIR);
+        let ro = get_host_ro_shared_path(&id).join(PASSTHROUGH_FS_DIR);
+        let mountinfo = fs::read_to_string(PROC_SELF_MOUNTINFO)
+            .with_context(|| format!("read {}", PROC_SELF_MOUNTINFO))?;
+        for dir in [&rw, &ro] {
+            for mount_point in mount_points_under(&mountinfo, dir) {
+                umount_all(&mount_point, true)
+                    .with_context(|| format!("umount {:?}", mount_point))?;
+            }
+        }
+        remove_dir_all_if_exists(&rw).with_context(|| format!("remove {:?}", rw))?;
+        self.exports[index].mounted_info_set().lock().await.clear();
+        Ok(())
+    }
+
+    /// cleanup tears down all the exports with the sandbox, the containers
+    /// are gone.
+    pub(crate) async fn cleanup(&self) -> Result<()> {
+        for (index, export) in self.exports.iter().enumerate() {
+            self.clear(index)
+                .await
+                .with_context(|| format!("clear share fs export {}", index))?;
+            export
+                .get_share_fs_mount()
+                .cleanup(&export_id(&self.sid, index))
+                .await
+                .with_context(|| format!("cleanup share fs export {}", index))?;
+        }
+        Ok(())
+    }
+
+    /// check_health fails if the virtiofsd of an export is gone.
+    pub(crate) async fn check_health(&self) -> Result<()> {
+        for (index, export) in self.exports.iter().enumerate() {
+            export
+                .check_health()
+                .await
+                .with_context(|| format!("share fs export {}", index))?;
+        }
+        Ok(())
+    }
+
+    /// kill_daemons signals the virtiofsd of the exports, they're all tried.
+    pub(crate) fn kill_daemons(&self) -> Result<()> {
+        let mut result = Ok(());
+        for (index, export) in self.exports.iter().enumerate() {
+            if let Err(e) = export.kill_daemon() {
+                warn!(
+                    sl!(),
+                    "couldn't kill virtiofsd of share fs export {}: {:?}", index, e
+                );
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// lazy_umount detaches the shared directories of the exports without
+    /// blocking on the busy ones.
+    pub(crate) fn lazy_umount(&self) -> Result<()> {
+        for index in 0..self.exports.len() {
+            super::lazy_umount_shared_path(&export_id(&self.sid, index))?;
+        }
+        Ok(())
+    }
+}
+
+// assign returns the export of the container, the first one free is given
+// to it if it has none yet
+fn assign(owners: &mut [Option<String>], cid: &str) -> Option<usize> {
+    if let Some(index) = owners.iter().position(|o| o.as_deref() == Some(cid)) {
+        return Some(index);
+    }
+    let index = owners.iter().position(|o| o.is_none())?;
+    owners[index] = Some(cid.to_string());
+    Some(index)
+}
+
+// mount_points_under returns the mount points of the mountinfo under the
+// directory, the deepest first so that the nested ones go before
+fn mount_points_under(mountinfo: &str, dir: &Path) -> Vec<PathBuf> {
+    let mut mount_points: Vec<PathBuf> = mountinfo
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(4))
+        .map(|p| PathBuf::from(unescape_mount_point(p)))
+        .filter(|p| p.starts_with(dir) && p != dir)
+        .collect();
+    mount_points.sort_by_key(|p| std::cmp::Reverse(p.components().count()));
+    mount_points
+}
+
+// unescape_mount_point decodes the octal escapes of the mountinfo, e.g.
+// "\040" of a space
+fn unescape_mount_point(p: &str) -> String {
+    let mut out = String::new();
+    let mut rest = p;
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        let escape = rest.get(i + 1..i + 4).unwrap_or_default();
+        match u8::from_str_radix(escape, 8) {
+            Ok(c) if escape.len() == 3 => {
+                out.push(c as char);
+                rest = &rest[i + 4..];
+            }
+            _ => {
+                out.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assign() {
+        let mut owners = vec![None; 2];
+        assert_eq!(assign(&mut owners, "a"), Some(0));
+        assert_eq!(assign(&mut owners, "b"), Some(1));
+        // the same export again
+        assert_eq!(assign(&mut owners, "a"), Some(0));
+        // all taken
+        assert_eq!(assign(&mut owners, "c"), None);
+
+        // the first one freed is given next
+        owners[0] = None;
+        assert_eq!(assign(&mut owners, "c"), Some(0));
+        assert_eq!(owners, vec![Some("c".to_string()), Some("b".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn test_restore() {
+        let config = SharedFsInfo {
+            shared_fs: Some(VIRTIO_FS.to_string()),
+            ..Default::default()
+        };
+        let events = Arc::new(ResourceEvents::new("sid"));
+        let exports = ContainerExports::new("sid", &config, 2, events.clone()).unwrap();
+        exports.acquire("c1").unwrap();
+        exports.acquire("c2").unwrap();
+        // c1 is gone
+        exports.owners.lock().unwrap()[0] = None;
+        let owners = exports.owners();
+        assert_eq!(owners, vec![None, Some("c2".to_string())]);
+
+        let exports = ContainerExports::restore("sid", &config, owners, events).unwrap();
+        // the container keeps its export, the guest mounts it at its dir
+        let storages = exports.storages("c2").await.unwrap();
+        assert_eq!(
+            storages[0].mount_point,
+            "/run/kata-containers/shared/exports/1/"
+        );
+        // the free one is given to the next container
+        exports.acquire("c3").unwrap();
+        assert_eq!(
+            exports.owners(),
+            vec![Some("c3".to_string()), Some("c2".to_string())]
+        );
+        assert!(exports.check_free().is_err());
+    }
+
+    #[test]
+    fn test_mount_points_under() {
+        let mountinfo = "\
+22 1 253:1 / / rw,relatime shared:1 - ext4 /dev/vda1 rw
+81 22 0:45 / /run/sb/exports/0/rw/passthrough rw shared:40 - tmpfs tmpfs rw
+82 81 253:1 /data /run/sb/exports/0/rw/passthrough/c1-data rw shared:1 - ext4 /dev/vda1 rw
+83 81 0:46 / /run/sb/exports/0/rw/passthrough/c1/rootfs rw shared:41 - overlay overlay rw
+84 81 253:1 /a\\040b /run/sb/exports/0/rw/passthrough/a\\040b rw shared:1 - ext4 /dev/vda1 rw
+85 22 253:1 /x /run/sb/exports/1/rw/passthrough/c2-data rw shared:1 - ext4 /dev/vda1 rw
+";
+        let dir = Path::new("/run/sb/exports/0/rw/passthrough");
+        let mount_points = mount_points_under(mountinfo, dir);
+        assert_eq!(mount_points[0], dir.join("c1/rootfs"));
+        let mut rest = mount_points[1..].to_vec();
+        rest.sort();
+        assert_eq!(rest, vec![dir.join("a b"), dir.join("c1-data")]);
+    }
+
+    #[test]
+    fn test_unescape_mount_point() {
+        assert_eq!(unescape_mount_point("/a\\040b\\011c"), "/a b\tc");
+        assert_eq!(unescape_mount_point("/a\\b"), "/a\\b");
+        assert_eq!(unescape_mount_point("/a\\04"), "/a\\04");
+    }
+}
@@ -4,17 +4,27 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+mod container_exports;
+pub(crate) use container_exports::ContainerExports;
+mod ready;
+pub(crate) use ready::{stat_mounted, wait_mounted};
 mod share_virtio_fs;
 pub use share_virtio_fs::rafs_mount;
+use share_virtio_fs::ShareFsExport;
 mod share_virtio_fs_inline;
 use share_virtio_fs_inline::ShareVirtioFsInline;
 mod share_virtio_fs_standalone;
 use share_virtio_fs_standalone::ShareVirtioFsStandalone;
+pub use share_virtio_fs_standalone::VirtiofsdNotReady;
 mod utils;
 use tokio::sync::Mutex;
 pub use utils::{
     do_get_guest_path, do_get_guest_share_path, do_get_host_path, get_host_rw_shared_path,
 };
+pub(crate) use utils::{
+    remove_dir_all_if_exists, remove_host_shared_root, set_host_shared_root,
+    validate_host_shared_root,
+};
 mod virtio_fs_share_mount;
 use virtio_fs_share_mount::VirtiofsShareMount;
 pub use virtio_fs_share_mount::EPHEMERAL_PATH;
@@ -25,8 +35,11 @@ use std::{collections::HashMap, fmt::Debug, path::PathBuf, sync::Arc};
 use agent::Storage;
 use anyhow::{anyhow, Context, Ok, Result};
 use async_trait::async_trait;
-use hypervisor::Hypervisor;
-use kata_types::config::hypervisor::SharedFsInfo;
+use hypervisor::{GuestProtection, Hypervisor};
+use kata_sys_util::mount::umount_all;
+use kata_types::config::hypervisor::{SharedFsInfo, SHARED_FS_AUTO};
+
+use crate::events::ResourceEvents;
 
 const VIRTIO_FS: &str = "virtio-fs";
 const _VIRTIO_FS_NYDUS: &str = "virtio-fs-nydus";
@@ -35,7 +48,11 @@ const INLINE_VIRTIO_FS: &str = "inline-virtio-fs";
 const KATA_HOST_SHARED_DIR: &str = "/run/kata-containers/shared/sandboxes/";
 
 /// share fs (for example virtio-fs) mount path in the guest
-const KATA_GUEST_SHARE_DIR: &str = "/run/kata-containers/shared/containers/";
+pub(crate) const KATA_GUEST_SHARE_DIR: &str = "/run/kata-containers/shared/containers/";
+
+/// the guest mount paths of the exports of the containers with
+/// per_container_sharefs, one directory by export
+pub(crate) const KATA_GUEST_EXPORTS_DIR: &str = "/run/kata-containers/shared/exports/";
 
 pub(crate) const DEFAULT_KATA_GUEST_SANDBOX_DIR: &str = "/run/kata-containers/sandbox/";
 
@@ -47,8 +64,38 @@ pub trait ShareFs: Send + Sync {
     fn get_share_fs_mount(&self) -> Arc<dyn ShareFsMount>;
     async fn setup_device_before_start_vm(&self, h: &dyn Hypervisor) -> Result<()>;
     async fn setup_device_after_start_vm(&self, h: &dyn Hypervisor) -> Result<()>;
+    /// hotplug_device adds the device to the VM running already, e.g. the
+    /// one adopted from a pool, its daemon is started first as the guest
+    /// probes the device as soon as it's plugged.
+    async fn hotplug_device(&self, _h: &dyn Hypervisor) -> Result<()> {
+        Err(anyhow!("share fs device can't be hotplugged"))
+    }
     async fn get_storages(&self) -> Result<Vec<Storage>>;
     fn mounted_info_set(&self) -> Arc<Mutex<HashMap<String, MountedInfo>>>;
+    /// kill_daemon signals the daemon serving the share fs without waiting
+    /// for it, it's a no-op if there's no daemon or it's gone already.
+    fn kill_daemon(&self) -> Result<()> {
+        Ok(())
+    }
+    /// check_health fails if the daemon serving the share fs is gone, there's
+    /// nothing to check without a daemon.
+    async fn check_health(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// is_guest_shared_path tells if the guest path is shared from the host,
+/// through the export of the sandbox or of a container.
+pub(crate) fn is_guest_shared_path(path: &str) -> bool {
+    path.starts_with(KATA_GUEST_SHARE_DIR) || path.starts_with(KATA_GUEST_EXPORTS_DIR)
+}
+
+/// lazy_umount_shared_path detaches the mounts shared with the guest of the
+/// sandbox without blocking on the busy ones.
+pub(crate) fn lazy_umount_shared_path(sid: &str) -> Result<()> {
+    umount_all(utils::get_host_allowed_shared_path(sid), true)
+        .context("umount allowed shared path")?;
+    umount_all(utils::get_host_ro_shared_path(sid), true).context("umount ro shared path")
 }
 
 #[derive(Debug, Clone)]
@@ -86,6 +133,8 @@ pub struct MountedInfo {
     pub ro_ref_count: usize,
     // Ref count of containers that uses this volume with read write permission
     pub rw_ref_count: usize,
+    // Storages the agent mounts the volume from, shared by all the containers
+    pub storages: Vec<agent::Storage>,
 }
 
 impl MountedInfo {
@@ -94,6 +143,7 @@ impl MountedInfo {
             guest_path,
             ro_ref_count: readonly.into(),
             rw_ref_count: (!readonly).into(),
+            storages: vec![],
         }
     }
 
@@ -138,16 +188,67 @@ pub trait ShareFsMount: Send + Sync {
     async fn cleanup(&self, sid: &str) -> Result<()>;
 }
 
-pub fn new(id: &str, config: &SharedFsInfo) -> Result<Arc<dyn ShareFs>> {
+pub fn new(
+    id: &str,
+    config: &SharedFsInfo,
+    events: Arc<ResourceEvents>,
+) -> Result<Arc<dyn ShareFs>> {
     let shared_fs = config.shared_fs.clone();
     let shared_fs = shared_fs.unwrap_or_default();
     match shared_fs.as_str() {
         INLINE_VIRTIO_FS => Ok(Arc::new(
             ShareVirtioFsInline::new(id, config).context("new inline virtio fs")?,
         )),
-        VIRTIO_FS => Ok(Arc::new(
-            ShareVirtioFsStandalone::new(id, config).context("new standalone virtio fs")?,
+        VIRTIO_FS | SHARED_FS_AUTO => Ok(Arc::new(
+            ShareVirtioFsStandalone::new(id, config, ShareFsExport::sandbox(), events)
+                .context("new standalone virtio fs")?,
         )),
         _ => Err(anyhow!("unsupported shred fs {:?}", &shared_fs)),
     }
 }
+
+/// is_allowed_by_protection tells if the share fs is set up for the guest of
+/// the protection: sharing the host directories defeats the protection of the
+/// guest, so the share fs is refused if the config asks for it, and skipped
+/// with "auto".
+pub(crate) fn is_allowed_by_protection(
+    protection: GuestProtection,
+    shared_fs: Option<&str>,
+) -> Result<bool> {
+    if !protection.is_protected() {
+        return Ok(true);
+    }
+    match shared_fs {
+        None | Some(SHARED_FS_AUTO) => Ok(false),
+        Some(fs) => Err(anyhow!(
+            "shared_fs {} shares the host directories with the guest protected by {}, set shared_fs to {} to share nothing",
+            fs,
+            protection,
+            SHARED_FS_AUTO
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed_by_protection() {
+        for fs in [None, Some(VIRTIO_FS), Some(SHARED_FS_AUTO)] {
+            assert!(is_allowed_by_protection(GuestProtection::NoProtection, fs).unwrap());
+        }
+
+        for protection in [
+            GuestProtection::Tdx,
+            GuestProtection::Sev,
+            GuestProtection::Snp,
+            GuestProtection::Se,
+        ] {
+            assert!(!is_allowed_by_protection(protection, Some(SHARED_FS_AUTO)).unwrap());
+            assert!(!is_allowed_by_protection(protection, None).unwrap());
+            assert!(is_allowed_by_protection(protection, Some(VIRTIO_FS)).is_err());
+            assert!(is_allowed_by_protection(protection, Some(INLINE_VIRTIO_FS)).is_err());
+        }
+    }
+}
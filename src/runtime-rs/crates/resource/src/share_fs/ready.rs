@@ -0,0 +1,253 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{future::Future, path::Path};
+
+use agent::{Agent, VolumeStatsRequest, VolumeStatsResponse, VolumeUsageUnit};
+use anyhow::{anyhow, Result};
+use kata_types::config::RetryPolicy;
+use tokio::time::{self, Duration, Instant};
+
+// check the mount points again and again until the timeout, quickly at first
+const DEFAULT_MOUNT_RETRY_POLICY: RetryPolicy = RetryPolicy {
+    max_attempts: u32::MAX,
+    base_delay_ms: 10,
+    max_delay_ms: 500,
+    jitter: 0,
+};
+
+/// wait_mounted polls the mount points of the storages in the guest with
+/// stat until they're all mounted, the ones mounted already aren't checked
+/// again. They're checked again by the policy, the built-in one if unset. It
+/// fails with the mount points still missing, and the last error of stat,
+/// once the timeout or the attempts are over. stat is cut by the timeout as
+/// well.
+pub(crate) async fn wait_mounted<F, Fut>(
+    mount_points: &[String],
+    timeout: Duration,
+    policy: Option<RetryPolicy>,
+    mut stat: F,
+) -> Result<()>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let start = Instant::now();
+    let mut delays = policy.unwrap_or(DEFAULT_MOUNT_RETRY_POLICY).delays();
+    let mut pending: Vec<String> = vec![];
+    for mount_point in mount_points {
+        if !pending.contains(mount_point) {
+            pending.push(mount_point.clone());
+        }
+    }
+
+    loop {
+        let mut missing = vec![];
+        let mut last_err = None;
+        for mount_point in pending {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            let err = match time::timeout(remaining, stat(mount_point.clone())).await {
+                Ok(Ok(())) => continue,
+                Ok(Err(e)) => e,
+                Err(_) => anyhow!("stat of {} timed out", mount_point),
+            };
+            last_err = Some(err);
+            missing.push(mount_point);
+        }
+        let err = match last_err {
+            Some(err) => err,
+            None => return Ok(()),
+        };
+
+        let waited = start.elapsed();
+        let delay = match delays.next() {
+            Some(delay) if waited < timeout => delay,
+            _ => {
+                return Err(err.context(format!(
+                    "share fs storages {} aren't mounted in the guest after waiting {} ms",
+                    missing.join(", "),
+                    waited.as_millis()
+                )))
+            }
+        };
+        debug!(sl!(), "share fs storages {:?} aren't mounted yet", missing);
+        pending = missing;
+        time::sleep(delay.min(timeout - waited)).await;
+    }
+}
+
+/// stat_mounted fails unless the storage is mounted at the mount point in
+/// the guest. The mount point is created before the storage is mounted over
+/// it, until then the agent finds the filesystem of its parent there, with
+/// the same capacity.
+pub(crate) async fn stat_mounted(agent: &dyn Agent, mount_point: &str) -> Result<()> {
+    let parent = Path::new(mount_point)
+        .parent()
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|| "/".to_string());
+    let stats = volume_stats(agent, mount_point).await?;
+    let parent_stats = volume_stats(agent, &parent).await?;
+    if capacity(&stats) == capacity(&parent_stats) {
+        return Err(anyhow!(
+            "{} is still on the filesystem of {}",
+            mount_point,
+            parent
+        ));
+    }
+    Ok(())
+}
+
+async fn volume_stats(agent: &dyn Agent, path: &str) -> Result<VolumeStatsResponse> {
+    agent
+        .get_volume_stats(VolumeStatsRequest {
+            volume_guest_path: path.to_string(),
+        })
+        .await
+}
+
+// capacity returns the totals of the filesystem, the ones used change
+fn capacity(stats: &VolumeStatsResponse) -> Vec<(VolumeUsageUnit, u64)> {
+    stats.usage.iter().map(|u| (u.unit, u.total)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use agent::VolumeUsage;
+
+    use super::*;
+    use crate::mock_agent::MockAgent;
+
+    fn stats(total: u64, used: u64) -> VolumeStatsResponse {
+        VolumeStatsResponse {
+            usage: vec![VolumeUsage {
+                total,
+                used,
+                available: total - used,
+                unit: VolumeUsageUnit::Bytes,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stat_mounted() {
+        let agent = MockAgent::new("3.2.0");
+        let mount_point = "/run/kata-containers/shared/containers";
+        // missing
+        assert!(stat_mounted(&agent, mount_point).await.is_err());
+
+        // the directory created on the tmpfs of /run, not mounted over yet
+        agent.set_volume_stats("/run/kata-containers/shared", stats(1024, 10));
+        agent.set_volume_stats(mount_point, stats(1024, 12));
+        let e = stat_mounted(&agent, mount_point).await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "/run/kata-containers/shared/containers is still on the filesystem of /run/kata-containers/shared"
+        );
+
+        agent.set_volume_stats(mount_point, stats(4096, 12));
+        stat_mounted(&agent, mount_point).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wait_mounted() {
+        // a storage mounted at the third stat, the other at once
+        let stats = Arc::new(Mutex::new(vec![]));
+        let mount_points = vec!["/a".to_string(), "/b".to_string(), "/a".to_string()];
+        let s = stats.clone();
+        wait_mounted(
+            &mount_points,
+            Duration::from_secs(5),
+            None,
+            move |mount_point| {
+                let s = s.clone();
+                async move {
+                    let mut stats = s.lock().unwrap();
+                    stats.push(mount_point.clone());
+                    let count = stats.iter().filter(|m| **m == mount_point).count();
+                    if mount_point == "/a" && count < 3 {
+                        return Err(anyhow!("no such file or directory"));
+                    }
+                    Ok(())
+                }
+            },
+        )
+        .await
+        .unwrap();
+        // the duplicate is checked once, the mounted one isn't checked again
+        assert_eq!(*stats.lock().unwrap(), vec!["/a", "/b", "/a", "/a"]);
+
+        // none
+        wait_mounted(&[], Duration::from_millis(0), None, |_| async {
+            Err(anyhow!("unexpected stat"))
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wait_mounted_timeout() {
+        let mount_points = vec!["/a".to_string(), "/b".to_string()];
+        let e = wait_mounted(
+            &mount_points,
+            Duration::from_millis(50),
+            None,
+            |mount_point| async move {
+                if mount_point == "/b" {
+                    return Ok(());
+                }
+                Err(anyhow!("no such file or directory"))
+            },
+        )
+        .await
+        .unwrap_err();
+        let message = format!("{:#}", e);
+        assert!(
+            message.starts_with("share fs storages /a aren't mounted in the guest"),
+            "{}",
+            message
+        );
+        assert!(
+            message.ends_with("no such file or directory"),
+            "{}",
+            message
+        );
+
+        // a stat hanging is cut by the timeout
+        let e = wait_mounted(&mount_points, Duration::from_millis(50), None, |_| async {
+            time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        })
+        .await
+        .unwrap_err();
+        let message = format!("{:#}", e);
+        assert!(
+            message.contains("storages /a, /b aren't mounted"),
+            "{}",
+            message
+        );
+        assert!(message.ends_with("stat of /b timed out"), "{}", message);
+
+        // it gives up once the attempts of the policy are over
+        let stats = Arc::new(Mutex::new(0));
+        let s = stats.clone();
+        let e = wait_mounted(
+            &mount_points[..1],
+            Duration::from_secs(60),
+            Some(RetryPolicy::fixed(2, 0)),
+            move |_| {
+                *s.lock().unwrap() += 1;
+                async { Err(anyhow!("no such file or directory")) }
+            },
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{:#}", e).contains("storages /a aren't mounted"));
+        assert_eq!(*stats.lock().unwrap(), 2);
+    }
+}
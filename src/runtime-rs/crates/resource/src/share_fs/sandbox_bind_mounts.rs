@@ -15,14 +15,81 @@
 use std::{
     collections::HashMap,
     fs,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
 use anyhow::{anyhow, Context, Result};
 
 use super::utils::{do_get_host_path, mkdir_with_permissions};
+use crate::path_jail::PathJail;
 use kata_sys_util::{fs::get_base_name, mount};
-use kata_types::mount::{SANDBOX_BIND_MOUNTS_DIR, SANDBOX_BIND_MOUNTS_RO, SANDBOX_BIND_MOUNTS_RW};
+use kata_types::{
+    annotations::KATA_ANNO_CFG_SANDBOX_BIND_MOUNTS,
+    config::TomlConfig,
+    mount::{
+        split_bind_mounts, SANDBOX_BIND_MOUNTS_DIR, SANDBOX_BIND_MOUNTS_RO, SANDBOX_BIND_MOUNTS_RW,
+    },
+};
+
+/// effective_bind_mounts returns the sandbox bind mounts of the config merged
+/// with those of the pod annotation. The annotated host paths must be absolute
+/// without "..", and stay under one of valid_sandbox_bind_mount_prefixes once
+/// their symlinks are resolved. A path given twice is mounted once, with the
+/// mode it's given first, the config coming first.
+pub(crate) fn effective_bind_mounts(config: &TomlConfig) -> Result<Vec<String>> {
+    let mut mounts = config.runtime.sandbox_bind_mounts.clone();
+    let annotated = match config.annotations.get(KATA_ANNO_CFG_SANDBOX_BIND_MOUNTS) {
+        Some(annotated) => annotated,
+        None => return Ok(mounts),
+    };
+
+    // the prefixes missing on the host allow nothing
+    let prefixes: Vec<PathBuf> = config
+        .runtime
+        .valid_sandbox_bind_mount_prefixes
+        .iter()
+        .filter_map(|p| fs::canonicalize(p).ok())
+        .collect();
+    for bind in annotated.split_ascii_whitespace() {
+        let (path, mode) = split_bind_mounts(bind);
+        let real_path = allowed_bind_mount(path, &prefixes)
+            .with_context(|| format!("sandbox bind mount {} of the annotation", bind))?;
+        if let Some(m) = mounts
+            .iter()
+            .find(|m| Path::new(split_bind_mounts(m).0) == real_path)
+        {
+            warn!(
+                sl!(),
+                "sandbox bind mount {} of the annotation is given already as {}", bind, m
+            );
+            continue;
+        }
+        mounts.push(format!("{}{}", real_path.display(), mode));
+    }
+    Ok(mounts)
+}
+
+// allowed_bind_mount returns the real path of the annotated host path if it's
+// under one of the real prefixes
+fn allowed_bind_mount(path: &str, prefixes: &[PathBuf]) -> Result<PathBuf> {
+    let path = Path::new(path);
+    if !path.is_absolute() {
+        return Err(anyhow!("{} isn't an absolute path", path.display()));
+    }
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(anyhow!("{} has a parent dir component", path.display()));
+    }
+    let real_path = fs::canonicalize(path).with_context(|| format!("canonicalize {:?}", path))?;
+    // compared by components, /data-other isn't under /data
+    if !prefixes.iter().any(|p| real_path.starts_with(p)) {
+        return Err(anyhow!(
+            "{} isn't under the valid prefixes {:?}",
+            real_path.display(),
+            prefixes
+        ));
+    }
+    Ok(real_path)
+}
 
 #[derive(Clone, Default, Debug)]
 pub struct SandboxBindMounts {
@@ -59,10 +126,25 @@ impl SandboxBindMounts {
         Ok((bindmount_mode, bindmount))
     }
 
-    pub fn setup_sandbox_bind_mounts(&self) -> Result<()> {
+    /// setup_sandbox_bind_mounts bind mounts the host paths into the shared
+    /// directory of the sandbox, once their symlinks are resolved beneath
+    /// the prefixes allowed by the jail.
+    pub(crate) fn setup_sandbox_bind_mounts(&self, jail: &PathJail) -> Result<()> {
+        // all of them are checked before anything is mounted
+        let mut resolved = Vec::with_capacity(self.sandbox_bindmounts.len());
+        for src in &self.sandbox_bindmounts {
+            let (_, bindmount) = self
+                .parse_sandbox_bind_mounts(src)
+                .context("parse sandbox bind mounts failed")?;
+            resolved.push(
+                jail.safe_resolve(bindmount)
+                    .with_context(|| format!("sandbox bind mount {}", bindmount))?,
+            );
+        }
+
         let mut mounted_list: Vec<PathBuf> = Vec::new();
         let mut mounted_map: HashMap<String, bool> = HashMap::new();
-        for src in &self.sandbox_bindmounts {
+        for (src, resolved) in self.sandbox_bindmounts.iter().zip(resolved) {
             let (bindmount_mode, bindmount) = self
                 .parse_sandbox_bind_mounts(src)
                 .context("parse sandbox bind mounts failed")?;
@@ -101,7 +183,7 @@ impl SandboxBindMounts {
 
             // mount -o bind,ro host_shared mount_dest
             // host_shared: ${bindmount}
-            mount::bind_mount_unchecked(Path::new(bindmount), &mount_dest, true).map_err(|e| {
+            mount::bind_mount_unchecked(&resolved, &mount_dest, true).map_err(|e| {
                 for p in &mounted_list {
                     nix::mount::umount(p).unwrap_or_else(|x| {
                         format!("do umount failed: {:?}", x);
@@ -128,7 +210,13 @@ impl SandboxBindMounts {
         Ok(())
     }
 
+    /// cleanup_sandbox_bind_mounts umounts the sandbox bind mounts, the ones
+    /// gone already are skipped, so that it could be retried.
     pub fn cleanup_sandbox_bind_mounts(&self) -> Result<()> {
+        if !self.host_mounts_path.exists() {
+            return Ok(());
+        }
+
         for src in &self.sandbox_bindmounts {
             let parsed_mnts = self
                 .parse_sandbox_bind_mounts(src)
@@ -140,16 +228,106 @@ impl SandboxBindMounts {
 
             // /run/kata-containers/shared/sandboxes/<sid>/passthrough/rw/sandbox-mounts/dir
             let mnt_dest = self.host_mounts_path.join(mnt_name.as_str());
-            mount::umount_timeout(mnt_dest, 0).context("umount bindmount failed")?;
+            if let Err(e) = mount::umount_timeout(mnt_dest, 0) {
+                if !is_umounted(&e) {
+                    return Err(e).context("umount bindmount failed");
+                }
+            }
         }
 
-        if fs::metadata(self.host_mounts_path.clone())?.is_dir() {
-            fs::remove_dir_all(self.host_mounts_path.clone()).context(format!(
-                "remove sandbox bindmount point {:?}.",
-                self.host_mounts_path.clone()
-            ))?;
-        }
+        fs::remove_dir_all(self.host_mounts_path.clone()).context(format!(
+            "remove sandbox bindmount point {:?}.",
+            self.host_mounts_path.clone()
+        ))?;
 
         Ok(())
     }
 }
+
+// EINVAL is returned for the path which isn't a mount point, ENOENT for the
+// one removed
+fn is_umounted(e: &mount::Error) -> bool {
+    match e {
+        mount::Error::Umount(_, e) | mount::Error::ReadMetadata(_, e) => matches!(
+            e.kind(),
+            std::io::ErrorKind::InvalidInput | std::io::ErrorKind::NotFound
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn test_effective_bind_mounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let tenants = dir.path().join("tenants");
+        let cache = tenants.join("cache");
+        let secrets = dir.path().join("secrets");
+        fs::create_dir_all(&cache).unwrap();
+        fs::create_dir_all(&secrets).unwrap();
+        fs::create_dir_all(dir.path().join("tenants-other")).unwrap();
+        let cache = fs::canonicalize(cache).unwrap();
+        let secrets = fs::canonicalize(secrets).unwrap();
+
+        let mut config = TomlConfig {
+            ..Default::default()
+        };
+        config.runtime.sandbox_bind_mounts = vec![format!("{}:rw", cache.display())];
+        assert_eq!(
+            effective_bind_mounts(&config).unwrap(),
+            config.runtime.sandbox_bind_mounts
+        );
+
+        // refused without the valid prefixes
+        let annotate = |config: &mut TomlConfig, value: String| {
+            config
+                .annotations
+                .insert(KATA_ANNO_CFG_SANDBOX_BIND_MOUNTS.to_string(), value);
+        };
+        annotate(&mut config, format!("{}", secrets.display()));
+        assert!(effective_bind_mounts(&config).is_err());
+
+        // the escapes out of the prefixes
+        config.runtime.valid_sandbox_bind_mount_prefixes = vec![tenants.display().to_string()];
+        let escape = tenants.join("escape");
+        symlink(&secrets, &escape).unwrap();
+        for path in [
+            format!("{}/../secrets", tenants.display()),
+            format!("{}:ro", escape.display()),
+            format!("{}", secrets.display()),
+            "tenants/cache".to_string(),
+            format!("{}-other", tenants.display()),
+        ] {
+            annotate(&mut config, path.clone());
+            assert!(effective_bind_mounts(&config).is_err(), "{}", path);
+        }
+
+        // the duplicates of the config and of the annotation are mounted once
+        let models = tenants.join("models");
+        fs::create_dir(&models).unwrap();
+        let models = fs::canonicalize(models).unwrap();
+        annotate(
+            &mut config,
+            format!(
+                "{} {}:ro {}/./ {}:rw",
+                cache.display(),
+                models.display(),
+                models.display(),
+                tenants.join("link").display()
+            ),
+        );
+        symlink(&cache, tenants.join("link")).unwrap();
+        assert_eq!(
+            effective_bind_mounts(&config).unwrap(),
+            vec![
+                format!("{}:rw", cache.display()),
+                format!("{}:ro", models.display())
+            ]
+        );
+    }
+}
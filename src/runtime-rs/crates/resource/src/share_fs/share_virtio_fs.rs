@@ -19,17 +19,62 @@ use hypervisor::{
 };
 use kata_sys_util::mount;
 
-use super::{utils, PASSTHROUGH_FS_DIR};
+use super::{utils, KATA_GUEST_EXPORTS_DIR, KATA_GUEST_SHARE_DIR, PASSTHROUGH_FS_DIR};
 
 pub(crate) const MOUNT_GUEST_TAG: &str = "kataShared";
 
 pub(crate) const FS_TYPE_VIRTIO_FS: &str = "virtiofs";
 pub(crate) const KATA_VIRTIO_FS_DEV_TYPE: &str = "virtio-fs";
+// the driver option of the virtio-fs storage telling the agent whether the
+// share has the extended attributes of the files
+const XATTR_DRIVER_OPTION: &str = "xattr=";
 
 const VIRTIO_FS_SOCKET: &str = "virtiofsd.sock";
 
-pub(crate) fn generate_sock_path(root: &str) -> String {
-    let socket_path = Path::new(root).join(VIRTIO_FS_SOCKET);
+/// ShareFsExport names a virtio-fs export: the tag of its device, the socket
+/// of its virtiofsd and where the guest mounts it. The names of the export of
+/// a container are made of its index, so that they're the same every time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ShareFsExport {
+    pub tag: String,
+    pub sock_name: String,
+    pub guest_dir: String,
+}
+
+impl ShareFsExport {
+    /// sandbox is the export of the sandbox, all the containers share it
+    /// without per_container_sharefs.
+    pub(crate) fn sandbox() -> Self {
+        Self {
+            tag: MOUNT_GUEST_TAG.to_string(),
+            sock_name: VIRTIO_FS_SOCKET.to_string(),
+            guest_dir: KATA_GUEST_SHARE_DIR.to_string(),
+        }
+    }
+
+    /// container is the export given to a container with
+    /// per_container_sharefs.
+    pub(crate) fn container(index: usize) -> Self {
+        Self {
+            tag: format!("{}-{}", MOUNT_GUEST_TAG, index),
+            sock_name: format!("virtiofsd-{}.sock", index),
+            guest_dir: format!("{}{}/", KATA_GUEST_EXPORTS_DIR, index),
+        }
+    }
+}
+
+/// xattr_driver_option is the driver option of the virtio-fs storage of the
+/// backend serving the extended attributes or not.
+pub(crate) fn xattr_driver_option(enabled: bool) -> String {
+    format!(
+        "{}{}",
+        XATTR_DRIVER_OPTION,
+        if enabled { "on" } else { "off" }
+    )
+}
+
+pub(crate) fn generate_sock_path(root: &str, sock_name: &str) -> String {
+    let socket_path = Path::new(root).join(sock_name);
     socket_path.to_str().unwrap().to_string()
 }
 
@@ -38,7 +83,15 @@ pub(crate) async fn prepare_virtiofs(
     fs_type: &str,
     id: &str,
     root: &str,
+    export: &ShareFsExport,
 ) -> Result<()> {
+    prepare_shared_dirs(id)?;
+    add_virtiofs_device(h, fs_type, id, root, export).await
+}
+
+// prepare_shared_dirs mounts the directory shared read-write on the host
+// read-only where virtiofsd serves it
+pub(crate) fn prepare_shared_dirs(id: &str) -> Result<()> {
     let host_ro_dest = utils::get_host_ro_shared_path(id);
     utils::ensure_dir_exist(&host_ro_dest)?;
 
@@ -46,12 +99,23 @@ pub(crate) async fn prepare_virtiofs(
     utils::ensure_dir_exist(&host_rw_dest)?;
 
     mount::bind_mount_unchecked(&host_rw_dest, &host_ro_dest, true)
-        .context("bind mount shared_fs directory")?;
+        .context("bind mount shared_fs directory")
+}
 
+// add_virtiofs_device adds the virtio-fs device of the export to the VM, it's
+// hotplugged if the VM is running
+pub(crate) async fn add_virtiofs_device(
+    h: &dyn Hypervisor,
+    fs_type: &str,
+    id: &str,
+    root: &str,
+    export: &ShareFsExport,
+) -> Result<()> {
+    let host_ro_dest = utils::get_host_ro_shared_path(id);
     let share_fs_device = ShareFsDevice {
         config: ShareFsDeviceConfig {
-            sock_path: generate_sock_path(root),
-            mount_tag: String::from(MOUNT_GUEST_TAG),
+            sock_path: generate_sock_path(root, &export.sock_name),
+            mount_tag: export.tag.clone(),
             host_path: String::from(host_ro_dest.to_str().unwrap()),
             fs_type: fs_type.to_string(),
             queue_size: 0,
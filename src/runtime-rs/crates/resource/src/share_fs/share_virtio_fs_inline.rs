@@ -15,8 +15,8 @@ use tokio::sync::Mutex;
 
 use super::{
     share_virtio_fs::{
-        prepare_virtiofs, setup_inline_virtiofs, FS_TYPE_VIRTIO_FS, KATA_VIRTIO_FS_DEV_TYPE,
-        MOUNT_GUEST_TAG,
+        prepare_virtiofs, setup_inline_virtiofs, xattr_driver_option, ShareFsExport,
+        FS_TYPE_VIRTIO_FS, KATA_VIRTIO_FS_DEV_TYPE, MOUNT_GUEST_TAG,
     },
     ShareFs, *,
 };
@@ -28,6 +28,9 @@ lazy_static! {
 #[derive(Debug, Clone)]
 pub struct ShareVirtioFsInlineConfig {
     pub id: String,
+    // enable_xattr enables the extended attributes, the VMM enables them by
+    // default
+    pub enable_xattr: bool,
 }
 
 pub struct ShareVirtioFsInline {
@@ -37,9 +40,12 @@ pub struct ShareVirtioFsInline {
 }
 
 impl ShareVirtioFsInline {
-    pub(crate) fn new(id: &str, _config: &SharedFsInfo) -> Result<Self> {
+    pub(crate) fn new(id: &str, config: &SharedFsInfo) -> Result<Self> {
         Ok(Self {
-            config: ShareVirtioFsInlineConfig { id: id.to_string() },
+            config: ShareVirtioFsInlineConfig {
+                id: id.to_string(),
+                enable_xattr: config.enable_xattr.unwrap_or(true),
+            },
             share_fs_mount: Arc::new(VirtiofsShareMount::new(id)),
             mounted_info_set: Arc::new(Mutex::new(HashMap::new())),
         })
@@ -53,12 +59,23 @@ impl ShareFs for ShareVirtioFsInline {
     }
 
     async fn setup_device_before_start_vm(&self, h: &dyn Hypervisor) -> Result<()> {
-        prepare_virtiofs(h, INLINE_VIRTIO_FS, &self.config.id, "")
-            .await
-            .context("prepare virtiofs")?;
+        prepare_virtiofs(
+            h,
+            INLINE_VIRTIO_FS,
+            &self.config.id,
+            "",
+            &ShareFsExport::sandbox(),
+        )
+        .await
+        .context("prepare virtiofs")?;
         Ok(())
     }
 
+    // the VMM serves the inline virtio-fs itself, there's no daemon to start
+    async fn hotplug_device(&self, h: &dyn Hypervisor) -> Result<()> {
+        self.setup_device_before_start_vm(h).await
+    }
+
     async fn setup_device_after_start_vm(&self, h: &dyn Hypervisor) -> Result<()> {
         setup_inline_virtiofs(&self.config.id, h)
             .await
@@ -71,7 +88,7 @@ impl ShareFs for ShareVirtioFsInline {
 
         let shared_volume: Storage = Storage {
             driver: String::from(KATA_VIRTIO_FS_DEV_TYPE),
-            driver_options: Vec::new(),
+            driver_options: vec![xattr_driver_option(self.config.enable_xattr)],
             source: String::from(MOUNT_GUEST_TAG),
             fs_type: String::from(FS_TYPE_VIRTIO_FS),
             fs_group: None,
@@ -87,3 +104,22 @@ impl ShareFs for ShareVirtioFsInline {
         self.mounted_info_set.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_storage_xattr() {
+        let mut config = SharedFsInfo::default();
+        let share_fs = ShareVirtioFsInline::new("sid", &config).unwrap();
+        // the VMM enables them by default
+        let storages = share_fs.get_storages().await.unwrap();
+        assert_eq!(storages[0].driver_options, vec!["xattr=on"]);
+
+        config.enable_xattr = Some(false);
+        let share_fs = ShareVirtioFsInline::new("sid", &config).unwrap();
+        let storages = share_fs.get_storages().await.unwrap();
+        assert_eq!(storages[0].driver_options, vec!["xattr=off"]);
+    }
+}
@@ -4,31 +4,77 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::{collections::HashMap, process::Stdio, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
 
+use crate::events::{ResourceEventKind, ResourceEvents};
+use crate::metrics;
 use crate::share_fs::share_virtio_fs::{
-    prepare_virtiofs, FS_TYPE_VIRTIO_FS, KATA_VIRTIO_FS_DEV_TYPE, MOUNT_GUEST_TAG,
+    add_virtiofs_device, prepare_shared_dirs, prepare_virtiofs, xattr_driver_option, ShareFsExport,
+    FS_TYPE_VIRTIO_FS, KATA_VIRTIO_FS_DEV_TYPE,
 };
-use crate::share_fs::{KATA_GUEST_SHARE_DIR, VIRTIO_FS};
+use crate::share_fs::VIRTIO_FS;
 use agent::Storage;
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use hypervisor::Hypervisor;
+use kata_sys_util::mount::{self, umount_all};
 use kata_types::config::hypervisor::SharedFsInfo;
 use tokio::{
+    fs,
     io::{AsyncBufReadExt, BufReader},
     process::{Child, Command},
     sync::{
-        mpsc::{channel, Receiver, Sender},
+        mpsc::{channel, error::TryRecvError, Receiver, Sender},
         Mutex, RwLock,
     },
+    time,
 };
 
 use super::{
-    share_virtio_fs::generate_sock_path, utils::ensure_dir_exist, utils::get_host_ro_shared_path,
-    virtio_fs_share_mount::VirtiofsShareMount, MountedInfo, ShareFs, ShareFsMount,
+    share_virtio_fs::generate_sock_path,
+    utils::{
+        ensure_dir_exist, get_host_allowed_shared_path, get_host_ro_shared_path,
+        get_host_rw_shared_path,
+    },
+    virtio_fs_share_mount::VirtiofsShareMount,
+    MountedInfo, ShareFs, ShareFsMount,
 };
 
+// the last lines of stderr virtiofsd writes before it's ready, kept for the
+// error if it doesn't get ready
+const EARLY_STDERR_LINES: usize = 20;
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const PROC_NET_UNIX: &str = "/proc/net/unix";
+// __SO_ACCEPTCON, the flag of the listening sockets in /proc/net/unix
+const SO_ACCEPTCON: u32 = 1 << 16;
+
+/// VirtiofsdNotReady is the error of virtiofsd which doesn't listen on its
+/// vhost-user socket in time, or exits before, with its early stderr.
+#[derive(Debug)]
+pub struct VirtiofsdNotReady {
+    pub reason: String,
+    pub stderr: Vec<String>,
+}
+
+impl fmt::Display for VirtiofsdNotReady {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "virtiofsd isn't ready: {}", self.reason)?;
+        if self.stderr.is_empty() {
+            return write!(f, ", no stderr");
+        }
+        write!(f, ", stderr: {}", self.stderr.join("; "))
+    }
+}
+
+impl std::error::Error for VirtiofsdNotReady {}
+
 #[derive(Debug, Clone)]
 pub struct ShareVirtioFsStandaloneConfig {
     id: String,
@@ -39,6 +85,27 @@ pub struct ShareVirtioFsStandaloneConfig {
     pub virtio_fs_cache: String,
     // virtio_fs_extra_args passes options to virtiofsd daemon
     pub virtio_fs_extra_args: Vec<String>,
+    // enable_xattr enables the extended attributes, virtiofsd disables them
+    // by default
+    pub enable_xattr: Option<bool>,
+    // ready_timeout is how long virtiofsd is waited for to listen on its
+    // socket
+    pub ready_timeout: Duration,
+    // allowed_dirs are the subdirectories of the shared directory virtiofsd
+    // is confined to, all of it is served if empty
+    pub allowed_dirs: Vec<String>,
+    // export names the device, the socket and the guest mount point, the
+    // ones of the sandbox or of a container
+    export: ShareFsExport,
+}
+
+impl ShareVirtioFsStandaloneConfig {
+    // xattr_enabled tells whether virtiofsd serves the extended attributes,
+    // the extra args may enable them unless enable_xattr is set
+    fn xattr_enabled(&self) -> bool {
+        self.enable_xattr
+            .unwrap_or_else(|| self.virtio_fs_extra_args.iter().any(|a| a == "--xattr"))
+    }
 }
 
 #[derive(Default, Debug)]
@@ -51,10 +118,17 @@ pub(crate) struct ShareVirtioFsStandalone {
     config: ShareVirtioFsStandaloneConfig,
     share_fs_mount: Arc<dyn ShareFsMount>,
     mounted_info_set: Arc<Mutex<HashMap<String, MountedInfo>>>,
+    // the health transitions of virtiofsd are emitted as events
+    events: Arc<ResourceEvents>,
 }
 
 impl ShareVirtioFsStandalone {
-    pub(crate) fn new(id: &str, config: &SharedFsInfo) -> Result<Self> {
+    pub(crate) fn new(
+        id: &str,
+        config: &SharedFsInfo,
+        export: ShareFsExport,
+        events: Arc<ResourceEvents>,
+    ) -> Result<Self> {
         Ok(Self {
             inner: Arc::new(RwLock::new(ShareVirtioFsStandaloneInner::default())),
             config: ShareVirtioFsStandaloneConfig {
@@ -62,42 +136,39 @@ impl ShareVirtioFsStandalone {
                 virtio_fs_daemon: config.virtio_fs_daemon.clone(),
                 virtio_fs_cache: config.virtio_fs_cache.clone(),
                 virtio_fs_extra_args: config.virtio_fs_extra_args.clone(),
+                enable_xattr: config.enable_xattr,
+                ready_timeout: Duration::from_secs(config.virtio_fs_ready_timeout_secs as u64),
+                allowed_dirs: config.virtio_fs_allowed_dirs.clone(),
+                export: export.clone(),
             },
-            share_fs_mount: Arc::new(VirtiofsShareMount::new(id)),
+            share_fs_mount: Arc::new(
+                VirtiofsShareMount::with_allowed_dirs(id, &config.virtio_fs_allowed_dirs)
+                    .with_guest_dir(&export.guest_dir),
+            ),
             mounted_info_set: Arc::new(Mutex::new(HashMap::new())),
+            events,
         })
     }
 
     fn virtiofsd_args(&self, sock_path: &str) -> Result<Vec<String>> {
-        let source_path = get_host_ro_shared_path(&self.config.id);
-        ensure_dir_exist(&source_path)?;
+        let source_path = if self.config.allowed_dirs.is_empty() {
+            let source_path = get_host_ro_shared_path(&self.config.id);
+            ensure_dir_exist(&source_path)?;
+            source_path
+        } else {
+            prepare_allowed_dirs(&self.config.id, &self.config.allowed_dirs)
+                .context("prepare allowed dirs")?
+        };
         let shared_dir = source_path
             .to_str()
             .ok_or_else(|| anyhow!("convert source path {:?} to str failed", source_path))?;
 
-        let mut args: Vec<String> = vec![
-            String::from("--socket-path"),
-            String::from(sock_path),
-            String::from("--shared-dir"),
-            String::from(shared_dir),
-            String::from("--cache"),
-            self.config.virtio_fs_cache.clone(),
-            String::from("--sandbox"),
-            String::from("none"),
-            String::from("--seccomp"),
-            String::from("none"),
-        ];
-
-        if !self.config.virtio_fs_extra_args.is_empty() {
-            let mut extra_args: Vec<String> = self.config.virtio_fs_extra_args.clone();
-            args.append(&mut extra_args);
-        }
-
-        Ok(args)
+        Ok(virtiofsd_args(&self.config, sock_path, shared_dir))
     }
 
     async fn setup_virtiofsd(&self, h: &dyn Hypervisor) -> Result<()> {
-        let sock_path = generate_sock_path(&h.get_jailer_root().await?);
+        let sock_path =
+            generate_sock_path(&h.get_jailer_root().await?, &self.config.export.sock_name);
         let args = self.virtiofsd_args(&sock_path).context("virtiofsd args")?;
 
         let mut cmd = Command::new(&self.config.virtio_fs_daemon);
@@ -111,22 +182,37 @@ impl ShareVirtioFsStandalone {
         }
 
         let (tx, mut rx): (Sender<Result<()>>, Receiver<Result<()>>) = channel(100);
-        tokio::spawn(run_virtiofsd(child, tx));
+        let early_stderr = Arc::new(StdMutex::new(vec![]));
+        tokio::spawn(run_virtiofsd(
+            child,
+            tx,
+            self.inner.clone(),
+            self.events.clone(),
+            early_stderr.clone(),
+        ));
 
-        // TODO: support timeout
-        match rx.recv().await.unwrap() {
-            Ok(_) => {
+        // the VM fails to probe the virtio-fs device if it starts before
+        // virtiofsd listens on the socket
+        let timeout = self.config.ready_timeout;
+        let reason = match time::timeout(timeout, wait_virtiofsd_ready(&mut rx, &sock_path)).await {
+            Ok(Ok(())) => {
                 info!(sl!(), "start virtiofsd successfully");
-                Ok(())
-            }
-            Err(e) => {
-                error!(sl!(), "failed to start virtiofsd {}", e);
-                self.shutdown_virtiofsd()
-                    .await
-                    .context("shutdown_virtiofsd")?;
-                Err(anyhow!("failed to start virtiofsd"))
+                self.events.emit(ResourceEventKind::ShareFsHealthChanged {
+                    healthy: true,
+                    reason: "virtiofsd started".to_string(),
+                });
+                return Ok(());
             }
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => format!("not listening on socket {} after {:?}", sock_path, timeout),
+        };
+
+        error!(sl!(), "failed to start virtiofsd: {}", reason);
+        if let Err(e) = self.shutdown_virtiofsd().await {
+            warn!(sl!(), "couldn't shut down virtiofsd: {:?}", e);
         }
+        let stderr = early_stderr.lock().unwrap().clone();
+        Err(VirtiofsdNotReady { reason, stderr }.into())
     }
 
     async fn shutdown_virtiofsd(&self) -> Result<()> {
@@ -147,22 +233,159 @@ impl ShareVirtioFsStandalone {
     }
 }
 
-async fn run_virtiofsd(mut child: Child, tx: Sender<Result<()>>) -> Result<()> {
+// prepare_allowed_dirs makes the directory served by virtiofsd confined to
+// the allowed dirs: a read-only directory where only them are bound from the
+// ro shared directory. virtiofsd pivots into it in its mount namespace, the
+// rest of the host is out of its reach.
+fn prepare_allowed_dirs(id: &str, allowed_dirs: &[String]) -> Result<PathBuf> {
+    let ro_path = get_host_ro_shared_path(id);
+    let rw_path = get_host_rw_shared_path(id);
+    let allowed_path = get_host_allowed_shared_path(id);
+    ensure_dir_exist(&allowed_path)?;
+    // the mount points are made before the directory is read-only
+    for dir in allowed_dirs.iter() {
+        ensure_dir_exist(&rw_path.join(dir))?;
+        ensure_dir_exist(&allowed_path.join(dir))?;
+    }
+    mount::bind_mount_unchecked(&allowed_path, &allowed_path, true)
+        .with_context(|| format!("bind mount {:?} read-only", allowed_path))?;
+    for dir in allowed_dirs.iter() {
+        let (source, target) = (ro_path.join(dir), allowed_path.join(dir));
+        if let Err(e) = mount::bind_mount_unchecked(&source, &target, true) {
+            if let Err(e) = umount_all(&allowed_path, true) {
+                warn!(sl!(), "failed to umount {:?}: {:?}", allowed_path, e);
+            }
+            return Err(e).with_context(|| format!("bind mount {:?} to {:?}", source, target));
+        }
+    }
+    info!(
+        sl!(),
+        "virtiofsd is confined to {:?} of {:?}", allowed_dirs, ro_path
+    );
+    Ok(allowed_path)
+}
+
+fn virtiofsd_args(
+    config: &ShareVirtioFsStandaloneConfig,
+    sock_path: &str,
+    shared_dir: &str,
+) -> Vec<String> {
+    // virtiofsd is sandboxed in a mount namespace if it's confined to the
+    // allowed dirs, the ones out of them aren't in there
+    let sandbox = if config.allowed_dirs.is_empty() {
+        "none"
+    } else {
+        "namespace"
+    };
+    let mut args: Vec<String> = vec![
+        String::from("--socket-path"),
+        String::from(sock_path),
+        String::from("--shared-dir"),
+        String::from(shared_dir),
+        String::from("--cache"),
+        config.virtio_fs_cache.clone(),
+        String::from("--sandbox"),
+        String::from(sandbox),
+        String::from("--seccomp"),
+        String::from("none"),
+    ];
+
+    if config.enable_xattr == Some(true) {
+        args.push(String::from("--xattr"));
+    }
+
+    if !config.virtio_fs_extra_args.is_empty() {
+        let mut extra_args: Vec<String> = config.virtio_fs_extra_args.clone();
+        args.append(&mut extra_args);
+    }
+
+    args
+}
+
+// wait_virtiofsd_ready waits until virtiofsd tells it's waiting for the
+// connection of the VMM, or its socket is seen listening, whichever comes
+// first. It fails if virtiofsd exits before.
+async fn wait_virtiofsd_ready(rx: &mut Receiver<Result<()>>, sock_path: &str) -> Result<()> {
+    loop {
+        match rx.try_recv() {
+            Ok(result) => return result,
+            Err(TryRecvError::Disconnected) => return Err(anyhow!("virtiofsd exited")),
+            Err(TryRecvError::Empty) => {}
+        }
+        // procfs is read off the executor, unlike a connection to the socket
+        // it doesn't take the one connection virtiofsd accepts
+        if let Ok(content) = fs::read_to_string(PROC_NET_UNIX).await {
+            if is_unix_socket_listening(&content, Path::new(sock_path)) {
+                return Ok(());
+            }
+        }
+        time::sleep(READY_POLL_INTERVAL).await;
+    }
+}
+
+// is_unix_socket_listening tells if the unix socket bound to the path is
+// listening in the content of /proc/net/unix
+fn is_unix_socket_listening(content: &str, path: &Path) -> bool {
+    // Num RefCount Protocol Flags Type St Inode Path
+    content.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.len() >= 8
+            && Path::new(fields[7]) == path
+            && u32::from_str_radix(fields[3], 16)
+                .map(|flags| flags & SO_ACCEPTCON != 0)
+                .unwrap_or(false)
+    })
+}
+
+async fn run_virtiofsd(
+    mut child: Child,
+    tx: Sender<Result<()>>,
+    inner: Arc<RwLock<ShareVirtioFsStandaloneInner>>,
+    events: Arc<ResourceEvents>,
+    early_stderr: Arc<StdMutex<Vec<String>>>,
+) -> Result<()> {
+    let pid = child.id();
     let stderr = child.stderr.as_mut().unwrap();
     let stderr_reader = BufReader::new(stderr);
     let mut lines = stderr_reader.lines();
+    let mut ready = false;
 
     while let Some(buffer) = lines.next_line().await.context("read next line")? {
         let trim_buffer = buffer.trim_end();
         if !trim_buffer.is_empty() {
             info!(sl!(), "source: virtiofsd {}", trim_buffer);
+            if !ready {
+                let mut early_stderr = early_stderr.lock().unwrap();
+                if early_stderr.len() == EARLY_STDERR_LINES {
+                    early_stderr.remove(0);
+                }
+                early_stderr.push(trim_buffer.to_string());
+            }
         }
-        if buffer.contains("Waiting for vhost-user socket connection") {
-            tx.send(Ok(())).await.unwrap();
+        if !ready && buffer.contains("Waiting for vhost-user socket connection") {
+            ready = true;
+            // the setup may have seen the socket listening already
+            tx.send(Ok(())).await.ok();
         }
     }
 
-    info!(sl!(), "wait virtiofsd {:?}", child.wait().await);
+    let status = child.wait().await;
+    info!(sl!(), "wait virtiofsd {:?}", status);
+    if !ready {
+        tx.send(Err(anyhow!("virtiofsd exited: {:?}", status)))
+            .await
+            .ok();
+    }
+    // forget the pid once reaped, it might be reused by another process
+    let mut inner = inner.write().await;
+    if inner.pid == pid {
+        inner.pid = None;
+        // the pid is taken when virtiofsd is shut down or killed on purpose
+        events.emit(ResourceEventKind::ShareFsHealthChanged {
+            healthy: false,
+            reason: format!("virtiofsd exited: {:?}", status),
+        });
+    }
     Ok(())
 }
 
@@ -173,10 +396,22 @@ impl ShareFs for ShareVirtioFsStandalone {
     }
 
     async fn setup_device_before_start_vm(&self, h: &dyn Hypervisor) -> Result<()> {
-        prepare_virtiofs(h, VIRTIO_FS, &self.config.id, &h.get_jailer_root().await?)
-            .await
-            .context("prepare virtiofs")?;
-        self.setup_virtiofsd(h).await.context("setup virtiofsd")?;
+        prepare_virtiofs(
+            h,
+            VIRTIO_FS,
+            &self.config.id,
+            &h.get_jailer_root().await?,
+            &self.config.export,
+        )
+        .await
+        .context("prepare virtiofs")?;
+        metrics::time(
+            metrics::OP_VIRTIOFSD_START,
+            VIRTIO_FS,
+            self.setup_virtiofsd(h),
+        )
+        .await
+        .context("setup virtiofsd")?;
         Ok(())
     }
 
@@ -184,17 +419,37 @@ impl ShareFs for ShareVirtioFsStandalone {
         Ok(())
     }
 
+    async fn hotplug_device(&self, h: &dyn Hypervisor) -> Result<()> {
+        prepare_shared_dirs(&self.config.id).context("prepare shared dirs")?;
+        metrics::time(
+            metrics::OP_VIRTIOFSD_START,
+            VIRTIO_FS,
+            self.setup_virtiofsd(h),
+        )
+        .await
+        .context("setup virtiofsd")?;
+        add_virtiofs_device(
+            h,
+            VIRTIO_FS,
+            &self.config.id,
+            &h.get_jailer_root().await?,
+            &self.config.export,
+        )
+        .await
+        .context("hotplug virtiofs device")
+    }
+
     async fn get_storages(&self) -> Result<Vec<Storage>> {
         let mut storages: Vec<Storage> = Vec::new();
 
         let shared_volume: Storage = Storage {
             driver: String::from(KATA_VIRTIO_FS_DEV_TYPE),
-            driver_options: Vec::new(),
-            source: String::from(MOUNT_GUEST_TAG),
+            driver_options: vec![xattr_driver_option(self.config.xattr_enabled())],
+            source: self.config.export.tag.clone(),
             fs_type: String::from(FS_TYPE_VIRTIO_FS),
             fs_group: None,
             options: vec![String::from("nodev")],
-            mount_point: String::from(KATA_GUEST_SHARE_DIR),
+            mount_point: self.config.export.guest_dir.clone(),
         };
 
         storages.push(shared_volume);
@@ -204,4 +459,126 @@ impl ShareFs for ShareVirtioFsStandalone {
     fn mounted_info_set(&self) -> Arc<Mutex<HashMap<String, MountedInfo>>> {
         self.mounted_info_set.clone()
     }
+
+    async fn check_health(&self) -> Result<()> {
+        let pid = self
+            .inner
+            .read()
+            .await
+            .pid
+            .ok_or_else(|| anyhow!("virtiofsd isn't running"))?;
+        ::nix::sys::signal::kill(::nix::unistd::Pid::from_raw(pid as i32), None)
+            .with_context(|| format!("virtiofsd pid {} is gone", pid))
+    }
+
+    fn kill_daemon(&self) -> Result<()> {
+        let mut inner = self
+            .inner
+            .try_write()
+            .map_err(|_| anyhow!("virtiofsd is being set up or shut down"))?;
+        if let Some(pid) = inner.pid.take() {
+            info!(sl!(), "kill virtiofsd pid {}", pid);
+            let pid = ::nix::unistd::Pid::from_raw(pid as i32);
+            if let Err(err) = ::nix::sys::signal::kill(pid, nix::sys::signal::SIGKILL) {
+                if err != ::nix::Error::ESRCH {
+                    return Err(anyhow!("failed to kill virtiofsd pid {} {}", pid, err));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtiofsd_args_xattr() {
+        let mut config = ShareVirtioFsStandaloneConfig {
+            id: String::from("sid"),
+            virtio_fs_daemon: String::from("/usr/libexec/virtiofsd"),
+            virtio_fs_cache: String::from("auto"),
+            virtio_fs_extra_args: vec![String::from("--thread-pool-size=1")],
+            enable_xattr: None,
+            ready_timeout: Duration::from_secs(10),
+            allowed_dirs: vec![],
+            export: ShareFsExport::sandbox(),
+        };
+
+        // virtiofsd keeps its default
+        let args = virtiofsd_args(&config, "/run/vfsd.sock", "/run/shared");
+        assert!(!args.contains(&String::from("--xattr")));
+        assert_eq!(args.last().unwrap(), "--thread-pool-size=1");
+        assert!(!config.xattr_enabled());
+        config.virtio_fs_extra_args.push(String::from("--xattr"));
+        assert!(config.xattr_enabled());
+        config.virtio_fs_extra_args.pop();
+
+        config.enable_xattr = Some(false);
+        let args = virtiofsd_args(&config, "/run/vfsd.sock", "/run/shared");
+        assert!(!args.contains(&String::from("--xattr")));
+
+        config.enable_xattr = Some(true);
+        let args = virtiofsd_args(&config, "/run/vfsd.sock", "/run/shared");
+        assert!(args.contains(&String::from("--xattr")));
+        assert!(config.xattr_enabled());
+        assert_eq!(args.last().unwrap(), "--thread-pool-size=1");
+
+        // confined to the allowed dirs by the namespace sandbox
+        assert!(args
+            .windows(2)
+            .any(|a| a[0] == "--sandbox" && a[1] == "none"));
+        config.allowed_dirs = vec![String::from("passthrough")];
+        let args = virtiofsd_args(&config, "/run/vfsd.sock", "/run/allowed");
+        assert!(args
+            .windows(2)
+            .any(|a| a[0] == "--sandbox" && a[1] == "namespace"));
+    }
+
+    #[test]
+    fn test_is_unix_socket_listening() {
+        let content = "Num       RefCount Protocol Flags    Type St Inode Path
+0000000000000000: 00000002 00000000 00010000 0001 01 31822 /run/vc/vm/sid/vhost-fs.sock
+0000000000000000: 00000003 00000000 00000000 0001 03 31825 /run/vc/vm/sid2/vhost-fs.sock
+0000000000000000: 00000003 00000000 00000000 0001 03 24312
+";
+        assert!(is_unix_socket_listening(
+            content,
+            Path::new("/run/vc/vm/sid/vhost-fs.sock")
+        ));
+        // connected, not listening
+        assert!(!is_unix_socket_listening(
+            content,
+            Path::new("/run/vc/vm/sid2/vhost-fs.sock")
+        ));
+        assert!(!is_unix_socket_listening(
+            content,
+            Path::new("/run/vc/vm/sid3/vhost-fs.sock")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_wait_virtiofsd_ready() {
+        let (tx, mut rx) = channel(1);
+        tx.send(Ok(())).await.unwrap();
+        wait_virtiofsd_ready(&mut rx, "/nonexistent.sock")
+            .await
+            .unwrap();
+
+        // virtiofsd exited before it was ready
+        drop(tx);
+        assert!(wait_virtiofsd_ready(&mut rx, "/nonexistent.sock")
+            .await
+            .is_err());
+
+        let err = VirtiofsdNotReady {
+            reason: "virtiofsd exited: exit status: 1".to_string(),
+            stderr: vec!["Error: no shared dir".to_string()],
+        };
+        assert_eq!(
+            err.to_string(),
+            "virtiofsd isn't ready: virtiofsd exited: exit status: 1, stderr: Error: no shared dir"
+        );
+    }
 }
@@ -5,15 +5,73 @@
 //
 
 use std::{
+    collections::HashMap,
     os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
+    sync::RwLock,
 };
 
 use anyhow::Result;
 use kata_sys_util::mount;
+use nix::unistd::{access, AccessFlags};
 
 use super::*;
 
+lazy_static! {
+    // the root of the shared directories of each sandbox, set from its
+    // config as it's created or restored
+    static ref HOST_SHARED_ROOTS: RwLock<HashMap<String, PathBuf>> = RwLock::new(HashMap::new());
+}
+
+/// set_host_shared_root sets the root of the shared directories of the
+/// sandbox from shared_dir_root, the default one if it's empty.
+pub(crate) fn set_host_shared_root(sid: &str, root: &str) {
+    let root = if root.is_empty() {
+        KATA_HOST_SHARED_DIR
+    } else {
+        root
+    };
+    HOST_SHARED_ROOTS
+        .write()
+        .unwrap()
+        .insert(sid.to_string(), PathBuf::from(root));
+}
+
+/// remove_host_shared_root forgets the root of the shared directories of
+/// the sandbox cleaned up.
+pub(crate) fn remove_host_shared_root(sid: &str) {
+    HOST_SHARED_ROOTS.write().unwrap().remove(sid);
+}
+
+// host_shared_root returns the root of the shared directories of the
+// sandbox the id is of, the id of the export of a container is under the
+// one of its sandbox
+fn host_shared_root(id: &str) -> PathBuf {
+    let sid = id.split('/').next().unwrap_or_default();
+    HOST_SHARED_ROOTS
+        .read()
+        .unwrap()
+        .get(sid)
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from(KATA_HOST_SHARED_DIR))
+}
+
+/// validate_host_shared_root checks the configured root of the shared
+/// directories is an existing writable directory, the default one is
+/// created as needed.
+pub(crate) fn validate_host_shared_root(root: &str) -> Result<()> {
+    if root.is_empty() {
+        return Ok(());
+    }
+    let path = Path::new(root);
+    if !path.is_dir() {
+        return Err(anyhow!("shared_dir_root {} isn't a directory", root));
+    }
+    access(path, AccessFlags::W_OK)
+        .with_context(|| format!("shared_dir_root {} isn't writable", root))?;
+    Ok(())
+}
+
 pub(crate) fn mkdir_with_permissions(path_target: PathBuf, mode: u32) -> Result<()> {
     let new_path = &path_target;
     std::fs::create_dir_all(new_path)
@@ -25,6 +83,15 @@ pub(crate) fn mkdir_with_permissions(path_target: PathBuf, mode: u32) -> Result<
     Ok(())
 }
 
+/// remove_dir_all_if_exists removes the directory, the one gone already is
+/// taken as removed, so that the cleanup could be retried.
+pub(crate) fn remove_dir_all_if_exists<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    match std::fs::remove_dir_all(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::result::Result::Ok(()),
+        r => r,
+    }
+}
+
 pub(crate) fn ensure_dir_exist(path: &Path) -> Result<()> {
     if !path.exists() {
         std::fs::create_dir_all(path).context(format!("failed to create directory {:?}", path))?;
@@ -66,15 +133,22 @@ pub(crate) fn share_to_guest(
 //
 // 3. host-guest shared files/directories are mounted one-level under /run/kata-containers/shared/sandboxes/$sbx_id/rw/passthrough and thus present to guest at one level under run/kata-containers/shared/containers/passthrough.
 pub(crate) fn get_host_ro_shared_path(id: &str) -> PathBuf {
-    Path::new(KATA_HOST_SHARED_DIR).join(id).join("ro")
+    host_shared_root(id).join(id).join("ro")
 }
 
 pub fn get_host_rw_shared_path(sid: &str) -> PathBuf {
-    Path::new(KATA_HOST_SHARED_DIR).join(sid).join("rw")
+    host_shared_root(sid).join(sid).join("rw")
+}
+
+/// get_host_allowed_shared_path is the directory virtiofsd serves if it's
+/// confined to some subdirectories of the ro shared directory, only them are
+/// bind mounted there.
+pub(crate) fn get_host_allowed_shared_path(sid: &str) -> PathBuf {
+    host_shared_root(sid).join(sid).join("allowed")
 }
 
 pub fn get_host_shared_path(sid: &str) -> PathBuf {
-    Path::new(KATA_HOST_SHARED_DIR).join(sid)
+    host_shared_root(sid).join(sid)
 }
 
 fn do_get_guest_any_path(
@@ -133,3 +207,50 @@ pub fn do_get_host_path(
     };
     path.to_str().unwrap().to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_host_shared_root() {
+        validate_host_shared_root("").unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        validate_host_shared_root(dir.path().to_str().unwrap()).unwrap();
+
+        let missing = dir.path().join("missing");
+        validate_host_shared_root(missing.to_str().unwrap()).unwrap_err();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        validate_host_shared_root(file.to_str().unwrap()).unwrap_err();
+    }
+
+    #[test]
+    fn test_host_shared_root() {
+        // each sandbox has the root of its own config, the exports of its
+        // containers as well
+        set_host_shared_root("sandbox-a", "/var/lib/kata/shared");
+        set_host_shared_root("sandbox-b", "");
+        assert_eq!(
+            get_host_rw_shared_path("sandbox-a"),
+            PathBuf::from("/var/lib/kata/shared/sandbox-a/rw")
+        );
+        assert_eq!(
+            get_host_ro_shared_path("sandbox-a/exports/0"),
+            PathBuf::from("/var/lib/kata/shared/sandbox-a/exports/0/ro")
+        );
+        assert_eq!(
+            get_host_shared_path("sandbox-b"),
+            Path::new(KATA_HOST_SHARED_DIR).join("sandbox-b")
+        );
+
+        // the default root once the sandbox is cleaned up
+        remove_host_shared_root("sandbox-a");
+        assert_eq!(
+            get_host_shared_path("sandbox-a"),
+            Path::new(KATA_HOST_SHARED_DIR).join("sandbox-a")
+        );
+        remove_host_shared_root("sandbox-b");
+    }
+}
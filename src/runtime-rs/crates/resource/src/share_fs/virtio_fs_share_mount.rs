@@ -7,7 +7,9 @@
 use agent::Storage;
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
-use kata_sys_util::mount::{bind_remount, umount_all, umount_timeout};
+use kata_sys_util::mount::{
+    bind_remount, parse_propagation, set_propagation, umount_all, umount_timeout,
+};
 use kata_types::k8s::is_watchable_mount;
 use kata_types::mount;
 use nix::sys::stat::stat;
@@ -21,8 +23,8 @@ pub const EPHEMERAL_PATH: &str = "/run/kata-containers/sandbox/ephemeral";
 use super::{
     get_host_rw_shared_path,
     utils::{
-        self, do_get_host_path, get_host_ro_shared_path, get_host_shared_path,
-        mkdir_with_permissions,
+        self, do_get_host_path, get_host_allowed_shared_path, get_host_ro_shared_path,
+        get_host_shared_path, mkdir_with_permissions, remove_dir_all_if_exists,
     },
     ShareFsMount, ShareFsMountResult, ShareFsRootfsConfig, ShareFsVolumeConfig,
     KATA_GUEST_SHARE_DIR, PASSTHROUGH_FS_DIR,
@@ -31,18 +33,83 @@ use super::{
 #[derive(Debug)]
 pub struct VirtiofsShareMount {
     id: String,
+    // the subdirectories of the ro shared directory virtiofsd is confined
+    // to, all of it if empty
+    allowed_dirs: Vec<String>,
+    // where the guest mounts the export, the one of the sandbox or of a
+    // container
+    guest_dir: String,
 }
 
 impl VirtiofsShareMount {
     pub fn new(id: &str) -> Self {
-        Self { id: id.to_string() }
+        Self::with_allowed_dirs(id, &[])
+    }
+
+    pub fn with_allowed_dirs(id: &str, allowed_dirs: &[String]) -> Self {
+        Self {
+            id: id.to_string(),
+            allowed_dirs: allowed_dirs.to_vec(),
+            guest_dir: KATA_GUEST_SHARE_DIR.to_string(),
+        }
+    }
+
+    /// with_guest_dir sets where the guest mounts the export the files are
+    /// shared through.
+    pub fn with_guest_dir(mut self, guest_dir: &str) -> Self {
+        self.guest_dir = guest_dir.to_string();
+        self
     }
+
+    // guest_path moves the guest path of a shared file under the guest
+    // mount point of the export
+    fn guest_path(&self, path: String) -> String {
+        if self.guest_dir == KATA_GUEST_SHARE_DIR {
+            return path;
+        }
+        path.strip_prefix(KATA_GUEST_SHARE_DIR)
+            .map(|relative| format!("{}{}", self.guest_dir, relative))
+            .unwrap_or(path)
+    }
+
+    // check_allowed fails if the guest couldn't get to the host path shared,
+    // virtiofsd would deny it, so that it's told here rather than with an
+    // ENOENT of the guest mount
+    fn check_allowed(&self, host_path: &str) -> Result<()> {
+        if self.allowed_dirs.is_empty() {
+            return Ok(());
+        }
+        let relative = Path::new(host_path)
+            .strip_prefix(get_host_ro_shared_path(&self.id))
+            .unwrap_or_else(|_| Path::new(host_path));
+        if is_under_allowed_dirs(relative, &self.allowed_dirs) {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "{} is shared outside of virtio_fs_allowed_dirs {:?}, virtiofsd denies the guest access to it",
+            relative.display(),
+            self.allowed_dirs
+        ))
+    }
+}
+
+/// is_under_allowed_dirs tells if the path relative to the shared directory
+/// is in one of the allowed dirs.
+pub(crate) fn is_under_allowed_dirs(relative: &Path, allowed_dirs: &[String]) -> bool {
+    allowed_dirs.iter().any(|dir| relative.starts_with(dir))
 }
 
 #[async_trait]
 impl ShareFsMount for VirtiofsShareMount {
     async fn share_rootfs(&self, config: &ShareFsRootfsConfig) -> Result<ShareFsMountResult> {
         // TODO: select virtiofs or support nydus
+        self.check_allowed(&do_get_host_path(
+            &config.target,
+            &self.id,
+            &config.cid,
+            false,
+            true,
+        ))?;
         let guest_path = utils::share_to_guest(
             &config.source,
             &config.target,
@@ -54,22 +121,45 @@ impl ShareFsMount for VirtiofsShareMount {
         )
         .context("share to guest")?;
         Ok(ShareFsMountResult {
-            guest_path,
+            guest_path: self.guest_path(guest_path),
             storages: vec![],
         })
     }
 
     async fn share_volume(&self, config: &ShareFsVolumeConfig) -> Result<ShareFsMountResult> {
-        let mut guest_path = utils::share_to_guest(
-            &config.source,
+        self.check_allowed(&do_get_host_path(
             &config.target,
             &self.id,
             &config.cid,
-            config.readonly,
             true,
-            config.is_rafs,
-        )
-        .context("share to guest")?;
+            true,
+        ))?;
+        let mut guest_path = self.guest_path(
+            utils::share_to_guest(
+                &config.source,
+                &config.target,
+                &self.id,
+                &config.cid,
+                config.readonly,
+                true,
+                config.is_rafs,
+            )
+            .context("share to guest")?,
+        );
+
+        // the mounts made later on the host under the source only get into
+        // the guest if they propagate to the shared dir
+        if let Some(propagation) = parse_propagation(&config.mount.options) {
+            let host_dest = do_get_host_path(&config.target, &self.id, &config.cid, true, false);
+            if let Err(e) = set_propagation(&host_dest, propagation) {
+                if let Err(e) = umount_timeout(&host_dest, 0) {
+                    warn!(sl!(), "failed to umount {}: {:?}", host_dest, e);
+                }
+                return Err(e).with_context(|| {
+                    format!("set propagation of {} to {:?}", host_dest, propagation)
+                });
+            }
+        }
 
         // watchable mounts
         if is_watchable_mount(&config.source) {
@@ -90,7 +180,7 @@ impl ShareFsMount for VirtiofsShareMount {
             let file_name = Path::new(&guest_path)
                 .file_name()
                 .context("get file name from guest path")?;
-            let watchable_guest_mount = Path::new(KATA_GUEST_SHARE_DIR)
+            let watchable_guest_mount = Path::new(&self.guest_dir)
                 .join(PASSTHROUGH_FS_DIR)
                 .join(WATCHABLE_PATH_NAME)
                 .join(file_name)
@@ -227,16 +317,62 @@ impl ShareFsMount for VirtiofsShareMount {
     }
 
     async fn cleanup(&self, sid: &str) -> Result<()> {
+        // the allowed dirs are bound from the ro path, they're unmounted
+        // first so that nothing is removed through them
+        let host_allowed_dest = get_host_allowed_shared_path(sid);
+        umount_all(host_allowed_dest.clone(), true).context("failed to umount allowed path")?;
+        remove_dir_all_if_exists(host_allowed_dest).context("failed to remove allowed path")?;
         // Unmount ro path
         let host_ro_dest = get_host_ro_shared_path(sid);
         umount_all(host_ro_dest.clone(), true).context("failed to umount ro path")?;
-        fs::remove_dir_all(host_ro_dest).context("failed to remove ro path")?;
+        // the paths gone already are skipped, so that it could be retried
+        remove_dir_all_if_exists(host_ro_dest).context("failed to remove ro path")?;
         // As the rootfs and volume have been umounted before calling this function, so just remove the rw dir directly
         let host_rw_dest = get_host_rw_shared_path(sid);
-        fs::remove_dir_all(host_rw_dest).context("failed to remove rw path")?;
+        remove_dir_all_if_exists(host_rw_dest).context("failed to remove rw path")?;
         // remove the host share directory
         let host_path = get_host_shared_path(sid);
-        fs::remove_dir_all(host_path).context("failed to remove host shared path")?;
+        remove_dir_all_if_exists(host_path).context("failed to remove host shared path")?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_under_allowed_dirs() {
+        let allowed_dirs = vec!["passthrough/watchable".to_string(), "rafs".to_string()];
+        assert!(is_under_allowed_dirs(
+            Path::new("passthrough/watchable/config"),
+            &allowed_dirs
+        ));
+        assert!(is_under_allowed_dirs(Path::new("rafs"), &allowed_dirs));
+        assert!(!is_under_allowed_dirs(
+            Path::new("passthrough/cid/rootfs"),
+            &allowed_dirs
+        ));
+        // the components are compared, not the strings
+        assert!(!is_under_allowed_dirs(Path::new("rafs2/a"), &allowed_dirs));
+    }
+
+    #[test]
+    fn test_guest_path() {
+        let path = format!("{}passthrough/cid/rootfs", KATA_GUEST_SHARE_DIR);
+        let mount = VirtiofsShareMount::new("sid");
+        assert_eq!(mount.guest_path(path.clone()), path);
+
+        let mount = VirtiofsShareMount::new("sid/exports/1")
+            .with_guest_dir("/run/kata-containers/shared/exports/1/");
+        assert_eq!(
+            mount.guest_path(path),
+            "/run/kata-containers/shared/exports/1/passthrough/cid/rootfs"
+        );
+        // the paths out of the share dir are kept, e.g. the ephemeral ones
+        assert_eq!(
+            mount.guest_path(EPHEMERAL_PATH.to_string()),
+            EPHEMERAL_PATH.to_string()
+        );
+    }
+}
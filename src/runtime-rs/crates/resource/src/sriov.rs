@@ -0,0 +1,714 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use hypervisor::{
+    device::{driver, DeviceType},
+    Hypervisor, VfioConfig, VfioDevice,
+};
+use kata_types::{annotations::KATA_ANNO_CONTAINER_SRIOV_VFS, config::TomlConfig};
+use nix::fcntl::{flock, FlockArg};
+use serde::{Deserialize, Serialize};
+use tokio::{process::Command, sync::Mutex};
+
+const IP_PATH: &str = "/sbin/ip";
+const SYS_CLASS_NET: &str = "/sys/class/net";
+// the VFs taken by the shims of the host, one file for each named by the
+// bdf of the VF, under the lock shared by the shims
+const SRIOV_REGISTRY_DIR: &str = "/run/kata-containers/sriov";
+const SRIOV_REGISTRY_LOCK: &str = ".lock";
+// the mac address of a VF without one, the guest driver picks a random one
+const VF_ZERO_MAC: &str = "00:00:00:00:00:00";
+const VLAN_ID_MAX: u16 = 4094;
+
+/// VfConfig is the config of a VF set on its PF before the VF is passed to
+/// the guest, the ones unset are left as they are. Only the mac and the vlan
+/// are set, the trust and the spoof checking of the VF stay the ones of the
+/// host.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VfConfig {
+    /// the network interface of the PF on the host
+    pub pf: String,
+    pub mac: Option<String>,
+    pub vlan: Option<u16>,
+}
+
+impl VfConfig {
+    /// parse parses a VF of the annotation, e.g.
+    /// pf=ens1f0,mac=02:00:00:00:00:01,vlan=100
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for option in spec.split(',').map(|o| o.trim()).filter(|o| !o.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid VF option {}", option))?;
+            match key {
+                "pf" => config.pf = value.to_string(),
+                "mac" => config.mac = Some(parse_mac(value)?),
+                "vlan" => {
+                    let vlan = value
+                        .parse::<u16>()
+                        .ok()
+                        .filter(|v| *v <= VLAN_ID_MAX)
+                        .ok_or_else(|| anyhow!("invalid VF vlan {}", value))?;
+                    config.vlan = Some(vlan);
+                }
+                _ => return Err(anyhow!("unknown VF option {}", option)),
+            }
+        }
+        if config.pf.is_empty() {
+            return Err(anyhow!("no PF of VF {}", spec));
+        }
+        Ok(config)
+    }
+
+    /// reset returns the config setting back the defaults of the kernel for
+    /// the ones this config sets.
+    fn reset(&self) -> Self {
+        Self {
+            pf: self.pf.clone(),
+            mac: self.mac.as_ref().map(|_| VF_ZERO_MAC.to_string()),
+            vlan: self.vlan.map(|_| 0),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.mac.is_none() && self.vlan.is_none()
+    }
+
+    // the arguments of ip setting the config of the VF on its PF
+    fn ip_link_args(&self, index: u32) -> Vec<String> {
+        let mut args: Vec<String> = vec!["link", "set", "dev", self.pf.as_str(), "vf"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        args.push(index.to_string());
+        if let Some(mac) = &self.mac {
+            args.push("mac".to_string());
+            args.push(mac.clone());
+        }
+        if let Some(vlan) = self.vlan {
+            args.push("vlan".to_string());
+            args.push(vlan.to_string());
+        }
+        args
+    }
+}
+
+fn parse_mac(mac: &str) -> Result<String> {
+    let octets: Vec<&str> = mac.split(':').collect();
+    if octets.len() != 6
+        || octets
+            .iter()
+            .any(|o| o.len() != 2 || u8::from_str_radix(o, 16).is_err())
+    {
+        return Err(anyhow!("invalid VF mac {}", mac));
+    }
+    Ok(mac.to_lowercase())
+}
+
+/// vf_configs returns the VFs the container asks for in the annotation,
+/// separated by ';'. The annotation has to be enabled by enable_annotations
+/// of the hypervisor, and the PFs allowed by sriov_allowed_pfs.
+pub fn vf_configs(
+    config: &TomlConfig,
+    annotations: &HashMap<String, String>,
+) -> Result<Vec<VfConfig>> {
+    let vfs = match annotations.get(KATA_ANNO_CONTAINER_SRIOV_VFS) {
+        Some(vfs) => vfs,
+        None => return Ok(vec![]),
+    };
+    let enabled = config
+        .hypervisor
+        .get(&config.runtime.hypervisor_name)
+        .map(|hv| {
+            hv.security_info
+                .is_container_annotation_enabled(KATA_ANNO_CONTAINER_SRIOV_VFS)
+        })
+        .unwrap_or(false);
+    if !enabled {
+        return Err(anyhow!(
+            "annotation {} not allowed by enable_annotations",
+            KATA_ANNO_CONTAINER_SRIOV_VFS
+        ));
+    }
+    let allowed_pfs = &config.runtime.sriov_allowed_pfs;
+    vfs.split(';')
+        .filter(|v| !v.trim().is_empty())
+        .map(|v| {
+            let config = VfConfig::parse(v).with_context(|| format!("parse VF {}", v))?;
+            if !allowed_pfs.contains(&config.pf) {
+                return Err(anyhow!("PF {} not allowed by sriov_allowed_pfs", config.pf));
+            }
+            Ok(config)
+        })
+        .collect()
+}
+
+/// HostVf is a VF of a PF on the host.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostVf {
+    /// the index of the VF on its PF
+    pub index: u32,
+    pub bdf: String,
+    /// the host driver of the VF, only the ones bound to vfio-pci are taken
+    pub driver: String,
+    pub vendor_device_id: String,
+}
+
+// host_vfs returns the VFs of the PF enabled on the host, and the number of
+// them the PF supports
+fn host_vfs(sys_class_net: &Path, pf: &str) -> Result<(Vec<HostVf>, u32)> {
+    let pf_device = sys_class_net.join(pf).join("device");
+    let read = |name: &str| -> Result<String> {
+        let path = pf_device.join(name);
+        fs::read_to_string(&path)
+            .map(|s| s.trim().to_string())
+            .with_context(|| format!("read {:?}", path))
+    };
+    let total = read("sriov_totalvfs")
+        .with_context(|| format!("PF {} doesn't support SR-IOV", pf))?
+        .parse::<u32>()
+        .context("parse sriov_totalvfs")?;
+    let num = read("sriov_numvfs")?
+        .parse::<u32>()
+        .context("parse sriov_numvfs")?;
+
+    let mut vfs = vec![];
+    for index in 0..num {
+        let virtfn = pf_device.join(format!("virtfn{}", index));
+        let bdf = fs::read_link(&virtfn)
+            .with_context(|| format!("read link {:?}", virtfn))?
+            .file_name()
+            .and_then(|f| f.to_str())
+            .map(String::from)
+            .ok_or_else(|| anyhow!("invalid VF {:?}", virtfn))?;
+        let driver = fs::read_link(virtfn.join("driver"))
+            .ok()
+            .and_then(|d| d.file_name().and_then(|f| f.to_str()).map(String::from))
+            .unwrap_or_default();
+        let vendor = read(&format!("virtfn{}/vendor", index))?;
+        let device = read(&format!("virtfn{}/device", index))?;
+        vfs.push(HostVf {
+            index,
+            bdf,
+            driver,
+            vendor_device_id: format!("{}_{}", vendor, device),
+        });
+    }
+    Ok((vfs, total))
+}
+
+/// allocate_vf picks a free VF of the PF: bound to vfio-pci and not taken by
+/// a sandbox of the host. The VFs of the host drivers are in use by the host
+/// networking or by the other runtimes, they're never taken.
+pub fn allocate_vf(sys_class_net: &Path, pf: &str, taken: &HashSet<String>) -> Result<HostVf> {
+    let (vfs, total) = host_vfs(sys_class_net, pf)?;
+    if vfs.is_empty() {
+        return Err(anyhow!(
+            "PF {} has no VF enabled of the {} it supports, set its sriov_numvfs",
+            pf,
+            total
+        ));
+    }
+    let num = vfs.len();
+    vfs.into_iter()
+        .find(|vf| !taken.contains(&vf.bdf) && vf.driver == driver::VFIO_PCI)
+        .ok_or_else(|| {
+            anyhow!(
+                "no free VF of PF {}, none of its {} VFs enabled is bound to vfio-pci and unused",
+                pf,
+                num
+            )
+        })
+}
+
+/// SriovRegistry records the VFs taken by the sandboxes of the host, so
+/// that the sandboxes starting concurrently never get the same one.
+#[derive(Clone, Debug)]
+struct SriovRegistry {
+    sid: String,
+    dir: PathBuf,
+}
+
+impl SriovRegistry {
+    fn new(sid: &str) -> Self {
+        Self {
+            sid: sid.to_string(),
+            dir: PathBuf::from(SRIOV_REGISTRY_DIR),
+        }
+    }
+
+    // take takes a free VF of each config under the lock of the registry,
+    // the ones taken are given back if one fails
+    fn take(&self, sys_class_net: &Path, configs: &[VfConfig]) -> Result<Vec<HostVf>> {
+        fs::create_dir_all(&self.dir).with_context(|| format!("create {}", self.dir.display()))?;
+        let lock =
+            File::create(self.dir.join(SRIOV_REGISTRY_LOCK)).context("create registry lock")?;
+        // released with the file
+        flock(lock.as_raw_fd(), FlockArg::LockExclusive).context("lock registry")?;
+
+        let mut taken = self.taken()?;
+        let mut vfs: Vec<HostVf> = vec![];
+        for config in configs {
+            let result = allocate_vf(sys_class_net, &config.pf, &taken).and_then(|vf| {
+                fs::write(self.dir.join(&vf.bdf), &self.sid)
+                    .with_context(|| format!("register VF {}", vf.bdf))?;
+                Ok(vf)
+            });
+            match result {
+                Ok(vf) => {
+                    taken.insert(vf.bdf.clone());
+                    vfs.push(vf);
+                }
+                Err(e) => {
+                    vfs.iter().for_each(|vf| self.give_back(vf));
+                    return Err(e);
+                }
+            }
+        }
+        Ok(vfs)
+    }
+
+    // plan picks the VFs the same way as take, without taking them
+    fn plan(&self, sys_class_net: &Path, configs: &[VfConfig]) -> Vec<Result<HostVf>> {
+        let mut taken = match self.taken() {
+            Ok(taken) => taken,
+            Err(e) => return configs.iter().map(|_| Err(anyhow!("{:?}", e))).collect(),
+        };
+        configs
+            .iter()
+            .map(|config| {
+                let vf = allocate_vf(sys_class_net, &config.pf, &taken)?;
+                taken.insert(vf.bdf.clone());
+                Ok(vf)
+            })
+            .collect()
+    }
+
+    fn taken(&self) -> Result<HashSet<String>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(e) => return Err(e).context("read registry"),
+        };
+        let mut taken = HashSet::new();
+        for entry in entries {
+            if let Some(name) = entry?.file_name().to_str() {
+                if name != SRIOV_REGISTRY_LOCK {
+                    taken.insert(name.to_string());
+                }
+            }
+        }
+        Ok(taken)
+    }
+
+    // give_back gives the VF back to the host, what fails is logged
+    fn give_back(&self, vf: &HostVf) {
+        match fs::remove_file(self.dir.join(&vf.bdf)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                warn!(sl!(), "couldn't unregister VF {}: {:?}", vf.bdf, e)
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn run_ip(args: &[String]) -> Result<()> {
+    let output = Command::new(IP_PATH)
+        .args(args)
+        .output()
+        .await
+        .context("run ip")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ip {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// SriovVf is a VF configured on its PF and passed through to the guest.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SriovVf {
+    id: String,
+    config: VfConfig,
+    vf: HostVf,
+}
+
+impl SriovVf {
+    /// attach configures the VF on its PF and passes it to the guest, what's
+    /// done is undone if it fails, the VF is given back by the caller then.
+    async fn attach(
+        cid: &str,
+        config: VfConfig,
+        vf: HostVf,
+        hypervisor: &dyn Hypervisor,
+    ) -> Result<Self> {
+        let sriov_vf = Self {
+            id: format!("sriov_{}_{}_vf{}", cid, config.pf, vf.index),
+            config,
+            vf,
+        };
+        if !sriov_vf.config.is_empty() {
+            run_ip(&sriov_vf.config.ip_link_args(sriov_vf.vf.index))
+                .await
+                .context("configure VF on PF")?;
+        }
+        if let Err(e) = sriov_vf.pass_through(hypervisor).await {
+            sriov_vf.reset().await;
+            return Err(e);
+        }
+        Ok(sriov_vf)
+    }
+
+    // pass_through adds the VF, bound to vfio-pci already, to the guest
+    async fn pass_through(&self, hypervisor: &dyn Hypervisor) -> Result<()> {
+        let topology_supported = hypervisor
+            .capabilities()
+            .await
+            .context("get capabilities")?
+            .is_pci_topology_supported();
+        hypervisor
+            .add_device(self.vfio_device(topology_supported))
+            .await
+            .context("add VF device")
+    }
+
+    fn vfio_device(&self, topology_supported: bool) -> DeviceType {
+        // keep the VF close to the NUMA node of the host it's on
+        let config = VfioConfig {
+            sysfs_path: "".to_string(),
+            bus_slot_func: self.vf.bdf.clone(),
+            mode: driver::VfioBusMode::PCI,
+            topology_hint: Some(driver::PciTopology::on_host_numa_node(&self.vf.bdf)),
+        };
+        DeviceType::Vfio(VfioDevice {
+            id: self.id.clone(),
+            placement: config.placement(topology_supported),
+            config,
+            guest_pci_slot: None,
+            attach_count: 0,
+        })
+    }
+
+    /// detach removes the VF from the guest and resets it, it's left bound
+    /// to vfio-pci for the next sandbox.
+    async fn detach(&self, hypervisor: &dyn Hypervisor) -> Result<()> {
+        hypervisor
+            .remove_device(self.vfio_device(false))
+            .await
+            .with_context(|| format!("remove VF device {}", self.id))?;
+        self.reset().await;
+        Ok(())
+    }
+
+    // reset resets the config of the VF on the PF, what fails is logged
+    async fn reset(&self) {
+        let reset = self.config.reset();
+        if !reset.is_empty() {
+            if let Err(e) = run_ip(&reset.ip_link_args(self.vf.index)).await {
+                warn!(
+                    sl!(),
+                    "couldn't reset VF {} of PF {}: {:?}", self.vf.index, self.config.pf, e
+                );
+            }
+        }
+    }
+}
+
+/// SriovResource is the VFs passed through to the guest by container.
+pub struct SriovResource {
+    registry: SriovRegistry,
+    vfs: Mutex<HashMap<String, Vec<SriovVf>>>,
+}
+
+impl SriovResource {
+    pub fn new(sid: &str) -> Self {
+        Self::restore(sid, HashMap::new())
+    }
+
+    /// restore restores the VFs saved, so that they're released on cleanup.
+    pub fn restore(sid: &str, vfs: HashMap<String, Vec<SriovVf>>) -> Self {
+        Self {
+            registry: SriovRegistry::new(sid),
+            vfs: Mutex::new(vfs),
+        }
+    }
+
+    pub async fn save(&self) -> HashMap<String, Vec<SriovVf>> {
+        self.vfs.lock().await.clone()
+    }
+
+    /// attach takes the VFs of the container and attaches them, the ones
+    /// attached are detached and all of them given back if one fails. The
+    /// ids of the devices are returned.
+    pub async fn attach(
+        &self,
+        cid: &str,
+        configs: Vec<VfConfig>,
+        hypervisor: &dyn Hypervisor,
+    ) -> Result<Vec<String>> {
+        if configs.is_empty() {
+            return Ok(vec![]);
+        }
+        // the VFs are taken under the lock of the registry shared by the
+        // shims, it's blocking
+        let registry = self.registry.clone();
+        let c = configs.clone();
+        let host_vfs =
+            tokio::task::spawn_blocking(move || registry.take(Path::new(SYS_CLASS_NET), &c))
+                .await
+                .context("join taking VFs")??;
+
+        let mut vfs = self.vfs.lock().await;
+        let mut attached: Vec<SriovVf> = vec![];
+        for (config, vf) in configs.into_iter().zip(host_vfs.iter().cloned()) {
+            info!(
+                sl!(),
+                "attach VF {} {} of PF {} to container {}", vf.index, vf.bdf, config.pf, cid
+            );
+            match SriovVf::attach(cid, config, vf, hypervisor).await {
+                Ok(vf) => attached.push(vf),
+                Err(e) => {
+                    for vf in attached.iter().rev() {
+                        if let Err(err) = vf.detach(hypervisor).await {
+                            warn!(sl!(), "couldn't roll back VF {}: {:?}", vf.id, err);
+                        }
+                    }
+                    host_vfs.iter().for_each(|vf| self.registry.give_back(vf));
+                    return Err(e);
+                }
+            }
+        }
+        let ids = attached.iter().map(|v| v.id.clone()).collect();
+        vfs.entry(cid.to_string()).or_default().extend(attached);
+        Ok(ids)
+    }
+
+    /// plan picks the VFs of the configs the same way as attach, without
+    /// taking them or changing anything on the host.
+    pub async fn plan(&self, configs: &[VfConfig]) -> Vec<Result<HostVf>> {
+        self.registry.plan(Path::new(SYS_CLASS_NET), configs)
+    }
+
+    /// detach detaches the VFs of the container, they're given back even if
+    /// the guest fails to remove them. The ids of the devices are returned.
+    pub async fn detach(&self, cid: &str, hypervisor: &dyn Hypervisor) -> Result<Vec<String>> {
+        let vfs = match self.vfs.lock().await.remove(cid) {
+            Some(vfs) => vfs,
+            None => return Ok(vec![]),
+        };
+        let mut result = Ok(());
+        let mut ids = vec![];
+        for vf in vfs.iter().rev() {
+            if let Err(e) = vf.detach(hypervisor).await {
+                vf.reset().await;
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+            self.registry.give_back(&vf.vf);
+            ids.push(vf.id.clone());
+        }
+        result.map(|_| ids)
+    }
+
+    /// release gives back the VFs left once the VM is gone.
+    pub async fn release(&self) {
+        for (_, vfs) in self.vfs.lock().await.drain() {
+            for vf in vfs.iter().rev() {
+                vf.reset().await;
+                self.registry.give_back(&vf.vf);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    // fake_pf creates the sysfs of a PF with its VFs bound to the drivers
+    fn fake_pf(root: &Path, pf: &str, total: u32, drivers: &[&str]) {
+        let pci = root.join("pci");
+        let device = pci.join(format!("{}-pf", pf));
+        fs::create_dir_all(&device).unwrap();
+        fs::create_dir_all(root.join("net").join(pf)).unwrap();
+        symlink(&device, root.join("net").join(pf).join("device")).unwrap();
+        fs::write(device.join("sriov_totalvfs"), format!("{}\n", total)).unwrap();
+        fs::write(device.join("sriov_numvfs"), format!("{}\n", drivers.len())).unwrap();
+        for (i, d) in drivers.iter().enumerate() {
+            let vf = pci.join(format!("0000:3b:02.{}", i));
+            fs::create_dir_all(&vf).unwrap();
+            fs::write(vf.join("vendor"), "0x8086\n").unwrap();
+            fs::write(vf.join("device"), "0x154c\n").unwrap();
+            symlink(root.join("drivers").join(d), vf.join("driver")).unwrap();
+            symlink(&vf, device.join(format!("virtfn{}", i))).unwrap();
+        }
+    }
+
+    fn registry(sid: &str, dir: &Path) -> SriovRegistry {
+        SriovRegistry {
+            sid: sid.to_string(),
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    #[test]
+    fn test_vf_config() {
+        let config = VfConfig::parse("pf=ens1f0,mac=02:00:00:00:00:0A,vlan=100").unwrap();
+        assert_eq!(config.mac.as_deref(), Some("02:00:00:00:00:0a"));
+        assert_eq!(
+            config.ip_link_args(3).join(" "),
+            "link set dev ens1f0 vf 3 mac 02:00:00:00:00:0a vlan 100"
+        );
+        assert_eq!(
+            config.reset().ip_link_args(3).join(" "),
+            "link set dev ens1f0 vf 3 mac 00:00:00:00:00:00 vlan 0"
+        );
+        assert!(VfConfig::parse("pf=ens1f0").unwrap().reset().is_empty());
+
+        for spec in vec![
+            "mac=02:00:00:00:00:0a",
+            "pf=ens1f0,vlan=4095",
+            "pf=ens1f0,mac=02:00:00:00:00",
+            // the security of the host NIC isn't up to the container
+            "pf=ens1f0,trust=on",
+            "pf=ens1f0,spoofchk=off",
+            "pf=ens1f0,qos=1",
+        ] {
+            assert!(VfConfig::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_vf_configs() {
+        let annotations: HashMap<String, String> = vec![(
+            KATA_ANNO_CONTAINER_SRIOV_VFS.to_string(),
+            "pf=ens1f0,vlan=100; pf=ens1f1".to_string(),
+        )]
+        .into_iter()
+        .collect();
+        let mut config = TomlConfig::default();
+        config.runtime.hypervisor_name = "dragonball".to_string();
+        config
+            .hypervisor
+            .insert("dragonball".to_string(), Default::default());
+        config.runtime.sriov_allowed_pfs = vec!["ens1f0".to_string(), "ens1f1".to_string()];
+        assert!(vf_configs(&config, &HashMap::new()).unwrap().is_empty());
+
+        // not enabled
+        let e = vf_configs(&config, &annotations).unwrap_err();
+        assert!(e.to_string().contains("not allowed by enable_annotations"));
+
+        let hv = config.hypervisor.get_mut("dragonball").unwrap();
+        hv.security_info.enable_annotations = vec!["sriov_vfs".to_string()];
+        let configs = vf_configs(&config, &annotations).unwrap();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(configs[1].pf, "ens1f1");
+
+        // the PF not allowed
+        config.runtime.sriov_allowed_pfs = vec!["ens1f0".to_string()];
+        assert_eq!(
+            vf_configs(&config, &annotations).unwrap_err().to_string(),
+            "PF ens1f1 not allowed by sriov_allowed_pfs"
+        );
+    }
+
+    #[test]
+    fn test_allocate_vf() {
+        let dir = tempfile::tempdir().unwrap();
+        fake_pf(dir.path(), "ens1f0", 8, &["vfio-pci", "iavf", "vfio-pci"]);
+        fake_pf(dir.path(), "ens1f1", 8, &[]);
+        let sys_class_net = dir.path().join("net");
+
+        let mut taken = HashSet::new();
+        let vf = allocate_vf(&sys_class_net, "ens1f0", &taken).unwrap();
+        assert_eq!(
+            vf,
+            HostVf {
+                index: 0,
+                bdf: "0000:3b:02.0".to_string(),
+                driver: "vfio-pci".to_string(),
+                vendor_device_id: "0x8086_0x154c".to_string(),
+            }
+        );
+        // the one of the host driver is left to the host
+        taken.insert(vf.bdf);
+        assert_eq!(
+            allocate_vf(&sys_class_net, "ens1f0", &taken).unwrap().index,
+            2
+        );
+
+        taken.insert("0000:3b:02.2".to_string());
+        assert_eq!(
+            allocate_vf(&sys_class_net, "ens1f0", &taken)
+                .unwrap_err()
+                .to_string(),
+            "no free VF of PF ens1f0, none of its 3 VFs enabled is bound to vfio-pci and unused"
+        );
+        assert_eq!(
+            allocate_vf(&sys_class_net, "ens1f1", &taken)
+                .unwrap_err()
+                .to_string(),
+            "PF ens1f1 has no VF enabled of the 8 it supports, set its sriov_numvfs"
+        );
+        assert!(allocate_vf(&sys_class_net, "eth0", &taken).is_err());
+    }
+
+    #[test]
+    fn test_registry() {
+        let dir = tempfile::tempdir().unwrap();
+        fake_pf(dir.path(), "ens1f0", 8, &["vfio-pci", "vfio-pci", "iavf"]);
+        let sys_class_net = dir.path().join("net");
+        let registry_dir = dir.path().join("registry");
+        let a = registry("a", &registry_dir);
+        let b = registry("b", &registry_dir);
+        let config = VfConfig::parse("pf=ens1f0").unwrap();
+
+        // the VF taken by a isn't taken by b
+        let vfs = a.take(&sys_class_net, &[config.clone()]).unwrap();
+        assert_eq!(vfs[0].index, 0);
+        assert_eq!(
+            fs::read_to_string(registry_dir.join("0000:3b:02.0")).unwrap(),
+            "a"
+        );
+        assert_eq!(
+            b.plan(&sys_class_net, &[config.clone()])[0]
+                .as_ref()
+                .unwrap()
+                .index,
+            1
+        );
+        let vfs_b = b.take(&sys_class_net, &[config.clone()]).unwrap();
+        assert_eq!(vfs_b[0].index, 1);
+
+        // none left, nothing is taken
+        assert!(b.take(&sys_class_net, &[config.clone()]).is_err());
+        assert_eq!(b.taken().unwrap().len(), 2);
+
+        // given back twice, the VF is free again, and all of the ones of a
+        // failure are given back
+        a.give_back(&vfs[0]);
+        a.give_back(&vfs[0]);
+        assert!(a
+            .take(&sys_class_net, &[config.clone(), config.clone()])
+            .is_err());
+        assert_eq!(b.taken().unwrap().len(), 1);
+        assert_eq!(a.take(&sys_class_net, &[config]).unwrap()[0].index, 0);
+    }
+}
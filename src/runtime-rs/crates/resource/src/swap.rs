@@ -0,0 +1,160 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    fs::{self, OpenOptions},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use agent::{AddSwapRequest, Agent};
+use anyhow::{anyhow, Context, Result};
+use hypervisor::{
+    device::{device_manager::DeviceManager, DeviceConfig, DeviceType},
+    BlockConfig,
+};
+use kata_types::config::hypervisor::MemoryInfo;
+use shim_interface::KATA_PATH;
+use tokio::{process::Command, sync::RwLock};
+use tracing::Instrument;
+
+use crate::{metrics, trace};
+
+// found in PATH, it's not in /sbin on every distribution
+const MKSWAP_CMD: &str = "mkswap";
+const SWAP_FILE_NAME: &str = "swap";
+const MIB: u64 = 1024 * 1024;
+
+/// SwapResource is the swap device of the guest, which is backed by a raw
+/// file on the host.
+pub struct SwapResource {
+    path: PathBuf,
+    size_mb: u64,
+}
+
+impl SwapResource {
+    pub fn new(sid: &str, size_mb: u64) -> Self {
+        Self {
+            path: Path::new(KATA_PATH).join(sid).join(SWAP_FILE_NAME),
+            size_mb,
+        }
+    }
+
+    /// setup creates the backing file, inserts it to the guest as a block
+    /// device and asks the agent to swap on it. It must be called after the
+    /// agent is connected.
+    pub async fn setup(&self, d: &RwLock<DeviceManager>, agent: &dyn Agent) -> Result<()> {
+        create_swap_file(&self.path, self.size_mb)
+            .await
+            .context("create swap file")?;
+
+        let block_config = BlockConfig {
+            path_on_host: self.path.display().to_string(),
+            ..Default::default()
+        };
+        let device_info = metrics::handle_device(d, &DeviceConfig::BlockCfg(block_config))
+            .await
+            .context("attach swap device")?;
+        let device = match device_info {
+            DeviceType::Block(device) => device,
+            _ => return Err(anyhow!("swap device isn't a block device")),
+        };
+        agent
+            .add_swap(AddSwapRequest {
+                pci_path: vec![],
+                device_path: device.config.virt_path.clone(),
+            })
+            .instrument(trace::agent_span("add_swap", ""))
+            .await
+            .context("swap on in the guest")?;
+
+        info!(
+            sl!(),
+            "swap device {} of {} MiB added as {}",
+            self.path.display(),
+            self.size_mb,
+            device.config.virt_path
+        );
+        Ok(())
+    }
+
+    /// cleanup removes the backing file, the device goes with the VM.
+    pub async fn cleanup(&self) -> Result<()> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)
+                .with_context(|| format!("remove swap file {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// swap_size_mb returns the size of the swap device, which is configured or
+/// the memory limit of the pod, or the default memory without the limit.
+pub fn swap_size_mb(memory_info: &MemoryInfo, pod_mem_mb: Option<u32>) -> u64 {
+    if memory_info.guest_swap_size != 0 {
+        return memory_info.guest_swap_size as u64;
+    }
+
+    match pod_mem_mb {
+        Some(mb) if mb != 0 => mb as u64,
+        _ => memory_info.default_memory as u64,
+    }
+}
+
+async fn create_swap_file(path: &Path, size_mb: u64) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("create dir {:?}", parent))?;
+    }
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("open {}", path.display()))?;
+    file.set_len(size_mb * MIB)
+        .with_context(|| format!("truncate {}", path.display()))?;
+
+    let output = match Command::new(MKSWAP_CMD).arg(path).output().await {
+        Ok(output) => output,
+        Err(e) => {
+            let _ = fs::remove_file(path);
+            return Err(e).with_context(|| format!("run {}", MKSWAP_CMD));
+        }
+    };
+    if !output.status.success() {
+        let _ = fs::remove_file(path);
+        return Err(anyhow!(
+            "mkswap {} failed: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_swap_size_mb() {
+        let memory_info = MemoryInfo {
+            default_memory: 2048,
+            ..Default::default()
+        };
+        assert_eq!(swap_size_mb(&memory_info, None), 2048);
+        assert_eq!(swap_size_mb(&memory_info, Some(0)), 2048);
+        assert_eq!(swap_size_mb(&memory_info, Some(512)), 512);
+
+        let memory_info = MemoryInfo {
+            default_memory: 2048,
+            guest_swap_size: 1024,
+            ..Default::default()
+        };
+        assert_eq!(swap_size_mb(&memory_info, Some(512)), 1024);
+    }
+}
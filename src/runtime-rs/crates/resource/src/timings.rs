@@ -0,0 +1,160 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+use tracing::Instrument;
+
+use crate::trace;
+
+// the oldest spans are dropped beyond it, so that a long running sandbox
+// with many containers doesn't grow the timings forever
+const MAX_TIMING_SPANS: usize = 1024;
+
+pub const PHASE_SHARE_FS: &str = "share_fs";
+pub const PHASE_NETWORK: &str = "network";
+pub const PHASE_INITIAL_SIZE: &str = "initial_size";
+pub const PHASE_SHARE_FS_AFTER_START: &str = "share_fs_after_start";
+pub const PHASE_AGENT_FEATURES: &str = "agent_features";
+pub const PHASE_NETWORK_AFTER_START: &str = "network_after_start";
+pub const PHASE_GUEST_SWAP: &str = "guest_swap";
+pub const PHASE_HOSTNAME: &str = "hostname";
+pub const PHASE_ROOTFS: &str = "rootfs";
+pub const PHASE_VOLUMES: &str = "volumes";
+pub const PHASE_DEVICES: &str = "devices";
+pub const PHASE_DEVICE_ATTACH: &str = "device_attach";
+
+/// TimingSpan is how long a phase of the resource setup took.
+#[derive(Clone, Debug, Serialize)]
+pub struct TimingSpan {
+    pub phase: &'static str,
+    // the container id, or the device for the device attach, empty for the
+    // phases of the sandbox
+    pub target: String,
+    // milliseconds since the unix epoch when the phase started
+    pub start_ms: u128,
+    pub duration_us: u128,
+    pub succeeded: bool,
+}
+
+/// Timings records the spans of the resource setup phases to diagnose the
+/// slow sandbox creation, it only costs two clock reads and a push per phase.
+/// The phases are traced as well if the tracing is enabled.
+#[derive(Debug, Default)]
+pub struct Timings {
+    sid: String,
+    spans: Mutex<VecDeque<TimingSpan>>,
+}
+
+impl Timings {
+    pub fn new(sid: &str) -> Self {
+        Self {
+            sid: sid.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// time runs the phase of the container, or of the sandbox if cid is
+    /// empty, and records how long it took, whether it succeeds or not.
+    pub async fn time<T, F>(&self, phase: &'static str, cid: &str, f: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let span = trace::span(phase, &self.sid, cid, "");
+        self.record_phase(phase, cid, f.instrument(span)).await
+    }
+
+    /// time_resource times the phase handling the resource of the container,
+    /// e.g. a device, the resource is the target recorded.
+    pub async fn time_resource<T, F>(
+        &self,
+        phase: &'static str,
+        cid: &str,
+        resource: &str,
+        f: F,
+    ) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let span = trace::span(phase, &self.sid, cid, resource);
+        self.record_phase(phase, resource, f.instrument(span)).await
+    }
+
+    async fn record_phase<T, F>(&self, phase: &'static str, target: &str, f: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let start_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let start = Instant::now();
+        let result = f.await;
+        self.record(TimingSpan {
+            phase,
+            target: target.to_string(),
+            start_ms,
+            duration_us: start.elapsed().as_micros(),
+            succeeded: result.is_ok(),
+        });
+        result
+    }
+
+    fn record(&self, span: TimingSpan) {
+        let mut spans = self.spans.lock().unwrap();
+        if spans.len() >= MAX_TIMING_SPANS {
+            spans.pop_front();
+        }
+        spans.push_back(span);
+    }
+
+    /// spans returns the spans recorded, the oldest first.
+    pub fn spans(&self) -> Vec<TimingSpan> {
+        self.spans.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[tokio::test]
+    async fn test_timings() {
+        let timings = Timings::new("sid");
+        let v = timings
+            .time(PHASE_ROOTFS, "c1", async { Ok(1) })
+            .await
+            .unwrap();
+        assert_eq!(v, 1);
+        let result: Result<()> = timings
+            .time(PHASE_VOLUMES, "c1", async { Err(anyhow!("failed")) })
+            .await;
+        assert!(result.is_err());
+
+        let spans = timings.spans();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].phase, PHASE_ROOTFS);
+        assert_eq!(spans[0].target, "c1");
+        assert!(spans[0].succeeded);
+        assert_eq!(spans[1].phase, PHASE_VOLUMES);
+        assert!(!spans[1].succeeded);
+
+        for _ in 0..MAX_TIMING_SPANS {
+            timings.record(spans[0].clone());
+        }
+        let spans = timings.spans();
+        assert_eq!(spans.len(), MAX_TIMING_SPANS);
+        assert!(spans.iter().all(|s| s.phase == PHASE_ROOTFS));
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tracing::Span;
+
+// off unless enable_tracing is set in the runtime config
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// set_enabled turns the spans of the resource setup on or off, they cost
+/// no more than an atomic load when off.
+pub(crate) fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// span returns the span of a resource setup step, the current span is its
+/// parent. The step is named by otel.name for the OpenTelemetry layer, the
+/// ids left empty are those of the parent.
+pub(crate) fn span(name: &str, sid: &str, cid: &str, resource: &str) -> Span {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Span::none();
+    }
+    tracing::info_span!(
+        "resource",
+        otel.name = name,
+        sandbox_id = sid,
+        container_id = cid,
+        resource = resource
+    )
+}
+
+/// agent_span returns the span of an agent rpc issued by the resource setup.
+pub(crate) fn agent_span(rpc: &str, sid: &str) -> Span {
+    span(rpc, sid, "", "agent")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_span_disabled() {
+        set_enabled(false);
+        assert!(span("share_fs", "sid", "", "").is_none());
+        assert!(agent_span("setup_network", "sid").is_none());
+    }
+}
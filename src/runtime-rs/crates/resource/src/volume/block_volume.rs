@@ -4,29 +4,73 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use std::collections::HashMap;
+
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
-use nix::sys::{stat, stat::SFlag};
+use kata_types::{
+    config::hypervisor::{BLOCK_DEVICE_AIO_MODES, MAX_BLOCK_DEVICE_QUEUE_SIZE},
+    mount::DirectVolumeMountInfo,
+};
+use nix::sys::{
+    stat,
+    stat::{FileStat, SFlag},
+};
 use tokio::sync::RwLock;
 
 use super::Volume;
+use crate::metrics;
 use crate::volume::utils::{
     generate_shared_path, volume_mount_info, DEFAULT_VOLUME_FS_TYPE, KATA_DIRECT_VOLUME_TYPE,
     KATA_MOUNT_BIND_TYPE,
 };
 use hypervisor::{
-    device::{
-        device_manager::{do_handle_device, DeviceManager},
-        DeviceConfig, DeviceType,
-    },
+    device::{device_manager::DeviceManager, DeviceConfig, DeviceType},
     BlockConfig,
 };
 
+// metadata of direct volumes tuning the virtio-blk queues of the device
+const DIRECT_VOLUME_NUM_QUEUES: &str = "num_queues";
+const DIRECT_VOLUME_QUEUE_SIZE: &str = "queue_size";
+// metadata of direct volumes telling if the device is reachable from the
+// other hosts, overriding the one guessed from the device
+const DIRECT_VOLUME_MIGRATABLE: &str = "migratable";
+// metadata of direct volumes placing the device at a guest pci slot, so
+// that its name in the guest is the same across the restarts
+const DIRECT_VOLUME_GUEST_PCI_SLOT: &str = "guest_pci_slot";
+
+// the sysfs paths of the block devices backed by the network, i.e. rbd, nbd,
+// iSCSI and NVMe over fabrics
+const NETWORK_BLOCK_DEVICE_PATHS: [&str; 4] = ["/rbd", "/nbd", "/session", "/nvme-fabrics/"];
+
+// mount options of the block volumes overriding the io settings of the
+// hypervisor config, they're dropped from the mount in the guest
+const MOUNT_OPTION_AIO: &str = "kata.block_device_aio=";
+const MOUNT_OPTION_CACHE_DIRECT: &str = "kata.block_device_cache_direct=";
+const MOUNT_OPTION_CACHE_NOFLUSH: &str = "kata.block_device_cache_noflush=";
+// mount option of the block volumes placing the device at a guest pci slot,
+// the volume fails if the slot is held by another device
+const MOUNT_OPTION_GUEST_PCI_SLOT: &str = "kata.guest_pci_slot=";
+
+// mount option naming the key of the LUKS encrypted block volume, the key is
+// provisioned into the guest and the volume is decrypted there, so that the
+// host only sees the ciphertext
+const MOUNT_OPTION_ENCRYPTION_KEY: &str = "kata.encryption_key=";
+const DRIVER_OPTION_ENCRYPTION_KEY: &str = "encryption_key=";
+
+// mount option of the bind mounts of a raw disk image file on the host, the
+// file is passed to the guest as a block device rather than shared, with the
+// fs type of the image given by the other option, ext4 by default
+const MOUNT_OPTION_RAW_IMAGE: &str = "kata.raw_image";
+const MOUNT_OPTION_FS_TYPE: &str = "kata.fs_type=";
+
 #[derive(Clone)]
 pub(crate) struct BlockVolume {
     storage: Option<agent::Storage>,
     mount: oci::Mount,
     device_id: String,
+    // the device is reachable from the host the VM migrates to
+    migratable: bool,
 }
 
 /// BlockVolume for bind-mount block volume and direct block volume
@@ -41,41 +85,51 @@ impl BlockVolume {
         let mnt_src: &str = &m.source;
         // default block device fs type: ext4.
         let mut blk_dev_fstype = DEFAULT_VOLUME_FS_TYPE.to_string();
+        let migratable;
 
         let block_device_config = match m.r#type.as_str() {
             KATA_MOUNT_BIND_TYPE => {
                 let fstat = stat::stat(mnt_src).context(format!("stat {}", m.source))?;
+                let (major, minor) = (
+                    stat::major(fstat.st_rdev) as i64,
+                    stat::minor(fstat.st_rdev) as i64,
+                );
+                migratable = is_network_block_device(major, minor);
 
                 BlockConfig {
-                    major: stat::major(fstat.st_rdev) as i64,
-                    minor: stat::minor(fstat.st_rdev) as i64,
+                    major,
+                    minor,
                     ..Default::default()
                 }
             }
             KATA_DIRECT_VOLUME_TYPE => {
-                // get volume mountinfo from mountinfo.json
-                let v = volume_mount_info(mnt_src)
-                    .context("deserde information from mountinfo.json")?;
-                // check volume type
-                if v.volume_type != KATA_DIRECT_VOLUME_TYPE {
-                    return Err(anyhow!("volume type {:?} is invalid", v.volume_type));
-                }
-
-                let fstat = stat::stat(v.device.as_str())
-                    .with_context(|| format!("stat volume device file: {}", v.device.clone()))?;
-                if SFlag::from_bits_truncate(fstat.st_mode) != SFlag::S_IFREG
-                    && SFlag::from_bits_truncate(fstat.st_mode) != SFlag::S_IFBLK
-                {
-                    return Err(anyhow!(
-                        "invalid volume device {:?} for volume type {:?}",
-                        v.device,
-                        v.volume_type
-                    ));
-                }
-
+                let (v, fstat) = direct_volume_device(mnt_src)?;
                 blk_dev_fstype = v.fs_type.clone();
+                // a file on the host is local unless the metadata tells
+                migratable = match v.metadata.get(DIRECT_VOLUME_MIGRATABLE) {
+                    Some(flag) => flag.parse::<bool>().with_context(|| {
+                        format!(
+                            "invalid volume metadata {}={}",
+                            DIRECT_VOLUME_MIGRATABLE, flag
+                        )
+                    })?,
+                    None => {
+                        SFlag::from_bits_truncate(fstat.st_mode) == SFlag::S_IFBLK
+                            && is_network_block_device(
+                                stat::major(fstat.st_rdev) as i64,
+                                stat::minor(fstat.st_rdev) as i64,
+                            )
+                    }
+                };
 
                 BlockConfig {
+                    num_queues: get_metadata_u32(&v.metadata, DIRECT_VOLUME_NUM_QUEUES)?,
+                    queue_size: get_metadata_queue_size(&v.metadata)?,
+                    guest_pci_slot: v
+                        .metadata
+                        .get(DIRECT_VOLUME_GUEST_PCI_SLOT)
+                        .map(|v| parse_guest_pci_slot(DIRECT_VOLUME_GUEST_PCI_SLOT, v))
+                        .transpose()?,
                     path_on_host: v.device,
                     ..Default::default()
                 }
@@ -88,8 +142,67 @@ impl BlockVolume {
             }
         };
 
+        let volume = Self::attach(
+            d,
+            m,
+            read_only,
+            cid,
+            sid,
+            block_device_config,
+            blk_dev_fstype,
+        )
+        .await?;
+        Ok(Self {
+            migratable,
+            ..volume
+        })
+    }
+
+    /// new_from_image passes the raw disk image file of the bind mount
+    /// through to the guest as a block device, without a loop device on the
+    /// host. The read-only image is attached read-only.
+    pub(crate) async fn new_from_image(
+        d: &RwLock<DeviceManager>,
+        m: &oci::Mount,
+        read_only: bool,
+        cid: &str,
+        sid: &str,
+    ) -> Result<Self> {
+        let path = raw_image_file(m)?;
+        let (fs_type, options) = take_raw_image_options(&m.options)
+            .with_context(|| format!("raw image of volume {}", m.destination))?;
+        let config = BlockConfig {
+            path_on_host: path,
+            is_readonly: read_only,
+            ..Default::default()
+        };
+        let m = &oci::Mount {
+            options,
+            ..m.clone()
+        };
+        // a file on the host is local
+        Self::attach(d, m, read_only, cid, sid, config, fs_type).await
+    }
+
+    async fn attach(
+        d: &RwLock<DeviceManager>,
+        m: &oci::Mount,
+        read_only: bool,
+        cid: &str,
+        sid: &str,
+        mut block_device_config: BlockConfig,
+        blk_dev_fstype: String,
+    ) -> Result<Self> {
+        let (encryption_key, options) = take_encryption_key(&m.options)
+            .with_context(|| format!("encryption of volume {}", m.destination))?;
+        let m = &oci::Mount {
+            options: apply_io_options(&mut block_device_config, &options)
+                .with_context(|| format!("io options of volume {}", m.destination))?,
+            ..m.clone()
+        };
+
         // create and insert block device into Kata VM
-        let device_info = do_handle_device(d, &DeviceConfig::BlockCfg(block_device_config.clone()))
+        let device_info = metrics::handle_device(d, &DeviceConfig::BlockCfg(block_device_config))
             .await
             .context("do handle device failed.")?;
 
@@ -104,6 +217,12 @@ impl BlockVolume {
             ..Default::default()
         };
 
+        if let Some(key) = encryption_key {
+            storage
+                .driver_options
+                .push(format!("{}{}", DRIVER_OPTION_ENCRYPTION_KEY, key));
+        }
+
         storage.options = if read_only {
             vec!["ro".to_string()]
         } else {
@@ -143,6 +262,7 @@ impl BlockVolume {
             storage: Some(storage),
             mount,
             device_id,
+            migratable: false,
         })
     }
 }
@@ -174,6 +294,175 @@ impl Volume for BlockVolume {
     fn get_device_id(&self) -> Result<Option<String>> {
         Ok(Some(self.device_id.clone()))
     }
+
+    fn is_migratable(&self) -> bool {
+        self.migratable
+    }
+}
+
+// is_network_block_device tells if the block device is backed by the network
+// rather than a disk of the host, the ones unknown are taken as local.
+fn is_network_block_device(major: i64, minor: i64) -> bool {
+    std::fs::canonicalize(format!("/sys/dev/block/{}:{}", major, minor))
+        .map(|p| is_network_sysfs_path(&p.display().to_string()))
+        .unwrap_or(false)
+}
+
+fn is_network_sysfs_path(path: &str) -> bool {
+    NETWORK_BLOCK_DEVICE_PATHS.iter().any(|p| path.contains(p))
+}
+
+// get_metadata_u32 returns 0 if the metadata isn't set.
+fn get_metadata_u32(metadata: &HashMap<String, String>, key: &str) -> Result<u32> {
+    match metadata.get(key) {
+        Some(v) => v
+            .parse::<u32>()
+            .with_context(|| format!("invalid volume metadata {}={}", key, v)),
+        None => Ok(0),
+    }
+}
+
+// get_metadata_queue_size gets the size of the virtio-blk queues asked for
+// the volume, which is checked like the one of the config, 0 if unset.
+fn get_metadata_queue_size(metadata: &HashMap<String, String>) -> Result<u32> {
+    let queue_size = get_metadata_u32(metadata, DIRECT_VOLUME_QUEUE_SIZE)?;
+    if queue_size != 0
+        && (!queue_size.is_power_of_two() || queue_size > MAX_BLOCK_DEVICE_QUEUE_SIZE)
+    {
+        return Err(anyhow!(
+            "invalid volume metadata {}={}, isn't a power of 2 up to {}",
+            DIRECT_VOLUME_QUEUE_SIZE,
+            queue_size,
+            MAX_BLOCK_DEVICE_QUEUE_SIZE
+        ));
+    }
+    Ok(queue_size)
+}
+
+// parse_guest_pci_slot parses the guest pci slot asked for the volume, it's
+// checked against the slots of the bus once the device is created.
+fn parse_guest_pci_slot(key: &str, v: &str) -> Result<u8> {
+    v.parse::<u8>()
+        .with_context(|| format!("invalid guest pci slot {}{}", key, v))
+}
+
+// apply_io_options sets the io settings and the guest pci slot of the block
+// device given by the mount options, the other options are returned.
+fn apply_io_options(config: &mut BlockConfig, options: &[String]) -> Result<Vec<String>> {
+    let mut others = vec![];
+    for opt in options {
+        if let Some(v) = opt.strip_prefix(MOUNT_OPTION_AIO) {
+            if !BLOCK_DEVICE_AIO_MODES.contains(&v) {
+                return Err(anyhow!(
+                    "unsupported aio mode {}, must be one of {:?}",
+                    v,
+                    BLOCK_DEVICE_AIO_MODES
+                ));
+            }
+            config.aio = v.to_string();
+        } else if let Some(v) = opt.strip_prefix(MOUNT_OPTION_CACHE_DIRECT) {
+            config.cache_direct = Some(
+                v.parse::<bool>()
+                    .with_context(|| format!("invalid mount option {}", opt))?,
+            );
+        } else if let Some(v) = opt.strip_prefix(MOUNT_OPTION_CACHE_NOFLUSH) {
+            config.cache_noflush = Some(
+                v.parse::<bool>()
+                    .with_context(|| format!("invalid mount option {}", opt))?,
+            );
+        } else if let Some(v) = opt.strip_prefix(MOUNT_OPTION_GUEST_PCI_SLOT) {
+            config.guest_pci_slot = Some(parse_guest_pci_slot(MOUNT_OPTION_GUEST_PCI_SLOT, v)?);
+        } else {
+            others.push(opt.clone());
+        }
+    }
+    Ok(others)
+}
+
+/// has_encryption_key tells if the mount asks for a LUKS encrypted volume,
+/// which only the block volumes are decrypted in the guest for.
+pub(crate) fn has_encryption_key(m: &oci::Mount) -> bool {
+    m.options
+        .iter()
+        .any(|o| o.starts_with(MOUNT_OPTION_ENCRYPTION_KEY))
+}
+
+// take_encryption_key returns the name of the key of the encrypted volume
+// given by the mount options, and the other options.
+fn take_encryption_key(options: &[String]) -> Result<(Option<String>, Vec<String>)> {
+    let mut key = None;
+    let mut others = vec![];
+    for opt in options {
+        match opt.strip_prefix(MOUNT_OPTION_ENCRYPTION_KEY) {
+            // the key is looked up by name in the guest, never a path
+            Some(v) if v.is_empty() || v.contains('/') || v == "." || v == ".." => {
+                return Err(anyhow!("invalid encryption key name {:?}", v));
+            }
+            Some(v) => key = Some(v.to_string()),
+            None => others.push(opt.clone()),
+        }
+    }
+    Ok((key, others))
+}
+
+/// direct_volume_device returns the mount info of the direct volume from its
+/// mountinfo.json, and the stat of the device it tells, which is a regular
+/// file or a block device.
+pub(crate) fn direct_volume_device(mnt_src: &str) -> Result<(DirectVolumeMountInfo, FileStat)> {
+    // get volume mountinfo from mountinfo.json
+    let v = volume_mount_info(mnt_src).context("deserde information from mountinfo.json")?;
+    // check volume type
+    if v.volume_type != KATA_DIRECT_VOLUME_TYPE {
+        return Err(anyhow!("volume type {:?} is invalid", v.volume_type));
+    }
+
+    let fstat = stat::stat(v.device.as_str())
+        .with_context(|| format!("stat volume device file: {}", v.device.clone()))?;
+    if SFlag::from_bits_truncate(fstat.st_mode) != SFlag::S_IFREG
+        && SFlag::from_bits_truncate(fstat.st_mode) != SFlag::S_IFBLK
+    {
+        return Err(anyhow!(
+            "invalid volume device {:?} for volume type {:?}",
+            v.device,
+            v.volume_type
+        ));
+    }
+    Ok((v, fstat))
+}
+
+/// is_raw_image_volume tells if the bind mount asks for its source, a raw
+/// disk image file, to be passed to the guest as a block device.
+pub(crate) fn is_raw_image_volume(m: &oci::Mount) -> bool {
+    m.r#type == KATA_MOUNT_BIND_TYPE && m.options.iter().any(|o| o == MOUNT_OPTION_RAW_IMAGE)
+}
+
+/// raw_image_file returns the path of the raw image file of the volume, it
+/// fails if the source isn't a regular file.
+pub(crate) fn raw_image_file(m: &oci::Mount) -> Result<String> {
+    let path = std::fs::canonicalize(&m.source)
+        .with_context(|| format!("canonicalize raw image {}", m.source))?;
+    if !path.is_file() {
+        return Err(anyhow!("raw image {} isn't a regular file", m.source));
+    }
+    Ok(path.display().to_string())
+}
+
+// take_raw_image_options returns the fs type of the raw image given by the
+// mount options, and the other options
+fn take_raw_image_options(options: &[String]) -> Result<(String, Vec<String>)> {
+    let mut fs_type = DEFAULT_VOLUME_FS_TYPE.to_string();
+    let mut others = vec![];
+    for opt in options {
+        if opt == MOUNT_OPTION_RAW_IMAGE {
+            continue;
+        }
+        match opt.strip_prefix(MOUNT_OPTION_FS_TYPE) {
+            Some("") => return Err(anyhow!("invalid mount option {}", opt)),
+            Some(v) => fs_type = v.to_string(),
+            None => others.push(opt.clone()),
+        }
+    }
+    Ok((fs_type, others))
 }
 
 pub(crate) fn is_block_volume(m: &oci::Mount) -> Result<bool> {
@@ -195,3 +484,142 @@ pub(crate) fn is_block_volume(m: &oci::Mount) -> Result<bool> {
         _ => Ok(false),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_metadata_u32() {
+        let mut metadata = HashMap::new();
+        assert_eq!(
+            get_metadata_u32(&metadata, DIRECT_VOLUME_NUM_QUEUES).unwrap(),
+            0
+        );
+
+        metadata.insert(DIRECT_VOLUME_NUM_QUEUES.to_string(), "4".to_string());
+        metadata.insert(DIRECT_VOLUME_QUEUE_SIZE.to_string(), "deep".to_string());
+        assert_eq!(
+            get_metadata_u32(&metadata, DIRECT_VOLUME_NUM_QUEUES).unwrap(),
+            4
+        );
+        assert!(get_metadata_u32(&metadata, DIRECT_VOLUME_QUEUE_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_get_metadata_queue_size() {
+        let mut metadata = HashMap::new();
+        assert_eq!(get_metadata_queue_size(&metadata).unwrap(), 0);
+
+        for (v, ok) in [
+            ("256", true),
+            ("1024", true),
+            ("100", false),
+            ("2048", false),
+        ] {
+            metadata.insert(DIRECT_VOLUME_QUEUE_SIZE.to_string(), v.to_string());
+            assert_eq!(get_metadata_queue_size(&metadata).is_ok(), ok, "{}", v);
+        }
+    }
+
+    #[test]
+    fn test_is_network_sysfs_path() {
+        for path in [
+            "/sys/devices/virtual/block/rbd0",
+            "/sys/devices/virtual/block/nbd1",
+            "/sys/devices/platform/host2/session1/target2:0:0/2:0:0:0/block/sdb",
+            "/sys/devices/virtual/nvme-fabrics/ctl/nvme1/nvme1n1",
+        ] {
+            assert!(is_network_sysfs_path(path), "{}", path);
+        }
+        for path in [
+            "/sys/devices/pci0000:00/0000:00:1d.0/0000:3d:00.0/nvme/nvme0/nvme0n1",
+            "/sys/devices/virtual/block/loop0",
+            "/sys/devices/pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda",
+        ] {
+            assert!(!is_network_sysfs_path(path), "{}", path);
+        }
+        // no such device
+        assert!(!is_network_block_device(0, 0));
+    }
+
+    #[test]
+    fn test_apply_io_options() {
+        let mut config = BlockConfig::default();
+        let options = [
+            "rbind",
+            "kata.block_device_aio=io_uring",
+            "kata.block_device_cache_direct=true",
+            "kata.guest_pci_slot=5",
+            "ro",
+        ]
+        .iter()
+        .map(|o| o.to_string())
+        .collect::<Vec<_>>();
+        let others = apply_io_options(&mut config, &options).unwrap();
+        assert_eq!(others, vec!["rbind".to_string(), "ro".to_string()]);
+        assert_eq!(config.aio, "io_uring");
+        assert_eq!(config.cache_direct, Some(true));
+        assert_eq!(config.cache_noflush, None);
+        assert_eq!(config.guest_pci_slot, Some(5));
+
+        for opt in [
+            "kata.block_device_aio=posix",
+            "kata.block_device_cache_noflush=yes",
+            "kata.guest_pci_slot=0x05",
+        ] {
+            let mut config = BlockConfig::default();
+            assert!(apply_io_options(&mut config, &[opt.to_string()]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_raw_image_volume() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk.img");
+        std::fs::write(&image, "").unwrap();
+        let mut m = oci::Mount {
+            destination: "/data".to_string(),
+            r#type: KATA_MOUNT_BIND_TYPE.to_string(),
+            source: image.display().to_string(),
+            options: ["rbind", "kata.raw_image", "kata.fs_type=xfs", "ro"]
+                .iter()
+                .map(|o| o.to_string())
+                .collect(),
+        };
+        assert!(is_raw_image_volume(&m));
+        assert_eq!(raw_image_file(&m).unwrap(), image.display().to_string());
+
+        let (fs_type, others) = take_raw_image_options(&m.options).unwrap();
+        assert_eq!(fs_type, "xfs");
+        assert_eq!(others, vec!["rbind".to_string(), "ro".to_string()]);
+        let (fs_type, _) = take_raw_image_options(&others).unwrap();
+        assert_eq!(fs_type, DEFAULT_VOLUME_FS_TYPE);
+        assert!(take_raw_image_options(&["kata.fs_type=".to_string()]).is_err());
+
+        // only a regular file is attached
+        m.source = dir.path().display().to_string();
+        assert!(raw_image_file(&m).is_err());
+        m.options.retain(|o| o != MOUNT_OPTION_RAW_IMAGE);
+        assert!(!is_raw_image_volume(&m));
+    }
+
+    #[test]
+    fn test_take_encryption_key() {
+        let options = ["rbind", "kata.encryption_key=volume-key", "ro"]
+            .iter()
+            .map(|o| o.to_string())
+            .collect::<Vec<_>>();
+        let (key, others) = take_encryption_key(&options).unwrap();
+        assert_eq!(key.as_deref(), Some("volume-key"));
+        assert_eq!(others, vec!["rbind".to_string(), "ro".to_string()]);
+
+        let (key, others) = take_encryption_key(&others).unwrap();
+        assert_eq!(key, None);
+        assert_eq!(others.len(), 2);
+
+        for opt in ["kata.encryption_key=", "kata.encryption_key=../key"] {
+            assert!(take_encryption_key(&[opt.to_string()]).is_err());
+        }
+    }
+}
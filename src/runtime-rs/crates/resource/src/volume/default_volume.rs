@@ -45,4 +45,9 @@ impl Volume for DefaultVolume {
     fn get_device_id(&self) -> Result<Option<String>> {
         Ok(None)
     }
+
+    // the mount is passed through to the guest as is
+    fn is_migratable(&self) -> bool {
+        true
+    }
 }
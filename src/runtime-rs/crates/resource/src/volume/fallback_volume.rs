@@ -0,0 +1,261 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use agent::Agent;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use hypervisor::device::device_manager::DeviceManager;
+use kata_sys_util::mount::get_linux_mount_info;
+use kata_types::annotations::KATA_ANNO_CONTAINER_SHARE_FS_FALLBACK;
+use tokio::sync::RwLock;
+
+use super::{
+    share_fs_volume::{copy_file_name, copy_file_to_guest, is_small_file},
+    Volume,
+};
+use crate::share_fs::is_guest_shared_path;
+
+// the context of the error of the agent naming the storage it failed to add
+const AGENT_ADD_STORAGE: &str = "add storage ";
+
+/// VolumeFallback is how a volume the guest failed to mount is degraded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VolumeFallback {
+    /// bound from the shared directory of the sandbox, without its own
+    /// storage in the guest
+    Bind,
+    /// copied into the guest, the updates on the host aren't seen
+    Copy,
+}
+
+/// FallbackEligibility tells which volumes of a container fall back if the
+/// guest fails to mount them.
+pub(crate) struct FallbackEligibility<'a> {
+    fs_types: &'a [String],
+    destinations: Vec<&'a str>,
+}
+
+impl<'a> FallbackEligibility<'a> {
+    pub(crate) fn new(fs_types: &'a [String], annotations: &'a HashMap<String, String>) -> Self {
+        let destinations = annotations
+            .get(KATA_ANNO_CONTAINER_SHARE_FS_FALLBACK)
+            .map(|d| {
+                d.split(',')
+                    .map(|d| d.trim())
+                    .filter(|d| !d.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            fs_types,
+            destinations,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.fs_types.is_empty() && self.destinations.is_empty()
+    }
+
+    /// is_eligible tells if the volume mounted from the source on the host
+    /// at the destination in the container falls back.
+    pub(crate) fn is_eligible(&self, source: &str, destination: &str) -> bool {
+        if self.destinations.contains(&destination) {
+            return true;
+        }
+        if self.fs_types.is_empty() {
+            return false;
+        }
+        match host_fs_type(Path::new(source)) {
+            Some(fs_type) => self.fs_types.contains(&fs_type),
+            None => false,
+        }
+    }
+}
+
+// host_fs_type returns the filesystem type of the mount the path is on
+fn host_fs_type(path: &Path) -> Option<String> {
+    let path = std::fs::canonicalize(path).ok()?;
+    path.ancestors()
+        .filter_map(|p| p.to_str())
+        .find_map(|p| get_linux_mount_info(p).ok())
+        .map(|info| info.fs_type)
+}
+
+/// failed_storages returns the guest mount points of the storages the agent
+/// failed to add, from its error.
+pub(crate) fn failed_storages(error: &str) -> Vec<&str> {
+    error
+        .match_indices(AGENT_ADD_STORAGE)
+        .filter_map(|(i, _)| {
+            error[i + AGENT_ADD_STORAGE.len()..]
+                .split_whitespace()
+                .next()
+        })
+        .collect()
+}
+
+/// FallbackVolume is a volume whose storage the guest failed to mount,
+/// degraded to mount without it. The volume is still cleaned up as before.
+pub(crate) struct FallbackVolume {
+    volume: Arc<dyn Volume>,
+    fallback: VolumeFallback,
+    mounts: Vec<oci::Mount>,
+}
+
+impl FallbackVolume {
+    /// new degrades the volume whose storage failed: it's bound from the
+    /// shared directory if the storage is from there, or as a last resort
+    /// copied into the guest if it's a small file the container only reads.
+    /// None if it can't be.
+    pub(crate) async fn new(
+        volume: Arc<dyn Volume>,
+        failed: &agent::Storage,
+        cid: &str,
+        source: &str,
+        agent: &dyn Agent,
+        copy_file_max_size: u64,
+    ) -> Result<Option<Self>> {
+        let read_only = volume
+            .get_volume_mount()?
+            .iter()
+            .all(|m| m.options.iter().any(|opt| opt == "ro"));
+        let (fallback, guest_path) = if is_guest_shared_path(&failed.source) {
+            (VolumeFallback::Bind, failed.source.clone())
+        } else if read_only && is_small_file(source, copy_file_max_size) {
+            let src = std::fs::canonicalize(source)
+                .with_context(|| format!("failed to canonicalize file {}", source))?;
+            let dest = copy_file_to_guest(agent, &src, &copy_file_name(cid, source)).await?;
+            (VolumeFallback::Copy, dest)
+        } else {
+            return Ok(None);
+        };
+
+        let mounts = volume
+            .get_volume_mount()?
+            .into_iter()
+            .map(|mut m| {
+                if m.source == failed.mount_point {
+                    m.source = guest_path.clone();
+                }
+                m
+            })
+            .collect();
+        Ok(Some(Self {
+            volume,
+            fallback,
+            mounts,
+        }))
+    }
+}
+
+#[async_trait]
+impl Volume for FallbackVolume {
+    fn get_volume_mount(&self) -> Result<Vec<oci::Mount>> {
+        Ok(self.mounts.clone())
+    }
+
+    fn get_storage(&self) -> Result<Vec<agent::Storage>> {
+        Ok(vec![])
+    }
+
+    fn get_device_id(&self) -> Result<Option<String>> {
+        self.volume.get_device_id()
+    }
+
+    // the copy is kept in the guest
+    fn is_migratable(&self) -> bool {
+        self.fallback == VolumeFallback::Copy || self.volume.is_migratable()
+    }
+
+    fn fallback(&self) -> Option<VolumeFallback> {
+        Some(self.fallback)
+    }
+
+    async fn cleanup(&self, device_manager: &RwLock<DeviceManager>) -> Result<()> {
+        self.volume.cleanup(device_manager).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_agent::MockAgent;
+    use crate::volume::default_volume::DefaultVolume;
+
+    #[test]
+    fn test_failed_storages() {
+        let error = "agent create container\n\nCaused by:\n    0: add storage /run/kata-containers/shared/containers/watchable/a\n    1: EINVAL";
+        assert_eq!(
+            failed_storages(error),
+            vec!["/run/kata-containers/shared/containers/watchable/a"]
+        );
+        assert!(failed_storages("no such file or directory").is_empty());
+    }
+
+    #[test]
+    fn test_fallback_eligibility() {
+        let fs_types = vec!["vfat".to_string()];
+        let annotations: HashMap<String, String> = vec![(
+            KATA_ANNO_CONTAINER_SHARE_FS_FALLBACK.to_string(),
+            "/config, /data".to_string(),
+        )]
+        .into_iter()
+        .collect();
+        let eligibility = FallbackEligibility::new(&fs_types, &annotations);
+        assert!(!eligibility.is_empty());
+        assert!(eligibility.is_eligible("/var/lib/config", "/data"));
+        // not on a vfat filesystem of the host
+        assert!(!eligibility.is_eligible("/proc", "/logs"));
+
+        let annotations = HashMap::new();
+        assert!(FallbackEligibility::new(&[], &annotations).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fallback_volume_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("hosts");
+        std::fs::write(&source, "127.0.0.1 localhost").unwrap();
+        let source = source.display().to_string();
+        let failed = agent::Storage {
+            source: "/dev/vdb".to_string(),
+            mount_point: "/run/kata-containers/sandbox/storage/hosts".to_string(),
+            ..Default::default()
+        };
+        let mut m = oci::Mount {
+            destination: "/etc/hosts".to_string(),
+            source: failed.mount_point.clone(),
+            options: vec!["rbind".to_string(), "ro".to_string()],
+            ..Default::default()
+        };
+        let agent = MockAgent::new("3.2.0");
+
+        // the copy is named after the container and the source
+        let volume = Arc::new(DefaultVolume::new(&m).unwrap());
+        let fallback = FallbackVolume::new(volume, &failed, "c1", &source, &agent, 4096)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fallback.fallback(), Some(VolumeFallback::Copy));
+        let guest_path = fallback.get_volume_mount().unwrap()[0].source.clone();
+        assert!(guest_path.starts_with("/run/kata-containers/sandbox/"));
+        assert!(guest_path.ends_with(&format!("/passthrough/{}", copy_file_name("c1", &source))));
+        assert_eq!(agent.calls(), vec!["copy_file"]);
+
+        // the writes to the copy wouldn't get back to the host
+        m.options.pop();
+        let volume = Arc::new(DefaultVolume::new(&m).unwrap());
+        assert!(
+            FallbackVolume::new(volume, &failed, "c1", &source, &agent, 4096)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(agent.calls().len(), 1);
+    }
+}
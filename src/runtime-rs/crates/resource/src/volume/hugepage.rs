@@ -96,6 +96,10 @@ impl Volume for Hugepage {
     fn get_device_id(&self) -> Result<Option<String>> {
         Ok(None)
     }
+
+    fn is_migratable(&self) -> bool {
+        true
+    }
 }
 
 pub(crate) fn get_huge_page_option(m: &oci::Mount) -> Result<Option<Vec<String>>> {
@@ -0,0 +1,155 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{os::unix::fs::MetadataExt, path::Path};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use hypervisor::device::device_manager::DeviceManager;
+use kata_types::{k8s, mount};
+use tokio::sync::RwLock;
+
+use super::Volume;
+use crate::share_fs::{DEFAULT_KATA_GUEST_SANDBOX_DIR, EPHEMERAL_PATH};
+
+// the agent creates the directory of the local storage in the guest
+pub(crate) const KATA_LOCAL_DEV_TYPE: &str = "local";
+const LOCAL_DIR: &str = "local";
+const LOCAL_DIR_MODE: &str = "mode=0777";
+
+/// LocalVolume is the emptyDir volume of the pod kept in the guest instead
+/// of the host, for the guests not trusting the host: the memory backed ones
+/// are tmpfs, the others are directories of the guest.
+pub(crate) struct LocalVolume {
+    mount: oci::Mount,
+    storage: agent::Storage,
+}
+
+impl LocalVolume {
+    pub(crate) fn new(m: &oci::Mount) -> Result<Self> {
+        let file_name = Path::new(&m.source)
+            .file_name()
+            .context("get file name from mount source")?;
+
+        // the fsGroup of the volume is passed to the guest
+        let mut options = match std::fs::metadata(&m.source) {
+            Ok(meta) if meta.gid() != 0 => vec![format!("fsgid={}", meta.gid())],
+            _ => vec![],
+        };
+        let storage = if m.r#type == mount::KATA_EPHEMERAL_VOLUME_TYPE {
+            agent::Storage {
+                driver: mount::KATA_EPHEMERAL_VOLUME_TYPE.to_string(),
+                source: "tmpfs".to_string(),
+                fs_type: "tmpfs".to_string(),
+                options,
+                mount_point: Path::new(EPHEMERAL_PATH)
+                    .join(file_name)
+                    .display()
+                    .to_string(),
+                ..Default::default()
+            }
+        } else {
+            options.push(LOCAL_DIR_MODE.to_string());
+            agent::Storage {
+                driver: KATA_LOCAL_DEV_TYPE.to_string(),
+                source: KATA_LOCAL_DEV_TYPE.to_string(),
+                fs_type: KATA_LOCAL_DEV_TYPE.to_string(),
+                options,
+                mount_point: Path::new(DEFAULT_KATA_GUEST_SANDBOX_DIR)
+                    .join(LOCAL_DIR)
+                    .join(file_name)
+                    .display()
+                    .to_string(),
+                ..Default::default()
+            }
+        };
+
+        Ok(Self {
+            mount: oci::Mount {
+                destination: m.destination.clone(),
+                r#type: "bind".to_string(),
+                source: storage.mount_point.clone(),
+                options: m.options.clone(),
+            },
+            storage,
+        })
+    }
+}
+
+#[async_trait]
+impl Volume for LocalVolume {
+    fn get_volume_mount(&self) -> Result<Vec<oci::Mount>> {
+        Ok(vec![self.mount.clone()])
+    }
+
+    fn get_storage(&self) -> Result<Vec<agent::Storage>> {
+        Ok(vec![self.storage.clone()])
+    }
+
+    fn get_device_id(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn is_migratable(&self) -> bool {
+        true
+    }
+
+    async fn cleanup(&self, _device_manager: &RwLock<DeviceManager>) -> Result<()> {
+        // the agent removes the storage with the last container using it
+        Ok(())
+    }
+}
+
+pub(crate) fn is_local_volume(m: &oci::Mount) -> bool {
+    m.r#type == mount::KATA_EPHEMERAL_VOLUME_TYPE
+        || (m.r#type == "bind" && k8s::is_empty_dir(&m.source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_volume() {
+        let m = oci::Mount {
+            destination: "/data".to_string(),
+            r#type: "bind".to_string(),
+            source: "/var/lib/kubelet/pods/uid/volumes/kubernetes.io~empty-dir/data".to_string(),
+            options: vec!["rbind".to_string()],
+        };
+        assert!(is_local_volume(&m));
+        let volume = LocalVolume::new(&m).unwrap();
+        let storage = &volume.get_storage().unwrap()[0];
+        assert_eq!(storage.driver, KATA_LOCAL_DEV_TYPE);
+        assert_eq!(
+            storage.mount_point,
+            "/run/kata-containers/sandbox/local/data"
+        );
+        let mounts = volume.get_volume_mount().unwrap();
+        assert_eq!(mounts[0].source, storage.mount_point);
+        assert_eq!(mounts[0].destination, "/data");
+
+        let m = oci::Mount {
+            r#type: mount::KATA_EPHEMERAL_VOLUME_TYPE.to_string(),
+            ..m
+        };
+        assert!(is_local_volume(&m));
+        let volume = LocalVolume::new(&m).unwrap();
+        let storage = &volume.get_storage().unwrap()[0];
+        assert_eq!(storage.fs_type, "tmpfs");
+        assert_eq!(
+            storage.mount_point,
+            "/run/kata-containers/sandbox/ephemeral/data"
+        );
+
+        let m = oci::Mount {
+            r#type: "bind".to_string(),
+            source: "/var/lib/kubelet/pods/uid/volumes/kubernetes.io~secret/token".to_string(),
+            ..m
+        };
+        assert!(!is_local_volume(&m));
+    }
+}
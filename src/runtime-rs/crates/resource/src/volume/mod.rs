@@ -6,21 +6,43 @@
 
 mod block_volume;
 mod default_volume;
+mod fallback_volume;
 pub mod hugepage;
+mod local_volume;
 mod share_fs_volume;
+mod shared_storage;
 mod shm_volume;
+mod stats;
 pub mod utils;
 
-use std::{sync::Arc, vec::Vec};
+use std::{collections::HashSet, path::Path, sync::Arc, time::Instant, vec::Vec};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
+pub(crate) use self::fallback_volume::FallbackEligibility;
+pub use self::fallback_volume::VolumeFallback;
+use self::fallback_volume::{failed_storages, FallbackVolume};
 use self::hugepage::{get_huge_page_limits_map, get_huge_page_option};
-use crate::{share_fs::ShareFs, volume::block_volume::is_block_volume};
+use self::shared_storage::{SharedStorageVolume, StorageRegistry};
+use self::utils::KATA_DIRECT_VOLUME_TYPE;
+use crate::{
+    metrics,
+    plan::{self, PlannedResource},
+    share_fs::ShareFs,
+    volume::block_volume::{has_encryption_key, is_block_volume, is_raw_image_volume},
+};
 use agent::Agent;
 use hypervisor::device::device_manager::DeviceManager;
+use kata_sys_util::mount::parse_propagation;
+use kata_types::k8s::is_watchable_mount;
+use nix::mount::MsFlags;
+pub(crate) use shm_volume::is_shim_volume;
+pub use shm_volume::ShmLimits;
+pub(crate) use stats::volume_stats;
+pub use stats::{is_volume_not_found, UsageUnit, VolumeNotFound, VolumeStats};
 
 const BIND: &str = "bind";
 
@@ -28,13 +50,69 @@ const BIND: &str = "bind";
 pub trait Volume: Send + Sync {
     fn get_volume_mount(&self) -> Result<Vec<oci::Mount>>;
     fn get_storage(&self) -> Result<Vec<agent::Storage>>;
+    /// get_referenced_storage returns the storages the volume mounts from,
+    /// including the ones added by the other containers.
+    fn get_referenced_storage(&self) -> Result<Vec<agent::Storage>> {
+        self.get_storage()
+    }
     fn get_device_id(&self) -> Result<Option<String>>;
+    /// is_migratable tells if the volume follows the VM migrated to another
+    /// host, i.e. it's kept in the guest or backed by a storage reachable
+    /// from the other hosts rather than a local one of the host.
+    fn is_migratable(&self) -> bool;
+    /// fallback tells how the volume is degraded if the guest failed to
+    /// mount it, none if it isn't.
+    fn fallback(&self) -> Option<VolumeFallback> {
+        None
+    }
     async fn cleanup(&self, device_manager: &RwLock<DeviceManager>) -> Result<()>;
 }
 
+/// VolumeState is the saved state of a volume of a container.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+pub struct VolumeState {
+    pub cid: String,
+    /// source of the oci mount on the host
+    pub source: String,
+    pub device_id: Option<String>,
+    /// size in bytes the block volume is resized to, none if it isn't
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+struct ContainerVolume {
+    cid: String,
+    source: String,
+    volume: Arc<dyn Volume>,
+    // the size of the block volume once resized
+    size: Option<u64>,
+}
+
 #[derive(Default)]
 pub struct VolumeResourceInner {
-    volumes: Vec<Arc<dyn Volume>>,
+    volumes: Vec<ContainerVolume>,
+    storages: StorageRegistry,
+    // the guest doesn't trust the host, the emptyDir volumes are kept in
+    // the guest rather than on the host
+    trusted_storage: bool,
+    // the volumes saved before the restore, saved again until their
+    // container is deleted
+    restored: Vec<VolumeState>,
+}
+
+impl VolumeResourceInner {
+    fn release_storages(&mut self, volume: &dyn Volume) {
+        for s in volume.get_referenced_storage().unwrap_or_default() {
+            self.storages.release(&s);
+        }
+    }
+
+    // remove forgets the volume at the index and releases its storages
+    fn remove(&mut self, index: usize) -> Arc<dyn Volume> {
+        let volume = self.volumes.remove(index).volume;
+        self.release_storages(volume.as_ref());
+        volume
+    }
 }
 
 #[derive(Default)]
@@ -47,54 +125,249 @@ impl VolumeResource {
         Self::default()
     }
 
+    /// restore keeps the state of the volumes saved, with their size, so
+    /// that it's saved again.
+    pub fn restore(volumes: Vec<VolumeState>) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(VolumeResourceInner {
+                restored: volumes,
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// set_trusted_storage keeps the emptyDir volumes handled from now on in
+    /// the guest, e.g. for the confidential guests.
+    pub async fn set_trusted_storage(&self, trusted: bool) {
+        self.inner.write().await.trusted_storage = trusted;
+    }
+
+    /// handler_volumes sets up the volumes of the container, the ones shared
+    /// through the fs go through share_fs, but the watchable ones which go
+    /// through watchable_share_fs: the agent only watches the ones under the
+    /// share dir of the sandbox.
+    #[allow(clippy::too_many_arguments)]
     pub async fn handler_volumes(
         &self,
         share_fs: &Option<Arc<dyn ShareFs>>,
+        watchable_share_fs: &Option<Arc<dyn ShareFs>>,
         cid: &str,
         spec: &oci::Spec,
         d: &RwLock<DeviceManager>,
         sid: &str,
         agent: Arc<dyn Agent>,
+        copy_file_max_size: u64,
+        shm_limits: ShmLimits,
     ) -> Result<Vec<Arc<dyn Volume>>> {
-        let mut volumes: Vec<Arc<dyn Volume>> = vec![];
+        let mut volumes = vec![];
+        if let Err(e) = self
+            .add_volumes(
+                share_fs,
+                watchable_share_fs,
+                cid,
+                spec,
+                d,
+                sid,
+                agent,
+                copy_file_max_size,
+                shm_limits,
+                &mut volumes,
+            )
+            .await
+        {
+            self.rollback(&volumes, d).await;
+            return Err(e);
+        }
+        Ok(volumes)
+    }
+
+    // add_volumes sets up the volumes of the container one by one, the ones
+    // set up are added to volumes so that they're rolled back on a failure
+    #[allow(clippy::too_many_arguments)]
+    async fn add_volumes(
+        &self,
+        share_fs: &Option<Arc<dyn ShareFs>>,
+        watchable_share_fs: &Option<Arc<dyn ShareFs>>,
+        cid: &str,
+        spec: &oci::Spec,
+        d: &RwLock<DeviceManager>,
+        sid: &str,
+        agent: Arc<dyn Agent>,
+        copy_file_max_size: u64,
+        shm_limits: ShmLimits,
+        volumes: &mut Vec<Arc<dyn Volume>>,
+    ) -> Result<()> {
         let oci_mounts = &spec.mounts;
         info!(sl!(), " oci mount is : {:?}", oci_mounts.clone());
+        let trusted_storage = self.inner.read().await.trusted_storage;
         // handle mounts
         for m in oci_mounts {
-            let read_only = m.options.iter().any(|opt| opt == "ro");
-            let volume: Arc<dyn Volume> = if shm_volume::is_shim_volume(m) {
-                let shm_size = shm_volume::DEFAULT_SHM_SIZE;
-                Arc::new(
-                    shm_volume::ShmVolume::new(m, shm_size)
-                        .with_context(|| format!("new shm volume {:?}", m))?,
-                )
-            } else if is_block_volume(m).context("block volume type")? {
-                // handle block volume
-                Arc::new(
-                    block_volume::BlockVolume::new(d, m, read_only, cid, sid)
+            // the setup of each volume is observed by its type
+            let start = Instant::now();
+            let mut volume_type = "default";
+            let result: Result<Option<Arc<dyn Volume>>> = async {
+                let read_only = m.options.iter().any(|opt| opt == "ro");
+                let kind = VolumeKind::new(m, share_fs.is_some(), trusted_storage)?;
+                volume_type = kind.name();
+                let volume: Arc<dyn Volume> = match kind {
+                    VolumeKind::Shm => {
+                        let shm_size = shm_limits.size(m)?;
+                        Arc::new(
+                            shm_volume::ShmVolume::new(m, shm_size)
+                                .with_context(|| format!("new shm volume {:?}", m))?,
+                        )
+                    }
+                    VolumeKind::Block => Arc::new(
+                        block_volume::BlockVolume::new(d, m, read_only, cid, sid)
+                            .await
+                            .with_context(|| format!("new share fs volume {:?}", m))?,
+                    ),
+                    VolumeKind::RawImage => Arc::new(
+                        block_volume::BlockVolume::new_from_image(d, m, read_only, cid, sid)
+                            .await
+                            .with_context(|| format!("new raw image volume {:?}", m))?,
+                    ),
+                    VolumeKind::Hugepage(options) => {
+                        // get hugepage limits from oci
+                        let hugepage_limits =
+                            get_huge_page_limits_map(spec).context("get huge page option")?;
+                        // handle container hugepage
+                        Arc::new(
+                            hugepage::Hugepage::new(m, hugepage_limits, options)
+                                .with_context(|| format!("handle hugepages {:?}", m))?,
+                        )
+                    }
+                    VolumeKind::Local => Arc::new(
+                        local_volume::LocalVolume::new(m)
+                            .with_context(|| format!("new local volume {:?}", m))?,
+                    ),
+                    VolumeKind::ShareFs => Arc::new(
+                        share_fs_volume::ShareFsVolume::new(
+                            if is_watchable_mount(&m.source) {
+                                watchable_share_fs
+                            } else {
+                                share_fs
+                            },
+                            m,
+                            cid,
+                            read_only,
+                            agent.clone(),
+                            copy_file_max_size,
+                        )
                         .await
                         .with_context(|| format!("new share fs volume {:?}", m))?,
-                )
-            } else if let Some(options) =
-                get_huge_page_option(m).context("failed to check huge page")?
+                    ),
+                    VolumeKind::Skip => {
+                        info!(sl!(), "skip volume {:?}", m);
+                        return Ok(None);
+                    }
+                    VolumeKind::Default => Arc::new(
+                        default_volume::DefaultVolume::new(m)
+                            .with_context(|| format!("new default volume {:?}", m))?,
+                    ),
+                };
+                Ok(Some(volume))
+            }
+            .await;
+            if !matches!(result, Ok(None)) {
+                metrics::observe(
+                    metrics::OP_VOLUME_SETUP,
+                    volume_type,
+                    start.elapsed(),
+                    result.is_ok(),
+                );
+            }
+            let volume = match result? {
+                Some(volume) => volume,
+                None => continue,
+            };
+
+            let mut inner = self.inner.write().await;
+            let volume = match SharedStorageVolume::new(&mut inner.storages, volume.clone()) {
+                Ok(volume) => volume,
+                Err(e) => {
+                    // unregistered, the volume is rolled back by itself
+                    volumes.push(volume);
+                    return Err(e).with_context(|| format!("share storage of volume {:?}", m));
+                }
+            };
+            volumes.push(volume.clone());
+            inner.volumes.push(ContainerVolume {
+                cid: cid.to_owned(),
+                source: m.source.clone(),
+                volume,
+                size: None,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// handler_unshared_volumes handles the volumes when nothing is shared
+    /// with the guest from the host, e.g. with the remote hypervisors: the
+    /// small regular files are copied into the guest, the block volumes are
+    /// rejected, and the other mounts are left untouched for the remote side.
+    pub async fn handler_unshared_volumes(
+        &self,
+        cid: &str,
+        spec: &oci::Spec,
+        agent: Arc<dyn Agent>,
+        copy_file_max_size: u64,
+    ) -> Result<Vec<Arc<dyn Volume>>> {
+        let mut volumes = vec![];
+        if let Err(e) = self
+            .add_unshared_volumes(cid, spec, agent, copy_file_max_size, &mut volumes)
+            .await
+        {
+            // nothing is set up on the host without host sharing
+            self.forget(&volumes).await;
+            return Err(e);
+        }
+        Ok(volumes)
+    }
+
+    async fn add_unshared_volumes(
+        &self,
+        cid: &str,
+        spec: &oci::Spec,
+        agent: Arc<dyn Agent>,
+        copy_file_max_size: u64,
+        volumes: &mut Vec<Arc<dyn Volume>>,
+    ) -> Result<()> {
+        for m in spec.mounts.iter() {
+            // the source of the mount may only exist on the remote side
+            if m.r#type == KATA_DIRECT_VOLUME_TYPE
+                || is_block_volume(m).unwrap_or_default()
+                || is_raw_image_volume(m)
             {
-                // get hugepage limits from oci
-                let hugepage_limits =
-                    get_huge_page_limits_map(spec).context("get huge page option")?;
-                // handle container hugepage
-                Arc::new(
-                    hugepage::Hugepage::new(m, hugepage_limits, options)
-                        .with_context(|| format!("handle hugepages {:?}", m))?,
-                )
-            } else if share_fs_volume::is_share_fs_volume(m) {
+                return Err(anyhow!(
+                    "block volume {} of container {} is unsupported without host sharing",
+                    m.source,
+                    cid
+                ));
+            }
+            if has_encryption_key(m) {
+                return Err(unencrypted_volume(m));
+            }
+
+            // the copy is the only way into the guest without the share fs,
+            // even for the files written by the container
+            let volume: Arc<dyn Volume> = if share_fs_volume::is_share_fs_volume(m)
+                && share_fs_volume::is_small_file(&m.source, copy_file_max_size)
+            {
+                let read_only = m.options.iter().any(|opt| opt == "ro");
                 Arc::new(
-                    share_fs_volume::ShareFsVolume::new(share_fs, m, cid, read_only, agent.clone())
-                        .await
-                        .with_context(|| format!("new share fs volume {:?}", m))?,
+                    share_fs_volume::ShareFsVolume::new(
+                        &None,
+                        m,
+                        cid,
+                        read_only,
+                        agent.clone(),
+                        copy_file_max_size,
+                    )
+                    .await
+                    .with_context(|| format!("copy volume {:?}", m))?,
                 )
-            } else if is_skip_volume(m) {
-                info!(sl!(), "skip volume {:?}", m);
-                continue;
             } else {
                 Arc::new(
                     default_volume::DefaultVolume::new(m)
@@ -104,10 +377,311 @@ impl VolumeResource {
 
             volumes.push(volume.clone());
             let mut inner = self.inner.write().await;
-            inner.volumes.push(volume);
+            inner.volumes.push(ContainerVolume {
+                cid: cid.to_owned(),
+                source: m.source.clone(),
+                volume,
+                size: None,
+            });
         }
 
-        Ok(volumes)
+        Ok(())
+    }
+
+    // rollback undoes the volumes set up for a container which fails to be
+    // created, the same way as they're cleaned up with the container
+    pub(crate) async fn rollback(&self, volumes: &[Arc<dyn Volume>], d: &RwLock<DeviceManager>) {
+        self.forget(volumes).await;
+        for v in volumes.iter().rev() {
+            info!(sl!(), "roll back volume {:?}", v.get_volume_mount());
+            if let Err(e) = v.cleanup(d).await {
+                warn!(
+                    sl!(),
+                    "couldn't roll back volume {:?}: {:?}",
+                    v.get_volume_mount(),
+                    e
+                );
+            }
+        }
+    }
+
+    // forget removes the volumes from the registry
+    async fn forget(&self, volumes: &[Arc<dyn Volume>]) {
+        let mut inner = self.inner.write().await;
+        for v in volumes {
+            if let Some(index) = inner
+                .volumes
+                .iter()
+                .position(|cv| Arc::ptr_eq(&cv.volume, v))
+            {
+                inner.remove(index);
+            }
+        }
+    }
+
+    /// get_volume returns the volume of the container mounted from the
+    /// source on the host.
+    pub async fn get_volume(&self, cid: &str, source: &str) -> Result<Arc<dyn Volume>> {
+        let inner = self.inner.read().await;
+        inner
+            .volumes
+            .iter()
+            .find(|v| v.cid == cid && v.source == source)
+            .map(|v| v.volume.clone())
+            .ok_or_else(|| anyhow!("volume {} of container {} not found", source, cid))
+    }
+
+    /// find_volume returns the volume mounted from the source on the host by
+    /// any container, e.g. from the publish path of the kubelet.
+    pub async fn find_volume(&self, source: &str) -> Option<Arc<dyn Volume>> {
+        let inner = self.inner.read().await;
+        inner
+            .volumes
+            .iter()
+            .find(|v| Path::new(&v.source) == Path::new(source))
+            .map(|v| v.volume.clone())
+    }
+
+    /// set_volume_size records the size the volume of the container mounted
+    /// from the source on the host is resized to.
+    pub async fn set_volume_size(&self, cid: &str, source: &str, size: u64) -> Result<()> {
+        let mut inner = self.inner.write().await;
+        if let Some(v) = inner
+            .volumes
+            .iter_mut()
+            .find(|v| v.cid == cid && v.source == source)
+        {
+            v.size = Some(size);
+            return Ok(());
+        }
+        let v = inner
+            .restored
+            .iter_mut()
+            .find(|v| v.cid == cid && v.source == source)
+            .ok_or_else(|| anyhow!("volume {} of container {} not found", source, cid))?;
+        v.size = Some(size);
+        Ok(())
+    }
+
+    /// removable_storages returns the guest mount points of the storages
+    /// the volume is the last one to mount from, the storages shared with
+    /// other containers are left in the guest.
+    pub async fn removable_storages(&self, volume: &dyn Volume) -> Result<Vec<String>> {
+        let inner = self.inner.read().await;
+        let mut mount_points = vec![];
+        for s in volume.get_referenced_storage()? {
+            if let Some(mount_point) = inner.storages.last_reference(&s) {
+                mount_points.push(mount_point);
+            }
+        }
+        Ok(mount_points)
+    }
+
+    /// remove_volume forgets the volume of the container mounted from the
+    /// source on the host.
+    pub async fn remove_volume(&self, cid: &str, source: &str) -> Option<Arc<dyn Volume>> {
+        let mut inner = self.inner.write().await;
+        let index = inner
+            .volumes
+            .iter()
+            .position(|v| v.cid == cid && v.source == source)?;
+        Some(inner.remove(index))
+    }
+
+    /// delete_container forgets the volumes of the deleted container, they
+    /// are cleaned up with the container, and the agent drops the references
+    /// of the container on the storages. The volumes forgotten are returned.
+    pub async fn delete_container(&self, cid: &str) -> Vec<Arc<dyn Volume>> {
+        let mut inner = self.inner.write().await;
+        let (deleted, volumes): (Vec<_>, Vec<_>) =
+            inner.volumes.drain(..).partition(|v| v.cid == cid);
+        inner.volumes = volumes;
+        for v in deleted.iter() {
+            inner.release_storages(v.volume.as_ref());
+        }
+        inner.restored.retain(|v| v.cid != cid);
+        deleted.into_iter().map(|v| v.volume).collect()
+    }
+
+    /// plan tells what the volumes of the spec would be set up with, and why
+    /// they couldn't, without setting up any of them. The volumes are copied
+    /// or left to the remote side without host sharing.
+    pub async fn plan(
+        &self,
+        spec: &oci::Spec,
+        share_fs: bool,
+        host_sharing: bool,
+        copy_file_max_size: u64,
+        shm_limits: &ShmLimits,
+    ) -> Vec<PlannedResource> {
+        let trusted_storage = self.inner.read().await.trusted_storage;
+        let mut volumes = vec![];
+        for m in spec.mounts.iter() {
+            let planned = if host_sharing {
+                plan_volume(m, spec, share_fs, trusted_storage, shm_limits).transpose()
+            } else {
+                Some(plan_unshared_volume(m, copy_file_max_size))
+            };
+            if let Some(backend) = planned {
+                volumes.push(
+                    PlannedResource::new(plan::KIND_VOLUME, &m.source, &m.destination)
+                        .with_result(backend),
+                );
+            }
+        }
+        volumes
+    }
+
+    /// guest_mount_count returns the mounts of the volumes in the guest: the
+    /// mounts in the containers and the storages they're mounted from, the
+    /// storages shared by several volumes are counted once.
+    pub async fn guest_mount_count(&self) -> usize {
+        let inner = self.inner.read().await;
+        let volumes: Vec<_> = inner.volumes.iter().map(|v| v.volume.clone()).collect();
+        guest_mount_count(&volumes)
+    }
+
+    /// non_migratable_volumes returns the container ids and the sources of
+    /// the volumes left behind if the VM migrates to another host, the
+    /// sandbox can't be migrated as is with any of them.
+    pub async fn non_migratable_volumes(&self) -> Vec<(String, String)> {
+        let inner = self.inner.read().await;
+        inner
+            .volumes
+            .iter()
+            .filter(|v| !v.volume.is_migratable())
+            .map(|v| (v.cid.clone(), v.source.clone()))
+            .collect()
+    }
+
+    pub async fn save(&self) -> Vec<VolumeState> {
+        let inner = self.inner.read().await;
+        let mut states: Vec<VolumeState> = inner
+            .volumes
+            .iter()
+            .map(|v| VolumeState {
+                cid: v.cid.clone(),
+                source: v.source.clone(),
+                device_id: v.volume.get_device_id().ok().flatten(),
+                size: v.size,
+            })
+            .collect();
+        for v in inner.restored.iter() {
+            if !states
+                .iter()
+                .any(|s| s.cid == v.cid && s.source == v.source)
+            {
+                states.push(v.clone());
+            }
+        }
+        states
+    }
+
+    /// fallback_volumes degrades the eligible volumes of the container whose
+    /// storages the agent failed to add, named in its error, so that the
+    /// container could be created again. It returns all the volumes of the
+    /// container, none if no volume is degraded.
+    pub(crate) async fn fallback_volumes(
+        &self,
+        cid: &str,
+        error: &str,
+        eligibility: &FallbackEligibility<'_>,
+        agent: &dyn Agent,
+        copy_file_max_size: u64,
+    ) -> Result<Option<Vec<Arc<dyn Volume>>>> {
+        let failed = failed_storages(error);
+        if failed.is_empty() {
+            return Ok(None);
+        }
+
+        let candidates: Vec<(Arc<dyn Volume>, String)> = {
+            let inner = self.inner.read().await;
+            inner
+                .volumes
+                .iter()
+                .filter(|v| v.cid == cid)
+                .map(|v| (v.volume.clone(), v.source.clone()))
+                .collect()
+        };
+        let mut degraded = vec![];
+        for (volume, source) in candidates {
+            let storage = match volume
+                .get_storage()?
+                .into_iter()
+                .find(|s| failed.contains(&s.mount_point.as_str()))
+            {
+                Some(storage) => storage,
+                None => continue,
+            };
+            let destination = volume
+                .get_volume_mount()?
+                .first()
+                .map(|m| m.destination.clone())
+                .unwrap_or_default();
+            if !eligibility.is_eligible(&source, &destination) {
+                info!(
+                    sl!(),
+                    "volume {} of container {} failed in the guest, it isn't eligible to fall back",
+                    destination,
+                    cid
+                );
+                continue;
+            }
+            match FallbackVolume::new(
+                volume.clone(),
+                &storage,
+                cid,
+                &source,
+                agent,
+                copy_file_max_size,
+            )
+            .await
+            {
+                Ok(Some(fallback)) => {
+                    warn!(
+                        sl!(),
+                        "volume {} of container {} failed in the guest, fall back to {:?}",
+                        destination,
+                        cid,
+                        fallback.fallback()
+                    );
+                    degraded.push((volume, Arc::new(fallback) as Arc<dyn Volume>));
+                }
+                Ok(None) => info!(
+                    sl!(),
+                    "volume {} of container {} failed in the guest, no fallback for it",
+                    destination,
+                    cid
+                ),
+                Err(e) => warn!(
+                    sl!(),
+                    "couldn't fall back volume {} of container {}: {:?}", destination, cid, e
+                ),
+            }
+        }
+        if degraded.is_empty() {
+            return Ok(None);
+        }
+
+        let mut inner = self.inner.write().await;
+        for (volume, fallback) in degraded {
+            inner.release_storages(volume.as_ref());
+            if let Some(v) = inner
+                .volumes
+                .iter_mut()
+                .find(|v| Arc::ptr_eq(&v.volume, &volume))
+            {
+                v.volume = fallback;
+            }
+        }
+        Ok(Some(
+            inner
+                .volumes
+                .iter()
+                .filter(|v| v.cid == cid)
+                .map(|v| v.volume.clone())
+                .collect(),
+        ))
     }
 
     pub async fn dump(&self) {
@@ -115,15 +689,974 @@ impl VolumeResource {
         for v in &inner.volumes {
             info!(
                 sl!(),
-                "volume mount {:?}: count {}",
-                v.get_volume_mount(),
-                Arc::strong_count(v)
+                "volume mount {:?} of container {}: count {}",
+                v.volume.get_volume_mount(),
+                v.cid,
+                Arc::strong_count(&v.volume)
             );
+            if let Some(fallback) = v.volume.fallback() {
+                warn!(
+                    sl!(),
+                    "volume {} of container {} is degraded: {:?}", v.source, v.cid, fallback
+                );
+            }
+        }
+    }
+}
+
+/// needs_share_fs tells if the volume gets into the guest only by the fs
+/// sharing, the regular files are copied into the guest without it.
+pub(crate) fn needs_share_fs(m: &oci::Mount) -> Result<bool> {
+    if shm_volume::is_shim_volume(m)
+        || is_block_volume(m).context("block volume type")?
+        || is_raw_image_volume(m)
+        || get_huge_page_option(m)
+            .context("failed to check huge page")?
+            .is_some()
+        || !share_fs_volume::is_share_fs_volume(m)
+    {
+        return Ok(false);
+    }
+    Ok(!Path::new(&m.source).is_file())
+}
+
+/// unsupported_propagation returns the propagation option of the mount if
+/// it asks for the mounts made later on the host to get into the container,
+/// which its backend can't do: only the directories shared by the fs sharing
+/// see them, neither the block volumes nor the files copied into the guest.
+pub(crate) fn unsupported_propagation(
+    m: &oci::Mount,
+    share_fs_enabled: bool,
+) -> Result<Option<String>> {
+    let from_host = parse_propagation(&m.options)
+        .map(|p| p.intersects(MsFlags::MS_SHARED | MsFlags::MS_SLAVE))
+        .unwrap_or_default();
+    if !from_host {
+        return Ok(None);
+    }
+    if !is_block_volume(m).context("block volume type")?
+        && !is_raw_image_volume(m)
+        && (share_fs_enabled || !share_fs_volume::is_share_fs_volume(m))
+    {
+        return Ok(None);
+    }
+    // the last propagation option is the one in effect
+    Ok(m.options
+        .iter()
+        .rev()
+        .find(|o| parse_propagation(std::slice::from_ref(o)).is_some())
+        .cloned())
+}
+
+// guest_mount_count counts the mounts of the volumes and their storages by
+// their guest mount points, the shared ones refer to the same
+fn guest_mount_count(volumes: &[Arc<dyn Volume>]) -> usize {
+    let mut storages = HashSet::new();
+    let mut mounts = 0;
+    for v in volumes {
+        mounts += v.get_volume_mount().map(|m| m.len()).unwrap_or_default();
+        for s in v.get_referenced_storage().unwrap_or_default() {
+            storages.insert(s.mount_point);
         }
     }
+    mounts + storages.len()
+}
+
+/// is_host_volume tells if the volume is from the host, shared with the guest
+/// or passed as a block device, it counts against max_volumes_per_container.
+pub(crate) fn is_host_volume(m: &oci::Mount) -> bool {
+    share_fs_volume::is_share_fs_volume(m) || is_direct_volume(m)
+}
+
+/// is_direct_volume tells if the volume is a direct volume, whose source is
+/// the key of its mount info rather than a path bound from the host.
+pub(crate) fn is_direct_volume(m: &oci::Mount) -> bool {
+    m.r#type == KATA_DIRECT_VOLUME_TYPE
+}
+
+/// is_device_volume tells if the volume is attached to the guest as a block
+/// device, it counts against max_devices_per_sandbox.
+pub(crate) fn is_device_volume(m: &oci::Mount) -> bool {
+    is_direct_volume(m) || is_block_volume(m).unwrap_or_default() || is_raw_image_volume(m)
+}
+
+/// has_host_source tells if the volume is bound or attached from its source
+/// on the host, the source is kept under the allowed prefixes.
+pub(crate) fn has_host_source(m: &oci::Mount) -> bool {
+    is_host_volume(m) && !shm_volume::is_shim_volume(m)
+}
+
+// unencrypted_volume is the error of the encryption asked for a volume that
+// isn't a block volume, which can't be decrypted in the guest
+fn unencrypted_volume(m: &oci::Mount) -> anyhow::Error {
+    anyhow!(
+        "volume {} of {} can't be encrypted, only the block volumes can",
+        m.source,
+        m.destination
+    )
 }
 
 fn is_skip_volume(_m: &oci::Mount) -> bool {
     // TODO: support volume check
     false
 }
+
+// VolumeKind is the backend a volume is set up with
+enum VolumeKind {
+    Shm,
+    Block,
+    // the raw disk image file passed as a block device, never shared
+    RawImage,
+    Hugepage(Vec<String>),
+    Local,
+    ShareFs,
+    Skip,
+    Default,
+}
+
+impl VolumeKind {
+    // new picks the backend of the mount, the same way for the setup and the
+    // plan of the volume
+    fn new(m: &oci::Mount, share_fs: bool, trusted_storage: bool) -> Result<Self> {
+        if shm_volume::is_shim_volume(m) {
+            return Ok(VolumeKind::Shm);
+        }
+        if is_block_volume(m).context("block volume type")? {
+            return Ok(VolumeKind::Block);
+        }
+        if is_raw_image_volume(m) {
+            return Ok(VolumeKind::RawImage);
+        }
+        // the volume asked to be encrypted isn't set up in the clear
+        if has_encryption_key(m) {
+            return Err(unencrypted_volume(m));
+        }
+        if let Some(options) = get_huge_page_option(m).context("failed to check huge page")? {
+            return Ok(VolumeKind::Hugepage(options));
+        }
+        if trusted_storage && local_volume::is_local_volume(m) {
+            return Ok(VolumeKind::Local);
+        }
+        let is_share_fs_volume = share_fs_volume::is_share_fs_volume(m);
+        // without the fs sharing, nothing but the regular files copied gets
+        // into the guest. The block device a directory is mounted from isn't
+        // passed through, the host has it mounted already: only the direct
+        // volumes and the block devices bound are.
+        if !share_fs && is_share_fs_volume && !Path::new(&m.source).is_file() {
+            return Err(anyhow!(
+                "bind mount {} of {} needs filesystem sharing, use a direct volume or a block device",
+                m.source,
+                m.destination
+            ));
+        }
+        if is_share_fs_volume {
+            return Ok(VolumeKind::ShareFs);
+        }
+        if is_skip_volume(m) {
+            return Ok(VolumeKind::Skip);
+        }
+        Ok(VolumeKind::Default)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            VolumeKind::Shm => "shm",
+            VolumeKind::Block => "block",
+            VolumeKind::RawImage => "raw_image",
+            VolumeKind::Hugepage(_) => "hugepage",
+            VolumeKind::Local => "local",
+            VolumeKind::ShareFs => "share_fs",
+            VolumeKind::Skip => "skip",
+            VolumeKind::Default => "default",
+        }
+    }
+}
+
+// plan_volume tells the backend of the volume, checking what its setup
+// would check without setting it up, none if the volume is skipped
+fn plan_volume(
+    m: &oci::Mount,
+    spec: &oci::Spec,
+    share_fs: bool,
+    trusted_storage: bool,
+    shm_limits: &ShmLimits,
+) -> Result<Option<&'static str>> {
+    let kind = VolumeKind::new(m, share_fs, trusted_storage)?;
+    match &kind {
+        VolumeKind::Shm => {
+            shm_limits.size(m)?;
+        }
+        VolumeKind::Block if m.r#type == KATA_DIRECT_VOLUME_TYPE => {
+            block_volume::direct_volume_device(&m.source)?;
+        }
+        VolumeKind::RawImage => {
+            block_volume::raw_image_file(m)?;
+        }
+        VolumeKind::Hugepage(options) => {
+            let hugepage_limits = get_huge_page_limits_map(spec).context("get huge page option")?;
+            hugepage::Hugepage::new(m, hugepage_limits, options.clone())
+                .with_context(|| format!("handle hugepages {:?}", m))?;
+        }
+        VolumeKind::Local => {
+            local_volume::LocalVolume::new(m)
+                .with_context(|| format!("new local volume {:?}", m))?;
+        }
+        // only the regular files are copied into the guest without the fs
+        // sharing, the others are refused above
+        VolumeKind::ShareFs if !share_fs => return Ok(Some("copy")),
+        VolumeKind::ShareFs => {
+            std::fs::metadata(&m.source)
+                .with_context(|| format!("stat volume source {}", m.source))?;
+        }
+        VolumeKind::Skip => return Ok(None),
+        _ => {}
+    }
+    Ok(Some(kind.name()))
+}
+
+// plan_unshared_volume tells the backend of the volume when nothing is
+// shared with the guest, the same way as add_unshared_volumes picks it
+fn plan_unshared_volume(m: &oci::Mount, copy_file_max_size: u64) -> Result<&'static str> {
+    if m.r#type == KATA_DIRECT_VOLUME_TYPE
+        || is_block_volume(m).unwrap_or_default()
+        || is_raw_image_volume(m)
+    {
+        return Err(anyhow!(
+            "block volume {} is unsupported without host sharing",
+            m.source
+        ));
+    }
+    if has_encryption_key(m) {
+        return Err(unencrypted_volume(m));
+    }
+    if share_fs_volume::is_share_fs_volume(m)
+        && share_fs_volume::is_small_file(&m.source, copy_file_max_size)
+    {
+        return Ok("copy");
+    }
+    Ok("default")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use hypervisor::{qemu::Qemu, Hypervisor};
+    use tokio::sync::Mutex;
+
+    use super::*;
+    use crate::mock_agent::MockAgent;
+    use crate::share_fs::{
+        MountedInfo, ShareFsMount, ShareFsMountResult, ShareFsRootfsConfig, ShareFsVolumeConfig,
+    };
+
+    struct FakeVolume(Option<String>);
+
+    #[async_trait]
+    impl Volume for FakeVolume {
+        fn get_volume_mount(&self) -> Result<Vec<oci::Mount>> {
+            Ok(vec![])
+        }
+
+        fn get_storage(&self) -> Result<Vec<agent::Storage>> {
+            Ok(vec![])
+        }
+
+        fn get_device_id(&self) -> Result<Option<String>> {
+            Ok(self.0.clone())
+        }
+
+        // the block volumes are local ones
+        fn is_migratable(&self) -> bool {
+            self.0.is_none()
+        }
+
+        async fn cleanup(&self, _device_manager: &RwLock<DeviceManager>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // the block volume of a pvc mounted by the container
+    struct FakeBlockVolume(String);
+
+    impl FakeBlockVolume {
+        fn mount_point(&self) -> String {
+            format!("/run/kata-containers/shared/containers/{}-data", self.0)
+        }
+    }
+
+    #[async_trait]
+    impl Volume for FakeBlockVolume {
+        fn get_volume_mount(&self) -> Result<Vec<oci::Mount>> {
+            Ok(vec![oci::Mount {
+                destination: "/data".to_owned(),
+                r#type: "ext4".to_owned(),
+                source: self.mount_point(),
+                options: vec![],
+            }])
+        }
+
+        fn get_storage(&self) -> Result<Vec<agent::Storage>> {
+            Ok(vec![agent::Storage {
+                driver: "blk".to_owned(),
+                source: "/dev/vdb".to_owned(),
+                fs_type: "ext4".to_owned(),
+                mount_point: self.mount_point(),
+                ..Default::default()
+            }])
+        }
+
+        fn get_device_id(&self) -> Result<Option<String>> {
+            Ok(Some("blk1".to_owned()))
+        }
+
+        fn is_migratable(&self) -> bool {
+            false
+        }
+
+        async fn cleanup(&self, _device_manager: &RwLock<DeviceManager>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shared_storage() {
+        let resource = VolumeResource::new();
+        let mut volumes = vec![];
+        for cid in ["c1", "c2", "c3"] {
+            let mut inner = resource.inner.write().await;
+            let volume = Arc::new(FakeBlockVolume(cid.to_owned()));
+            let volume = SharedStorageVolume::new(&mut inner.storages, volume).unwrap();
+            inner.volumes.push(ContainerVolume {
+                cid: cid.to_owned(),
+                source: "/dev/sdb".to_owned(),
+                volume: volume.clone(),
+                size: None,
+            });
+            volumes.push(volume);
+        }
+
+        // the storage is only added by the first container, the others
+        // mount from it
+        let mount_point = "/run/kata-containers/shared/containers/c1-data";
+        assert_eq!(volumes[0].get_storage().unwrap().len(), 1);
+        assert_eq!(resource.guest_mount_count().await, 4);
+        for v in volumes.iter() {
+            assert_eq!(v.get_volume_mount().unwrap()[0].source, mount_point);
+        }
+        for v in volumes[1..].iter() {
+            assert!(v.get_storage().unwrap().is_empty());
+            assert_eq!(v.get_device_id().unwrap(), Some("blk1".to_owned()));
+        }
+
+        // the storage is kept until the last container is done with it
+        for v in volumes.iter() {
+            assert!(resource
+                .removable_storages(v.as_ref())
+                .await
+                .unwrap()
+                .is_empty());
+        }
+        assert!(resource.remove_volume("c2", "/dev/sdb").await.is_some());
+        let deleted = resource.delete_container("c1").await;
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].get_device_id().unwrap(), Some("blk1".to_owned()));
+        assert_eq!(resource.guest_mount_count().await, 2);
+        assert_eq!(
+            resource
+                .removable_storages(volumes[2].as_ref())
+                .await
+                .unwrap(),
+            vec![mount_point.to_owned()]
+        );
+
+        // a new container adds the storage again
+        resource.delete_container("c3").await;
+        let mut inner = resource.inner.write().await;
+        let volume = Arc::new(FakeBlockVolume("c4".to_owned()));
+        let volume = SharedStorageVolume::new(&mut inner.storages, volume).unwrap();
+        assert_eq!(volume.get_storage().unwrap().len(), 1);
+    }
+
+    // the watchable volume of a configmap shared by the share fs, the
+    // storage is mounted at the same point for all the containers
+    struct FakeShareFsVolume;
+
+    impl FakeShareFsVolume {
+        const MOUNT_POINT: &'static str =
+            "/run/kata-containers/shared/containers/passthrough/watchable/sandbox-config";
+    }
+
+    #[async_trait]
+    impl Volume for FakeShareFsVolume {
+        fn get_volume_mount(&self) -> Result<Vec<oci::Mount>> {
+            Ok(vec![oci::Mount {
+                destination: "/etc/config".to_owned(),
+                r#type: "bind".to_owned(),
+                source: Self::MOUNT_POINT.to_owned(),
+                options: vec![],
+            }])
+        }
+
+        fn get_storage(&self) -> Result<Vec<agent::Storage>> {
+            Ok(vec![agent::Storage {
+                driver: "watchable-bind".to_owned(),
+                source: "/run/kata-containers/shared/containers/sandbox-config".to_owned(),
+                fs_type: "bind".to_owned(),
+                mount_point: Self::MOUNT_POINT.to_owned(),
+                ..Default::default()
+            }])
+        }
+
+        fn get_device_id(&self) -> Result<Option<String>> {
+            Ok(None)
+        }
+
+        fn is_migratable(&self) -> bool {
+            false
+        }
+
+        async fn cleanup(&self, _device_manager: &RwLock<DeviceManager>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shared_share_fs_storage() {
+        let resource = VolumeResource::new();
+        let mut volumes = vec![];
+        for cid in ["c1", "c2"] {
+            let mut inner = resource.inner.write().await;
+            let volume =
+                SharedStorageVolume::new(&mut inner.storages, Arc::new(FakeShareFsVolume)).unwrap();
+            inner.volumes.push(ContainerVolume {
+                cid: cid.to_owned(),
+                source: "/var/lib/kubelet/pods/uid/volumes/config".to_owned(),
+                volume: volume.clone(),
+                size: None,
+            });
+            volumes.push(volume);
+        }
+
+        // the storage is added to the agent once
+        assert_eq!(volumes[0].get_storage().unwrap().len(), 1);
+        assert!(volumes[1].get_storage().unwrap().is_empty());
+        assert_eq!(
+            volumes[1].get_volume_mount().unwrap()[0].source,
+            FakeShareFsVolume::MOUNT_POINT
+        );
+
+        // removing the volume of one container keeps the storage
+        assert!(resource
+            .removable_storages(volumes[0].as_ref())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(resource
+            .remove_volume("c1", "/var/lib/kubelet/pods/uid/volumes/config")
+            .await
+            .is_some());
+        assert_eq!(
+            resource
+                .removable_storages(volumes[1].as_ref())
+                .await
+                .unwrap(),
+            vec![FakeShareFsVolume::MOUNT_POINT.to_owned()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remove_volume() {
+        let resource = VolumeResource::new();
+        {
+            let mut inner = resource.inner.write().await;
+            for (cid, source, device_id) in [
+                ("c1", "/dev/sdb", Some("blk1")),
+                ("c1", "/data", None),
+                ("c2", "/dev/sdb", Some("blk1")),
+            ] {
+                inner.volumes.push(ContainerVolume {
+                    cid: cid.to_owned(),
+                    source: source.to_owned(),
+                    volume: Arc::new(FakeVolume(device_id.map(|d| d.to_owned()))),
+                    size: None,
+                });
+            }
+        }
+
+        assert!(resource.get_volume("c1", "/dev/sdb").await.is_ok());
+        assert_eq!(
+            resource.non_migratable_volumes().await,
+            vec![
+                ("c1".to_owned(), "/dev/sdb".to_owned()),
+                ("c2".to_owned(), "/dev/sdb".to_owned()),
+            ]
+        );
+        assert!(resource.remove_volume("c1", "/dev/sdb").await.is_some());
+        assert!(resource.get_volume("c1", "/dev/sdb").await.is_err());
+        assert!(resource.remove_volume("c1", "/dev/sdb").await.is_none());
+
+        // the same source of the other container is kept
+        resource
+            .set_volume_size("c2", "/dev/sdb", 10 << 30)
+            .await
+            .unwrap();
+        assert!(resource.set_volume_size("c1", "/dev/sdb", 0).await.is_err());
+        let state = resource.save().await;
+        assert_eq!(
+            state,
+            vec![
+                VolumeState {
+                    cid: "c1".to_owned(),
+                    source: "/data".to_owned(),
+                    device_id: None,
+                    size: None,
+                },
+                VolumeState {
+                    cid: "c2".to_owned(),
+                    source: "/dev/sdb".to_owned(),
+                    device_id: Some("blk1".to_owned()),
+                    size: Some(10 << 30),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_volume_size() {
+        let state = vec![
+            VolumeState {
+                cid: "c1".to_owned(),
+                source: "/dev/sdb".to_owned(),
+                device_id: Some("blk1".to_owned()),
+                size: Some(10 << 30),
+            },
+            VolumeState {
+                cid: "c2".to_owned(),
+                source: "/dev/sdc".to_owned(),
+                device_id: Some("blk2".to_owned()),
+                size: None,
+            },
+        ];
+        let resource = VolumeResource::restore(state.clone());
+        assert_eq!(resource.save().await, state);
+
+        resource
+            .set_volume_size("c2", "/dev/sdc", 20 << 30)
+            .await
+            .unwrap();
+        resource.delete_container("c1").await;
+        assert_eq!(
+            resource.save().await,
+            vec![VolumeState {
+                cid: "c2".to_owned(),
+                source: "/dev/sdc".to_owned(),
+                device_id: Some("blk2".to_owned()),
+                size: Some(20 << 30),
+            }]
+        );
+    }
+
+    // the volumes are shared as the files in the directory, standing for
+    // the mounts in the shared directory of the host, into the guest dir
+    struct FakeShareFsMount(PathBuf, &'static str);
+
+    #[async_trait]
+    impl ShareFsMount for FakeShareFsMount {
+        async fn share_rootfs(&self, _config: &ShareFsRootfsConfig) -> Result<ShareFsMountResult> {
+            Err(anyhow!("unsupported"))
+        }
+
+        async fn share_volume(&self, config: &ShareFsVolumeConfig) -> Result<ShareFsMountResult> {
+            std::fs::write(self.0.join(&config.target), "")?;
+            Ok(ShareFsMountResult {
+                guest_path: format!("{}{}", self.1, config.target),
+                storages: vec![],
+            })
+        }
+
+        async fn upgrade_to_rw(&self, _file_name: &str) -> Result<()> {
+            Ok(())
+        }
+
+        async fn downgrade_to_ro(&self, _file_name: &str) -> Result<()> {
+            Ok(())
+        }
+
+        async fn umount_volume(&self, file_name: &str) -> Result<()> {
+            std::fs::remove_file(self.0.join(file_name))?;
+            Ok(())
+        }
+
+        async fn umount_rootfs(&self, _config: &ShareFsRootfsConfig) -> Result<()> {
+            Ok(())
+        }
+
+        async fn cleanup(&self, _sid: &str) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    struct FakeShareFs {
+        mount: Arc<FakeShareFsMount>,
+        mounted_info_set: Arc<Mutex<HashMap<String, MountedInfo>>>,
+    }
+
+    #[async_trait]
+    impl ShareFs for FakeShareFs {
+        fn get_share_fs_mount(&self) -> Arc<dyn ShareFsMount> {
+            self.mount.clone()
+        }
+
+        async fn setup_device_before_start_vm(&self, _h: &dyn Hypervisor) -> Result<()> {
+            Ok(())
+        }
+
+        async fn setup_device_after_start_vm(&self, _h: &dyn Hypervisor) -> Result<()> {
+            Ok(())
+        }
+
+        async fn get_storages(&self) -> Result<Vec<agent::Storage>> {
+            Ok(vec![])
+        }
+
+        fn mounted_info_set(&self) -> Arc<Mutex<HashMap<String, MountedInfo>>> {
+            self.mounted_info_set.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_volumes_rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared_dir = dir.path().join("shared");
+        std::fs::create_dir(&shared_dir).unwrap();
+        let mounted_info_set = Arc::new(Mutex::new(HashMap::new()));
+        let share_fs: Option<Arc<dyn ShareFs>> = Some(Arc::new(FakeShareFs {
+            mount: Arc::new(FakeShareFsMount(
+                shared_dir.clone(),
+                "/run/kata-containers/shared/containers/",
+            )),
+            mounted_info_set: mounted_info_set.clone(),
+        }));
+        let d = RwLock::new(DeviceManager::new(Arc::new(Qemu::new())).unwrap());
+        let agent: Arc<dyn Agent> = Arc::new(agent::kata::KataAgent::new(Default::default()));
+
+        // the third volume fails, it's a direct volume without mount info
+        let mut spec = oci::Spec::default();
+        for (name, r#type) in [
+            ("config", BIND),
+            ("data", BIND),
+            ("direct", KATA_DIRECT_VOLUME_TYPE),
+        ] {
+            let source = dir.path().join(name);
+            std::fs::create_dir(&source).unwrap();
+            spec.mounts.push(oci::Mount {
+                destination: format!("/{}", name),
+                r#type: r#type.to_owned(),
+                source: source.display().to_string(),
+                options: vec!["rbind".to_owned()],
+            });
+        }
+        let resource = VolumeResource::new();
+        assert!(resource
+            .handler_volumes(
+                &share_fs,
+                &share_fs,
+                "c1",
+                &spec,
+                &d,
+                "sid",
+                agent.clone(),
+                0,
+                ShmLimits::default(),
+            )
+            .await
+            .is_err());
+
+        // the host is left as it was before the container
+        let shared = || std::fs::read_dir(&shared_dir).unwrap().count();
+        assert_eq!(shared(), 0);
+        assert!(mounted_info_set.lock().await.is_empty());
+        assert!(d.read().await.list_devices().await.is_empty());
+        assert!(resource.save().await.is_empty());
+
+        // the volumes are set up again once the failing one is gone
+        spec.mounts.pop();
+        let volumes = resource
+            .handler_volumes(
+                &share_fs,
+                &share_fs,
+                "c2",
+                &spec,
+                &d,
+                "sid",
+                agent,
+                0,
+                ShmLimits::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(volumes.len(), 2);
+        assert_eq!(shared(), 2);
+        assert_eq!(resource.save().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_handler_volumes_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared_dir = dir.path().join("shared");
+        std::fs::create_dir(&shared_dir).unwrap();
+        let share_fs: Option<Arc<dyn ShareFs>> = Some(Arc::new(FakeShareFs {
+            mount: Arc::new(FakeShareFsMount(
+                shared_dir.clone(),
+                "/run/kata-containers/shared/containers/",
+            )),
+            mounted_info_set: Arc::new(Mutex::new(HashMap::new())),
+        }));
+        let d = RwLock::new(DeviceManager::new(Arc::new(Qemu::new())).unwrap());
+        let agent = Arc::new(MockAgent::new("3.2.0"));
+
+        // the files of the same name from two pods, one read only
+        let mut spec = oci::Spec::default();
+        for (pod, option) in [("a", "ro"), ("b", "rw")] {
+            let source = dir.path().join(pod).join("hosts");
+            std::fs::create_dir(source.parent().unwrap()).unwrap();
+            std::fs::write(&source, "127.0.0.1 localhost").unwrap();
+            spec.mounts.push(oci::Mount {
+                destination: format!("/etc/hosts-{}", pod),
+                r#type: BIND.to_owned(),
+                source: source.display().to_string(),
+                options: vec!["rbind".to_owned(), option.to_owned()],
+            });
+        }
+
+        let volumes = VolumeResource::new()
+            .handler_volumes(
+                &share_fs,
+                &share_fs,
+                "c1",
+                &spec,
+                &d,
+                "sid",
+                agent.clone(),
+                4096,
+                ShmLimits::default(),
+            )
+            .await
+            .unwrap();
+        let sources: Vec<String> = volumes
+            .iter()
+            .flat_map(|v| v.get_volume_mount().unwrap())
+            .map(|m| m.source)
+            .collect();
+        // only the read only one is copied, under the name of the container
+        // and its source, the other one is shared for its writes to get back
+        assert_eq!(agent.calls(), vec!["copy_file"]);
+        assert!(sources[0].starts_with("/run/kata-containers/sandbox/"));
+        assert!(sources[0].ends_with(&format!(
+            "/passthrough/{}",
+            share_fs_volume::copy_file_name("c1", &spec.mounts[0].source)
+        )));
+        assert!(
+            sources[1].starts_with("/run/kata-containers/shared/containers/"),
+            "{}",
+            sources[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handler_volumes_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let new_share_fs = |name: &str, guest_dir: &'static str| {
+            let shared_dir = dir.path().join(name);
+            std::fs::create_dir(&shared_dir).unwrap();
+            let share_fs: Option<Arc<dyn ShareFs>> = Some(Arc::new(FakeShareFs {
+                mount: Arc::new(FakeShareFsMount(shared_dir, guest_dir)),
+                mounted_info_set: Arc::new(Mutex::new(HashMap::new())),
+            }));
+            share_fs
+        };
+        let export = new_share_fs("export", "/run/kata-containers/shared/exports/1/");
+        let sandbox = new_share_fs("sandbox", "/run/kata-containers/shared/containers/");
+        let d = RwLock::new(DeviceManager::new(Arc::new(Qemu::new())).unwrap());
+        let agent: Arc<dyn Agent> = Arc::new(agent::kata::KataAgent::new(Default::default()));
+
+        let data = dir.path().join("data");
+        std::fs::create_dir(&data).unwrap();
+        let config = dir.path().join("kubernetes.io~configmap").join("config");
+        std::fs::create_dir_all(&config).unwrap();
+        std::fs::write(config.join("key"), "value").unwrap();
+        let mut spec = oci::Spec::default();
+        for (destination, source) in [("/data", &data), ("/config", &config)] {
+            spec.mounts.push(oci::Mount {
+                destination: destination.to_owned(),
+                r#type: BIND.to_owned(),
+                source: source.display().to_string(),
+                options: vec!["rbind".to_owned()],
+            });
+        }
+
+        let volumes = VolumeResource::new()
+            .handler_volumes(
+                &export,
+                &sandbox,
+                "c1",
+                &spec,
+                &d,
+                "sid",
+                agent,
+                0,
+                ShmLimits::default(),
+            )
+            .await
+            .unwrap();
+        let sources: Vec<String> = volumes
+            .iter()
+            .flat_map(|v| v.get_volume_mount().unwrap())
+            .map(|m| m.source)
+            .collect();
+        // the volume goes into the export of the container, the watchable
+        // one stays in the share dir of the sandbox the agent watches
+        assert_eq!(sources.len(), 2);
+        assert!(
+            sources[0].starts_with("/run/kata-containers/shared/exports/1/"),
+            "{}",
+            sources[0]
+        );
+        assert!(
+            sources[1].starts_with("/run/kata-containers/shared/containers/"),
+            "{}",
+            sources[1]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_propagation() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut m = oci::Mount {
+            destination: "/var/lib/csi".to_owned(),
+            r#type: "bind".to_owned(),
+            source: dir.path().display().to_string(),
+            options: vec!["rbind".to_owned(), "rshared".to_owned()],
+        };
+
+        // the directory shared by the share fs gets the propagation
+        assert_eq!(unsupported_propagation(&m, true).unwrap(), None);
+        assert_eq!(
+            unsupported_propagation(&m, false).unwrap(),
+            Some("rshared".to_owned())
+        );
+
+        // private is kept in the guest, the last option wins
+        m.options.push("rprivate".to_owned());
+        assert_eq!(unsupported_propagation(&m, false).unwrap(), None);
+
+        // the block volumes never see the mounts on the host
+        m.r#type = KATA_DIRECT_VOLUME_TYPE.to_owned();
+        m.options = vec!["rslave".to_owned()];
+        assert_eq!(
+            unsupported_propagation(&m, true).unwrap(),
+            Some("rslave".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_plan_volumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mount = |source: &str, destination: &str, r#type: &str| oci::Mount {
+            destination: destination.to_owned(),
+            r#type: r#type.to_owned(),
+            source: source.to_owned(),
+            options: vec![],
+        };
+        let spec = oci::Spec {
+            mounts: vec![
+                mount("shm", "/dev/shm", "bind"),
+                mount(dir.path().to_str().unwrap(), "/data", "bind"),
+                mount("/no/such/dir", "/missing", "bind"),
+                mount("proc", "/proc", "proc"),
+            ],
+            ..Default::default()
+        };
+        let resource = VolumeResource::new();
+        let shm_limits = ShmLimits::default();
+
+        let planned = resource.plan(&spec, true, true, 0, &shm_limits).await;
+        let backends: Vec<_> = planned.iter().map(|p| p.backend.as_str()).collect();
+        assert_eq!(backends, vec!["shm", "share_fs", "", "default"]);
+        assert!(planned[2].error.is_some());
+        // nothing is set up
+        assert!(resource.inner.read().await.volumes.is_empty());
+
+        // the directories are refused without the fs sharing, rather than
+        // passing the host device they're on through
+        let planned = resource.plan(&spec, false, true, 0, &shm_limits).await;
+        assert!(planned[1]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("needs filesystem sharing"));
+        let file = dir.path().join("hosts");
+        std::fs::write(&file, "").unwrap();
+        let files = oci::Spec {
+            mounts: vec![mount(file.to_str().unwrap(), "/etc/hosts", "bind")],
+            ..Default::default()
+        };
+        let planned = resource.plan(&files, false, true, 0, &shm_limits).await;
+        assert_eq!(planned[0].backend, "copy");
+
+        let direct = oci::Spec {
+            mounts: vec![mount("/no/such/volume", "/data", KATA_DIRECT_VOLUME_TYPE)],
+            ..Default::default()
+        };
+        let planned = resource.plan(&direct, true, false, 0, &shm_limits).await;
+        assert!(planned[0]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("unsupported without host sharing"));
+
+        // the raw image is passed as a block device, not shared
+        let image = dir.path().join("disk.img");
+        std::fs::write(&image, "").unwrap();
+        let mut raw = mount(image.to_str().unwrap(), "/image", "bind");
+        raw.options.push("kata.raw_image".to_string());
+        assert!(!needs_share_fs(&raw).unwrap());
+        let raw_spec = oci::Spec {
+            mounts: vec![raw.clone()],
+            ..Default::default()
+        };
+        let planned = resource.plan(&raw_spec, true, true, 0, &shm_limits).await;
+        assert_eq!(planned[0].backend, "raw_image");
+        let planned = resource.plan(&raw_spec, true, false, 0, &shm_limits).await;
+        assert!(planned[0].error.is_some());
+        raw.source = dir.path().to_str().unwrap().to_owned();
+        let raw_spec = oci::Spec {
+            mounts: vec![raw],
+            ..Default::default()
+        };
+        let planned = resource.plan(&raw_spec, true, true, 0, &shm_limits).await;
+        assert!(planned[0].error.is_some());
+
+        // only the block volumes are encrypted, the others are refused
+        // rather than set up in the clear
+        let mut encrypted = mount(dir.path().to_str().unwrap(), "/secret", "bind");
+        encrypted
+            .options
+            .push("kata.encryption_key=volume-key".to_string());
+        let encrypted_spec = oci::Spec {
+            mounts: vec![encrypted],
+            ..Default::default()
+        };
+        for host_sharing in [true, false] {
+            let planned = resource
+                .plan(&encrypted_spec, true, host_sharing, 0, &shm_limits)
+                .await;
+            assert!(planned[0]
+                .error
+                .as_ref()
+                .unwrap()
+                .contains("can't be encrypted"));
+        }
+    }
+}
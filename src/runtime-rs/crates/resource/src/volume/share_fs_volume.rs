@@ -27,6 +27,8 @@ use crate::share_fs::DEFAULT_KATA_GUEST_SANDBOX_DIR;
 use crate::share_fs::PASSTHROUGH_FS_DIR;
 
 const SYS_MOUNT_PREFIX: [&str; 2] = ["/proc", "/sys"];
+// mode of the guest directories created for the files copied
+const COPY_FILE_DIR_MODE: u32 = 0o750;
 
 // copy file to container's rootfs if filesystem sharing is not supported, otherwise
 // bind mount it in the shared directory.
@@ -38,6 +40,8 @@ pub(crate) struct ShareFsVolume {
     share_fs: Option<Arc<dyn ShareFs>>,
     mounts: Vec<oci::Mount>,
     storages: Vec<agent::Storage>,
+    // the file is copied into the guest instead of being shared
+    copied: bool,
 }
 
 impl ShareFsVolume {
@@ -47,6 +51,7 @@ impl ShareFsVolume {
         cid: &str,
         readonly: bool,
         agent: Arc<dyn Agent>,
+        copy_file_max_size: u64,
     ) -> Result<Self> {
         // The file_name is in the format of "sandbox-{uuid}-{file_name}"
         let file_name = Path::new(&m.source).file_name().unwrap().to_str().unwrap();
@@ -56,6 +61,7 @@ impl ShareFsVolume {
             share_fs: share_fs.as_ref().map(Arc::clone),
             mounts: vec![],
             storages: vec![],
+            copied: false,
         };
         match share_fs {
             None => {
@@ -71,62 +77,9 @@ impl ShareFsVolume {
 
                 // If the mount source is a file, we can copy it to the sandbox
                 if src.is_file() {
-                    // This is where we set the value for the guest path
-                    let dest = [
-                        DEFAULT_KATA_GUEST_SANDBOX_DIR,
-                        PASSTHROUGH_FS_DIR,
-                        file_name.clone().as_str(),
-                    ]
-                    .join("/");
-
-                    debug!(
-                        sl!(),
-                        "copy local file {:?} to guest {:?}",
-                        &m.source,
-                        dest.clone()
-                    );
-
-                    // Read file metadata
-                    let file_metadata = std::fs::metadata(src.clone())
-                        .with_context(|| format!("Failed to read metadata from file: {:?}", src))?;
-
-                    // Open file
-                    let mut file = File::open(&src)
-                        .with_context(|| format!("Failed to open file: {:?}", src))?;
-
-                    // Open read file contents to buffer
-                    let mut buffer = Vec::new();
-                    file.read_to_end(&mut buffer)
-                        .with_context(|| format!("Failed to read file: {:?}", src))?;
-
-                    // Create gRPC request
-                    let r = agent::CopyFileRequest {
-                        path: dest.clone(),
-                        file_size: file_metadata.len() as i64,
-                        uid: file_metadata.uid() as i32,
-                        gid: file_metadata.gid() as i32,
-                        file_mode: file_metadata.mode(),
-                        data: buffer,
-                        ..Default::default()
-                    };
-
-                    debug!(sl!(), "copy_file: {:?} to sandbox {:?}", &src, dest.clone());
-
-                    // Issue gRPC request to agent
-                    agent.copy_file(r).await.with_context(|| {
-                        format!(
-                            "copy file request failed: src: {:?}, dest: {:?}",
-                            file_name, dest
-                        )
-                    })?;
-
-                    // append oci::Mount structure to volume mounts
-                    volume.mounts.push(oci::Mount {
-                        destination: m.destination.clone(),
-                        r#type: "bind".to_string(),
-                        source: dest.clone(),
-                        options: m.options.clone(),
-                    })
+                    volume
+                        .copy_to_guest(agent.as_ref(), m, &src, &copy_file_name(cid, &m.source))
+                        .await?;
                 } else {
                     // If not, we can ignore it. Let's issue a warning so that the user knows.
                     warn!(
@@ -135,6 +88,14 @@ impl ShareFsVolume {
                     );
                 }
             }
+            Some(_) if is_copyable(m, copy_file_max_size) => {
+                let src = std::fs::canonicalize(&m.source)
+                    .with_context(|| format!("failed to canonicalize file {}", &m.source))?;
+                volume
+                    .copy_to_guest(agent.as_ref(), m, &src, &copy_file_name(cid, &m.source))
+                    .await?;
+                volume.copied = true;
+            }
             Some(share_fs) => {
                 let share_fs_mount = share_fs.get_share_fs_mount();
                 let mounted_info_set = share_fs.mounted_info_set();
@@ -168,6 +129,9 @@ impl ShareFsVolume {
                     } else {
                         mounted_info.rw_ref_count += 1;
                     }
+                    // the storages are referred by all the containers, the
+                    // registry of the volumes adds them to the agent once
+                    volume.storages = mounted_info.storages.clone();
                     mounted_info_set.insert(m.source.clone(), mounted_info);
 
                     volume.mounts.push(oci::Mount {
@@ -191,11 +155,12 @@ impl ShareFsVolume {
                         })
                         .await
                         .context("mount shared volume")?;
-                    let mounted_info = MountedInfo::new(
+                    let mut mounted_info = MountedInfo::new(
                         PathBuf::from_str(&mount_result.guest_path)
                             .context("convert guest path")?,
                         readonly,
                     );
+                    mounted_info.storages = mount_result.storages.clone();
                     mounted_info_set.insert(m.source.clone(), mounted_info);
                     // set storages for the volume
                     volume.storages = mount_result.storages;
@@ -212,6 +177,84 @@ impl ShareFsVolume {
         }
         Ok(volume)
     }
+
+    // copy the file into the guest and bind mount it in the container
+    async fn copy_to_guest(
+        &mut self,
+        agent: &dyn Agent,
+        m: &oci::Mount,
+        src: &Path,
+        file_name: &str,
+    ) -> Result<()> {
+        let dest = copy_file_to_guest(agent, src, file_name).await?;
+
+        // append oci::Mount structure to volume mounts
+        self.mounts.push(oci::Mount {
+            destination: m.destination.clone(),
+            r#type: "bind".to_string(),
+            source: dest,
+            options: m.options.clone(),
+        });
+        Ok(())
+    }
+}
+
+/// copy_file_to_guest copies the file into the guest by the agent, it
+/// returns the path of the copy in the guest.
+pub(crate) async fn copy_file_to_guest(
+    agent: &dyn Agent,
+    src: &Path,
+    file_name: &str,
+) -> Result<String> {
+    // This is where we set the value for the guest path
+    let dest = [
+        DEFAULT_KATA_GUEST_SANDBOX_DIR,
+        PASSTHROUGH_FS_DIR,
+        file_name,
+    ]
+    .join("/");
+
+    debug!(
+        sl!(),
+        "copy local file {:?} to guest {:?}",
+        src,
+        dest.clone()
+    );
+
+    // Read file metadata
+    let file_metadata = std::fs::metadata(src)
+        .with_context(|| format!("Failed to read metadata from file: {:?}", src))?;
+
+    // Open file
+    let mut file = File::open(src).with_context(|| format!("Failed to open file: {:?}", src))?;
+
+    // Open read file contents to buffer
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)
+        .with_context(|| format!("Failed to read file: {:?}", src))?;
+
+    // Create gRPC request
+    let r = agent::CopyFileRequest {
+        path: dest.clone(),
+        file_size: buffer.len() as i64,
+        uid: file_metadata.uid() as i32,
+        gid: file_metadata.gid() as i32,
+        file_mode: file_metadata.mode(),
+        dir_mode: COPY_FILE_DIR_MODE,
+        data: buffer,
+        ..Default::default()
+    };
+
+    debug!(sl!(), "copy_file: {:?} to sandbox {:?}", src, dest.clone());
+
+    // Issue gRPC request to agent
+    agent.copy_file(r).await.with_context(|| {
+        format!(
+            "copy file request failed: src: {:?}, dest: {:?}",
+            file_name, dest
+        )
+    })?;
+    Ok(dest)
 }
 
 #[async_trait]
@@ -226,8 +269,8 @@ impl Volume for ShareFsVolume {
 
     async fn cleanup(&self, _device_manager: &RwLock<DeviceManager>) -> Result<()> {
         let share_fs = match self.share_fs.as_ref() {
-            Some(fs) => fs,
-            None => return Ok(()),
+            Some(fs) if !self.copied => fs,
+            _ => return Ok(()),
         };
 
         let mounted_info_set = share_fs.mounted_info_set();
@@ -295,6 +338,11 @@ impl Volume for ShareFsVolume {
     fn get_device_id(&self) -> Result<Option<String>> {
         Ok(None)
     }
+
+    // the files copied are in the guest, the shared ones on the host
+    fn is_migratable(&self) -> bool {
+        self.share_fs.is_none() || self.copied
+    }
 }
 
 pub(crate) fn is_share_fs_volume(m: &oci::Mount) -> bool {
@@ -338,6 +386,36 @@ fn is_system_mount(src: &str) -> bool {
     false
 }
 
+// is_small_file tells if the source is a regular file to be copied into the
+// guest, 0 max_size disables the copy.
+pub(crate) fn is_small_file(src: &str, max_size: u64) -> bool {
+    if max_size == 0 {
+        return false;
+    }
+    match std::fs::metadata(src) {
+        Ok(m) => m.is_file() && m.len() <= max_size,
+        Err(_) => false,
+    }
+}
+
+/// is_copyable tells if the file of the mount is copied into the guest
+/// rather than shared: a small file only read by the container, the writes
+/// to the copy wouldn't get back to the host.
+pub(crate) fn is_copyable(m: &oci::Mount, max_size: u64) -> bool {
+    m.options.iter().any(|opt| opt == "ro") && is_small_file(&m.source, max_size)
+}
+
+/// copy_file_name returns the name of the copy in the guest of the file of
+/// the container, by the container and the source, so that the files of the
+/// same name from different sources or containers don't overwrite each other.
+pub(crate) fn copy_file_name(cid: &str, source: &str) -> String {
+    // FNV-1a, the same source gets the same name
+    let hash = source.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{}-{:016x}", cid, hash)
+}
+
 // Note, don't generate random name, attaching rafs depends on the predictable name.
 pub fn generate_mount_path(id: &str, file_name: &str) -> String {
     let mut nid = String::from(id);
@@ -370,4 +448,38 @@ mod test {
         assert!(is_system_mount(proc_sub_dir));
         assert!(!is_system_mount(not_sys_dir));
     }
+
+    #[test]
+    fn test_is_small_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("hostname");
+        std::fs::write(&file, "kata").unwrap();
+        let file = file.to_str().unwrap();
+
+        assert!(is_small_file(file, 4));
+        assert!(!is_small_file(file, 3));
+        assert!(!is_small_file(file, 0));
+        assert!(!is_small_file(dir.path().to_str().unwrap(), 4096));
+        assert!(!is_small_file("/path/not/exist", 4096));
+
+        // only the files read by the container are copied
+        let mut m = oci::Mount {
+            source: file.to_string(),
+            options: vec!["rbind".to_string(), "ro".to_string()],
+            ..Default::default()
+        };
+        assert!(is_copyable(&m, 4));
+        m.options.pop();
+        assert!(!is_copyable(&m, 4));
+    }
+
+    #[test]
+    fn test_copy_file_name() {
+        let name = copy_file_name("c1", "/pods/a/etc-hosts/hosts");
+        assert!(name.starts_with("c1-"));
+        assert_eq!(name, copy_file_name("c1", "/pods/a/etc-hosts/hosts"));
+        // the files of the same name are told apart by their source
+        assert_ne!(name, copy_file_name("c1", "/pods/b/etc-hosts/hosts"));
+        assert_ne!(name, copy_file_name("c2", "/pods/a/etc-hosts/hosts"));
+    }
 }
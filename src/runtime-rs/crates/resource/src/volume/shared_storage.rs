@@ -0,0 +1,192 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use hypervisor::{
+    device::device_manager::DeviceManager, KATA_BLK_DEV_TYPE, KATA_MMIO_BLK_DEV_TYPE,
+};
+use tokio::sync::RwLock;
+
+use super::Volume;
+
+// identical storages of the same device are mounted once in the guest, the
+// other storages, e.g. the ones of the share fs volumes, are the same if
+// they're mounted at the same point of the guest
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum StorageKey {
+    Device {
+        driver: String,
+        source: String,
+        fs_type: String,
+        options: Vec<String>,
+    },
+    MountPoint(String),
+}
+
+impl StorageKey {
+    fn new(s: &agent::Storage) -> Option<Self> {
+        if s.driver == KATA_BLK_DEV_TYPE || s.driver == KATA_MMIO_BLK_DEV_TYPE {
+            return Some(Self::Device {
+                driver: s.driver.clone(),
+                source: s.source.clone(),
+                fs_type: s.fs_type.clone(),
+                options: s.options.clone(),
+            });
+        }
+        if s.mount_point.is_empty() {
+            return None;
+        }
+        Some(Self::MountPoint(s.mount_point.clone()))
+    }
+}
+
+struct SharedStorage {
+    // the guest mount point of the first storage added
+    mount_point: String,
+    count: usize,
+}
+
+/// StorageRegistry is the sandbox-level registry of the storages mounted in
+/// the guest, identical storages get a single canonical mount point, and the
+/// reference count decides when the agent is asked to remove it.
+#[derive(Default)]
+pub(crate) struct StorageRegistry {
+    storages: HashMap<StorageKey, SharedStorage>,
+}
+
+impl StorageRegistry {
+    /// acquire takes a reference on the storage, the canonical mount point is
+    /// returned if an identical storage is mounted already.
+    pub(crate) fn acquire(&mut self, storage: &agent::Storage) -> Option<String> {
+        let key = StorageKey::new(storage)?;
+        match self.storages.get_mut(&key) {
+            Some(shared) => {
+                shared.count += 1;
+                Some(shared.mount_point.clone())
+            }
+            None => {
+                self.storages.insert(
+                    key,
+                    SharedStorage {
+                        mount_point: storage.mount_point.clone(),
+                        count: 1,
+                    },
+                );
+                None
+            }
+        }
+    }
+
+    /// release drops a reference on the storage, it's forgotten with the
+    /// last reference.
+    pub(crate) fn release(&mut self, storage: &agent::Storage) {
+        let key = match StorageKey::new(storage) {
+            Some(key) => key,
+            None => return,
+        };
+        if let Some(shared) = self.storages.get_mut(&key) {
+            shared.count -= 1;
+            if shared.count == 0 {
+                self.storages.remove(&key);
+            }
+        }
+    }
+
+    /// last_reference returns the guest mount point of the storage if no
+    /// other volume refers to it, so that it could be removed from the guest.
+    pub(crate) fn last_reference(&self, storage: &agent::Storage) -> Option<String> {
+        let shared = match StorageKey::new(storage) {
+            Some(key) => self.storages.get(&key),
+            None => return Some(storage.mount_point.clone()),
+        };
+        match shared {
+            Some(shared) if shared.count > 1 => None,
+            Some(shared) => Some(shared.mount_point.clone()),
+            None => Some(storage.mount_point.clone()),
+        }
+    }
+}
+
+/// SharedStorageVolume is a volume whose storages are mounted in the guest
+/// by other containers already, its mounts refer to the canonical mount
+/// points instead of adding the storages again.
+pub(crate) struct SharedStorageVolume {
+    volume: Arc<dyn Volume>,
+    mounts: Vec<oci::Mount>,
+    storages: Vec<agent::Storage>,
+}
+
+impl SharedStorageVolume {
+    /// new returns the volume itself if none of its storages is shared.
+    pub(crate) fn new(
+        registry: &mut StorageRegistry,
+        volume: Arc<dyn Volume>,
+    ) -> Result<Arc<dyn Volume>> {
+        let mut mounts = volume.get_volume_mount()?;
+        let mut storages = vec![];
+        let mut shared = false;
+        for s in volume.get_storage()? {
+            let mount_point = match registry.acquire(&s) {
+                Some(mount_point) => mount_point,
+                None => {
+                    storages.push(s);
+                    continue;
+                }
+            };
+            info!(
+                sl!(),
+                "storage {} is mounted at {} already", s.source, mount_point
+            );
+            for m in mounts.iter_mut() {
+                match Path::new(&m.source).strip_prefix(&s.mount_point) {
+                    Ok(p) if p.as_os_str().is_empty() => m.source = mount_point.clone(),
+                    Ok(p) => m.source = Path::new(&mount_point).join(p).display().to_string(),
+                    Err(_) => {}
+                }
+            }
+            shared = true;
+        }
+
+        if !shared {
+            return Ok(volume);
+        }
+        Ok(Arc::new(Self {
+            volume,
+            mounts,
+            storages,
+        }))
+    }
+}
+
+#[async_trait]
+impl Volume for SharedStorageVolume {
+    fn get_volume_mount(&self) -> Result<Vec<oci::Mount>> {
+        Ok(self.mounts.clone())
+    }
+
+    fn get_storage(&self) -> Result<Vec<agent::Storage>> {
+        Ok(self.storages.clone())
+    }
+
+    fn get_referenced_storage(&self) -> Result<Vec<agent::Storage>> {
+        self.volume.get_storage()
+    }
+
+    fn get_device_id(&self) -> Result<Option<String>> {
+        self.volume.get_device_id()
+    }
+
+    fn is_migratable(&self) -> bool {
+        self.volume.is_migratable()
+    }
+
+    async fn cleanup(&self, device_manager: &RwLock<DeviceManager>) -> Result<()> {
+        self.volume.cleanup(device_manager).await
+    }
+}
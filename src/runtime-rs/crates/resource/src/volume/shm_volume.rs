@@ -6,7 +6,7 @@
 
 use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use hypervisor::device::device_manager::DeviceManager;
 use tokio::sync::RwLock;
@@ -22,6 +22,63 @@ pub const DEFAULT_SHM_SIZE: u64 = 65536 * 1024;
 // KATA_EPHEMERAL_DEV_TYPE creates a tmpfs backed volume for sharing files between containers.
 pub const KATA_EPHEMERAL_DEV_TYPE: &str = "ephemeral";
 
+/// ShmLimits sizes the /dev/shm of the containers in the guest.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShmLimits {
+    /// size in bytes of the /dev/shm mounted without a size, DEFAULT_SHM_SIZE
+    /// if 0
+    pub default_size: u64,
+    /// memory of the guest in bytes, the size is clamped to it since tmpfs
+    /// pages are allocated from it, 0 if unknown
+    pub guest_memory: u64,
+}
+
+impl ShmLimits {
+    /// size returns the size in bytes of the /dev/shm of the mount, from its
+    /// size option like the tmpfs one, e.g. size=1g or size=50%.
+    pub(crate) fn size(&self, m: &oci::Mount) -> Result<u64> {
+        let size = match m.options.iter().find_map(|o| o.strip_prefix("size=")) {
+            Some(size) => parse_size(size, self.guest_memory)
+                .with_context(|| format!("parse size of {}", m.destination))?,
+            None if self.default_size > 0 => self.default_size,
+            None => DEFAULT_SHM_SIZE,
+        };
+
+        if self.guest_memory > 0 && size > self.guest_memory {
+            warn!(
+                sl!(),
+                "size {} of {} is larger than the guest memory, clamped to {}",
+                size,
+                m.destination,
+                self.guest_memory
+            );
+            return Ok(self.guest_memory);
+        }
+        Ok(size)
+    }
+}
+
+// parse_size parses the size option of tmpfs, in bytes, with a k, m or g
+// suffix, or in percent of the memory
+fn parse_size(size: &str, memory: u64) -> Result<u64> {
+    let end = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (num, unit) = size.split_at(end);
+    let n: u64 = num
+        .parse()
+        .map_err(|_| anyhow!("invalid size {:?}", size))?;
+    let bytes = match unit {
+        "" => Some(n),
+        "k" | "K" => n.checked_mul(1 << 10),
+        "m" | "M" => n.checked_mul(1 << 20),
+        "g" | "G" => n.checked_mul(1 << 30),
+        "%" if memory > 0 => n.checked_mul(memory).map(|b| b / 100),
+        _ => return Err(anyhow!("invalid size {:?}", size)),
+    };
+    bytes.ok_or_else(|| anyhow!("size {:?} overflows", size))
+}
+
 #[derive(Debug)]
 pub(crate) struct ShmVolume {
     mount: oci::Mount,
@@ -110,8 +167,63 @@ impl Volume for ShmVolume {
     fn get_device_id(&self) -> Result<Option<String>> {
         Ok(None)
     }
+
+    // a tmpfs of the guest either way
+    fn is_migratable(&self) -> bool {
+        true
+    }
 }
 
 pub(crate) fn is_shim_volume(m: &oci::Mount) -> bool {
     m.destination == "/dev/shm" && m.r#type != KATA_EPHEMERAL_DEV_TYPE
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shm_mount(options: &[&str]) -> oci::Mount {
+        oci::Mount {
+            destination: "/dev/shm".to_string(),
+            r#type: "tmpfs".to_string(),
+            source: "shm".to_string(),
+            options: options.iter().map(|o| o.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_shm_size() {
+        let limits = ShmLimits {
+            default_size: 0,
+            guest_memory: 2 << 30,
+        };
+
+        // the size reaches the storage
+        let m = shm_mount(&["nosuid", "size=1g"]);
+        assert!(is_shim_volume(&m));
+        let size = limits.size(&m).unwrap();
+        assert_eq!(size, 1 << 30);
+        let volume = ShmVolume::new(&m, size).unwrap();
+        let storage = &volume.get_storage().unwrap()[0];
+        assert!(storage.options.contains(&format!("size={}", 1u64 << 30)));
+
+        assert_eq!(limits.size(&shm_mount(&[])).unwrap(), DEFAULT_SHM_SIZE);
+        let limits_with_default = ShmLimits {
+            default_size: 128 << 20,
+            ..limits
+        };
+        assert_eq!(
+            limits_with_default.size(&shm_mount(&[])).unwrap(),
+            128 << 20
+        );
+        assert_eq!(limits.size(&shm_mount(&["size=25%"])).unwrap(), 512 << 20);
+        assert_eq!(limits.size(&shm_mount(&["size=4096"])).unwrap(), 4096);
+
+        // clamped to the guest memory
+        assert_eq!(limits.size(&shm_mount(&["size=8G"])).unwrap(), 2 << 30);
+
+        for size in ["size=", "size=1t", "size=-1", "size=99999999999999999999g"] {
+            assert!(limits.size(&shm_mount(&[size])).is_err(), "{}", size);
+        }
+    }
+}
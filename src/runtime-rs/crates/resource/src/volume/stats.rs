@@ -0,0 +1,385 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::fmt;
+
+use agent::{Agent, Storage, VolumeStatsRequest, VolumeStatsResponse, VolumeUsageUnit};
+use anyhow::{anyhow, Context, Result};
+use hypervisor::{KATA_BLK_DEV_TYPE, KATA_MMIO_BLK_DEV_TYPE, KATA_SCSI_DEV_TYPE};
+use kata_types::mount::KATA_EPHEMERAL_VOLUME_TYPE;
+use nix::sys::statfs::statfs;
+use serde::{Deserialize, Serialize};
+
+use super::{local_volume::KATA_LOCAL_DEV_TYPE, Volume};
+
+const CONDITION_OK: &str = "OK";
+
+// the drivers of the storages of a filesystem in the guest, the agent stats
+// them. The storages of virtio-fs, and the watchable copies of its files,
+// are the filesystems of the host.
+const GUEST_FS_DRIVERS: &[&str] = &[
+    KATA_BLK_DEV_TYPE,
+    KATA_MMIO_BLK_DEV_TYPE,
+    KATA_SCSI_DEV_TYPE,
+    KATA_EPHEMERAL_VOLUME_TYPE,
+    KATA_LOCAL_DEV_TYPE,
+];
+
+/// VolumeNotFound is the error of the volume path no container of the
+/// sandbox mounts, there are no stats rather than empty ones.
+#[derive(Debug)]
+pub struct VolumeNotFound {
+    pub path: String,
+}
+
+impl fmt::Display for VolumeNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "volume {} isn't mounted in the sandbox", self.path)
+    }
+}
+
+impl std::error::Error for VolumeNotFound {}
+
+/// is_volume_not_found tells if the error is of a volume path unknown to
+/// the sandbox.
+pub fn is_volume_not_found(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| e.is::<VolumeNotFound>())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum UsageUnit {
+    Bytes,
+    Inodes,
+}
+
+/// VolumeUsage is the capacity of the filesystem of the volume in a unit.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VolumeUsage {
+    pub available: u64,
+    pub total: u64,
+    pub used: u64,
+    pub unit: UsageUnit,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VolumeCondition {
+    pub abnormal: bool,
+    pub message: String,
+}
+
+/// VolumeStats is the usage of the filesystem of a volume as the container
+/// sees it, in bytes and in inodes. It's serialized in the shape of the CSI
+/// NodeGetVolumeStats response the direct-volume stats command prints.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VolumeStats {
+    pub usage: Vec<VolumeUsage>,
+    pub volume_condition: VolumeCondition,
+}
+
+impl VolumeStats {
+    /// from_agent returns the stats the agent reported of the volume in the
+    /// guest, the usage in an unknown unit is left out.
+    pub(crate) fn from_agent(resp: VolumeStatsResponse) -> Result<Self> {
+        let usage: Vec<VolumeUsage> = resp
+            .usage
+            .iter()
+            .filter_map(|u| {
+                let unit = match u.unit {
+                    VolumeUsageUnit::Bytes => UsageUnit::Bytes,
+                    VolumeUsageUnit::Inodes => UsageUnit::Inodes,
+                    VolumeUsageUnit::Unknown => return None,
+                };
+                Some(VolumeUsage {
+                    available: u.available,
+                    total: u.total,
+                    used: u.used,
+                    unit,
+                })
+            })
+            .collect();
+        if !usage.iter().any(|u| u.unit == UsageUnit::Bytes) {
+            return Err(anyhow!("agent reported no capacity in bytes"));
+        }
+        Ok(Self {
+            usage,
+            volume_condition: VolumeCondition {
+                abnormal: resp.abnormal,
+                message: resp.message,
+            },
+        })
+    }
+
+    /// from_host returns the stats of the filesystem the path is on, for the
+    /// volumes shared from the host.
+    pub(crate) fn from_host(path: &str) -> Result<Self> {
+        let stat = statfs(path).with_context(|| format!("statfs {}", path))?;
+        let block_size = stat.block_size() as u64;
+        let blocks = stat.blocks() as u64;
+        let files = stat.files() as u64;
+        Ok(Self {
+            usage: vec![
+                VolumeUsage {
+                    available: stat.blocks_available() as u64 * block_size,
+                    total: blocks * block_size,
+                    used: blocks.saturating_sub(stat.blocks_free() as u64) * block_size,
+                    unit: UsageUnit::Bytes,
+                },
+                VolumeUsage {
+                    available: stat.files_free() as u64,
+                    total: files,
+                    used: files.saturating_sub(stat.files_free() as u64),
+                    unit: UsageUnit::Inodes,
+                },
+            ],
+            volume_condition: VolumeCondition {
+                abnormal: false,
+                message: CONDITION_OK.to_string(),
+            },
+        })
+    }
+
+    pub fn usage(&self, unit: UsageUnit) -> Option<&VolumeUsage> {
+        self.usage.iter().find(|u| u.unit == unit)
+    }
+}
+
+/// volume_stats returns the usage of the filesystem of the volume mounted
+/// from the path on the host. The storages of the filesystems in the guest,
+/// e.g. of a block device or of an emptyDir in the guest, are stat'ed by the
+/// agent, the volumes shared from the host are stat'ed on the host.
+pub(crate) async fn volume_stats(
+    agent: &dyn Agent,
+    volume_path: &str,
+    volume: &dyn Volume,
+) -> Result<VolumeStats> {
+    // the storage is added to the agent by the first container mounting it
+    let storage = match guest_storage(volume.get_referenced_storage()?) {
+        Some(storage) => storage,
+        None => return VolumeStats::from_host(volume_path),
+    };
+    // the device passed through to the container has no filesystem
+    if storage.fs_type == "bind" {
+        return Err(anyhow!("volume {} has no filesystem to stat", volume_path));
+    }
+
+    let resp = agent
+        .get_volume_stats(VolumeStatsRequest {
+            volume_guest_path: storage.mount_point.clone(),
+        })
+        .await
+        .with_context(|| format!("stat volume {} in the guest", volume_path))?;
+    VolumeStats::from_agent(resp).with_context(|| format!("stats of volume {}", volume_path))
+}
+
+// guest_storage returns the storage of the filesystem of the volume in the
+// guest, none if the volume is shared from the host
+fn guest_storage(storages: Vec<Storage>) -> Option<Storage> {
+    storages
+        .into_iter()
+        .find(|s| GUEST_FS_DRIVERS.contains(&s.driver.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use hypervisor::device::device_manager::DeviceManager;
+    use tokio::sync::RwLock;
+
+    use super::*;
+    use crate::mock_agent::MockAgent;
+
+    struct FakeVolume(Vec<Storage>);
+
+    #[async_trait]
+    impl Volume for FakeVolume {
+        fn get_volume_mount(&self) -> Result<Vec<oci::Mount>> {
+            Ok(vec![])
+        }
+
+        fn get_storage(&self) -> Result<Vec<Storage>> {
+            Ok(self.0.clone())
+        }
+
+        fn get_device_id(&self) -> Result<Option<String>> {
+            Ok(None)
+        }
+
+        fn is_migratable(&self) -> bool {
+            false
+        }
+
+        async fn cleanup(&self, _device_manager: &RwLock<DeviceManager>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn storage(driver: &str, fs_type: &str, mount_point: &str) -> Storage {
+        Storage {
+            driver: driver.to_string(),
+            fs_type: fs_type.to_string(),
+            mount_point: mount_point.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn agent_stats(used: u64) -> VolumeStatsResponse {
+        VolumeStatsResponse {
+            usage: vec![agent::VolumeUsage {
+                available: 100 - used,
+                total: 100,
+                used,
+                unit: VolumeUsageUnit::Bytes,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_volume_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let agent = MockAgent::new("3.2.0");
+        agent.set_volume_stats("/run/kata-containers/sandbox/storage/vdb", agent_stats(10));
+        agent.set_volume_stats(
+            "/run/kata-containers/sandbox/ephemeral/data",
+            agent_stats(20),
+        );
+        agent.set_volume_stats("/run/kata-containers/sandbox/local/cache", agent_stats(30));
+
+        // the filesystems in the guest are stat'ed by the agent, whatever
+        // their mount point
+        for (driver, fs_type, mount_point, used) in [
+            (
+                KATA_BLK_DEV_TYPE,
+                "ext4",
+                "/run/kata-containers/sandbox/storage/vdb",
+                10,
+            ),
+            (
+                KATA_SCSI_DEV_TYPE,
+                "xfs",
+                "/run/kata-containers/sandbox/storage/vdb",
+                10,
+            ),
+            (
+                KATA_EPHEMERAL_VOLUME_TYPE,
+                "tmpfs",
+                "/run/kata-containers/sandbox/ephemeral/data",
+                20,
+            ),
+            (
+                KATA_LOCAL_DEV_TYPE,
+                "local",
+                "/run/kata-containers/sandbox/local/cache",
+                30,
+            ),
+        ] {
+            let volume = FakeVolume(vec![storage(driver, fs_type, mount_point)]);
+            let stats = volume_stats(&agent, path, &volume).await.unwrap();
+            assert_eq!(
+                stats.usage(UsageUnit::Bytes).unwrap().used,
+                used,
+                "{}",
+                driver
+            );
+        }
+
+        // the volumes of virtio-fs are stat'ed on the host, the watchable
+        // ones as well though their copy is mounted out of the share dir
+        let calls = agent.calls().len();
+        for volume in [
+            FakeVolume(vec![]),
+            FakeVolume(vec![storage(
+                "virtio-fs",
+                "virtiofs",
+                "/run/kata-containers/shared/exports/0/",
+            )]),
+            FakeVolume(vec![storage(
+                "watchable-bind",
+                "bind",
+                "/run/kata-containers/shared/containers/watchable/config",
+            )]),
+        ] {
+            let stats = volume_stats(&agent, path, &volume).await.unwrap();
+            assert!(stats.usage(UsageUnit::Inodes).is_some());
+        }
+        assert_eq!(agent.calls().len(), calls);
+
+        // the raw device has no filesystem
+        let volume = FakeVolume(vec![storage(KATA_BLK_DEV_TYPE, "bind", "/dev/vdc")]);
+        assert!(volume_stats(&agent, path, &volume).await.is_err());
+
+        // the agent failing to stat is told
+        let volume = FakeVolume(vec![storage(
+            KATA_BLK_DEV_TYPE,
+            "ext4",
+            "/run/kata-containers/sandbox/storage/vdd",
+        )]);
+        let e = volume_stats(&agent, path, &volume).await.unwrap_err();
+        assert!(format!("{:#}", e).contains("no such file or directory"));
+        assert_eq!(
+            agent.calls().last().unwrap(),
+            "get_volume_stats /run/kata-containers/sandbox/storage/vdd"
+        );
+    }
+
+    #[test]
+    fn test_from_agent() {
+        let resp = VolumeStatsResponse {
+            usage: vec![
+                agent::VolumeUsage {
+                    available: 60,
+                    total: 100,
+                    used: 40,
+                    unit: VolumeUsageUnit::Bytes,
+                },
+                agent::VolumeUsage {
+                    available: 7,
+                    total: 10,
+                    used: 3,
+                    unit: VolumeUsageUnit::Inodes,
+                },
+                agent::VolumeUsage::default(),
+            ],
+            message: CONDITION_OK.to_string(),
+            ..Default::default()
+        };
+        let stats = VolumeStats::from_agent(resp).unwrap();
+        assert_eq!(stats.usage.len(), 2);
+        assert_eq!(stats.usage(UsageUnit::Bytes).unwrap().used, 40);
+        assert_eq!(stats.usage(UsageUnit::Inodes).unwrap().available, 7);
+        assert_eq!(
+            serde_json::to_string(&stats.usage[1]).unwrap(),
+            r#"{"available":7,"total":10,"used":3,"unit":"INODES"}"#
+        );
+
+        // no zeros made up
+        assert!(VolumeStats::from_agent(VolumeStatsResponse::default()).is_err());
+    }
+
+    #[test]
+    fn test_from_host() {
+        let dir = tempfile::tempdir().unwrap();
+        let stats = VolumeStats::from_host(dir.path().to_str().unwrap()).unwrap();
+        let bytes = stats.usage(UsageUnit::Bytes).unwrap();
+        assert!(bytes.total > 0);
+        assert!(bytes.used <= bytes.total);
+        assert!(stats.usage(UsageUnit::Inodes).is_some());
+
+        assert!(VolumeStats::from_host("/no/such/volume").is_err());
+    }
+
+    #[test]
+    fn test_is_volume_not_found() {
+        let e = anyhow::Error::from(VolumeNotFound {
+            path: "/var/lib/kubelet/pods/a/volumes/data".to_string(),
+        })
+        .context("volume stats");
+        assert!(is_volume_not_found(&e));
+        assert!(!is_volume_not_found(&anyhow!("statfs failed")));
+    }
+}
@@ -0,0 +1,343 @@
+// Copyright (c) 2019-2023 Alibaba Cloud
+// Copyright (c) 2019-2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Context, Result};
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+    sys::signal::kill,
+    unistd::Pid,
+};
+use serde::{Deserialize, Serialize};
+
+// the cids allocated by the shims of the host, one file for each named by
+// the cid, under the lock shared by the shims
+const VSOCK_REGISTRY_DIR: &str = "/run/kata-containers/vsock";
+const VSOCK_REGISTRY_LOCK: &str = ".lock";
+const CID_FILE_PREFIX: &str = "cid-";
+const VHOST_VSOCK_DEVICE: &str = "/dev/vhost-vsock";
+
+// first usable cid above VMADDR_CID_HOST, see vsock(7), it's the one of the
+// guests behind a hybrid vsock which is local to the VM
+const FIRST_GUEST_CID: u32 = 3;
+const CID_RETRY_COUNT: u32 = 50;
+
+// From <linux/vhost.h>, it fails with EADDRINUSE if the cid is used by
+// another VM
+const VHOST_VIRTIO_IOCTL: u8 = 0xAF;
+const VHOST_VSOCK_SET_GUEST_CID: u8 = 0x60;
+nix::ioctl_write_ptr!(
+    vhost_vsock_set_guest_cid,
+    VHOST_VIRTIO_IOCTL,
+    VHOST_VSOCK_SET_GUEST_CID,
+    u64
+);
+
+/// VsockBackend is how the host reaches the vsock of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VsockBackend {
+    /// a vhost-vsock device, the cid is unique on the host, e.g. qemu
+    Vhost,
+    /// a unix socket of the hypervisor, e.g. dragonball or cloud hypervisor,
+    /// the cid is local to the VM
+    Hybrid,
+}
+
+/// VsockState is the vsock allocation of the sandbox, saved to release it
+/// once restored.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VsockState {
+    pub cid: Option<u32>,
+    pub backend: Option<VsockBackend>,
+}
+
+/// VsockAllocation is the cid allocated for the guest, with the vhost-vsock
+/// fd holding it for the vhost backend, to be handed to the hypervisor.
+#[derive(Debug)]
+pub struct VsockAllocation {
+    pub cid: u32,
+    pub vhost_fd: Option<File>,
+}
+
+/// VsockAllocator allocates the vsock cid of the sandbox, the cids of the
+/// vhost backend are recorded in a registry shared by the shims so that the
+/// sandboxes starting concurrently never get the same one.
+pub struct VsockAllocator {
+    sid: String,
+    registry_dir: PathBuf,
+    state: Mutex<VsockState>,
+}
+
+impl VsockAllocator {
+    pub fn new(sid: &str) -> Self {
+        Self::restore(sid, VsockState::default())
+    }
+
+    /// restore registers the vhost cid of the sandbox again for this shim,
+    /// the one registered by the shim before is reclaimed by the others
+    /// once it's gone.
+    pub fn restore(sid: &str, state: VsockState) -> Self {
+        let allocator = Self {
+            sid: sid.to_string(),
+            registry_dir: PathBuf::from(VSOCK_REGISTRY_DIR),
+            state: Mutex::new(state.clone()),
+        };
+        if let (Some(cid), Some(VsockBackend::Vhost)) = (state.cid, state.backend) {
+            if let Err(e) = register_cid(&allocator.registry_dir, &allocator.sid, cid) {
+                warn!(sl!(), "couldn't register vsock cid {} again: {:?}", cid, e);
+            }
+        }
+        allocator
+    }
+
+    /// allocate_cid allocates the cid of the guest, the vhost-vsock device
+    /// is probed for a cid free on the host, out of the runtime as the
+    /// registry lock is blocking.
+    pub async fn allocate_cid(&self, backend: VsockBackend) -> Result<VsockAllocation> {
+        let allocation = match backend {
+            VsockBackend::Hybrid => VsockAllocation {
+                cid: FIRST_GUEST_CID,
+                vhost_fd: None,
+            },
+            VsockBackend::Vhost => {
+                let (sid, registry_dir) = (self.sid.clone(), self.registry_dir.clone());
+                tokio::task::spawn_blocking(move || allocate_vhost_cid(&registry_dir, &sid))
+                    .await
+                    .context("join vsock cid allocation")??
+            }
+        };
+        self.record_cid(allocation.cid, backend);
+        Ok(allocation)
+    }
+
+    fn cid_path(&self, cid: u32) -> PathBuf {
+        cid_path(&self.registry_dir, cid)
+    }
+
+    fn record_cid(&self, cid: u32, backend: VsockBackend) {
+        let mut state = self.state.lock().unwrap();
+        state.cid = Some(cid);
+        state.backend = Some(backend);
+    }
+
+    /// release releases the cid of the sandbox, the one released already is
+    /// skipped so that the cleanup could be retried.
+    pub fn release(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let (Some(cid), Some(VsockBackend::Vhost)) = (state.cid, state.backend) {
+            remove_file_if_exists(&self.cid_path(cid))
+                .with_context(|| format!("unregister cid {}", cid))?;
+        }
+        state.cid = None;
+        state.backend = None;
+        Ok(())
+    }
+
+    pub fn state(&self) -> VsockState {
+        self.state.lock().unwrap().clone()
+    }
+}
+
+fn allocate_vhost_cid(registry_dir: &Path, sid: &str) -> Result<VsockAllocation> {
+    let vhost_fd = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(VHOST_VSOCK_DEVICE)
+        .with_context(|| {
+            format!(
+                "open {}, try to run modprobe vhost_vsock",
+                VHOST_VSOCK_DEVICE
+            )
+        })?;
+    let cid = allocate_registered_cid(registry_dir, sid, |cid| {
+        // safe as the fd is a vhost-vsock device
+        unsafe { vhost_vsock_set_guest_cid(vhost_fd.as_raw_fd(), &(cid as u64)) }.map(|_| ())
+    })?;
+    Ok(VsockAllocation {
+        cid,
+        vhost_fd: Some(vhost_fd),
+    })
+}
+
+// allocate_registered_cid probes the cids not registered by the other
+// shims, the ones used by the VMs out of kata fail with EADDRINUSE
+fn allocate_registered_cid<F>(registry_dir: &Path, sid: &str, mut probe: F) -> Result<u32>
+where
+    F: FnMut(u32) -> nix::Result<()>,
+{
+    fs::create_dir_all(registry_dir)
+        .with_context(|| format!("create {}", registry_dir.display()))?;
+    let lock =
+        File::create(registry_dir.join(VSOCK_REGISTRY_LOCK)).context("create registry lock")?;
+    // released with the file
+    flock(lock.as_raw_fd(), FlockArg::LockExclusive).context("lock registry")?;
+
+    let registered = registered_cids(registry_dir)?;
+    let mut cid = FIRST_GUEST_CID;
+    for _ in 0..CID_RETRY_COUNT {
+        while registered.contains(&cid) {
+            cid += 1;
+        }
+        match probe(cid) {
+            Ok(_) => {
+                register_cid(registry_dir, sid, cid)?;
+                info!(sl!(), "vsock cid {} allocated to sandbox {}", cid, sid);
+                return Ok(cid);
+            }
+            Err(nix::Error::EADDRINUSE) => cid += 1,
+            Err(e) => return Err(e).context("set guest cid"),
+        }
+    }
+    Err(anyhow!(
+        "no free vsock cid found after {} attempts",
+        CID_RETRY_COUNT
+    ))
+}
+
+// registered_cids returns the cids registered by the shims alive, the ones
+// left behind by the shims gone are removed
+fn registered_cids(registry_dir: &Path) -> Result<HashSet<u32>> {
+    let mut cids = HashSet::new();
+    for entry in fs::read_dir(registry_dir).context("read registry")? {
+        let entry = entry?;
+        let cid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.strip_prefix(CID_FILE_PREFIX))
+            .and_then(|c| c.parse().ok())
+        {
+            Some(cid) => cid,
+            None => continue,
+        };
+        let owner = fs::read_to_string(entry.path()).unwrap_or_default();
+        if is_stale(&owner) {
+            info!(sl!(), "reclaim vsock cid {} of {}", cid, owner);
+            remove_file_if_exists(&entry.path())
+                .with_context(|| format!("unregister cid {}", cid))?;
+            continue;
+        }
+        cids.insert(cid);
+    }
+    Ok(cids)
+}
+
+// the cid is registered with the sandbox and the pid of the shim holding it
+fn register_cid(registry_dir: &Path, sid: &str, cid: u32) -> Result<()> {
+    fs::write(
+        cid_path(registry_dir, cid),
+        format!("{} {}", sid, std::process::id()),
+    )
+    .with_context(|| format!("register cid {}", cid))
+}
+
+// is_stale tells if the shim which registered the cid is gone, the cid is
+// probed anyway before it's used again
+fn is_stale(owner: &str) -> bool {
+    match owner
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pid| pid.parse().ok())
+    {
+        Some(pid) => kill(Pid::from_raw(pid), None) == Err(Errno::ESRCH),
+        None => false,
+    }
+}
+
+fn cid_path(registry_dir: &Path, cid: u32) -> PathBuf {
+    registry_dir.join(format!("{}{}", CID_FILE_PREFIX, cid))
+}
+
+fn remove_file_if_exists(path: &Path) -> std::io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocator(sid: &str, dir: &Path) -> VsockAllocator {
+        VsockAllocator {
+            sid: sid.to_string(),
+            registry_dir: dir.to_path_buf(),
+            state: Mutex::new(VsockState::default()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_allocate_cid() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = allocator("a", dir.path());
+
+        // the cid registered by a isn't probed by b, the one used out of
+        // kata is skipped
+        assert_eq!(
+            allocate_registered_cid(dir.path(), "a", |_| Ok(())).unwrap(),
+            3
+        );
+        a.record_cid(3, VsockBackend::Vhost);
+        let cid = allocate_registered_cid(dir.path(), "b", |cid| match cid {
+            4 => Err(nix::Error::EADDRINUSE),
+            _ => Ok(()),
+        })
+        .unwrap();
+        assert_eq!(cid, 5);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("cid-5")).unwrap(),
+            format!("b {}", std::process::id())
+        );
+
+        // released twice, the cid is free again
+        a.release().unwrap();
+        a.release().unwrap();
+        assert_eq!(a.state().cid, None);
+        assert_eq!(
+            allocate_registered_cid(dir.path(), "a", |_| Ok(())).unwrap(),
+            3
+        );
+
+        allocate_registered_cid(dir.path(), "b", |_| Err(nix::Error::EPERM)).unwrap_err();
+        allocate_registered_cid(dir.path(), "b", |_| Err(nix::Error::EADDRINUSE)).unwrap_err();
+
+        // the hybrid cid is local to the VM
+        let c = allocator("c", dir.path());
+        let allocation = c.allocate_cid(VsockBackend::Hybrid).await.unwrap();
+        assert_eq!(allocation.cid, FIRST_GUEST_CID);
+        assert!(allocation.vhost_fd.is_none());
+        // only the vhost cids are registered
+        c.release().unwrap();
+        assert!(dir.path().join("cid-3").exists());
+    }
+
+    #[test]
+    fn test_reclaim_stale_cid() {
+        let dir = tempfile::tempdir().unwrap();
+        // the shim of d is gone, beyond pid_max, the one of e is alive
+        fs::write(dir.path().join("cid-3"), "d 999999999").unwrap();
+        fs::write(
+            dir.path().join("cid-4"),
+            format!("e {}", std::process::id()),
+        )
+        .unwrap();
+        assert_eq!(
+            allocate_registered_cid(dir.path(), "f", |_| Ok(())).unwrap(),
+            3
+        );
+        assert_eq!(registered_cids(dir.path()).unwrap(), HashSet::from([3, 4]));
+        assert!(is_stale("d 999999999"));
+        assert!(!is_stale("d"));
+    }
+}
@@ -6,6 +6,7 @@
 
 use anyhow::Result;
 use async_trait::async_trait;
+use tokio::sync::mpsc::Receiver;
 
 #[derive(Clone)]
 pub struct SandboxNetworkEnv {
@@ -34,4 +35,17 @@ pub trait Sandbox: Send + Sync {
     async fn get_iptables(&self, is_ipv6: bool) -> Result<Vec<u8>>;
     async fn direct_volume_stats(&self, volume_path: &str) -> Result<String>;
     async fn direct_volume_resize(&self, resize_req: agent::ResizeVolumeRequest) -> Result<()>;
+    async fn reclaim_memory(&self, size_mb: u64) -> Result<()>;
+    async fn resource_timings(&self) -> Result<String>;
+    /// resource_plan returns in json what the resources of the container
+    /// would be set up with, without setting up any of them.
+    async fn resource_plan(
+        &self,
+        spec: &oci::Spec,
+        rootfs_mounts: &[kata_types::mount::Mount],
+    ) -> Result<String>;
+    async fn resource_metrics(&self) -> Result<String>;
+    /// resource_events returns the events of the resources in json, until
+    /// the receiver is dropped.
+    async fn resource_events(&self) -> Result<Receiver<String>>;
 }
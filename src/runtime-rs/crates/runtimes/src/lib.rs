@@ -13,4 +13,3 @@ pub mod manager;
 pub use manager::RuntimeHandlerManager;
 pub use shim_interface;
 mod shim_mgmt;
-mod static_resource;
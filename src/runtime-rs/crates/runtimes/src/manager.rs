@@ -6,7 +6,7 @@
 
 use std::{path::PathBuf, str::from_utf8, sync::Arc};
 
-use crate::{shim_mgmt::server::MgmtServer, static_resource::StaticResourceManager};
+use crate::shim_mgmt::server::MgmtServer;
 use anyhow::{anyhow, Context, Result};
 use common::{
     message::Message,
@@ -408,29 +408,16 @@ fn load_config(spec: &oci::Spec, option: &Option<Vec<u8>>) -> Result<TomlConfig>
     info!(sl!(), "get config path {:?}", &config_path);
     let (mut toml_config, _) =
         TomlConfig::load_from_file(&config_path).context("load toml config")?;
+    // the overrides are checked against the node configuration, before
+    // they're merged into it
+    resource::overrides::validate_overrides(&toml_config, &spec.annotations)
+        .context("validate annotations")?;
     annotation.update_config_by_annotation(&mut toml_config)?;
     update_agent_kernel_params(&mut toml_config)?;
 
     // validate configuration and return the error
     toml_config.validate()?;
 
-    // Sandbox sizing information *may* be provided in two scenarios:
-    //   1. The upper layer runtime (ie, containerd or crio) provide sandbox sizing information as an annotation
-    //	in the 'sandbox container's' spec. This would typically be a scenario where as part of a create sandbox
-    //	request the upper layer runtime receives this information as part of a pod, and makes it available to us
-    //	for sizing purposes.
-    //   2. If this is not a sandbox infrastructure container, but instead a standalone single container (analogous to "docker run..."),
-    //	then the container spec itself will contain appropriate sizing information for the entire sandbox (since it is
-    //	a single container.
-    if toml_config.runtime.static_sandbox_resource_mgmt {
-        info!(sl!(), "static resource management enabled");
-        let static_resource_manager = StaticResourceManager::new(spec)
-            .context("failed to construct static resource manager")?;
-        static_resource_manager
-            .setup_config(&mut toml_config)
-            .context("failed to setup static resource mgmt config")?;
-    }
-
     info!(sl!(), "get config content {:?}", &toml_config);
     Ok(toml_config)
 }
@@ -11,12 +11,14 @@ use agent::ResizeVolumeRequest;
 use anyhow::{anyhow, Context, Result};
 use common::Sandbox;
 use hyper::{Body, Method, Request, Response, StatusCode};
+use resource::{plan::PlanRequest, volume::is_volume_not_found};
 use std::sync::Arc;
 use url::Url;
 
 use shim_interface::shim_mgmt::{
     AGENT_URL, DIRECT_VOLUME_PATH_KEY, DIRECT_VOLUME_RESIZE_URL, DIRECT_VOLUME_STATS_URL,
-    IP6_TABLE_URL, IP_TABLE_URL,
+    IP6_TABLE_URL, IP_TABLE_URL, MEMORY_RECLAIM_SIZE_KEY, MEMORY_RECLAIM_URL, METRICS_URL,
+    RESOURCE_EVENTS_URL, RESOURCE_PLAN_URL, RESOURCE_TIMINGS_URL,
 };
 
 // main router for response, this works as a multiplexer on
@@ -43,6 +45,11 @@ pub(crate) async fn handler_mux(
         (&Method::POST, DIRECT_VOLUME_RESIZE_URL) => {
             direct_volume_resize_handler(sandbox, req).await
         }
+        (&Method::POST, MEMORY_RECLAIM_URL) => memory_reclaim_handler(sandbox, req).await,
+        (&Method::GET, RESOURCE_TIMINGS_URL) => resource_timings_handler(sandbox, req).await,
+        (&Method::GET, METRICS_URL) => metrics_handler(sandbox, req).await,
+        (&Method::GET, RESOURCE_EVENTS_URL) => resource_events_handler(sandbox, req).await,
+        (&Method::POST, RESOURCE_PLAN_URL) => resource_plan_handler(sandbox, req).await,
         _ => Ok(not_found(req).await),
     }
 }
@@ -126,7 +133,12 @@ async fn direct_volume_stats_handler(
     let result = sandbox.direct_volume_stats(volume_path).await;
     match result {
         Ok(stats) => Ok(Response::new(Body::from(stats))),
-        _ => Err(anyhow!("handler: Failed to get volume stats")),
+        // no stats rather than empty ones for a volume the sandbox lacks
+        Err(e) if is_volume_not_found(&e) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from(format!("{:#}", e)))
+            .map_err(|e| anyhow!(e)),
+        Err(e) => Err(e.context("handler: Failed to get volume stats")),
     }
 }
 
@@ -146,3 +158,97 @@ async fn direct_volume_resize_handler(
         _ => Err(anyhow!("handler: Failed to resize volume")),
     }
 }
+
+async fn memory_reclaim_handler(
+    sandbox: Arc<dyn Sandbox>,
+    req: Request<Body>,
+) -> Result<Response<Body>> {
+    let params = Url::parse(&req.uri().to_string())
+        .map_err(|e| anyhow!(e))?
+        .query_pairs()
+        .into_owned()
+        .collect::<std::collections::HashMap<String, String>>();
+    let size_mb = params
+        .get(MEMORY_RECLAIM_SIZE_KEY)
+        .context("shim-mgmt: memory size key not found in request params")?
+        .parse::<u64>()
+        .context("shim-mgmt: parse memory size")?;
+    let result = sandbox.reclaim_memory(size_mb).await;
+
+    match result {
+        Ok(_) => Ok(Response::new(Body::from(""))),
+        Err(e) => Err(anyhow!("handler: Failed to reclaim memory: {:?}", e)),
+    }
+}
+
+// returns the timings of the resource setup phases in json
+async fn resource_timings_handler(
+    sandbox: Arc<dyn Sandbox>,
+    _req: Request<Body>,
+) -> Result<Response<Body>> {
+    match sandbox.resource_timings().await {
+        Ok(timings) => Ok(Response::new(Body::from(timings))),
+        Err(e) => Err(anyhow!("handler: Failed to get resource timings: {:?}", e)),
+    }
+}
+
+// returns in json what the resources of the container of the spec would be
+// set up with, nothing is set up
+async fn resource_plan_handler(
+    sandbox: Arc<dyn Sandbox>,
+    req: Request<Body>,
+) -> Result<Response<Body>> {
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let plan_req: PlanRequest =
+        serde_json::from_slice(&body).context("shim-mgmt: deserialize plan request failed")?;
+    match sandbox
+        .resource_plan(&plan_req.spec, &plan_req.rootfs_mounts)
+        .await
+    {
+        Ok(report) => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from(report))
+            .map_err(|e| anyhow!(e)),
+        Err(e) => Err(anyhow!("handler: Failed to plan resources: {:?}", e)),
+    }
+}
+
+// returns the metrics of the shim in the prometheus text format, only the
+// ones of the resources for now
+async fn metrics_handler(sandbox: Arc<dyn Sandbox>, _req: Request<Body>) -> Result<Response<Body>> {
+    match sandbox.resource_metrics().await {
+        Ok(metrics) => Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(metrics))
+            .map_err(|e| anyhow!(e)),
+        Err(e) => Err(anyhow!("handler: Failed to get metrics: {:?}", e)),
+    }
+}
+
+// streams the events of the resources as json lines, until the client goes
+// away
+async fn resource_events_handler(
+    sandbox: Arc<dyn Sandbox>,
+    _req: Request<Body>,
+) -> Result<Response<Body>> {
+    let mut events = match sandbox.resource_events().await {
+        Ok(events) => events,
+        Err(e) => return Err(anyhow!("handler: Failed to get resource events: {:?}", e)),
+    };
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            if sender
+                .send_data(format!("{}\n", event).into())
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    Response::builder()
+        .header("Content-Type", "application/x-ndjson")
+        .body(body)
+        .map_err(|e| anyhow!(e))
+}
@@ -6,6 +6,7 @@
 
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use agent::Agent;
 use anyhow::{anyhow, Context, Result};
@@ -17,9 +18,10 @@ use common::{
     },
 };
 use kata_sys_util::k8s::update_ephemeral_storage_type;
+use kata_types::annotations::Annotation;
 
 use oci::{LinuxResources, Process as OCIProcess};
-use resource::ResourceManager;
+use resource::{volume::Volume, ResourceManager};
 use tokio::sync::RwLock;
 
 use super::{
@@ -81,13 +83,55 @@ impl Container {
         })
     }
 
-    pub async fn create(&self, mut spec: oci::Spec) -> Result<()> {
-        // process oci spec
+    pub async fn create(&self, spec: oci::Spec) -> Result<()> {
         let mut inner = self.inner.write().await;
+        if let Err(e) = self.do_create(&mut inner, spec).await {
+            self.rollback_create(&mut inner).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // rollback_create cleans up the resources set up for the container which
+    // fails to be created, the same way as they're cleaned up on its deletion
+    async fn rollback_create(&self, inner: &mut ContainerInner) {
+        let cid = &self.config.container_id;
+        let device_manager = self.resource_manager.get_device_manager().await;
+        if let Err(e) = inner.clean_volumes(&device_manager).await {
+            warn!(
+                self.logger,
+                "couldn't roll back volumes of {}: {:?}", cid, e
+            );
+        }
+        if let Err(e) = inner.clean_rootfs(&device_manager).await {
+            warn!(self.logger, "couldn't roll back rootfs of {}: {:?}", cid, e);
+        }
+        if let Err(e) = self.resource_manager.delete_container_resources(cid).await {
+            warn!(
+                self.logger,
+                "couldn't roll back resources of {}: {:?}", cid, e
+            );
+        }
+    }
+
+    async fn do_create(&self, inner: &mut ContainerInner, mut spec: oci::Spec) -> Result<()> {
+        // process oci spec
         let toml_config = self.resource_manager.config().await;
         let config = &self.config;
         let sandbox_pidns = is_pid_namespace_enabled(&spec);
-        amend_spec(&mut spec, toml_config.runtime.disable_guest_seccomp).context("amend spec")?;
+        amend_spec(
+            &mut spec,
+            toml_config.runtime.disable_guest_seccomp,
+            toml_config.runtime.guest_seccomp_for_devices,
+        )
+        .context("amend spec")?;
+        let enable_guest_swap = toml_config
+            .hypervisor
+            .get(&toml_config.runtime.hypervisor_name)
+            .map_or(false, |h| h.memory_info.enable_guest_swap);
+        if enable_guest_swap {
+            amend_swappiness(&mut spec).context("amend swappiness")?;
+        }
 
         // get mutable root from oci spec
         let mut root = match spec.root.as_mut() {
@@ -103,6 +147,7 @@ impl Container {
                 root,
                 &config.bundle,
                 &config.rootfs_mounts,
+                &spec.annotations,
             )
             .await
             .context("handler rootfs")?;
@@ -113,10 +158,14 @@ impl Container {
             .await
             .context("get guest rootfs path")?;
 
-        let mut storages = vec![];
-        if let Some(storage) = rootfs.get_storage().await {
-            storages.push(storage);
-        }
+        // the export of the container with per_container_sharefs is mounted
+        // before its rootfs and its volumes
+        let mut rootfs_storages = self
+            .resource_manager
+            .get_storage_for_container(&config.container_id)
+            .await
+            .context("get storage for container")?;
+        rootfs_storages.append(&mut rootfs.get_storages().await);
         inner.rootfs.push(rootfs);
 
         // handler volumes
@@ -125,29 +174,25 @@ impl Container {
             .handler_volumes(&config.container_id, &spec)
             .await
             .context("handler volumes")?;
-        let mut oci_mounts = vec![];
-        for v in volumes {
-            let mut volume_mounts = v.get_volume_mount().context("get volume mount")?;
-            if !volume_mounts.is_empty() {
-                oci_mounts.append(&mut volume_mounts);
-            }
-
-            let mut s = v.get_storage().context("get storage")?;
-            if !s.is_empty() {
-                storages.append(&mut s);
-            }
-            inner.volumes.push(v);
-        }
+        let (oci_mounts, storages) = volume_mounts_and_storages(&rootfs_storages, &volumes)?;
+        inner.volumes.extend(volumes);
         spec.mounts = oci_mounts;
 
         let linux = spec
             .linux
-            .as_ref()
+            .as_mut()
             .context("OCI spec missing linux field")?;
 
+        let capabilities = spec.process.as_ref().and_then(|p| p.capabilities.as_ref());
         let devices_agent = self
             .resource_manager
-            .handler_devices(&config.container_id, linux)
+            .handler_devices(&config.container_id, linux, &spec.annotations, capabilities)
+            .await?;
+
+        // the oom_score_adj is applied by the agent in the guest, alongside
+        // the cgroups
+        self.resource_manager
+            .handler_oom_score_adj(&config.container_id, spec.process.as_mut())
             .await?;
 
         // update cgroups
@@ -159,9 +204,19 @@ impl Container {
                     .and_then(|linux| linux.resources.as_ref()),
             )
             .await?;
+        // the block io left is for the cgroup of the container in the guest
+        self.resource_manager
+            .handler_block_io(
+                &config.container_id,
+                spec.linux
+                    .as_mut()
+                    .and_then(|linux| linux.resources.as_mut()),
+            )
+            .await;
 
         // create container
-        let r = agent::CreateContainerRequest {
+        let annotations = spec.annotations.clone();
+        let mut r = agent::CreateContainerRequest {
             process_id: agent::ContainerProcessID::new(&config.container_id, ""),
             storages,
             oci: Some(spec),
@@ -170,15 +225,47 @@ impl Container {
             ..Default::default()
         };
 
-        self.agent
-            .create_container(r)
-            .await
-            .context("agent create container")?;
+        // the volumes the guest fails to mount are degraded and the container
+        // is created again, if they're eligible to fall back
+        while let Err(err) = self.agent.create_container(r.clone()).await {
+            let volumes = match self
+                .resource_manager
+                .fallback_volumes(&config.container_id, &annotations, &format!("{:?}", err))
+                .await?
+            {
+                Some(volumes) => volumes,
+                None => return Err(err).context("agent create container"),
+            };
+            info!(
+                self.logger,
+                "create container {} again with the volumes degraded", config.container_id
+            );
+            let (oci_mounts, storages) = volume_mounts_and_storages(&rootfs_storages, &volumes)?;
+            inner.volumes = volumes;
+            r.storages = storages;
+            if let Some(spec) = r.oci.as_mut() {
+                spec.mounts = oci_mounts;
+            }
+        }
         self.resource_manager.dump().await;
         Ok(())
     }
 
     pub async fn start(&self, process: &ContainerProcess) -> Result<()> {
+        // the guest may still be mounting the share fs, the early accesses of
+        // the container to its files would fail. It's waited for without the
+        // container locked, its state and stats are still served meanwhile.
+        if matches!(process.process_type, ProcessType::Container) {
+            let toml_config = self.resource_manager.config().await;
+            let ready_timeout_ms = toml_config.runtime.share_fs_ready_timeout_ms;
+            if ready_timeout_ms != 0 {
+                self.resource_manager
+                    .wait_share_fs_ready(Duration::from_millis(ready_timeout_ms))
+                    .await
+                    .context("wait for share fs")?;
+            }
+        }
+
         let mut inner = self.inner.write().await;
         match process.process_type {
             ProcessType::Container => {
@@ -402,9 +489,13 @@ impl Container {
             .update_cgroups(&self.config.container_id, Some(resources))
             .await?;
 
+        let mut resources = resources.clone();
+        self.resource_manager
+            .handler_block_io(&self.config.container_id, Some(&mut resources))
+            .await;
         let req = agent::UpdateContainerRequest {
             container_id: self.container_id.container_id.clone(),
-            resources: resources.clone(),
+            resources,
             mounts: Vec::new(),
         };
         self.agent
@@ -423,10 +514,30 @@ impl Container {
     }
 }
 
-fn amend_spec(spec: &mut oci::Spec, disable_guest_seccomp: bool) -> Result<()> {
-    // Only the StartContainer hook needs to be reserved for execution in the guest
+fn amend_spec(
+    spec: &mut oci::Spec,
+    disable_guest_seccomp: bool,
+    guest_seccomp_for_devices: bool,
+) -> Result<()> {
+    // Only the StartContainer hook needs to be reserved for execution in the guest,
+    // the others refer to the host binaries. The hooks of the guest are run by the
+    // agent from guest_hook_path of the guest image instead.
     let start_container_hooks = match spec.hooks.as_ref() {
-        Some(hooks) => hooks.start_container.clone(),
+        Some(hooks) => {
+            let host_hooks = hooks.prestart.len()
+                + hooks.create_runtime.len()
+                + hooks.create_container.len()
+                + hooks.poststart.len()
+                + hooks.poststop.len();
+            if host_hooks != 0 {
+                warn!(
+                    sl!(),
+                    "{} host hooks of the spec are dropped, the guest runs the hooks under guest_hook_path",
+                    host_hooks
+                );
+            }
+            hooks.start_container.clone()
+        }
         None => Vec::new(),
     };
 
@@ -443,8 +554,19 @@ fn amend_spec(spec: &mut oci::Spec, disable_guest_seccomp: bool) -> Result<()> {
     update_ephemeral_storage_type(spec);
 
     if let Some(linux) = spec.linux.as_mut() {
+        // the seccomp profile is still applied in the guest to the container
+        // accessing devices if asked for, so that the devices don't bypass
+        // the syscall filtering. no_new_privileges goes with the process
+        // untouched.
         if disable_guest_seccomp {
-            linux.seccomp = None;
+            if !guest_seccomp_for_devices || !exposes_devices(linux) {
+                linux.seccomp = None;
+            } else if linux.seccomp.is_some() {
+                warn!(
+                    sl!(),
+                    "keep the seccomp profile of the container accessing devices"
+                );
+            }
         }
 
         if let Some(resource) = linux.resources.as_mut() {
@@ -474,6 +596,59 @@ fn amend_spec(spec: &mut oci::Spec, disable_guest_seccomp: bool) -> Result<()> {
     Ok(())
 }
 
+// exposes_devices checks if the container accesses any device, the ones in the
+// spec or all of them when it's privileged. The rules of all the devices
+// allowing only mknod, the default of the container runtimes, don't give
+// access to any of them.
+fn exposes_devices(linux: &oci::Linux) -> bool {
+    !linux.devices.is_empty()
+        || linux.resources.as_ref().map_or(false, |r| {
+            r.devices.iter().any(|d| {
+                // no access given is all of them
+                d.allow
+                    && d.major.is_none()
+                    && d.minor.is_none()
+                    && (d.access.is_empty() || d.access.contains('r') || d.access.contains('w'))
+            })
+        })
+}
+
+// amend_swappiness forwards the swappiness set by the annotation to the guest
+// cgroup of the container, the one in the oci spec takes precedence.
+fn amend_swappiness(spec: &mut oci::Spec) -> Result<()> {
+    let swappiness = Annotation::new(spec.annotations.clone())
+        .get_container_resource_swappiness()
+        .context("get swappiness")?;
+
+    if let (Some(swappiness), Some(linux)) = (swappiness, spec.linux.as_mut()) {
+        let memory = linux
+            .resources
+            .get_or_insert_with(Default::default)
+            .memory
+            .get_or_insert_with(Default::default);
+        if memory.swappiness.is_none() {
+            memory.swappiness = Some(swappiness as u64);
+        }
+    }
+
+    Ok(())
+}
+
+// volume_mounts_and_storages returns the mounts of the volumes of the
+// container, and the storages of its rootfs and of its volumes
+fn volume_mounts_and_storages(
+    rootfs_storages: &[agent::Storage],
+    volumes: &[Arc<dyn Volume>],
+) -> Result<(Vec<oci::Mount>, Vec<agent::Storage>)> {
+    let mut oci_mounts = vec![];
+    let mut storages = rootfs_storages.to_vec();
+    for v in volumes {
+        oci_mounts.append(&mut v.get_volume_mount().context("get volume mount")?);
+        storages.append(&mut v.get_storage().context("get storage")?);
+    }
+    Ok((oci_mounts, storages))
+}
+
 // is_pid_namespace_enabled checks if Pid namespace for a container needs to be shared with its sandbox
 // pid namespace.
 fn is_pid_namespace_enabled(spec: &oci::Spec) -> bool {
@@ -491,7 +666,9 @@ fn is_pid_namespace_enabled(spec: &oci::Spec) -> bool {
 #[cfg(test)]
 mod tests {
     use super::amend_spec;
+    use super::amend_swappiness;
     use super::is_pid_namespace_enabled;
+    use kata_types::annotations::KATA_ANNO_CONTAINER_RES_SWAPPINESS;
     #[test]
     fn test_amend_spec_disable_guest_seccomp() {
         let mut spec = oci::Spec {
@@ -505,14 +682,151 @@ mod tests {
         assert!(spec.linux.as_ref().unwrap().seccomp.is_some());
 
         // disable_guest_seccomp = false
-        amend_spec(&mut spec, false).unwrap();
+        amend_spec(&mut spec, false, false).unwrap();
         assert!(spec.linux.as_ref().unwrap().seccomp.is_some());
 
         // disable_guest_seccomp = true
-        amend_spec(&mut spec, true).unwrap();
+        amend_spec(&mut spec, true, false).unwrap();
         assert!(spec.linux.as_ref().unwrap().seccomp.is_none());
     }
 
+    #[test]
+    fn test_amend_spec_hooks() {
+        let hook = oci::Hook {
+            path: "/usr/bin/nvidia-ctk".to_string(),
+            ..Default::default()
+        };
+        let mut spec = oci::Spec {
+            hooks: Some(oci::Hooks {
+                prestart: vec![hook.clone()],
+                poststop: vec![hook.clone()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        amend_spec(&mut spec, false, false).unwrap();
+        assert!(spec.hooks.is_none());
+
+        // the hooks run in the container are kept
+        spec.hooks = Some(oci::Hooks {
+            prestart: vec![hook.clone()],
+            start_container: vec![hook.clone()],
+            ..Default::default()
+        });
+        amend_spec(&mut spec, false, false).unwrap();
+        let hooks = spec.hooks.unwrap();
+        assert!(hooks.prestart.is_empty());
+        assert_eq!(hooks.start_container, vec![hook]);
+    }
+
+    #[test]
+    fn test_amend_spec_seccomp_with_devices() {
+        let seccomp = oci::LinuxSeccomp {
+            default_action: "SCMP_ACT_ERRNO".to_string(),
+            ..Default::default()
+        };
+        let new_spec = |linux: oci::Linux| oci::Spec {
+            process: Some(oci::Process {
+                no_new_privileges: true,
+                ..Default::default()
+            }),
+            linux: Some(oci::Linux {
+                seccomp: Some(seccomp.clone()),
+                ..linux
+            }),
+            ..Default::default()
+        };
+
+        // the container with a device keeps its profile and no_new_privileges
+        let mut spec = new_spec(oci::Linux {
+            devices: vec![oci::LinuxDevice {
+                path: "/dev/vdb".to_string(),
+                r#type: "b".to_string(),
+                major: 254,
+                minor: 16,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        amend_spec(&mut spec, true, true).unwrap();
+        assert_eq!(spec.linux.as_ref().unwrap().seccomp, Some(seccomp.clone()));
+        assert!(spec.process.as_ref().unwrap().no_new_privileges);
+        // unless it's not asked for
+        amend_spec(&mut spec, true, false).unwrap();
+        assert_eq!(spec.linux.as_ref().unwrap().seccomp, None);
+
+        // so does the privileged container accessing all devices
+        let mut spec = new_spec(oci::Linux {
+            resources: Some(oci::LinuxResources {
+                devices: vec![oci::LinuxDeviceCgroup {
+                    allow: true,
+                    access: "rwm".to_string(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        amend_spec(&mut spec, true, true).unwrap();
+        assert_eq!(spec.linux.as_ref().unwrap().seccomp, Some(seccomp.clone()));
+
+        // the default rules allowing mknod of any device access none
+        let mknod = |r#type: &str| oci::LinuxDeviceCgroup {
+            allow: true,
+            r#type: r#type.to_string(),
+            access: "m".to_string(),
+            ..Default::default()
+        };
+        let mut spec = new_spec(oci::Linux {
+            resources: Some(oci::LinuxResources {
+                devices: vec![mknod("c"), mknod("b")],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        amend_spec(&mut spec, true, true).unwrap();
+        assert_eq!(spec.linux.as_ref().unwrap().seccomp, None);
+    }
+
+    #[test]
+    fn test_amend_swappiness() {
+        let memory = |spec: &oci::Spec| {
+            spec.linux
+                .as_ref()
+                .and_then(|l| l.resources.as_ref())
+                .and_then(|r| r.memory.as_ref())
+                .and_then(|m| m.swappiness)
+        };
+        let mut spec = oci::Spec {
+            linux: Some(oci::Linux::default()),
+            ..Default::default()
+        };
+
+        amend_swappiness(&mut spec).unwrap();
+        assert_eq!(memory(&spec), None);
+
+        spec.annotations.insert(
+            KATA_ANNO_CONTAINER_RES_SWAPPINESS.to_string(),
+            "60".to_string(),
+        );
+        amend_swappiness(&mut spec).unwrap();
+        assert_eq!(memory(&spec), Some(60));
+
+        // the swappiness of the oci spec is kept
+        spec.annotations.insert(
+            KATA_ANNO_CONTAINER_RES_SWAPPINESS.to_string(),
+            "10".to_string(),
+        );
+        amend_swappiness(&mut spec).unwrap();
+        assert_eq!(memory(&spec), Some(60));
+
+        spec.annotations.insert(
+            KATA_ANNO_CONTAINER_RES_SWAPPINESS.to_string(),
+            "101".to_string(),
+        );
+        assert!(amend_swappiness(&mut spec).is_err());
+    }
+
     #[test]
     fn test_is_pid_namespace_enabled() {
         struct TestData<'a> {
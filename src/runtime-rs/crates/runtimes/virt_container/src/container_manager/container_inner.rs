@@ -285,7 +285,10 @@ impl ContainerInner {
         Ok(())
     }
 
-    async fn clean_volumes(&mut self, device_manager: &RwLock<DeviceManager>) -> Result<()> {
+    pub(crate) async fn clean_volumes(
+        &mut self,
+        device_manager: &RwLock<DeviceManager>,
+    ) -> Result<()> {
         let mut unhandled = Vec::new();
         for v in self.volumes.iter() {
             if let Err(err) = v.cleanup(device_manager).await {
@@ -304,7 +307,10 @@ impl ContainerInner {
         Ok(())
     }
 
-    async fn clean_rootfs(&mut self, device_manager: &RwLock<DeviceManager>) -> Result<()> {
+    pub(crate) async fn clean_rootfs(
+        &mut self,
+        device_manager: &RwLock<DeviceManager>,
+    ) -> Result<()> {
         let mut unhandled = Vec::new();
         for rootfs in self.rootfs.iter() {
             if let Err(err) = rootfs.cleanup(device_manager).await {
@@ -120,6 +120,19 @@ impl ContainerManager for VirtContainerManager {
                     .remove(container_id)
                     .ok_or_else(|| Error::ContainerNotFound(container_id.to_string()))?;
 
+                // a failure to shrink the sandbox is retried on the next
+                // change, it mustn't fail the delete
+                if let Err(err) = self
+                    .resource_manager
+                    .delete_container_resources(container_id)
+                    .await
+                {
+                    warn!(
+                        sl!(),
+                        "failed to delete resources of container {}: {:?}", container_id, err
+                    );
+                }
+
                 // Poststop Hooks:
                 // * should be run in runtime namespace
                 // * should be run after the container is deleted but before delete operation returns
@@ -73,7 +73,7 @@ impl RuntimeHandler for VirtContainer {
         let hypervisor = new_hypervisor(&config).await.context("new hypervisor")?;
 
         // get uds from hypervisor and get config from toml_config
-        let agent = new_agent(&config).context("new agent")?;
+        let agent = new_agent(&config).await.context("new agent")?;
         let resource_manager = Arc::new(ResourceManager::new(
             sid,
             agent.clone(),
@@ -122,14 +122,17 @@ async fn new_hypervisor(toml_config: &TomlConfig) -> Result<Arc<dyn Hypervisor>>
     // issue: https://github.com/kata-containers/kata-containers/issues/4634
     match hypervisor_name.as_str() {
         HYPERVISOR_DRAGONBALL => {
-            let mut hypervisor = Dragonball::new();
+            let hypervisor = Dragonball::new();
             hypervisor
                 .set_hypervisor_config(hypervisor_config.clone())
                 .await;
+            if let Some(policy) = toml_config.runtime.retry.hypervisor_request {
+                hypervisor.set_retry_policy(policy).await;
+            }
             Ok(Arc::new(hypervisor))
         }
         HYPERVISOR_QEMU => {
-            let mut hypervisor = Qemu::new();
+            let hypervisor = Qemu::new();
             hypervisor
                 .set_hypervisor_config(hypervisor_config.clone())
                 .await;
@@ -138,7 +141,7 @@ async fn new_hypervisor(toml_config: &TomlConfig) -> Result<Arc<dyn Hypervisor>>
 
         #[cfg(feature = "cloud-hypervisor")]
         HYPERVISOR_NAME_CH => {
-            let mut hypervisor = CloudHypervisor::new();
+            let hypervisor = CloudHypervisor::new();
 
             hypervisor
                 .set_hypervisor_config(hypervisor_config.clone())
@@ -150,7 +153,7 @@ async fn new_hypervisor(toml_config: &TomlConfig) -> Result<Arc<dyn Hypervisor>>
     }
 }
 
-fn new_agent(toml_config: &TomlConfig) -> Result<Arc<KataAgent>> {
+async fn new_agent(toml_config: &TomlConfig) -> Result<Arc<KataAgent>> {
     let agent_name = &toml_config.runtime.agent_name;
     let agent_config = toml_config
         .agent
@@ -160,6 +163,12 @@ fn new_agent(toml_config: &TomlConfig) -> Result<Arc<KataAgent>> {
     match agent_name.as_str() {
         AGENT_KATA => {
             let agent = KataAgent::new(agent_config.clone());
+            if let Some(policy) = toml_config.runtime.retry.agent_connect {
+                agent.set_retry_policy(policy).await;
+            }
+            if let Some(policy) = toml_config.runtime.retry.agent_request {
+                agent.set_request_retry_policy(policy).await;
+            }
             Ok(Arc::new(agent))
         }
         _ => Err(anyhow!("Unsupported agent {}", &agent_name)),
@@ -182,11 +191,11 @@ agent_name="kata"
         TomlConfig::load(config_content).map_err(|e| anyhow!("can not load config toml: {}", e))
     }
 
-    #[test]
-    fn test_new_agent() {
+    #[tokio::test]
+    async fn test_new_agent() {
         let toml_config = default_toml_config_agent().unwrap();
 
-        let res = new_agent(&toml_config);
+        let res = new_agent(&toml_config).await;
         assert!(res.is_ok());
     }
 
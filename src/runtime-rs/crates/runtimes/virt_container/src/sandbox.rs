@@ -8,7 +8,6 @@ use std::sync::Arc;
 
 use agent::{
     self, kata::KataAgent, types::KernelModule, Agent, GetIPTablesRequest, SetIPTablesRequest,
-    VolumeStatsRequest,
 };
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
@@ -17,20 +16,31 @@ use common::{
     Sandbox, SandboxNetworkEnv,
 };
 use containerd_shim_protos::events::task::TaskOOM;
-use hypervisor::{dragonball::Dragonball, Hypervisor, HYPERVISOR_DRAGONBALL};
+use hypervisor::{
+    dragonball::Dragonball, Hypervisor, HYPERVISOR_DRAGONBALL, HYPERVISOR_NAME_CH, HYPERVISOR_QEMU,
+};
 use kata_sys_util::hooks::HookStates;
-use kata_types::config::TomlConfig;
+use kata_types::{config::TomlConfig, mount::Mount};
 use resource::{
+    cpu_mem::initial_size::InitialSizeManager,
+    hostname::{truncate_hostname, HostnameConfig},
     manager::ManagerArgs,
-    network::{NetworkConfig, NetworkWithNetNsConfig},
+    network::{self, NetworkConfig, NetworkWithNetNsConfig},
+    vsock::VsockBackend,
     ResourceConfig, ResourceManager,
 };
-use tokio::sync::{mpsc::Sender, Mutex, RwLock};
+use tokio::sync::{
+    mpsc::{channel, Receiver, Sender},
+    Mutex, RwLock,
+};
 
 use crate::health_check::HealthCheck;
 use persist::{self, sandbox_persist::Persist};
 
 pub(crate) const VIRTCONTAINER: &str = "virt_container";
+// the resource events waiting for the management client
+const RESOURCE_EVENTS_BUFFER_SIZE: usize = 64;
+
 pub struct SandboxRestoreArgs {
     pub sid: String,
     pub toml_config: TomlConfig,
@@ -91,9 +101,28 @@ impl VirtSandbox {
     async fn prepare_config_for_sandbox(
         &self,
         _id: &str,
+        spec: &oci::Spec,
         network_env: SandboxNetworkEnv,
     ) -> Result<Vec<ResourceConfig>> {
         let mut resource_configs = vec![];
+
+        // Sandbox sizing information *may* be provided in two scenarios:
+        //   1. The upper layer runtime (ie, containerd or crio) provide sandbox sizing information as an annotation
+        //	in the 'sandbox container's' spec. This would typically be a scenario where as part of a create sandbox
+        //	request the upper layer runtime receives this information as part of a pod, and makes it available to us
+        //	for sizing purposes.
+        //   2. If this is not a sandbox infrastructure container, but instead a standalone single container (analogous to "docker run..."),
+        //	then the container spec itself will contain appropriate sizing information for the entire sandbox (since it is
+        //	a single container.
+        // It must come first, as the devices set up later depend on the size of the sandbox.
+        let config = self.resource_manager.config().await;
+        if config.runtime.static_sandbox_resource_mgmt {
+            info!(sl!(), "static resource management enabled");
+            let initial_size_manager = InitialSizeManager::new(spec)
+                .context("failed to construct static resource manager")?;
+            resource_configs.push(ResourceConfig::InitialSize(initial_size_manager));
+        }
+
         if !network_env.network_created {
             if let Some(netns_path) = network_env.netns {
                 let network_config = ResourceConfig::Network(
@@ -107,6 +136,19 @@ impl VirtSandbox {
         let virtio_fs_config = ResourceConfig::ShareFs(hypervisor_config.shared_fs);
         resource_configs.push(virtio_fs_config);
 
+        // the sandboxes without a hostname or a hosts file are left as is
+        match HostnameConfig::new(spec) {
+            Ok(Some(c)) => resource_configs.push(ResourceConfig::Hostname(c)),
+            Ok(None) => {}
+            Err(e) => warn!(sl!(), "skip hostname of sandbox: {:?}", e),
+        }
+
+        // applied in the guest even if the sandbox has no network
+        let net_sysctls = network::net_sysctls(spec);
+        if !net_sysctls.is_empty() {
+            resource_configs.push(ResourceConfig::NetSysctls(net_sysctls));
+        }
+
         Ok(resource_configs)
     }
 
@@ -149,19 +191,73 @@ impl VirtSandbox {
         network_created: bool,
     ) -> NetworkConfig {
         let config = self.resource_manager.config().await;
+        let hypervisor_config = self.hypervisor.hypervisor_config().await;
         NetworkConfig::NetworkResourceWithNetNs(NetworkWithNetNsConfig {
             network_model: config.runtime.internetworking_model.clone(),
+            endpoint_type: config.runtime.network_endpoint_type.clone(),
             netns_path,
-            queues: self
-                .hypervisor
-                .hypervisor_config()
-                .await
-                .network_info
-                .network_queues as usize,
+            queues: hypervisor_config.network_info.network_queues as usize,
             network_created,
         })
     }
 
+    // reclaim the memory the guest reports as free periodically if it's
+    // configured
+    async fn start_memory_reclaimer(&self) -> Result<()> {
+        let hypervisor_config = self.hypervisor.hypervisor_config().await;
+        let interval = hypervisor_config.memory_info.memory_reclaim_interval;
+        if interval == 0 {
+            return Ok(());
+        }
+        if !self
+            .hypervisor
+            .capabilities()
+            .await
+            .context("get capabilities")?
+            .is_balloon_supported()
+        {
+            warn!(
+                sl!(),
+                "hypervisor doesn't support memory balloon, memory reclaim is disabled"
+            );
+            return Ok(());
+        }
+
+        info!(sl!(), "memory reclaimer start, interval {}s", interval);
+        let inner = self.inner.clone();
+        let resource_manager = self.resource_manager.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(std::time::Duration::from_secs(interval as u64)).await;
+                if inner.read().await.state != SandboxState::Running {
+                    break;
+                }
+
+                if let Err(err) = resource_manager.reclaim_free_memory().await {
+                    warn!(sl!(), "failed to reclaim memory error {:?}", err);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // the vsock cid of the guest is allocated by the resource manager, so
+    // that the sandboxes starting concurrently never get the same one, the
+    // other hypervisors allocate their own
+    async fn allocate_vsock(&self) -> Result<()> {
+        let config = self.resource_manager.config().await;
+        let backend = match config.runtime.hypervisor_name.as_str() {
+            HYPERVISOR_DRAGONBALL | HYPERVISOR_NAME_CH => VsockBackend::Hybrid,
+            HYPERVISOR_QEMU => VsockBackend::Vhost,
+            _ => return Ok(()),
+        };
+        let vsock = self.resource_manager.allocate_vsock(backend).await?;
+        self.hypervisor
+            .set_guest_vsock(vsock.cid, vsock.vhost_fd)
+            .await
+            .context("set guest vsock")
+    }
+
     fn has_prestart_hooks(
         &self,
         prestart_hooks: Vec<oci::Hook>,
@@ -190,6 +286,7 @@ impl Sandbox for VirtSandbox {
             return Ok(());
         }
 
+        self.allocate_vsock().await.context("allocate vsock")?;
         self.hypervisor
             .prepare_vm(id, network_env.netns.clone())
             .await
@@ -198,7 +295,7 @@ impl Sandbox for VirtSandbox {
         // generate device and setup before start vm
         // should after hypervisor.prepare_vm
         let resources = self
-            .prepare_config_for_sandbox(id, network_env.clone())
+            .prepare_config_for_sandbox(id, spec, network_env.clone())
             .await?;
         self.resource_manager
             .prepare_before_start_vm(resources)
@@ -258,7 +355,7 @@ impl Sandbox for VirtSandbox {
         let agent_config = self.agent.agent_config().await;
         let kernel_modules = KernelModule::set_kernel_modules(agent_config.kernel_modules)?;
         let req = agent::CreateSandboxRequest {
-            hostname: spec.hostname.clone(),
+            hostname: truncate_hostname(&spec.hostname).to_string(),
             dns,
             storages: self
                 .resource_manager
@@ -280,10 +377,15 @@ impl Sandbox for VirtSandbox {
             .create_sandbox(req)
             .await
             .context("create sandbox")?;
+        self.resource_manager
+            .ready()
+            .await
+            .context("resources ready")?;
 
         inner.state = SandboxState::Running;
         let agent = self.agent.clone();
         let sender = self.msg_sender.clone();
+        let resource_manager = self.resource_manager.clone();
         info!(sl!(), "oom watcher start");
         tokio::spawn(async move {
             loop {
@@ -295,6 +397,7 @@ impl Sandbox for VirtSandbox {
                     Ok(resp) => {
                         let cid = &resp.container_id;
                         warn!(sl!(), "send oom event for container {}", &cid);
+                        resource_manager.report_oom(cid);
                         let event = TaskOOM {
                             container_id: cid.to_string(),
                             ..Default::default()
@@ -316,6 +419,7 @@ impl Sandbox for VirtSandbox {
             }
         });
         self.monitor.start(id, self.agent.clone());
+        self.start_memory_reclaimer().await?;
         self.save().await.context("save state")?;
         Ok(())
     }
@@ -369,24 +473,67 @@ impl Sandbox for VirtSandbox {
         self.agent.agent_sock().await
     }
 
-    async fn direct_volume_stats(&self, volume_guest_path: &str) -> Result<String> {
-        let req: agent::VolumeStatsRequest = VolumeStatsRequest {
-            volume_guest_path: volume_guest_path.to_string(),
-        };
-        let result = self
-            .agent
-            .get_volume_stats(req)
+    async fn direct_volume_stats(&self, volume_path: &str) -> Result<String> {
+        let stats = self
+            .resource_manager
+            .volume_stats(volume_path)
             .await
             .context("sandbox: failed to process direct volume stats query")?;
-        Ok(result.data)
+        serde_json::to_string(&stats).context("sandbox: failed to serialize volume stats")
     }
 
     async fn direct_volume_resize(&self, resize_req: agent::ResizeVolumeRequest) -> Result<()> {
-        self.agent
-            .resize_volume(resize_req)
+        self.resource_manager
+            .resize_direct_volume(&resize_req.volume_guest_path, resize_req.size)
             .await
-            .context("sandbox: failed to resize direct-volume")?;
-        Ok(())
+            .context("sandbox: failed to resize direct-volume")
+    }
+
+    async fn reclaim_memory(&self, size_mb: u64) -> Result<()> {
+        info!(sl!(), "sb: reclaim_memory {} MiB invoked", size_mb);
+        self.resource_manager
+            .reclaim_memory(size_mb)
+            .await
+            .context("sandbox: failed to reclaim memory")
+    }
+
+    async fn resource_timings(&self) -> Result<String> {
+        let spans = self.resource_manager.timings();
+        serde_json::to_string(&spans).context("sandbox: failed to serialize resource timings")
+    }
+
+    async fn resource_plan(&self, spec: &oci::Spec, rootfs_mounts: &[Mount]) -> Result<String> {
+        let report = self
+            .resource_manager
+            .validate_plan(spec, rootfs_mounts)
+            .await;
+        serde_json::to_string(&report).context("sandbox: failed to serialize resource plan")
+    }
+
+    async fn resource_metrics(&self) -> Result<String> {
+        Ok(resource::metrics::gather())
+    }
+
+    async fn resource_events(&self) -> Result<Receiver<String>> {
+        let mut subscriber = self.resource_manager.subscribe_events();
+        let (sender, receiver) = channel(RESOURCE_EVENTS_BUFFER_SIZE);
+        // a slow receiver only holds up this task, the events it misses
+        // are dropped by the subscriber
+        tokio::spawn(async move {
+            while let Some(event) = subscriber.recv().await {
+                let event = match serde_json::to_string(&event) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        warn!(sl!(), "failed to serialize resource event: {:?}", e);
+                        continue;
+                    }
+                };
+                if sender.send(event).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(receiver)
     }
 
     async fn set_iptables(&self, is_ipv6: bool, data: Vec<u8>) -> Result<Vec<u8>> {
@@ -56,6 +56,9 @@ pub enum Commands {
     /// Gather metrics associated with infrastructure used to run a sandbox
     Metrics(MetricsCommand),
 
+    /// Query the resources of a sandbox
+    Resource(ResourceCommand),
+
     /// Display version details
     Version,
 }
@@ -86,6 +89,19 @@ pub enum CheckSubCommand {
 
     /// List all available checks
     List,
+
+    /// Check the consistency of a configuration file against the host, without creating a sandbox
+    Config(CheckConfigArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct CheckConfigArgs {
+    /// Configuration file to check, the default configuration file if not set
+    #[arg(short = 'f', long = "file")]
+    pub file: Option<String>,
+    /// Fail on the warnings too
+    #[arg(long)]
+    pub strict: bool,
 }
 
 #[derive(Debug, Args)]
@@ -122,6 +138,29 @@ pub enum IpTablesArguments {
     Metrics,
 }
 
+#[derive(Debug, Args)]
+pub struct ResourceCommand {
+    #[clap(subcommand)]
+    pub resource_cmd: ResourceSubCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ResourceSubCommand {
+    /// Tell what the resources of a container would be set up with by a sandbox, without setting them up
+    Plan(ResourcePlanArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct ResourcePlanArgs {
+    /// pod sandbox ID.
+    pub sandbox_id: String,
+    /// OCI spec of the container, e.g. the config.json of its bundle
+    pub spec: String,
+    /// JSON file of the mounts of the container rootfs, the rootfs of the spec is mounted already if not set
+    #[arg(long)]
+    pub rootfs_mounts: Option<String>,
+}
+
 #[derive(Debug, Args)]
 pub struct DirectVolumeCommand {
     #[clap(subcommand)]
@@ -22,6 +22,7 @@ use ops::check_ops::{
 };
 use ops::env_ops::handle_env;
 use ops::exec_ops::handle_exec;
+use ops::resource_ops::handle_resource;
 use ops::volume_ops::handle_direct_volume;
 use slog::{error, o};
 
@@ -52,6 +53,7 @@ fn real_main() -> Result<()> {
         Commands::Factory => handle_factory(),
         Commands::Iptables(args) => handle_iptables(args),
         Commands::Metrics(args) => handle_metrics(args),
+        Commands::Resource(args) => handle_resource(args),
         Commands::Version => handle_version(),
     };
 
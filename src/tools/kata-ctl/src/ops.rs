@@ -6,5 +6,6 @@
 pub mod check_ops;
 pub mod env_ops;
 pub mod exec_ops;
+pub mod resource_ops;
 pub mod version;
 pub mod volume_ops;
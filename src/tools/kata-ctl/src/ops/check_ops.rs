@@ -5,7 +5,9 @@
 
 use crate::arch::arch_specific::get_checks;
 
-use crate::args::{CheckArgument, CheckSubCommand, IptablesCommand, MetricsCommand};
+use crate::args::{
+    CheckArgument, CheckConfigArgs, CheckSubCommand, IptablesCommand, MetricsCommand,
+};
 
 use crate::check;
 
@@ -13,7 +15,9 @@ use crate::ops::version;
 
 use crate::types::*;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use kata_types::annotations::Annotation;
+use kata_types::config::{validate_violations, TomlConfig};
 
 use slog::{info, o, warn};
 
@@ -111,11 +115,36 @@ pub fn handle_check(checkcmd: CheckArgument) -> Result<()> {
             // retrieve ALL releases including prerelease
             check::check_all_releases()?;
         }
+        CheckSubCommand::Config(args) => {
+            check_config(args)?;
+        }
     }
 
     Ok(())
 }
 
+// check_config runs the consistency checks done when a sandbox is created
+// against the configuration file, the capabilities of the hypervisor aren't
+// known without a sandbox.
+fn check_config(args: CheckConfigArgs) -> Result<()> {
+    let file = args.file.unwrap_or_default();
+    // loaded raw, the paths not found on the host are reported with the
+    // other violations rather than failing the load
+    let (mut config, path) = TomlConfig::load_raw_from_file(&file).context("load config")?;
+    // the defaults set by the runtime without annotations
+    Annotation::new(Default::default())
+        .update_config_by_annotation(&mut config)
+        .context("set config defaults")?;
+
+    let warnings = validate_violations(config.check_consistency(), args.strict)
+        .with_context(|| format!("check config {}", path.display()))?;
+    for w in warnings.iter() {
+        warn!(sl!(), "{}", w);
+    }
+    info!(sl!(), "config {} is consistent", path.display());
+    Ok(())
+}
+
 pub fn handle_factory() -> Result<()> {
     Ok(())
 }
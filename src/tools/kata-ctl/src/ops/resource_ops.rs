@@ -0,0 +1,108 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::{fs, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use crate::args::{ResourceCommand, ResourcePlanArgs, ResourceSubCommand};
+use shim_interface::shim_mgmt::{client::MgmtClient, RESOURCE_PLAN_URL};
+
+const TIMEOUT: Duration = Duration::from_millis(5000);
+const CONTENT_TYPE_JSON: &str = "application/json";
+
+pub fn handle_resource(resource_cmd: ResourceCommand) -> Result<()> {
+    match resource_cmd.resource_cmd {
+        ResourceSubCommand::Plan(args) => {
+            let report = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(plan(&args))?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !is_admissible(&report) {
+                return Err(anyhow!(
+                    "the resources of the container can't be satisfied by sandbox {}",
+                    args.sandbox_id
+                ));
+            }
+            Ok(())
+        }
+    }
+}
+
+// plan asks the shim of the sandbox what the resources of the container
+// would be set up with, nothing is set up
+async fn plan(args: &ResourcePlanArgs) -> Result<Value> {
+    let spec: Value = read_json(&args.spec)?;
+    let rootfs_mounts = match &args.rootfs_mounts {
+        Some(path) => read_json(path)?,
+        None => json!([]),
+    };
+    let request = json!({
+        "spec": spec,
+        "rootfs_mounts": rootfs_mounts,
+    });
+
+    let shim_client = MgmtClient::new(&args.sandbox_id, Some(TIMEOUT))?;
+    let response = shim_client
+        .post(RESOURCE_PLAN_URL, CONTENT_TYPE_JSON, &request.to_string())
+        .await?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    if status != StatusCode::OK {
+        return Err(anyhow!(
+            "failed to plan resources ({:?}): {}",
+            status,
+            String::from_utf8_lossy(&body)
+        ));
+    }
+    serde_json::from_slice(&body).context("parse resource plan")
+}
+
+fn read_json(path: &str) -> Result<Value> {
+    let content = fs::read_to_string(path).with_context(|| format!("read {}", path))?;
+    serde_json::from_str(&content).with_context(|| format!("parse {}", path))
+}
+
+// is_admissible tells if the plan has no hard failure
+fn is_admissible(report: &Value) -> bool {
+    let no_errors = report["errors"]
+        .as_array()
+        .map(|e| e.is_empty())
+        .unwrap_or(true);
+    let resources_ok = report["resources"]
+        .as_array()
+        .map(|r| r.iter().all(|r| r["error"].is_null()))
+        .unwrap_or(true);
+    no_errors && resources_ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_admissible() {
+        let report = json!({
+            "resources": [{"kind": "volume", "source": "/data", "destination": "/data", "backend": "share_fs"}],
+            "errors": [],
+        });
+        assert!(is_admissible(&report));
+
+        let report = json!({
+            "resources": [{"kind": "device", "source": "8:16", "destination": "/dev/sdb", "backend": "", "error": "host block device 8:16"}],
+            "errors": [],
+        });
+        assert!(!is_admissible(&report));
+
+        let report = json!({
+            "resources": [],
+            "errors": ["container planned asks for 5 devices, over max_devices_per_container 4"],
+        });
+        assert!(!is_admissible(&report));
+    }
+}
@@ -83,10 +83,10 @@ async fn resize(volume_path: &str, size: u64) -> Result<Option<String>> {
 
 async fn stats(volume_path: &str) -> Result<Option<String>> {
     let sandbox_id = get_sandbox_id_for_volume(volume_path)?;
-    let mount_info = get_volume_mount_info(volume_path)?;
 
+    // the shim finds the filesystem of the volume from its path on the host
     let req_url = url::form_urlencoded::Serializer::new(String::from(DIRECT_VOLUME_STATS_URL))
-        .append_pair(DIRECT_VOLUME_PATH_KEY, &mount_info.device)
+        .append_pair(DIRECT_VOLUME_PATH_KEY, volume_path)
         .finish();
 
     let shim_client = MgmtClient::new(&sandbox_id, Some(TIMEOUT))?;